@@ -0,0 +1,991 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context;
+use walkdir::WalkDir;
+
+use crate::Error;
+use crate::backend;
+use crate::encryption::Cipher;
+use crate::file_errors::FileErrors;
+use crate::archive::unpack_archive_as;
+use crate::attributes::MetaDataWriter;
+use crate::platform::{self, MetadataExt, OsStrExt};
+use crate::report::{FileReport, UnappliedMetaData};
+use crate::order::prioritize;
+use crate::owners::Ownership;
+use crate::oci::LayerCompression;
+use crate::overlay::split_upper_dir;
+use crate::metadata::{Algo, ApplyState, Journal, MetaData, SpecialKind, REVERSE_DELTA_DIR};
+use crate::signing;
+use crate::sparse::{find_holes, SparseWriter};
+use crate::throttle::IoLimiter;
+use crate::patch_from;
+use crate::stream;
+use crate::tree_digest::tree_digest;
+use crate::utils::{drop_components, get_meta_data, temp_path_for, path_from_bytes,
+    parallel_map, default_jobs, digest_file, save_parent_modtime, make_special,
+    restore_modtimes, create_beneath, ensure_beneath, open_dir_beneath};
+
+/// Options controlling delta application
+#[derive(Debug, Clone, Default)]
+pub struct ApplyOptions {
+    /// Unpack the delta tree from this archive file first
+    pub archive: Option<PathBuf>,
+
+    /// Restore the source tree instead, from the target tree given as the
+    /// source directory and the reverse delta of a bidirectional diff
+    pub reverse: bool,
+
+    /// Undo an interrupted apply instead, leaving the delta directory as it
+    /// was before
+    pub rollback: bool,
+
+    /// For OCI images, keep the layers of the source image and add one
+    /// layer of the changes on top, with whiteouts for deleted paths,
+    /// instead of restoring a single-layer image
+    pub layered: bool,
+
+    /// For OCI images, compression of the layers written to the restored
+    /// image
+    pub layer_compression: LayerCompression,
+
+    /// Refuse deltas whose meta-data is not signed by the ed25519 secret key
+    /// matching the public key stored in this file
+    pub verify_key: Option<PathBuf>,
+
+    /// Decrypt the stored files of encrypted deltas with the AES-256-GCM key
+    /// stored in this file
+    pub decrypt_key: Option<PathBuf>,
+
+    /// Extra source trees that diff was given, in the same order, for the
+    /// files patched against them
+    pub extra_sources: Vec<PathBuf>,
+
+    /// Number of files to restore concurrently, defaulting to the number of CPUs
+    pub jobs: Option<usize>,
+
+    /// How the ownership of restored files is set
+    pub ownership: Ownership,
+
+    /// Go on when the ownership, permissions or xattrs of a file cannot be
+    /// set, listing them in `ApplyStats::unapplied_metadata`
+    pub best_effort_metadata: bool,
+
+    /// Leave the modification and access times of restored files and
+    /// directories as apply makes them, as for deltas made without times
+    pub ignore_times: bool,
+
+    /// Give up after this many files failed to be restored, or 0 to go
+    /// through all files first. The failures are reported together.
+    pub max_errors: usize,
+
+    /// Whole configuration of the source image, to reconstruct the one of
+    /// the target image from, as recorded in the delta
+    pub source_config: Option<serde_json::Value>,
+
+    /// Glob patterns of paths to restore first, in the order given, ahead of
+    /// the order recorded in the delta
+    pub priority: Vec<String>,
+
+    /// Limit the rate at which restored files are written, in bytes per
+    /// second, not to starve other workloads of disk bandwidth
+    pub io_limit: Option<u64>,
+
+    /// Move the paths of the restored tree that differ from the source tree
+    /// into this overlayfs upper directory, with whiteouts for the deleted
+    /// ones, for the source tree to serve as its lower directory
+    pub overlay_upper_dir: Option<PathBuf>,
+
+    /// What is flushed to disk before the apply is done
+    pub durability: Durability,
+}
+
+/// What apply flushes to disk, trading crash consistency for speed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Only the meta-data journal, leaving the rest to the filesystem, as
+    /// for trees that are thrown away if the host crashes
+    None,
+    /// The directories holding the restored paths, so that the renames
+    /// survive a crash
+    Dir,
+    /// The restored files before they are renamed over their placeholders,
+    /// and the directories holding them
+    #[default]
+    Full,
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Durability::None),
+            "dir" => Ok(Durability::Dir),
+            "full" => Ok(Durability::Full),
+            _ => Err(format!("unknown durability {}", s)),
+        }
+    }
+}
+
+/// Size totals of an applied delta
+#[derive(Debug, Clone)]
+pub struct ApplyStats {
+    /// Total size of the patches read from the delta directory
+    pub reduced_size: u64,
+
+    /// Total size of the restored files
+    pub total_size: u64,
+
+    /// How each restored file was handled
+    pub files: Vec<FileReport>,
+
+    /// Time taken by the whole apply
+    pub duration: Duration,
+
+    /// Digest of the restored tree, checked against the one of the target
+    /// tree if the delta has it
+    pub tree_digest: Option<String>,
+
+    /// Attributes that could not be set, with `ApplyOptions::best_effort_metadata`
+    pub unapplied_metadata: Vec<UnappliedMetaData>,
+
+    /// Configuration of the target image, with `ApplyOptions::source_config`
+    pub image_config: Option<serde_json::Value>,
+}
+
+/// Restores the target tree from a delta directory and the source tree it
+/// was computed against.
+pub struct DeltaApplier {
+    source_dir: PathBuf,
+    delta_target_dir: PathBuf,
+    options: ApplyOptions,
+    writer: MetaDataWriter,
+    limiter: Option<Arc<IoLimiter>>,
+}
+
+impl DeltaApplier {
+    pub fn new(source_dir: impl Into<PathBuf>, delta_target_dir: impl Into<PathBuf>) -> Self {
+        let delta_target_dir = delta_target_dir.into();
+        Self {
+            source_dir: source_dir.into(),
+            writer: MetaDataWriter::new(&delta_target_dir, false, true),
+            delta_target_dir,
+            options: ApplyOptions::default(),
+            limiter: None,
+        }
+    }
+
+    pub fn options(mut self, options: ApplyOptions) -> Self {
+        self.writer = MetaDataWriter::new(&self.delta_target_dir, options.best_effort_metadata,
+            !options.ignore_times);
+        self.limiter = options.io_limit.map(|limit| Arc::new(IoLimiter::new(limit)));
+        self.options = options;
+        self
+    }
+
+    /// Restore the target tree in place from the delta directory.
+    ///
+    /// Restored files are first staged under temporary names next to their
+    /// placeholders and validated, then renamed over them, with the progress
+    /// journaled in the meta-data file. An interrupted apply is resumed by
+    /// running it again, or can be rolled back with `ApplyOptions::rollback`.
+    pub fn run(&self) -> anyhow::Result<ApplyStats> {
+        let started = Instant::now();
+
+        if let Some(archive) = &self.options.archive {
+            // Unless resuming an apply of the already unpacked archive
+            let resuming = MetaData::load(&self.delta_target_dir)
+                .map(|md| md.journal.is_some()).unwrap_or(false);
+            if !resuming {
+                unpack_archive_as(archive, &self.delta_target_dir, &self.options.ownership,
+                    &self.writer)?;
+            }
+        }
+
+        if self.options.rollback {
+            return self.rollback().map(|_| ApplyStats {
+                reduced_size: 0,
+                total_size: 0,
+                files: vec![],
+                duration: started.elapsed(),
+                tree_digest: None,
+                unapplied_metadata: self.writer.take_unapplied(),
+                image_config: None,
+            });
+        }
+
+        if self.options.reverse {
+            return self.run_reverse();
+        }
+
+        let mut md = MetaData::load(&self.delta_target_dir)?;
+        md.check_paths()?;
+        if let Some(key_path) = &self.options.verify_key {
+            signing::verify(&md, &self.delta_target_dir, key_path)?;
+        }
+        let cipher = Cipher::for_delta(&md, &self.delta_target_dir, self.options.decrypt_key.as_deref())?;
+        if let Some(missing) = md.extra_sources.get(self.options.extra_sources.len()..).filter(|x| !x.is_empty()) {
+            return Err(Error::MissingExtraSources(missing.to_vec()).into());
+        }
+
+        // Times and ownership that diff did not record are not restored
+        let ignore_times = md.ignore_times && !self.options.ignore_times;
+        let ignore_owner = md.ignore_owner && self.options.ownership != Ownership::Skip;
+        if ignore_times || ignore_owner {
+            let options = ApplyOptions {
+                archive: None,
+                ignore_times: self.options.ignore_times || md.ignore_times,
+                ownership: match md.ignore_owner {
+                    true => Ownership::Skip,
+                    false => self.options.ownership.clone(),
+                },
+                ..self.options.clone()
+            };
+            return DeltaApplier::new(&self.source_dir, &self.delta_target_dir).options(options).run();
+        }
+        let mut parent_modtime_save = HashMap::new();
+
+        // Before the meta-data goes away with the apply
+        let image_config = match &self.options.source_config {
+            Some(source_config) => match &md.image_config {
+                Some(config_delta) => Some(config_delta.apply(source_config)?),
+                None => return Err(Error::NoImageConfig(self.delta_target_dir.clone()).into()),
+            },
+            None => None,
+        };
+
+        let journal = match md.journal.take() {
+            Some(journal) => {
+                tracing::warn!("Resuming interrupted apply of {}", self.delta_target_dir.display());
+                journal
+            }
+            None => {
+                let journal = Journal {
+                    state: ApplyState::Staging,
+                    link_groups: self.link_groups()?,
+                };
+                md.journal = Some(journal.clone());
+                md.save(&self.delta_target_dir)?;
+                journal
+            }
+        };
+
+        // Recreate directories that went missing from the delta tree
+        for directory in md.directories.iter() {
+            let delta_path = self.delta_target_dir.join(path_from_bytes(&directory.path));
+            ensure_beneath(&self.delta_target_dir, &delta_path)?;
+            if std::fs::symlink_metadata(&delta_path).is_err() {
+                save_parent_modtime(&mut parent_modtime_save, &delta_path)?;
+                std::fs::create_dir(&delta_path)
+                    .with_context(|| format!("failed creating directory {}", delta_path.display()))?;
+            }
+        }
+
+        // Recreate the placeholders that diff left out of the delta tree
+        for placeholder in md.placeholders.iter() {
+            let delta_path = self.delta_target_dir.join(path_from_bytes(&placeholder.path));
+            ensure_beneath(&self.delta_target_dir, &delta_path)?;
+            if std::fs::symlink_metadata(&delta_path).is_err() {
+                save_parent_modtime(&mut parent_modtime_save, &delta_path)?;
+                create_beneath(&self.delta_target_dir, &delta_path)?;
+                self.writer.set(&delta_path, self.options.ownership.stored(placeholder.meta_data())?)
+                    .with_context(|| format!("failed to set meta-data to {}", delta_path.display()))?;
+            }
+        }
+
+        let (files, reduced_size, total_size) = if journal.state == ApplyState::Staging {
+            match self.stage(&md, cipher.as_ref(), &mut parent_modtime_save) {
+                Ok(staged) => staged,
+                Err(err) => {
+                    // Leave the delta directory as it was
+                    self.rollback()?;
+                    return Err(err);
+                }
+            }
+        } else {
+            (vec![], 0, 0)
+        };
+
+        if journal.state != ApplyState::Committed {
+            md.journal = Some(Journal { state: ApplyState::Committing, ..journal.clone() });
+            md.save(&self.delta_target_dir)?;
+
+            for delta_path in self.staged_paths(&md) {
+                let staged_path = temp_path_for(&delta_path);
+                let backup_path = backup_path_for(&delta_path);
+                ensure_beneath(&self.delta_target_dir, &delta_path)?;
+                if staged_path.exists() {
+                    if !backup_path.exists() {
+                        std::fs::rename(&delta_path, &backup_path)
+                            .with_context(|| format!("failed renaming {}", delta_path.display()))?;
+                    }
+                    std::fs::rename(&staged_path, &delta_path)
+                        .with_context(|| format!("failed renaming {}", staged_path.display()))?;
+                }
+            }
+            let parents: BTreeSet<_> = self.staged_paths(&md).into_iter()
+                .filter_map(|delta_path| delta_path.parent().map(Path::to_owned))
+                .collect();
+            self.sync_dirs(parents.iter().map(PathBuf::as_path))?;
+
+            md.journal = Some(Journal { state: ApplyState::Committed, ..journal.clone() });
+            md.save(&self.delta_target_dir)?;
+
+            for delta_path in self.staged_paths(&md) {
+                let backup_path = backup_path_for(&delta_path);
+                if backup_path.exists() {
+                    std::fs::remove_file(&backup_path)
+                        .with_context(|| format!("failed removing {}", backup_path.display()))?;
+                }
+            }
+        }
+
+        let tree_digest = self.finish(&md, &journal, parent_modtime_save)?;
+
+        if let Some(upper_dir) = &self.options.overlay_upper_dir {
+            let (moved, whiteouts) = split_upper_dir(&self.source_dir, &self.delta_target_dir, upper_dir,
+                &self.writer)?;
+            tracing::info!("Moved {} paths and {} whiteouts to {}", moved, whiteouts, upper_dir.display());
+        }
+
+        let unapplied_metadata = self.writer.take_unapplied();
+        if !unapplied_metadata.is_empty() {
+            tracing::warn!("Could not set {} attributes of restored files", unapplied_metadata.len());
+        }
+
+        Ok(ApplyStats { reduced_size, total_size, files, duration: started.elapsed(), tree_digest,
+            unapplied_metadata, image_config })
+    }
+
+    /// Digest the restored tree and check it against the digest of the target
+    /// tree, if the delta has it. Only the restored files that were validated
+    /// are not read again, and the excluded paths taken from the source are
+    /// not part of it.
+    fn check_tree_digest(&self, md: &MetaData) -> anyhow::Result<Option<String>> {
+        let Some(expected) = &md.tree_digest else { return Ok(None) };
+        // Owners set other than as stored are not the ones digested by diff
+        if self.options.ownership != Ownership::Numeric {
+            return Ok(None);
+        }
+
+        let excluded: HashSet<_> = md.excluded.iter().map(|path| path_from_bytes(path)).collect();
+        let known = md.checksums.iter()
+            .map(|(path, checksum)| (path_from_bytes(path), checksum.clone()))
+            .collect();
+        let digest = tree_digest(&self.delta_target_dir, |rel_path| excluded.contains(rel_path),
+            &known)?;
+        if &digest != expected {
+            return Err(Error::TreeDigestMismatch(self.delta_target_dir.clone(), expected.clone(),
+                digest).into());
+        }
+        Ok(Some(digest))
+    }
+
+    /// Groups of hardlinked files of the delta tree, before any of them is replaced
+    fn link_groups(&self) -> anyhow::Result<Vec<Vec<Vec<u8>>>> {
+        let mut fsid_link_groups: HashMap<_, Vec<_>> = HashMap::new();
+        let n = self.delta_target_dir.components().count();
+
+        for entry in WalkDir::new(&self.delta_target_dir) {
+            let entry = entry?;
+            let path = entry.path();
+            let rel_path = drop_components(n, path);
+
+            if entry.file_type().is_file() {
+                let metadata = entry.metadata()?;
+                let fsid = (metadata.ino(), metadata.dev());
+                if metadata.nlink() >= 2 {
+                    fsid_link_groups.entry(fsid).or_default()
+                        .push(rel_path.as_os_str().as_bytes().to_owned());
+                }
+            }
+        }
+
+        Ok(fsid_link_groups.into_values().collect())
+    }
+
+    /// Create a file staging a restored one, with no symlink of the delta tree
+    /// followed on the way to it
+    fn create_staged<'a>(&self, staged_path: &Path, holes: &'a [(u64, u64)])
+        -> anyhow::Result<SparseWriter<'a>>
+    {
+        Ok(SparseWriter::new(create_beneath(&self.delta_target_dir, staged_path)?, holes)
+            .limited(self.limiter.clone()))
+    }
+
+    /// Flush a restored file to disk, with `Durability::Full`
+    fn sync_staged(&self, path: &Path) -> anyhow::Result<()> {
+        match self.options.durability {
+            Durability::Full => sync_file(path),
+            Durability::None | Durability::Dir => Ok(()),
+        }
+    }
+
+    /// Flush the entries of directories to disk, unless with `Durability::None`
+    fn sync_dirs<'a>(&self, dirs: impl Iterator<Item = &'a Path>) -> anyhow::Result<()> {
+        if self.options.durability == Durability::None {
+            return Ok(());
+        }
+        for dir in dirs {
+            sync_file(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Paths of the delta tree that get replaced by restored files
+    fn staged_paths(&self, md: &MetaData) -> Vec<PathBuf> {
+        md.changes.iter().map(|(_, path)| path)
+            .chain(md.keep_files.iter())
+            .map(|path| self.delta_target_dir.join(path_from_bytes(path)))
+            .collect()
+    }
+
+    /// Restore the modified and unmodified files next to their placeholders,
+    /// up to `ApplyOptions::jobs` at a time, and validate them against the
+    /// digests taken at diff time.
+    fn stage(&self, md: &MetaData, cipher: Option<&Cipher>,
+        parent_modtime_save: &mut HashMap<PathBuf, SystemTime>)
+        -> anyhow::Result<(Vec<FileReport>, u64, u64)>
+    {
+        let sparse: HashMap<_, _> = md.sparse.iter().map(|(path, holes)| (path, &holes[..])).collect();
+        let bases: HashMap<_, _> = md.bases.iter().map(|(path, base)| (path, *base as usize)).collect();
+
+        // Modified files, then files that were not modified
+        let changes: BTreeSet<_> = md.changes.iter().collect();
+        let mut work: Vec<_> = changes.into_iter().map(|(algo, path)| (Some(algo), path))
+            .chain(md.keep_files.iter().map(|path| (None, path)))
+            .collect();
+        prioritize(&mut work, |(_, path)| path, &self.options.priority, md.apply_order.as_deref())?;
+
+        // Serially, as files share parent directories
+        for (_, relative_path) in work.iter() {
+            let delta_path = self.delta_target_dir.join(path_from_bytes(relative_path));
+            ensure_beneath(&self.delta_target_dir, &delta_path)?;
+            save_parent_modtime(parent_modtime_save, &delta_path)?;
+        }
+
+        let jobs = self.options.jobs.unwrap_or_else(default_jobs);
+        let errors = FileErrors::new(self.options.max_errors);
+        let files = parallel_map(jobs, &work, |(algo, relative_path)| {
+            let file_started = Instant::now();
+            let holes = sparse.get(*relative_path).copied().unwrap_or_default();
+            let source_dir = match bases.get(*relative_path) {
+                Some(&base) => &self.options.extra_sources[base],
+                None => &self.source_dir,
+            };
+            let relative_path = path_from_bytes(relative_path);
+            let _span = tracing::trace_span!("file", path = %relative_path.display()).entered();
+            let staged = match algo {
+                Some(algo) => self.stage_change(algo, source_dir, &relative_path, holes, cipher),
+                None => self.stage_keep(&relative_path, holes).map(|size| (size, 0)),
+            };
+            match staged {
+                Ok((size, patch_size)) =>
+                    Ok(Some(FileReport::new(&relative_path, *algo, size, patch_size, file_started.elapsed()))),
+                Err(err) => errors.record(&relative_path, err).map(|()| None),
+            }
+        })?;
+        errors.into_result()?;
+        let files: Vec<_> = files.into_iter().flatten().collect();
+
+        let reduced_size = files.iter().map(|file| file.delta_size).sum();
+        let total_size = files.iter().map(|file| file.original_size).sum();
+        tracing::info!("Reduced size: {}", reduced_size);
+        tracing::info!("Inflated size: {}", total_size);
+
+        // Validate the restored files against the digests taken at diff time.
+        // Files outside of the staged ones, such as new files, are already in
+        // place.
+        let staged: HashSet<_> = self.staged_paths(md).into_iter().collect();
+        let mismatches = parallel_map(jobs, &md.checksums, |(relative_path, checksum)| {
+            let relative_path = path_from_bytes(relative_path);
+            let delta_path = self.delta_target_dir.join(&relative_path);
+            let path = if staged.contains(&delta_path) { temp_path_for(&delta_path) } else { delta_path };
+            let digest = digest_file(&path)?;
+            Ok((&digest != checksum).then_some(relative_path))
+        })?;
+        let mismatches: Vec<_> = mismatches.into_iter().flatten().collect();
+        if !mismatches.is_empty() {
+            return Err(Error::ChecksumMismatch(mismatches).into());
+        }
+
+        Ok((files, reduced_size, total_size))
+    }
+
+    /// Restore a modified file next to its placeholder, patched against the
+    /// file of `source_dir`, the source tree or an extra one. Returns the size
+    /// of the file and of the patch read for it.
+    fn stage_change(&self, algo: &Algo, source_dir: &Path, relative_path: &Path, holes: &[(u64, u64)],
+        cipher: Option<&Cipher>) -> anyhow::Result<(u64, u64)>
+    {
+        let source_path = match algo {
+            Algo::XDelta3From(from) => source_dir.join(path_from_bytes(from)),
+            _ => source_dir.join(relative_path),
+        };
+        let delta_path = self.delta_target_dir.join(relative_path);
+        let staged_path = temp_path_for(&delta_path);
+
+        if let Algo::CopyFrom(from) = algo {
+            // File moved from elsewhere in the source
+            let from_path = source_dir.join(path_from_bytes(from));
+            ensure_beneath(source_dir, &from_path)?;
+            let meta_data = get_meta_data(&delta_path)?;
+            let mut staged = self.create_staged(&staged_path, holes)?;
+            staged.copy_from(&mut std::fs::File::open(&from_path)?)?;
+            let size = staged.finish()?;
+
+            tracing::debug!("Copied {} <- {}: {}", relative_path.display(), from_path.display(), size);
+
+            self.sync_staged(&staged_path)?;
+            self.writer.set(&staged_path, self.options.ownership.on_disk(meta_data))?;
+            return Ok((size, 0));
+        }
+
+        if let (Algo::AsIs, None) = (algo, cipher) {
+            // Possibly large file stored as it is, copied without holding it
+            // in memory
+            let meta_data = get_meta_data(&delta_path)?;
+            let mut staged = self.create_staged(&staged_path, holes)?;
+            staged.copy_from(&mut std::fs::File::open(&delta_path)?)?;
+            let size = staged.finish()?;
+
+            tracing::debug!("Copied {}: {}", relative_path.display(), size);
+
+            self.sync_staged(&staged_path)?;
+            self.writer.set(&staged_path, self.options.ownership.on_disk(meta_data))?;
+            return Ok((size, size));
+        }
+
+        if let (Algo::AsIs, Some(cipher)) = (algo, cipher) {
+            // Possibly large new file, decrypted without holding it in memory
+            let meta_data = get_meta_data(&delta_path)?;
+            let mut staged = self.create_staged(&staged_path, holes)?;
+            let file = std::fs::File::open(&delta_path)?;
+            cipher.decrypt_to(std::io::BufReader::new(file), &mut staged, &delta_path)?;
+            let size = staged.finish()?;
+
+            tracing::debug!("Decrypted {}: {}", relative_path.display(), size);
+
+            self.sync_staged(&staged_path)?;
+            self.writer.set(&staged_path, self.options.ownership.on_disk(meta_data))?;
+            return Ok((size, size));
+        }
+
+        if let Algo::XDelta3Chunked(_) | Algo::ZstdPatchFrom(_) = algo {
+            // Large file - reconstruct it without holding it in memory
+            let meta_data = get_meta_data(&delta_path)?;
+            let decrypted_path = temp_path_for(&staged_path);
+            let (patch_path, patch_size) = match cipher {
+                Some(cipher) => {
+                    let output = create_beneath(&self.delta_target_dir, &decrypted_path)?;
+                    let patch_size = cipher.decrypt_file_to(&delta_path, output)?;
+                    (&decrypted_path, patch_size)
+                }
+                None => (&delta_path, delta_path.metadata()?.len()),
+            };
+            ensure_beneath(source_dir, &source_path)?;
+            let mut staged = self.create_staged(&staged_path, holes)?;
+            match algo {
+                Algo::XDelta3Chunked(chunk_size) => {
+                    stream::decode(&source_path, patch_path, &mut staged, *chunk_size)?
+                }
+                Algo::ZstdPatchFrom(window_log) => {
+                    patch_from::decode(&source_path, patch_path, &mut staged, *window_log)?
+                }
+                _ => unreachable!(),
+            };
+            let size = staged.finish()?;
+            if cipher.is_some() {
+                std::fs::remove_file(&decrypted_path)?;
+            }
+
+            tracing::debug!("Modified {}: {} -> {}", relative_path.display(), patch_size, size);
+
+            self.sync_staged(&staged_path)?;
+            self.writer.set(&staged_path, self.options.ownership.on_disk(meta_data))?;
+            return Ok((size, patch_size));
+        }
+
+        // Taken before reading the patch, which may update its access time
+        let meta_data = get_meta_data(&delta_path)?;
+        let backend = backend::for_algo(algo).expect("file stored whole");
+        // New files may be stored compressed, without a source file
+        let orig = match backend.uses_source() {
+            true => {
+                ensure_beneath(source_dir, &source_path)?;
+                std::fs::read(&source_path)?
+            }
+            false => vec![],
+        };
+        let patch_data = match cipher {
+            Some(cipher) => cipher.decrypt_file(&delta_path)?,
+            None => std::fs::read(&delta_path)?,
+        };
+
+        tracing::debug!("Checking {}, {} + {} ->", relative_path.display(),
+            orig.len(), delta_path.metadata()?.len());
+
+        let deflated_content = backend.decode(&orig, &patch_data)
+            .ok_or_else(|| Error::FailedDecode(algo.name(), source_path.clone(),
+                delta_path.clone()))?;
+
+        tracing::debug!("Modified {}: {} -> {}", relative_path.display(), patch_data.len(),
+            deflated_content.len());
+
+        let mut staged = self.create_staged(&staged_path, holes)?;
+        staged.write_all(&deflated_content)?;
+        staged.finish()?;
+        self.sync_staged(&staged_path)?;
+        self.writer.set(&staged_path, self.options.ownership.on_disk(meta_data))?;
+        Ok((deflated_content.len() as u64, patch_data.len() as u64))
+    }
+
+    /// Restore a file that was not modified next to its placeholder, simply
+    /// copying it from the source. Returns the size of the file.
+    fn stage_keep(&self, relative_path: &Path, holes: &[(u64, u64)]) -> anyhow::Result<u64> {
+        tracing::debug!("Checking {}", relative_path.display());
+        let source_path = self.source_dir.join(relative_path);
+        let delta_path = self.delta_target_dir.join(relative_path);
+        let staged_path = temp_path_for(&delta_path);
+        ensure_beneath(&self.source_dir, &source_path)?;
+
+        let meta_data = get_meta_data(&delta_path)?;
+        let mut staged = self.create_staged(&staged_path, holes)?;
+        staged.copy_from(&mut std::fs::File::open(&source_path)?)?;
+        let size = staged.finish()?;
+
+        tracing::debug!("Keeping {}: {}", relative_path.display(), size);
+
+        self.sync_staged(&staged_path)?;
+        self.writer.set(&staged_path, self.options.ownership.on_disk(meta_data))?;
+        Ok(size)
+    }
+
+    /// Steps following the replacement of the staged files, which can all be
+    /// done again when resuming. Returns the digest of the restored tree.
+    fn finish(&self, md: &MetaData, journal: &Journal,
+        mut parent_modtime_save: HashMap<PathBuf, SystemTime>) -> anyhow::Result<Option<String>>
+    {
+        // The reverse delta of a bidirectional diff is not part of the target tree
+        let reverse_delta_path = self.delta_target_dir.join(REVERSE_DELTA_DIR);
+        if md.reverse.is_some() && reverse_delta_path.is_dir() {
+            save_parent_modtime(&mut parent_modtime_save, &reverse_delta_path)?;
+            std::fs::remove_dir_all(&reverse_delta_path)
+                .with_context(|| format!("failed removing {}", reverse_delta_path.display()))?;
+        }
+
+        // Remove files that were deleted between the two trees
+        for relative_path in md.deleted_files.iter() {
+            let relative_path = path_from_bytes(relative_path);
+            let delta_path = self.delta_target_dir.join(&relative_path);
+
+            ensure_beneath(&self.delta_target_dir, &delta_path)?;
+            if std::fs::symlink_metadata(&delta_path).map(|m| m.is_file()).unwrap_or(false) {
+                tracing::debug!("Deleting {}", relative_path.display());
+
+                save_parent_modtime(&mut parent_modtime_save, &delta_path)?;
+                std::fs::remove_file(&delta_path)
+                    .with_context(|| format!("failed removing {}", delta_path.display()))?;
+            }
+        }
+
+        // Take the paths that were excluded from the delta from the source as
+        // they are
+        let mut excluded_dirs = Vec::new();
+        for relative_path in md.excluded.iter() {
+            let relative_path = path_from_bytes(relative_path);
+            let source_path = self.source_dir.join(&relative_path);
+            let delta_path = self.delta_target_dir.join(&relative_path);
+
+            let Ok(metadata) = std::fs::symlink_metadata(&source_path) else { continue };
+            ensure_beneath(&self.source_dir, &source_path)?;
+            ensure_beneath(&self.delta_target_dir, &delta_path)?;
+            if std::fs::symlink_metadata(&delta_path).is_ok() {
+                continue;
+            }
+
+            tracing::debug!("Taking excluded {}", relative_path.display());
+
+            save_parent_modtime(&mut parent_modtime_save, &delta_path)?;
+            if metadata.is_dir() {
+                std::fs::create_dir(&delta_path)
+                    .with_context(|| format!("failed creating directory {}", delta_path.display()))?;
+                let meta_data = self.options.ownership.on_disk(get_meta_data(&source_path)?);
+                excluded_dirs.push((delta_path, meta_data));
+            } else if metadata.is_symlink() {
+                platform::symlink(&std::fs::read_link(&source_path)?, &delta_path)
+                    .with_context(|| format!("failed creating symlink {}", delta_path.display()))?;
+                let (uid, gid) = self.options.ownership.on_disk_ids(metadata.uid(), metadata.gid());
+                self.writer.set_symlink_owner(&delta_path, uid, gid)
+                    .with_context(|| format!("failed to chown symlink {}", delta_path.display()))?;
+            } else {
+                // Staged, so that a resumed apply does not find it half-copied
+                let staged_path = temp_path_for(&delta_path);
+                let meta_data = get_meta_data(&source_path)?;
+                let holes = find_holes(&source_path)?;
+                let mut staged = SparseWriter::new(
+                    create_beneath(&self.delta_target_dir, &staged_path)?, &holes)
+                    .limited(self.limiter.clone());
+                staged.copy_from(&mut std::fs::File::open(&source_path)?)?;
+                staged.finish()?;
+                self.writer.set(&staged_path, self.options.ownership.on_disk(meta_data))?;
+                std::fs::rename(&staged_path, &delta_path)?;
+            }
+        }
+
+        // Repair symlinks that are missing or were changed
+        for symlink in md.symlinks.iter() {
+            let relative_path = path_from_bytes(&symlink.path);
+            let delta_path = self.delta_target_dir.join(&relative_path);
+            let target = path_from_bytes(&symlink.target);
+
+            ensure_beneath(&self.delta_target_dir, &delta_path)?;
+            if std::fs::read_link(&delta_path).ok().as_ref() != Some(&target) {
+                tracing::debug!("Restoring symlink {} -> {}", relative_path.display(), target.display());
+
+                save_parent_modtime(&mut parent_modtime_save, &delta_path)?;
+                if let Ok(metadata) = std::fs::symlink_metadata(&delta_path) {
+                    if metadata.is_dir() {
+                        return Err(Error::SymlinkIsDir(delta_path).into());
+                    }
+                    std::fs::remove_file(&delta_path)?;
+                }
+                platform::symlink(&target, &delta_path)
+                    .with_context(|| format!("failed creating symlink {}", delta_path.display()))?;
+            }
+
+            let (uid, gid) = self.options.ownership.stored_ids(symlink.uid, symlink.gid)?;
+            self.writer.set_symlink_owner(&delta_path, uid, gid)
+                .with_context(|| format!("failed to chown symlink {}", delta_path.display()))?;
+        }
+
+        // Recreate special files that are missing or were changed
+        for special in md.specials.iter() {
+            let relative_path = path_from_bytes(&special.path);
+            let delta_path = self.delta_target_dir.join(&relative_path);
+
+            ensure_beneath(&self.delta_target_dir, &delta_path)?;
+            let existing = std::fs::symlink_metadata(&delta_path).ok();
+            let matches = existing.as_ref().map(|metadata| {
+                SpecialKind::of(metadata.file_type()) == Some(special.kind)
+                    && metadata.rdev() == special.rdev()
+            });
+            if matches != Some(true) {
+                tracing::debug!("Restoring special file {}", relative_path.display());
+
+                save_parent_modtime(&mut parent_modtime_save, &delta_path)?;
+                if let Some(metadata) = existing {
+                    if metadata.is_dir() {
+                        return Err(Error::SpecialIsDir(delta_path).into());
+                    }
+                    std::fs::remove_file(&delta_path)?;
+                }
+                make_special(&delta_path, special.kind, special.rdev())
+                    .with_context(|| format!("failed creating special file {}", delta_path.display()))?;
+            }
+
+            self.writer.set(&delta_path, self.options.ownership.stored(special.meta_data())?)
+                .with_context(|| format!("failed to set meta-data to {}", delta_path.display()))?;
+        }
+
+        // Restore hardlinks, only between files that are still identical. Groups
+        // recorded by diff are linked even if the delta tree lost its links.
+        let recreated_paths: HashSet<_> = md.changes.iter().map(|(_, path)| path)
+            .chain(md.keep_files.iter())
+            .collect();
+        let checksums: HashMap<_, _> = md.checksums.iter()
+            .map(|(path, checksum)| (path, checksum))
+            .collect();
+        let link_groups: Vec<(&[Vec<u8>], Option<&String>)> = match &md.link_groups {
+            Some(groups) => groups.iter().map(|group| (&group.paths[..], Some(&group.checksum))).collect(),
+            None => journal.link_groups.iter().map(|paths| (&paths[..], None)).collect(),
+        };
+        for (linkgroup, checksum) in link_groups {
+            let Some(path) = linkgroup.iter().find(|path| recreated_paths.contains(path))
+                .or_else(|| checksum.and_then(|_| linkgroup.iter()
+                    .find(|path| self.delta_target_dir.join(path_from_bytes(path)).is_file())))
+            else {
+                continue
+            };
+            let abs_path = self.delta_target_dir.join(path_from_bytes(path));
+            ensure_beneath(&self.delta_target_dir, &abs_path)?;
+
+            let expected = checksum.or_else(|| checksums.get(path).copied());
+            if let Some(checksum) = checksum {
+                let restored = match checksums.get(path) {
+                    Some(restored) => (*restored).clone(),
+                    None => digest_file(&abs_path)?,
+                };
+                if &restored != checksum {
+                    tracing::warn!("Not restoring the hardlinks of {}, whose content differs",
+                        path_from_bytes(path).display());
+                    continue;
+                }
+            }
+
+            for other_path in linkgroup.iter().filter(|other_path| *other_path != path) {
+                // Files of a group that diverged are left apart
+                let differs = checksums.get(other_path).zip(expected)
+                    .map(|(other, expected)| *other != expected)
+                    .unwrap_or(false);
+                if differs {
+                    tracing::debug!("Not linking {} to {}, whose content differs",
+                        path_from_bytes(other_path).display(), path_from_bytes(path).display());
+                    continue;
+                }
+
+                let abs_other_path = self.delta_target_dir.join(path_from_bytes(other_path));
+
+                ensure_beneath(&self.delta_target_dir, &abs_other_path)?;
+                save_parent_modtime(&mut parent_modtime_save, &abs_other_path)?;
+
+                if let Ok(metadata) = std::fs::symlink_metadata(&abs_other_path) {
+                    let linked = std::fs::metadata(&abs_path)
+                        .map(|path_metadata| (path_metadata.ino(), path_metadata.dev())
+                            == (metadata.ino(), metadata.dev()))
+                        .unwrap_or(false);
+                    if linked {
+                        continue;
+                    }
+                    std::fs::remove_file(&abs_other_path)?;
+                }
+                std::fs::hard_link(&abs_path, &abs_other_path)
+                    .with_context(|| format!("failed linking {} -> {}",
+                            abs_path.display(), abs_other_path.display()))?;
+            }
+        }
+
+        let touched_dirs: Vec<_> = parent_modtime_save.keys().cloned().collect();
+        restore_modtimes(parent_modtime_save)?;
+
+        MetaData::remove(&self.delta_target_dir)?;
+
+        // Restore directory meta-data last, so that the modification times stick
+        let restore_directories = || -> anyhow::Result<()> {
+            for directory in md.directories.iter() {
+                let delta_path = self.delta_target_dir.join(path_from_bytes(&directory.path));
+                // Opened first, for a directory replaced with a symlink not to
+                // get the ownership and permissions changed through it
+                open_dir_beneath(&self.delta_target_dir, &delta_path)?;
+                self.writer.set(&delta_path, self.options.ownership.stored(directory.meta_data())?)
+                    .with_context(|| format!("failed to set meta-data to {}", delta_path.display()))?;
+            }
+            for (delta_path, meta_data) in excluded_dirs.iter() {
+                self.writer.set(delta_path, meta_data.clone())
+                    .with_context(|| format!("failed to set meta-data to {}", delta_path.display()))?;
+            }
+            Ok(())
+        };
+        restore_directories()?;
+        // With the meta-data file gone from the top directory
+        self.sync_dirs(touched_dirs.iter().map(PathBuf::as_path)
+            .chain(std::iter::once(self.delta_target_dir.as_path())))?;
+
+        // Reading the directories for the digest updates their access times
+        let tree_digest = self.check_tree_digest(md)?;
+        if tree_digest.is_some() {
+            restore_directories()?;
+        }
+
+        Ok(tree_digest)
+    }
+
+    /// Undo an interrupted apply, putting the placeholders and patches that
+    /// were replaced back in place.
+    fn rollback(&self) -> anyhow::Result<()> {
+        let mut md = MetaData::load(&self.delta_target_dir)?;
+        md.check_paths()?;
+        match md.journal.as_ref().map(|journal| &journal.state) {
+            None => return Ok(()),
+            Some(ApplyState::Committed) => {
+                return Err(Error::CannotRollBack(self.delta_target_dir.clone()).into());
+            }
+            Some(ApplyState::Staging | ApplyState::Committing) => {}
+        }
+
+        for delta_path in self.staged_paths(&md) {
+            let staged_path = temp_path_for(&delta_path);
+            let backup_path = backup_path_for(&delta_path);
+            if backup_path.exists() {
+                std::fs::rename(&backup_path, &delta_path)
+                    .with_context(|| format!("failed renaming {}", backup_path.display()))?;
+            }
+            for path in [temp_path_for(&staged_path), staged_path] {
+                // Along with the patch decrypted for staging, if any
+                if path.exists() {
+                    std::fs::remove_file(&path)
+                        .with_context(|| format!("failed removing {}", path.display()))?;
+                }
+            }
+        }
+
+        md.journal = None;
+        md.save(&self.delta_target_dir)
+    }
+
+    /// Restore the source tree in place of the delta directory, by applying
+    /// the reverse delta to the target tree.
+    fn run_reverse(&self) -> anyhow::Result<ApplyStats> {
+        let md = MetaData::load(&self.delta_target_dir)?;
+        md.check_paths()?;
+        if let Some(key_path) = &self.options.verify_key {
+            signing::verify(&md, &self.delta_target_dir, key_path)?;
+        }
+        let reverse_md = md.reverse
+            .ok_or_else(|| Error::NoReverseDelta(self.delta_target_dir.clone()))?;
+        let reverse_delta_path = self.delta_target_dir.join(REVERSE_DELTA_DIR);
+        reverse_md.save(&reverse_delta_path)?;
+
+        // The reverse delta is covered by the signature of the forward one
+        let options = ApplyOptions { reverse: false, archive: None, verify_key: None,
+            ..self.options.clone() };
+        let mut stats = DeltaApplier::new(&self.source_dir, &reverse_delta_path)
+            .options(options)
+            .run()?;
+
+        // Replace the forward delta with the restored tree
+        let meta_data = self.options.ownership.on_disk(get_meta_data(&reverse_delta_path)?);
+        for entry in std::fs::read_dir(&self.delta_target_dir)? {
+            let path = entry?.path();
+            if path == reverse_delta_path {
+                continue;
+            }
+            if std::fs::symlink_metadata(&path)?.is_dir() {
+                std::fs::remove_dir_all(&path)
+            } else {
+                std::fs::remove_file(&path)
+            }.with_context(|| format!("failed removing {}", path.display()))?;
+        }
+        for entry in std::fs::read_dir(&reverse_delta_path)? {
+            let entry = entry?;
+            std::fs::rename(entry.path(), self.delta_target_dir.join(entry.file_name()))
+                .with_context(|| format!("failed moving {}", entry.path().display()))?;
+        }
+
+        std::fs::remove_dir(&reverse_delta_path)?;
+        self.writer.set(&self.delta_target_dir, meta_data)
+            .with_context(|| format!("failed to set meta-data to {}",
+                    self.delta_target_dir.display()))?;
+        stats.unapplied_metadata.extend(self.writer.take_unapplied());
+
+        Ok(stats)
+    }
+}
+
+/// A sibling path keeping a replaced file until an apply is committed
+fn backup_path_for(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(|x| x.to_owned()).unwrap_or_default();
+    name.push(".deltaimage-orig");
+    path.with_file_name(name)
+}
+
+fn sync_file(path: &Path) -> anyhow::Result<()> {
+    std::fs::File::open(path)?.sync_all()
+        .with_context(|| format!("failed to sync {}", path.display()))
+}
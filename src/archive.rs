@@ -0,0 +1,340 @@
+//! Single-file container for a delta tree, easier to ship over HTTP or store
+//! in artifact repositories than a directory.
+//!
+//! Layout, all integers little-endian:
+//!
+//! ```text
+//! header:  "DELTAIMGARCH" | u32 version
+//! entries: u8 kind | path | [attributes] | kind-specific data
+//! index:   per entry: path | u64 offset of the entry
+//! footer:  u64 offset of the index | u64 number of entries | "DELTAIDX"
+//! ```
+//!
+//! Paths and other byte strings are stored as `u32 length | bytes`. Hardlinks
+//! carry no attributes and refer to the path of their first occurrence.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use walkdir::WalkDir;
+
+use crate::Error;
+use crate::metadata::Timestamp;
+use crate::attributes::MetaDataWriter;
+use crate::owners::Ownership;
+use crate::platform::{self, MetadataExt, OsStrExt};
+use crate::utils::{self, create_beneath, drop_components, ensure_beneath, get_meta_data, is_plain_relative,
+    path_from_bytes};
+
+const MAGIC: &[u8] = b"DELTAIMGARCH";
+const INDEX_MAGIC: &[u8] = b"DELTAIDX";
+/// Version 2 added the access times
+const VERSION: u32 = 2;
+
+const KIND_DIR: u8 = 0;
+const KIND_FILE: u8 = 1;
+const KIND_SYMLINK: u8 = 2;
+const KIND_HARDLINK: u8 = 3;
+
+struct ArchiveWriter {
+    out: BufWriter<File>,
+    offset: u64,
+}
+
+impl ArchiveWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.out.write_all(data)?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    fn write_u32(&mut self, value: u32) -> std::io::Result<()> {
+        self.write(&value.to_le_bytes())
+    }
+
+    fn write_u64(&mut self, value: u64) -> std::io::Result<()> {
+        self.write(&value.to_le_bytes())
+    }
+
+    fn write_bytes(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.write_u32(data.len() as u32)?;
+        self.write(data)
+    }
+
+    fn write_attributes(&mut self, meta_data: utils::MetaData) -> std::io::Result<()> {
+        let (modified, accessed, mode, uid, gid, xattrs, _, _) = meta_data;
+
+        self.write_u32(mode)?;
+        self.write_u32(uid)?;
+        self.write_u32(gid)?;
+        self.write_u64(modified.secs as u64)?;
+        self.write_u32(modified.nanos)?;
+        self.write_u64(accessed.secs as u64)?;
+        self.write_u32(accessed.nanos)?;
+        self.write_u32(xattrs.len() as u32)?;
+        for (key, value) in xattrs {
+            self.write_bytes(key.as_bytes())?;
+            self.write_bytes(&value)?;
+        }
+        Ok(())
+    }
+}
+
+struct ArchiveReader {
+    input: BufReader<File>,
+    version: u32,
+}
+
+impl ArchiveReader {
+    fn read_exact<const N: usize>(&mut self) -> std::io::Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.input.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_u32(&mut self) -> std::io::Result<u32> {
+        Ok(u32::from_le_bytes(self.read_exact()?))
+    }
+
+    fn read_u64(&mut self) -> std::io::Result<u64> {
+        Ok(u64::from_le_bytes(self.read_exact()?))
+    }
+
+    fn read_bytes(&mut self) -> std::io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; self.read_u32()? as usize];
+        self.input.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_attributes(&mut self) -> std::io::Result<utils::MetaData> {
+        let mode = self.read_u32()?;
+        let uid = self.read_u32()?;
+        let gid = self.read_u32()?;
+        let modified = Timestamp { secs: self.read_u64()? as i64, nanos: self.read_u32()? };
+        let accessed = match self.version {
+            1 => modified,
+            _ => Timestamp { secs: self.read_u64()? as i64, nanos: self.read_u32()? },
+        };
+        let mut xattrs = vec![];
+        for _ in 0..self.read_u32()? {
+            let key = platform::os_string_from_bytes(&self.read_bytes()?);
+            xattrs.push((key, self.read_bytes()?));
+        }
+
+        Ok((modified, accessed, mode, uid, gid, xattrs, 0, 0))
+    }
+
+    /// Read the footer, returning the offset of the index and the number of entries
+    fn read_footer(&mut self) -> anyhow::Result<(u64, u64)> {
+        self.input.seek(SeekFrom::End(-(16 + INDEX_MAGIC.len() as i64)))?;
+        let index_offset = self.read_u64()?;
+        let nr_entries = self.read_u64()?;
+        if self.read_exact::<8>()? != INDEX_MAGIC {
+            return Err(Error::InvalidArchive("bad index magic").into());
+        }
+        Ok((index_offset, nr_entries))
+    }
+}
+
+/// Pack a delta tree into a single archive file.
+pub fn pack_archive(dir: &Path, archive: &Path) -> anyhow::Result<()> {
+    let mut writer = ArchiveWriter {
+        out: BufWriter::new(File::create(archive)
+            .with_context(|| format!("failed to create {}", archive.display()))?),
+        offset: 0,
+    };
+    writer.write(MAGIC)?;
+    writer.write_u32(VERSION)?;
+
+    let n = dir.components().count();
+    let mut index = vec![];
+    let mut first_links: HashMap<_, Vec<u8>> = HashMap::new();
+
+    for entry in WalkDir::new(dir) {
+        let entry = entry?;
+        let path = entry.path();
+        let rel_path = drop_components(n, path);
+        let rel_bytes = rel_path.as_os_str().as_bytes();
+        let metadata = entry.metadata()?;
+
+        index.push((rel_bytes.to_owned(), writer.offset));
+
+        if entry.file_type().is_dir() {
+            writer.write(&[KIND_DIR])?;
+            writer.write_bytes(rel_bytes)?;
+            writer.write_attributes(get_meta_data(path)?)?;
+        } else if entry.file_type().is_symlink() {
+            let modified = filetime::FileTime::from_last_modification_time(&metadata).into();
+            let accessed = filetime::FileTime::from_last_access_time(&metadata).into();
+            writer.write(&[KIND_SYMLINK])?;
+            writer.write_bytes(rel_bytes)?;
+            writer.write_attributes((modified, accessed, metadata.mode(), metadata.uid(),
+                metadata.gid(), vec![], 0, 0))?;
+            writer.write_bytes(std::fs::read_link(path)?.as_os_str().as_bytes())?;
+        } else if entry.file_type().is_file() {
+            if metadata.nlink() >= 2 {
+                let fsid = (metadata.ino(), metadata.dev());
+                if let Some(first) = first_links.get(&fsid) {
+                    writer.write(&[KIND_HARDLINK])?;
+                    writer.write_bytes(rel_bytes)?;
+                    writer.write_bytes(first)?;
+                    continue;
+                }
+                first_links.insert(fsid, rel_bytes.to_owned());
+            }
+
+            writer.write(&[KIND_FILE])?;
+            writer.write_bytes(rel_bytes)?;
+            writer.write_attributes(get_meta_data(path)?)?;
+            writer.write_u64(metadata.len())?;
+            let copied = std::io::copy(&mut File::open(path)?, &mut writer.out)
+                .with_context(|| format!("failed to archive {}", path.display()))?;
+            writer.offset += copied;
+            if copied != metadata.len() {
+                return Err(Error::InvalidArchive("file changed while archiving").into());
+            }
+        } else {
+            // Other file types are not part of delta trees
+            index.pop();
+        }
+    }
+
+    let index_offset = writer.offset;
+    for (path, offset) in index.iter() {
+        writer.write_bytes(path)?;
+        writer.write_u64(*offset)?;
+    }
+    writer.write_u64(index_offset)?;
+    writer.write_u64(index.len() as u64)?;
+    writer.write(INDEX_MAGIC)?;
+
+    writer.out.flush()
+        .with_context(|| format!("failed to write to {}", archive.display()))?;
+
+    Ok(())
+}
+
+/// Read the index of an archive: the paths it contains and the offsets of
+/// their entries.
+pub fn read_archive_index(archive: &Path) -> anyhow::Result<Vec<(PathBuf, u64)>> {
+    let mut reader = ArchiveReader {
+        input: BufReader::new(File::open(archive)
+            .with_context(|| format!("failed to open {}", archive.display()))?),
+        version: VERSION,
+    };
+
+    let (index_offset, nr_entries) = reader.read_footer()?;
+    reader.input.seek(SeekFrom::Start(index_offset))?;
+
+    let mut index = vec![];
+    for _ in 0..nr_entries {
+        let path = path_from_bytes(&reader.read_bytes()?);
+        index.push((path, reader.read_u64()?));
+    }
+
+    Ok(index)
+}
+
+/// Unpack an archive into a delta tree at `dir`, which must not exist.
+/// Entries with paths outside of `dir`, or beneath a symlink of the archive,
+/// are refused.
+pub fn unpack_archive(archive: &Path, dir: &Path) -> anyhow::Result<()> {
+    unpack_archive_as(archive, dir, &Ownership::default(), &MetaDataWriter::new(dir, false, true))
+}
+
+/// Unpack an archive, setting the ownership of its entries as `ownership` says
+/// and their meta-data through `writer`
+pub(crate) fn unpack_archive_as(archive: &Path, dir: &Path, ownership: &Ownership,
+    writer: &MetaDataWriter) -> anyhow::Result<()>
+{
+    if dir.exists() {
+        return Err(Error::DeltaDirExists(dir.to_owned()).into());
+    }
+
+    let mut reader = ArchiveReader {
+        input: BufReader::new(File::open(archive)
+            .with_context(|| format!("failed to open {}", archive.display()))?),
+        version: VERSION,
+    };
+
+    let (index_offset, nr_entries) = reader.read_footer()?;
+    reader.input.seek(SeekFrom::Start(0))?;
+    if reader.read_exact::<12>()? != MAGIC {
+        return Err(Error::InvalidArchive("bad magic").into());
+    }
+    reader.version = reader.read_u32()?;
+    if !(1..=VERSION).contains(&reader.version) {
+        return Err(Error::InvalidArchive("unsupported version").into());
+    }
+
+    // Directory meta-data is restored last, once their content is in place
+    let mut directories = vec![];
+
+    for _ in 0..nr_entries {
+        let [kind] = reader.read_exact::<1>()?;
+        let rel_path = path_from_bytes(&reader.read_bytes()?);
+        if !is_plain_relative(&rel_path) {
+            return Err(Error::UnsafePath(rel_path).into());
+        }
+        let path = dir.join(&rel_path);
+        // Not through a symlink made by an earlier entry
+        ensure_beneath(dir, &path)?;
+
+        match kind {
+            KIND_DIR => {
+                let meta_data = ownership.stored(reader.read_attributes()?)?;
+                std::fs::create_dir(&path)
+                    .with_context(|| format!("failed creating directory {}", path.display()))?;
+                directories.push((path, meta_data));
+            }
+            KIND_FILE => {
+                let meta_data = ownership.stored(reader.read_attributes()?)?;
+                let size = reader.read_u64()?;
+                let mut file = create_beneath(dir, &path)?;
+                let copied = std::io::copy(&mut (&mut reader.input).take(size), &mut file)?;
+                if copied != size {
+                    return Err(Error::InvalidArchive("truncated file entry").into());
+                }
+                writer.set(&path, meta_data)
+                    .with_context(|| format!("failed to set meta-data to {}", path.display()))?;
+            }
+            KIND_SYMLINK => {
+                let (modified, accessed, _, uid, gid, _, _, _) = reader.read_attributes()?;
+                let target = path_from_bytes(&reader.read_bytes()?);
+                platform::symlink(&target, &path)
+                    .with_context(|| format!("failed creating symlink {}", path.display()))?;
+                let (uid, gid) = ownership.stored_ids(uid, gid)?;
+                writer.set_symlink_owner(&path, uid, gid)?;
+                filetime::set_symlink_file_times(&path, accessed.into(), modified.into()).map_err(|e| {
+                    Error::FileTimeError(e, path.to_owned())
+                })?;
+            }
+            KIND_HARDLINK => {
+                let first = path_from_bytes(&reader.read_bytes()?);
+                if !is_plain_relative(&first) {
+                    return Err(Error::UnsafePath(first).into());
+                }
+                let first = dir.join(first);
+                ensure_beneath(dir, &first)?;
+                std::fs::hard_link(&first, &path)
+                    .with_context(|| format!("failed linking {} -> {}",
+                            first.display(), path.display()))?;
+            }
+            _ => return Err(Error::InvalidArchive("unknown entry kind").into()),
+        }
+    }
+
+    if reader.input.stream_position()? != index_offset {
+        return Err(Error::InvalidArchive("entries do not end at the index").into());
+    }
+
+    for (path, meta_data) in directories {
+        writer.set(&path, meta_data)
+            .with_context(|| format!("failed to set meta-data to {}", path.display()))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,64 @@
+//! Setting of the meta-data of restored files, either failing on the first
+//! attribute that cannot be set, or recording it and going on, for applies
+//! run without the privileges to set ownership or some xattrs.
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::Context;
+
+use crate::file_errors::FileErrorKind;
+use crate::report::UnappliedMetaData;
+use crate::utils::{self, MetaData};
+
+pub(crate) struct MetaDataWriter {
+    /// Directory that reported paths are relative to
+    root: PathBuf,
+    best_effort: bool,
+    /// Whether to set modification and access times
+    times: bool,
+    unapplied: Mutex<Vec<UnappliedMetaData>>,
+}
+
+impl MetaDataWriter {
+    pub(crate) fn new(root: &Path, best_effort: bool, times: bool) -> Self {
+        Self { root: root.to_owned(), best_effort, times, unapplied: Mutex::new(vec![]) }
+    }
+
+    fn record(&self, path: &Path, attribute: String, err: anyhow::Error) {
+        let rel_path = path.strip_prefix(&self.root).unwrap_or(path).to_string_lossy();
+        // Staged files are reported by the name they are restored as
+        let rel_path = rel_path.trim_end_matches(".deltaimage-tmp");
+
+        tracing::warn!("Could not set {} of {}: {:#}", attribute, rel_path, err);
+        self.unapplied.lock().unwrap()
+            .push(UnappliedMetaData::new(Path::new(rel_path), attribute, format!("{:#}", err)));
+    }
+
+    pub(crate) fn set(&self, path: &Path, meta_data: MetaData) -> anyhow::Result<()> {
+        if !self.best_effort {
+            return utils::set_meta_data_with(path, meta_data, self.times, &mut |_, err| Err(err))
+                .context(FileErrorKind::MetaData);
+        }
+
+        utils::set_meta_data_with(path, meta_data, self.times, &mut |attribute, err| {
+            self.record(path, attribute, err);
+            Ok(())
+        }).context(FileErrorKind::MetaData)
+    }
+
+    pub(crate) fn set_symlink_owner(&self, path: &Path, uid: u32, gid: u32) -> anyhow::Result<()> {
+        match utils::set_symlink_owner(path, uid, gid) {
+            Err(err) if self.best_effort => {
+                self.record(path, "owner".to_owned(), err);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// The attributes that could not be set so far
+    pub(crate) fn take_unapplied(&self) -> Vec<UnappliedMetaData> {
+        std::mem::take(&mut self.unapplied.lock().unwrap())
+    }
+}
@@ -0,0 +1,226 @@
+//! Ways of storing the content of a modified file in the delta directory.
+//!
+//! Each backend turns the source and target contents of a file into the
+//! content stored in the delta directory, and back. The meta-data records the
+//! `Algo` of each stored file, from which apply finds the backend decoding it.
+//! Files above the stream threshold are always encoded in chunks by xdelta3,
+//! see `stream`, and files above the patch-from threshold by zstd, see
+//! `patch_from`.
+
+use crate::deflate::{self, Segment};
+use crate::diff::DiffOptions;
+use crate::metadata::Algo;
+use crate::patch_from;
+use crate::xdelta;
+
+/// Largest ELF file that `auto` also tries bsdiff on, as it needs many times
+/// the size of the file in memory
+const BSDIFF_TRIAL_LIMIT: usize = 16 << 20;
+
+pub(crate) trait Backend: Sync {
+    /// How files stored by this backend are recorded in the meta-data, whose
+    /// name is the one of the backend for `--algo`
+    fn algo(&self) -> Algo;
+
+    /// How a file encoded from the given contents is recorded in the meta-data
+    fn stored_algo(&self, _source: &[u8], _target: &[u8]) -> Algo {
+        self.algo()
+    }
+
+    /// Content to store for `target`, or `None` if the backend cannot encode it
+    fn encode(&self, source: &[u8], target: &[u8], options: &DiffOptions) -> Option<Vec<u8>>;
+
+    /// Restore the target content from the source content and the stored one
+    fn decode(&self, source: &[u8], stored: &[u8]) -> Option<Vec<u8>>;
+
+    /// Whether decoding needs the source content
+    fn uses_source(&self) -> bool {
+        true
+    }
+}
+
+struct XDelta3Backend;
+
+impl Backend for XDelta3Backend {
+    fn algo(&self) -> Algo {
+        Algo::XDelta3
+    }
+
+    fn encode(&self, source: &[u8], target: &[u8], options: &DiffOptions) -> Option<Vec<u8>> {
+        xdelta::encode(target, source, &options.xdelta3)
+    }
+
+    fn decode(&self, source: &[u8], stored: &[u8]) -> Option<Vec<u8>> {
+        xdelta3::decode(stored, source)
+    }
+}
+
+/// bsdiff, which does better than xdelta3 on executables, whose changes
+/// shift many addresses by the same amount. Its patches are zstd-compressed.
+struct BsDiffBackend;
+
+impl Backend for BsDiffBackend {
+    fn algo(&self) -> Algo {
+        Algo::BsDiff
+    }
+
+    fn encode(&self, source: &[u8], target: &[u8], options: &DiffOptions) -> Option<Vec<u8>> {
+        let mut patch = vec![];
+        bsdiff::diff(source, target, &mut patch).ok()?;
+        zstd::stream::encode_all(&patch[..], options.compression_level).ok()
+    }
+
+    fn decode(&self, source: &[u8], stored: &[u8]) -> Option<Vec<u8>> {
+        let patch = zstd::stream::decode_all(stored).ok()?;
+        let mut target = vec![];
+        bsdiff::patch(source, &mut &patch[..], &mut target).ok()?;
+        Some(target)
+    }
+}
+
+/// xdelta3 of gzip files and zip archives with their deflate streams
+/// inflated, see `deflate`. The layout deflating them back is stored ahead
+/// of the patch, CBOR-encoded and prefixed by its length.
+struct DeflateBackend;
+
+impl Backend for DeflateBackend {
+    fn algo(&self) -> Algo {
+        Algo::Deflate
+    }
+
+    fn encode(&self, source: &[u8], target: &[u8], options: &DiffOptions) -> Option<Vec<u8>> {
+        let (normalized, layout) = deflate::normalize(target)?;
+        let inflated_source = deflate::inflate_all(source);
+        let patch = xdelta::encode(&normalized, inflated_source.as_deref().unwrap_or(source), &options.xdelta3)?;
+
+        let mut encoded_layout = vec![];
+        ciborium::into_writer(&layout, &mut encoded_layout).ok()?;
+        let mut stored = (encoded_layout.len() as u32).to_le_bytes().to_vec();
+        stored.extend(encoded_layout);
+        stored.extend(patch);
+        Some(stored)
+    }
+
+    fn decode(&self, source: &[u8], stored: &[u8]) -> Option<Vec<u8>> {
+        let len = u32::from_le_bytes(stored.get(..4)?.try_into().ok()?) as usize;
+        let layout: Vec<Segment> = ciborium::from_reader(stored.get(4..4 + len)?).ok()?;
+        let inflated_source = deflate::inflate_all(source);
+        let normalized = xdelta3::decode(&stored[4 + len..], inflated_source.as_deref().unwrap_or(source))?;
+        deflate::denormalize(&normalized, &layout)
+    }
+}
+
+/// zstd patch-from in memory, see `patch_from` for large files
+struct ZstdPatchBackend;
+
+impl Backend for ZstdPatchBackend {
+    fn algo(&self) -> Algo {
+        Algo::ZstdPatchFrom(patch_from::WINDOW_LOG_MAX)
+    }
+
+    fn stored_algo(&self, source: &[u8], target: &[u8]) -> Algo {
+        Algo::ZstdPatchFrom(patch_from::window_log(source.len() as u64, target.len() as u64))
+    }
+
+    fn encode(&self, source: &[u8], target: &[u8], options: &DiffOptions) -> Option<Vec<u8>> {
+        patch_from::encode_all(source, target, options.compression_level).ok()
+    }
+
+    fn decode(&self, source: &[u8], stored: &[u8]) -> Option<Vec<u8>> {
+        patch_from::decode_all(source, stored).ok()
+    }
+}
+
+/// Compressed target content, for files that have nothing in common with
+/// their source
+struct ZstdBackend;
+
+impl Backend for ZstdBackend {
+    fn algo(&self) -> Algo {
+        Algo::Zstd
+    }
+
+    fn encode(&self, _source: &[u8], target: &[u8], options: &DiffOptions) -> Option<Vec<u8>> {
+        zstd::stream::encode_all(target, options.compression_level).ok()
+    }
+
+    fn decode(&self, _source: &[u8], stored: &[u8]) -> Option<Vec<u8>> {
+        zstd::stream::decode_all(stored).ok()
+    }
+
+    fn uses_source(&self) -> bool {
+        false
+    }
+}
+
+/// Target content as it is
+struct AsIsBackend;
+
+impl Backend for AsIsBackend {
+    fn algo(&self) -> Algo {
+        Algo::AsIs
+    }
+
+    fn encode(&self, _source: &[u8], target: &[u8], _options: &DiffOptions) -> Option<Vec<u8>> {
+        Some(target.to_owned())
+    }
+
+    fn decode(&self, _source: &[u8], stored: &[u8]) -> Option<Vec<u8>> {
+        Some(stored.to_owned())
+    }
+
+    fn uses_source(&self) -> bool {
+        false
+    }
+}
+
+static XDELTA3: XDelta3Backend = XDelta3Backend;
+static BSDIFF: BsDiffBackend = BsDiffBackend;
+static DEFLATE: DeflateBackend = DeflateBackend;
+static ZSTD_PATCH: ZstdPatchBackend = ZstdPatchBackend;
+static ZSTD: ZstdBackend = ZstdBackend;
+static AS_IS: AsIsBackend = AsIsBackend;
+
+pub(crate) static BACKENDS: [&dyn Backend; 6] = [&XDELTA3, &BSDIFF, &DEFLATE, &ZSTD_PATCH, &ZSTD, &AS_IS];
+
+pub(crate) fn by_name(name: &str) -> Option<&'static dyn Backend> {
+    BACKENDS.iter().copied().find(|backend| backend.algo().name() == name)
+}
+
+/// Backend decoding files stored with `algo`, for those stored whole
+pub(crate) fn for_algo(algo: &Algo) -> Option<&'static dyn Backend> {
+    match algo {
+        Algo::XDelta3 | Algo::XDelta3From(_) => Some(&XDELTA3),
+        Algo::BsDiff => Some(&BSDIFF),
+        Algo::Deflate => Some(&DEFLATE),
+        Algo::Zstd => Some(&ZSTD),
+        Algo::AsIs => Some(&AS_IS),
+        Algo::ZstdPatchFrom(_) => Some(&ZSTD_PATCH),
+        Algo::XDelta3Chunked(_) | Algo::CopyFrom(_) => None,
+    }
+}
+
+/// Patch backends to try on a modified file, keeping the smallest result
+pub(crate) fn candidates(target: &[u8], options: &DiffOptions) -> Vec<&'static dyn Backend> {
+    if let Some(backend) = options.algo.as_deref().and_then(by_name) {
+        return vec![backend];
+    }
+
+    // Heuristics of `auto`, or every backend that may do better with `optimize`
+    let mut candidates: Vec<&dyn Backend> = vec![&XDELTA3];
+    if (options.optimize || target.starts_with(b"\x7fELF")) && target.len() <= BSDIFF_TRIAL_LIMIT {
+        candidates.push(&BSDIFF);
+    }
+    if deflate::is_deflated(target) {
+        candidates.push(&DEFLATE);
+    }
+    if options.optimize {
+        candidates.extend([&ZSTD_PATCH as &dyn Backend, &ZSTD]);
+    }
+    candidates
+}
+
+/// Backends storing a file on its own, for when patches do not help
+pub(crate) fn fallbacks() -> [&'static dyn Backend; 2] {
+    [&ZSTD, &AS_IS]
+}
@@ -0,0 +1,416 @@
+//! Block-level deltas between two raw disk images or partitions, such as the
+//! images of virtual machines, for artifacts that are not directory trees.
+//!
+//! The target image is split into chunks, either of a fixed size or at
+//! content-defined boundaries, so that data shifted by insertions is still
+//! found. Chunks found in the source image are copied from it, and the others
+//! are encoded with xdelta3 against a window of the source image around where
+//! they would be, or stored compressed. All integers are little-endian:
+//!
+//! ```text
+//! header:  "DELTAIMGBLK" | u32 version | u8 chunking | u64 chunk size | u64 target length
+//! copy:    u8 0 | u64 source offset | u64 length
+//! zero:    u8 1 | u64 length
+//! patch:   u8 2 | u64 window offset | u64 window length | u64 length | xdelta3 patch
+//! raw:     u8 3 | u64 length | zstd-compressed data
+//! end:     u8 4 | SHA-256 of the target
+//! ```
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+
+use crate::Error;
+use crate::platform::FileTypeExt;
+use crate::stream::read_at;
+use crate::utils::{default_jobs, open_noatime, parallel_map, temp_path_for};
+use crate::xdelta::{self, XDelta3Params};
+
+const MAGIC: &[u8] = b"DELTAIMGBLK";
+const VERSION: u32 = 1;
+
+const RECORD_COPY: u8 = 0;
+const RECORD_ZERO: u8 = 1;
+const RECORD_PATCH: u8 = 2;
+const RECORD_RAW: u8 = 3;
+const RECORD_END: u8 = 4;
+
+/// How images are split into chunks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Chunking {
+    /// Chunks of the same size at the same offsets, for images whose blocks
+    /// stay in place, such as filesystems
+    #[default]
+    Fixed,
+    /// Chunks cut where the content matches a rolling hash, averaging the
+    /// chunk size, for images where data moves
+    ContentDefined,
+}
+
+impl FromStr for Chunking {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(Chunking::Fixed),
+            "content-defined" => Ok(Chunking::ContentDefined),
+            _ => Err(format!("unknown chunking {}", s)),
+        }
+    }
+}
+
+/// Options of block-level diffs
+#[derive(Debug, Clone)]
+pub struct BlockDiffOptions {
+    pub chunking: Chunking,
+
+    /// Size of fixed chunks, or average size of content-defined ones
+    pub chunk_size: u64,
+
+    /// Number of chunks to encode concurrently, defaulting to the number of CPUs
+    pub jobs: Option<usize>,
+
+    /// Zstd level of the chunks stored whole
+    pub compression_level: i32,
+
+    pub xdelta3: XDelta3Params,
+}
+
+impl Default for BlockDiffOptions {
+    fn default() -> Self {
+        Self {
+            chunking: Chunking::Fixed,
+            chunk_size: 1 << 20,
+            jobs: None,
+            compression_level: 3,
+            xdelta3: XDelta3Params::default(),
+        }
+    }
+}
+
+/// Byte totals of a block-level diff, by how the chunks are restored
+#[derive(Debug, Clone, Default)]
+pub struct BlockStats {
+    pub target_size: u64,
+    pub delta_size: u64,
+    pub copied: u64,
+    pub zero: u64,
+    pub patched: u64,
+    pub raw: u64,
+    pub duration: Duration,
+}
+
+enum Record {
+    Copy { offset: u64, len: u64 },
+    Zero { len: u64 },
+    Patch { window_offset: u64, window_len: u64, patch: Vec<u8> },
+    Raw { data: Vec<u8> },
+}
+
+/// Gear table of the rolling hash, from splitmix64
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Offsets and lengths of the chunks of a file
+pub(crate) fn chunks(file: &File, chunking: Chunking, chunk_size: u64) -> anyhow::Result<Vec<(u64, u64)>> {
+    let len = file.metadata()?.len();
+    let len = match len {
+        // Block devices report no length
+        0 => file.try_clone()?.seek(SeekFrom::End(0))?,
+        len => len,
+    };
+
+    if chunking == Chunking::Fixed {
+        return Ok((0..len.div_ceil(chunk_size))
+            .map(|index| (index * chunk_size, chunk_size.min(len - index * chunk_size)))
+            .collect());
+    }
+
+    let (min_size, max_size) = (chunk_size / 4, chunk_size * 4);
+    let mask = (chunk_size / 2).next_power_of_two() * 2 - 1;
+    let mut chunks = vec![];
+    let mut reader = BufReader::with_capacity(1 << 20, file.try_clone()?);
+    reader.seek(SeekFrom::Start(0))?;
+    let (mut start, mut offset, mut hash) = (0u64, 0u64, 0u64);
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        let nread = buf.len();
+        for &byte in buf {
+            offset += 1;
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            let size = offset - start;
+            if (size >= min_size && hash & mask == 0) || size >= max_size {
+                chunks.push((start, size));
+                start = offset;
+                hash = 0;
+            }
+        }
+        reader.consume(nread);
+    }
+    if offset > start {
+        chunks.push((start, offset - start));
+    }
+    Ok(chunks)
+}
+
+/// Compute the block-level delta of `target` against `source` into the new
+/// file `delta_path`
+pub fn diff_block(source_path: &Path, target_path: &Path, delta_path: &Path,
+    options: &BlockDiffOptions) -> anyhow::Result<BlockStats>
+{
+    let started = Instant::now();
+    let source = open_noatime(source_path)
+        .with_context(|| format!("failed to open {}", source_path.display()))?;
+    let target = open_noatime(target_path)
+        .with_context(|| format!("failed to open {}", target_path.display()))?;
+    let chunk_size = options.chunk_size;
+
+    let mut index = HashMap::new();
+    for (offset, len) in chunks(&source, options.chunking, chunk_size)? {
+        let digest: [u8; 32] = Sha256::digest(read_at(&source, offset, len)?).into();
+        index.entry((digest, len)).or_insert(offset);
+    }
+    let source_len = source.try_clone()?.seek(SeekFrom::End(0))?;
+    let target_chunks = chunks(&target, options.chunking, chunk_size)?;
+    let target_len = target_chunks.last().map(|(offset, len)| offset + len).unwrap_or(0);
+
+    let mut out = BufWriter::new(File::create(delta_path)
+        .with_context(|| format!("failed to create {}", delta_path.display()))?);
+    out.write_all(MAGIC)?;
+    out.write_all(&VERSION.to_le_bytes())?;
+    out.write_all(&[options.chunking as u8])?;
+    out.write_all(&chunk_size.to_le_bytes())?;
+    out.write_all(&target_len.to_le_bytes())?;
+
+    let mut stats = BlockStats { target_size: target_len, ..BlockStats::default() };
+    let mut hasher = Sha256::new();
+    let jobs = options.jobs.unwrap_or_else(default_jobs);
+    // Where the source data of the last chunk found was, relative to the target
+    let mut shift = 0i64;
+
+    for batch in target_chunks.chunks(jobs * 4) {
+        let mut records = vec![];
+        let mut pending = vec![];
+        for &(offset, len) in batch {
+            let data = read_at(&target, offset, len)?;
+            hasher.update(&data);
+            let digest: [u8; 32] = Sha256::digest(&data).into();
+            if data.iter().all(|byte| *byte == 0) {
+                records.push(Some(Record::Zero { len }));
+            } else if let Some(&source_offset) = index.get(&(digest, len)) {
+                shift = source_offset as i64 - offset as i64;
+                records.push(Some(Record::Copy { offset: source_offset, len }));
+            } else {
+                let center = (offset as i64 + shift).clamp(0, source_len as i64) as u64;
+                let window_offset = center.saturating_sub(chunk_size);
+                pending.push((records.len(), data, window_offset, len + 2 * chunk_size));
+                records.push(None);
+            }
+        }
+
+        let encoded = parallel_map(jobs, &pending, |(_, data, window_offset, window_len)| {
+            let window = read_at(&source, *window_offset, *window_len)?;
+            let raw = zstd::stream::encode_all(&data[..], options.compression_level)?;
+            let patch = xdelta::encode(data, &window, &options.xdelta3)
+                .filter(|patch| patch.len() < raw.len())
+                .filter(|patch| xdelta3::decode(patch, &window).as_deref() == Some(&data[..]));
+            Ok(match patch {
+                Some(patch) => Record::Patch { window_offset: *window_offset, window_len: window.len() as u64,
+                    patch },
+                None => Record::Raw { data: raw },
+            })
+        })?;
+        for ((slot, ..), record) in pending.iter().zip(encoded) {
+            records[*slot] = Some(record);
+        }
+
+        for (record, &(_, len)) in records.into_iter().flatten().zip(batch) {
+            stats.delta_size += write_record(&mut out, &record)?;
+            match record {
+                Record::Copy { .. } => stats.copied += len,
+                Record::Zero { .. } => stats.zero += len,
+                Record::Patch { .. } => stats.patched += len,
+                Record::Raw { .. } => stats.raw += len,
+            }
+        }
+    }
+
+    out.write_all(&[RECORD_END])?;
+    out.write_all(&hasher.finalize())?;
+    out.flush().with_context(|| format!("failed to write to {}", delta_path.display()))?;
+    stats.delta_size += MAGIC.len() as u64 + 21 + 33;
+    stats.duration = started.elapsed();
+    Ok(stats)
+}
+
+fn write_record(out: &mut impl Write, record: &Record) -> std::io::Result<u64> {
+    let mut fields = vec![];
+    let payload: &[u8] = match record {
+        Record::Copy { offset, len } => {
+            fields.extend([RECORD_COPY]);
+            fields.extend(offset.to_le_bytes());
+            fields.extend(len.to_le_bytes());
+            &[]
+        }
+        Record::Zero { len } => {
+            fields.extend([RECORD_ZERO]);
+            fields.extend(len.to_le_bytes());
+            &[]
+        }
+        Record::Patch { window_offset, window_len, patch } => {
+            fields.extend([RECORD_PATCH]);
+            fields.extend(window_offset.to_le_bytes());
+            fields.extend(window_len.to_le_bytes());
+            fields.extend((patch.len() as u64).to_le_bytes());
+            patch
+        }
+        Record::Raw { data } => {
+            fields.extend([RECORD_RAW]);
+            fields.extend((data.len() as u64).to_le_bytes());
+            data
+        }
+    };
+    out.write_all(&fields)?;
+    out.write_all(payload)?;
+    Ok((fields.len() + payload.len()) as u64)
+}
+
+struct DeltaReader<R> {
+    input: R,
+}
+
+impl<R: Read> DeltaReader<R> {
+    fn read_exact<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.input.read_exact(&mut buf).map_err(|_| Error::InvalidBlockDelta("truncated"))?;
+        Ok(buf)
+    }
+
+    fn read_u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.read_exact()?))
+    }
+
+    fn read_payload(&mut self) -> anyhow::Result<Vec<u8>> {
+        let len = self.read_u64()?;
+        let mut buf = vec![];
+        (&mut self.input).take(len).read_to_end(&mut buf)?;
+        if buf.len() as u64 != len {
+            return Err(Error::InvalidBlockDelta("truncated").into());
+        }
+        Ok(buf)
+    }
+}
+
+/// Restore the target image of a block-level delta from the source image,
+/// into `output`: a new file, or an existing block device written in place
+pub fn apply_block(source_path: &Path, delta_path: &Path, output: &Path) -> anyhow::Result<()> {
+    let device = std::fs::metadata(output).is_ok_and(|metadata| metadata.file_type().is_block_device());
+    if !device && std::fs::symlink_metadata(output).is_ok() {
+        return Err(Error::OutputImageExists(output.to_owned()).into());
+    }
+
+    let source = File::open(source_path)
+        .with_context(|| format!("failed to open {}", source_path.display()))?;
+    let mut delta = DeltaReader { input: BufReader::new(File::open(delta_path)
+        .with_context(|| format!("failed to open {}", delta_path.display()))?) };
+    if delta.read_exact::<11>()? != MAGIC {
+        return Err(Error::InvalidBlockDelta("bad magic").into());
+    }
+    if u32::from_le_bytes(delta.read_exact()?) != VERSION {
+        return Err(Error::InvalidBlockDelta("unsupported version").into());
+    }
+    let [_chunking] = delta.read_exact()?;
+    let _chunk_size = delta.read_u64()?;
+    let target_len = delta.read_u64()?;
+
+    let write_path = match device {
+        true => output.to_owned(),
+        false => temp_path_for(output),
+    };
+    let file = match device {
+        true => std::fs::OpenOptions::new().write(true).open(&write_path),
+        false => File::create(&write_path),
+    }.with_context(|| format!("failed to open {}", write_path.display()))?;
+    let mut out = BufWriter::new(file);
+    let mut hasher = Sha256::new();
+    let deflation_error = || Error::XDelta3FailedDeflation(source_path.to_owned(), delta_path.to_owned());
+
+    loop {
+        let [kind] = delta.read_exact()?;
+        let data = match kind {
+            RECORD_COPY => {
+                let offset = delta.read_u64()?;
+                let len = delta.read_u64()?;
+                let data = read_at(&source, offset, len)?;
+                if data.len() as u64 != len {
+                    return Err(Error::InvalidBlockDelta("copy past the end of the source").into());
+                }
+                data
+            }
+            RECORD_ZERO => {
+                let len = delta.read_u64()?;
+                let zeros = vec![0u8; len as usize];
+                hasher.update(&zeros);
+                if device {
+                    out.write_all(&zeros)?;
+                } else {
+                    // Left as a hole of the new file
+                    out.seek(SeekFrom::Current(len as i64))?;
+                }
+                continue;
+            }
+            RECORD_PATCH => {
+                let window_offset = delta.read_u64()?;
+                let window_len = delta.read_u64()?;
+                let patch = delta.read_payload()?;
+                let window = read_at(&source, window_offset, window_len)?;
+                xdelta3::decode(&patch, &window).ok_or_else(deflation_error)?
+            }
+            RECORD_RAW => zstd::stream::decode_all(&delta.read_payload()?[..])?,
+            RECORD_END => {
+                let digest: [u8; 32] = delta.read_exact()?;
+                let mut file = out.into_inner().map_err(|err| err.into_error())?;
+                if !device {
+                    file.set_len(target_len)?;
+                }
+                file.flush()?;
+                file.sync_all()?;
+                if hasher.finalize()[..] != digest {
+                    if !device {
+                        std::fs::remove_file(&write_path)?;
+                    }
+                    return Err(Error::BlockDigestMismatch(output.to_owned()).into());
+                }
+                if !device {
+                    std::fs::rename(&write_path, output).with_context(|| format!("failed renaming {} to {}",
+                        write_path.display(), output.display()))?;
+                }
+                return Ok(());
+            }
+            _ => return Err(Error::InvalidBlockDelta("unknown record kind").into()),
+        };
+        hasher.update(&data);
+        out.write_all(&data)?;
+    }
+}
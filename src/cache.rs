@@ -0,0 +1,148 @@
+//! Content-addressed cache of encoded files, shared between diffs.
+//!
+//! Entries are keyed by the digests of the source and target contents and
+//! by the options affecting the encoding, so that pipelines diffing similar
+//! pairs of trees over and over only encode the files that changed since.
+//! Each entry is a file holding the JSON-encoded `Algo` on a first line,
+//! followed by the stored content:
+//!
+//! ```text
+//! <cache_dir>/<first two digits of the key>/<key>
+//! ```
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::Context;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{backend, patch_from, stream};
+use crate::diff::DiffOptions;
+use crate::metadata::{Algo, META_FORMAT_VERSION};
+use crate::utils::digest_bytes;
+use crate::xdelta::XDelta3Params;
+
+/// Everything that an entry depends on
+#[derive(Serialize)]
+struct Key<'a> {
+    format_version: u32,
+    source: &'a str,
+    target: &'a str,
+    algo: &'a Option<String>,
+    optimize: bool,
+    compression_level: i32,
+    stream_threshold: u64,
+    patch_from_threshold: u64,
+    xdelta3: &'a XDelta3Params,
+}
+
+/// Distinguishes the temporary files of concurrent stores
+static STORE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Cache entry of a pair of source and target contents
+pub(crate) struct CacheEntry {
+    path: PathBuf,
+}
+
+impl CacheEntry {
+    /// The entry for the given contents, if `options` has a cache directory.
+    /// The source digest is only computed then.
+    pub(crate) fn new(options: &DiffOptions, source_digest: impl FnOnce() -> anyhow::Result<String>,
+        target_digest: &str) -> anyhow::Result<Option<Self>>
+    {
+        let Some(cache_dir) = &options.cache_dir else { return Ok(None) };
+        let key = Key {
+            format_version: META_FORMAT_VERSION,
+            source: &source_digest()?,
+            target: target_digest,
+            algo: &options.algo,
+            optimize: options.optimize,
+            compression_level: options.compression_level,
+            stream_threshold: options.stream_threshold,
+            patch_from_threshold: options.patch_from_threshold,
+            xdelta3: &options.xdelta3,
+        };
+        let key = digest_bytes(&serde_json::to_vec(&key)?);
+        Ok(Some(Self { path: cache_dir.join(&key[..2]).join(&key) }))
+    }
+
+    fn open(&self) -> Option<(Algo, BufReader<File>)> {
+        let mut reader = BufReader::new(File::open(&self.path).ok()?);
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        Some((serde_json::from_str(&line).ok()?, reader))
+    }
+
+    /// Stored content of a file that is encoded whole, if cached and still
+    /// decoding to the target content
+    pub(crate) fn load(&self, source: &[u8], target: &[u8]) -> Option<(Algo, Vec<u8>)> {
+        let (algo, mut reader) = self.open()?;
+        let mut stored = vec![];
+        reader.read_to_end(&mut stored).ok()?;
+
+        let backend = backend::for_algo(&algo)?;
+        if backend.decode(source, &stored).as_deref() != Some(target) {
+            return None;
+        }
+        Some((algo, stored))
+    }
+
+    /// Copy the stored content of a large file to `output_path`, if cached and
+    /// still decoding to the target content of the given checksum. Returns its
+    /// size.
+    pub(crate) fn load_to_file(&self, source_path: &Path, checksum: &str, output_path: &Path)
+        -> Option<(Algo, u64)>
+    {
+        let (algo, mut reader) = self.open()?;
+        let mut output = File::create(output_path).ok()?;
+        let size = std::io::copy(&mut reader, &mut output).ok()?;
+
+        let mut hasher = Sha256::new();
+        match algo {
+            Algo::XDelta3Chunked(chunk_size) =>
+                stream::decode(source_path, output_path, &mut hasher, chunk_size).ok()?,
+            Algo::ZstdPatchFrom(window_log) =>
+                patch_from::decode(source_path, output_path, &mut hasher, window_log).ok()?,
+            _ => return None,
+        };
+        if format!("{:x}", hasher.finalize()) != checksum {
+            return None;
+        }
+        Some((algo, size))
+    }
+
+    /// Store the content of `reader` in the entry, replacing any previous one
+    fn store_from(&self, algo: &Algo, reader: &mut impl Read) -> anyhow::Result<()> {
+        let parent = self.path.parent().unwrap();
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+
+        let mut tmp_name = self.path.file_name().unwrap().to_owned();
+        tmp_name.push(format!(".{}.{}.tmp", std::process::id(),
+            STORE_COUNTER.fetch_add(1, Ordering::Relaxed)));
+        let tmp_path = self.path.with_file_name(tmp_name);
+
+        let mut file = File::create(&tmp_path)
+            .with_context(|| format!("failed to create {}", tmp_path.display()))?;
+        writeln!(file, "{}", serde_json::to_string(algo)?)?;
+        std::io::copy(reader, &mut file)
+            .with_context(|| format!("failed to write to {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, &self.path)
+            .with_context(|| format!("failed to create {}", self.path.display()))?;
+
+        Ok(())
+    }
+
+    pub(crate) fn store(&self, algo: &Algo, mut stored: &[u8]) -> anyhow::Result<()> {
+        self.store_from(algo, &mut stored)
+    }
+
+    pub(crate) fn store_file(&self, algo: &Algo, path: &Path) -> anyhow::Result<()> {
+        let mut file = File::open(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        self.store_from(algo, &mut file)
+    }
+}
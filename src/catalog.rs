@@ -0,0 +1,127 @@
+//! Catalogs of the deltas available between image digests, for fleets of
+//! hosts upgrading from many different versions.
+//!
+//! A catalog is a JSON file, or a CBOR one when its name ends in `.cbor`,
+//! listing each delta with the digests of the images it goes from and to, its
+//! size, and where to fetch it from. Resolving finds the chain of deltas from
+//! one digest to another that is the cheapest to download.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::path::Path;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+use crate::utils::temp_path_for;
+
+/// A delta listed in a catalog
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    /// Digest of the image that the delta applies to
+    pub from: String,
+    /// Digest of the image that the delta restores
+    pub to: String,
+    /// Size of the delta in bytes, as downloaded
+    pub size: u64,
+    /// Where to fetch the delta from, such as a URL or an object store key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Catalog {
+    pub deltas: Vec<CatalogEntry>,
+}
+
+fn is_cbor(path: &Path) -> bool {
+    path.extension().map(|ext| ext == "cbor").unwrap_or(false)
+}
+
+impl Catalog {
+    /// Load a catalog, or start an empty one if `path` does not exist
+    pub fn load_or_default(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Self::load(path)
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let context = || format!("failed to read catalog {}", path.display());
+        let data = std::fs::read(path).with_context(context)?;
+        match is_cbor(path) {
+            true => ciborium::from_reader(&data[..]).with_context(context),
+            false => serde_json::from_slice(&data).with_context(context),
+        }
+    }
+
+    /// Atomically replace the catalog file
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let data = match is_cbor(path) {
+            true => {
+                let mut data = vec![];
+                ciborium::into_writer(self, &mut data).context("Failed to serialize data")?;
+                data
+            }
+            false => serde_json::to_vec_pretty(self)?,
+        };
+
+        let tmp_path = temp_path_for(path);
+        std::fs::write(&tmp_path, data)
+            .with_context(|| format!("Failed to write to file {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Add a delta, replacing the one listed between the same digests
+    pub fn add(&mut self, entry: CatalogEntry) {
+        self.deltas.retain(|delta| (&delta.from, &delta.to) != (&entry.from, &entry.to));
+        self.deltas.push(entry);
+    }
+
+    /// The chain of deltas to apply in turn to go from the image of digest
+    /// `from` to the one of digest `to`, smallest in total size, and then in
+    /// number of deltas
+    pub fn resolve(&self, from: &str, to: &str) -> anyhow::Result<Vec<&CatalogEntry>> {
+        let mut edges: HashMap<&str, Vec<&CatalogEntry>> = HashMap::new();
+        for delta in &self.deltas {
+            edges.entry(delta.from.as_str()).or_default().push(delta);
+        }
+
+        // Dijkstra, remembering the delta through which each digest was reached
+        let mut best: HashMap<&str, (u64, usize)> = HashMap::from([(from, (0, 0))]);
+        let mut reached_by: HashMap<&str, &CatalogEntry> = HashMap::new();
+        let mut queue = BinaryHeap::from([Reverse((0u64, 0usize, from))]);
+
+        while let Some(Reverse((size, hops, digest))) = queue.pop() {
+            if digest == to {
+                break;
+            }
+            if best.get(digest).map(|&cost| cost < (size, hops)).unwrap_or(false) {
+                continue;
+            }
+            for delta in edges.get(digest).into_iter().flatten() {
+                let cost = (size + delta.size, hops + 1);
+                if best.get(delta.to.as_str()).map(|&known| cost < known).unwrap_or(true) {
+                    best.insert(&delta.to, cost);
+                    reached_by.insert(&delta.to, delta);
+                    queue.push(Reverse((cost.0, cost.1, &delta.to)));
+                }
+            }
+        }
+
+        let mut chain = vec![];
+        let mut digest = to;
+        while digest != from {
+            let Some(delta) = reached_by.get(digest) else {
+                return Err(Error::NoDeltaChain(from.to_owned(), to.to_owned()).into());
+            };
+            chain.push(*delta);
+            digest = &delta.from;
+        }
+        chain.reverse();
+        Ok(chain)
+    }
+}
@@ -0,0 +1,206 @@
+//! Content-addressed store of the chunks of delta archives, in the manner of
+//! casync and ostree, so that hosts applying the deltas of many images that
+//! share components only fetch the chunks they do not have yet.
+//!
+//! The delta tree is packed into an archive, which is cut into content-defined
+//! chunks. Each chunk is stored once, zstd-compressed, as
+//! `<store>/chunks/<first two hex digits>/<sha256>.zst`, and the delta is
+//! described by a JSON index listing its chunks in order. A store can hold
+//! the chunks of any number of deltas, and be served over HTTP(S) as plain
+//! files.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+use crate::archive::pack_archive;
+use crate::block::{chunks, Chunking};
+use crate::fetch::{fetch_delta, is_url, FetchOptions};
+use crate::stream::read_at;
+use crate::utils::{default_jobs, deserialize_from_json, digest_bytes, digest_file, parallel_map,
+    serialize_to_json, temp_path_for};
+
+const INDEX_VERSION: u32 = 1;
+
+/// Options of exporting deltas into a chunk store
+#[derive(Debug, Clone)]
+pub struct ChunkOptions {
+    /// Average size of the chunks
+    pub chunk_size: u64,
+
+    /// Zstd level of the stored chunks
+    pub compression_level: i32,
+
+    /// Number of chunks to compress concurrently, defaulting to the number of CPUs
+    pub jobs: Option<usize>,
+}
+
+impl Default for ChunkOptions {
+    fn default() -> Self {
+        Self {
+            chunk_size: 64 << 10,
+            compression_level: 3,
+            jobs: None,
+        }
+    }
+}
+
+/// The chunks that a delta archive is made of
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChunkIndex {
+    pub version: u32,
+    /// Size of the archive
+    pub size: u64,
+    /// SHA-256 of the archive
+    pub digest: String,
+    /// SHA-256 and size of each chunk, in order
+    pub chunks: Vec<(String, u64)>,
+}
+
+impl ChunkIndex {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let index: ChunkIndex = deserialize_from_json(path)?;
+        if index.version != INDEX_VERSION {
+            return Err(Error::UnsupportedChunkIndex(index.version).into());
+        }
+        Ok(index)
+    }
+
+    /// Digests of the chunks, each listed once
+    fn unique_chunks(&self) -> Vec<&(String, u64)> {
+        let mut seen = HashSet::new();
+        self.chunks.iter().filter(|(digest, _)| seen.insert(digest.as_str())).collect()
+    }
+}
+
+/// Chunk counts of an export or a fetch
+#[derive(Debug, Clone, Default)]
+pub struct ChunkStats {
+    /// Chunks of the delta
+    pub chunks: usize,
+    /// Size of the delta archive
+    pub size: u64,
+    /// Chunks added to the store, or fetched into the local store
+    pub new_chunks: usize,
+    /// Compressed size of the new chunks
+    pub new_size: u64,
+}
+
+fn chunk_key(digest: &str) -> String {
+    format!("chunks/{}/{}.zst", &digest[..2], digest)
+}
+
+/// Export the delta tree at `delta_dir` into the chunk store `store_dir`,
+/// writing its index to `index_path`
+pub fn export_chunks(delta_dir: &Path, store_dir: &Path, index_path: &Path, options: &ChunkOptions)
+    -> anyhow::Result<ChunkStats>
+{
+    let archive = temp_path_for(index_path);
+    pack_archive(delta_dir, &archive)?;
+    let result = export_archive(&archive, store_dir, index_path, options);
+    std::fs::remove_file(&archive)?;
+    result
+}
+
+fn export_archive(archive: &Path, store_dir: &Path, index_path: &Path, options: &ChunkOptions)
+    -> anyhow::Result<ChunkStats>
+{
+    let jobs = options.jobs.unwrap_or_else(default_jobs);
+    let file = File::open(archive)?;
+    let ranges = chunks(&file, Chunking::ContentDefined, options.chunk_size)?;
+    let digests = parallel_map(jobs, &ranges, |&(offset, len)| {
+        Ok(digest_bytes(&read_at(&file, offset, len)?))
+    })?;
+
+    let index = ChunkIndex {
+        version: INDEX_VERSION,
+        size: std::fs::metadata(archive)?.len(),
+        digest: format!("sha256:{}", digest_file(archive)?),
+        chunks: digests.into_iter().zip(ranges.iter().map(|&(_, len)| len)).collect(),
+    };
+
+    // Chunks repeated within the archive are only written once
+    let mut seen = HashSet::new();
+    let new: Vec<_> = index.chunks.iter().zip(ranges.iter())
+        .filter(|((digest, _), _)| seen.insert(digest.as_str()))
+        .filter(|((digest, _), _)| !store_dir.join(chunk_key(digest)).exists())
+        .map(|((digest, _), &(offset, len))| (digest.as_str(), offset, len))
+        .collect();
+    let sizes = parallel_map(jobs, &new, |&(digest, offset, len)| {
+        let compressed = zstd::stream::encode_all(&read_at(&file, offset, len)?[..], options.compression_level)?;
+        let path = store_dir.join(chunk_key(digest));
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        // Never leave a partial chunk in place
+        let staged = temp_path_for(&path);
+        std::fs::write(&staged, &compressed)
+            .with_context(|| format!("failed to write {}", staged.display()))?;
+        std::fs::rename(&staged, &path)?;
+        Ok(compressed.len() as u64)
+    })?;
+
+    serialize_to_json(&index, index_path)?;
+    Ok(ChunkStats {
+        chunks: index.chunks.len(),
+        size: index.size,
+        new_chunks: new.len(),
+        new_size: sizes.iter().sum(),
+    })
+}
+
+/// Rebuild the delta archive of `index` at `archive`, from the chunks of the
+/// local store `local_dir`, fetching the ones it lacks from `store`: a
+/// directory, or the base URL of a store served over HTTP(S)
+pub fn assemble_chunks(index: &ChunkIndex, store: &str, local_dir: &Path, archive: &Path,
+    jobs: Option<usize>, fetch_options: &FetchOptions) -> anyhow::Result<ChunkStats>
+{
+    let missing: Vec<_> = index.unique_chunks().into_iter()
+        .filter(|(digest, _)| !local_dir.join(chunk_key(digest)).exists())
+        .collect();
+    let sizes = parallel_map(jobs.unwrap_or_else(default_jobs), &missing, |(digest, _)| {
+        let key = chunk_key(digest);
+        let path = local_dir.join(&key);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let staged = temp_path_for(&path);
+        if is_url(store) {
+            fetch_delta(&format!("{}/{}", store.trim_end_matches('/'), key), &staged, fetch_options)?;
+        } else {
+            std::fs::copy(Path::new(store).join(&key), &staged)
+                .with_context(|| format!("failed to copy chunk {} from {}", digest, store))?;
+        }
+        std::fs::rename(&staged, &path)?;
+        Ok(path.metadata()?.len())
+    })?;
+
+    let mut out = BufWriter::new(File::create(archive)
+        .with_context(|| format!("failed to create {}", archive.display()))?);
+    for (digest, len) in index.chunks.iter() {
+        let path = local_dir.join(chunk_key(digest));
+        let data = zstd::stream::decode_all(&std::fs::read(&path)?[..])
+            .with_context(|| format!("failed to decompress {}", path.display()))?;
+        if data.len() as u64 != *len || digest_bytes(&data) != *digest {
+            // Fetched again on the next attempt
+            std::fs::remove_file(&path)?;
+            return Err(Error::ChunkDigestMismatch(path).into());
+        }
+        out.write_all(&data)?;
+    }
+    out.flush().with_context(|| format!("failed to write to {}", archive.display()))?;
+    drop(out);
+
+    let digest = format!("sha256:{}", digest_file(archive)?);
+    if digest != index.digest {
+        return Err(Error::ArchiveDigestMismatch(index.digest.clone(), digest).into());
+    }
+
+    Ok(ChunkStats {
+        chunks: index.chunks.len(),
+        size: index.size,
+        new_chunks: missing.len(),
+        new_size: sizes.iter().sum(),
+    })
+}
@@ -1,16 +1,1115 @@
 use std::path::PathBuf;
+use std::str::FromStr;
 use structopt::StructOpt;
 
+use deltaimage::{Chunking, Durability, Engine, IoPriority, LayerCompression, LogFormat, MetaFormat, OnError,
+    XDelta3Secondary};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Dir,
+    Archive,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dir" => Ok(Format::Dir),
+            "archive" => Ok(Format::Archive),
+            _ => Err(format!("unknown format {}", s)),
+        }
+    }
+}
+
+/// Machine-readable summary of a diff
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SummaryFormat {
+    /// Step outputs of GitHub Actions, appended to `$GITHUB_OUTPUT`
+    Github,
+    /// `key=value` lines on stdout
+    KeyValue,
+}
+
+impl FromStr for SummaryFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "github" => Ok(SummaryFormat::Github),
+            "key-value" => Ok(SummaryFormat::KeyValue),
+            _ => Err(format!("unknown summary format {}", s)),
+        }
+    }
+}
+
+/// Container image builder that a generated Dockerfile targets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builder {
+    Docker,
+    Podman,
+    Buildah,
+    Kaniko,
+}
+
+impl FromStr for Builder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "docker" => Ok(Builder::Docker),
+            "podman" => Ok(Builder::Podman),
+            "buildah" => Ok(Builder::Buildah),
+            "kaniko" => Ok(Builder::Kaniko),
+            _ => Err(format!("unknown builder {}", s)),
+        }
+    }
+}
+
+/// Comma separated list of target platforms, such as `linux/arm64,linux/amd64`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Platforms(pub Vec<String>);
+
+impl FromStr for Platforms {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut platforms = vec![];
+
+        for platform in s.split(',') {
+            let parts: Vec<&str> = platform.split('/').collect();
+            if !(2..=3).contains(&parts.len()) || parts.iter().any(|part| part.is_empty()) {
+                return Err(format!("invalid platform {}, expected os/arch[/variant]", platform));
+            }
+            platforms.push(platform.to_owned());
+        }
+
+        Ok(Platforms(platforms))
+    }
+}
+
+impl std::fmt::Display for Platforms {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0.join(","))
+    }
+}
+
+/// Image label given as `key=value`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub key: String,
+    pub value: String,
+}
+
+impl FromStr for Label {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((key, value)) if !key.is_empty() && !value.contains('\n') => {
+                Ok(Label { key: key.to_owned(), value: value.to_owned() })
+            }
+            _ => Err(format!("invalid label {}, expected key=value", s)),
+        }
+    }
+}
+
 #[derive(Debug, StructOpt)]
 pub struct Diff {
     pub source_dir: PathBuf,
     pub target_delta_dir: PathBuf,
+
+    /// Another source tree to patch each modified file against, keeping the
+    /// smallest patch, such as the one of another common base image. Apply
+    /// needs the same extra sources.
+    #[structopt(long, number_of_values=1)]
+    pub extra_source: Vec<PathBuf>,
+
+    /// Number of files to encode concurrently (defaults to the number of CPUs)
+    #[structopt(long, short="j")]
+    pub jobs: Option<usize>,
+
+    /// Files of at least this many bytes are encoded in bounded-memory chunks
+    #[structopt(long, default_value="268435456")]
+    pub stream_threshold: u64,
+
+    /// Files of at least this many bytes are encoded with zstd patch-from
+    /// and long-distance matching instead of chunked xdelta3
+    #[structopt(long, default_value="1073741824")]
+    pub patch_from_threshold: u64,
+
+    /// Zstd level used for files that xdelta3 cannot encode and for patch-from
+    #[structopt(long, default_value="3")]
+    pub compression_level: i32,
+
+    /// xdelta3 compression level, from 0 for none to 9 for the smallest
+    /// patches (defaults to the xdelta3 default)
+    #[structopt(long, possible_values=&["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"])]
+    pub xdelta3_level: Option<u8>,
+
+    /// Secondary compression of xdelta3 patches
+    #[structopt(long, default_value="none", possible_values=&["none", "djw", "fgk"])]
+    pub xdelta3_secondary: XDelta3Secondary,
+
+    /// Size of the chunks that files above the stream threshold are encoded
+    /// in, each one against a source window three times as large
+    #[structopt(long, default_value="16777216")]
+    pub xdelta3_window: u64,
+
+    /// Delta algorithm for modified files, or `auto` to try xdelta3, bsdiff
+    /// on executables and deflate on gzip and zip files, keeping the
+    /// smallest patch
+    #[structopt(long, default_value="auto",
+        possible_values=&["auto", "xdelta3", "bsdiff", "deflate", "zstd-patch-from", "zstd", "as-is"])]
+    pub algo: String,
+
+    /// Store modified files smaller than this many bytes compressed on their
+    /// own, without trying to patch them
+    #[structopt(long, default_value="0")]
+    pub min_file_size: u64,
+
+    /// Store modified files compressed on their own unless patching saves at
+    /// least this fraction of their size, such as 0.1 for 10%
+    #[structopt(long, default_value="0")]
+    pub min_delta_ratio: f64,
+
+    /// With `--algo auto`, try all of xdelta3, bsdiff, zstd patch-from and
+    /// plain zstd on each modified file in parallel, keeping the smallest
+    #[structopt(long)]
+    pub optimize: bool,
+
+    /// Cache of encoded files, reused by later diffs of the same pairs of
+    /// files with the same options
+    #[structopt(long)]
+    pub cache_dir: Option<PathBuf>,
+
+    /// Sign the meta-data with the secret key in this file, made by keygen
+    #[structopt(long)]
+    pub sign_key: Option<PathBuf>,
+
+    /// Encrypt the stored files with the key in this file, made by
+    /// encryption-keygen
+    #[structopt(long, conflicts_with("from-tar"))]
+    pub encrypt_key: Option<PathBuf>,
+
+    /// What to do with files that cannot be read: fail, leave them out of the
+    /// delta for apply to take them from the source, or leave them whole in it
+    #[structopt(long, default_value="fail", possible_values=&["fail", "skip", "as-is"])]
+    pub on_error: OnError,
+
+    /// Delta format: an in-place directory tree, or also a single archive file
+    #[structopt(long, default_value="dir", possible_values=&["dir", "archive"])]
+    pub format: Format,
+
+    /// Path of the archive file to write, with `--format archive`
+    #[structopt(long, required_if("format", "archive"))]
+    pub archive: Option<PathBuf>,
+
+    /// Write a JSON report of how each file was handled to this path
+    #[structopt(long)]
+    pub report: Option<PathBuf>,
+
+    /// Print the sizes, ratios and stage timings of the delta as `key=value`
+    /// lines, or as the step outputs of GitHub Actions
+    #[structopt(long, possible_values=&["github", "key-value"])]
+    pub summary_format: Option<SummaryFormat>,
+
+    /// Exit with 2 if the trees have the same files, and with 3 if the delta
+    /// is fallback-heavy, rather than with 0
+    #[structopt(long)]
+    pub exit_code: bool,
+
+    /// Fraction of the modified content stored whole rather than patched
+    /// above which the delta is fallback-heavy
+    #[structopt(long, default_value="0.5")]
+    pub fallback_threshold: f64,
+
+    /// Do not look for new files that were moved from elsewhere in the source
+    #[structopt(long)]
+    pub no_detect_renames: bool,
+
+    /// Do not encode other new files against similar source files
+    #[structopt(long)]
+    pub no_pair_similar: bool,
+
+    /// Do not hash the files of both trees in a pre-pass to keep the
+    /// unchanged ones quickly, comparing each file in full instead
+    #[structopt(long)]
+    pub no_prehash: bool,
+
+    /// Keep the files with the same size and modification time in both trees
+    /// without reading them. Changes that preserve both go unnoticed.
+    #[structopt(long)]
+    pub trust_mtime: bool,
+
+    /// Store files with identical content that are not hardlinked once, and
+    /// restore them as hardlinks
+    #[structopt(long)]
+    pub dedup_identical: bool,
+
+    /// Store new files zstd-compressed when it makes them smaller, rather
+    /// than as they are
+    #[structopt(long)]
+    pub compress_new_files: bool,
+
+    /// Leave the placeholders of unmodified files out of the delta, recording
+    /// their meta-data instead
+    #[structopt(long)]
+    pub drop_placeholders: bool,
+
+    /// Leave modification and access times out of the delta, for apply not
+    /// to restore them
+    #[structopt(long)]
+    pub ignore_times: bool,
+
+    /// Leave ownership out of the delta, for apply not to restore it
+    #[structopt(long)]
+    pub ignore_owner: bool,
+
+    /// Give up after this many files failed, or 0 to go through all of them,
+    /// reporting the failures together
+    #[structopt(long, default_value="0")]
+    pub max_errors: usize,
+
+    /// Leave paths matching this glob, such as `/var/log/*`, out of the delta,
+    /// keeping whatever the source provides for them on apply
+    #[structopt(long, number_of_values=1)]
+    pub exclude: Vec<String>,
+
+    /// Keep paths matching this glob in the delta even if excluded
+    #[structopt(long, number_of_values=1)]
+    pub include: Vec<String>,
+
+    /// Configuration of the source image, such as printed by `skopeo inspect
+    /// --config`, to record the differences with the target one in the delta
+    #[structopt(long, requires("target-config"))]
+    pub source_config: Option<PathBuf>,
+
+    /// Configuration of the target image, with `--source-config`
+    #[structopt(long, requires("source-config"))]
+    pub target_config: Option<PathBuf>,
+
+    /// Only print how each file would be handled, leaving the target
+    /// directory untouched
+    #[structopt(long, conflicts_with("from-registry"))]
+    pub dry_run: bool,
+
+    /// Diff a target tree that holds the meta-data of a delta already,
+    /// overwriting it
+    #[structopt(long)]
+    pub force: bool,
+
+    /// Fail on the journal of an interrupted diff in the target tree instead
+    /// of resuming it, as generated Dockerfiles do
+    #[structopt(long)]
+    pub fresh: bool,
+
+    /// Write the delta tree to this new directory instead of rewriting
+    /// `target_delta_dir` in place
+    #[structopt(long, conflicts_with("from-registry"))]
+    pub output: Option<PathBuf>,
+
+    /// Also embed the reverse delta, for `apply --reverse` to restore the
+    /// source tree from the target tree
+    #[structopt(long)]
+    pub bidirectional: bool,
+
+    /// Pull the two images from registries, taking the two paths as image
+    /// references, and push the delta image with `--push`
+    #[structopt(long, requires("push"))]
+    pub from_registry: bool,
+
+    /// Take the two paths as tarballs, such as layers of `docker save`, and
+    /// write a tar delta to `--output` without extracting them
+    #[structopt(long, requires("output"), conflicts_with("from-registry"))]
+    pub from_tar: bool,
+
+    /// Take the two paths as IDs of containers of `--engine`, and write the
+    /// delta of their root filesystems to `--output`
+    #[structopt(long, alias="container", requires("output"),
+        conflicts_with_all(&["from-registry", "from-tar"]))]
+    pub from_container: bool,
+
+    /// Container engine running the containers, with `--from-container`
+    #[structopt(long, default_value="docker", possible_values=&["docker", "podman", "containerd"])]
+    pub engine: Engine,
+
+    /// Reference of the delta image to push, with `--from-registry`
+    #[structopt(long)]
+    pub push: Option<String>,
+
+    /// Talk plain HTTP to registries
+    #[structopt(long)]
+    pub insecure_registry: bool,
+
+    /// Encoding of the meta-data file, `json` keeping it readable
+    #[structopt(long, default_value="cbor-zstd", possible_values=&["cbor-zstd", "cbor", "json"])]
+    pub meta_format: MetaFormat,
 }
 
 #[derive(Debug, StructOpt)]
 pub struct Apply {
     pub source_dir: PathBuf,
+
+    /// Delta directories to apply in sequence, each one against the tree
+    /// restored by the previous one
+    #[structopt(required=true)]
+    pub delta_target_dirs: Vec<PathBuf>,
+
+    /// Extra source tree given to diff, in the same order
+    #[structopt(long, number_of_values=1)]
+    pub extra_source: Vec<PathBuf>,
+
+    /// Number of files to restore concurrently (defaults to the number of CPUs)
+    #[structopt(long, short="j")]
+    pub jobs: Option<usize>,
+
+    /// Delta format: an in-place directory tree, or a single archive file
+    /// unpacked into the first delta directory first
+    #[structopt(long, default_value="dir", possible_values=&["dir", "archive"])]
+    pub format: Format,
+
+    /// Path or HTTP(S) URL of the archive file to read, with `--format archive`
+    #[structopt(long, required_if("format", "archive"))]
+    pub archive: Option<PathBuf>,
+
+    /// Write a JSON report of how each file was handled to this path
+    #[structopt(long)]
+    pub report: Option<PathBuf>,
+
+    /// Restore paths matching this glob, such as `/usr/bin/myservice`, first,
+    /// ahead of the order recorded in the delta
+    #[structopt(long="priority-glob", number_of_values=1)]
+    pub priority: Vec<String>,
+
+    /// Limit the rate at which restored files are written, in MB/s
+    #[structopt(long, conflicts_with("from-tar"))]
+    pub io_limit: Option<f64>,
+
+    /// Run at this niceness, such as 19 for the lowest CPU priority
+    #[structopt(long, allow_hyphen_values=true)]
+    pub nice: Option<i32>,
+
+    /// I/O scheduling class to run in: `idle`, or `best-effort` with an
+    /// optional level from 0 to 7, such as `best-effort:7`
+    #[structopt(long)]
+    pub ionice: Option<IoPriority>,
+
+    /// Restore the source tree instead, taking the target tree as
+    /// `source_dir`, from a delta made with `diff --bidirectional`
+    #[structopt(long)]
+    pub reverse: bool,
+
+    /// Undo an interrupted apply instead, putting the delta directory back as
+    /// it was. Running apply again resumes it.
+    #[structopt(long)]
+    pub rollback: bool,
+
+    /// Refuse deltas not signed by the secret key matching the public key in
+    /// this file, before anything is patched
+    #[structopt(long)]
+    pub verify_key: Option<PathBuf>,
+
+    /// Decrypt the stored files of an encrypted delta with the key in this file
+    #[structopt(long, conflicts_with("from-tar"))]
+    pub decrypt_key: Option<PathBuf>,
+
+    /// Take the source and the delta as a tarball and a tar delta made by
+    /// `diff --from-tar`, and write the restored tarball to `--output`
+    #[structopt(long, requires("output"))]
+    pub from_tar: bool,
+
+    /// Path of the restored tarball, with `--from-tar`
+    #[structopt(long)]
+    pub output: Option<PathBuf>,
+
+    /// Leave in the delta directory only the paths that differ from the
+    /// source tree, moved into this overlayfs upper directory, with
+    /// whiteouts for the deleted ones, keeping the source tree as the lower
+    /// directory
+    #[structopt(long, conflicts_with_all(&["from-tar", "reverse", "rollback"]))]
+    pub overlay_upperdir: Option<PathBuf>,
+
+    /// Clone the source tree, the root of a btrfs subvolume or of a ZFS
+    /// dataset, into this directory, and apply the delta on the clone,
+    /// dropping it if the apply fails
+    #[structopt(long, conflicts_with_all(&["from-tar", "reverse", "rollback", "overlay-upperdir"]))]
+    pub snapshot: Option<PathBuf>,
+
+    /// What to flush to disk: nothing but the journal, for trees thrown
+    /// away on a crash, the directories holding the restored paths, or also
+    /// the restored files, for crash-consistent updates
+    #[structopt(long, default_value="full", possible_values=&["none", "dir", "full"])]
+    pub durability: Durability,
+
+    /// Set the numeric owners and groups stored in the delta as they are, the
+    /// default
+    #[structopt(long, conflicts_with_all(&["owner-map", "skip-chown"]))]
+    pub numeric_owner: bool,
+
+    /// Translate the owners and groups stored in the delta through this
+    /// ID-mapping table, or through the ranges of the current user in
+    /// /etc/subuid and /etc/subgid if given as `subuid`
+    #[structopt(long, conflicts_with("skip-chown"))]
+    pub owner_map: Option<PathBuf>,
+
+    /// Never change the ownership of restored files
+    #[structopt(long)]
+    pub skip_chown: bool,
+
+    /// Leave the modification and access times of restored files and
+    /// directories as apply makes them
+    #[structopt(long)]
+    pub ignore_times: bool,
+
+    /// Go on when the ownership, permissions or xattrs of a file cannot be
+    /// set, such as when not running as root, listing them in the report
+    #[structopt(long)]
+    pub best_effort_metadata: bool,
+
+    /// Give up after this many files failed to be restored, or 0 to go
+    /// through all of them, reporting the failures together
+    #[structopt(long, default_value="0")]
+    pub max_errors: usize,
+
+    /// Expected digest of a delta given as a URL, such as `sha256:...`
+    #[structopt(long)]
+    pub delta_digest: Option<String>,
+
+    /// Number of times a failed download of a delta given as a URL is resumed
+    #[structopt(long, default_value="5")]
+    pub fetch_retries: u32,
+
+    /// Configuration of the source image, such as printed by `skopeo inspect
+    /// --config`, to reconstruct the one of the target image from
+    #[structopt(long)]
+    pub source_config: Option<PathBuf>,
+
+    /// Write the reconstructed configuration of the target image to this path
+    #[structopt(long, requires("source-config"))]
+    pub emit_config: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Estimate {
+    pub source_dir: PathBuf,
+    pub target_dir: PathBuf,
+
+    /// Number of files to read concurrently (defaults to the number of CPUs)
+    #[structopt(long, short="j")]
+    pub jobs: Option<usize>,
+
+    /// Leave out paths matching this glob, as diff does
+    #[structopt(long, number_of_values=1)]
+    pub exclude: Vec<String>,
+
+    /// Keep paths matching this glob even if excluded
+    #[structopt(long, number_of_values=1)]
+    pub include: Vec<String>,
+
+    /// Print the estimate as JSON
+    #[structopt(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Watch {
+    pub source_dir: PathBuf,
+    pub target_dir: PathBuf,
+
+    /// Delta directory to keep up to date, rebuilt from scratch on start
+    #[structopt(long, short="o")]
+    pub output: PathBuf,
+
+    /// Number of files to encode concurrently (defaults to the number of CPUs)
+    #[structopt(long, short="j")]
+    pub jobs: Option<usize>,
+
+    /// Leave out paths matching this glob, as diff does
+    #[structopt(long, number_of_values=1)]
+    pub exclude: Vec<String>,
+
+    /// Keep paths matching this glob even if excluded
+    #[structopt(long, number_of_values=1)]
+    pub include: Vec<String>,
+
+    /// Milliseconds without changes to wait for before updating the delta
+    #[structopt(long, default_value="300")]
+    pub debounce_ms: u64,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Verify {
+    pub source_dir: PathBuf,
+    pub delta_dir: PathBuf,
+
+    /// Extra source tree given to diff, in the same order
+    #[structopt(long, number_of_values=1)]
+    pub extra_source: Vec<PathBuf>,
+
+    /// Number of files to decode concurrently (defaults to the number of CPUs)
+    #[structopt(long, short="j")]
+    pub jobs: Option<usize>,
+
+    /// Decrypt the stored files of an encrypted delta with the key in this file
+    #[structopt(long)]
+    pub decrypt_key: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Squash {
+    /// Tree that the first delta was computed against
+    pub source_dir: PathBuf,
+    pub first_delta_dir: PathBuf,
+    pub second_delta_dir: PathBuf,
+    /// New directory for the merged delta
+    pub output_dir: PathBuf,
+
+    /// Encoding of the meta-data file, `json` keeping it readable
+    #[structopt(long, default_value="cbor-zstd", possible_values=&["cbor-zstd", "cbor", "json"])]
+    pub meta_format: MetaFormat,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Stats {
+    pub delta_dir: PathBuf,
+
+    /// Number of largest stored files to list
+    #[structopt(long, default_value="10")]
+    pub top: usize,
+
+    /// SBOM of the source image, an SPDX or CycloneDX JSON document or the
+    /// JSON output of syft, to list the packages that changed
+    #[structopt(long, requires("target-sbom"))]
+    pub source_sbom: Option<PathBuf>,
+
+    /// SBOM of the target image, whose packages the stored files are
+    /// attributed to
+    #[structopt(long, requires("source-sbom"))]
+    pub target_sbom: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct List {
+    pub delta_dir: PathBuf,
+
+    /// Print the listing as JSON
+    #[structopt(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Compare {
+    /// Earlier delta directory, such as the one of the last release
+    pub old_delta_dir: PathBuf,
+    /// Later delta directory
+    pub new_delta_dir: PathBuf,
+
+    /// Report paths whose delta size grew or shrank by at least this factor
+    #[structopt(long, default_value="2")]
+    pub factor: f64,
+
+    /// Leave out paths smaller than this in both deltas, in bytes
+    #[structopt(long, default_value="1048576")]
+    pub min_size: u64,
+
+    /// Print the comparison as JSON
+    #[structopt(long)]
+    pub json: bool,
+
+    /// Fail if any path is reported, such as to catch bloat in CI
+    #[structopt(long)]
+    pub check: bool,
+}
+
+#[cfg(feature = "tui")]
+#[derive(Debug, StructOpt)]
+pub struct Tui {
+    pub delta_dir: PathBuf,
+
+    /// Tree that the delta was computed against, to show how the meta-data
+    /// of each path changed
+    #[structopt(long)]
+    pub source: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Digest {
+    pub dir: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Keygen {
+    /// File to write the secret key to, given to `diff --sign-key`
+    pub secret_key: PathBuf,
+    /// File to write the public key to, given to `apply --verify-key`
+    pub public_key: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct EncryptionKeygen {
+    /// File to write the key to, given to `diff --encrypt-key` and
+    /// `apply --decrypt-key`
+    pub key: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct RecordOrder {
+    pub delta_dir: PathBuf,
+
+    /// Report of a trial apply of the delta, written by `apply --report`
+    #[structopt(long)]
+    pub report: PathBuf,
+
+    /// Sign the meta-data again with the secret key in this file, for signed
+    /// deltas
+    #[structopt(long)]
+    pub sign_key: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct MigrateMeta {
+    pub delta_dir: PathBuf,
+
+    /// Also convert the meta-data file to this encoding
+    #[structopt(long, possible_values=&["cbor-zstd", "cbor", "json"])]
+    pub meta_format: Option<MetaFormat>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct DiffOci {
+    /// OCI image layout directory or tarball of the source image
+    pub source_image: PathBuf,
+    /// OCI image layout directory or tarball of the target image
+    pub target_image: PathBuf,
+    /// Path of the delta image to write, as a tarball if it ends with `.tar`
+    pub output_image: PathBuf,
+
+    /// Number of files to encode concurrently (defaults to the number of CPUs)
+    #[structopt(long, short="j")]
+    pub jobs: Option<usize>,
+
+    /// Encode each layer of the target image on its own, so that apply-oci
+    /// restores the image with the same layers and diff IDs
+    #[structopt(long)]
+    pub per_layer: bool,
+
+    /// Compression of the delta layers: `gzip`, `zstd`, `none`, or `estargz`
+    /// and `zstd:chunked` for lazy pulling, with the level after a colon,
+    /// such as `gzip:9` or `zstd:19`
+    #[structopt(long, default_value="gzip")]
+    pub layer_compression: LayerCompression,
+
+    /// Split the delta into layers of about this many bytes, for registries
+    /// limiting the size of a blob
+    #[structopt(long)]
+    pub layer_size: Option<u64>,
+
+    /// Scratch directory for the unpacked images, which must not exist
+    #[structopt(long)]
+    pub work_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct DiffBlock {
+    /// Raw disk image or partition of the source
+    pub source: PathBuf,
+    /// Raw disk image or partition of the target
+    pub target: PathBuf,
+    /// Path of the block delta to write
+    pub output: PathBuf,
+
+    /// Split the images into chunks of the same size, or at content-defined
+    /// boundaries, finding data that moved
+    #[structopt(long, default_value="fixed", possible_values=&["fixed", "content-defined"])]
+    pub chunking: Chunking,
+
+    /// Size of the chunks, or their average size when content-defined
+    #[structopt(long, default_value="1048576")]
+    pub chunk_size: u64,
+
+    /// Number of chunks to encode concurrently (defaults to the number of CPUs)
+    #[structopt(long, short="j")]
+    pub jobs: Option<usize>,
+
+    /// Zstd level of the chunks stored whole
+    #[structopt(long, default_value="3")]
+    pub compression_level: i32,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ApplyBlock {
+    /// Raw disk image or partition of the source
+    pub source: PathBuf,
+    /// Block delta made by diff-block
+    pub delta: PathBuf,
+    /// Path of the image to write, or block device to write it to
+    pub output: PathBuf,
+}
+
+#[cfg(feature = "fs-image")]
+#[derive(Debug, StructOpt)]
+pub struct DiffFsImage {
+    /// squashfs or EROFS image of the source tree
+    pub source_image: PathBuf,
+    /// squashfs or EROFS image of the target tree
+    pub target_image: PathBuf,
+    /// Delta directory to write
+    pub output: PathBuf,
+
+    /// Number of files to encode concurrently (defaults to the number of CPUs)
+    #[structopt(long, short="j")]
+    pub jobs: Option<usize>,
+
+    /// Loop-mount the images read-only instead of unpacking them, which needs root
+    #[structopt(long)]
+    pub mount: bool,
+
+    /// Option of mksquashfs or mkfs.erofs to make the target image again
+    /// with, beyond those read from its superblock, such as `-Xdict-size=100%`
+    #[structopt(long, allow_hyphen_values=true, number_of_values=1)]
+    pub mkfs_arg: Vec<String>,
+
+    /// Sign the meta-data with the secret key in this file, made by keygen
+    #[structopt(long)]
+    pub sign_key: Option<PathBuf>,
+
+    /// Encoding of the meta-data file, `json` keeping it readable
+    #[structopt(long, default_value="cbor-zstd", possible_values=&["cbor-zstd", "cbor", "json"])]
+    pub meta_format: MetaFormat,
+
+    /// Scratch directory for the unpacked images, which must not exist
+    #[structopt(long)]
+    pub work_dir: Option<PathBuf>,
+}
+
+#[cfg(feature = "fs-image")]
+#[derive(Debug, StructOpt)]
+pub struct ApplyFsImage {
+    /// squashfs or EROFS image of the source tree
+    pub source_image: PathBuf,
+    /// Delta directory made by diff-fs-image, restored into the target tree
+    /// in place
+    pub delta_dir: PathBuf,
+    /// Path of the target image to write
+    pub output_image: PathBuf,
+
+    /// Number of files to restore concurrently (defaults to the number of CPUs)
+    #[structopt(long, short="j")]
+    pub jobs: Option<usize>,
+
+    /// Loop-mount the source image read-only instead of unpacking it, which
+    /// needs root
+    #[structopt(long)]
+    pub mount: bool,
+
+    /// Refuse the delta unless its meta-data is signed by the secret key
+    /// matching the public key in this file
+    #[structopt(long)]
+    pub verify_key: Option<PathBuf>,
+
+    /// Scratch directory for the unpacked image, which must not exist
+    #[structopt(long)]
+    pub work_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ApplyOci {
+    /// OCI image layout directory or tarball of the delta image
+    pub delta_image: PathBuf,
+    /// Path of the restored image to write, as a tarball if it ends with `.tar`
+    pub output_image: PathBuf,
+
+    /// Keep the layers of the source image in the restored image, adding one
+    /// layer of the changes on top
+    #[structopt(long)]
+    pub layered: bool,
+
+    /// Compression of the restored layers: `gzip`, `zstd`, `none`, `estargz`
+    /// or `zstd:chunked`, with the level after a colon. Layers kept from the
+    /// source image stay as they are.
+    #[structopt(long, default_value="gzip")]
+    pub layer_compression: LayerCompression,
+
+    /// Scratch directory for the unpacked images, which must not exist
+    #[structopt(long)]
+    pub work_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SaveDelta {
+    /// Image of the local store that the receiving host already has
+    pub source_image: String,
+    /// Image of the local store to transfer
+    pub target_image: String,
+
+    /// Path of the delta tarball to write
+    #[structopt(long, short="o")]
+    pub output: PathBuf,
+
+    /// Container engine holding the images
+    #[structopt(long, default_value="docker", possible_values=&["docker", "podman", "containerd"])]
+    pub engine: Engine,
+
+    /// Number of files to encode concurrently (defaults to the number of CPUs)
+    #[structopt(long, short="j")]
+    pub jobs: Option<usize>,
+
+    /// Scratch directory for the exported images, which must not exist
+    #[structopt(long)]
+    pub work_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct LoadDelta {
+    /// Delta tarball written by save-delta
+    pub delta: PathBuf,
+
+    /// Name to give the restored image instead of the one of the target image
+    #[structopt(long)]
+    pub tag: Option<String>,
+
+    /// Keep the layers of the source image in the restored image, adding one
+    /// layer of the changes on top
+    #[structopt(long)]
+    pub layered: bool,
+
+    /// Container engine holding the source image, into which the restored
+    /// image is loaded
+    #[structopt(long, default_value="docker", possible_values=&["docker", "podman", "containerd"])]
+    pub engine: Engine,
+
+    /// Scratch directory for the exported images, which must not exist
+    #[structopt(long)]
+    pub work_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ContainerdSnapshot {
+    /// Source image of the delta, unpacked in containerd
+    pub source_image: String,
+
+    /// Delta directory or archive, or URL to download it from
+    pub delta: PathBuf,
+
+    /// Name of the snapshot to commit the restored tree as
+    pub name: String,
+
+    /// Snapshotter holding the snapshot of the source image
+    #[structopt(long, default_value="overlayfs")]
+    pub snapshotter: String,
+
+    /// Number of files to restore concurrently (defaults to the number of CPUs)
+    #[structopt(long, short="j")]
+    pub jobs: Option<usize>,
+
+    /// Refuse the delta unless its meta-data is signed by the secret key
+    /// matching the public key in this file
+    #[structopt(long)]
+    pub verify_key: Option<PathBuf>,
+
+    /// Decrypt the stored files of an encrypted delta with the key in this file
+    #[structopt(long)]
+    pub decrypt_key: Option<PathBuf>,
+
+    /// Expected digest of a delta given as a URL, such as `sha256:...`
+    #[structopt(long)]
+    pub delta_digest: Option<String>,
+
+    /// Scratch directory for mounting the snapshots, which must not exist
+    #[structopt(long)]
+    pub work_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Package {
+    /// Delta directory to pack
+    pub delta_dir: PathBuf,
+
+    /// Path of the archive or executable to write
+    #[structopt(long, short="o")]
+    pub output: PathBuf,
+
+    /// Write an executable shell script carrying deltaimage itself, which
+    /// applies the delta on hosts without deltaimage installed
+    #[structopt(long)]
+    pub self_extracting: bool,
+
+    /// deltaimage executable to carry, such as a static build for the
+    /// architecture of the receiving hosts (defaults to this one)
+    #[structopt(long, requires("self-extracting"))]
+    pub binary: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Push {
+    /// Object store to publish to, such as `s3://bucket/deltas`
+    pub store: String,
+
+    /// Delta archive to publish
+    pub archive: PathBuf,
+
+    /// Digest of the image that the delta applies to
+    #[structopt(long)]
+    pub from: String,
+
+    /// Digest of the image that the delta restores
+    #[structopt(long)]
+    pub to: String,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Pull {
+    /// Object store to fetch from, such as `s3://bucket/deltas`
+    pub store: String,
+
+    /// Digest of the image to apply the delta to
+    #[structopt(long)]
+    pub from: String,
+
+    /// Digest of the image to restore
+    #[structopt(long)]
+    pub to: String,
+
+    /// Path of the delta archive to write
+    #[structopt(long, short="o")]
+    pub output: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ExportChunks {
+    pub delta_dir: PathBuf,
+
+    /// Chunk store directory to add the chunks of the delta to, created if
+    /// missing
+    pub store: PathBuf,
+
+    /// Path of the index of the delta to write, given to apply-chunks
+    #[structopt(long, short="o")]
+    pub index: PathBuf,
+
+    /// Average size of the chunks
+    #[structopt(long, default_value="65536")]
+    pub chunk_size: u64,
+
+    /// Zstd level of the stored chunks
+    #[structopt(long, default_value="3")]
+    pub compression_level: i32,
+
+    /// Number of chunks to compress concurrently (defaults to the number of CPUs)
+    #[structopt(long, short="j")]
+    pub jobs: Option<usize>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ApplyChunks {
+    pub source_dir: PathBuf,
+    /// Index written by export-chunks, or a URL to download it from
+    pub index: PathBuf,
+    /// Path to restore the target tree at, which must not exist
     pub delta_target_dir: PathBuf,
+
+    /// Chunk store directory, or the URL of one served over HTTP(S)
+    #[structopt(long)]
+    pub store: String,
+
+    /// Local chunk store to keep fetched chunks in, so that they are not
+    /// fetched again for later deltas (defaults to the store, if a directory)
+    #[structopt(long)]
+    pub local_store: Option<PathBuf>,
+
+    /// Number of chunks to fetch and files to restore concurrently (defaults
+    /// to the number of CPUs)
+    #[structopt(long, short="j")]
+    pub jobs: Option<usize>,
+
+    /// Number of times a failed download of a chunk is resumed
+    #[structopt(long, default_value="5")]
+    pub fetch_retries: u32,
+
+    /// Refuse the delta unless its meta-data is signed by the secret key
+    /// matching the public key in this file
+    #[structopt(long)]
+    pub verify_key: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct CatalogAdd {
+    /// Catalog file to update, created if missing, in CBOR if named `*.cbor`
+    /// and JSON otherwise
+    pub catalog: PathBuf,
+
+    /// Delta archive or tar delta to list
+    pub delta: PathBuf,
+
+    /// Digest of the image that the delta applies to
+    #[structopt(long)]
+    pub from: String,
+
+    /// Digest of the image that the delta restores
+    #[structopt(long)]
+    pub to: String,
+
+    /// Where to fetch the delta from, such as its URL
+    #[structopt(long)]
+    pub location: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Resolve {
+    /// Catalog file to resolve from
+    pub catalog: PathBuf,
+
+    /// Digest of the image to upgrade from
+    #[structopt(long)]
+    pub from: String,
+
+    /// Digest of the image to upgrade to
+    #[structopt(long)]
+    pub to: String,
+
+    /// Print the chain of deltas as JSON
+    #[structopt(long)]
+    pub json: bool,
+}
+
+/// Where and how a generated Dockerfile is written
+#[derive(Debug, Clone, StructOpt)]
+pub struct DockerFileOutput {
+    /// Write the Dockerfile to this path instead of stdout
+    #[structopt(long, short="o")]
+    pub output: Option<PathBuf>,
+
+    /// Render this template instead, substituting `{{dockerfile}}`, `{{labels}}`,
+    /// `{{config}}`, `{{version}}`, `{{deltaimage}}` and the image arguments
+    /// such as `{{image_a}}`
+    #[structopt(long)]
+    pub template: Option<PathBuf>,
+
+    /// Add a label to the resulting image, as `key=value`
+    #[structopt(long="label")]
+    pub labels: Vec<Label>,
+
+    /// Refer to the deltaimage image by the digest of its version, resolved
+    /// from the registry, so that builds are not affected by the tag moving
+    #[structopt(long)]
+    pub pin_digest: bool,
+
+    /// Refer to the deltaimage image by this digest, such as `sha256:...`,
+    /// rather than resolving it
+    #[structopt(long)]
+    pub deltaimage_digest: Option<String>,
+
+    /// Start the Dockerfile with a `# syntax=` directive for this BuildKit
+    /// frontend, such as `docker/dockerfile:1`
+    #[structopt(long)]
+    pub syntax: Option<String>,
+
+    /// Have diff or apply place the meta-data file of the delta at this
+    /// path, as with `--meta-path`
+    #[structopt(long)]
+    pub delta_meta_path: Option<PathBuf>,
 }
 
 #[derive(Debug, StructOpt)]
@@ -21,27 +1120,188 @@ pub enum DockerFile {
 
         #[structopt(long)]
         override_version: Option<String>,
+
+        /// Image builder to emit a Dockerfile for
+        #[structopt(long, default_value="docker", possible_values=&["docker", "podman", "buildah", "kaniko"])]
+        builder: Builder,
+
+        /// Emit a multi-arch Dockerfile for these platforms, to be built with buildx
+        #[structopt(long)]
+        platform: Option<Platforms>,
+
+        #[structopt(flatten)]
+        output: DockerFileOutput,
     },
     Apply {
         delta_image: String,
 
         #[structopt(long)]
         override_version: Option<String>,
+
+        /// Give the resulting image the entrypoint, command, environment,
+        /// labels and user of this image, such as image B, fetched from its
+        /// registry
+        #[structopt(long)]
+        config_from: Option<String>,
+
+        /// As `--config-from`, with the configuration read from this file, as
+        /// printed by `docker inspect` or `skopeo inspect --config`
+        #[structopt(long, conflicts_with("config-from"))]
+        config_file: Option<PathBuf>,
+
+        /// Image builder to emit a Dockerfile for
+        #[structopt(long, default_value="docker", possible_values=&["docker", "podman", "buildah", "kaniko"])]
+        builder: Builder,
+
+        /// Emit a multi-arch Dockerfile for these platforms, to be built with buildx
+        #[structopt(long)]
+        platform: Option<Platforms>,
+
+        #[structopt(flatten)]
+        output: DockerFileOutput,
     },
+    /// Generate the Dockerfile described by a build spec, such as
+    /// `deltaimage.yaml`, naming either the images to diff or the delta image
+    Spec {
+        spec: PathBuf,
+
+        #[structopt(flatten)]
+        output: DockerFileOutput,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Fsck {
+    pub delta_dir: PathBuf,
+
+    /// Repair the problems that can be without changing what apply restores
+    #[structopt(long)]
+    pub fix: bool,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct SelfTest {
+    /// Scratch directory to try the capabilities in, which must not exist, on
+    /// the filesystem that deltas are to be applied on
+    #[structopt(long)]
+    pub work_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Version {
+    /// List the optional features, each prefixed by `+` if this build has it
+    /// or `-` if not
+    #[structopt(long)]
+    pub features: bool,
 }
 
 #[derive(Debug, StructOpt)]
 pub enum Command {
     Diff(Diff),
     Apply(Apply),
+    /// Predict the size of the delta between two trees without encoding it
+    Estimate(Estimate),
+    /// Keep a delta up to date with a target tree as its files change
+    Watch(Watch),
+    /// Check a delta directory against its source without modifying anything
+    Verify(Verify),
+    /// Check a delta directory against its own meta-data, for files left behind or missing
+    Fsck(Fsck),
+    /// Merge two consecutive deltas into a single one
+    Squash(Squash),
+    /// Print a breakdown of what makes up a delta directory
+    Stats(Stats),
+    /// List the paths of a delta directory with how each one is restored
+    List(List),
+    /// Report the paths that appeared, disappeared or changed size much
+    /// between two deltas, such as those of consecutive releases
+    Compare(Compare),
+    /// Browse the tree of a delta directory interactively, largest parts first
+    #[cfg(feature = "tui")]
+    Tui(Tui),
+    /// Print the digest of a tree, to compare with the one printed by apply
+    Digest(Digest),
+    /// Generate a key pair for signing deltas
+    Keygen(Keygen),
+    /// Generate a key for encrypting deltas
+    EncryptionKeygen(EncryptionKeygen),
+    /// Rewrite the meta-data of a delta directory made by an older release in
+    /// the current format version
+    MigrateMeta(MigrateMeta),
+    /// Record in a delta the order for apply to restore its files in, slowest
+    /// first, from the report of a trial apply
+    RecordOrder(RecordOrder),
+    /// Compute a delta image directly from two OCI images
+    DiffOci(DiffOci),
+    /// Restore an OCI image from a delta image made by diff-oci
+    ApplyOci(ApplyOci),
+    /// Compute a block-level delta between two raw disk images or partitions
+    DiffBlock(DiffBlock),
+    /// Restore a raw disk image or partition from a delta made by diff-block
+    ApplyBlock(ApplyBlock),
+    /// Compute a delta between two squashfs or EROFS images
+    #[cfg(feature = "fs-image")]
+    DiffFsImage(DiffFsImage),
+    /// Make the target image of a delta made by diff-fs-image again, bit for bit
+    #[cfg(feature = "fs-image")]
+    ApplyFsImage(ApplyFsImage),
+    /// Save the delta between two images of the local container engine to a
+    /// tarball
+    SaveDelta(SaveDelta),
+    /// Restore an image from a tarball made by save-delta and load it into the
+    /// local container engine
+    LoadDelta(LoadDelta),
+    /// Restore the snapshot of an image in containerd from the snapshot of
+    /// its source image and a delta
+    ContainerdSnapshot(ContainerdSnapshot),
+    /// Pack a delta directory into a single archive, or a self-extracting
+    /// script that applies it
+    Package(Package),
+    /// Publish a delta archive to an object store, keyed by the digests of
+    /// the images it goes from and to
+    Push(Push),
+    /// Fetch the delta archive between two image digests from an object store
+    Pull(Pull),
+    /// Add the chunks of a delta directory to a content-addressed chunk store
+    /// shared by many deltas, writing an index of the delta
+    ExportChunks(ExportChunks),
+    /// Restore a target tree from a delta exported by export-chunks, fetching
+    /// only the chunks that the local store lacks
+    ApplyChunks(ApplyChunks),
+    /// List a delta in a catalog of the deltas available between images
+    CatalogAdd(CatalogAdd),
+    /// Print the chain of deltas of a catalog that is the cheapest to go from
+    /// one image digest to another
+    Resolve(Resolve),
+    /// Check which of the capabilities that apply relies on work here, such
+    /// as setting xattrs or ownership
+    SelfTest(SelfTest),
+    /// Print the version, and which optional features this build has
+    Version(Version),
     DockerFile(DockerFile)
 }
 
 #[derive(StructOpt, Debug)]
 pub struct Cmdline {
+    /// Log more of the work done to stderr, repeated for more detail: `-v`
+    /// for progress, `-vv` for each file and `-vvv` for the time taken by each
+    #[structopt(long, short="v", parse(from_occurrences))]
+    pub verbose: u8,
+
+    /// Same as `-vv`
     #[structopt(long, short="d")]
     pub debug: bool,
 
+    /// Format of the log messages, `json` printing one object per line
+    #[structopt(long, default_value="text", possible_values=&["text", "json"])]
+    pub log_format: LogFormat,
+
+    /// Path of the meta-data file relative to the root of the delta, such as
+    /// `etc/deltaimage.meta`, for images holding files of the default names.
+    /// Every command on the delta is to be given the same one.
+    #[structopt(long, global=true)]
+    pub meta_path: Option<PathBuf>,
+
     #[structopt(subcommand)]
     pub command: Command,
 }
@@ -0,0 +1,153 @@
+//! Comparison of two deltas between different images, such as the ones of
+//! consecutive releases, to catch paths that bloat the later one.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::Error;
+use crate::list::DeltaEntry;
+use crate::stats::DeltaStats;
+
+/// Options of comparing deltas
+#[derive(Debug, Clone)]
+pub struct CompareOptions {
+    /// Factor by which the delta size of a path has to grow or shrink to be
+    /// reported
+    pub factor: f64,
+    /// Paths smaller than this in both deltas, by delta and restored size,
+    /// are left out
+    pub min_size: u64,
+}
+
+impl Default for CompareOptions {
+    fn default() -> Self {
+        Self {
+            factor: 2.0,
+            min_size: 1 << 20,
+        }
+    }
+}
+
+/// How a path differs between the deltas
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum PathChange {
+    /// Only in the later delta
+    Appeared,
+    /// Only in the earlier delta
+    Disappeared,
+    Grew,
+    Shrank,
+}
+
+impl PathChange {
+    pub fn name(self) -> &'static str {
+        match self {
+            PathChange::Appeared => "appeared",
+            PathChange::Disappeared => "disappeared",
+            PathChange::Grew => "grew",
+            PathChange::Shrank => "shrank",
+        }
+    }
+}
+
+/// The sizes of a path in a delta
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct PathSizes {
+    /// Size of what the delta stores for the path
+    pub delta_size: u64,
+    /// Size of the restored file, unknown for deltas of older releases
+    pub size: Option<u64>,
+}
+
+impl PathSizes {
+    fn max(&self) -> u64 {
+        self.delta_size.max(self.size.unwrap_or_default())
+    }
+}
+
+/// A path reported by a comparison
+#[derive(Serialize, Debug, Clone)]
+pub struct ComparedPath {
+    pub path: String,
+    pub change: PathChange,
+    /// Sizes in the earlier delta, if it has the path
+    pub old: Option<PathSizes>,
+    /// Sizes in the later delta, if it has the path
+    pub new: Option<PathSizes>,
+}
+
+/// Outcome of comparing two deltas
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct DeltaComparison {
+    pub old_delta_size: u64,
+    pub new_delta_size: u64,
+    /// By change, then largest first
+    pub paths: Vec<ComparedPath>,
+}
+
+impl DeltaComparison {
+    /// Compare the delta `new_delta_dir` against the earlier `old_delta_dir`
+    pub fn compare(old_delta_dir: &Path, new_delta_dir: &Path, options: &CompareOptions)
+        -> anyhow::Result<Self>
+    {
+        let (old_delta_size, old) = path_sizes(old_delta_dir)?;
+        let (new_delta_size, mut new) = path_sizes(new_delta_dir)?;
+
+        let mut paths = vec![];
+        for (path, old_sizes) in old {
+            let new_sizes = new.remove(&path);
+            if old_sizes.max().max(new_sizes.map(|sizes| sizes.max()).unwrap_or_default()) < options.min_size {
+                continue;
+            }
+            let change = match new_sizes {
+                None => PathChange::Disappeared,
+                Some(new_sizes) if new_sizes.delta_size as f64 >= old_sizes.delta_size as f64 * options.factor
+                    && new_sizes.delta_size > old_sizes.delta_size => PathChange::Grew,
+                Some(new_sizes) if old_sizes.delta_size as f64 >= new_sizes.delta_size as f64 * options.factor
+                    && old_sizes.delta_size > new_sizes.delta_size => PathChange::Shrank,
+                Some(_) => continue,
+            };
+            paths.push(ComparedPath { path, change, old: Some(old_sizes), new: new_sizes });
+        }
+        for (path, new_sizes) in new {
+            if new_sizes.max() >= options.min_size {
+                paths.push(ComparedPath { path, change: PathChange::Appeared, old: None, new: Some(new_sizes) });
+            }
+        }
+
+        let largest = |path: &ComparedPath| [path.old, path.new].iter().flatten()
+            .map(PathSizes::max).max().unwrap_or_default();
+        paths.sort_by(|a, b| a.change.cmp(&b.change)
+            .then_with(|| largest(b).cmp(&largest(a)))
+            .then_with(|| a.path.cmp(&b.path)));
+        Ok(Self { old_delta_size, new_delta_size, paths })
+    }
+
+    /// Turn a comparison reporting any path into an error
+    pub fn into_result(self) -> Result<Self, Error> {
+        match self.paths.len() {
+            0 => Ok(self),
+            count => Err(Error::DeltasDiffer(count)),
+        }
+    }
+}
+
+/// Total size of a delta, and the sizes of each path it restores
+fn path_sizes(delta_dir: &Path) -> anyhow::Result<(u64, BTreeMap<String, PathSizes>)> {
+    let stats = DeltaStats::collect(delta_dir)?;
+    let delta_sizes: HashMap<PathBuf, u64> = stats.files.into_iter()
+        .map(|file| (file.path, file.delta_size))
+        .collect();
+
+    let sizes = DeltaEntry::list(delta_dir)?.into_iter()
+        .filter(|entry| entry.kind != "deleted")
+        .map(|entry| {
+            let delta_size = delta_sizes.get(Path::new(&entry.path)).copied().unwrap_or_default();
+            (entry.path, PathSizes { delta_size, size: entry.size })
+        })
+        .collect();
+    Ok((stats.total_delta_size, sizes))
+}
@@ -0,0 +1,154 @@
+//! Delta-based pulls on containerd hosts: the snapshot of a target image is
+//! materialized from the snapshot of its source image plus a delta, without
+//! pulling the target image or running a build.
+//!
+//! The source image is read through a read-only view of its snapshot, and the
+//! delta is applied into a new snapshot without a parent, which is committed
+//! under the given name for containers to be created on.
+
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::Error;
+use crate::apply::{ApplyOptions, ApplyStats, DeltaApplier};
+use crate::engine::{unmount_snapshot, Engine};
+use crate::oci::{config_diff_ids, WorkDir};
+use crate::utils::{copy_tree, digest_bytes, get_meta_data, set_meta_data};
+
+/// Directory of the new snapshot that the delta is applied in, before its
+/// content is moved to the root of the snapshot
+const RESTORE_DIR: &str = "__deltaimage.restore";
+
+/// Options of materializing a snapshot
+#[derive(Debug, Clone)]
+pub struct SnapshotOptions {
+    /// containerd snapshotter holding the snapshot of the source image
+    pub snapshotter: String,
+    pub apply: ApplyOptions,
+}
+
+impl Default for SnapshotOptions {
+    fn default() -> Self {
+        Self { snapshotter: "overlayfs".to_owned(), apply: ApplyOptions::default() }
+    }
+}
+
+/// Restore the target tree of `delta`, a delta directory or archive, from the
+/// snapshot of `source_image` in containerd, as the new committed snapshot
+/// `name`.
+///
+/// The source image must be unpacked for the snapshotter. Snapshots are
+/// mounted under `work_dir`, which must not exist and is removed once done,
+/// which needs root.
+pub fn materialize_snapshot(source_image: &str, delta: &Path, name: &str, work_dir: &Path,
+    options: SnapshotOptions) -> anyhow::Result<ApplyStats>
+{
+    let ctr = Engine::Containerd;
+    let parent = image_chain_id(source_image)?;
+    let work_dir = WorkDir::create(work_dir)?;
+    let snapshots = |args: &[&str]| {
+        ctr.run(&[&["snapshots", "--snapshotter", &options.snapshotter], args].concat())
+    };
+    let view_key = format!("{}.deltaimage-view", name);
+    let active_key = format!("{}.deltaimage-active", name);
+
+    snapshots(&["view", &view_key, &parent])?;
+    let restored = snapshots(&["prepare", &active_key]).and_then(|()| {
+        let source_dir = work_dir.join("source");
+        let target_dir = work_dir.join("target");
+        ctr.mount_snapshot(&options.snapshotter, &view_key, &source_dir)?;
+        let restored = ctr.mount_snapshot(&options.snapshotter, &active_key, &target_dir)
+            .and_then(|()| {
+                let restored = apply_into(&source_dir, delta, &target_dir, options.apply.clone());
+                unmount_snapshot(&target_dir)?;
+                restored
+            });
+        unmount_snapshot(&source_dir)?;
+
+        match restored {
+            Ok(stats) => snapshots(&["commit", name, &active_key]).map(|()| stats),
+            Err(err) => {
+                if let Err(rm_err) = snapshots(&["rm", &active_key]) {
+                    tracing::warn!("Could not remove snapshot {}: {:#}", active_key, rm_err);
+                }
+                Err(err)
+            }
+        }
+    });
+    snapshots(&["rm", &view_key])?;
+    let stats = restored?;
+
+    work_dir.remove()?;
+    Ok(stats)
+}
+
+/// Apply the delta in a directory of the mounted snapshot, as apply restores
+/// the target tree in place of the delta tree, then move it up to the root
+fn apply_into(source_dir: &Path, delta: &Path, target_dir: &Path, mut options: ApplyOptions)
+    -> anyhow::Result<ApplyStats>
+{
+    let restore_dir = target_dir.join(RESTORE_DIR);
+    if delta.is_file() {
+        options.archive = Some(delta.to_owned());
+    } else {
+        copy_tree(delta, &restore_dir)?;
+    }
+    let stats = DeltaApplier::new(source_dir, &restore_dir).options(options).run()?;
+
+    for entry in std::fs::read_dir(&restore_dir)? {
+        let entry = entry?;
+        std::fs::rename(entry.path(), target_dir.join(entry.file_name()))
+            .with_context(|| format!("failed moving {}", entry.path().display()))?;
+    }
+    let meta_data = get_meta_data(&restore_dir)?;
+    std::fs::remove_dir(&restore_dir)
+        .with_context(|| format!("failed removing {}", restore_dir.display()))?;
+    set_meta_data(target_dir, meta_data)?;
+
+    Ok(stats)
+}
+
+/// Key of the top snapshot of an unpacked image, the chain ID of its layers
+fn image_chain_id(image: &str) -> anyhow::Result<String> {
+    let ctr = Engine::Containerd;
+    let content = |digest: &str| -> anyhow::Result<serde_json::Value> {
+        serde_json::from_str(&ctr.output(&["content", "get", digest])?)
+            .with_context(|| format!("failed to parse {}", digest))
+    };
+
+    let listing = ctr.output(&["images", "ls", &format!("name=={}", image)])?;
+    let digest = listing.lines().skip(1)
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .find(|columns| columns.first() == Some(&image))
+        .and_then(|columns| columns.get(2).map(|digest| digest.to_string()))
+        .ok_or_else(|| Error::ContainerdImageNotFound(image.to_owned()))?;
+
+    // Of multi-platform images, containerd unpacks the one of the host
+    let arch = match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        arch => arch,
+    };
+    let mut manifest = content(&digest)?;
+    while let Some(manifests) = manifest.get("manifests").and_then(|x| x.as_array()) {
+        let descriptor = manifests.iter()
+            .find(|descriptor| descriptor.pointer("/platform/architecture")
+                .and_then(|x| x.as_str()) == Some(arch))
+            .or_else(|| manifests.first())
+            .and_then(|descriptor| descriptor.get("digest")?.as_str())
+            .ok_or_else(|| Error::InvalidOciImage("index lists no manifests".to_owned()))?;
+        manifest = content(descriptor)?;
+    }
+    let config_digest = manifest.pointer("/config/digest").and_then(|x| x.as_str())
+        .ok_or_else(|| Error::InvalidOciImage("manifest has no configuration".to_owned()))?;
+
+    let mut chain_id = None;
+    for diff_id in config_diff_ids(&content(config_digest)?)? {
+        chain_id = Some(match chain_id {
+            None => diff_id,
+            Some(parent) => format!("sha256:{}", digest_bytes(format!("{} {}", parent, diff_id).as_bytes())),
+        });
+    }
+    chain_id.ok_or_else(|| Error::InvalidOciImage("configuration lists no layers".to_owned()).into())
+}
@@ -0,0 +1,242 @@
+//! Normalization of files made of deflate streams, gzip files and zip
+//! archives such as jars and wheels, in which xdelta3 finds little in common
+//! across releases as a small change reshuffles all of the compressed bytes.
+//!
+//! Their deflate streams are inflated before encoding, and deflated again on
+//! decoding at the level found to reproduce each one exactly. Streams that no
+//! level reproduces, such as those of another deflate implementation, are
+//! kept compressed.
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use serde::{Deserialize, Serialize};
+
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b\x08";
+const ZIP_LOCAL_HEADER: &[u8] = b"PK\x03\x04";
+const ZIP_DATA_DESCRIPTOR: &[u8] = b"PK\x07\x08";
+
+/// Largest normalized content of a file, beyond which it is left as it is
+const NORMALIZED_LIMIT: usize = 1 << 30;
+
+/// Levels tried on each stream, most common first
+const LEVELS: [u32; 10] = [6, 9, 1, 5, 4, 3, 2, 7, 8, 0];
+
+/// A part of a normalized file
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Segment {
+    /// Bytes kept as they are
+    Raw(u64),
+    /// Inflated deflate stream of the given length, deflated back at the
+    /// given level
+    Deflate(u64, u32),
+}
+
+/// Whether the content is a gzip file or zip archive
+pub(crate) fn is_deflated(content: &[u8]) -> bool {
+    content.starts_with(GZIP_MAGIC) || content.starts_with(ZIP_LOCAL_HEADER)
+}
+
+/// The content with its deflate streams inflated, and the layout to deflate
+/// them back with. `None` if there is no stream that can be reproduced.
+pub(crate) fn normalize(content: &[u8]) -> Option<(Vec<u8>, Vec<Segment>)> {
+    let mut normalizer = Normalizer::new(content, true);
+    normalizer.run()?;
+    match normalizer.layout.iter().any(|segment| matches!(segment, Segment::Deflate(..))) {
+        true => Some((normalizer.out, normalizer.layout)),
+        false => None,
+    }
+}
+
+/// The content with all of its deflate streams inflated, whether they can be
+/// reproduced or not, for source files that are only patched against
+pub(crate) fn inflate_all(content: &[u8]) -> Option<Vec<u8>> {
+    let mut normalizer = Normalizer::new(content, false);
+    normalizer.run()?;
+    Some(normalizer.out)
+}
+
+/// The original content of normalized content, from its layout
+pub(crate) fn denormalize(normalized: &[u8], layout: &[Segment]) -> Option<Vec<u8>> {
+    let mut content = Vec::with_capacity(normalized.len());
+    let mut pos = 0;
+    for segment in layout {
+        match *segment {
+            Segment::Raw(len) => {
+                content.extend_from_slice(normalized.get(pos..pos + len as usize)?);
+                pos += len as usize;
+            }
+            Segment::Deflate(len, level) => {
+                content.extend(deflate(normalized.get(pos..pos + len as usize)?, level)?);
+                pos += len as usize;
+            }
+        }
+    }
+    (pos == normalized.len()).then_some(content)
+}
+
+struct Normalizer<'a> {
+    content: &'a [u8],
+    /// Whether streams are only inflated if a level reproduces them
+    exact: bool,
+    pos: usize,
+    out: Vec<u8>,
+    layout: Vec<Segment>,
+    /// Level of the last stream reproduced, tried first on the next one
+    level: Option<u32>,
+}
+
+impl<'a> Normalizer<'a> {
+    fn new(content: &'a [u8], exact: bool) -> Self {
+        Self { content, exact, pos: 0, out: vec![], layout: vec![], level: None }
+    }
+
+    fn run(&mut self) -> Option<()> {
+        if self.content.starts_with(GZIP_MAGIC) {
+            while self.content[self.pos..].starts_with(GZIP_MAGIC) && self.gzip_member()? {}
+        } else if self.content.starts_with(ZIP_LOCAL_HEADER) {
+            while self.content[self.pos..].starts_with(ZIP_LOCAL_HEADER) && self.zip_entry()? {}
+        }
+        self.raw(self.content.len());
+        Some(())
+    }
+
+    /// Normalize the gzip member at the current position, returning whether
+    /// it is a valid one
+    fn gzip_member(&mut self) -> Option<bool> {
+        let header = &self.content[self.pos..];
+        let flags = *header.get(3)?;
+        let mut len = 10;
+        if flags & 0x04 != 0 {
+            len += 2 + u16::from_le_bytes(header.get(len..len + 2)?.try_into().ok()?) as usize;
+        }
+        for flag in [0x08, 0x10] {
+            if flags & flag != 0 {
+                len += header.get(len..)?.iter().position(|&c| c == 0)? + 1;
+            }
+        }
+        if flags & 0x02 != 0 {
+            len += 2;
+        }
+        if len + 8 > header.len() {
+            return Some(false);
+        }
+
+        self.raw(self.pos + len);
+        if !self.stream()? {
+            return Some(false);
+        }
+        // CRC and size
+        self.raw((self.pos + 8).min(self.content.len()));
+        Some(true)
+    }
+
+    /// Normalize the zip entry at the current position, returning whether
+    /// the entries that follow can be found
+    fn zip_entry(&mut self) -> Option<bool> {
+        let header = self.content.get(self.pos..self.pos + 30)?;
+        let u16_at = |offset: usize| u16::from_le_bytes([header[offset], header[offset + 1]]) as usize;
+        let (flags, method) = (u16_at(6), u16_at(8));
+        let compressed_size = u32::from_le_bytes(header[18..22].try_into().ok()?);
+        let data = self.pos + 30 + u16_at(26) + u16_at(28);
+        if data > self.content.len() {
+            return Some(false);
+        }
+        self.raw(data);
+
+        match method {
+            8 => if !self.stream()? {
+                return Some(false);
+            },
+            // Stored, whose size is only known from the header
+            _ if flags & 0x08 != 0 || compressed_size == u32::MAX => return Some(false),
+            _ => match data.checked_add(compressed_size as usize) {
+                Some(end) if end <= self.content.len() => self.raw(end),
+                _ => return Some(false),
+            },
+        }
+        if flags & 0x08 != 0 {
+            let len = match self.content[self.pos..].starts_with(ZIP_DATA_DESCRIPTOR) {
+                true => 16,
+                false => 12,
+            };
+            self.raw((self.pos + len).min(self.content.len()));
+        }
+        Some(true)
+    }
+
+    /// Inflate the deflate stream at the current position, or keep it as it
+    /// is if no level reproduces it. Returns whether there is a valid stream.
+    fn stream(&mut self) -> Option<bool> {
+        let Some((inflated, consumed)) = inflate(&self.content[self.pos..]) else {
+            return Some(false);
+        };
+        if self.out.len() + inflated.len() > NORMALIZED_LIMIT {
+            return None;
+        }
+
+        let compressed = &self.content[self.pos..self.pos + consumed];
+        let level = match self.exact {
+            true => self.level.into_iter().chain(LEVELS)
+                .find(|&level| deflate(&inflated, level).as_deref() == Some(compressed)),
+            false => Some(0),
+        };
+        match level {
+            Some(level) => {
+                self.level = Some(level);
+                self.out.extend_from_slice(&inflated);
+                self.layout.push(Segment::Deflate(inflated.len() as u64, level));
+                self.pos += consumed;
+            }
+            None => self.raw(self.pos + consumed),
+        }
+        Some(true)
+    }
+
+    /// Keep the content up to `end` as it is
+    fn raw(&mut self, end: usize) {
+        if end <= self.pos {
+            return;
+        }
+        self.out.extend_from_slice(&self.content[self.pos..end]);
+        match self.layout.last_mut() {
+            Some(Segment::Raw(len)) => *len += (end - self.pos) as u64,
+            _ => self.layout.push(Segment::Raw((end - self.pos) as u64)),
+        }
+        self.pos = end;
+    }
+}
+
+/// Inflate the raw deflate stream at the start of `data`, returning its
+/// content and the length of the stream
+fn inflate(data: &[u8]) -> Option<(Vec<u8>, usize)> {
+    let mut decompress = Decompress::new(false);
+    let mut out = Vec::with_capacity(data.len().saturating_mul(4).min(NORMALIZED_LIMIT));
+    loop {
+        if out.len() == out.capacity() {
+            out.reserve(out.len().max(1 << 16));
+        }
+        let (total_in, total_out) = (decompress.total_in(), decompress.total_out());
+        let status = decompress.decompress_vec(&data[total_in as usize..], &mut out, FlushDecompress::None).ok()?;
+        if status == Status::StreamEnd {
+            return Some((out, decompress.total_in() as usize));
+        }
+        // Truncated, or too large to bother
+        if (decompress.total_in(), decompress.total_out()) == (total_in, total_out) || out.len() > NORMALIZED_LIMIT {
+            return None;
+        }
+    }
+}
+
+/// Deflate `data` as a raw stream at `level`
+fn deflate(data: &[u8], level: u32) -> Option<Vec<u8>> {
+    let mut compress = Compress::new(Compression::new(level), false);
+    let mut out = Vec::with_capacity(data.len() / 2 + 64);
+    loop {
+        if out.len() == out.capacity() {
+            out.reserve(out.len().max(1 << 16));
+        }
+        let total_in = compress.total_in() as usize;
+        if compress.compress_vec(&data[total_in..], &mut out, FlushCompress::Finish).ok()? == Status::StreamEnd {
+            return Some(out);
+        }
+    }
+}
@@ -0,0 +1,1636 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::Error;
+use crate::archive::pack_archive;
+use crate::backend::{self, Backend};
+use crate::cache::CacheEntry;
+use crate::encryption::{Cipher, CIPHER};
+use crate::file_errors::{FileErrorKind, FileErrors};
+use crate::filter::PathFilter;
+use crate::image_config::ConfigDelta;
+use crate::oci::LayerCompression;
+use crate::journal::{DiffJournal, DiffJournalEntry, DiffJournalHeader, DIFF_JOURNAL_FILE};
+use crate::platform::{MetadataExt, OsStrExt};
+use crate::report::{FailedFile, FileReport};
+use crate::signing;
+use crate::similarity::Sketch;
+use crate::sparse::find_holes;
+use crate::mmap;
+use crate::metadata::{meta_file_paths, Algo, Directory, Holes, LinkGroup, MetaData, MetaFormat, Placeholder, Special, SpecialKind, Symlink, Timestamp, META_FORMAT_VERSION,
+    REVERSE_DELTA_DIR};
+use crate::owners::UNCHANGED;
+use crate::patch_from;
+use crate::stream;
+use crate::tree_digest::tree_digest;
+use crate::xdelta::{self, XDelta3Params};
+use crate::utils::{self, drop_components, get_meta_data, set_meta_data,
+    parallel_map, default_jobs, temp_path_for, is_temp_path, digest_bytes, digest_file, save_parent_modtime,
+    restore_modtimes, copy_tree, path_from_bytes, open_noatime, read_noatime};
+
+/// Options controlling delta generation
+#[derive(Debug, Clone)]
+pub struct DiffOptions {
+    /// Number of files to encode concurrently, defaulting to the number of CPUs
+    pub jobs: Option<usize>,
+
+    /// Files of at least this many bytes are encoded in bounded-memory chunks
+    pub stream_threshold: u64,
+
+    /// Files of at least this many bytes are encoded with zstd patch-from,
+    /// holding only the source file in memory
+    pub patch_from_threshold: u64,
+
+    /// Zstd level used for files that xdelta3 cannot encode and for patch-from
+    pub compression_level: i32,
+
+    /// Also pack the resulting delta tree into this single archive file
+    pub archive: Option<PathBuf>,
+
+    /// Store new files that have the same content as a source file at another
+    /// path as references to it
+    pub detect_renames: bool,
+
+    /// Encode other new files against the most similar source file, such as
+    /// a previous version of a library with a versioned file name
+    pub pair_similar: bool,
+
+    /// Hash the files of the same size in both trees in a parallel pre-pass,
+    /// keeping the unchanged ones without comparing them again, which makes
+    /// diffs of mostly identical trees fast
+    pub prehash: bool,
+
+    /// Keep the files with the same size and modification time in both trees
+    /// without reading them, as rsync does. A file changed in place with its
+    /// modification time restored goes unnoticed, and the kept files are not
+    /// verified by apply. The delta then has no digest of the target tree.
+    pub trust_mtime: bool,
+
+    /// Link together target files with identical content, permissions,
+    /// ownership and xattrs that are not hardlinked already, so that they are
+    /// stored once and restored as hardlinks
+    pub dedup_identical: bool,
+
+    /// Store the files of the target tree that are new, and not found in the
+    /// source tree, zstd-compressed when it makes them smaller, rather than
+    /// leaving them as they are
+    pub compress_new_files: bool,
+
+    /// Leave the placeholders of unmodified files out of the delta tree,
+    /// recording their meta-data instead, so that diffing an unchanged tree
+    /// does not touch them
+    pub drop_placeholders: bool,
+
+    /// Leave the modification and access times of the target tree
+    /// unrecorded, for apply not to restore them
+    pub ignore_times: bool,
+
+    /// Leave the ownership of the target tree unrecorded, for apply not to
+    /// restore it. The delta then has no digest of the target tree.
+    pub ignore_owner: bool,
+
+    /// Give up after this many files failed, or 0 to go through all files
+    /// first. The failures are reported together.
+    pub max_errors: usize,
+
+    /// Glob patterns of paths to leave out of the delta, such as `/var/log/*`,
+    /// for which apply keeps whatever the source tree provides
+    pub exclude: Vec<String>,
+
+    /// Glob patterns of paths to keep in the delta even if they match `exclude`
+    pub include: Vec<String>,
+
+    /// Compare the trees and report how each file would be handled, leaving
+    /// the target directory untouched
+    pub dry_run: bool,
+
+    /// Diff a target tree that holds the meta-data of a delta already, which
+    /// is otherwise refused, overwriting it
+    pub force: bool,
+
+    /// Refuse a target tree holding the journal of an interrupted diff
+    /// instead of resuming it, for builds where it can only be the leftover
+    /// of a cached or partially built stage
+    pub fresh: bool,
+
+    /// Other source trees, such as those of other common base images, that
+    /// each modified file is also patched against, keeping the smallest
+    /// patch. Apply needs the extra sources that patches were kept against.
+    pub extra_sources: Vec<PathBuf>,
+
+    /// Write the delta tree to this new directory, leaving the target
+    /// directory untouched
+    pub output: Option<PathBuf>,
+
+    /// For OCI delta images, compression of the delta layer
+    pub layer_compression: LayerCompression,
+
+    /// For OCI delta images, split the delta layer into layers of about this
+    /// many bytes each
+    pub layer_size: Option<u64>,
+
+    /// Also compute the reverse delta, restoring the source tree from the
+    /// target tree, and embed it in the delta directory
+    pub bidirectional: bool,
+
+    /// Encoding of the meta-data file
+    pub meta_format: MetaFormat,
+
+    /// Tuning of xdelta3, recorded in the meta-data
+    pub xdelta3: XDelta3Params,
+
+    /// Name of the backend to store modified files with, such as `bsdiff`,
+    /// instead of picking one for each file
+    pub algo: Option<String>,
+
+    /// Try every backend on each modified file and keep the smallest patch,
+    /// at the cost of encode time
+    pub optimize: bool,
+
+    /// Directory of a cache of encoded files shared between diffs, reused
+    /// for pairs of files encoded before with the same options
+    pub cache_dir: Option<PathBuf>,
+
+    /// Sign the meta-data with the ed25519 secret key stored in this file
+    pub sign_key: Option<PathBuf>,
+
+    /// Encrypt the stored files with the AES-256-GCM key stored in this
+    /// file, including new files that would otherwise be left as they are
+    pub encrypt_key: Option<PathBuf>,
+
+    /// What to do with files that cannot be read, instead of failing
+    pub on_error: OnError,
+
+    /// Modified files smaller than this many bytes are stored on their own,
+    /// compressed, without trying to patch them
+    pub min_file_size: u64,
+
+    /// Smallest fraction of the size of a modified file that a patch must
+    /// save, such as 0.1 for 10%, for it to be stored on its own otherwise.
+    /// Only for files below the stream threshold.
+    pub min_delta_ratio: f64,
+
+    /// Whole configurations of the source and target images, such as
+    /// printed by `skopeo inspect --config`, whose differences are recorded
+    /// in the meta-data
+    pub image_configs: Option<(serde_json::Value, serde_json::Value)>,
+}
+
+/// What diff does with a file that it fails to read or encode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnError {
+    /// Fail the whole diff
+    #[default]
+    Fail,
+    /// Leave the file out of the delta, as if excluded, so that apply takes
+    /// whatever the source tree provides for it
+    Skip,
+    /// Leave the file whole in the delta, unchecked, as for a new file. The
+    /// delta then has no digest of the target tree.
+    AsIs,
+}
+
+impl OnError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OnError::Fail => "fail",
+            OnError::Skip => "skip",
+            OnError::AsIs => "as-is",
+        }
+    }
+}
+
+impl FromStr for OnError {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(OnError::Fail),
+            "skip" => Ok(OnError::Skip),
+            "as-is" => Ok(OnError::AsIs),
+            _ => Err(format!("unknown error policy {}", s)),
+        }
+    }
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            jobs: None,
+            stream_threshold: 256 << 20,
+            patch_from_threshold: 1 << 30,
+            compression_level: 3,
+            archive: None,
+            detect_renames: true,
+            pair_similar: true,
+            prehash: true,
+            trust_mtime: false,
+            dedup_identical: false,
+            compress_new_files: false,
+            drop_placeholders: false,
+            ignore_times: false,
+            ignore_owner: false,
+            max_errors: 0,
+            force: false,
+            fresh: false,
+            extra_sources: vec![],
+            exclude: vec![],
+            include: vec![],
+            dry_run: false,
+            output: None,
+            layer_compression: LayerCompression::default(),
+            layer_size: None,
+            bidirectional: false,
+            meta_format: MetaFormat::default(),
+            xdelta3: XDelta3Params::default(),
+            algo: None,
+            optimize: false,
+            cache_dir: None,
+            sign_key: None,
+            encrypt_key: None,
+            on_error: OnError::default(),
+            min_file_size: 0,
+            min_delta_ratio: 0.0,
+            image_configs: None,
+        }
+    }
+}
+
+/// Size totals of a generated delta
+#[derive(Debug, Clone, Default)]
+pub struct DiffStats {
+    /// Total size of the target files that exist in both trees, or were moved
+    /// or paired with a similar source file
+    pub total_size: u64,
+
+    /// Total size of the patches replacing the modified ones
+    pub reduced_size: u64,
+
+    /// How each compared file was handled
+    pub files: Vec<FileReport>,
+
+    /// Time taken by the whole diff
+    pub duration: Duration,
+
+    /// Files that could not be read, handled according to `DiffOptions::on_error`
+    pub failed: Vec<FailedFile>,
+
+    /// Files of the target tree that were not in the source tree
+    pub new_files: usize,
+
+    /// Files of the source tree that are no longer in the target tree
+    pub deleted_files: usize,
+
+    /// Time taken by each stage of the diff, such as `scan`, `encode` and
+    /// `finish`
+    pub stages: Vec<(&'static str, Duration)>,
+}
+
+impl DiffStats {
+    /// Whether the target tree has the same files as the source tree, with
+    /// the same content
+    pub fn is_unchanged(&self) -> bool {
+        self.new_files == 0 && self.deleted_files == 0 && self.reduced_size == 0
+            && self.files.iter().all(|file| file.algo == "keep")
+    }
+
+    /// Fraction of the modified content that was stored on its own rather
+    /// than as a patch
+    pub fn fallback_ratio(&self) -> f64 {
+        let modified = self.files.iter().filter(|file| file.algo != "keep");
+        let (stored, total) = modified.fold((0, 0), |(stored, total), file| {
+            match file.algo.as_str() {
+                "zstd" | "as-is" => (stored + file.original_size, total + file.original_size),
+                _ => (stored, total + file.original_size),
+            }
+        });
+        if total == 0 { 0.0 } else { stored as f64 / total as f64 }
+    }
+}
+
+/// Turns a copy of the target tree into a delta against a source tree.
+///
+/// Files that exist in both trees are replaced with xdelta3 patches against
+/// their source counterpart, or with zero-sized placeholders if unmodified,
+/// and a meta-data file describing how to restore them is written at the root.
+pub struct DeltaBuilder {
+    source_dir: PathBuf,
+    target_delta_dir: PathBuf,
+    options: DiffOptions,
+}
+
+impl DeltaBuilder {
+    pub fn new(source_dir: impl Into<PathBuf>, target_delta_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            source_dir: source_dir.into(),
+            target_delta_dir: target_delta_dir.into(),
+            options: DiffOptions::default(),
+        }
+    }
+
+    pub fn options(mut self, options: DiffOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Compute the delta, rewriting the target directory in place, or a copy
+    /// of it if an output directory is given.
+    pub fn run(&self) -> anyhow::Result<DiffStats> {
+        if let Some(algo) = self.options.algo.as_ref().filter(|algo| backend::by_name(algo).is_none()) {
+            return Err(Error::UnknownAlgo(algo.clone()).into());
+        }
+
+        if let Some(output) = self.options.output.as_ref().filter(|_| !self.options.dry_run) {
+            if std::fs::symlink_metadata(output).is_err() {
+                copy_tree(&self.target_delta_dir, output)?;
+            } else if !output.join(DIFF_JOURNAL_FILE).exists() {
+                // Only the output of an interrupted diff can be picked up
+                return Err(Error::DeltaDirExists(output.clone()).into());
+            }
+
+            let builder = DeltaBuilder {
+                source_dir: self.source_dir.clone(),
+                target_delta_dir: output.clone(),
+                options: DiffOptions { output: None, ..self.options.clone() },
+            };
+            return builder.run();
+        }
+
+        if self.options.bidirectional && !self.options.dry_run {
+            return self.run_bidirectional();
+        }
+        if !self.options.dry_run {
+            self.check_target()?;
+        }
+
+        let started = Instant::now();
+        let cipher = self.options.encrypt_key.as_deref().map(Cipher::load).transpose()?;
+
+        let mut changes: Vec<_> = Vec::new();
+        let mut keep_files: Vec<_> = Vec::new();
+        let mut checksums: Vec<_> = Vec::new();
+        let mut orig_files = BTreeSet::new();
+        let mut excluded = Vec::new();
+        let filter = PathFilter::new(&self.options.exclude, &self.options.include)?;
+
+        let n = self.source_dir.components().count();
+        let mut total_size = 0u64;
+        let mut reduced_size = 0u64;
+
+        let mut source_index = SourceIndex::default();
+
+        for entry in WalkDir::new(&self.source_dir) {
+            let entry = entry?;
+            let path = entry.path();
+            let rel_path = drop_components(n, path);
+
+            if filter.is_excluded(&rel_path) {
+                let file_type = entry.file_type();
+                if file_type.is_file() || file_type.is_dir() || file_type.is_symlink() {
+                    excluded.push(rel_path.as_os_str().as_bytes().to_owned());
+                }
+                continue;
+            }
+
+            if entry.file_type().is_file() {
+                if self.options.detect_renames || self.options.pair_similar {
+                    source_index.insert(entry.metadata()?.len(), &rel_path);
+                }
+                orig_files.insert(rel_path);
+            }
+        }
+
+        // Reading the directories would lose their access times, so the tree
+        // is only walked here if there is anything to exclude
+        if !self.options.dry_run && !filter.is_empty() {
+            remove_excluded(&self.target_delta_dir, &filter)?;
+        }
+
+        // Pick up the work of an interrupted diff
+        let resumed = match self.options.dry_run {
+            true => None,
+            false => DiffJournal::load(&self.target_delta_dir)?,
+        };
+        if resumed.is_some() {
+            tracing::warn!("Resuming interrupted diff of {}", self.target_delta_dir.display());
+            remove_stale_temps(&self.target_delta_dir)?;
+        }
+        let journaled = resumed.as_ref().map(|(_, entries)| entries);
+        let jobs = self.options.jobs.unwrap_or_else(default_jobs);
+
+        let mut parent_modtime_save = HashMap::new();
+        let mut fsid_link_groups: HashMap<_, Vec<PathBuf>> = HashMap::new();
+        let mut symlinks = Vec::new();
+        let mut specials = Vec::new();
+        let mut directories = Vec::new();
+        let mut dedup_candidates = Vec::new();
+
+        let n = self.target_delta_dir.components().count();
+        for entry in WalkDir::new(&self.target_delta_dir) {
+            let entry = entry?;
+            let path = entry.path();
+            let rel_path = drop_components(n, path);
+
+            if filter.is_excluded(&rel_path) || rel_path == Path::new(DIFF_JOURNAL_FILE) {
+                continue;
+            }
+
+            if entry.file_type().is_file() {
+                let metadata = entry.metadata()?;
+                let fsid = (metadata.ino(), metadata.dev());
+                if metadata.nlink() >= 2 {
+                    fsid_link_groups.entry(fsid).or_default().push(rel_path);
+                } else if self.options.dedup_identical && resumed.is_none() && metadata.len() > 0 {
+                    dedup_candidates.push((rel_path, metadata.len()));
+                }
+            } else if entry.file_type().is_dir() {
+                directories.push(Directory::new(&rel_path, self.meta_data(path)?));
+            } else if entry.file_type().is_symlink() {
+                let metadata = entry.metadata()?;
+                symlinks.push(Symlink {
+                    path: rel_path.as_os_str().as_bytes().to_owned(),
+                    target: std::fs::read_link(path)?.as_os_str().as_bytes().to_owned(),
+                    uid: if self.options.ignore_owner { UNCHANGED } else { metadata.uid() },
+                    gid: if self.options.ignore_owner { UNCHANGED } else { metadata.gid() },
+                });
+            } else if let Some(kind) = SpecialKind::of(entry.file_type()) {
+                let rdev = entry.metadata()?.rdev();
+                specials.push(Special::new(&rel_path, kind, rdev, self.meta_data(path)?));
+            }
+        }
+
+        // Rewritten files no longer show the directories and hardlinks of the
+        // target tree as they were, so a resumed diff takes them from the journal
+        let link_groups: Vec<Vec<Vec<u8>>> = match &resumed {
+            Some((header, _)) => {
+                directories = header.directories.clone();
+                header.link_groups.clone()
+            }
+            None => fsid_link_groups.into_values()
+                .chain(identical_files(&self.target_delta_dir, dedup_candidates, jobs)?)
+                .map(|paths| paths.iter().map(|path| path.as_os_str().as_bytes().to_owned()).collect())
+                .collect(),
+        };
+
+        let mut path_link_groups = HashMap::new();
+        for group in link_groups.iter() {
+            // Keep the representative that an interrupted diff already rewrote
+            let representative = group.iter()
+                .find(|path| journaled.map(|entries| entries.contains_key(*path)).unwrap_or(false))
+                .map(|path| self.target_delta_dir.join(path_from_bytes(path)));
+            let item = Rc::new(RefCell::new(representative));
+            for path in group {
+                path_link_groups.insert(path_from_bytes(path), item.clone());
+            }
+        }
+
+        // Plan the work serially: files to compare, and hardlinks to restore once
+        // the representative of each link group has been rewritten.
+        let mut work = Vec::new();
+        let mut links = Vec::new();
+        let mut new_files = 0;
+
+        for entry in WalkDir::new(&self.target_delta_dir) {
+            let entry = entry?;
+            let path = entry.path();
+            let rel_path = drop_components(n, path);
+
+            if !entry.file_type().is_file() || filter.is_excluded(&rel_path)
+                || rel_path == Path::new(DIFF_JOURNAL_FILE)
+            {
+                continue;
+            }
+
+            let new = !orig_files.remove(&rel_path);
+            if new {
+                // New file, which may have been moved from elsewhere in the source,
+                // or resemble a source file at another path. All of them are
+                // stored when encrypting or compressing them, hardlinked ones as
+                // their link group.
+                new_files += 1;
+                let metadata = entry.metadata()?;
+                let size = metadata.len();
+                let movable = self.options.detect_renames && source_index.has_size(size);
+                let pairable = self.options.pair_similar && size > 0
+                    && size < self.options.stream_threshold;
+                let rewritten = journaled
+                    .map(|entries| entries.contains_key(rel_path.as_os_str().as_bytes()))
+                    .unwrap_or(false);
+                // Identical files found by dedup are not hardlinked yet
+                let deduped = metadata.nlink() < 2 && path_link_groups.contains_key(&rel_path);
+                if !(((movable || pairable) && metadata.nlink() < 2) || rewritten || deduped
+                    || cipher.is_some() || self.options.compress_new_files)
+                {
+                    continue;
+                }
+            }
+
+            // Files in both images need to be compared, and hardlinks are
+            // restored from the first file of their link group
+            let target_path = self.target_delta_dir.join(&rel_path);
+
+            save_parent_modtime(&mut parent_modtime_save, &target_path)?;
+
+            if let Some(x) = path_link_groups.get(&rel_path) {
+                let mut m = x.borrow_mut();
+                match &*m {
+                    Some(other_path) if *other_path != target_path => {
+                        total_size += entry.metadata()?.len();
+                        links.push((target_path, other_path.clone()));
+                        continue;
+                    },
+                    _ => {
+                        *m = Some(target_path.clone());
+                    },
+                }
+            };
+
+            work.push(match new {
+                true => Work::New(rel_path),
+                false => Work::Compare(rel_path),
+            });
+        }
+
+        let mut stages = vec![("scan", started.elapsed())];
+        let mut stage_started = Instant::now();
+
+        // Members of link groups keep their placeholders, for the others to
+        // be linked to
+        let linked: HashSet<_> = path_link_groups.keys().cloned().collect();
+
+        // Checksums and sizes of the files found unchanged by the pre-pass,
+        // with no checksum for the ones trusted by their modification times.
+        // Files it could not read are left to the encode stage to report.
+        let pre_pass = self.options.prehash || self.options.trust_mtime;
+        let unchanged: HashMap<&Path, (u64, String)> = match pre_pass && resumed.is_none() {
+            true => {
+                let compared: Vec<_> = work.iter()
+                    .filter_map(|work| match work {
+                        Work::Compare(rel_path) => Some(rel_path.as_path()),
+                        Work::New(_) => None,
+                    })
+                    .collect();
+                let unchanged = parallel_map(jobs, &compared, |rel_path| {
+                    let (src_path, target_path) = (self.source_dir.join(rel_path),
+                        self.target_delta_dir.join(rel_path));
+                    // Link group members are digested from their content
+                    let trusted = match self.options.trust_mtime && !linked.contains(*rel_path) {
+                        true => quick_check_unchanged(&src_path, &target_path).ok().flatten(),
+                        false => None,
+                    };
+                    let unchanged = match trusted {
+                        Some(size) => Some((size, String::new())),
+                        None if self.options.prehash => prehash_unchanged(&src_path, &target_path).ok().flatten(),
+                        None => None,
+                    };
+                    Ok(unchanged.map(|unchanged| (*rel_path, unchanged)))
+                })?;
+                let unchanged: HashMap<_, _> = unchanged.into_iter().flatten().collect();
+                tracing::info!("Pre-pass found {} of {} files unchanged", unchanged.len(), compared.len());
+                stages.push(("hash", std::mem::replace(&mut stage_started, Instant::now()).elapsed()));
+                unchanged
+            }
+            false => HashMap::new(),
+        };
+
+        let journal = match &resumed {
+            _ if self.options.dry_run => None,
+            Some((header, entries)) => {
+                parent_modtime_save = header.parent_modtimes.iter().cloned().collect();
+                Some(DiffJournal::create(&self.target_delta_dir, header, entries.values())?)
+            }
+            None => {
+                let header = DiffJournalHeader {
+                    directories: directories.clone(),
+                    link_groups: link_groups.clone(),
+                    parent_modtimes: parent_modtime_save.iter()
+                        .map(|(path, modified)| (path.clone(), *modified))
+                        .collect(),
+                };
+                Some(DiffJournal::create(&self.target_delta_dir, &header, std::iter::empty())?)
+            }
+        };
+
+        let diff_one = |work: &Work| -> anyhow::Result<FileOutcome> {
+            let file_started = Instant::now();
+            let rel_path = work.path();
+            let _span = tracing::trace_span!("file", path = %rel_path.display()).entered();
+            let target_path = self.target_delta_dir.join(rel_path);
+
+            let entry = journaled.and_then(|entries| entries.get(rel_path.as_os_str().as_bytes()));
+            if let Some(entry) = entry {
+                if !is_original(&target_path, entry)? {
+                    let holes = entry.holes.clone();
+                    return Ok(FileOutcome::Diffed(Some(Box::new(FileDiff::from(entry.clone()))), holes,
+                        Duration::ZERO));
+                }
+            }
+
+            let diffed = find_holes(&target_path).and_then(|holes| {
+                let result = match work {
+                    Work::Compare(rel_path) => match unchanged.get(rel_path.as_path()) {
+                        Some((total_size, checksum)) => Some(keep_placeholder(rel_path,
+                            self.meta_data(&target_path)?, *total_size, checksum.clone())?),
+                        None => Some(self.diff_file(rel_path)?),
+                    },
+                    Work::New(rel_path) => match self.diff_new_file(rel_path, &source_index)? {
+                        None if cipher.is_some() || self.options.compress_new_files => {
+                            self.store_new_file(rel_path, cipher.is_some())?
+                        }
+                        result => result,
+                    },
+                };
+                Ok((result, holes))
+            });
+            let (mut result, holes) = match diffed {
+                Ok(diffed) => diffed,
+                Err(err) if self.options.on_error != OnError::Fail => {
+                    tracing::warn!("Failed to read {}, handled as {}: {:#}", rel_path.display(),
+                        self.options.on_error.name(), err);
+                    return Ok(FileOutcome::Failed(format!("{:#}", err)));
+                }
+                Err(err) => return Err(err),
+            };
+            if let Some(result) = result.as_mut().filter(|result| result.algo.is_none()) {
+                if self.options.drop_placeholders && !linked.contains(rel_path) {
+                    // Left as it is until the meta-data is saved, then removed
+                    result.rewrite = None;
+                }
+            }
+
+            // Journal the outcome first, so that a resumed diff does not take
+            // the rewritten file for the original one
+            if let (Some(result), Some(journal)) = (&mut result, &journal) {
+                journal.record(&DiffJournalEntry {
+                    path: rel_path.as_os_str().as_bytes().to_owned(),
+                    algo: result.algo.clone(),
+                    total_size: result.total_size,
+                    reduced_size: result.reduced_size,
+                    checksum: result.checksum.clone(),
+                    holes: holes.clone(),
+                    base: result.base,
+                })?;
+                result.commit(&target_path, cipher.as_ref())?;
+            }
+            Ok(FileOutcome::Diffed(result.map(Box::new), holes, file_started.elapsed()))
+        };
+        let errors = FileErrors::new(self.options.max_errors);
+        let results = parallel_map(jobs, &work, |work| {
+            diff_one(work).or_else(|err| {
+                errors.record(work.path(), err)?;
+                // Not looked at, as the diff fails once all files are done
+                Ok(FileOutcome::Failed(String::new()))
+            })
+        })?;
+        errors.into_result()?;
+        stages.push(("encode", std::mem::replace(&mut stage_started, Instant::now()).elapsed()));
+
+        let mut files = Vec::with_capacity(work.len());
+        let mut sparse = Vec::new();
+        let mut sizes = Vec::new();
+        let mut bases = Vec::new();
+        let mut placeholders = Vec::new();
+        let mut failed = Vec::new();
+        for (work, outcome) in work.into_iter().zip(results) {
+            let (result, holes, duration) = match outcome {
+                FileOutcome::Diffed(result, holes, duration) => (result, holes, duration),
+                FileOutcome::Failed(error) => {
+                    let rel_path = work.into_path();
+                    failed.push(FailedFile::new(&rel_path, error, self.options.on_error.name()));
+                    if self.options.on_error == OnError::Skip {
+                        let target_path = self.target_delta_dir.join(&rel_path);
+                        if !self.options.dry_run {
+                            save_parent_modtime(&mut parent_modtime_save, &target_path)?;
+                            std::fs::remove_file(&target_path)
+                                .with_context(|| format!("failed removing {}", target_path.display()))?;
+                        }
+                        excluded.push(rel_path.as_os_str().as_bytes().to_owned());
+                    }
+                    continue;
+                }
+            };
+            let Some(result) = result.map(|result| *result) else { continue };
+            let rel_path = work.into_path();
+            total_size += result.total_size;
+            reduced_size += result.reduced_size;
+            files.push(FileReport::new(&rel_path, result.algo.as_ref(), result.total_size,
+                result.reduced_size, duration));
+
+            let rel_path = rel_path.as_os_str().as_bytes().to_owned();
+            if !result.checksum.is_empty() {
+                checksums.push((rel_path.clone(), result.checksum));
+            }
+            sizes.push((rel_path.clone(), result.total_size));
+            if !holes.is_empty() {
+                sparse.push((rel_path.clone(), holes));
+            }
+            if let Some(base) = result.base {
+                bases.push((rel_path.clone(), base));
+            }
+            match result.algo {
+                Some(algo) => changes.push((algo, rel_path)),
+                None => {
+                    let rel_path = path_from_bytes(&rel_path);
+                    if self.options.drop_placeholders && !self.options.dry_run && !linked.contains(&rel_path) {
+                        let meta_data = self.meta_data(&self.target_delta_dir.join(&rel_path))?;
+                        placeholders.push(Placeholder::new(&rel_path, meta_data));
+                    }
+                    keep_files.push(rel_path.as_os_str().as_bytes().to_owned());
+                }
+            }
+        }
+
+        // Whatever is left from the source was deleted in the target, unless
+        // it was replaced by something that is not a regular file
+        let deleted_files: Vec<_> = orig_files.into_iter()
+            .filter(|rel_path| std::fs::symlink_metadata(self.target_delta_dir.join(rel_path)).is_err())
+            .map(|rel_path| rel_path.as_os_str().as_bytes().to_owned())
+            .collect();
+        let deleted_count = deleted_files.len();
+
+        tracing::info!("Total size: {}", total_size);
+        tracing::info!("Reduced size: {}", reduced_size);
+        if !failed.is_empty() {
+            tracing::warn!("{} files could not be read and were handled as {}", failed.len(),
+                self.options.on_error.name());
+        }
+
+        if self.options.dry_run {
+            return Ok(DiffStats { total_size, reduced_size, files, duration: started.elapsed(),
+                failed, new_files, deleted_files: deleted_count, stages });
+        }
+
+        // Rewritten files no longer have the content of the target tree,
+        // which was digested already, as for the links to them
+        let mut known: HashMap<_, _> = checksums.iter()
+            .map(|(rel_path, checksum)| (path_from_bytes(rel_path), checksum.clone()))
+            .collect();
+        for (target_path, target_other_path) in links.iter() {
+            let checksum = known.get(&drop_components(n, target_other_path)).cloned();
+            if let Some(checksum) = checksum {
+                known.insert(drop_components(n, target_path), checksum);
+            }
+        }
+
+        for (target_path, target_other_path) in links {
+            let tmp_path = temp_path_for(&target_path);
+            std::fs::hard_link(target_other_path, &tmp_path)?;
+            std::fs::rename(&tmp_path, &target_path)
+                .with_context(|| format!("failed replacing {}",
+                        target_path.display()))?;
+        }
+
+        // Record the content of each group, for apply to only link files that
+        // are still identical
+        let mut recorded_link_groups = Vec::with_capacity(link_groups.len());
+        for paths in link_groups {
+            let rel_paths: Vec<_> = paths.iter().map(|path| path_from_bytes(path)).collect();
+            let checksum = match rel_paths.iter().find_map(|rel_path| known.get(rel_path)) {
+                Some(checksum) => checksum.clone(),
+                None => match rel_paths.iter().map(|rel_path| self.target_delta_dir.join(rel_path))
+                    .find(|path| path.is_file())
+                {
+                    Some(path) => digest_file(&path)?,
+                    // Left out of the delta altogether
+                    None => continue,
+                },
+            };
+            recorded_link_groups.push(LinkGroup { paths, checksum });
+        }
+
+        let mut md = MetaData {
+            format_version: META_FORMAT_VERSION,
+            keep_files,
+            changes,
+            checksums,
+            symlinks,
+            deleted_files,
+            directories,
+            excluded,
+            reverse: None,
+            journal: None,
+            sparse,
+            specials,
+            sizes,
+            xdelta3: Some(self.options.xdelta3.clone()),
+            // Files left as they are, unread, cannot be digested, and the
+            // digest covers ownership
+            tree_digest: match (failed.is_empty() || self.options.on_error == OnError::Skip)
+                && !self.options.ignore_owner && !self.options.trust_mtime
+            {
+                true => Some(tree_digest(&self.target_delta_dir,
+                    |rel_path| rel_path == Path::new(DIFF_JOURNAL_FILE) || filter.is_excluded(rel_path),
+                    &known)?),
+                false => None,
+            },
+            link_groups: Some(recorded_link_groups),
+            image_config: self.options.image_configs.as_ref()
+                .map(|(source, target)| ConfigDelta::new(source, target)),
+            apply_order: None,
+            fs_image: None,
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            signature: None,
+            encryption: cipher.is_some().then(|| CIPHER.to_owned()),
+            extra_sources: match bases.is_empty() {
+                true => vec![],
+                false => self.options.extra_sources.iter().map(|dir| dir.display().to_string()).collect(),
+            },
+            bases,
+            placeholders,
+            ignore_times: self.options.ignore_times,
+            ignore_owner: self.options.ignore_owner,
+        };
+        if let Some(key_path) = &self.options.sign_key {
+            signing::sign(&mut md, key_path)?;
+        }
+
+        restore_modtimes(parent_modtime_save)?;
+
+        md.save_as(&self.target_delta_dir, self.options.meta_format)?;
+        DiffJournal::remove(&self.target_delta_dir)?;
+
+        // An interrupted removal leaves whole files, which apply takes as placeholders
+        let mut parent_modtime_save = HashMap::new();
+        for placeholder in &md.placeholders {
+            let target_path = self.target_delta_dir.join(path_from_bytes(&placeholder.path));
+            save_parent_modtime(&mut parent_modtime_save, &target_path)?;
+            std::fs::remove_file(&target_path)
+                .with_context(|| format!("failed removing {}", target_path.display()))?;
+        }
+        restore_modtimes(parent_modtime_save)?;
+
+        if let Some(archive) = &self.options.archive {
+            pack_archive(&self.target_delta_dir, archive)?;
+        }
+
+        stages.push(("finish", stage_started.elapsed()));
+        Ok(DiffStats { total_size, reduced_size, files, duration: started.elapsed(), failed,
+            new_files, deleted_files: deleted_count, stages })
+    }
+
+    /// Meta-data of a target path, without the times and ownership that are
+    /// not to be recorded. Rewritten files then get zero times, and keep the
+    /// owner of the user running diff.
+    fn meta_data(&self, path: &Path) -> anyhow::Result<utils::MetaData> {
+        let (mut modified, mut accessed, mode, mut uid, mut gid, xattrs, ino, dev) = get_meta_data(path)
+            .context(FileErrorKind::MetaData)?;
+        if self.options.ignore_times {
+            (modified, accessed) = (Timestamp::default(), Timestamp::default());
+        }
+        if self.options.ignore_owner {
+            (uid, gid) = (UNCHANGED, UNCHANGED);
+        }
+        Ok((modified, accessed, mode, uid, gid, xattrs, ino, dev))
+    }
+
+    /// Refuse a target tree that diffing in place would turn into a corrupt
+    /// delta, as it is a delta already
+    fn check_target(&self) -> anyhow::Result<()> {
+        let journaled = self.target_delta_dir.join(DIFF_JOURNAL_FILE).exists();
+        if journaled && self.options.fresh {
+            return Err(Error::UnexpectedDiffJournal(self.target_delta_dir.clone()).into());
+        }
+        // Files of the trees where the meta-data file goes would be lost
+        if let Some(path) = meta_file_paths(&self.source_dir).into_iter()
+            .find(|path| std::fs::symlink_metadata(path).is_ok())
+        {
+            return Err(Error::MetaPathCollision(path).into());
+        }
+        if journaled {
+            return Ok(());
+        }
+        if MetaData::load(&self.target_delta_dir).is_ok() {
+            if !self.options.force {
+                return Err(Error::TargetIsDelta(self.target_delta_dir.clone()).into());
+            }
+        } else if let Some(path) = meta_file_paths(&self.target_delta_dir).into_iter()
+            .find(|path| std::fs::symlink_metadata(path).is_ok())
+        {
+            return Err(Error::MetaPathCollision(path).into());
+        }
+        Ok(())
+    }
+
+    /// Compute the reverse delta into a copy of the source tree first, while
+    /// the target tree is still intact, then the forward delta, and move the
+    /// former into the latter.
+    fn run_bidirectional(&self) -> anyhow::Result<DiffStats> {
+        let reverse_dir = temp_path_for(&self.target_delta_dir);
+        let reverse_done = MetaData::exists(&reverse_dir);
+        if std::fs::symlink_metadata(&reverse_dir).is_err() {
+            copy_tree(&self.source_dir, &reverse_dir)?;
+        } else if !reverse_done && !reverse_dir.join(DIFF_JOURNAL_FILE).exists() {
+            // Only the reverse delta of an interrupted diff can be picked up
+            return Err(Error::DeltaDirExists(reverse_dir).into());
+        }
+
+        // Signed once both deltas are in place
+        let options = DiffOptions { bidirectional: false, archive: None, sign_key: None,
+            ..self.options.clone() };
+        if !reverse_done {
+            let image_configs = self.options.image_configs.clone()
+                .map(|(source, target)| (target, source));
+            DeltaBuilder {
+                source_dir: self.target_delta_dir.clone(),
+                target_delta_dir: reverse_dir.clone(),
+                options: DiffOptions { image_configs, extra_sources: vec![], ..options.clone() },
+            }.run().context("failed computing the reverse delta")?;
+        }
+
+        // The forward delta may be complete already if interrupted right after
+        let forward_done = reverse_done && MetaData::exists(&self.target_delta_dir)
+            && !self.target_delta_dir.join(DIFF_JOURNAL_FILE).exists();
+        let stats = if forward_done {
+            DiffStats::default()
+        } else {
+            DeltaBuilder {
+                source_dir: self.source_dir.clone(),
+                target_delta_dir: self.target_delta_dir.clone(),
+                options,
+            }.run()?
+        };
+
+        let mut md = MetaData::load(&self.target_delta_dir)?;
+        md.reverse = Some(Box::new(MetaData::load(&reverse_dir)?));
+        MetaData::remove(&reverse_dir)?;
+        if let Some(key_path) = &self.options.sign_key {
+            signing::sign(&mut md, key_path)?;
+        }
+
+        let mut parent_modtime_save = HashMap::new();
+        let reverse_delta_path = self.target_delta_dir.join(REVERSE_DELTA_DIR);
+        save_parent_modtime(&mut parent_modtime_save, &reverse_delta_path)?;
+        std::fs::rename(&reverse_dir, &reverse_delta_path)
+            .with_context(|| format!("failed moving {}", reverse_dir.display()))?;
+        restore_modtimes(parent_modtime_save)?;
+
+        md.save_as(&self.target_delta_dir, self.options.meta_format)?;
+
+        if let Some(archive) = &self.options.archive {
+            pack_archive(&self.target_delta_dir, archive)?;
+        }
+
+        Ok(stats)
+    }
+
+    /// Look for a source file with the same content as a new target file, in
+    /// which case only a placeholder referring to it is kept. Otherwise, try
+    /// to encode it against the most similar source file.
+    fn diff_new_file(&self, rel_path: &Path, source_index: &SourceIndex) -> anyhow::Result<Option<FileDiff>> {
+        let target_path = self.target_delta_dir.join(rel_path);
+        // Taken before reading the file, which may update its access time
+        let meta_data = self.meta_data(&target_path)?;
+        let total_size = target_path.metadata()?.len();
+        let checksum = digest_file(&target_path)?;
+
+        let found = if self.options.detect_renames {
+            source_index.find(&self.source_dir, total_size, &checksum)?
+        } else {
+            None
+        };
+        let Some(src_rel_path) = found else {
+            if self.options.pair_similar && total_size < self.options.stream_threshold {
+                return self.diff_similar_file(rel_path, source_index, meta_data, total_size,
+                    checksum);
+            }
+            return Ok(None);
+        };
+
+        tracing::debug!("Renamed {} <- {}", rel_path.display(), src_rel_path.display());
+
+        let algo = Algo::CopyFrom(src_rel_path.as_os_str().as_bytes().to_owned());
+        let rewrite = Some(Rewrite::Content(vec![], meta_data));
+
+        Ok(Some(FileDiff { algo: Some(algo), total_size, reduced_size: 0, checksum, rewrite, base: None }))
+    }
+
+    /// Store a new target file compressed, with `compress_new_files`, or
+    /// whole if `encrypted`, so that it gets encrypted along with the stored
+    /// files. Otherwise, it is left as it is.
+    fn store_new_file(&self, rel_path: &Path, encrypted: bool) -> anyhow::Result<Option<FileDiff>> {
+        let target_path = self.target_delta_dir.join(rel_path);
+        let meta_data = self.meta_data(&target_path)?;
+        let total_size = target_path.metadata()?.len();
+
+        if self.options.compress_new_files && total_size < self.options.stream_threshold {
+            let content = read_noatime(&target_path)?;
+            let checksum = digest_bytes(&content);
+            let compressed = zstd::stream::encode_all(&content[..], self.options.compression_level)
+                .with_context(|| format!("failed to compress {}", target_path.display()))?;
+            if compressed.len() < content.len() {
+                tracing::debug!("Compressed {}: {} -> {}", rel_path.display(), total_size, compressed.len());
+
+                let reduced_size = compressed.len() as u64;
+                let rewrite = Some(Rewrite::Content(compressed, meta_data));
+                return Ok(Some(FileDiff { algo: Some(Algo::Zstd), total_size, reduced_size, checksum,
+                    rewrite, base: None }));
+            }
+        }
+        if !encrypted {
+            return Ok(None);
+        }
+
+        let checksum = digest_file(&target_path)?;
+        tracing::debug!("Stored {}: {}", rel_path.display(), total_size);
+
+        let rewrite = Some(Rewrite::Original(meta_data));
+        Ok(Some(FileDiff { algo: Some(Algo::AsIs), total_size, reduced_size: total_size, checksum, rewrite,
+            base: None }))
+    }
+
+    /// Encode a new target file against the source file that resembles it
+    /// the most, if the resulting patch is smaller than the file itself.
+    fn diff_similar_file(&self, rel_path: &Path, source_index: &SourceIndex,
+        meta_data: utils::MetaData, total_size: u64, checksum: String)
+        -> anyhow::Result<Option<FileDiff>>
+    {
+        let target_path = self.target_delta_dir.join(rel_path);
+        let Some((src_rel_path, similarity)) = source_index.find_similar(&self.source_dir,
+            &target_path, total_size, self.options.stream_threshold)? else {
+            return Ok(None);
+        };
+
+        let old_content = std::fs::read(self.source_dir.join(&src_rel_path))?;
+        let new_content = read_noatime(&target_path)?;
+        let delta = xdelta::encode(&new_content, &old_content, &self.options.xdelta3)
+            .ok_or(Error::XDelta3EncodeError)?;
+
+        match xdelta3::decode(&delta, &old_content) {
+            Some(deflated_content) if deflated_content != new_content => {
+                return Err(Error::XDelta3FailedValidation(self.source_dir.join(&src_rel_path),
+                    target_path).into());
+            }
+            Some(_) if (delta.len() as u64) < total_size => {}
+            // Leave the new file as it is
+            _ => return Ok(None),
+        }
+
+        tracing::debug!("Paired {} ~ {} ({:.2}): {} -> {}", rel_path.display(),
+            src_rel_path.display(), similarity, total_size, delta.len());
+
+        let reduced_size = delta.len() as u64;
+        let rewrite = Some(Rewrite::Content(delta, meta_data));
+
+        let algo = Algo::XDelta3From(src_rel_path.as_os_str().as_bytes().to_owned());
+        Ok(Some(FileDiff { algo: Some(algo), total_size, reduced_size, checksum, rewrite, base: None }))
+    }
+
+    pub(crate) fn diff_file(&self, rel_path: &Path) -> anyhow::Result<FileDiff> {
+        let src_path = self.source_dir.join(rel_path);
+        let target_path = self.target_delta_dir.join(rel_path);
+        let meta_data = self.meta_data(&target_path)?;
+
+        let total_size = target_path.metadata()?.len();
+        let source_size = src_path.metadata()?.len();
+        if total_size.max(source_size) >= self.options.stream_threshold.min(self.options.patch_from_threshold) {
+            return diff_file_streamed(&self.options, rel_path, &src_path, &target_path, meta_data);
+        }
+
+        // Unmodified files are found without reading them into memory
+        if total_size == source_size && mmap::files_equal(&src_path, &target_path)? {
+            let checksum = digest_file(&target_path)?;
+            return keep_placeholder(rel_path, meta_data, total_size, checksum);
+        }
+
+        let old_content = std::fs::read(&src_path)?;
+        let new_content = read_noatime(&target_path)?;
+        let checksum = digest_bytes(&new_content);
+
+        if old_content != new_content {
+            // Modified files, keep only the changes, unless already encoded
+            // by a previous diff
+            let cache_entry = CacheEntry::new(&self.options, || Ok(digest_bytes(&old_content)),
+                &checksum)?;
+            let cached = cache_entry.as_ref()
+                .and_then(|entry| entry.load(&old_content, &new_content));
+            let (algo, delta) = match cached {
+                Some(cached) => cached,
+                None => {
+                    let encoded = encode(&self.options, &src_path, &target_path, &old_content,
+                        &new_content)?;
+                    if let Some(entry) = &cache_entry {
+                        entry.store(&encoded.0, &encoded.1)?;
+                    }
+                    encoded
+                }
+            };
+
+            tracing::debug!("Modified {}: {} {} -> {} ({})", rel_path.display(),
+                old_content.len(), new_content.len(), delta.len(), algo.name());
+
+            let (algo, delta, base) = match self.encode_against_extra_sources(rel_path, &target_path,
+                &new_content, delta.len())?
+            {
+                Some((base, algo, delta)) => (algo, delta, Some(base)),
+                None => (algo, delta, None),
+            };
+            let reduced_size = delta.len() as u64;
+
+            // The changes are written on commit, the meta-data of the original file are copied
+            let rewrite = Some(Rewrite::Content(delta, meta_data));
+
+            Ok(FileDiff { algo: Some(algo), total_size, reduced_size, checksum, rewrite, base })
+        } else {
+            keep_placeholder(rel_path, meta_data, total_size, checksum)
+        }
+    }
+
+    /// Patch of a modified file against the extra source giving the smallest
+    /// one, if smaller than `best_size`, with the index of that extra source
+    fn encode_against_extra_sources(&self, rel_path: &Path, target_path: &Path, new_content: &[u8],
+        mut best_size: usize) -> anyhow::Result<Option<(u32, Algo, Vec<u8>)>>
+    {
+        let mut best = None;
+        for (index, extra_source) in self.options.extra_sources.iter().enumerate() {
+            let base_path = extra_source.join(rel_path);
+            if !std::fs::symlink_metadata(&base_path).is_ok_and(|metadata| metadata.is_file()) {
+                continue;
+            }
+            let base_content = std::fs::read(&base_path)?;
+            let (algo, delta) = encode(&self.options, &base_path, target_path, &base_content,
+                new_content)?;
+            // Content stored whole does not depend on any source
+            let patched = backend::for_algo(&algo).is_some_and(|backend| backend.uses_source());
+            if patched && delta.len() < best_size {
+                best_size = delta.len();
+                best = Some((index as u32, algo, delta));
+            }
+        }
+
+        if let Some((index, algo, _)) = &best {
+            tracing::debug!("Patched {} against {} ({})", rel_path.display(),
+                self.options.extra_sources[*index as usize].display(), algo.name());
+        }
+        Ok(best)
+    }
+}
+
+/// Groups of the given files, none of them hardlinked, that have the same
+/// content, permissions, ownership and xattrs, and can be linked together
+fn identical_files(target_dir: &Path, candidates: Vec<(PathBuf, u64)>, jobs: usize)
+    -> anyhow::Result<Vec<Vec<PathBuf>>>
+{
+    let mut by_size: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    for (rel_path, size) in candidates {
+        by_size.entry(size).or_default().push(rel_path);
+    }
+    let work: Vec<PathBuf> = by_size.into_values()
+        .filter(|paths| paths.len() >= 2)
+        .flatten()
+        .collect();
+
+    let keys = parallel_map(jobs, &work, |rel_path| {
+        let path = target_dir.join(rel_path);
+        let (_, _, mode, uid, gid, xattrs, _, _) = get_meta_data(&path)?;
+        Ok((digest_file(&path)?, mode, uid, gid, xattrs))
+    })?;
+
+    let mut groups: HashMap<_, Vec<PathBuf>> = HashMap::new();
+    for (rel_path, key) in work.into_iter().zip(keys) {
+        groups.entry(key).or_default().push(rel_path);
+    }
+    let groups: Vec<_> = groups.into_values().filter(|paths| paths.len() >= 2).collect();
+    tracing::info!("Identical files to link: {}", groups.iter().map(|paths| paths.len() - 1).sum::<usize>());
+    Ok(groups)
+}
+
+/// Encode a modified file with the backend giving the smallest valid
+/// patch. Several backends are tried in parallel.
+pub(crate) fn encode(options: &DiffOptions, src_path: &Path, target_path: &Path,
+    old_content: &[u8], new_content: &[u8]) -> anyhow::Result<(Algo, Vec<u8>)>
+{
+    // Store the new content on its own, compressed unless that does not help
+    let store_whole = || -> anyhow::Result<(&dyn Backend, Vec<u8>)> {
+        let [compressed, as_is] = backend::fallbacks();
+        let content = compressed.encode(old_content, new_content, options)
+            .with_context(|| format!("failed to compress {}", target_path.display()))?;
+        Ok(match content.len() < new_content.len() {
+            true => (compressed, content),
+            false => (as_is, new_content.to_owned()),
+        })
+    };
+
+    if (new_content.len() as u64) < options.min_file_size {
+        let (backend, content) = store_whole()?;
+        return Ok((backend.stored_algo(old_content, new_content), content));
+    }
+
+    let candidates = backend::candidates(new_content, options);
+    let trial = |backend: &dyn Backend| {
+        let delta = backend.encode(old_content, new_content, options)?;
+        let valid = backend.decode(old_content, &delta)
+            .map(|deflated_content| deflated_content == new_content)?;
+        Some((delta, valid))
+    };
+    let trials = match candidates.len() {
+        1 => vec![trial(candidates[0])],
+        _ => std::thread::scope(|scope| {
+            let handles: Vec<_> = candidates.iter()
+                .map(|backend| scope.spawn(|| trial(*backend)))
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        }),
+    };
+
+    let mut best: Option<(&dyn Backend, Vec<u8>)> = None;
+    for (backend, trial) in candidates.into_iter().zip(trials) {
+        let Some((delta, valid)) = trial else { continue };
+        if !valid {
+            return Err(Error::FailedValidation(backend.algo().name(), src_path.to_owned(),
+                target_path.to_owned()).into());
+        }
+        if best.as_ref().map(|(_, best)| delta.len() < best.len()).unwrap_or(true) {
+            best = Some((backend, delta));
+        }
+    }
+
+    // Patches saving too little are not worth the work of applying them
+    let max_delta_size = new_content.len() as f64 * (1.0 - options.min_delta_ratio);
+    let (backend, delta) = match best {
+        Some(best) if best.1.len() as f64 <= max_delta_size => best,
+        _ => {
+            let best = store_whole()?;
+            tracing::debug!("Fallback to {} {}", best.0.algo().name(), target_path.display());
+            best
+        }
+    };
+
+    Ok((backend.stored_algo(old_content, new_content), delta))
+}
+
+/// Remove the excluded paths from the target tree, leaving the directories
+/// that still hold included paths.
+fn remove_excluded(target_delta_dir: &Path, filter: &PathFilter) -> anyhow::Result<()> {
+    let mut parent_modtime_save = HashMap::new();
+    let n = target_delta_dir.components().count();
+
+    for entry in WalkDir::new(target_delta_dir).contents_first(true) {
+        let entry = entry?;
+        let path = entry.path();
+        if !filter.is_excluded(&drop_components(n, path)) {
+            continue;
+        }
+
+        save_parent_modtime(&mut parent_modtime_save, path)?;
+        if entry.file_type().is_dir() {
+            if std::fs::read_dir(path)?.next().is_none() {
+                std::fs::remove_dir(path)
+                    .with_context(|| format!("failed removing {}", path.display()))?;
+            }
+        } else {
+            std::fs::remove_file(path)
+                .with_context(|| format!("failed removing {}", path.display()))?;
+        }
+    }
+
+    // Directories that were removed themselves have nothing to restore
+    parent_modtime_save.retain(|pathname, _| pathname.exists());
+    restore_modtimes(parent_modtime_save)
+}
+
+pub(crate) struct FileDiff {
+    /// `None` if the file is unmodified and kept as a placeholder
+    pub(crate) algo: Option<Algo>,
+    pub(crate) total_size: u64,
+    pub(crate) reduced_size: u64,
+    /// Digest of the original target file
+    pub(crate) checksum: String,
+    /// Replacement of the target file, pending until committed
+    pub(crate) rewrite: Option<Rewrite>,
+    /// Index of the extra source that the file is patched against, if not
+    /// the source
+    pub(crate) base: Option<u32>,
+}
+
+/// New content of a target file, along with the meta-data of the original file
+pub(crate) enum Rewrite {
+    Content(Vec<u8>, utils::MetaData),
+    /// Already written to the temporary path of the target file
+    Staged(utils::MetaData),
+    /// The original content, to be encrypted
+    Original(utils::MetaData),
+}
+
+impl FileDiff {
+    /// Replace the target file with its rewrite. The replacement is staged
+    /// next to it and renamed over it, so that an interruption leaves either
+    /// the original file or the rewritten one.
+    ///
+    /// With a cipher, stored content is encrypted, leaving placeholders empty.
+    pub(crate) fn commit(&mut self, target_path: &Path, cipher: Option<&Cipher>) -> anyhow::Result<()> {
+        let tmp_path = temp_path_for(target_path);
+        let stored = !matches!(self.algo, None | Some(Algo::CopyFrom(_)));
+        let meta_data = match (self.rewrite.take(), cipher.filter(|_| stored)) {
+            (None, _) => return Ok(()),
+            (Some(Rewrite::Content(content, meta_data)), cipher) => {
+                let content = match cipher {
+                    Some(cipher) => cipher.encrypt(&content)?,
+                    None => content,
+                };
+                std::fs::write(&tmp_path, content)
+                    .with_context(|| format!("failed to write to {}",
+                            tmp_path.display()))?;
+                meta_data
+            }
+            (Some(Rewrite::Staged(meta_data)), None) => meta_data,
+            (Some(Rewrite::Staged(meta_data)), Some(cipher)) => {
+                let encrypted_path = temp_path_for(&tmp_path);
+                cipher.encrypt_file(&tmp_path, &encrypted_path)?;
+                std::fs::rename(&encrypted_path, &tmp_path)?;
+                meta_data
+            }
+            (Some(Rewrite::Original(meta_data)), cipher) => {
+                let cipher = cipher.expect("kept whole only to be encrypted");
+                cipher.encrypt_file(target_path, &tmp_path)?;
+                meta_data
+            }
+        };
+
+        set_meta_data(&tmp_path, meta_data)
+            .with_context(|| format!("failed to set meta-data to {}",
+                    tmp_path.display()))
+            .context(FileErrorKind::MetaData)?;
+        std::fs::rename(&tmp_path, target_path)
+            .with_context(|| format!("failed to replace {}",
+                    target_path.display()))?;
+
+        Ok(())
+    }
+}
+
+impl From<DiffJournalEntry> for FileDiff {
+    /// Outcome of a file rewritten by an interrupted diff
+    fn from(entry: DiffJournalEntry) -> Self {
+        Self {
+            algo: entry.algo,
+            total_size: entry.total_size,
+            reduced_size: entry.reduced_size,
+            checksum: entry.checksum,
+            rewrite: None,
+            base: entry.base,
+        }
+    }
+}
+
+/// Whether a target file is still the original one, as opposed to the
+/// rewrite journaled by an interrupted diff
+fn is_original(target_path: &Path, entry: &DiffJournalEntry) -> anyhow::Result<bool> {
+    Ok(target_path.metadata()?.len() == entry.total_size
+        && digest_file(target_path)? == entry.checksum)
+}
+
+/// Remove the temporary files of rewrites that were interrupted before being
+/// committed
+fn remove_stale_temps(target_delta_dir: &Path) -> anyhow::Result<()> {
+    let mut parent_modtime_save = HashMap::new();
+    for entry in WalkDir::new(target_delta_dir) {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type().is_file() && is_temp_path(path) {
+            save_parent_modtime(&mut parent_modtime_save, path)?;
+            std::fs::remove_file(path)
+                .with_context(|| format!("failed removing {}", path.display()))?;
+        }
+    }
+    restore_modtimes(parent_modtime_save)
+}
+
+/// Encode a file too large to be held in memory, into its temporary path:
+/// in chunks with xdelta3, or with zstd patch-from above the patch-from
+/// threshold
+fn diff_file_streamed(options: &DiffOptions, rel_path: &Path, src_path: &Path,
+    target_path: &Path, meta_data: utils::MetaData) -> anyhow::Result<FileDiff>
+{
+    let total_size = target_path.metadata()?.len();
+    let source_size = src_path.metadata()?.len();
+    let checksum = digest_file(target_path)?;
+
+    if mmap::files_equal(src_path, target_path)? {
+        return keep_placeholder(rel_path, meta_data, total_size, checksum);
+    }
+
+    let tmp_path = temp_path_for(target_path);
+    let cache_entry = match options.dry_run {
+        true => None,
+        false => CacheEntry::new(options, || digest_file(src_path), &checksum)?,
+    };
+    let cached = cache_entry.as_ref().and_then(|entry| entry.load_to_file(src_path, &checksum, &tmp_path));
+    let hit = cached.is_some();
+
+    let (algo, reduced_size) = match cached {
+        Some(cached) => cached,
+        None if total_size.max(source_size) >= options.patch_from_threshold => {
+            let window_log = patch_from::window_log(source_size, total_size);
+            let level = options.compression_level;
+            let reduced_size = if options.dry_run {
+                patch_from::encode(src_path, target_path, &mut std::io::sink(), level, window_log)?
+            } else {
+                let reduced_size = patch_from::encode_to_file(src_path, target_path, &tmp_path,
+                    level, window_log)?;
+                let mut hasher = Sha256::new();
+                patch_from::decode(src_path, &tmp_path, &mut hasher, window_log)?;
+                if format!("{:x}", hasher.finalize()) != checksum {
+                    return Err(Error::FailedValidation("zstd-patch-from", src_path.to_owned(),
+                        target_path.to_owned()).into());
+                }
+                reduced_size
+            };
+            (Algo::ZstdPatchFrom(window_log), reduced_size)
+        }
+        None => {
+            let reduced_size = if options.dry_run {
+                stream::encode(src_path, target_path, &mut std::io::sink(), &options.xdelta3)?
+            } else {
+                stream::encode_to_file(src_path, target_path, &tmp_path, &options.xdelta3)?
+            };
+            (Algo::XDelta3Chunked(options.xdelta3.window), reduced_size)
+        }
+    };
+    if let (false, Some(entry)) = (hit, &cache_entry) {
+        entry.store_file(&algo, &tmp_path)?;
+    }
+
+    tracing::debug!("Modified {}: {} {} -> {} ({})", rel_path.display(), source_size, total_size,
+        reduced_size, algo.name());
+
+    Ok(FileDiff {
+        algo: Some(algo),
+        total_size,
+        reduced_size,
+        checksum,
+        rewrite: (!options.dry_run).then_some(Rewrite::Staged(meta_data)),
+        base: None,
+    })
+}
+
+/// Size and SHA-256 of a target file with the same content as the source
+/// file, as told by their sizes and BLAKE3 digests, reading the target file
+/// once for both digests
+fn prehash_unchanged(src_path: &Path, target_path: &Path) -> anyhow::Result<Option<(u64, String)>> {
+    let size = target_path.metadata()?.len();
+    if src_path.metadata()?.len() != size {
+        return Ok(None);
+    }
+
+    let mut source_hasher = blake3::Hasher::new();
+    std::io::copy(&mut open_noatime(src_path)?, &mut source_hasher)?;
+
+    let (mut target_hasher, mut checksum) = (blake3::Hasher::new(), Sha256::new());
+    let mut target = open_noatime(target_path)?;
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let nread = target.read(&mut buf)?;
+        if nread == 0 {
+            break;
+        }
+        target_hasher.update(&buf[..nread]);
+        checksum.update(&buf[..nread]);
+    }
+
+    Ok((source_hasher.finalize() == target_hasher.finalize())
+        .then(|| (size, format!("{:x}", checksum.finalize()))))
+}
+
+/// Size of a target file with the same size and modification time as the
+/// source file, which is taken to have the same content without reading it
+fn quick_check_unchanged(src_path: &Path, target_path: &Path) -> anyhow::Result<Option<u64>> {
+    let (src, target) = (src_path.metadata()?, target_path.metadata()?);
+    Ok((src.len() == target.len() && src.modified()? == target.modified()?).then_some(target.len()))
+}
+
+fn keep_placeholder(rel_path: &Path, meta_data: utils::MetaData, total_size: u64, checksum: String)
+    -> anyhow::Result<FileDiff>
+{
+    // File not modified - keep a zero-sized file just for meta-data
+
+    tracing::debug!("Keep {}: {}", rel_path.display(), total_size);
+
+    let rewrite = Some(Rewrite::Content(vec![], meta_data));
+
+    Ok(FileDiff { algo: None, total_size, reduced_size: 0, checksum, rewrite, base: None })
+}
+
+/// Outcome of the parallel stage of diff for one file
+enum FileOutcome {
+    /// How the file is stored, if it is, with its holes and the time taken
+    Diffed(Option<Box<FileDiff>>, Holes, Duration),
+    /// The file could not be read, with the error
+    Failed(String),
+}
+
+/// Work item of the parallel stage of diff
+enum Work {
+    /// File that exists in both trees
+    Compare(PathBuf),
+    /// File that exists only in the target tree
+    New(PathBuf),
+}
+
+impl Work {
+    fn path(&self) -> &Path {
+        match self {
+            Work::Compare(path) | Work::New(path) => path,
+        }
+    }
+
+    fn into_path(self) -> PathBuf {
+        match self {
+            Work::Compare(path) | Work::New(path) => path,
+        }
+    }
+}
+
+/// Least estimated resemblance for a source file to be used as the base of a
+/// new target file
+const MIN_SIMILARITY: f64 = 0.25;
+
+/// Source files by size, with their digests and sketches computed on demand,
+/// to find the origin of files that were moved or renamed.
+#[derive(Default)]
+struct SourceIndex {
+    by_size: BTreeMap<u64, Vec<PathBuf>>,
+    digests: Mutex<HashMap<PathBuf, String>>,
+    sketches: Mutex<HashMap<PathBuf, Sketch>>,
+}
+
+impl SourceIndex {
+    fn insert(&mut self, size: u64, rel_path: &Path) {
+        // Empty files cost nothing to store anyway
+        if size > 0 {
+            self.by_size.entry(size).or_default().push(rel_path.to_owned());
+        }
+    }
+
+    fn has_size(&self, size: u64) -> bool {
+        self.by_size.contains_key(&size)
+    }
+
+    fn find(&self, source_dir: &Path, size: u64, checksum: &str) -> anyhow::Result<Option<PathBuf>> {
+        for candidate in self.by_size.get(&size).into_iter().flatten() {
+            let cached = self.digests.lock().unwrap().get(candidate).cloned();
+            let digest = match cached {
+                Some(digest) => digest,
+                None => {
+                    let digest = digest_file(&source_dir.join(candidate))?;
+                    self.digests.lock().unwrap().insert(candidate.clone(), digest.clone());
+                    digest
+                }
+            };
+            if digest == checksum {
+                return Ok(Some(candidate.clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Find the source file that resembles the target file the most, among
+    /// the ones between half and twice its size.
+    fn find_similar(&self, source_dir: &Path, target_path: &Path, size: u64, max_size: u64)
+        -> anyhow::Result<Option<(PathBuf, f64)>>
+    {
+        let range = size / 2..=size.saturating_mul(2).min(max_size.saturating_sub(1));
+        if range.is_empty() {
+            return Ok(None);
+        }
+
+        let mut target_sketch = None;
+        let mut best: Option<(PathBuf, f64)> = None;
+        for candidate in self.by_size.range(range).flat_map(|(_, paths)| paths) {
+            let target_sketch = match &target_sketch {
+                Some(sketch) => sketch,
+                None => target_sketch.insert(Sketch::of_file(target_path)
+                    .with_context(|| format!("failed to read {}", target_path.display()))?),
+            };
+
+            let cached = self.sketches.lock().unwrap().get(candidate).cloned();
+            let sketch = match cached {
+                Some(sketch) => sketch,
+                None => {
+                    let path = source_dir.join(candidate);
+                    let sketch = Sketch::of_file(&path)
+                        .with_context(|| format!("failed to read {}", path.display()))?;
+                    self.sketches.lock().unwrap().insert(candidate.clone(), sketch.clone());
+                    sketch
+                }
+            };
+
+            let similarity = target_sketch.similarity(&sketch);
+            if similarity >= MIN_SIMILARITY && !matches!(&best, Some((_, s)) if *s >= similarity) {
+                best = Some((candidate.clone(), similarity));
+            }
+        }
+
+        Ok(best)
+    }
+}
+
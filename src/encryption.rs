@@ -0,0 +1,204 @@
+//! AES-256-GCM encryption of the files stored in a delta directory, so that
+//! deltas of proprietary images can be served from public mirrors.
+//!
+//! Files are sealed in chunks of `CHUNK_SIZE` bytes, each under a nonce made
+//! of a random prefix kept at the start of the file, the index of the chunk
+//! and whether it is the last one, so that chunks cannot be reordered, mixed
+//! between files or truncated unnoticed. The last chunk is always shorter
+//! than a full one, and empty if the content is a multiple of the chunk size.
+//! Keys are stored hex-encoded in text files, like the signing keys.
+//!
+//! Without the `encryption` feature, deltas can be neither encrypted nor
+//! decrypted, and loading a key fails.
+
+use std::io::{Read, Write};
+use std::path::Path;
+
+#[cfg(feature = "encryption")]
+use aes_gcm::aead::{Aead, KeyInit};
+#[cfg(feature = "encryption")]
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::Context;
+
+use crate::Error;
+use crate::metadata::MetaData;
+#[cfg(feature = "encryption")]
+use crate::signing::read_key;
+use crate::signing::{random_bytes, write_secret_key};
+use crate::utils::open_noatime;
+
+/// Name of the cipher as recorded in the meta-data
+pub(crate) const CIPHER: &str = "aes-256-gcm";
+
+#[cfg(feature = "encryption")]
+const CHUNK_SIZE: usize = 1 << 20;
+#[cfg(feature = "encryption")]
+const PREFIX_SIZE: usize = 7;
+#[cfg(feature = "encryption")]
+const TAG_SIZE: usize = 16;
+
+#[cfg(feature = "encryption")]
+pub(crate) struct Cipher(Aes256Gcm);
+
+/// No cipher can be had without the `encryption` feature
+#[cfg(not(feature = "encryption"))]
+pub(crate) enum Cipher {}
+
+impl Cipher {
+    /// Cipher with the key stored at `key_path`
+    #[cfg(feature = "encryption")]
+    pub(crate) fn load(key_path: &Path) -> anyhow::Result<Self> {
+        let key: [u8; 32] = read_key(key_path)?;
+        Ok(Self(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))))
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    pub(crate) fn load(_key_path: &Path) -> anyhow::Result<Self> {
+        Err(Error::FeatureDisabled("encryption").into())
+    }
+
+    /// Cipher decrypting the files of the delta directory of `md`, if they
+    /// are encrypted
+    pub(crate) fn for_delta(md: &MetaData, delta_dir: &Path, key_path: Option<&Path>)
+        -> anyhow::Result<Option<Self>>
+    {
+        match (&md.encryption, key_path) {
+            (None, _) => Ok(None),
+            (Some(cipher), _) if cipher != CIPHER => Err(Error::UnsupportedCipher(cipher.clone()).into()),
+            (Some(_), None) => Err(Error::MissingDecryptionKey(delta_dir.to_owned()).into()),
+            (Some(_), Some(key_path)) => Self::load(key_path).map(Some),
+        }
+    }
+
+    /// Encrypt everything read from `input` to `output`, returning the number
+    /// of bytes written
+    #[cfg(feature = "encryption")]
+    pub(crate) fn encrypt_to(&self, mut input: impl Read, mut output: impl Write) -> anyhow::Result<u64> {
+        let prefix: [u8; PREFIX_SIZE] = random_bytes()?;
+        output.write_all(&prefix)?;
+        let mut written = PREFIX_SIZE as u64;
+
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        for index in 0.. {
+            let len = read_full(&mut input, &mut chunk)?;
+            let last = len < CHUNK_SIZE;
+            let sealed = self.0.encrypt(Nonce::from_slice(&nonce(&prefix, index, last)), &chunk[..len])
+                .expect("chunk within the limits of AES-GCM");
+            output.write_all(&sealed)?;
+            written += sealed.len() as u64;
+            if last {
+                break;
+            }
+        }
+
+        Ok(written)
+    }
+
+    /// Decrypt everything read from `input`, the content of the file at
+    /// `path`, to `output`, returning the number of bytes written
+    #[cfg(feature = "encryption")]
+    pub(crate) fn decrypt_to(&self, mut input: impl Read, mut output: impl Write, path: &Path)
+        -> anyhow::Result<u64>
+    {
+        let failed = || Error::DecryptionFailed(path.to_owned());
+        let mut prefix = [0u8; PREFIX_SIZE];
+        if read_full(&mut input, &mut prefix)? < PREFIX_SIZE {
+            return Err(failed().into());
+        }
+        let mut written = 0;
+
+        let mut chunk = vec![0u8; CHUNK_SIZE + TAG_SIZE];
+        for index in 0.. {
+            let len = read_full(&mut input, &mut chunk)?;
+            let last = len < chunk.len();
+            let opened = self.0.decrypt(Nonce::from_slice(&nonce(&prefix, index, last)), &chunk[..len])
+                .map_err(|_| failed())?;
+            output.write_all(&opened)?;
+            written += opened.len() as u64;
+            if last {
+                break;
+            }
+        }
+
+        Ok(written)
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    pub(crate) fn encrypt_to(&self, _input: impl Read, _output: impl Write) -> anyhow::Result<u64> {
+        match *self {}
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    pub(crate) fn decrypt_to(&self, _input: impl Read, _output: impl Write, _path: &Path)
+        -> anyhow::Result<u64>
+    {
+        match *self {}
+    }
+
+    pub(crate) fn encrypt(&self, content: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut sealed = vec![];
+        self.encrypt_to(content, &mut sealed)?;
+        Ok(sealed)
+    }
+
+    /// Encrypt the file at `path` into a new file at `output_path`
+    pub(crate) fn encrypt_file(&self, path: &Path, output_path: &Path) -> anyhow::Result<u64> {
+        let file = open_noatime(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        let output = std::fs::File::create(output_path)
+            .with_context(|| format!("failed to create {}", output_path.display()))?;
+        let mut output = std::io::BufWriter::new(output);
+        let written = self.encrypt_to(std::io::BufReader::new(file), &mut output)?;
+        output.flush()?;
+        Ok(written)
+    }
+
+    /// Decrypt the content of the file at `path`
+    pub(crate) fn decrypt_file(&self, path: &Path) -> anyhow::Result<Vec<u8>> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        let mut content = vec![];
+        self.decrypt_to(std::io::BufReader::new(file), &mut content, path)?;
+        Ok(content)
+    }
+
+    /// Decrypt the file at `path` into `output`, a newly created file
+    pub(crate) fn decrypt_file_to(&self, path: &Path, output: std::fs::File) -> anyhow::Result<u64> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        let mut output = std::io::BufWriter::new(output);
+        let written = self.decrypt_to(std::io::BufReader::new(file), &mut output, path)?;
+        output.flush()?;
+        Ok(written)
+    }
+}
+
+#[cfg(feature = "encryption")]
+fn nonce(prefix: &[u8; PREFIX_SIZE], index: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[PREFIX_SIZE..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+/// Read until `buf` is full or the end of `input`, returning the number of
+/// bytes read
+#[cfg(feature = "encryption")]
+fn read_full(input: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match input.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// Generate a new key for encrypting deltas, readable only by the current user
+pub fn generate_encryption_key(key_path: &Path) -> anyhow::Result<()> {
+    write_secret_key(key_path, &random_bytes::<32>()?)
+}
@@ -0,0 +1,222 @@
+//! Moving images between hosts as deltas, through the image stores of local
+//! container engines.
+//!
+//! `save_delta` exports both images with `docker save` (or its equivalent),
+//! and writes a thin delta image holding only the delta layer. On the
+//! receiving host, `load_delta` exports the source image from the local store,
+//! restores the target image and imports it back with `docker load`.
+//!
+//! `diff_containers` diffs the root filesystems of two containers instead,
+//! such as one modified by hand from the other, without committing them.
+
+use std::io::BufReader;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+use anyhow::Context;
+
+use crate::Error;
+use crate::apply::{ApplyOptions, ApplyStats};
+use crate::diff::{DeltaBuilder, DiffOptions, DiffStats};
+use crate::oci::{apply_oci_images, diff_oci_images, unpack_tree, ImageNames, WorkDir};
+use crate::platform;
+use crate::utils::copy_tree;
+
+/// Container engine holding the local images
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    Docker,
+    Podman,
+    /// containerd, through `ctr`
+    Containerd,
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "docker" => Ok(Engine::Docker),
+            "podman" => Ok(Engine::Podman),
+            "containerd" => Ok(Engine::Containerd),
+            _ => Err(format!("unknown container engine {}", s)),
+        }
+    }
+}
+
+impl Engine {
+    fn program(&self) -> &'static str {
+        match self {
+            Engine::Docker => "docker",
+            Engine::Podman => "podman",
+            Engine::Containerd => "ctr",
+        }
+    }
+
+    pub(crate) fn run(&self, args: &[&str]) -> anyhow::Result<()> {
+        let program = self.program();
+        let command = format!("{} {}", program, args.join(" "));
+        let status = Command::new(program).args(args).status()
+            .with_context(|| format!("failed to run {}", program))?;
+        if !status.success() {
+            return Err(Error::EngineCommandFailed(command).into());
+        }
+        Ok(())
+    }
+
+    /// Run a command, returning what it printed
+    pub(crate) fn output(&self, args: &[&str]) -> anyhow::Result<String> {
+        let program = self.program();
+        let command = format!("{} {}", program, args.join(" "));
+        let output = Command::new(program).args(args).stderr(Stdio::inherit()).output()
+            .with_context(|| format!("failed to run {}", program))?;
+        if !output.status.success() {
+            return Err(Error::EngineCommandFailed(command).into());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Write a tarball of a local image to `path`
+    pub fn export_image(&self, image: &str, path: &Path) -> anyhow::Result<()> {
+        let path = path.to_string_lossy();
+        match self {
+            Engine::Docker | Engine::Podman => self.run(&["save", "-o", &path, image]),
+            Engine::Containerd => self.run(&["images", "export", &path, image]),
+        }
+    }
+
+    /// Add the image of the tarball at `path` to the local store
+    pub fn import_image(&self, path: &Path) -> anyhow::Result<()> {
+        let path = path.to_string_lossy();
+        match self {
+            Engine::Docker | Engine::Podman => self.run(&["load", "-i", &path]),
+            Engine::Containerd => self.run(&["images", "import", &path]),
+        }
+    }
+
+    /// Unpack the root filesystem of a container into the new directory
+    /// `root`, streaming it from `docker export` (or its equivalent)
+    pub fn export_container(&self, container: &str, root: &Path) -> anyhow::Result<()> {
+        if *self == Engine::Containerd {
+            return self.copy_snapshot(container, root);
+        }
+
+        let program = self.program();
+        let mut child = Command::new(program).args(["export", container])
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to run {}", program))?;
+        let stdout = child.stdout.take().expect("piped stdout");
+        let unpacked = unpack_tree(BufReader::new(stdout), root);
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(Error::EngineCommandFailed(format!("{} export {}", program, container)).into());
+        }
+        unpacked.with_context(|| format!("failed to unpack the root filesystem of {}", container))
+    }
+
+    /// `ctr` cannot export containers, so their snapshot is mounted next to
+    /// `root` and copied
+    fn copy_snapshot(&self, container: &str, root: &Path) -> anyhow::Result<()> {
+        let info: serde_json::Value = serde_json::from_str(&self.output(&["containers", "info", container])?)
+            .with_context(|| format!("failed to parse the information of container {}", container))?;
+        let (Some(snapshotter), Some(key)) = (info["Snapshotter"].as_str(), info["SnapshotKey"].as_str())
+        else {
+            return Err(Error::ContainerSnapshotNotFound(container.to_owned()).into());
+        };
+
+        let mount_dir = root.with_extension("mount");
+        self.mount_snapshot(snapshotter, key, &mount_dir)?;
+        let copied = copy_tree(&mount_dir, root);
+        unmount_snapshot(&mount_dir)?;
+        copied.with_context(|| format!("failed to copy the root filesystem of {}", container))
+    }
+
+    /// Mount a containerd snapshot on `dir`, a new directory, as `ctr`
+    /// prints the mount command rather than mounting it
+    pub(crate) fn mount_snapshot(&self, snapshotter: &str, key: &str, dir: &Path) -> anyhow::Result<()> {
+        std::fs::create_dir(dir)
+            .with_context(|| format!("failed creating directory {}", dir.display()))?;
+        let mount = self.output(&["snapshots", "--snapshotter", snapshotter, "mounts",
+            &dir.to_string_lossy(), key])?;
+        let status = Command::new("sh").args(["-c", &mount]).status()
+            .context("failed to run mount")?;
+        if !status.success() {
+            return Err(Error::EngineCommandFailed(mount.trim().to_owned()).into());
+        }
+        Ok(())
+    }
+}
+
+/// Undo [`Engine::mount_snapshot`]
+pub(crate) fn unmount_snapshot(dir: &Path) -> anyhow::Result<()> {
+    platform::unmount(dir)
+        .with_context(|| format!("failed unmounting {}", dir.display()))?;
+    std::fs::remove_dir(dir)
+        .with_context(|| format!("failed removing {}", dir.display()))
+}
+
+/// Compute the delta between the root filesystems of two containers of
+/// `engine`, into the new delta directory `output`.
+///
+/// The root filesystems are unpacked under `work_dir`, which must not exist
+/// and is removed once done.
+pub fn diff_containers(engine: Engine, source_container: &str, target_container: &str,
+    output: &Path, work_dir: &Path, options: DiffOptions) -> anyhow::Result<DiffStats>
+{
+    let work_dir = WorkDir::create(work_dir)?;
+    let source_root = work_dir.join("source");
+    let target_root = work_dir.join("target");
+
+    engine.export_container(source_container, &source_root)?;
+    engine.export_container(target_container, &target_root)?;
+    let options = DiffOptions { output: Some(output.to_owned()), ..options };
+    let stats = DeltaBuilder::new(&source_root, &target_root).options(options).run()?;
+
+    work_dir.remove()?;
+    Ok(stats)
+}
+
+/// Compute the delta between two images of the local store of `engine`, and
+/// write it to `output` as a thin delta image tarball, to be restored by
+/// [`load_delta`] on a host having the source image.
+///
+/// The images are exported under `work_dir`, which must not exist and is
+/// removed once done.
+pub fn save_delta(engine: Engine, source_image: &str, target_image: &str, output: &Path,
+    work_dir: &Path, options: DiffOptions) -> anyhow::Result<DiffStats>
+{
+    let work_dir = WorkDir::create(work_dir)?;
+    let source_tar = work_dir.join("source.tar");
+    let target_tar = work_dir.join("target.tar");
+
+    engine.export_image(source_image, &source_tar)?;
+    engine.export_image(target_image, &target_tar)?;
+    let names = ImageNames { source: source_image, target: target_image };
+    let stats = diff_oci_images(&source_tar, &target_tar, output, &work_dir.join("diff"), options,
+        Some(&names))?;
+
+    work_dir.remove()?;
+    Ok(stats)
+}
+
+/// Restore the target image of a delta saved by [`save_delta`] against the
+/// source image in the local store of `engine`, and import it there, under
+/// `tag` or else the name it had on the saving host.
+///
+/// As with [`save_delta`], the images are stored under `work_dir`.
+pub fn load_delta(engine: Engine, delta: &Path, tag: Option<&str>, work_dir: &Path,
+    options: ApplyOptions) -> anyhow::Result<ApplyStats>
+{
+    let work_dir = WorkDir::create(work_dir)?;
+    let restored_tar = work_dir.join("restored.tar");
+
+    let export = |image: &str, path: &Path| engine.export_image(image, path);
+    let stats = apply_oci_images(delta, &restored_tar, &work_dir.join("apply"), options,
+        Some(&export), tag)?;
+    engine.import_image(&restored_tar)?;
+
+    work_dir.remove()?;
+    Ok(stats)
+}
@@ -0,0 +1,240 @@
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::file_errors::FileErrorSummary;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("XDelta3 encode error")]
+    XDelta3EncodeError,
+
+    #[error("XDelta3 decode error")]
+    XDelta3DecodeError,
+
+    #[error("XDelta3 failed validation: {0} -> {1}")]
+    XDelta3FailedValidation(PathBuf, PathBuf),
+
+    #[error("{0} failed validation: {1} -> {2}")]
+    FailedValidation(&'static str, PathBuf, PathBuf),
+
+    #[error("Unknown delta algorithm {0}")]
+    UnknownAlgo(String),
+
+    #[error("Failed to decode {0} content: {1} -> {2}")]
+    FailedDecode(&'static str, PathBuf, PathBuf),
+
+    #[error("XDelta3 failed deflation: {0} -> {1}")]
+    XDelta3FailedDeflation(PathBuf, PathBuf),
+
+    #[error("Zstd decompression failed: {0}")]
+    ZstdDecodeError(PathBuf),
+
+    #[error("File time error")]
+    FileTimeError(std::io::Error, PathBuf),
+
+    #[error("Output delta dir already exists: {0}")]
+    DeltaDirExists(PathBuf),
+
+    #[error("Delta is patched against extra sources that were not given: {}", .0.join(", "))]
+    MissingExtraSources(Vec<String>),
+
+    #[error("Target tree {0} holds the meta-data of a delta already, use --force to diff it anyway")]
+    TargetIsDelta(PathBuf),
+
+    #[error("Target tree {0} holds the journal of an interrupted diff, refused with --fresh")]
+    UnexpectedDiffJournal(PathBuf),
+
+    #[error("{0} is where the meta-data file goes, pick another one with --meta-path")]
+    MetaPathCollision(PathBuf),
+
+    #[error("Invalid meta-data path {0}, expected a path relative to the root of the delta")]
+    InvalidMetaPath(PathBuf),
+
+    #[error("Checksum mismatch after apply, the source tree may not match the delta: {}",
+        display_paths(.0))]
+    ChecksumMismatch(Vec<PathBuf>),
+
+    #[error("Cannot replace directory with a symlink: {0}")]
+    SymlinkIsDir(PathBuf),
+
+    #[error("Cannot replace directory with a special file: {0}")]
+    SpecialIsDir(PathBuf),
+
+    #[error("Invalid delta archive: {0}")]
+    InvalidArchive(&'static str),
+
+    #[error("Refusing to write through a symlink or outside of the delta tree: {0}")]
+    UnsafePath(PathBuf),
+
+    #[error("The meta-data lists a path outside of the tree: {0}")]
+    UnsafeMetaPath(PathBuf),
+
+    #[error("Invalid tarball: {0}")]
+    InvalidTar(&'static str),
+
+    #[error("Invalid tar delta: {0}")]
+    InvalidTarDelta(&'static str),
+
+    #[error("Invalid block delta: {0}")]
+    InvalidBlockDelta(&'static str),
+
+    #[error("Restored tarball {0} does not match the original, the source tarball may not match the delta")]
+    TarDigestMismatch(PathBuf),
+
+    #[error("Restored image {0} does not match the original, the source image may not match the delta")]
+    BlockDigestMismatch(PathBuf),
+
+    #[error("Restored tree {0} has digest {2} instead of {1}")]
+    TreeDigestMismatch(PathBuf, String, String),
+
+    #[error("Invalid key file: {0}")]
+    InvalidKey(PathBuf),
+
+    #[error("Delta is not signed: {0}")]
+    MissingSignature(PathBuf),
+
+    #[error("Delta signature does not match the key: {0}")]
+    BadSignature(PathBuf),
+
+    #[error("Invalid meta-data file: {0}")]
+    InvalidMetaData(&'static str),
+
+    #[error("Meta-data of {0} was produced by {1} in format version {2}, and this release supports \
+        versions {3} to {4}: use a newer release of deltaimage")]
+    MetaFormatTooNew(PathBuf, String, u32, u32, u32),
+
+    #[error("Meta-data of {0} was produced by {1} in format version {2}, and this release supports \
+        versions {3} to {4}: upgrade it with `deltaimage migrate-meta` of an older release")]
+    MetaFormatTooOld(PathBuf, String, u32, u32, u32),
+
+    #[error("Delta verification failed for {0} paths")]
+    VerificationFailed(usize),
+
+    #[error("Delta directory check found {0} problems left unrepaired")]
+    FsckFailed(usize),
+
+    #[error("Invalid OCI image: {0}")]
+    InvalidOciImage(String),
+
+    #[error("Output image already exists: {0}")]
+    OutputImageExists(PathBuf),
+
+    #[error("Local image {0} is not the source image of the delta")]
+    SourceImageMismatch(String),
+
+    #[error("Restored layer does not match its digest {0}")]
+    LayerDigestMismatch(String),
+
+    #[error("Container engine command failed: {0}")]
+    EngineCommandFailed(String),
+
+    #[error("No snapshot found for container {0}")]
+    ContainerSnapshotNotFound(String),
+
+    #[error("Image {0} not found in containerd")]
+    ContainerdImageNotFound(String),
+
+    #[error("Not a squashfs or EROFS image: {0}")]
+    UnknownFsImage(PathBuf),
+
+    #[error("Filesystem image command failed: {0}")]
+    FsImageCommandFailed(String),
+
+    #[error("Made image {1} instead of the target image {0}, as the tools or their options differ")]
+    FsImageMismatch(String, String),
+
+    #[error("Delta {0} was not made from filesystem images")]
+    NotFsImageDelta(PathBuf),
+
+    #[error("Invalid image reference: {0}")]
+    InvalidImageReference(String),
+
+    #[error("Registry error: {0}")]
+    RegistryError(String),
+
+    #[error("No reverse delta in {0}, it was not made with --bidirectional")]
+    NoReverseDelta(PathBuf),
+
+    #[error("Cannot squash deltas: {0}")]
+    CannotSquash(String),
+
+    #[error("Delta {0} is encrypted, a key is needed to decrypt it")]
+    MissingDecryptionKey(PathBuf),
+
+    #[error("Unsupported cipher of encrypted delta: {0}")]
+    UnsupportedCipher(String),
+
+    #[error("Failed to decrypt {0}, wrong key or corrupt file")]
+    DecryptionFailed(PathBuf),
+
+    #[error("Delta fetched from {0} has digest {2} instead of {1}")]
+    FetchDigestMismatch(String, String, String),
+
+    #[error("Unsupported object store URL {0}")]
+    UnsupportedStore(String),
+
+    #[error("Object store error: {0}")]
+    StoreError(String),
+
+    #[error("No chain of deltas from {0} to {1} in the catalog")]
+    NoDeltaChain(String, String),
+
+    #[error("Invalid owner map {0}, line {1}")]
+    InvalidOwnerMap(PathBuf, usize),
+
+    #[error("The {0} ID {1} is outside of the owner map")]
+    UnmappedOwner(&'static str, u32),
+
+    #[error("{0}")]
+    FileErrors(FileErrorSummary),
+
+    #[error("Apply of {0} went too far to be rolled back, it can only be resumed")]
+    CannotRollBack(PathBuf),
+
+    #[error("Delta {0} has no image configuration")]
+    NoImageConfig(PathBuf),
+
+    #[error("Image configuration mismatch, delta made against {0}, given {1}")]
+    ImageConfigMismatch(String, String),
+
+    #[error("Delta {0} is signed, a key is needed to sign it again")]
+    SigningKeyNeeded(PathBuf),
+
+    #[error("Watch mode does not support {0}")]
+    UnsupportedWatchOption(&'static str),
+
+    #[error("Self-test found {0} required capabilities missing")]
+    SelfTestFailed(usize),
+
+    #[error("The deltas differ by {0} paths")]
+    DeltasDiffer(usize),
+
+    #[error("Unsupported chunk index version {0}")]
+    UnsupportedChunkIndex(u32),
+
+    #[error("Chunk {0} is corrupt, it was removed to be fetched again")]
+    ChunkDigestMismatch(PathBuf),
+
+    #[error("Delta rebuilt from chunks has digest {1} instead of {0}")]
+    ArchiveDigestMismatch(String, String),
+
+    #[error("Overlayfs upper directory {0} is not empty")]
+    UpperDirNotEmpty(PathBuf),
+
+    #[error("{0} is not the root of a btrfs subvolume or a mounted ZFS dataset")]
+    NotSnapshottable(PathBuf),
+
+    #[error("Snapshot command failed: {0}")]
+    SnapshotCommandFailed(String),
+
+    #[error("{0} is neither an SPDX nor a CycloneDX JSON document, nor syft output")]
+    UnknownSbomFormat(PathBuf),
+
+    #[error("Built without the `{0}` feature")]
+    FeatureDisabled(&'static str),
+}
+
+fn display_paths(paths: &[PathBuf]) -> String {
+    paths.iter().map(|x| x.display().to_string()).collect::<Vec<_>>().join(", ")
+}
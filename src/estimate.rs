@@ -0,0 +1,171 @@
+//! Quick estimate of the size of a delta, without encoding anything.
+//!
+//! Files of the same path and size are compared, and the others are split
+//! into content-defined chunks, with the gear hash of similarity sketches.
+//! The chunks of a target file not found in the source file of the same path
+//! are counted as stored, at the ratio that zstd compresses a sample of them.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use walkdir::WalkDir;
+
+use crate::filter::PathFilter;
+use crate::mmap::Mmap;
+use crate::similarity::GEAR;
+use crate::utils::{default_jobs, drop_components, open_noatime, parallel_map};
+
+/// Chunk boundaries are where the low bits of the hash are zero, for chunks
+/// of 8 KiB on average
+const CHUNK_MASK: u64 = (1 << 13) - 1;
+const MIN_CHUNK: usize = 2 << 10;
+const MAX_CHUNK: usize = 64 << 10;
+
+/// Amount of stored content compressed per file to estimate its ratio
+const SAMPLE_SIZE: usize = 1 << 20;
+
+/// Options of a delta estimate
+#[derive(Debug, Clone, Default)]
+pub struct EstimateOptions {
+    /// Number of files to read concurrently, defaulting to the number of CPUs
+    pub jobs: Option<usize>,
+
+    /// Glob patterns of paths left out, as with `DiffOptions::exclude`
+    pub exclude: Vec<String>,
+
+    /// Glob patterns of paths kept despite matching `exclude`
+    pub include: Vec<String>,
+}
+
+/// Predicted outcome of a diff
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Estimate {
+    pub unchanged_files: usize,
+    pub modified_files: usize,
+    pub new_files: usize,
+    /// Total size of the regular files of the target tree
+    pub total_size: u64,
+    /// Estimated size of the delta, leaving out its meta-data
+    pub estimated_delta_size: u64,
+    pub duration: Duration,
+}
+
+enum FileEstimate {
+    Unchanged,
+    Modified(u64),
+    New(u64),
+}
+
+/// Estimate the size of the delta between two trees, reading them but not
+/// encoding anything
+pub fn estimate(source_dir: &Path, target_dir: &Path, options: &EstimateOptions)
+    -> anyhow::Result<Estimate>
+{
+    let started = Instant::now();
+    let filter = PathFilter::new(&options.exclude, &options.include)?;
+
+    let n = target_dir.components().count();
+    let mut work: Vec<(PathBuf, u64)> = vec![];
+    for entry in WalkDir::new(target_dir) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let rel_path = drop_components(n, entry.path());
+        if !filter.is_excluded(&rel_path) {
+            work.push((rel_path, entry.metadata()?.len()));
+        }
+    }
+
+    let jobs = options.jobs.unwrap_or_else(default_jobs);
+    let estimates = parallel_map(jobs, &work, |(rel_path, _)| {
+        let source_path = source_dir.join(rel_path);
+        let target_path = target_dir.join(rel_path);
+        estimate_file(&source_path, &target_path)
+    })?;
+
+    let mut estimate = Estimate::default();
+    for ((_, size), file) in work.iter().zip(estimates) {
+        estimate.total_size += size;
+        match file {
+            FileEstimate::Unchanged => estimate.unchanged_files += 1,
+            FileEstimate::Modified(delta_size) => {
+                estimate.modified_files += 1;
+                estimate.estimated_delta_size += delta_size;
+            }
+            FileEstimate::New(delta_size) => {
+                estimate.new_files += 1;
+                estimate.estimated_delta_size += delta_size;
+            }
+        }
+    }
+
+    estimate.duration = started.elapsed();
+    Ok(estimate)
+}
+
+fn estimate_file(source_path: &Path, target_path: &Path) -> anyhow::Result<FileEstimate> {
+    let target = Mmap::map(&open_noatime(target_path)?)?;
+
+    let source = match std::fs::symlink_metadata(source_path) {
+        Ok(metadata) if metadata.is_file() => Mmap::map(&open_noatime(source_path)?)?,
+        _ => return Ok(FileEstimate::New(compressed_size(&target, target.len() as u64))),
+    };
+    if *source == *target {
+        return Ok(FileEstimate::Unchanged);
+    }
+
+    let mut source_chunks = HashSet::new();
+    for_each_chunk(&source, |chunk| { source_chunks.insert(chunk_hash(chunk)); });
+
+    let mut stored = 0u64;
+    let mut sample = vec![];
+    for_each_chunk(&target, |chunk| {
+        if !source_chunks.contains(&chunk_hash(chunk)) {
+            stored += chunk.len() as u64;
+            if sample.len() < SAMPLE_SIZE {
+                sample.extend_from_slice(chunk);
+            }
+        }
+    });
+
+    Ok(FileEstimate::Modified(compressed_size(&sample, stored)))
+}
+
+/// Size of `total` bytes compressed at the ratio of `sample`
+fn compressed_size(sample: &[u8], total: u64) -> u64 {
+    let sample = &sample[..sample.len().min(SAMPLE_SIZE)];
+    if sample.is_empty() {
+        return 0;
+    }
+    let compressed = zstd::bulk::compress(sample, 1).map(|data| data.len()).unwrap_or(sample.len());
+    (total as f64 * compressed as f64 / sample.len() as f64) as u64
+}
+
+fn for_each_chunk(data: &[u8], mut f: impl FnMut(&[u8])) {
+    let mut start = 0;
+    let mut hash = 0u64;
+
+    for (i, byte) in data.iter().enumerate() {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        let len = i + 1 - start;
+        if (len >= MIN_CHUNK && hash & CHUNK_MASK == 0) || len >= MAX_CHUNK {
+            f(&data[start..=i]);
+            start = i + 1;
+        }
+    }
+
+    if start < data.len() {
+        f(&data[start..]);
+    }
+}
+
+fn chunk_hash(chunk: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    chunk.hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,13 @@
+//! Optional functionality, selected by crate features so that the binary
+//! copied into delta images can be built without what it does not need.
+
+/// Each optional feature, with whether this build has it
+pub const FEATURES: &[(&str, bool)] = &[
+    ("s3", cfg!(feature = "s3")),
+    ("gcs", cfg!(feature = "gcs")),
+    ("azblob", cfg!(feature = "azblob")),
+    ("registry", cfg!(feature = "registry")),
+    ("encryption", cfg!(feature = "encryption")),
+    ("fs-image", cfg!(feature = "fs-image")),
+    ("tui", cfg!(feature = "tui")),
+];
@@ -0,0 +1,93 @@
+//! Download of single-file deltas over HTTP(S), so that devices can apply a
+//! delta straight from where it is published.
+//!
+//! Downloads are written to a local file, and resumed from where they stopped
+//! with range requests, both when retrying failed requests and when a previous
+//! download of the same file was interrupted.
+
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::Error;
+use crate::utils::digest_file;
+
+/// Options for downloading deltas
+#[derive(Debug, Clone)]
+pub struct FetchOptions {
+    /// Number of times a failed download is resumed before giving up
+    pub retries: u32,
+
+    /// Expected digest of the delta, such as `sha256:...`
+    pub digest: Option<String>,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self { retries: 5, digest: None }
+    }
+}
+
+/// Whether a delta path given by the user is rather a URL to download it from
+pub fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+/// Download the delta at `url` to `path`, resuming the download that `path`
+/// may hold, and check it against the expected digest.
+pub fn fetch_delta(url: &str, path: &Path, options: &FetchOptions) -> anyhow::Result<()> {
+    let agent = ureq::AgentBuilder::new()
+        .redirects(5)
+        .timeout_connect(Duration::from_secs(30))
+        .timeout_read(Duration::from_secs(60))
+        .build();
+
+    let mut attempt = 0;
+    while let Err(err) = fetch_once(&agent, url, path) {
+        if attempt == options.retries {
+            return Err(err.context(format!("failed to fetch {}", url)));
+        }
+        attempt += 1;
+        tracing::warn!("Fetching {} failed, resuming ({}/{}): {:#}", url, attempt, options.retries, err);
+        std::thread::sleep(Duration::from_secs(1 << attempt.min(5)));
+    }
+
+    if let Some(expected) = &options.digest {
+        let digest = format!("sha256:{}", digest_file(path)?);
+        if &digest != expected {
+            // Not to be resumed from
+            std::fs::remove_file(path)?;
+            return Err(Error::FetchDigestMismatch(url.to_owned(), expected.clone(), digest).into());
+        }
+    }
+
+    Ok(())
+}
+
+fn fetch_once(agent: &ureq::Agent, url: &str, path: &Path) -> anyhow::Result<()> {
+    let offset = path.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+    let mut request = agent.get(url);
+    if offset > 0 {
+        request = request.set("Range", &format!("bytes={}-", offset));
+    }
+
+    let response = match request.call() {
+        // Nothing left after the offset
+        Err(ureq::Error::Status(416, _)) if offset > 0 => return Ok(()),
+        response => response?,
+    };
+
+    let mut file = match response.status() {
+        206 => {
+            tracing::info!("Resuming {} from {}", url, offset);
+            OpenOptions::new().append(true).open(path)?
+        }
+        _ => {
+            tracing::info!("Fetching {}", url);
+            File::create(path)?
+        }
+    };
+    std::io::copy(&mut response.into_reader(), &mut file)?;
+    file.sync_all()?;
+    Ok(())
+}
@@ -0,0 +1,125 @@
+//! Failures of single files, collected across a diff or apply rather than
+//! stopping at the first one, so that they can all be fixed in one pass.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::Error;
+
+/// Failures listed in full in the error, beyond which only the counts are
+const LISTED: usize = 20;
+
+/// What failed for a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FileErrorKind {
+    /// Reading or writing the content
+    Read,
+    /// Encoding or decoding the content
+    Encode,
+    /// Reading or setting ownership, permissions, times or xattrs
+    MetaData,
+}
+
+impl FileErrorKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            FileErrorKind::Read => "read",
+            FileErrorKind::Encode => "encode",
+            FileErrorKind::MetaData => "meta-data",
+        }
+    }
+
+    /// Kind of a failure, as given by context, or told by its cause otherwise
+    fn of(err: &anyhow::Error) -> Self {
+        if let Some(kind) = err.downcast_ref::<FileErrorKind>() {
+            return *kind;
+        }
+        match err.chain().any(|cause| cause.is::<std::io::Error>()) {
+            true => FileErrorKind::Read,
+            false => FileErrorKind::Encode,
+        }
+    }
+}
+
+/// As context of an error, tells its kind
+impl fmt::Display for FileErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} failed", self.name())
+    }
+}
+
+/// The failures of a run, sorted by path
+#[derive(Debug, Clone)]
+pub struct FileErrorSummary {
+    pub failures: Vec<(PathBuf, FileErrorKind, String)>,
+    /// Whether the run gave up at the maximum number of failures, so that
+    /// there may be more
+    pub stopped: bool,
+}
+
+impl fmt::Display for FileErrorSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut counts = BTreeMap::new();
+        for (_, kind, _) in &self.failures {
+            *counts.entry(kind.name()).or_insert(0) += 1;
+        }
+        let counts: Vec<_> = counts.iter().map(|(name, count)| format!("{} {}", name, count)).collect();
+        write!(f, "{} files failed ({})", self.failures.len(), counts.join(", "))?;
+        if self.stopped {
+            write!(f, ", giving up at --max-errors")?;
+        }
+        for (path, kind, error) in self.failures.iter().take(LISTED) {
+            write!(f, "\n  {} [{}]: {}", path.display(), kind.name(), error)?;
+        }
+        if self.failures.len() > LISTED {
+            write!(f, "\n  and {} more", self.failures.len() - LISTED)?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects the failures of files processed in parallel
+pub(crate) struct FileErrors {
+    max_errors: usize,
+    failures: Mutex<Vec<(PathBuf, FileErrorKind, String)>>,
+}
+
+impl FileErrors {
+    /// Giving up after `max_errors` failures, or never with 0
+    pub(crate) fn new(max_errors: usize) -> Self {
+        Self { max_errors, failures: Mutex::new(vec![]) }
+    }
+
+    /// Record the failure of a file, failing once the maximum is reached.
+    /// Paths leading out of the trees fail at once, as the trees are not to
+    /// be trusted any further.
+    pub(crate) fn record(&self, path: &Path, err: anyhow::Error) -> anyhow::Result<()> {
+        if matches!(err.downcast_ref::<Error>(), Some(Error::UnsafePath(_))) {
+            return Err(err);
+        }
+        let kind = FileErrorKind::of(&err);
+        tracing::warn!("Failed on {}: {:#}", path.display(), err);
+        let mut failures = self.failures.lock().unwrap();
+        failures.push((path.to_owned(), kind, format!("{:#}", err)));
+        if failures.len() == self.max_errors {
+            return Err(Error::FileErrors(summary(failures.clone(), true)).into());
+        }
+        Ok(())
+    }
+
+    /// Fail if any file failed
+    pub(crate) fn into_result(self) -> Result<(), Error> {
+        let failures = self.failures.into_inner().unwrap();
+        match failures.is_empty() {
+            true => Ok(()),
+            false => Err(Error::FileErrors(summary(failures, false))),
+        }
+    }
+}
+
+fn summary(mut failures: Vec<(PathBuf, FileErrorKind, String)>, stopped: bool) -> FileErrorSummary {
+    failures.sort_by(|a, b| a.0.cmp(&b.0));
+    FileErrorSummary { failures, stopped }
+}
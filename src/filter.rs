@@ -0,0 +1,52 @@
+use std::path::Path;
+
+use anyhow::Context;
+use glob::Pattern;
+
+/// Glob patterns selecting the paths of the two trees that take no part in
+/// the delta. Patterns are matched against the path from the root of the
+/// tree, such as `/var/log/*`, and a path matches if it or any of its parent
+/// directories does.
+#[derive(Default)]
+pub(crate) struct PathFilter {
+    exclude: Vec<Pattern>,
+    include: Vec<Pattern>,
+}
+
+impl PathFilter {
+    pub(crate) fn new(exclude: &[String], include: &[String]) -> anyhow::Result<Self> {
+        let compile = |patterns: &[String]| -> anyhow::Result<Vec<Pattern>> {
+            patterns.iter()
+                .map(|pattern| Pattern::new(pattern)
+                    .with_context(|| format!("invalid pattern {}", pattern)))
+                .collect()
+        };
+
+        Ok(Self {
+            exclude: compile(exclude)?,
+            include: compile(include)?,
+        })
+    }
+
+    /// Whether no path is excluded at all
+    pub(crate) fn is_empty(&self) -> bool {
+        self.exclude.is_empty()
+    }
+
+    /// Whether a relative path is excluded, which happens if it matches an
+    /// exclude pattern and no include pattern.
+    pub(crate) fn is_excluded(&self, rel_path: &Path) -> bool {
+        if self.exclude.is_empty() || rel_path.as_os_str().is_empty() {
+            return false;
+        }
+
+        let path = Path::new("/").join(rel_path);
+        let matches = |patterns: &[Pattern]| {
+            path.ancestors()
+                .take_while(|ancestor| ancestor.parent().is_some())
+                .any(|ancestor| patterns.iter().any(|pattern| pattern.matches_path(ancestor)))
+        };
+
+        matches(&self.exclude) && !matches(&self.include)
+    }
+}
@@ -0,0 +1,283 @@
+//! Deltas of read-only filesystem images, such as the squashfs and EROFS
+//! root filesystems that embedded systems ship.
+//!
+//! Both images are unpacked, or loop-mounted read-only, and diffed as trees.
+//! The parameters that the target image was made with are read from its
+//! superblock and recorded in the meta-data of the delta, so that apply makes
+//! it again from the restored tree with `mksquashfs` or `mkfs.erofs`, checking
+//! that it is the same image bit for bit.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::Context;
+
+use crate::Error;
+use crate::apply::{ApplyOptions, ApplyStats, DeltaApplier};
+use crate::diff::{DeltaBuilder, DiffOptions, DiffStats};
+use crate::metadata::{FsImage, FsImageKind, MetaData};
+use crate::oci::WorkDir;
+use crate::platform;
+use crate::signing;
+use crate::utils::{digest_file, temp_path_for};
+
+const SQUASHFS_MAGIC: &[u8] = b"hsqs";
+const EROFS_MAGIC: u32 = 0xe0f5_e1e2;
+const EROFS_SUPER_OFFSET: usize = 1024;
+
+/// Flags of the squashfs superblock, and the mksquashfs option leading to each
+const SQUASHFS_FLAGS: &[(u16, &str)] = &[
+    (0x0001, "-noI"),
+    (0x0002, "-noD"),
+    (0x0008, "-noF"),
+    (0x0010, "-no-fragments"),
+    (0x0020, "-always-use-fragments"),
+    (0x0100, "-noX"),
+    (0x0200, "-no-xattrs"),
+    (0x0800, "-noId"),
+];
+const SQUASHFS_DUPLICATES: u16 = 0x0040;
+const SQUASHFS_EXPORTABLE: u16 = 0x0080;
+
+/// Options of diffing and applying filesystem images
+#[derive(Debug, Clone, Default)]
+pub struct FsImageOptions {
+    /// Loop-mount the images read-only instead of unpacking them, which
+    /// needs root
+    pub mount: bool,
+    /// Options of mksquashfs or mkfs.erofs that the superblock of the target
+    /// image does not tell, such as those of its compressor
+    pub mkfs_args: Vec<String>,
+}
+
+fn run(program: &str, args: &[&str]) -> anyhow::Result<()> {
+    let command = format!("{} {}", program, args.join(" "));
+    let status = Command::new(program).args(args).status()
+        .with_context(|| format!("failed to run {}", program))?;
+    if !status.success() {
+        return Err(Error::FsImageCommandFailed(command).into());
+    }
+    Ok(())
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+impl FsImageKind {
+    /// The kind of an image, with the start of the image holding its superblock
+    fn of(path: &Path) -> anyhow::Result<(Self, Vec<u8>)> {
+        let mut superblock = vec![];
+        File::open(path).with_context(|| format!("failed to open {}", path.display()))?
+            .take(EROFS_SUPER_OFFSET as u64 + 128)
+            .read_to_end(&mut superblock)?;
+
+        if superblock.starts_with(SQUASHFS_MAGIC) && superblock.len() >= 96 {
+            Ok((FsImageKind::Squashfs, superblock))
+        } else if superblock.len() >= EROFS_SUPER_OFFSET + 128
+            && u32_at(&superblock, EROFS_SUPER_OFFSET) == EROFS_MAGIC
+        {
+            Ok((FsImageKind::Erofs, superblock))
+        } else {
+            Err(Error::UnknownFsImage(path.to_owned()).into())
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            FsImageKind::Squashfs => "squashfs",
+            FsImageKind::Erofs => "erofs",
+        }
+    }
+}
+
+impl FsImage {
+    /// Read the parameters an image was made with from its superblock,
+    /// adding `extra_args`
+    fn read(path: &Path, extra_args: &[String]) -> anyhow::Result<Self> {
+        let (kind, superblock) = FsImageKind::of(path)?;
+        let mut mkfs_args = match kind {
+            FsImageKind::Squashfs => squashfs_args(&superblock)
+                .ok_or_else(|| Error::UnknownFsImage(path.to_owned()))?,
+            FsImageKind::Erofs => erofs_args(&superblock[EROFS_SUPER_OFFSET..]),
+        };
+        mkfs_args.extend(extra_args.iter().cloned());
+
+        Ok(Self { kind, mkfs_args, digest: format!("sha256:{}", digest_file(path)?) })
+    }
+
+    /// Make the image of `tree` at `output`, if it is the same as the target
+    /// image was
+    fn make(&self, tree: &Path, output: &Path) -> anyhow::Result<()> {
+        if std::fs::symlink_metadata(output).is_ok() {
+            return Err(Error::OutputImageExists(output.to_owned()).into());
+        }
+
+        let tmp_path = temp_path_for(output);
+        let (tree, tmp) = (tree.to_string_lossy(), tmp_path.to_string_lossy());
+        let args: Vec<_> = self.mkfs_args.iter().map(|arg| arg.as_str()).collect();
+        match self.kind {
+            FsImageKind::Squashfs => run("mksquashfs", &[&[&*tree, &*tmp], &args[..]].concat())?,
+            FsImageKind::Erofs => run("mkfs.erofs", &[&args[..], &[&*tmp, &*tree]].concat())?,
+        }
+
+        let digest = format!("sha256:{}", digest_file(&tmp_path)?);
+        if digest != self.digest {
+            std::fs::remove_file(&tmp_path)?;
+            return Err(Error::FsImageMismatch(self.digest.clone(), digest).into());
+        }
+        std::fs::rename(&tmp_path, output)
+            .with_context(|| format!("failed renaming {} to {}", tmp_path.display(), output.display()))
+    }
+}
+
+fn squashfs_args(superblock: &[u8]) -> Option<Vec<String>> {
+    let compression = match u16_at(superblock, 20) {
+        1 => "gzip",
+        2 => "lzma",
+        3 => "lzo",
+        4 => "xz",
+        5 => "lz4",
+        6 => "zstd",
+        _ => return None,
+    };
+    let flags = u16_at(superblock, 24);
+
+    let mut args = vec!["-noappend".to_owned(), "-no-progress".to_owned(),
+        "-comp".to_owned(), compression.to_owned(),
+        "-b".to_owned(), u32_at(superblock, 12).to_string(),
+        "-mkfs-time".to_owned(), u32_at(superblock, 8).to_string()];
+    args.extend(SQUASHFS_FLAGS.iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, arg)| arg.to_string()));
+    if flags & SQUASHFS_DUPLICATES == 0 {
+        args.push("-no-duplicates".to_owned());
+    }
+    if flags & SQUASHFS_EXPORTABLE == 0 {
+        args.push("-no-exports".to_owned());
+    }
+    Some(args)
+}
+
+fn erofs_args(superblock: &[u8]) -> Vec<String> {
+    let uuid = &superblock[48..64];
+    let uuid = format!("{}-{}-{}-{}-{}", hex(&uuid[..4]), hex(&uuid[4..6]), hex(&uuid[6..8]),
+        hex(&uuid[8..10]), hex(&uuid[10..]));
+    let mut args = vec![format!("-T{}", u64_at(superblock, 24)), format!("-U{}", uuid),
+        format!("-b{}", 1u32 << superblock[12].min(31))];
+
+    let label = &superblock[64..80];
+    let label = &label[..label.iter().position(|&c| c == 0).unwrap_or(label.len())];
+    if !label.is_empty() {
+        args.push(format!("-L{}", String::from_utf8_lossy(label)));
+    }
+    args
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The tree of an image, unpacked or mounted under the work directory
+struct Tree {
+    dir: PathBuf,
+    mounted: bool,
+}
+
+impl Tree {
+    fn open(image: &Path, kind: FsImageKind, dir: PathBuf, mount: bool) -> anyhow::Result<Self> {
+        let (image, path) = (image.to_string_lossy(), dir.to_string_lossy());
+        if mount {
+            std::fs::create_dir(&dir)
+                .with_context(|| format!("failed creating directory {}", dir.display()))?;
+            run("mount", &["-t", kind.name(), "-o", "loop,ro", &image, &path])?;
+        } else {
+            match kind {
+                FsImageKind::Squashfs => run("unsquashfs", &["-no-progress", "-d", &path, &image])?,
+                FsImageKind::Erofs => run("fsck.erofs", &["--preserve", &format!("--extract={}", path), &image])?,
+            }
+        }
+        Ok(Self { dir, mounted: mount })
+    }
+
+    fn close(self) -> anyhow::Result<()> {
+        if self.mounted {
+            platform::unmount(&self.dir)
+                .with_context(|| format!("failed unmounting {}", self.dir.display()))?;
+            std::fs::remove_dir(&self.dir)
+                .with_context(|| format!("failed removing {}", self.dir.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Compute the delta between two squashfs or EROFS images into the new delta
+/// directory `output`, recording how to make the target image again.
+///
+/// The images are unpacked or mounted under `work_dir`, which must not exist
+/// and is removed once done.
+pub fn diff_fs_image(source_image: &Path, target_image: &Path, output: &Path, work_dir: &Path,
+    fs_options: &FsImageOptions, options: DiffOptions) -> anyhow::Result<DiffStats>
+{
+    let (source_kind, _) = FsImageKind::of(source_image)?;
+    let fs_image = FsImage::read(target_image, &fs_options.mkfs_args)?;
+    let work_dir = WorkDir::create(work_dir)?;
+
+    let source = Tree::open(source_image, source_kind, work_dir.join("source"), fs_options.mount)?;
+    let target = Tree::open(target_image, fs_image.kind, work_dir.join("target"), fs_options.mount)?;
+    // Signed once the parameters of the target image are recorded
+    let sign_key = options.sign_key.clone();
+    let options = DiffOptions { output: Some(output.to_owned()), sign_key: None, ..options };
+    let stats = DeltaBuilder::new(&source.dir, &target.dir).options(options).run();
+    source.close()?;
+    target.close()?;
+    let stats = stats?;
+
+    let mut md = MetaData::load(output)?;
+    md.fs_image = Some(fs_image);
+    if let Some(key_path) = sign_key {
+        signing::sign(&mut md, &key_path)?;
+    }
+    md.save(output)?;
+
+    work_dir.remove()?;
+    Ok(stats)
+}
+
+/// Restore the target image of a delta made by [`diff_fs_image`] against the
+/// source image, into `output_image`. The delta directory is restored into
+/// the target tree in place, as by apply.
+///
+/// As with [`diff_fs_image`], the source image is unpacked or mounted under
+/// `work_dir`.
+pub fn apply_fs_image(source_image: &Path, delta_dir: &Path, output_image: &Path, work_dir: &Path,
+    fs_options: &FsImageOptions, options: ApplyOptions) -> anyhow::Result<ApplyStats>
+{
+    let Some(fs_image) = MetaData::load(delta_dir)?.fs_image else {
+        return Err(Error::NotFsImageDelta(delta_dir.to_owned()).into());
+    };
+    if std::fs::symlink_metadata(output_image).is_ok() {
+        return Err(Error::OutputImageExists(output_image.to_owned()).into());
+    }
+    let (source_kind, _) = FsImageKind::of(source_image)?;
+    let work_dir = WorkDir::create(work_dir)?;
+
+    let source = Tree::open(source_image, source_kind, work_dir.join("source"), fs_options.mount)?;
+    let stats = DeltaApplier::new(&source.dir, delta_dir).options(options).run();
+    source.close()?;
+    let stats = stats?;
+    fs_image.make(delta_dir, output_image)?;
+
+    work_dir.remove()?;
+    Ok(stats)
+}
@@ -0,0 +1,296 @@
+//! Consistency checks of a delta directory on its own, cross-checking its
+//! meta-data against the tree: leftovers of interrupted runs, paths listed
+//! in the meta-data that are missing from the tree, hardlinks of link groups
+//! that were broken, and placeholders that are not empty.
+//!
+//! Unlike verify, nothing is decoded and the source tree is not needed.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use walkdir::WalkDir;
+
+use crate::Error;
+use crate::journal::DIFF_JOURNAL_FILE;
+use crate::metadata::{is_meta_file, Algo, MetaData, REVERSE_DELTA_DIR};
+use crate::platform::{MetadataExt, OsStrExt};
+use crate::utils::{digest_file, drop_components, get_meta_data, is_temp_path, path_from_bytes,
+    restore_modtimes, save_parent_modtime, set_meta_data, temp_path_for};
+
+/// Options of a delta directory check
+#[derive(Debug, Clone, Default)]
+pub struct FsckOptions {
+    /// Repair what can be without changing what apply restores: remove
+    /// leftover temporary files and files listed as deleted, empty
+    /// placeholders, and link back diverged members of link groups whose
+    /// content is the same
+    pub fix: bool,
+}
+
+/// An inconsistency found in a delta directory
+#[derive(Debug, Clone)]
+pub struct FsckProblem {
+    pub path: PathBuf,
+    pub reason: String,
+    /// Whether `FsckOptions::fix` repaired it
+    pub fixed: bool,
+}
+
+/// Outcome of checking a delta directory
+#[derive(Debug, Clone, Default)]
+pub struct FsckReport {
+    /// Number of paths of the tree that were checked
+    pub checked: usize,
+    pub problems: Vec<FsckProblem>,
+}
+
+impl FsckReport {
+    /// Turn a report with problems left unrepaired into an error
+    pub fn into_result(self) -> Result<Self, Error> {
+        match self.problems.iter().filter(|problem| !problem.fixed).count() {
+            0 => Ok(self),
+            count => Err(Error::FsckFailed(count)),
+        }
+    }
+}
+
+/// Checks the consistency of a delta directory, without its source tree
+pub struct DeltaChecker {
+    delta_dir: PathBuf,
+    options: FsckOptions,
+}
+
+/// What the meta-data says a path of the tree is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expected {
+    /// Empty file, whose content is taken from the source
+    Placeholder,
+    /// File holding a patch or content
+    Stored,
+    Symlink,
+    Special,
+    Directory,
+    /// Listed as deleted, so not to be found in the tree
+    Deleted,
+    /// Left out of the delta, and taken from the source
+    Excluded,
+    /// Placeholder left out of the delta, which may be found in the tree if
+    /// its removal was interrupted
+    Dropped,
+}
+
+impl DeltaChecker {
+    pub fn new(delta_dir: impl Into<PathBuf>) -> Self {
+        Self { delta_dir: delta_dir.into(), options: FsckOptions::default() }
+    }
+
+    pub fn options(mut self, options: FsckOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn run(&self) -> anyhow::Result<FsckReport> {
+        let md = MetaData::load(&self.delta_dir)?;
+        md.check_paths()?;
+        let mut report = FsckReport::default();
+        let mut parent_modtime_save = HashMap::new();
+
+        if md.journal.is_some() {
+            // Staged files and replaced placeholders are expected then
+            report.problems.push(FsckProblem {
+                path: PathBuf::new(),
+                reason: "interrupted apply, to be resumed or rolled back first".to_owned(),
+                fixed: false,
+            });
+            return Ok(report);
+        }
+
+        let mut expected = HashMap::new();
+        for path in &md.keep_files {
+            expected.insert(path.as_slice(), Expected::Placeholder);
+        }
+        for (algo, path) in &md.changes {
+            let kind = match algo {
+                Algo::CopyFrom(_) => Expected::Placeholder,
+                _ => Expected::Stored,
+            };
+            expected.insert(path.as_slice(), kind);
+        }
+        for symlink in &md.symlinks {
+            expected.insert(symlink.path.as_slice(), Expected::Symlink);
+        }
+        for special in &md.specials {
+            expected.insert(special.path.as_slice(), Expected::Special);
+        }
+        for dir in &md.directories {
+            expected.insert(dir.path.as_slice(), Expected::Directory);
+        }
+        for path in &md.deleted_files {
+            expected.insert(path.as_slice(), Expected::Deleted);
+        }
+        for path in &md.excluded {
+            expected.insert(path.as_slice(), Expected::Excluded);
+        }
+        for placeholder in &md.placeholders {
+            expected.insert(placeholder.path.as_slice(), Expected::Dropped);
+        }
+
+        let n = self.delta_dir.components().count();
+        let mut seen = HashSet::new();
+        let mut walker = WalkDir::new(&self.delta_dir).into_iter();
+        while let Some(entry) = walker.next() {
+            let entry = entry?;
+            let path = entry.path();
+            let rel_path = drop_components(n, path);
+            let key = rel_path.as_os_str().as_bytes();
+            let file_type = entry.file_type();
+            report.checked += 1;
+
+            if is_meta_file(&rel_path) {
+                continue;
+            }
+            if rel_path == Path::new(REVERSE_DELTA_DIR) && md.reverse.is_some() {
+                walker.skip_current_dir();
+                continue;
+            }
+
+            let mut problem = |reason: String, fixed: bool| {
+                report.problems.push(FsckProblem { path: rel_path.clone(), reason, fixed });
+            };
+
+            let leftover = rel_path == Path::new(DIFF_JOURNAL_FILE) || is_temp_path(&rel_path);
+            let deleted = expected.get(key) == Some(&Expected::Deleted);
+            if (leftover || deleted) && !file_type.is_dir() {
+                let reason = match leftover {
+                    true => "leftover of an interrupted run",
+                    false => "listed as deleted",
+                };
+                if self.options.fix {
+                    save_parent_modtime(&mut parent_modtime_save, path)?;
+                    std::fs::remove_file(path)
+                        .with_context(|| format!("failed removing {}", path.display()))?;
+                }
+                problem(reason.to_owned(), self.options.fix);
+                continue;
+            }
+
+            seen.insert(key.to_owned());
+            let Some(&kind) = expected.get(key) else {
+                // New files are stored as they are, without being listed
+                if !file_type.is_file() {
+                    problem("not listed in the meta-data".to_owned(), false);
+                }
+                continue;
+            };
+
+            let matches = match kind {
+                Expected::Placeholder | Expected::Stored | Expected::Dropped => file_type.is_file(),
+                Expected::Symlink => file_type.is_symlink(),
+                Expected::Special => !file_type.is_file() && !file_type.is_dir() && !file_type.is_symlink(),
+                Expected::Directory => file_type.is_dir(),
+                Expected::Deleted => unreachable!(),
+                Expected::Excluded => {
+                    problem("excluded, yet found in the delta".to_owned(), false);
+                    continue;
+                }
+            };
+            if !matches {
+                problem(format!("expected to be a {}", kind.name()), false);
+                continue;
+            }
+
+            if matches!(kind, Expected::Placeholder | Expected::Dropped) {
+                let len = entry.metadata()?.len();
+                if len != 0 {
+                    // Apply only takes the meta-data of placeholders, which is kept
+                    if self.options.fix {
+                        let meta_data = get_meta_data(path)?;
+                        std::fs::OpenOptions::new().write(true).open(path)
+                            .and_then(|file| file.set_len(0))
+                            .with_context(|| format!("failed truncating {}", path.display()))?;
+                        set_meta_data(path, meta_data)?;
+                    }
+                    problem(format!("placeholder is {} bytes", len), self.options.fix);
+                }
+            }
+        }
+
+        for (path, kind) in &expected {
+            let missing = !matches!(kind, Expected::Deleted | Expected::Excluded | Expected::Dropped)
+                && !seen.contains(*path);
+            if missing {
+                report.problems.push(FsckProblem {
+                    path: path_from_bytes(path),
+                    reason: format!("{} missing from the tree", kind.name()),
+                    fixed: false,
+                });
+            }
+        }
+
+        for group in md.link_groups.iter().flatten() {
+            self.check_link_group(&group.paths, &mut report, &mut parent_modtime_save)?;
+        }
+
+        restore_modtimes(parent_modtime_save)?;
+        report.problems.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(report)
+    }
+
+    /// The members of a link group found in the tree are to be hardlinked
+    /// together, as diff leaves them
+    fn check_link_group(&self, paths: &[Vec<u8>], report: &mut FsckReport,
+        parent_modtime_save: &mut HashMap<PathBuf, std::time::SystemTime>) -> anyhow::Result<()>
+    {
+        let members: Vec<_> = paths.iter()
+            .map(|path| (path_from_bytes(path), self.delta_dir.join(path_from_bytes(path))))
+            .filter_map(|(rel_path, path)| {
+                let metadata = std::fs::symlink_metadata(&path).ok().filter(|metadata| metadata.is_file())?;
+                Some((rel_path, path, (metadata.dev(), metadata.ino())))
+            })
+            .collect();
+        let Some((_, first_path, first_id)) = members.first() else { return Ok(()) };
+
+        let mut first_digest = None;
+        for (rel_path, path, id) in &members[1..] {
+            if id == first_id {
+                continue;
+            }
+            let first_digest = match &first_digest {
+                Some(digest) => digest,
+                None => first_digest.insert(digest_file(first_path)?),
+            };
+            let same = digest_file(path)? == *first_digest;
+            let fixed = same && self.options.fix;
+            if fixed {
+                let tmp_path = temp_path_for(path);
+                save_parent_modtime(parent_modtime_save, path)?;
+                std::fs::hard_link(first_path, &tmp_path)?;
+                std::fs::rename(&tmp_path, path)
+                    .with_context(|| format!("failed replacing {}", path.display()))?;
+            }
+            let reason = match same {
+                true => "no longer hardlinked to the rest of its link group",
+                false => "no longer hardlinked to the rest of its link group, with other content",
+            };
+            report.problems.push(FsckProblem { path: rel_path.clone(), reason: reason.to_owned(), fixed });
+        }
+
+        Ok(())
+    }
+}
+
+impl Expected {
+    fn name(self) -> &'static str {
+        match self {
+            Expected::Placeholder => "placeholder file",
+            Expected::Stored => "regular file",
+            Expected::Symlink => "symlink",
+            Expected::Special => "special file",
+            Expected::Directory => "directory",
+            Expected::Deleted => "deleted file",
+            Expected::Excluded => "excluded path",
+            Expected::Dropped => "dropped placeholder",
+        }
+    }
+}
@@ -0,0 +1,184 @@
+//! The configuration of container images, such as their entrypoint,
+//! environment and labels, which the file-level deltas do not carry unless
+//! given the configurations of both images.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::Error;
+use crate::utils::digest_bytes;
+
+/// The `config` object of an OCI image configuration
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ImageConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exposed_ports: Option<BTreeMap<String, serde_json::Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entrypoint: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cmd: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volumes: Option<BTreeMap<String, serde_json::Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub labels: Option<BTreeMap<String, String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_signal: Option<String>,
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n"))
+}
+
+impl ImageConfig {
+    /// Take the runtime configuration out of a whole image configuration, or
+    /// of the output of `docker inspect`
+    pub fn from_json(value: &serde_json::Value) -> anyhow::Result<Self> {
+        let value = match value.as_array() {
+            Some(images) => images.first()
+                .ok_or_else(|| Error::InvalidOciImage("no image configuration".to_owned()))?,
+            None => value,
+        };
+        let config = value.get("config").or_else(|| value.get("Config"))
+            .filter(|config| !config.is_null());
+        match config {
+            Some(config) => serde_json::from_value(config.clone())
+                .context("failed to parse the image configuration"),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Self::from_json(&load_config_json(path)?)
+    }
+
+    /// Dockerfile instructions giving an image built from scratch this
+    /// configuration
+    pub fn dockerfile_instructions(&self) -> String {
+        let mut lines = vec![];
+        let json = |values: &[String]| serde_json::to_string(values).unwrap_or_default();
+
+        for var in self.env.iter().flatten() {
+            let (name, value) = var.split_once('=').unwrap_or((var, ""));
+            lines.push(format!("ENV {}={}", name, quote(value)));
+        }
+        for (key, value) in self.labels.iter().flatten() {
+            lines.push(format!("LABEL {}={}", quote(key), quote(value)));
+        }
+        for port in self.exposed_ports.iter().flat_map(|ports| ports.keys()) {
+            lines.push(format!("EXPOSE {}", port));
+        }
+        if let Some(volumes) = self.volumes.as_ref().filter(|volumes| !volumes.is_empty()) {
+            lines.push(format!("VOLUME {}", json(&volumes.keys().cloned().collect::<Vec<_>>())));
+        }
+        if let Some(working_dir) = self.working_dir.as_ref().filter(|dir| !dir.is_empty()) {
+            lines.push(format!("WORKDIR {}", working_dir));
+        }
+        if let Some(stop_signal) = &self.stop_signal {
+            lines.push(format!("STOPSIGNAL {}", stop_signal));
+        }
+        if let Some(user) = self.user.as_ref().filter(|user| !user.is_empty()) {
+            lines.push(format!("USER {}", user));
+        }
+        // Setting the entrypoint resets the command, so it goes first
+        if let Some(entrypoint) = &self.entrypoint {
+            lines.push(format!("ENTRYPOINT {}", json(entrypoint)));
+        }
+        if let Some(cmd) = &self.cmd {
+            lines.push(format!("CMD {}", json(cmd)));
+        }
+
+        lines.into_iter().map(|line| line + "\n").collect()
+    }
+}
+
+/// Differences between the whole configurations of two images, including
+/// their history, as a JSON merge patch (RFC 7386) of the source one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfigDelta {
+    /// Digest of the source configuration, as serialized by deltaimage
+    pub source_digest: String,
+    pub patch: serde_json::Value,
+}
+
+impl ConfigDelta {
+    /// Differences from `source` to `target`. Values that become null in
+    /// `target` are removed, which a merge patch cannot tell apart.
+    pub fn new(source: &serde_json::Value, target: &serde_json::Value) -> Self {
+        Self { source_digest: config_digest(source), patch: merge_patch(source, target) }
+    }
+
+    /// Reconstruct the target configuration from the source one
+    pub fn apply(&self, source: &serde_json::Value) -> anyhow::Result<serde_json::Value> {
+        let digest = config_digest(source);
+        if digest != self.source_digest {
+            return Err(Error::ImageConfigMismatch(self.source_digest.clone(), digest).into());
+        }
+        let mut target = source.clone();
+        apply_merge_patch(&mut target, &self.patch);
+        Ok(target)
+    }
+}
+
+fn config_digest(config: &serde_json::Value) -> String {
+    format!("sha256:{}", digest_bytes(&serde_json::to_vec(config).unwrap_or_default()))
+}
+
+fn merge_patch(source: &serde_json::Value, target: &serde_json::Value) -> serde_json::Value {
+    let (Some(source), Some(target)) = (source.as_object(), target.as_object()) else {
+        return target.clone();
+    };
+
+    let mut patch = serde_json::Map::new();
+    for (key, value) in target {
+        match source.get(key) {
+            Some(source_value) if source_value == value => {}
+            Some(source_value) => {
+                patch.insert(key.clone(), merge_patch(source_value, value));
+            }
+            None => {
+                patch.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    for key in source.keys().filter(|key| !target.contains_key(*key)) {
+        patch.insert(key.clone(), serde_json::Value::Null);
+    }
+    serde_json::Value::Object(patch)
+}
+
+fn apply_merge_patch(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    let Some(patch) = patch.as_object() else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = serde_json::Value::Object(serde_json::Map::new());
+    }
+
+    let target = target.as_object_mut().unwrap();
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            apply_merge_patch(target.entry(key.clone()).or_insert(serde_json::Value::Null), value);
+        }
+    }
+}
+
+/// Read a whole image configuration, such as printed by `skopeo inspect --config`
+pub fn load_config_json(path: &Path) -> anyhow::Result<serde_json::Value> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    serde_json::from_reader(std::io::BufReader::new(file))
+        .with_context(|| format!("failed to parse {}", path.display()))
+}
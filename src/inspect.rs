@@ -0,0 +1,117 @@
+//! The paths of a delta directory as a tree, with the sizes stored for each
+//! subtree, for finding out where the size of a delta comes from.
+
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+use crate::list::DeltaEntry;
+use crate::platform;
+use crate::stats::DeltaStats;
+
+/// A path of a delta, with the paths beneath it for a directory
+#[derive(Debug, Clone, Default)]
+pub struct DeltaNode {
+    /// Last component of the path, `.` for the root
+    pub name: String,
+    /// Path relative to the root of the tree, empty for the root
+    pub path: PathBuf,
+    /// How the path is restored, none for directories only implied by the
+    /// paths beneath them
+    pub entry: Option<DeltaEntry>,
+    /// Size of the file stored in the delta directory for this path
+    pub delta_size: u64,
+    /// Delta size of the subtree
+    pub total_delta_size: u64,
+    /// Restored size of the subtree, counting files of unknown size as empty
+    pub total_size: u64,
+    /// Largest delta size first
+    pub children: Vec<DeltaNode>,
+}
+
+impl DeltaNode {
+    /// The tree of every path of a delta directory, as listed by
+    /// [`DeltaEntry::list`]
+    pub fn load(delta_dir: &Path) -> anyhow::Result<Self> {
+        let delta_sizes: HashMap<_, _> = DeltaStats::collect(delta_dir)?.files.into_iter()
+            .map(|file| (file.path, file.delta_size))
+            .collect();
+
+        let mut root = DeltaNode { name: ".".to_owned(), ..Default::default() };
+        for entry in DeltaEntry::list(delta_dir)? {
+            let path = PathBuf::from(&entry.path);
+            let names: Vec<_> = path.components()
+                .filter_map(|component| match component {
+                    Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+                    _ => None,
+                })
+                .collect();
+            root.insert(&names, entry, delta_sizes.get(&path).copied().unwrap_or_default());
+        }
+        root.sum();
+        Ok(root)
+    }
+
+    fn insert(&mut self, names: &[String], entry: DeltaEntry, delta_size: u64) {
+        let Some((name, rest)) = names.split_first() else {
+            self.entry = Some(entry);
+            self.delta_size = delta_size;
+            return;
+        };
+
+        // Paths come sorted, so mostly under the last child added
+        let index = match self.children.iter().rposition(|child| &child.name == name) {
+            Some(index) => index,
+            None => {
+                self.children.push(DeltaNode {
+                    name: name.clone(),
+                    path: self.path.join(name),
+                    ..Default::default()
+                });
+                self.children.len() - 1
+            }
+        };
+        self.children[index].insert(rest, entry, delta_size);
+    }
+
+    fn sum(&mut self) {
+        self.total_delta_size = self.delta_size;
+        self.total_size = self.entry.as_ref().and_then(|entry| entry.size).unwrap_or_default();
+        for child in self.children.iter_mut() {
+            child.sum();
+            self.total_delta_size += child.total_delta_size;
+            self.total_size += child.total_size;
+        }
+        self.children.sort_by(|a, b| b.total_delta_size.cmp(&a.total_delta_size)
+            .then_with(|| a.name.cmp(&b.name)));
+    }
+
+    /// How the path differs from the same path of `source_dir`, such as
+    /// `mode 0644 -> 0755`, or `new` if the source has no such path
+    pub fn changes_from(&self, source_dir: &Path) -> Vec<String> {
+        let Some(entry) = &self.entry else {
+            return vec![];
+        };
+        let Ok(metadata) = std::fs::symlink_metadata(source_dir.join(&self.path)) else {
+            return vec!["new".to_owned()];
+        };
+        if entry.kind == "deleted" {
+            return vec!["deleted".to_owned()];
+        }
+
+        let (mode, uid, gid, _, _) = platform::attributes(&metadata);
+        let mut changes = vec![];
+        if let Some(new_mode) = entry.mode.filter(|&new_mode| new_mode != mode & 0o7777) {
+            changes.push(format!("mode {:04o} -> {:04o}", mode & 0o7777, new_mode));
+        }
+        if let Some(new_uid) = entry.uid.filter(|&new_uid| new_uid != uid) {
+            changes.push(format!("uid {} -> {}", uid, new_uid));
+        }
+        if let Some(new_gid) = entry.gid.filter(|&new_gid| new_gid != gid) {
+            changes.push(format!("gid {} -> {}", gid, new_gid));
+        }
+        if let Some(size) = entry.size.filter(|&size| metadata.is_file() && size != metadata.len()) {
+            changes.push(format!("size {} -> {}", metadata.len(), size));
+        }
+        changes
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use anyhow::Context;
+use serde::{Serialize, Deserialize};
+
+use crate::metadata::{Algo, Directory, Holes};
+use crate::utils::temp_path_for;
+
+/// Name of the work journal placed at the root of the target directory while
+/// a diff is in progress
+pub(crate) const DIFF_JOURNAL_FILE: &str = "__deltaimage.diff-journal";
+
+/// State of the target tree taken before any file was rewritten, which a
+/// resumed diff can no longer find in the tree itself
+#[derive(Serialize, Deserialize)]
+pub(crate) struct DiffJournalHeader {
+    pub(crate) directories: Vec<Directory>,
+    pub(crate) link_groups: Vec<Vec<Vec<u8>>>,
+    pub(crate) parent_modtimes: Vec<(PathBuf, SystemTime)>,
+}
+
+/// Outcome of a file of the target tree, journaled before the file is rewritten
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct DiffJournalEntry {
+    pub(crate) path: Vec<u8>,
+    pub(crate) algo: Option<Algo>,
+    pub(crate) total_size: u64,
+    pub(crate) reduced_size: u64,
+    pub(crate) checksum: String,
+    #[serde(default)]
+    pub(crate) holes: Holes,
+    #[serde(default)]
+    pub(crate) base: Option<u32>,
+}
+
+/// Journaled entries by path
+pub(crate) type DiffJournalEntries = HashMap<Vec<u8>, DiffJournalEntry>;
+
+/// Append-only journal of a diff, one JSON document per line: the header,
+/// then an entry for each processed file.
+pub(crate) struct DiffJournal {
+    file: Mutex<File>,
+}
+
+impl DiffJournal {
+    /// Read the journal left by an interrupted diff, if any. A last entry cut
+    /// short by the interruption is ignored.
+    pub(crate) fn load(target_dir: &Path)
+        -> anyhow::Result<Option<(DiffJournalHeader, DiffJournalEntries)>>
+    {
+        let path = target_dir.join(DIFF_JOURNAL_FILE);
+        let Ok(file) = File::open(&path) else { return Ok(None) };
+        let mut lines = BufReader::new(file).lines();
+
+        let Some(header) = lines.next() else { return Ok(None) };
+        let header = serde_json::from_str(&header?)
+            .with_context(|| format!("invalid diff journal {}", path.display()))?;
+
+        let mut entries = HashMap::new();
+        for line in lines {
+            let Ok(entry) = serde_json::from_str::<DiffJournalEntry>(&line?) else { break };
+            entries.insert(entry.path.clone(), entry);
+        }
+
+        Ok(Some((header, entries)))
+    }
+
+    /// Start the journal over with a header and the entries of an interrupted
+    /// diff, if any
+    pub(crate) fn create<'a>(target_dir: &Path, header: &DiffJournalHeader,
+        entries: impl Iterator<Item = &'a DiffJournalEntry>) -> anyhow::Result<Self>
+    {
+        let path = target_dir.join(DIFF_JOURNAL_FILE);
+        let tmp_path = temp_path_for(&path);
+        let mut file = File::create(&tmp_path)
+            .with_context(|| format!("failed to create {}", tmp_path.display()))?;
+        writeln!(file, "{}", serde_json::to_string(header)?)?;
+        for entry in entries {
+            writeln!(file, "{}", serde_json::to_string(entry)?)?;
+        }
+        file.sync_data()?;
+        std::fs::rename(&tmp_path, &path)
+            .with_context(|| format!("failed to create {}", path.display()))?;
+
+        Ok(Self { file: Mutex::new(file) })
+    }
+
+    pub(crate) fn record(&self, entry: &DiffJournalEntry) -> anyhow::Result<()> {
+        let line = serde_json::to_string(entry)?;
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", line)?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    pub(crate) fn remove(target_dir: &Path) -> anyhow::Result<()> {
+        let path = target_dir.join(DIFF_JOURNAL_FILE);
+        std::fs::remove_file(&path)
+            .with_context(|| format!("failed removing {}", path.display()))
+    }
+}
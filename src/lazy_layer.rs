@@ -0,0 +1,360 @@
+//! Layers in the eStargz and zstd:chunked formats, which lazy-pulling
+//! snapshotters, such as stargz-snapshotter and the one of containers/storage,
+//! read a file at a time with range requests, finding it through a table of
+//! contents.
+//!
+//! Both formats are made from an uncompressed layer, compressing the headers
+//! and the content of each file as separate gzip members or zstd frames, so
+//! that each can be decompressed on its own:
+//!
+//! - eStargz appends the table of contents as a `stargz.index.json` tar
+//!   entry of its own gzip member, followed by a gzip footer giving its
+//!   offset. Large files are split into chunks.
+//! - zstd:chunked appends it as a zstd skippable frame, followed by a
+//!   skippable frame giving its position, so that the layer still
+//!   decompresses to the very same tar.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use anyhow::Context;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::Error;
+use crate::stream::read_at;
+use crate::utils::{civil_date, digest_file};
+
+/// Entries at the root of eStargz layers that are not part of the tree
+pub(crate) const ESTARGZ_METADATA: [&str; 3] = [ESTARGZ_TOC_NAME, ".prefetch.landmark", ".no.prefetch.landmark"];
+
+const ESTARGZ_TOC_NAME: &str = "stargz.index.json";
+/// Size of the chunks that eStargz splits files into
+const ESTARGZ_CHUNK_SIZE: u64 = 4 << 20;
+
+const ANNOTATION_ESTARGZ_TOC_DIGEST: &str = "containerd.io/snapshot/stargz/toc.digest";
+const ANNOTATION_ESTARGZ_UNCOMPRESSED_SIZE: &str = "io.containers.estargz.uncompressed-size";
+const ANNOTATION_ZSTD_CHUNKED_CHECKSUM: &str = "io.github.containers.zstd-chunked.manifest-checksum";
+const ANNOTATION_ZSTD_CHUNKED_POSITION: &str = "io.github.containers.zstd-chunked.manifest-position";
+
+const ZSTD_SKIPPABLE_MAGIC: u32 = 0x184d2a50;
+const ZSTD_CHUNKED_MAGIC: &[u8] = b"GNUlInUx";
+/// Manifest type of zstd:chunked for a table of contents as eStargz has
+const ZSTD_CHUNKED_MANIFEST_TOC: u64 = 1;
+
+/// A layer written in one of the lazy-pulling formats
+pub(crate) struct LazyLayer {
+    /// Digest of the uncompressed layer
+    pub(crate) diff_id: String,
+    /// Annotations of the layer descriptor, locating the table of contents
+    pub(crate) annotations: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+struct Toc {
+    version: u32,
+    entries: Vec<TocEntry>,
+}
+
+/// Entry of the table of contents, a file or a chunk of a file, in the
+/// fields common to eStargz and zstd:chunked
+#[derive(Serialize, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+struct TocEntry {
+    name: String,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "is_zero")]
+    size: u64,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    modtime: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    link_name: String,
+    #[serde(skip_serializing_if = "is_zero")]
+    mode: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    uid: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    gid: u64,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    user_name: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    group_name: String,
+    #[serde(skip_serializing_if = "is_zero")]
+    dev_major: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    dev_minor: u64,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    digest: String,
+    #[serde(skip_serializing_if = "is_zero")]
+    offset: u64,
+    /// Only in zstd:chunked
+    #[serde(skip_serializing_if = "is_zero")]
+    end_offset: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    chunk_offset: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    chunk_size: u64,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    chunk_digest: String,
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+/// An entry of the uncompressed layer, with where its content lies
+struct TarEntry {
+    toc: TocEntry,
+    /// Offset of the content, after the headers
+    data_start: u64,
+    data_len: u64,
+}
+
+/// Counts the bytes written, for offsets in the table of contents
+struct CountingWriter {
+    inner: BufWriter<File>,
+    offset: u64,
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.offset += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl CountingWriter {
+    fn create(path: &Path) -> anyhow::Result<Self> {
+        let inner = BufWriter::new(File::create(path)
+            .with_context(|| format!("failed to create {}", path.display()))?);
+        Ok(Self { inner, offset: 0 })
+    }
+
+    /// Write `data` as a gzip member of its own
+    fn gzip_member(&mut self, data: &[u8], level: u32) -> std::io::Result<()> {
+        let mut encoder = flate2::write::GzEncoder::new(self, flate2::Compression::new(level));
+        encoder.write_all(data)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    /// Write `data` as a zstd frame of its own
+    fn zstd_frame(&mut self, data: &[u8], level: i32) -> std::io::Result<()> {
+        let mut encoder = zstd::stream::write::Encoder::new(self, level)?;
+        encoder.write_all(data)?;
+        encoder.finish()?;
+        Ok(())
+    }
+
+    fn zstd_skippable_frame(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.write_all(&ZSTD_SKIPPABLE_MAGIC.to_le_bytes())?;
+        self.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.write_all(data)
+    }
+}
+
+/// The entries of the uncompressed layer at `path`
+fn read_entries(path: &Path) -> anyhow::Result<Vec<TarEntry>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut archive = tar::Archive::new(BufReader::new(file));
+    let mut entries = vec![];
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let header = entry.header();
+        let kind = match header.entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Continuous => "reg",
+            tar::EntryType::Directory => "dir",
+            tar::EntryType::Symlink => "symlink",
+            tar::EntryType::Link => "hardlink",
+            tar::EntryType::Char => "char",
+            tar::EntryType::Block => "block",
+            tar::EntryType::Fifo => "fifo",
+            other => {
+                return Err(Error::InvalidOciImage(format!("unsupported tar entry type {:?} in a lazy layer",
+                    other)).into());
+            }
+        };
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let (data_start, data_len) = (entry.raw_file_position(), header.entry_size()?);
+        let toc = TocEntry {
+            name: path.trim_start_matches("./").trim_matches('/').to_owned(),
+            kind,
+            size: if kind == "reg" { data_len } else { 0 },
+            modtime: rfc3339(header.mtime()?),
+            link_name: entry.link_name()?.map(|name| name.to_string_lossy().into_owned()).unwrap_or_default(),
+            mode: header.mode()? as u64,
+            uid: header.uid()?,
+            gid: header.gid()?,
+            user_name: header.username().ok().flatten().unwrap_or_default().to_owned(),
+            group_name: header.groupname().ok().flatten().unwrap_or_default().to_owned(),
+            dev_major: header.device_major().ok().flatten().unwrap_or_default() as u64,
+            dev_minor: header.device_minor().ok().flatten().unwrap_or_default() as u64,
+            ..Default::default()
+        };
+        entries.push(TarEntry { toc, data_start, data_len });
+    }
+    Ok(entries)
+}
+
+/// Convert the uncompressed layer at `tar_path` to an eStargz layer at
+/// `path`, compressed with gzip at `level`
+pub(crate) fn write_estargz(tar_path: &Path, path: &Path, level: u32) -> anyhow::Result<LazyLayer> {
+    let entries = read_entries(tar_path)?;
+    let tar = File::open(tar_path)?;
+    let mut out = CountingWriter::create(path)?;
+    let mut hasher = Sha256::new();
+    let mut uncompressed_size = 0;
+    let mut member = |out: &mut CountingWriter, data: &[u8]| -> std::io::Result<()> {
+        hasher.update(data);
+        uncompressed_size += data.len() as u64;
+        out.gzip_member(data, level)
+    };
+
+    let mut toc = vec![];
+    let mut pos = 0;
+    for entry in entries {
+        // Headers, after the padding of the previous entry
+        member(&mut out, &read_at(&tar, pos, entry.data_start - pos)?)?;
+        pos = entry.data_start + entry.data_len;
+        if entry.toc.name.is_empty() {
+            continue;
+        }
+
+        let first = toc.len();
+        if entry.toc.kind != "reg" || entry.data_len == 0 {
+            toc.push(entry.toc);
+            continue;
+        }
+        let mut file_hasher = Sha256::new();
+        let mut chunk_offset = 0;
+        while chunk_offset < entry.data_len {
+            let chunk_len = ESTARGZ_CHUNK_SIZE.min(entry.data_len - chunk_offset);
+            let data = read_at(&tar, entry.data_start + chunk_offset, chunk_len)?;
+            file_hasher.update(&data);
+            let chunk = match chunk_offset {
+                0 => entry.toc.clone(),
+                _ => TocEntry { name: entry.toc.name.clone(), kind: "chunk", ..Default::default() },
+            };
+            toc.push(TocEntry {
+                offset: out.offset,
+                chunk_offset,
+                // Left out for the last chunk
+                chunk_size: if chunk_offset + chunk_len < entry.data_len { chunk_len } else { 0 },
+                chunk_digest: format!("sha256:{:x}", Sha256::digest(&data)),
+                ..chunk
+            });
+            member(&mut out, &data)?;
+            chunk_offset += chunk_len;
+        }
+        toc[first].digest = format!("sha256:{:x}", file_hasher.finalize());
+    }
+
+    // The table of contents replaces the end of the original archive
+    let toc_json = serde_json::to_vec(&Toc { version: 1, entries: toc })?;
+    let toc_offset = out.offset;
+    let mut toc_tar = read_at(&tar, pos, pos.next_multiple_of(512) - pos)?;
+    let mut header = tar::Header::new_ustar();
+    header.set_path(ESTARGZ_TOC_NAME)?;
+    header.set_size(toc_json.len() as u64);
+    header.set_mode(0o644);
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_cksum();
+    toc_tar.extend_from_slice(header.as_bytes());
+    toc_tar.extend_from_slice(&toc_json);
+    toc_tar.resize(toc_tar.len().next_multiple_of(512) + 1024, 0);
+    member(&mut out, &toc_tar)?;
+
+    // Empty gzip member carrying the offset of the table of contents in
+    // its extra field
+    out.write_all(&[0x1f, 0x8b, 8, 4, 0, 0, 0, 0, 0, 0xff, 26, 0, b'S', b'G', 22, 0])?;
+    out.write_all(format!("{:016x}STARGZ", toc_offset).as_bytes())?;
+    out.write_all(&[1, 0, 0, 0xff, 0xff, 0, 0, 0, 0, 0, 0, 0, 0])?;
+    out.flush().with_context(|| format!("failed to write to {}", path.display()))?;
+
+    let annotations = BTreeMap::from([
+        (ANNOTATION_ESTARGZ_TOC_DIGEST.to_owned(), format!("sha256:{:x}", Sha256::digest(&toc_json))),
+        (ANNOTATION_ESTARGZ_UNCOMPRESSED_SIZE.to_owned(), uncompressed_size.to_string()),
+    ]);
+    Ok(LazyLayer { diff_id: format!("sha256:{:x}", hasher.finalize()), annotations })
+}
+
+/// Convert the uncompressed layer at `tar_path` to a zstd:chunked layer at
+/// `path`, compressed with zstd at `level`
+pub(crate) fn write_zstd_chunked(tar_path: &Path, path: &Path, level: i32) -> anyhow::Result<LazyLayer> {
+    let entries = read_entries(tar_path)?;
+    let tar = File::open(tar_path)?;
+    let mut out = CountingWriter::create(path)?;
+
+    let mut toc = vec![];
+    let mut pos = 0;
+    for entry in entries {
+        out.zstd_frame(&read_at(&tar, pos, entry.data_start - pos)?, level)?;
+        pos = entry.data_start + entry.data_len;
+        if entry.toc.name.is_empty() {
+            continue;
+        }
+        if entry.toc.kind != "reg" || entry.data_len == 0 {
+            toc.push(entry.toc);
+            continue;
+        }
+        let offset = out.offset;
+        let mut encoder = zstd::stream::write::Encoder::new(&mut out, level)?;
+        let mut file_hasher = Sha256::new();
+        let mut copied = 0;
+        while copied < entry.data_len {
+            let data = read_at(&tar, entry.data_start + copied, (entry.data_len - copied).min(1 << 20))?;
+            if data.is_empty() {
+                return Err(Error::InvalidOciImage(format!("truncated layer {}", tar_path.display())).into());
+            }
+            file_hasher.update(&data);
+            encoder.write_all(&data)?;
+            copied += data.len() as u64;
+        }
+        encoder.finish()?;
+        toc.push(TocEntry {
+            digest: format!("sha256:{:x}", file_hasher.finalize()),
+            offset,
+            end_offset: out.offset,
+            ..entry.toc
+        });
+    }
+    // The end of the archive, for the layer to decompress to the same tar
+    let end = tar.metadata()?.len();
+    out.zstd_frame(&read_at(&tar, pos, end - pos)?, level)?;
+
+    let manifest = serde_json::to_vec(&Toc { version: 1, entries: toc })?;
+    let compressed = zstd::stream::encode_all(&manifest[..], level)?;
+    let manifest_offset = out.offset + 8;
+    out.zstd_skippable_frame(&compressed)?;
+
+    let mut footer = vec![];
+    for value in [manifest_offset, compressed.len() as u64, manifest.len() as u64, ZSTD_CHUNKED_MANIFEST_TOC] {
+        footer.extend_from_slice(&value.to_le_bytes());
+    }
+    footer.extend_from_slice(ZSTD_CHUNKED_MAGIC);
+    out.zstd_skippable_frame(&footer)?;
+    out.flush().with_context(|| format!("failed to write to {}", path.display()))?;
+
+    let annotations = BTreeMap::from([
+        (ANNOTATION_ZSTD_CHUNKED_CHECKSUM.to_owned(), format!("sha256:{:x}", Sha256::digest(&compressed))),
+        (ANNOTATION_ZSTD_CHUNKED_POSITION.to_owned(), format!("{}:{}:{}:{}", manifest_offset,
+            compressed.len(), manifest.len(), ZSTD_CHUNKED_MANIFEST_TOC)),
+    ]);
+    Ok(LazyLayer { diff_id: format!("sha256:{}", digest_file(tar_path)?), annotations })
+}
+
+/// Seconds since the epoch as an RFC 3339 UTC time
+fn rfc3339(secs: u64) -> String {
+    let (year, month, day) = civil_date((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, secs_of_day / 3600,
+        secs_of_day / 60 % 60, secs_of_day % 60)
+}
@@ -0,0 +1,130 @@
+//! Generate file-level deltas between two directory trees, typically the root
+//! file systems of two container images, and apply them to restore the
+//! second tree from the first.
+//!
+//! ```no_run
+//! use deltaimage::{DeltaBuilder, DeltaApplier, DiffOptions};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! // Turn a copy of image B into a delta against image A
+//! DeltaBuilder::new("/source", "/delta")
+//!     .options(DiffOptions { jobs: Some(4), ..Default::default() })
+//!     .run()?;
+//!
+//! // Later, restore image B from image A and the delta
+//! DeltaApplier::new("/", "/delta").run()?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Errors are returned as `anyhow::Error`, carrying context about the failing
+//! path. Failures specific to delta processing can be inspected by downcasting
+//! to [`Error`].
+
+mod apply;
+mod archive;
+mod attributes;
+mod backend;
+mod block;
+mod cache;
+mod catalog;
+mod chunk_store;
+mod compare;
+mod containerd;
+mod deflate;
+mod diff;
+mod encryption;
+mod engine;
+mod error;
+mod estimate;
+mod features;
+mod fetch;
+mod file_errors;
+#[cfg(feature = "fs-image")]
+mod fs_image;
+mod fsck;
+mod filter;
+mod image_config;
+mod inspect;
+mod journal;
+mod lazy_layer;
+mod list;
+mod logging;
+mod metadata;
+mod mmap;
+mod oci;
+mod order;
+mod overlay;
+mod owners;
+mod package;
+mod patch_from;
+mod platform;
+mod registry;
+mod report;
+mod sbom;
+mod self_test;
+mod signing;
+mod similarity;
+mod snapshot;
+mod sparse;
+mod squash;
+mod stats;
+mod store;
+mod stream;
+mod tar_delta;
+mod throttle;
+mod tree_digest;
+#[cfg(feature = "tui")]
+mod tui;
+mod utils;
+mod verify;
+mod watch;
+mod xdelta;
+
+pub use apply::{ApplyOptions, ApplyStats, DeltaApplier, Durability};
+pub use archive::{pack_archive, unpack_archive, read_archive_index};
+pub use block::{apply_block, diff_block, BlockDiffOptions, BlockStats, Chunking};
+pub use catalog::{Catalog, CatalogEntry};
+pub use chunk_store::{assemble_chunks, export_chunks, ChunkIndex, ChunkOptions, ChunkStats};
+pub use compare::{CompareOptions, ComparedPath, DeltaComparison, PathChange, PathSizes};
+pub use containerd::{materialize_snapshot, SnapshotOptions};
+pub use diff::{DeltaBuilder, DiffOptions, DiffStats, OnError};
+pub use encryption::generate_encryption_key;
+pub use engine::{diff_containers, load_delta, save_delta, Engine};
+pub use error::Error;
+pub use estimate::{estimate, Estimate, EstimateOptions};
+pub use features::FEATURES;
+pub use fetch::{fetch_delta, is_url, FetchOptions};
+pub use file_errors::{FileErrorKind, FileErrorSummary};
+#[cfg(feature = "fs-image")]
+pub use fs_image::{apply_fs_image, diff_fs_image, FsImageOptions};
+pub use fsck::{DeltaChecker, FsckOptions, FsckProblem, FsckReport};
+pub use image_config::{load_config_json, ConfigDelta, ImageConfig};
+pub use inspect::DeltaNode;
+pub use list::DeltaEntry;
+pub use logging::{init_logging, LogFormat};
+pub use metadata::{set_meta_path, Algo, ApplyState, Directory, FsImage, FsImageKind, Holes, Journal, LinkGroup,
+    MetaData, MetaFormat, Placeholder, Special, SpecialKind, Symlink, Timestamp, DELTAIMAGE_META_FILE,
+    DELTAIMAGE_META_BIN_FILE, META_FORMAT_VERSION, MIN_META_FORMAT_VERSION, REVERSE_DELTA_DIR};
+pub use oci::{apply_oci, diff_oci, diff_oci_layers, LayerCompression, DELTA_DIR_NAME};
+pub use order::record_apply_order;
+pub use owners::{IdRange, OwnerMap, Ownership};
+pub use package::package_self_extracting;
+pub use registry::{diff_registry, pull_config, pull_image, push_image, resolve_digest,
+    ImageReference, RegistryOptions};
+pub use report::{FailedFile, FileReport, Report, UnappliedMetaData};
+pub use sbom::{PackageChange, PackageChangeKind, PackageSummary, Sbom};
+pub use self_test::{build_description, self_test, Capability, SelfTestReport};
+pub use signing::generate_key;
+pub use snapshot::apply_on_snapshot;
+pub use squash::DeltaSquasher;
+pub use stats::{DeltaStats, StoredFile};
+pub use store::{pull_delta, push_delta};
+pub use tar_delta::{apply_tar, diff_tar};
+pub use throttle::{set_priorities, IoPriority};
+pub use tree_digest::digest_tree;
+#[cfg(feature = "tui")]
+pub use tui::browse_delta;
+pub use verify::{DeltaVerifier, VerifyOptions, VerifyProblem, VerifyReport};
+pub use watch::DeltaWatcher;
+pub use xdelta::{XDelta3Params, XDelta3Secondary};
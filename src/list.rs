@@ -0,0 +1,105 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use serde::Serialize;
+use walkdir::WalkDir;
+
+use crate::metadata::{is_meta_file, MetaData, SpecialKind, REVERSE_DELTA_DIR};
+use crate::platform::{MetadataExt, OsStrExt};
+use crate::utils::{drop_components, path_from_bytes};
+
+/// A path that a delta restores or deletes
+#[derive(Serialize, Debug, Clone)]
+pub struct DeltaEntry {
+    pub path: String,
+    /// Name of the algorithm for files, `keep` for unmodified files, `new` for
+    /// added ones and `deleted` for removed ones, or the type of other paths
+    pub kind: String,
+    /// Size of the restored file, unknown for deltas of older releases
+    pub size: Option<u64>,
+    /// Permission bits
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl DeltaEntry {
+    fn new(path: &[u8], kind: &str) -> Self {
+        let path = match path {
+            b"" => Path::new(".").to_owned(),
+            path => path_from_bytes(path),
+        };
+        Self {
+            path: path.to_string_lossy().into_owned(),
+            kind: kind.to_owned(),
+            size: None,
+            mode: None,
+            uid: None,
+            gid: None,
+        }
+    }
+
+    fn owned(self, mode: u32, uid: u32, gid: u32) -> Self {
+        Self { mode: Some(mode & 0o7777), uid: Some(uid), gid: Some(gid), ..self }
+    }
+
+    /// Every path of the delta directory with how it is restored, sorted by
+    /// path. The reverse delta of a bidirectional one is left out.
+    pub fn list(delta_dir: &Path) -> anyhow::Result<Vec<Self>> {
+        let md = MetaData::load(delta_dir)?;
+        let sizes: HashMap<_, _> = md.sizes.iter().cloned().collect();
+        let excluded: HashSet<_> = md.excluded.iter().collect();
+        let kinds: HashMap<_, _> = md.changes.iter().map(|(algo, path)| (path, algo.name()))
+            .chain(md.keep_files.iter().map(|path| (path, "keep")))
+            .collect();
+
+        let mut entries = Vec::new();
+        let n = delta_dir.components().count();
+        let walker = WalkDir::new(delta_dir).into_iter()
+            .filter_entry(|entry| entry.path() != delta_dir.join(REVERSE_DELTA_DIR));
+        for entry in walker {
+            let entry = entry?;
+            let rel_path = drop_components(n, entry.path());
+            if !entry.file_type().is_file() || is_meta_file(&rel_path) {
+                continue;
+            }
+
+            let path = rel_path.as_os_str().as_bytes().to_owned();
+            if excluded.contains(&path) {
+                continue;
+            }
+
+            // Files of the delta tree carry the meta-data of the restored ones
+            let metadata = entry.metadata()?;
+            let kind = kinds.get(&path).copied().unwrap_or("new");
+            let size = sizes.get(&path).copied().or_else(|| (kind == "new").then_some(metadata.len()));
+            entries.push(DeltaEntry { size, ..DeltaEntry::new(&path, kind) }
+                .owned(metadata.mode(), metadata.uid(), metadata.gid()));
+        }
+
+        for directory in md.directories.iter() {
+            entries.push(DeltaEntry::new(&directory.path, "directory")
+                .owned(directory.mode, directory.uid, directory.gid));
+        }
+        for symlink in md.symlinks.iter() {
+            entries.push(DeltaEntry::new(&symlink.path, "symlink")
+                .owned(0o777, symlink.uid, symlink.gid));
+        }
+        for special in md.specials.iter() {
+            let kind = match special.kind {
+                SpecialKind::Fifo => "fifo",
+                SpecialKind::CharDevice => "char-device",
+                SpecialKind::BlockDevice => "block-device",
+                SpecialKind::Socket => "socket",
+            };
+            entries.push(DeltaEntry::new(&special.path, kind)
+                .owned(special.mode, special.uid, special.gid));
+        }
+        for path in md.deleted_files.iter() {
+            entries.push(DeltaEntry::new(path, "deleted"));
+        }
+
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+}
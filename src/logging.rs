@@ -0,0 +1,63 @@
+//! Leveled logging of the work done by deltaimage, through the `tracing`
+//! crate, printed to stderr as text or as JSON lines.
+//!
+//! The work on each file runs under a `file` span, which tags the events
+//! logged meanwhile with the path of the file, and whose time taken is logged
+//! at the trace level once done.
+
+use std::str::FromStr;
+
+use tracing::Level;
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::prelude::*;
+
+/// How log messages are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// One human-readable line per message
+    #[default]
+    Text,
+    /// One JSON object per line, with the time, level, module, file and
+    /// message as fields
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {}", s)),
+        }
+    }
+}
+
+/// Send the events logged by deltaimage to stderr. Warnings are always
+/// printed, and each level of `verbosity` adds informational, debug and trace
+/// events in turn.
+pub fn init_logging(verbosity: u8, format: LogFormat) -> anyhow::Result<()> {
+    let level = match verbosity {
+        0 => Level::WARN,
+        1 => Level::INFO,
+        2 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+
+    // Spans are kept at all levels, for the events in them to name the file
+    let filter = filter_fn(move |metadata| {
+        metadata.target().starts_with("deltaimage") && (metadata.is_span() || *metadata.level() <= level)
+    });
+    let span_events = if level == Level::TRACE { FmtSpan::CLOSE } else { FmtSpan::NONE };
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_ansi(false)
+        .with_span_events(span_events);
+    let registry = tracing_subscriber::registry().with(filter);
+    match format {
+        LogFormat::Text => registry.with(layer.without_time().with_target(false)).try_init(),
+        LogFormat::Json => registry.with(layer.json().flatten_event(true)).try_init(),
+    }.map_err(|_| anyhow::anyhow!("logging is already initialized"))
+}
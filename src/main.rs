@@ -1,29 +1,577 @@
 mod cmdline;
-mod utils;
-
-use std::cell::RefCell;
-use std::collections::{BTreeSet, HashMap, HashSet};
-use std::ffi::OsStr;
-use std::os::unix::prelude::{OsStrExt, MetadataExt};
-use std::path::PathBuf;
-use std::rc::Rc;
+mod spec;
 
 use anyhow::Context;
 use structopt::StructOpt;
 use cmdline::Cmdline;
-use thiserror::Error;
-use utils::{drop_components, get_meta_data, set_meta_data, serialize_to_json, deserialize_from_json};
-use walkdir::WalkDir;
-use serde::{Serialize, Deserialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use deltaimage::{DeltaBuilder, DeltaApplier, DeltaWatcher, DeltaVerifier, DeltaSquasher, DiffOptions, ApplyOptions,
+    VerifyOptions, DeltaChecker, FsckOptions, RegistryOptions, Report, MetaData, META_FORMAT_VERSION, DeltaStats,
+    DeltaEntry, DeltaComparison, CompareOptions, XDelta3Params, FetchOptions, Catalog, CatalogEntry,
+    EstimateOptions, SnapshotOptions, BlockDiffOptions, OwnerMap, Ownership, ImageConfig, LayerCompression,
+    ChunkIndex, ChunkOptions, load_config_json, DiffStats, PackageSummary, Sbom};
 
 fn main() -> anyhow::Result<()> {
     let opt = Cmdline::from_args();
+    let verbosity = if opt.debug { opt.verbose.max(2) } else { opt.verbose };
+    // Watch reports each build as it goes
+    let verbosity = match opt.command {
+        cmdline::Command::Watch(_) => verbosity.max(1),
+        _ => verbosity,
+    };
+    deltaimage::init_logging(verbosity, opt.log_format)?;
+    if let Some(meta_path) = &opt.meta_path {
+        deltaimage::set_meta_path(meta_path)?;
+    }
+
     match opt.command {
         cmdline::Command::Diff(info) => {
-            diff(opt.debug, info)?;
+            if info.xdelta3_window == 0 {
+                return Err(anyhow::anyhow!("--xdelta3-window must not be zero"));
+            }
+            if !(0.0..=1.0).contains(&info.min_delta_ratio) {
+                return Err(anyhow::anyhow!("--min-delta-ratio must be between 0 and 1"));
+            }
+            if !(0.0..=1.0).contains(&info.fallback_threshold) {
+                return Err(anyhow::anyhow!("--fallback-threshold must be between 0 and 1"));
+            }
+            let options = DiffOptions {
+                jobs: info.jobs,
+                stream_threshold: info.stream_threshold,
+                patch_from_threshold: info.patch_from_threshold,
+                compression_level: info.compression_level,
+                archive: info.archive.filter(|_| info.format == cmdline::Format::Archive),
+                detect_renames: !info.no_detect_renames,
+                pair_similar: !info.no_pair_similar,
+                prehash: !info.no_prehash,
+                trust_mtime: info.trust_mtime,
+                dedup_identical: info.dedup_identical,
+                compress_new_files: info.compress_new_files,
+                drop_placeholders: info.drop_placeholders,
+                ignore_times: info.ignore_times,
+                ignore_owner: info.ignore_owner,
+                max_errors: info.max_errors,
+                exclude: info.exclude,
+                include: info.include,
+                dry_run: info.dry_run,
+                force: info.force,
+                fresh: info.fresh,
+                extra_sources: info.extra_source,
+                output: info.output.clone().filter(|_| !info.from_tar),
+                bidirectional: info.bidirectional,
+                meta_format: info.meta_format,
+                xdelta3: XDelta3Params {
+                    level: info.xdelta3_level,
+                    secondary: info.xdelta3_secondary,
+                    window: info.xdelta3_window,
+                },
+                algo: Some(info.algo).filter(|algo| algo != "auto"),
+                optimize: info.optimize,
+                cache_dir: info.cache_dir,
+                sign_key: info.sign_key,
+                encrypt_key: info.encrypt_key,
+                on_error: info.on_error,
+                min_file_size: info.min_file_size,
+                min_delta_ratio: info.min_delta_ratio,
+                image_configs: match (&info.source_config, &info.target_config) {
+                    (Some(source), Some(target)) => {
+                        Some((load_config_json(source)?, load_config_json(target)?))
+                    }
+                    _ => None,
+                },
+                layer_compression: LayerCompression::default(),
+                layer_size: None,
+            };
+            let stats = match &info.push {
+                _ if info.from_tar => {
+                    let delta_path = info.output.as_ref().unwrap();
+                    deltaimage::diff_tar(&info.source_dir, &info.target_delta_dir, delta_path,
+                        &options)?
+                }
+                _ if info.from_container => {
+                    deltaimage::diff_containers(info.engine, &info.source_dir.to_string_lossy(),
+                        &info.target_delta_dir.to_string_lossy(), info.output.as_ref().unwrap(),
+                        &default_work_dir(), options)?
+                }
+                Some(push) if info.from_registry => {
+                    let registry_options = RegistryOptions {
+                        insecure: info.insecure_registry,
+                    };
+                    deltaimage::diff_registry(&info.source_dir.to_string_lossy(),
+                        &info.target_delta_dir.to_string_lossy(), push, &default_work_dir(),
+                        &registry_options, options)?
+                }
+                _ => {
+                    DeltaBuilder::new(info.source_dir, info.target_delta_dir)
+                        .options(options)
+                        .run()?
+                }
+            };
+            if info.dry_run {
+                for file in &stats.files {
+                    println!("{} {}: {} -> {}", file.algo, file.path, file.original_size,
+                        file.delta_size);
+                }
+                println!("Total size: {}, estimated delta size: {}", stats.total_size,
+                    stats.reduced_size);
+            }
+            let outcome = DiffOutcome::of(&stats, info.fallback_threshold);
+            if let Some(format) = info.summary_format {
+                print_summary(format, &stats, outcome)?;
+            }
+            if let Some(report) = info.report {
+                Report::new(stats.files, stats.duration).failed_files(stats.failed).write(&report)?;
+            }
+            if info.exit_code {
+                std::process::exit(outcome.exit_code());
+            }
+        }
+        cmdline::Command::Apply(info) if info.from_tar => {
+            deltaimage::set_priorities(info.nice, info.ionice)?;
+            let [delta_path] = &info.delta_target_dirs[..] else {
+                return Err(anyhow::anyhow!("--from-tar applies a single delta"));
+            };
+            let output = info.output.unwrap();
+            let fetch_options = FetchOptions { retries: info.fetch_retries, digest: info.delta_digest };
+            let fetched = fetch_if_url(delta_path, &output, &fetch_options)?;
+            deltaimage::apply_tar(&info.source_dir, fetched.as_ref().unwrap_or(delta_path), &output)?;
+            if let Some(fetched) = fetched {
+                std::fs::remove_file(fetched)?;
+            }
         }
         cmdline::Command::Apply(info) => {
-            apply(opt.debug, info)?;
+            deltaimage::set_priorities(info.nice, info.ionice)?;
+            let io_limit = match info.io_limit {
+                Some(limit) if limit.is_finite() && limit > 0.0 => Some((limit * 1e6) as u64),
+                Some(_) => return Err(anyhow::anyhow!("--io-limit must be a positive number of MB/s")),
+                None => None,
+            };
+            if info.overlay_upperdir.is_some() && info.delta_target_dirs.len() > 1 {
+                return Err(anyhow::anyhow!("--overlay-upperdir applies a single delta"));
+            }
+            if info.snapshot.is_some() && info.delta_target_dirs.len() > 1 {
+                return Err(anyhow::anyhow!("--snapshot applies a single delta"));
+            }
+            let started = Instant::now();
+            let mut files = Vec::new();
+            let mut unapplied_metadata = Vec::new();
+            let fetch_options = FetchOptions { retries: info.fetch_retries, digest: info.delta_digest };
+            let fetched = match &info.archive {
+                Some(archive) if info.format == cmdline::Format::Archive => {
+                    fetch_if_url(archive, &info.delta_target_dirs[0], &fetch_options)?
+                }
+                _ => None,
+            };
+            let archive = fetched.clone().or(info.archive);
+            let ownership = match &info.owner_map {
+                _ if info.numeric_owner => Ownership::Numeric,
+                Some(path) if path.as_os_str() == "subuid" => Ownership::Map(OwnerMap::subordinate()?),
+                Some(path) => Ownership::Map(OwnerMap::load(path)?),
+                None if info.skip_chown => Ownership::Skip,
+                None => Ownership::Numeric,
+            };
+            let mut image_config = info.source_config.as_deref().map(load_config_json).transpose()?;
+            let mut source_dir = info.source_dir;
+            for (index, delta_target_dir) in info.delta_target_dirs.into_iter().enumerate() {
+                let options = ApplyOptions {
+                    archive: archive.clone()
+                        .filter(|_| index == 0 && info.format == cmdline::Format::Archive),
+                    reverse: info.reverse,
+                    rollback: info.rollback,
+                    verify_key: info.verify_key.clone(),
+                    decrypt_key: info.decrypt_key.clone(),
+                    extra_sources: info.extra_source.clone(),
+                    jobs: info.jobs,
+                    ownership: ownership.clone(),
+                    best_effort_metadata: info.best_effort_metadata,
+                    ignore_times: info.ignore_times,
+                    max_errors: info.max_errors,
+                    source_config: image_config.take(),
+                    priority: info.priority.clone(),
+                    io_limit,
+                    overlay_upper_dir: info.overlay_upperdir.clone(),
+                    durability: info.durability,
+                    ..Default::default()
+                };
+                let stats = match &info.snapshot {
+                    Some(target_dir) => deltaimage::apply_on_snapshot(&source_dir, &delta_target_dir,
+                        target_dir, options)?,
+                    None => DeltaApplier::new(&source_dir, &delta_target_dir)
+                        .options(options)
+                        .run()?,
+                };
+                if let Some(tree_digest) = &stats.tree_digest {
+                    println!("Restored tree digest: {}", tree_digest);
+                }
+                files.extend(stats.files);
+                unapplied_metadata.extend(stats.unapplied_metadata);
+                image_config = stats.image_config;
+                source_dir = delta_target_dir;
+            }
+            if let (Some(path), Some(image_config)) = (&info.emit_config, &image_config) {
+                std::fs::write(path, serde_json::to_vec_pretty(image_config)?)
+                    .with_context(|| format!("writing {}", path.display()))?;
+            }
+            if let Some(fetched) = fetched {
+                std::fs::remove_file(fetched)?;
+            }
+            if let Some(report) = info.report {
+                Report::new(files, started.elapsed())
+                    .unapplied_metadata(unapplied_metadata)
+                    .write(&report)?;
+            }
+        }
+        cmdline::Command::Watch(info) => {
+            let options = DiffOptions {
+                jobs: info.jobs,
+                exclude: info.exclude,
+                include: info.include,
+                ..DiffOptions::default()
+            };
+            DeltaWatcher::new(info.source_dir, info.target_dir, info.output)
+                .options(options)
+                .debounce(Duration::from_millis(info.debounce_ms))
+                .run()?;
+        }
+        cmdline::Command::Estimate(info) => {
+            let options = EstimateOptions {
+                jobs: info.jobs,
+                exclude: info.exclude,
+                include: info.include,
+            };
+            let estimate = deltaimage::estimate(&info.source_dir, &info.target_dir, &options)?;
+            if info.json {
+                println!("{}", serde_json::to_string_pretty(&estimate)?);
+            } else {
+                println!("Files: {} unchanged, {} modified, {} new", estimate.unchanged_files,
+                    estimate.modified_files, estimate.new_files);
+                println!("Total size: {}, estimated delta size: {}", estimate.total_size,
+                    estimate.estimated_delta_size);
+            }
+        }
+        cmdline::Command::Keygen(info) => {
+            deltaimage::generate_key(&info.secret_key, &info.public_key)?;
+        }
+        cmdline::Command::EncryptionKeygen(info) => {
+            deltaimage::generate_encryption_key(&info.key)?;
+        }
+        cmdline::Command::Digest(info) => {
+            println!("{}", deltaimage::digest_tree(&info.dir)?);
+        }
+        cmdline::Command::SelfTest(info) => {
+            let work_dir = info.work_dir.unwrap_or_else(default_work_dir);
+            let report = deltaimage::self_test(&work_dir)?;
+            println!("deltaimage {}", report.build);
+            for capability in &report.capabilities {
+                match &capability.problem {
+                    None => println!("{}: ok", capability.name),
+                    Some(problem) => println!("{}: unavailable{}, {}", capability.name,
+                        if capability.required { " (required)" } else { "" }, problem),
+                }
+            }
+            report.into_result()?;
+        }
+        cmdline::Command::Version(info) => {
+            println!("deltaimage {}", env!("CARGO_PKG_VERSION"));
+            if info.features {
+                let features: Vec<_> = deltaimage::FEATURES.iter()
+                    .map(|(name, enabled)| format!("{}{}", if *enabled { '+' } else { '-' }, name))
+                    .collect();
+                println!("{}", features.join(" "));
+            }
+        }
+        cmdline::Command::Verify(info) => {
+            let options = VerifyOptions {
+                jobs: info.jobs,
+                decrypt_key: info.decrypt_key,
+                extra_sources: info.extra_source,
+            };
+            let report = DeltaVerifier::new(info.source_dir, info.delta_dir)
+                .options(options)
+                .run()?;
+            for problem in &report.problems {
+                println!("{}: {}", problem.path.display(), problem.reason);
+            }
+            println!("Checked {} paths, {} problems", report.checked, report.problems.len());
+            report.into_result()?;
+        }
+        cmdline::Command::Fsck(info) => {
+            let report = DeltaChecker::new(info.delta_dir)
+                .options(FsckOptions { fix: info.fix })
+                .run()?;
+            for problem in &report.problems {
+                let fixed = if problem.fixed { " (fixed)" } else { "" };
+                println!("{}: {}{}", problem.path.display(), problem.reason, fixed);
+            }
+            println!("Checked {} paths, {} problems", report.checked, report.problems.len());
+            report.into_result()?;
+        }
+        cmdline::Command::Squash(info) => {
+            let options = DiffOptions {
+                meta_format: info.meta_format,
+                ..Default::default()
+            };
+            DeltaSquasher::new(info.source_dir, info.first_delta_dir, info.second_delta_dir)
+                .options(options)
+                .run(&info.output_dir)?;
+        }
+        cmdline::Command::Stats(info) => {
+            let stats = DeltaStats::collect(&info.delta_dir)?;
+            print_stats(&stats, info.top);
+            if let (Some(source_sbom), Some(target_sbom)) = (&info.source_sbom, &info.target_sbom) {
+                let summary = PackageSummary::new(&Sbom::load(source_sbom)?, &Sbom::load(target_sbom)?, &stats);
+                print_packages(&summary);
+            }
+        }
+        cmdline::Command::List(info) => {
+            let entries = DeltaEntry::list(&info.delta_dir)?;
+            if info.json {
+                println!("{}", serde_json::to_string_pretty(&entries)?);
+            } else {
+                fn show<T: ToString>(value: Option<T>) -> String {
+                    value.map(|value| value.to_string()).unwrap_or_else(|| "-".to_owned())
+                }
+                for entry in entries {
+                    println!("{:>4} {:>5}/{:<5} {:>12} {:<15} {}",
+                        show(entry.mode.map(|mode| format!("{:04o}", mode))),
+                        show(entry.uid), show(entry.gid), show(entry.size), entry.kind, entry.path);
+                }
+            }
+        }
+        cmdline::Command::Compare(info) => {
+            if info.factor < 1.0 {
+                return Err(anyhow::anyhow!("--factor must be at least 1"));
+            }
+            let options = CompareOptions { factor: info.factor, min_size: info.min_size };
+            let comparison = DeltaComparison::compare(&info.old_delta_dir, &info.new_delta_dir, &options)?;
+            if info.json {
+                println!("{}", serde_json::to_string_pretty(&comparison)?);
+            } else {
+                print_comparison(&comparison);
+            }
+            if info.check {
+                comparison.into_result()?;
+            }
+        }
+        #[cfg(feature = "tui")]
+        cmdline::Command::Tui(info) => {
+            deltaimage::browse_delta(&info.delta_dir, info.source.as_deref())?;
+        }
+        cmdline::Command::RecordOrder(info) => {
+            let report = Report::load(&info.report)?;
+            let count = deltaimage::record_apply_order(&info.delta_dir, &report,
+                info.sign_key.as_deref())?;
+            println!("Recorded the order of {} files in {}", count, info.delta_dir.display());
+        }
+        cmdline::Command::MigrateMeta(info) => {
+            let format_version = MetaData::migrate_dir(&info.delta_dir, info.meta_format)?;
+            if format_version == META_FORMAT_VERSION {
+                println!("{} is already in format version {}", info.delta_dir.display(),
+                    format_version);
+            } else {
+                println!("Migrated {} from format version {} to {}", info.delta_dir.display(),
+                    format_version, META_FORMAT_VERSION);
+            }
+        }
+        cmdline::Command::DiffOci(info) => {
+            if info.layer_size == Some(0) {
+                return Err(anyhow::anyhow!("--layer-size must not be zero"));
+            }
+            let options = DiffOptions {
+                jobs: info.jobs,
+                layer_compression: info.layer_compression,
+                layer_size: info.layer_size,
+                ..Default::default()
+            };
+            let work_dir = info.work_dir.unwrap_or_else(default_work_dir);
+            if info.per_layer {
+                deltaimage::diff_oci_layers(&info.source_image, &info.target_image,
+                    &info.output_image, &work_dir, options)?;
+            } else {
+                deltaimage::diff_oci(&info.source_image, &info.target_image, &info.output_image,
+                    &work_dir, options)?;
+            }
+        }
+        cmdline::Command::ApplyOci(info) => {
+            let options = ApplyOptions {
+                layered: info.layered,
+                layer_compression: info.layer_compression,
+                ..Default::default()
+            };
+            let work_dir = info.work_dir.unwrap_or_else(default_work_dir);
+            deltaimage::apply_oci(&info.delta_image, &info.output_image, &work_dir, options)?;
+        }
+        cmdline::Command::DiffBlock(info) => {
+            if info.chunk_size == 0 {
+                return Err(anyhow::anyhow!("--chunk-size must not be zero"));
+            }
+            let options = BlockDiffOptions {
+                chunking: info.chunking,
+                chunk_size: info.chunk_size,
+                jobs: info.jobs,
+                compression_level: info.compression_level,
+                ..Default::default()
+            };
+            let stats = deltaimage::diff_block(&info.source, &info.target, &info.output, &options)?;
+            println!("Total size: {}, delta size: {} (copied: {}, zero: {}, patched: {}, stored: {})",
+                stats.target_size, stats.delta_size, stats.copied, stats.zero, stats.patched, stats.raw);
+        }
+        cmdline::Command::ApplyBlock(info) => {
+            deltaimage::apply_block(&info.source, &info.delta, &info.output)?;
+        }
+        #[cfg(feature = "fs-image")]
+        cmdline::Command::DiffFsImage(info) => {
+            let fs_options = deltaimage::FsImageOptions { mount: info.mount, mkfs_args: info.mkfs_arg };
+            let options = DiffOptions {
+                jobs: info.jobs,
+                sign_key: info.sign_key,
+                meta_format: info.meta_format,
+                ..Default::default()
+            };
+            let work_dir = info.work_dir.unwrap_or_else(default_work_dir);
+            let stats = deltaimage::diff_fs_image(&info.source_image, &info.target_image, &info.output,
+                &work_dir, &fs_options, options)?;
+            println!("Total size: {}, reduced size: {}", stats.total_size, stats.reduced_size);
+        }
+        #[cfg(feature = "fs-image")]
+        cmdline::Command::ApplyFsImage(info) => {
+            let fs_options = deltaimage::FsImageOptions { mount: info.mount, ..Default::default() };
+            let options = ApplyOptions {
+                jobs: info.jobs,
+                verify_key: info.verify_key,
+                ..Default::default()
+            };
+            let work_dir = info.work_dir.unwrap_or_else(default_work_dir);
+            deltaimage::apply_fs_image(&info.source_image, &info.delta_dir, &info.output_image,
+                &work_dir, &fs_options, options)?;
+        }
+        cmdline::Command::SaveDelta(info) => {
+            let options = DiffOptions {
+                jobs: info.jobs,
+                ..Default::default()
+            };
+            let work_dir = info.work_dir.unwrap_or_else(default_work_dir);
+            deltaimage::save_delta(info.engine, &info.source_image, &info.target_image,
+                &info.output, &work_dir, options)?;
+        }
+        cmdline::Command::ContainerdSnapshot(info) => {
+            let options = SnapshotOptions {
+                snapshotter: info.snapshotter,
+                apply: ApplyOptions {
+                    jobs: info.jobs,
+                    verify_key: info.verify_key,
+                    decrypt_key: info.decrypt_key,
+                    ..Default::default()
+                },
+            };
+            let work_dir = info.work_dir.unwrap_or_else(default_work_dir);
+            let fetch_options = FetchOptions { digest: info.delta_digest, ..Default::default() };
+            let fetched = fetch_if_url(&info.delta, &work_dir, &fetch_options)?;
+            let stats = deltaimage::materialize_snapshot(&info.source_image,
+                fetched.as_ref().unwrap_or(&info.delta), &info.name, &work_dir, options)?;
+            if let Some(fetched) = fetched {
+                std::fs::remove_file(fetched)?;
+            }
+            println!("Committed snapshot {} in {:.2}s, total size: {}", info.name,
+                stats.duration.as_secs_f64(), stats.total_size);
+        }
+        cmdline::Command::LoadDelta(info) => {
+            let options = ApplyOptions {
+                layered: info.layered,
+                ..Default::default()
+            };
+            let work_dir = info.work_dir.unwrap_or_else(default_work_dir);
+            deltaimage::load_delta(info.engine, &info.delta, info.tag.as_deref(), &work_dir,
+                options)?;
+        }
+        cmdline::Command::Package(info) if info.self_extracting => {
+            let binary = match info.binary {
+                Some(binary) => binary,
+                None => std::env::current_exe()?,
+            };
+            deltaimage::package_self_extracting(&info.delta_dir, &binary, &info.output)?;
+        }
+        cmdline::Command::Package(info) => {
+            deltaimage::pack_archive(&info.delta_dir, &info.output)?;
+        }
+        cmdline::Command::Push(info) => {
+            let key = deltaimage::push_delta(&info.store, &info.from, &info.to, &info.archive)?;
+            println!("Pushed {}", key);
+        }
+        cmdline::Command::Pull(info) => {
+            deltaimage::pull_delta(&info.store, &info.from, &info.to, &info.output)?;
+        }
+        cmdline::Command::ExportChunks(info) => {
+            if info.chunk_size < 64 {
+                return Err(anyhow::anyhow!("--chunk-size must be at least 64"));
+            }
+            let options = ChunkOptions {
+                chunk_size: info.chunk_size,
+                compression_level: info.compression_level,
+                jobs: info.jobs,
+            };
+            let stats = deltaimage::export_chunks(&info.delta_dir, &info.store, &info.index, &options)?;
+            println!("Exported {} bytes as {} chunks, {} new taking {} bytes", stats.size, stats.chunks,
+                stats.new_chunks, stats.new_size);
+        }
+        cmdline::Command::ApplyChunks(info) => {
+            let local_store = match info.local_store {
+                Some(local_store) => local_store,
+                None if !deltaimage::is_url(&info.store) => PathBuf::from(&info.store),
+                None => return Err(anyhow::anyhow!("--local-store is needed for a store given as a URL")),
+            };
+            let fetch_options = FetchOptions { retries: info.fetch_retries, digest: None };
+            let fetched = fetch_if_url(&info.index, &info.delta_target_dir, &fetch_options)?;
+            let index = ChunkIndex::load(fetched.as_ref().unwrap_or(&info.index))?;
+            if let Some(fetched) = fetched {
+                std::fs::remove_file(fetched)?;
+            }
+
+            let mut name = info.delta_target_dir.file_name().unwrap_or_default().to_owned();
+            name.push(".deltaimage-chunks");
+            let archive = info.delta_target_dir.with_file_name(name);
+            let stats = deltaimage::assemble_chunks(&index, &info.store, &local_store, &archive, info.jobs,
+                &fetch_options)?;
+            println!("Fetched {} of {} chunks, {} bytes", stats.new_chunks, stats.chunks, stats.new_size);
+
+            let options = ApplyOptions {
+                archive: Some(archive.clone()),
+                verify_key: info.verify_key,
+                jobs: info.jobs,
+                ..Default::default()
+            };
+            let stats = DeltaApplier::new(&info.source_dir, &info.delta_target_dir)
+                .options(options)
+                .run()?;
+            std::fs::remove_file(&archive)?;
+            if let Some(tree_digest) = &stats.tree_digest {
+                println!("Restored tree digest: {}", tree_digest);
+            }
+        }
+        cmdline::Command::CatalogAdd(info) => {
+            let mut catalog = Catalog::load_or_default(&info.catalog)?;
+            catalog.add(CatalogEntry {
+                from: info.from,
+                to: info.to,
+                size: info.delta.metadata()?.len(),
+                location: info.location,
+            });
+            catalog.save(&info.catalog)?;
+        }
+        cmdline::Command::Resolve(info) => {
+            let catalog = Catalog::load(&info.catalog)?;
+            let chain = catalog.resolve(&info.from, &info.to)?;
+            if info.json {
+                println!("{}", serde_json::to_string_pretty(&chain)?);
+            } else {
+                for delta in &chain {
+                    println!("{} -> {}: {} {}", delta.from, delta.to, delta.size,
+                        delta.location.as_deref().unwrap_or("-"));
+                }
+                println!("Total size: {}", chain.iter().map(|delta| delta.size).sum::<u64>());
+            }
         }
         cmdline::Command::DockerFile(df) => {
             docker_file(&df)?;
@@ -33,429 +581,444 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn docker_file(df: &cmdline::DockerFile) -> anyhow::Result<()> {
-    let mut version = env!("CARGO_PKG_VERSION").to_owned();
-
-    let override_version = match df {
-        cmdline::DockerFile::Diff { override_version, .. } => {
-            override_version
-        },
-        cmdline::DockerFile::Apply { override_version, .. } => {
-            override_version
-        },
+/// Download a delta given as a URL next to `near`, where an interrupted
+/// download is resumed from by the next run, returning the downloaded file
+fn fetch_if_url(delta: &Path, near: &Path, options: &FetchOptions) -> anyhow::Result<Option<PathBuf>> {
+    let Some(url) = delta.to_str().filter(|delta| deltaimage::is_url(delta)) else {
+        return Ok(None);
     };
+    let mut name = near.file_name().unwrap_or_default().to_owned();
+    name.push(".deltaimage-fetch");
+    let path = near.with_file_name(name);
+    deltaimage::fetch_delta(url, &path, options)?;
+    Ok(Some(path))
+}
 
-    if let Some(override_version) = override_version {
-        version = override_version.to_owned();
+/// What a diff produced, for pipelines to branch on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DiffOutcome {
+    Delta,
+    Unchanged,
+    /// Most of the modified content is stored whole rather than patched
+    FallbackHeavy,
+}
+
+impl DiffOutcome {
+    fn of(stats: &DiffStats, fallback_threshold: f64) -> Self {
+        if stats.is_unchanged() {
+            DiffOutcome::Unchanged
+        } else if stats.fallback_ratio() > fallback_threshold {
+            DiffOutcome::FallbackHeavy
+        } else {
+            DiffOutcome::Delta
+        }
     }
 
-    match df {
-        cmdline::DockerFile::Diff { image_a, image_b, .. } => {
-            println!(r#"
-# Calculate delta under a temporary image
-FROM scratch as delta
-COPY --from={image_a} / /source/
-COPY --from={image_b} / /delta/
-COPY --from=deltaimage/deltaimage:{version} /opt/deltaimage /opt/deltaimage
-RUN ["/opt/deltaimage", "diff", "/source", "/delta"]
+    fn name(self) -> &'static str {
+        match self {
+            DiffOutcome::Delta => "delta",
+            DiffOutcome::Unchanged => "unchanged",
+            DiffOutcome::FallbackHeavy => "fallback-heavy",
+        }
+    }
 
-# Make the deltaimage
-FROM {image_a}
-COPY --from=delta /delta /__deltaimage__.delta
-"#);
-        },
-        cmdline::DockerFile::Apply { delta_image, .. } => {
-            println!(r#"
-# Apply a delta under a temporary image
-FROM {delta_image} as applied
-COPY --from=deltaimage/deltaimage:{version} /opt/deltaimage /opt/deltaimage
-USER root
-RUN ["/opt/deltaimage", "apply", "/", "/__deltaimage__.delta"]
+    /// Errors exit with 1
+    fn exit_code(self) -> i32 {
+        match self {
+            DiffOutcome::Delta => 0,
+            DiffOutcome::Unchanged => 2,
+            DiffOutcome::FallbackHeavy => 3,
+        }
+    }
+}
 
-# Make the original image by applying the delta
-FROM scratch
-COPY --from=applied /__deltaimage__.delta/ /
-"#);
-        },
+fn print_summary(format: cmdline::SummaryFormat, stats: &DiffStats, outcome: DiffOutcome)
+    -> anyhow::Result<()>
+{
+    let ratio = match stats.total_size {
+        0 => 0.0,
+        total_size => stats.reduced_size as f64 / total_size as f64,
+    };
+    let mut fields = vec![
+        ("result", outcome.name().to_owned()),
+        ("total_size", stats.total_size.to_string()),
+        ("delta_size", stats.reduced_size.to_string()),
+        ("saved_bytes", stats.total_size.saturating_sub(stats.reduced_size).to_string()),
+        ("delta_ratio", format!("{:.4}", ratio)),
+        ("fallback_ratio", format!("{:.4}", stats.fallback_ratio())),
+        ("modified_files", stats.files.iter().filter(|file| file.algo != "keep").count().to_string()),
+        ("new_files", stats.new_files.to_string()),
+        ("deleted_files", stats.deleted_files.to_string()),
+        ("failed_files", stats.failed.len().to_string()),
+        ("duration_secs", format!("{:.3}", stats.duration.as_secs_f64())),
+    ];
+    let stages: Vec<_> = stats.stages.iter()
+        .map(|(stage, duration)| (format!("{}_secs", stage), format!("{:.3}", duration.as_secs_f64())))
+        .collect();
+    fields.extend(stages.iter().map(|(key, value)| (key.as_str(), value.clone())));
+
+    let lines: String = fields.iter().map(|(key, value)| format!("{}={}\n", key, value)).collect();
+    match format {
+        cmdline::SummaryFormat::KeyValue => print!("{}", lines),
+        cmdline::SummaryFormat::Github => {
+            let append = |var: &str, text: &str| -> anyhow::Result<()> {
+                let path = std::env::var_os(var)
+                    .ok_or_else(|| anyhow::anyhow!("${} is not set, as by GitHub Actions", var))?;
+                let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&path)
+                    .with_context(|| format!("opening {}", Path::new(&path).display()))?;
+                std::io::Write::write_all(&mut file, text.as_bytes())?;
+                Ok(())
+            };
+            append("GITHUB_OUTPUT", &lines)?;
+            if std::env::var_os("GITHUB_STEP_SUMMARY").is_some() {
+                let table: String = fields.iter()
+                    .map(|(key, value)| format!("| {} | {} |\n", key, value))
+                    .collect();
+                append("GITHUB_STEP_SUMMARY", &format!("### Delta\n\n| | |\n|-|-|\n{}\n", table))?;
+            }
+        }
     }
 
     Ok(())
 }
 
-const DELTAIMAGE_META_FILE: &str = "__deltaimage.meta.json";
-
-#[derive(Error, Debug)]
-pub enum Error {
-    #[error("XDelta3 encode error")]
-    XDelta3EncodeError,
-
-    #[error("XDelta3 decode error")]
-    XDelta3DecodeError,
-
-    #[error("XDelta3 failed validation: {0} -> {1}")]
-    XDelta3FailedValidation(PathBuf, PathBuf),
-
-    #[error("XDelta3 failed deflation: {0} -> {1}")]
-    XDelta3FailedDeflation(PathBuf, PathBuf),
+fn default_work_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("deltaimage-{}", std::process::id()))
+}
 
-    #[error("File time error")]
-    FileTimeError(std::io::Error, PathBuf),
+fn print_stats(stats: &DeltaStats, top: usize) {
+    let counts: Vec<_> = stats.counts.iter().map(|(algo, count)| format!("{} {}", count, algo))
+        .collect();
+    println!("Files: {}, {} deleted", counts.join(", "), stats.deleted_files);
+    println!("Delta size: {}", stats.total_delta_size);
+
+    println!();
+    println!("Largest stored files:");
+    for file in stats.files.iter().take(top) {
+        let original_size = file.original_size.map(|size| size.to_string())
+            .unwrap_or_else(|| "?".to_owned());
+        println!("{:>12} {:>12} {:<15} {}", file.delta_size, original_size, file.algo,
+            file.path.display());
+    }
 
-    #[error("Output delta dir already exists: {0}")]
-    DeltaDirExists(PathBuf),
+    println!();
+    println!("Delta size / original size:");
+    let histogram = stats.ratio_histogram();
+    let max = histogram.iter().copied().max().unwrap_or(0).max(1);
+    for (bucket, count) in histogram.iter().enumerate() {
+        let range = match bucket {
+            10 => ">100%".to_owned(),
+            _ => format!("{}-{}%", bucket * 10, bucket * 10 + 10),
+        };
+        println!("{:>8} {:<40} {}", range, "#".repeat(count * 40 / max), count);
+    }
 }
 
-#[derive(Serialize, Deserialize, Hash, Eq, PartialEq, Ord, PartialOrd)]
-enum Algo {
-    XDelta3,
-    AsIs,
+fn print_packages(summary: &PackageSummary) {
+    fn show(value: &Option<String>) -> &str {
+        value.as_deref().unwrap_or("-")
+    }
+    println!();
+    println!("Package changes:");
+    for package in &summary.packages {
+        println!("{:<8} {:>12} {:>6} {} {} -> {}", package.change.name(), package.delta_size, package.files,
+            package.name, show(&package.old_version), show(&package.new_version));
+    }
+    println!("Delta size of files outside of packages: {}", summary.unpackaged_delta_size);
 }
 
-#[derive(Serialize, Deserialize)]
-struct MetaData {
-    version: String,
-    keep_files: Vec<Vec<u8>>,
-    changes: Vec<(Algo, Vec<u8>)>,
+fn print_comparison(comparison: &DeltaComparison) {
+    fn show(value: Option<u64>) -> String {
+        value.map(|value| value.to_string()).unwrap_or_else(|| "-".to_owned())
+    }
+    for path in &comparison.paths {
+        println!("{:<12} {:>12} -> {:<12} {:>12} -> {:<12} {}", path.change.name(),
+            show(path.old.map(|sizes| sizes.delta_size)), show(path.new.map(|sizes| sizes.delta_size)),
+            show(path.old.and_then(|sizes| sizes.size)), show(path.new.and_then(|sizes| sizes.size)),
+            path.path);
+    }
+    println!("Delta size: {} -> {}, {} paths reported", comparison.old_delta_size,
+        comparison.new_delta_size, comparison.paths.len());
 }
 
-fn diff(debug: bool, info: cmdline::Diff) -> anyhow::Result<()> {
-    let mut changes: Vec<_> = Vec::new();
-    let mut keep_files: Vec<_> = Vec::new();
-    let mut orig_files = BTreeSet::new();
+fn docker_file(df: &cmdline::DockerFile) -> anyhow::Result<()> {
+    if let cmdline::DockerFile::Spec { spec, output } = df {
+        return docker_file(&spec::docker_file(spec, output.clone())?);
+    }
 
-    let n = info.source_dir.components().count();
-    let mut total_size = 0u64;
-    let mut reduced_size = 0u64;
+    let mut version = env!("CARGO_PKG_VERSION").to_owned();
 
-    for entry in WalkDir::new(&info.source_dir) {
-        let entry = entry?;
-        let path = entry.path();
-        let rel_path = drop_components(n, &path);
+    let (override_version, output) = match df {
+        cmdline::DockerFile::Diff { override_version, output, .. } => {
+            (override_version, output)
+        },
+        cmdline::DockerFile::Apply { override_version, output, .. } => {
+            (override_version, output)
+        },
+        cmdline::DockerFile::Spec { .. } => unreachable!(),
+    };
 
-        if entry.file_type().is_file() {
-            orig_files.insert(rel_path);
-        }
+    if let Some(override_version) = override_version {
+        version = override_version.to_owned();
     }
 
-    let mut parent_modtime_save = HashMap::new();
-    let mut fsid_link_groups = HashMap::new();
-    let mut path_link_groups = HashMap::new();
-
-    let n = info.target_delta_dir.components().count();
-    for entry in WalkDir::new(&info.target_delta_dir) {
-        let entry = entry?;
-        let path = entry.path();
-        let rel_path = drop_components(n, &path);
-
-        if entry.file_type().is_file() {
-            let metadata = entry.metadata()?;
-            let fsid = (metadata.ino(), metadata.dev());
-            if metadata.nlink() >= 2 {
-                use std::collections::hash_map;
-                let item = match fsid_link_groups.entry(fsid) {
-                    hash_map::Entry::Vacant(v) => v.insert(Rc::new(RefCell::new(None::<PathBuf>))),
-                    hash_map::Entry::Occupied(o) => o.into_mut(),
-                };
-                path_link_groups.insert(rel_path, item.clone());
-            }
+    let mut deltaimage = format!("deltaimage/deltaimage:{}", version);
+    let digest = match &output.deltaimage_digest {
+        Some(digest) => Some(digest.clone()),
+        None if output.pin_digest => {
+            Some(deltaimage::resolve_digest(&deltaimage, &RegistryOptions::default())
+                .with_context(|| format!("resolving the digest of {}", deltaimage))?)
         }
-    }
-
-    for entry in WalkDir::new(&info.target_delta_dir) {
-        let entry = entry?;
-        let path = entry.path();
-        let rel_path = drop_components(n, &path);
-
-        if entry.file_type().is_file() {
-            if orig_files.remove(&rel_path) {
-                // File exists in two the two images, need to compare
-
-                let src_path = info.source_dir.join(&rel_path);
-                let old_content = std::fs::read(&src_path)?;
-                let target_path = info.target_delta_dir.join(&rel_path);
-                let meta_data = get_meta_data(&target_path)?;
-                let new_content = std::fs::read(&target_path)?;
-
-                if let Some(parent) = target_path.parent() {
-                    use std::collections::hash_map;
-                    match parent_modtime_save.entry(parent.to_owned()) {
-                        hash_map::Entry::Vacant(v) => {
-                            v.insert(parent.metadata()?.modified()?);
-                        },
-                        hash_map::Entry::Occupied(_) => {}
-                    }
-                }
-
-                total_size += new_content.len() as u64;
-
-                if let Some(x) = path_link_groups.get(&rel_path) {
-                    let mut m = x.borrow_mut();
-                    match &*m {
-                        Some(other_path) => {
-                            let target_other_path = info.target_delta_dir.join(&other_path);
-                            std::fs::remove_file(&target_path)
-                                .with_context(|| format!("failed removing {}",
-                                        target_path.display()))?;
-                            std::fs::hard_link(target_other_path, &target_path)?;
-                            continue;
-                        },
-                        None => {
-                            *m = Some(target_path.clone());
-                        },
-                    }
-                };
-
-                if old_content != new_content {
-                    // Modified files, keep only the changes
-                    let delta = xdelta3::encode(&new_content, &old_content)
-                        .ok_or_else(|| Error::XDelta3EncodeError)?;
-
-                    if debug {
-                        println!("Modified {}: {} {} -> {}", rel_path.display(),
-                            old_content.len(), new_content.len(), delta.len())
-                    }
-
-                    if let Some(deflated_content) = xdelta3::decode(&delta, &old_content) {
-                        if deflated_content != new_content {
-                            return Err(Error::XDelta3FailedValidation(src_path, target_path).into());
-                        }
-                    } else {
-                        println!("Fallback to AsIs {}", target_path.display());
-
-                        std::fs::remove_file(&target_path)
-                            .with_context(|| format!("failed removing {}",
-                                    target_path.display()))?;
-                        std::fs::write(&target_path, new_content)
-                            .with_context(|| format!("failed to write to {}",
-                                    target_path.display()))?;
-                        set_meta_data(&target_path, meta_data)
-                            .with_context(|| format!("failed to set meta-data to {}",
-                                    target_path.display()))?;
-                        changes.push((Algo::AsIs, rel_path.as_os_str().as_bytes().to_owned()));
-                        continue;
-                    }
-
-                    reduced_size += delta.len() as u64;
-
-                    // Now write the changes, the meta-data of the original file are copied
-                    std::fs::remove_file(&target_path)
-                        .with_context(|| format!("failed to remove {}",
-                                target_path.display()))?;
-                    std::fs::write(&target_path, delta)
-                        .with_context(|| format!("failed to write to {}",
-                                target_path.display()))?;
-                    set_meta_data(&target_path, meta_data)
-                        .with_context(|| format!("failed to set meta-data to {}",
-                                target_path.display()))?;
-
-                    // We register that we have a delta here
-                    changes.push((Algo::XDelta3, rel_path.as_os_str().as_bytes().to_owned()));
-                    continue;
-                } else {
-                    // File not modified - keep a zero-sized file just for meta-data
-
-                    if debug {
-                        println!("Keep {}: {}", rel_path.display(),
-                            entry.path().metadata()?.len());
-                    }
-
-                    std::fs::remove_file(&target_path)
-                        .with_context(|| format!("failed removing {}",
-                                target_path.display()))?;
-                    std::fs::write(&target_path, "")
-                        .with_context(|| format!("failed to write to {}",
-                                target_path.display()))?;
-                    set_meta_data(&target_path, meta_data)
-                        .with_context(|| format!("failed to set meta-data to {}",
-                                target_path.display()))?;
-                }
-
-                keep_files.push(rel_path.as_os_str().as_bytes().to_owned());
-            }
+        None => None,
+    };
+    if let Some(digest) = digest {
+        let valid = digest.strip_prefix("sha256:")
+            .is_some_and(|hex| hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()));
+        if !valid {
+            return Err(anyhow::anyhow!("invalid digest {}, expected sha256:<hex>", digest));
         }
+        deltaimage = format!("{}@{}", deltaimage, digest);
     }
 
-    if debug {
-        println!("Total size: {}", total_size);
-        println!("Reduced size: {}", reduced_size);
-    }
-
-    let md = MetaData {
-        keep_files,
-        changes,
-        version: env!("CARGO_PKG_VERSION").to_owned(),
+    // Arguments of the RUN instructions, in exec form
+    let meta_path = match &output.delta_meta_path {
+        Some(path) => format!(", \"--meta-path\", {}", serde_json::to_string(&path.to_string_lossy())?),
+        None => String::new(),
     };
 
-    for (pathname, modified) in parent_modtime_save {
-        let mtime = filetime::FileTime::from_system_time(modified);
-        filetime::set_file_times(&pathname, mtime, mtime).map_err(|e| {
-            crate::Error::FileTimeError(e, pathname.to_owned())
-        })?;
-    }
+    use cmdline::Builder;
 
-    serialize_to_json(&md, &info.target_delta_dir.join(DELTAIMAGE_META_FILE))?;
+    let dockerfile = match df {
+        cmdline::DockerFile::Diff { platform: Some(_), builder: Builder::Buildah | Builder::Kaniko, .. } |
+        cmdline::DockerFile::Apply { platform: Some(_), builder: Builder::Buildah | Builder::Kaniko, .. } => {
+            return Err(anyhow::anyhow!("--platform is only supported with the docker and podman builders"));
+        },
+        cmdline::DockerFile::Diff { image_a, image_b, platform: Some(platform), .. } => {
+            format!(r#"
+# Build for several platforms with:
+#     docker buildx build --platform {platform} ...
+#
+# Each platform variant of the images is copied in, while deltaimage itself
+# runs natively on the build platform.
+FROM --platform=$TARGETPLATFORM {image_a} as source
+FROM --platform=$TARGETPLATFORM {image_b} as target
+FROM --platform=$BUILDPLATFORM {deltaimage} as deltaimage
 
-    Ok(())
-}
+# Calculate delta under a temporary image
+FROM --platform=$BUILDPLATFORM scratch as delta
+COPY --from=source / /source/
+COPY --from=target / /delta/
+COPY --from=deltaimage /opt/deltaimage /opt/deltaimage
+RUN ["/opt/deltaimage"{meta_path}, "diff", "--fresh", "/source", "/delta"]
 
-fn apply(debug: bool, info: cmdline::Apply) -> anyhow::Result<()> {
-    let metadata_path = info.delta_target_dir.join(DELTAIMAGE_META_FILE);
-    let md: MetaData =
-        deserialize_from_json(&metadata_path)
-        .with_context(|| format!("error reading meta-data from {}", metadata_path.display()))?;
-
-    // Load lists
-    let changes: BTreeSet<_> = md.changes.into_iter().collect();
-    let mut parent_modtime_save = HashMap::new();
-
-    let mut reduced_size = 0;
-    let mut total_size = 0;
-
-    // Detect hardlinks
-    let mut fsid_link_groups = HashMap::new();
-    let n = info.delta_target_dir.components().count();
-    let mut recreated_paths = HashSet::new();
-
-    for entry in WalkDir::new(&info.delta_target_dir) {
-        let entry = entry?;
-        let path = entry.path();
-        let rel_path = drop_components(n, &path);
-
-        if entry.file_type().is_file() {
-            let metadata = entry.metadata()?;
-            let fsid = (metadata.ino(), metadata.dev());
-            if metadata.nlink() >= 2 {
-                use std::collections::hash_map;
-                let item = match fsid_link_groups.entry(fsid) {
-                    hash_map::Entry::Vacant(v) => v.insert(Rc::new(RefCell::new(Vec::new()))),
-                    hash_map::Entry::Occupied(o) => o.into_mut(),
-                };
-                item.borrow_mut().push(rel_path);
-            }
-        }
-    }
+# Make the deltaimage
+FROM --platform=$TARGETPLATFORM {image_a}
+COPY --from=delta /delta /__deltaimage__.delta
+"#)
+        },
+        cmdline::DockerFile::Apply { delta_image, platform: Some(platform), .. } => {
+            format!(r#"
+# Build for several platforms with:
+#     docker buildx build --platform {platform} ...
+#
+# Each platform variant of the delta image is copied in, while deltaimage
+# itself runs natively on the build platform.
+FROM --platform=$TARGETPLATFORM {delta_image} as deltaimage-source
+FROM --platform=$BUILDPLATFORM {deltaimage} as deltaimage
 
-    // Handle modified files
-    for (algo, relative_path) in changes.into_iter() {
-        let relative_path = PathBuf::from(OsStr::from_bytes(relative_path.as_ref()));
-        let source_path = info.source_dir.join(&relative_path);
+# Apply a delta under a temporary image
+FROM --platform=$BUILDPLATFORM scratch as applied
+COPY --from=deltaimage-source / /image/
+COPY --from=deltaimage /opt/deltaimage /opt/deltaimage
+RUN ["/opt/deltaimage"{meta_path}, "apply", "/image", "/image/__deltaimage__.delta"]
 
-        let orig = std::fs::read(&source_path)?;
-        let delta_path = info.delta_target_dir.join(&relative_path);
-        let patch_data = std::fs::read(&delta_path)?;
+# Make the original image by applying the delta
+FROM --platform=$TARGETPLATFORM scratch
+COPY --from=applied /image/__deltaimage__.delta/ /
+"#)
+        },
+        cmdline::DockerFile::Diff { image_a, image_b, builder: Builder::Docker, .. } => {
+            format!(r#"
+# Calculate delta under a temporary image
+FROM scratch as delta
+COPY --from={image_a} / /source/
+COPY --from={image_b} / /delta/
+COPY --from={deltaimage} /opt/deltaimage /opt/deltaimage
+RUN ["/opt/deltaimage"{meta_path}, "diff", "--fresh", "/source", "/delta"]
 
-        if let Some(parent) = delta_path.parent() {
-            use std::collections::hash_map;
-            match parent_modtime_save.entry(parent.to_owned()) {
-                hash_map::Entry::Vacant(v) => {
-                    v.insert(parent.metadata()?.modified()?);
-                },
-                hash_map::Entry::Occupied(_) => {}
-            }
-        }
+# Make the deltaimage
+FROM {image_a}
+COPY --from=delta /delta /__deltaimage__.delta
+"#)
+        },
+        cmdline::DockerFile::Diff { image_a, image_b, builder: Builder::Podman, .. } => {
+            format!(r#"
+# Calculate delta under a temporary image, with deltaimage bind-mounted
+FROM scratch as delta
+COPY --from={image_a} / /source/
+COPY --from={image_b} / /delta/
+RUN --mount=type=bind,from=docker.io/{deltaimage},source=/opt/deltaimage,target=/opt/deltaimage \
+    ["/opt/deltaimage"{meta_path}, "diff", "--fresh", "/source", "/delta"]
 
-        if debug {
-            println!("Checking {}, {} + {} ->", relative_path.display(),
-            orig.len(), delta_path.metadata()?.len());
-        }
+# Make the deltaimage
+FROM {image_a}
+COPY --from=delta /delta /__deltaimage__.delta
+"#)
+        },
+        cmdline::DockerFile::Diff { image_a, image_b, builder: Builder::Buildah, .. } => {
+            format!(r#"
+# Calculate delta under a temporary image based on deltaimage, rather than
+# running from scratch
+FROM docker.io/{deltaimage} as delta
+COPY --from={image_a} / /source/
+COPY --from={image_b} / /delta/
+RUN ["/opt/deltaimage"{meta_path}, "diff", "--fresh", "/source", "/delta"]
 
-        let deflated_content = match algo {
-            Algo::XDelta3 => xdelta3::decode(&patch_data, &orig)
-                .ok_or_else(|| Error::XDelta3FailedDeflation(source_path.clone(),
-                delta_path.clone()))?,
-            Algo::AsIs => patch_data.clone(),
-        };
+# Make the deltaimage
+FROM {image_a}
+COPY --from=delta /delta /__deltaimage__.delta
+"#)
+        },
+        cmdline::DockerFile::Diff { image_a, image_b, builder: Builder::Kaniko, .. } => {
+            format!(r#"
+# Name the images as stages first, for them to be copied from
+FROM {image_a} as source
+FROM {image_b} as target
+
+# Calculate delta under a temporary image based on deltaimage
+FROM docker.io/{deltaimage} as delta
+COPY --from=source / /source/
+COPY --from=target / /delta/
+RUN ["/opt/deltaimage"{meta_path}, "diff", "--fresh", "/source", "/delta"]
 
-        if debug {
-            println!("Modified {}: {} -> {}", relative_path.display(), patch_data.len(),
-                deflated_content.len())
-        }
+# Make the deltaimage
+FROM {image_a}
+COPY --from=delta /delta /__deltaimage__.delta
+"#)
+        },
+        cmdline::DockerFile::Apply { delta_image, builder: Builder::Docker, .. } => {
+            format!(r#"
+# Apply a delta under a temporary image
+FROM {delta_image} as applied
+COPY --from={deltaimage} /opt/deltaimage /opt/deltaimage
+USER root
+RUN ["/opt/deltaimage"{meta_path}, "apply", "/", "/__deltaimage__.delta"]
 
-        reduced_size += patch_data.len() as u64;
-        total_size += deflated_content.len() as u64;
+# Make the original image by applying the delta
+FROM scratch
+COPY --from=applied /__deltaimage__.delta/ /
+"#)
+        },
+        cmdline::DockerFile::Apply { delta_image, builder: Builder::Podman | Builder::Buildah, .. } => {
+            format!(r#"
+# Apply a delta under a temporary image, with deltaimage bind-mounted
+FROM {delta_image} as applied
+USER root
+RUN --mount=type=bind,from=docker.io/{deltaimage},source=/opt/deltaimage,target=/opt/deltaimage \
+    ["/opt/deltaimage"{meta_path}, "apply", "/", "/__deltaimage__.delta"]
 
-        let meta_data = get_meta_data(&delta_path)?;
-        std::fs::remove_file(&delta_path)?;
-        std::fs::write(&delta_path, deflated_content)?;
-        set_meta_data(&delta_path, meta_data)?;
-        recreated_paths.insert(relative_path);
-    }
+# Make the original image by applying the delta
+FROM scratch
+COPY --from=applied /__deltaimage__.delta/ /
+"#)
+        },
+        cmdline::DockerFile::Apply { delta_image, builder: Builder::Kaniko, .. } => {
+            format!(r#"
+# Name the deltaimage image as a stage first, for it to be copied from
+FROM docker.io/{deltaimage} as deltaimage
 
-    // Handle files that were not modified - simply copy from source
-    for relative_path in md.keep_files.into_iter() {
-        let relative_path = PathBuf::from(OsStr::from_bytes(relative_path.as_ref()));
-        if debug {
-            println!("Checking {}", relative_path.display())
-        }
-        let orig = std::fs::read(info.source_dir.join(&relative_path))?;
-        let delta_path = info.delta_target_dir.join(&relative_path);
+# Apply a delta under a temporary image
+FROM {delta_image} as applied
+COPY --from=deltaimage /opt/deltaimage /opt/deltaimage
+USER root
+RUN ["/opt/deltaimage"{meta_path}, "apply", "/", "/__deltaimage__.delta"]
 
-        if let Some(parent) = delta_path.parent() {
-            use std::collections::hash_map;
-            match parent_modtime_save.entry(parent.to_owned()) {
-                hash_map::Entry::Vacant(v) => {
-                    v.insert(parent.metadata()?.modified()?);
-                },
-                hash_map::Entry::Occupied(_) => {}
-            }
-        }
+# Make the original image by applying the delta
+FROM scratch
+COPY --from=applied /__deltaimage__.delta/ /
+"#)
+        },
+        cmdline::DockerFile::Spec { .. } => unreachable!(),
+    };
 
-        if debug {
-            println!("Keeping {}: {}", relative_path.display(), orig.len())
+    let config = match df {
+        cmdline::DockerFile::Apply { config_from: Some(image), .. } => {
+            deltaimage::pull_config(image, &RegistryOptions::default())
+                .with_context(|| format!("fetching the configuration of {}", image))?
+                .dockerfile_instructions()
         }
+        cmdline::DockerFile::Apply { config_file: Some(path), .. } => {
+            ImageConfig::load(path)?.dockerfile_instructions()
+        }
+        _ => String::new(),
+    };
 
-        total_size += orig.len() as u64;
-
-        let meta_data = get_meta_data(&delta_path)?;
-        std::fs::write(&delta_path, orig)?;
-        set_meta_data(&delta_path, meta_data)?;
-        recreated_paths.insert(relative_path);
-    }
-
-    if debug {
-        println!("Reduced size: {}", reduced_size);
-        println!("Inflated size: {}", total_size);
-    }
-
-    // Restore hardlinks
-    for (_, linkgroup) in fsid_link_groups.into_iter() {
-        let linkgroup = linkgroup.borrow();
-        for path in linkgroup.iter() {
-            if recreated_paths.contains(path) {
-                for other_path in linkgroup.iter() {
-                    if other_path != path {
-                        let abs_path = info.delta_target_dir.join(&path);
-                        let abs_other_path = info.delta_target_dir.join(&other_path);
-
-                        if let Some(parent) = abs_other_path.parent() {
-                            use std::collections::hash_map;
-                            match parent_modtime_save.entry(parent.to_owned()) {
-                                hash_map::Entry::Vacant(v) => {
-                                    v.insert(parent.metadata()?.modified()?);
-                                },
-                                hash_map::Entry::Occupied(_) => {}
-                            }
-                        }
-
-                        std::fs::remove_file(&abs_other_path)?;
-                        std::fs::hard_link(&abs_path, &abs_other_path)
-                            .with_context(|| format!("failed linking {} -> {}",
-                                    abs_path.display(), abs_other_path.display()))?;
-                    }
+    let labels: String = output.labels.iter()
+        .map(|label| format!("LABEL {}=\"{}\"\n", label.key,
+            label.value.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect();
+
+    let text = match &output.template {
+        Some(template) => {
+            let template = std::fs::read_to_string(template)
+                .with_context(|| format!("reading template {}", template.display()))?;
+            if !labels.is_empty() && !template.contains("{{labels}}") {
+                return Err(anyhow::anyhow!("--label needs {{{{labels}}}} in the template"));
+            }
+            if !config.is_empty() && !template.contains("{{config}}") {
+                return Err(anyhow::anyhow!("--config-from needs {{{{config}}}} in the template"));
+            }
+            let mut vars = vec![
+                ("dockerfile", dockerfile.as_str()),
+                ("labels", labels.as_str()),
+                ("config", config.as_str()),
+                ("version", version.as_str()),
+                ("deltaimage", deltaimage.as_str()),
+            ];
+            match df {
+                cmdline::DockerFile::Diff { image_a, image_b, .. } => {
+                    vars.extend([("image_a", image_a.as_str()), ("image_b", image_b.as_str())]);
+                }
+                cmdline::DockerFile::Apply { delta_image, .. } => {
+                    vars.push(("delta_image", delta_image.as_str()));
                 }
-                break;
+                cmdline::DockerFile::Spec { .. } => unreachable!(),
             }
+            render_template(&template, &vars)?
         }
-    }
+        None => dockerfile + &config + &labels,
+    };
+    // Parser directives are only recognized on the first lines
+    let text = match &output.syntax {
+        Some(syntax) => format!("# syntax={}\n{}", syntax, text),
+        None => text,
+    };
 
-    for (pathname, modified) in parent_modtime_save {
-        let mtime = filetime::FileTime::from_system_time(modified);
-        filetime::set_file_times(&pathname, mtime, mtime).map_err(|e| {
-            crate::Error::FileTimeError(e, pathname.to_owned())
-        })?;
+    match &output.output {
+        Some(path) => std::fs::write(path, format!("{}\n", text))
+            .with_context(|| format!("writing {}", path.display()))?,
+        None => println!("{}", text),
     }
 
-    std::fs::remove_file(&info.delta_target_dir.join(DELTAIMAGE_META_FILE))?;
-
     Ok(())
 }
+
+/// Substitute the `{{name}}` variables of a template, refusing unknown ones
+fn render_template(template: &str, vars: &[(&str, &str)]) -> anyhow::Result<String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let end = rest[start..].find("}}")
+            .ok_or_else(|| anyhow::anyhow!("unterminated {{{{ in template"))?;
+        let name = rest[start + 2..start + end].trim();
+        let value = vars.iter().find(|(var, _)| *var == name).map(|(_, value)| value)
+            .ok_or_else(|| anyhow::anyhow!("unknown template variable {}", name))?;
+        rendered.push_str(&rest[..start]);
+        rendered.push_str(value);
+        rest = &rest[start + end + 2..];
+    }
+
+    rendered.push_str(rest);
+    Ok(rendered)
+}
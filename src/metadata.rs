@@ -0,0 +1,740 @@
+use std::fs::{File, FileType};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use anyhow::Context;
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
+
+use filetime::FileTime;
+
+use crate::Error;
+use crate::image_config::ConfigDelta;
+use crate::platform::{self, FileTypeExt, OsStrExt};
+use crate::utils;
+use crate::xdelta::XDelta3Params;
+
+/// Name of the meta-data file placed at the root of the delta directory, in JSON
+pub const DELTAIMAGE_META_FILE: &str = "__deltaimage.meta.json";
+
+/// Name of the meta-data file placed at the root of the delta directory, in
+/// the binary formats
+pub const DELTAIMAGE_META_BIN_FILE: &str = "__deltaimage.meta";
+
+/// Path of the meta-data file relative to the root of delta directories,
+/// instead of the default names, for images holding files at those
+static META_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Place the meta-data file of delta directories at `path`, relative to
+/// their root, in any format, which is then told by the content of the file.
+/// The directory holding it must be part of the delta. Only the first call
+/// takes effect.
+pub fn set_meta_path(path: &Path) -> anyhow::Result<()> {
+    let rel_path = path.strip_prefix("/").unwrap_or(path);
+    let valid = rel_path.components().next().is_some()
+        && rel_path.components().all(|component| matches!(component, Component::Normal(_)));
+    if !valid {
+        return Err(Error::InvalidMetaPath(path.to_owned()).into());
+    }
+    let _ = META_PATH.set(rel_path.to_owned());
+    Ok(())
+}
+
+/// Paths that the meta-data file of a delta directory can be at
+pub(crate) fn meta_file_paths(delta_dir: &Path) -> Vec<PathBuf> {
+    match META_PATH.get() {
+        Some(meta_path) => vec![delta_dir.join(meta_path)],
+        None => vec![delta_dir.join(DELTAIMAGE_META_FILE), delta_dir.join(DELTAIMAGE_META_BIN_FILE)],
+    }
+}
+
+/// Whether a path relative to the root of a delta directory is its meta-data file
+pub(crate) fn is_meta_file(rel_path: &Path) -> bool {
+    meta_file_paths(Path::new("")).iter().any(|path| path == rel_path)
+}
+
+/// Layout version of binary meta-data files, stored in their first byte. The
+/// second byte tells whether the CBOR that follows is zstd-compressed.
+const META_BIN_VERSION: u8 = 1;
+
+/// Version of the contents of the meta-data file, bumped whenever earlier
+/// releases could not make sense of the meta-data written by this one, such
+/// as for a new `Algo`. Meta-data in older versions, down to
+/// `MIN_META_FORMAT_VERSION`, is upgraded when loaded.
+///
+/// - 1: meta-data files without a version
+/// - 2: directories always have an access time
+/// - 3: bsdiff patches
+/// - 4: zstd patch-from patches
+/// - 5: files patched against extra source trees
+/// - 6: placeholders left out of the delta tree
+/// - 7: times or ownership left unrecorded
+/// - 8: gzip files and zip archives patched inflated
+pub const META_FORMAT_VERSION: u32 = 8;
+
+/// Oldest version of the meta-data that can still be loaded
+pub const MIN_META_FORMAT_VERSION: u32 = 1;
+
+fn legacy_format_version() -> u32 {
+    1
+}
+
+/// The part of the meta-data that is read before the rest, to refuse
+/// versions that cannot be loaded
+#[derive(Deserialize)]
+struct FormatVersion {
+    #[serde(default = "legacy_format_version")]
+    format_version: u32,
+    /// Release that wrote the meta-data, to tell in errors
+    #[serde(default)]
+    version: String,
+}
+
+/// Encoding of the meta-data file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MetaFormat {
+    /// Readable, but large, as paths are arrays of byte values
+    Json,
+    Cbor,
+    #[default]
+    CborZstd,
+}
+
+impl MetaFormat {
+    /// Format of the meta-data file of a delta directory, if it has one
+    pub fn of(delta_dir: &Path) -> Option<Self> {
+        if let Some(meta_path) = META_PATH.get() {
+            let mut header = [0; 2];
+            File::open(delta_dir.join(meta_path)).ok()?.read_exact(&mut header).ok()?;
+            return Some(match header {
+                [b'{', _] => MetaFormat::Json,
+                [_, 0] => MetaFormat::Cbor,
+                _ => MetaFormat::CborZstd,
+            });
+        }
+        if let Ok(mut file) = File::open(delta_dir.join(DELTAIMAGE_META_BIN_FILE)) {
+            let mut header = [0; 2];
+            file.read_exact(&mut header).ok()?;
+            return Some(if header[1] == 0 { MetaFormat::Cbor } else { MetaFormat::CborZstd });
+        }
+        delta_dir.join(DELTAIMAGE_META_FILE).exists().then_some(MetaFormat::Json)
+    }
+
+    /// Path of the meta-data file of a delta directory in this format
+    pub(crate) fn path(self, delta_dir: &Path) -> PathBuf {
+        if let Some(meta_path) = META_PATH.get() {
+            return delta_dir.join(meta_path);
+        }
+        match self {
+            MetaFormat::Json => delta_dir.join(DELTAIMAGE_META_FILE),
+            MetaFormat::Cbor | MetaFormat::CborZstd => delta_dir.join(DELTAIMAGE_META_BIN_FILE),
+        }
+    }
+}
+
+impl FromStr for MetaFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(MetaFormat::Json),
+            "cbor" => Ok(MetaFormat::Cbor),
+            "cbor-zstd" => Ok(MetaFormat::CborZstd),
+            _ => Err(format!("unknown meta-data format {}", s)),
+        }
+    }
+}
+
+/// Name of the directory holding the reverse delta tree of a bidirectional diff
+pub const REVERSE_DELTA_DIR: &str = "__deltaimage.reverse";
+
+/// How the content of a modified file is stored in the delta directory
+#[derive(Serialize, Deserialize, Hash, Eq, PartialEq, Ord, PartialOrd, Debug, Clone)]
+pub enum Algo {
+    XDelta3,
+    AsIs,
+    /// Windowed xdelta3 of a large file, with the given chunk size
+    XDelta3Chunked(u64),
+    /// Zstd-compressed new content, for files that xdelta3 cannot encode
+    Zstd,
+    /// Same content as the source file at the given path
+    CopyFrom(Vec<u8>),
+    /// xdelta3 of a new file against the similar source file at the given path
+    XDelta3From(Vec<u8>),
+    /// Zstd-compressed bsdiff patch
+    BsDiff,
+    /// zstd patch-from of a large file against its source, with the given
+    /// window log
+    ZstdPatchFrom(u32),
+    /// xdelta3 of a gzip file or zip archive with its deflate streams
+    /// inflated, deflated again on apply
+    Deflate,
+}
+
+/// Holes of a sparse file, as offset and length ranges in increasing order
+pub type Holes = Vec<(u64, u64)>;
+
+/// Contents of the meta-data file of a delta directory
+#[derive(Serialize, Deserialize)]
+pub struct MetaData {
+    /// Version of the contents, `META_FORMAT_VERSION` when written
+    #[serde(default = "legacy_format_version")]
+    pub format_version: u32,
+
+    /// Release of deltaimage that wrote the meta-data
+    pub version: String,
+    pub keep_files: Vec<Vec<u8>>,
+    pub changes: Vec<(Algo, Vec<u8>)>,
+
+    /// Hex-encoded SHA-256 digest of each restored file, for validation after apply
+    #[serde(default)]
+    pub checksums: Vec<(Vec<u8>, String)>,
+
+    /// Symlinks of the target tree, recreated on apply if missing or changed
+    #[serde(default)]
+    pub symlinks: Vec<Symlink>,
+
+    /// Files of the source tree that do not exist in the target tree
+    #[serde(default)]
+    pub deleted_files: Vec<Vec<u8>>,
+
+    /// Directories of the target tree, with their ownership, permissions and xattrs
+    #[serde(default)]
+    pub directories: Vec<Directory>,
+
+    /// Paths of the source tree excluded from the delta, copied from the
+    /// source on apply
+    #[serde(default)]
+    pub excluded: Vec<Vec<u8>>,
+
+    /// Meta-data of the reverse delta, restoring the source tree from the
+    /// target tree, whose files are kept under `REVERSE_DELTA_DIR`
+    #[serde(default)]
+    pub reverse: Option<Box<MetaData>>,
+
+    /// Progress of an apply of this delta, if one was started
+    #[serde(default)]
+    pub journal: Option<Journal>,
+
+    /// Holes of the sparse files among the restored ones, so that apply
+    /// recreates them sparsely
+    #[serde(default)]
+    pub sparse: Vec<(Vec<u8>, Holes)>,
+
+    /// FIFOs, device nodes and sockets of the target tree, recreated on apply
+    /// if missing or changed
+    #[serde(default)]
+    pub specials: Vec<Special>,
+
+    /// Size of each restored file, for statistics
+    #[serde(default)]
+    pub sizes: Vec<(Vec<u8>, u64)>,
+
+    /// Tuning of xdelta3 when the delta was made, for reproducibility
+    #[serde(default)]
+    pub xdelta3: Option<XDelta3Params>,
+
+    /// Merkle-style digest of the target tree, checked once applied
+    #[serde(default)]
+    pub tree_digest: Option<String>,
+
+    /// Hardlinked files of the target tree. Deltas without them have their
+    /// links taken from the delta tree.
+    #[serde(default)]
+    pub link_groups: Option<Vec<LinkGroup>>,
+
+    /// Differences between the configurations of the source and target
+    /// images, if diff was given them
+    #[serde(default)]
+    pub image_config: Option<ConfigDelta>,
+
+    /// Paths of the modified files to restore first, in this order, as
+    /// recorded from a trial apply
+    #[serde(default)]
+    pub apply_order: Option<Vec<Vec<u8>>>,
+
+    /// How to make the target image again, for deltas of squashfs or EROFS
+    /// images
+    #[serde(default)]
+    pub fs_image: Option<FsImage>,
+
+    /// Cipher that the stored files are encrypted with, if any
+    #[serde(default)]
+    pub encryption: Option<String>,
+
+    /// Extra source trees, as given to diff, that some modified files are
+    /// patched against instead of the source tree
+    #[serde(default)]
+    pub extra_sources: Vec<String>,
+
+    /// Modified files patched against an extra source, with its index in
+    /// `extra_sources`
+    #[serde(default)]
+    pub bases: Vec<(Vec<u8>, u32)>,
+
+    /// Meta-data of the unmodified files whose placeholders were left out of
+    /// the delta tree, recreated on apply
+    #[serde(default)]
+    pub placeholders: Vec<Placeholder>,
+
+    /// Whether the modification and access times of the target tree were
+    /// left unrecorded, as zero, for apply not to restore them
+    #[serde(default)]
+    pub ignore_times: bool,
+
+    /// Whether the ownership of the target tree was left unrecorded, as -1,
+    /// for apply not to restore it
+    #[serde(default)]
+    pub ignore_owner: bool,
+
+    /// Hex-encoded ed25519 signature of the rest of the meta-data, if signed
+    #[serde(default)]
+    pub signature: Option<String>,
+}
+
+/// Files of the target tree hardlinked together, with the digest of their
+/// content, so that apply only links files that are still identical
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LinkGroup {
+    pub paths: Vec<Vec<u8>>,
+    pub checksum: String,
+}
+
+/// Progress of an apply, so that an interrupted one can be resumed or rolled back
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Journal {
+    pub state: ApplyState,
+
+    /// Groups of hardlinked files of the delta tree, before any was replaced
+    pub link_groups: Vec<Vec<Vec<u8>>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApplyState {
+    /// Restored files are being written next to the files they replace
+    Staging,
+    /// Restored files are being renamed over the files they replace, which
+    /// are kept aside until done
+    Committing,
+    /// All the restored files are in place
+    Committed,
+}
+
+/// A symbolic link of the target tree
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Symlink {
+    pub path: Vec<u8>,
+    pub target: Vec<u8>,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Algo {
+    /// Short name for reports and listings
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algo::XDelta3 => "xdelta3",
+            Algo::AsIs => "as-is",
+            Algo::XDelta3Chunked(_) => "xdelta3-chunked",
+            Algo::Zstd => "zstd",
+            Algo::CopyFrom(_) => "copy-from",
+            Algo::XDelta3From(_) => "xdelta3-from",
+            Algo::BsDiff => "bsdiff",
+            Algo::ZstdPatchFrom(_) => "zstd-patch-from",
+            Algo::Deflate => "deflate",
+        }
+    }
+}
+
+/// A file time as stored by the filesystem, with nanosecond precision. Older
+/// meta-data files use the field names of a serialized `SystemTime`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Timestamp {
+    /// Seconds since the Unix epoch
+    #[serde(alias = "secs_since_epoch")]
+    pub secs: i64,
+    #[serde(alias = "nanos_since_epoch")]
+    pub nanos: u32,
+}
+
+impl From<FileTime> for Timestamp {
+    fn from(time: FileTime) -> Self {
+        Self { secs: time.unix_seconds(), nanos: time.nanoseconds() }
+    }
+}
+
+impl From<Timestamp> for FileTime {
+    fn from(time: Timestamp) -> Self {
+        FileTime::from_unix_time(time.secs, time.nanos)
+    }
+}
+
+/// A directory of the target tree
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Directory {
+    pub path: Vec<u8>,
+    pub modified: Timestamp,
+    /// Missing from older meta-data files, taken as the modification time
+    #[serde(default)]
+    pub accessed: Option<Timestamp>,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Directory {
+    pub(crate) fn new(path: &Path, meta_data: utils::MetaData) -> Self {
+        let (modified, accessed, mode, uid, gid, xattrs, _, _) = meta_data;
+        Self {
+            path: path.as_os_str().as_bytes().to_owned(),
+            modified,
+            accessed: Some(accessed),
+            mode,
+            uid,
+            gid,
+            xattrs: xattrs.into_iter().map(|(k, v)| (k.as_bytes().to_owned(), v)).collect(),
+        }
+    }
+
+    pub(crate) fn meta_data(&self) -> utils::MetaData {
+        let xattrs = self.xattrs.iter()
+            .map(|(k, v)| (platform::os_string_from_bytes(k), v.clone()))
+            .collect();
+        let accessed = self.accessed.unwrap_or(self.modified);
+        (self.modified, accessed, self.mode, self.uid, self.gid, xattrs, 0, 0)
+    }
+}
+
+/// Unmodified file of the target tree, with its ownership, permissions and
+/// xattrs, for its placeholder to be recreated
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Placeholder {
+    pub path: Vec<u8>,
+    pub modified: Timestamp,
+    pub accessed: Timestamp,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Placeholder {
+    pub(crate) fn new(path: &Path, meta_data: utils::MetaData) -> Self {
+        let (modified, accessed, mode, uid, gid, xattrs, _, _) = meta_data;
+        Self {
+            path: path.as_os_str().as_bytes().to_owned(),
+            modified,
+            accessed,
+            mode,
+            uid,
+            gid,
+            xattrs: xattrs.into_iter().map(|(k, v)| (k.as_bytes().to_owned(), v)).collect(),
+        }
+    }
+
+    pub(crate) fn meta_data(&self) -> utils::MetaData {
+        let xattrs = self.xattrs.iter()
+            .map(|(k, v)| (platform::os_string_from_bytes(k), v.clone()))
+            .collect();
+        (self.modified, self.accessed, self.mode, self.uid, self.gid, xattrs, 0, 0)
+    }
+}
+
+/// Type of a special file
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecialKind {
+    Fifo,
+    CharDevice,
+    BlockDevice,
+    Socket,
+}
+
+impl SpecialKind {
+    pub(crate) fn of(file_type: FileType) -> Option<Self> {
+        if file_type.is_fifo() {
+            Some(SpecialKind::Fifo)
+        } else if file_type.is_char_device() {
+            Some(SpecialKind::CharDevice)
+        } else if file_type.is_block_device() {
+            Some(SpecialKind::BlockDevice)
+        } else if file_type.is_socket() {
+            Some(SpecialKind::Socket)
+        } else {
+            None
+        }
+    }
+}
+
+/// Kind of filesystem image
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsImageKind {
+    Squashfs,
+    Erofs,
+}
+
+/// How the target image of a delta is made again from the restored tree
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FsImage {
+    pub kind: FsImageKind,
+    /// Options of mksquashfs or mkfs.erofs
+    pub mkfs_args: Vec<String>,
+    /// Digest of the target image, such as `sha256:...`
+    pub digest: String,
+}
+
+/// A FIFO, device node or socket of the target tree
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Special {
+    pub path: Vec<u8>,
+    pub kind: SpecialKind,
+    /// Device numbers, for character and block devices
+    pub major: u64,
+    pub minor: u64,
+    pub modified: Timestamp,
+    pub accessed: Timestamp,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Special {
+    pub(crate) fn new(path: &Path, kind: SpecialKind, rdev: u64, meta_data: utils::MetaData) -> Self {
+        let (modified, accessed, mode, uid, gid, xattrs, _, _) = meta_data;
+        Self {
+            path: path.as_os_str().as_bytes().to_owned(),
+            kind,
+            major: platform::major(rdev),
+            minor: platform::minor(rdev),
+            modified,
+            accessed,
+            mode,
+            uid,
+            gid,
+            xattrs: xattrs.into_iter().map(|(k, v)| (k.as_bytes().to_owned(), v)).collect(),
+        }
+    }
+
+    pub(crate) fn rdev(&self) -> u64 {
+        platform::makedev(self.major, self.minor)
+    }
+
+    pub(crate) fn meta_data(&self) -> utils::MetaData {
+        let xattrs = self.xattrs.iter()
+            .map(|(k, v)| (platform::os_string_from_bytes(k), v.clone()))
+            .collect();
+        (self.modified, self.accessed, self.mode, self.uid, self.gid, xattrs, 0, 0)
+    }
+}
+
+impl MetaData {
+    /// Read the meta-data file from the root of a delta directory, in any
+    /// format, upgrading it to the current version
+    pub fn load(delta_dir: &Path) -> anyhow::Result<Self> {
+        Self::load_versioned(delta_dir).map(|(md, _)| md)
+    }
+
+    /// Like `load`, also returning the version that the meta-data was in
+    fn load_versioned(delta_dir: &Path) -> anyhow::Result<(Self, u32)> {
+        let format = MetaFormat::of(delta_dir).unwrap_or(MetaFormat::Json);
+        let metadata_path = format.path(delta_dir);
+        let context = || format!("error reading meta-data from {}", metadata_path.display());
+        let data = Self::read_payload(&metadata_path, format).with_context(context)?;
+
+        let FormatVersion { format_version, version } = decode(format, &data).with_context(context)?;
+        let supported = MIN_META_FORMAT_VERSION..=META_FORMAT_VERSION;
+        if !supported.contains(&format_version) {
+            let produced_by = match version.as_str() {
+                "" => "an unknown release".to_owned(),
+                version => format!("release {}", version),
+            };
+            let error = match format_version > META_FORMAT_VERSION {
+                true => Error::MetaFormatTooNew,
+                false => Error::MetaFormatTooOld,
+            };
+            return Err(error(delta_dir.to_owned(), produced_by, format_version,
+                MIN_META_FORMAT_VERSION, META_FORMAT_VERSION).into());
+        }
+
+        let mut md: Self = decode(format, &data).with_context(context)?;
+        if format_version < META_FORMAT_VERSION {
+            tracing::debug!("Upgrading meta-data of {} from format version {}", delta_dir.display(), format_version);
+        }
+        md.migrate();
+        Ok((md, format_version))
+    }
+
+    /// Content of a meta-data file, without the header and compression of the
+    /// binary formats
+    fn read_payload(metadata_path: &Path, format: MetaFormat) -> anyhow::Result<Vec<u8>> {
+        let data = std::fs::read(metadata_path)?;
+        if format == MetaFormat::Json {
+            return Ok(data);
+        }
+
+        let [version, compressed, payload @ ..] = &data[..] else {
+            return Err(Error::InvalidMetaData("truncated file").into());
+        };
+        if *version != META_BIN_VERSION {
+            return Err(Error::InvalidMetaData("binary layout of a newer release").into());
+        }
+        match compressed {
+            0 => Ok(payload.to_owned()),
+            _ => zstd::stream::decode_all(payload)
+                .map_err(|_| Error::ZstdDecodeError(metadata_path.to_owned()).into()),
+        }
+    }
+
+    /// Check that every path listed, including the sources of moved files and
+    /// the progress of an apply, is relative and free of `..`, before apply
+    /// joins them onto the source and delta trees
+    pub(crate) fn check_paths(&self) -> anyhow::Result<()> {
+        let changes = self.changes.iter().flat_map(|(algo, path)| {
+            let from = match algo {
+                Algo::CopyFrom(from) | Algo::XDelta3From(from) => Some(from),
+                _ => None,
+            };
+            std::iter::once(path).chain(from)
+        });
+        let link_groups = self.link_groups.iter().flatten().flat_map(|group| group.paths.iter());
+        let journal = self.journal.iter().flat_map(|journal| journal.link_groups.iter().flatten());
+        let paths = changes
+            .chain(self.keep_files.iter())
+            .chain(self.checksums.iter().map(|(path, _)| path))
+            .chain(self.symlinks.iter().map(|symlink| &symlink.path))
+            .chain(self.deleted_files.iter())
+            .chain(self.directories.iter().map(|directory| &directory.path))
+            .chain(self.excluded.iter())
+            .chain(self.sparse.iter().map(|(path, _)| path))
+            .chain(self.specials.iter().map(|special| &special.path))
+            .chain(self.apply_order.iter().flatten())
+            .chain(self.bases.iter().map(|(path, _)| path))
+            .chain(self.placeholders.iter().map(|placeholder| &placeholder.path))
+            .chain(link_groups)
+            .chain(journal);
+        for path in paths {
+            let path = utils::path_from_bytes(path);
+            if !utils::is_plain_relative(&path) {
+                return Err(Error::UnsafeMetaPath(path).into());
+            }
+        }
+        match &self.reverse {
+            Some(reverse) => reverse.check_paths(),
+            None => Ok(()),
+        }
+    }
+
+    /// Upgrade meta-data loaded in an older version to the current one
+    fn migrate(&mut self) {
+        if self.format_version < 2 {
+            for directory in self.directories.iter_mut() {
+                directory.accessed.get_or_insert(directory.modified);
+            }
+        }
+        if let Some(reverse) = &mut self.reverse {
+            reverse.migrate();
+        }
+        self.format_version = META_FORMAT_VERSION;
+    }
+
+    /// What a signature covers, in an encoding that later releases reproduce
+    /// from the same meta-data: everything but the progress of an apply, the
+    /// signature itself and the format version, which loading upgrades, and
+    /// without the fields left to their defaults, as are those added since
+    pub(crate) fn signed_content(&self) -> anyhow::Result<Vec<u8>> {
+        fn strip(value: &mut serde_json::Value) {
+            let Some(fields) = value.as_object_mut() else { return };
+            for name in ["format_version", "journal", "signature"] {
+                fields.remove(name);
+            }
+            fields.retain(|_, field| {
+                !field.is_null() && field.as_array().map(|items| !items.is_empty()).unwrap_or(true)
+            });
+            if let Some(reverse) = fields.get_mut("reverse") {
+                strip(reverse);
+            }
+        }
+
+        let mut value = serde_json::to_value(self)?;
+        strip(&mut value);
+        Ok(serde_json::to_vec(&value)?)
+    }
+
+    /// Atomically replace the meta-data file of a delta directory, keeping
+    /// its format
+    pub fn save(&self, delta_dir: &Path) -> anyhow::Result<()> {
+        self.save_as(delta_dir, MetaFormat::of(delta_dir).unwrap_or_default())
+    }
+
+    /// Atomically replace the meta-data file of a delta directory with one in
+    /// the given format
+    pub fn save_as(&self, delta_dir: &Path, format: MetaFormat) -> anyhow::Result<()> {
+        let metadata_path = format.path(delta_dir);
+        let tmp_path = utils::temp_path_for(&metadata_path);
+        match format {
+            MetaFormat::Json => utils::serialize_to_json(self, &tmp_path)?,
+            MetaFormat::Cbor | MetaFormat::CborZstd => {
+                let mut cbor = vec![];
+                ciborium::into_writer(self, &mut cbor).context("Failed to serialize data")?;
+                let compressed = format == MetaFormat::CborZstd;
+                let mut data = vec![META_BIN_VERSION, compressed as u8];
+                match compressed {
+                    // Default compression level
+                    true => data.extend(zstd::stream::encode_all(&cbor[..], 0)?),
+                    false => data.extend(cbor),
+                }
+                std::fs::write(&tmp_path, data)
+                    .with_context(|| format!("Failed to write to file {}", tmp_path.display()))?;
+            }
+        }
+        File::open(&tmp_path)?.sync_all()?;
+        std::fs::rename(&tmp_path, &metadata_path)
+            .with_context(|| format!("error writing meta-data to {}", metadata_path.display()))?;
+
+        // Drop the file of the other format, if the format changed
+        let other_path = match format {
+            MetaFormat::Json => MetaFormat::Cbor.path(delta_dir),
+            MetaFormat::Cbor | MetaFormat::CborZstd => MetaFormat::Json.path(delta_dir),
+        };
+        if other_path != metadata_path && other_path.exists() {
+            std::fs::remove_file(&other_path)
+                .with_context(|| format!("failed removing {}", other_path.display()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether a directory has a meta-data file, in any format
+    pub fn exists(delta_dir: &Path) -> bool {
+        MetaFormat::of(delta_dir).is_some()
+    }
+
+    /// Rewrite the meta-data file of a delta directory in the current version,
+    /// and in another format if given. Returns the version it was in.
+    pub fn migrate_dir(delta_dir: &Path, format: Option<MetaFormat>) -> anyhow::Result<u32> {
+        let (md, format_version) = Self::load_versioned(delta_dir)?;
+        match format {
+            Some(format) => md.save_as(delta_dir, format)?,
+            None => md.save(delta_dir)?,
+        }
+        Ok(format_version)
+    }
+
+    /// Remove the meta-data file of a delta directory
+    pub(crate) fn remove(delta_dir: &Path) -> anyhow::Result<()> {
+        let metadata_path = MetaFormat::of(delta_dir).unwrap_or(MetaFormat::Json).path(delta_dir);
+        std::fs::remove_file(&metadata_path)
+            .with_context(|| format!("failed removing {}", metadata_path.display()))
+    }
+}
+
+fn decode<T: DeserializeOwned>(format: MetaFormat, data: &[u8]) -> anyhow::Result<T> {
+    Ok(match format {
+        MetaFormat::Json => serde_json::from_slice(data).context("Failed to deserialize data")?,
+        MetaFormat::Cbor | MetaFormat::CborZstd => ciborium::from_reader(data)
+            .context("Failed to deserialize data")?,
+    })
+}
@@ -0,0 +1,71 @@
+//! Read-only memory maps of whole files.
+//!
+//! Used to compare files of the same size, so that unmodified files are found
+//! without reading them into memory, let alone attempting to encode them.
+
+use std::ffi::c_void;
+use std::fs::File;
+use std::num::NonZeroUsize;
+use std::ops::Deref;
+use std::path::Path;
+
+use crate::platform;
+use crate::stream;
+use crate::utils::open_noatime;
+
+/// A file mapped read-only into memory
+pub(crate) struct Mmap {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+// The mapping is read-only and owned by the value
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Map the whole of `file`, to be read sequentially
+    pub(crate) fn map(file: &File) -> std::io::Result<Self> {
+        let len = file.metadata()?.len() as usize;
+        let Some(length) = NonZeroUsize::new(len) else {
+            // Empty files cannot be mapped
+            return Ok(Self { ptr: std::ptr::null_mut(), len: 0 });
+        };
+
+        let ptr = platform::map_file(file, length)?;
+        Ok(Self { ptr, len })
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.len {
+            0 => &[],
+            len => unsafe { std::slice::from_raw_parts(self.ptr as *const u8, len) },
+        }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            platform::unmap_file(self.ptr, self.len);
+        }
+    }
+}
+
+/// Compare two files through memory maps, or by reading them in chunks on
+/// filesystems that cannot map them
+pub(crate) fn files_equal(a: &Path, b: &Path) -> std::io::Result<bool> {
+    let (file_a, file_b) = (open_noatime(a)?, open_noatime(b)?);
+    if file_a.metadata()?.len() != file_b.metadata()?.len() {
+        return Ok(false);
+    }
+
+    match (Mmap::map(&file_a), Mmap::map(&file_b)) {
+        (Ok(map_a), Ok(map_b)) => Ok(*map_a == *map_b),
+        _ => stream::files_equal(a, b),
+    }
+}
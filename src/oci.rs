@@ -0,0 +1,1547 @@
+//! Computing and applying deltas directly on OCI image layouts, either as
+//! directories or as tarballs, without a container build.
+//!
+//! A delta image mirrors the one built by the `docker-file diff` flow: the
+//! layers of the source image, plus one layer holding the delta tree under
+//! `/__deltaimage__.delta`. The configuration of the target image is kept in
+//! an annotation of the manifest, so that `apply-oci` can restore it.
+//!
+//! Per-layer delta images instead hold a patch of each layer of the target
+//! image against a layer of the source image, listed in another annotation.
+//!
+//! Either way, the delta layer may be split into several ones of a bounded
+//! size, counted by another annotation, for registries limiting the size of
+//! blobs and for pulls to download them in parallel.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::Context;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::Error;
+use crate::apply::{ApplyOptions, ApplyStats, DeltaApplier};
+use crate::diff::{DeltaBuilder, DiffOptions, DiffStats};
+use crate::lazy_layer::{self, ESTARGZ_METADATA};
+use crate::metadata::{SpecialKind, Timestamp};
+use crate::patch_from;
+use crate::platform::{self, MetadataExt, OsStrExt};
+use crate::utils::{self, drop_components, get_meta_data, set_meta_data, deserialize_from_json,
+    serialize_to_json, digest_file, set_symlink_owner, make_special};
+
+/// Path of the delta tree inside a delta image
+pub const DELTA_DIR_NAME: &str = "__deltaimage__.delta";
+
+pub(crate) const MEDIA_TYPE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+pub(crate) const MEDIA_TYPE_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+const MEDIA_TYPE_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+const MEDIA_TYPE_LAYER_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+const MEDIA_TYPE_LAYER_ZSTD: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
+const MEDIA_TYPE_LAYER: &str = "application/vnd.oci.image.layer.v1.tar";
+pub(crate) const MEDIA_TYPE_DOCKER_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+#[cfg_attr(not(feature = "registry"), allow(dead_code))]
+pub(crate) const MEDIA_TYPE_DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+const MEDIA_TYPE_DOCKER_LAYER_GZIP: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+const MEDIA_TYPE_DOCKER_LAYER: &str = "application/vnd.docker.image.rootfs.diff.tar";
+
+const ANNOTATION_VERSION: &str = "io.deltaimage.version";
+const ANNOTATION_TARGET_CONFIG: &str = "io.deltaimage.target-config";
+const ANNOTATION_SOURCE_IMAGE: &str = "io.deltaimage.source-image";
+const ANNOTATION_SOURCE_CONFIG: &str = "io.deltaimage.source-config";
+const ANNOTATION_TARGET_IMAGE: &str = "io.deltaimage.target-image";
+const ANNOTATION_LAYER_DELTAS: &str = "io.deltaimage.layer-deltas";
+const ANNOTATION_DELTA_LAYERS: &str = "io.deltaimage.delta-layers";
+const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+const ANNOTATION_CONTAINERD_NAME: &str = "io.containerd.image.name";
+
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+const WHITEOUT_OPAQUE: &[u8] = b".wh..wh..opq";
+const WHITEOUT_META_PREFIX: &[u8] = b".wh..wh.";
+const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Descriptor {
+    pub(crate) media_type: String,
+    pub(crate) digest: String,
+    pub(crate) size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) platform: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) annotations: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ImageIndex {
+    pub(crate) schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) media_type: Option<String>,
+    pub(crate) manifests: Vec<Descriptor>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Manifest {
+    pub(crate) schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) media_type: Option<String>,
+    pub(crate) config: Descriptor,
+    pub(crate) layers: Vec<Descriptor>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) annotations: BTreeMap<String, String>,
+}
+
+/// Entry of the `manifest.json` file of `docker save` archives
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerArchiveManifest {
+    config: String,
+    #[serde(default)]
+    repo_tags: Option<Vec<String>>,
+    layers: Vec<String>,
+}
+
+/// Compression of the layers written to output images. Layers copied from
+/// another image are kept as they are, for registries and hosts to still
+/// share them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayerCompression {
+    /// gzip at the given level, from 0 to 9
+    Gzip(u32),
+    /// zstd at the given level, which not all engines and registries support
+    Zstd(i32),
+    /// eStargz, gzip at the given level readable a file at a time by
+    /// lazy-pulling snapshotters
+    Estargz(u32),
+    /// zstd:chunked, zstd at the given level readable a file at a time by
+    /// lazy-pulling snapshotters
+    ZstdChunked(i32),
+    None,
+}
+
+impl Default for LayerCompression {
+    fn default() -> Self {
+        LayerCompression::Gzip(6)
+    }
+}
+
+impl FromStr for LayerCompression {
+    type Err = String;
+
+    /// `gzip`, `zstd`, `estargz`, `zstd:chunked` or `none`, with the level
+    /// after a colon, such as `gzip:9`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, level) = match s.strip_prefix("zstd:chunked") {
+            Some("") => ("zstd:chunked", None),
+            Some(rest) => ("zstd:chunked", Some(rest.strip_prefix(':').unwrap_or(rest))),
+            None => match s.split_once(':') {
+                Some((name, level)) => (name, Some(level)),
+                None => (s, None),
+            },
+        };
+        let invalid = || format!("invalid compression level in {}", s);
+        match (name, level) {
+            ("gzip", None) => Ok(LayerCompression::Gzip(6)),
+            ("gzip", Some(level)) => match level.parse() {
+                Ok(level @ 0..=9) => Ok(LayerCompression::Gzip(level)),
+                _ => Err(invalid()),
+            },
+            ("zstd", None) => Ok(LayerCompression::Zstd(3)),
+            ("zstd", Some(level)) => match level.parse() {
+                Ok(level @ -7..=22) => Ok(LayerCompression::Zstd(level)),
+                _ => Err(invalid()),
+            },
+            ("estargz", None) => Ok(LayerCompression::Estargz(6)),
+            ("estargz", Some(level)) => match level.parse() {
+                Ok(level @ 0..=9) => Ok(LayerCompression::Estargz(level)),
+                _ => Err(invalid()),
+            },
+            ("zstd:chunked", None) => Ok(LayerCompression::ZstdChunked(3)),
+            ("zstd:chunked", Some(level)) => match level.parse() {
+                Ok(level @ -7..=22) => Ok(LayerCompression::ZstdChunked(level)),
+                _ => Err(invalid()),
+            },
+            ("none", None) => Ok(LayerCompression::None),
+            _ => Err(format!("unknown layer compression {}", s)),
+        }
+    }
+}
+
+impl LayerCompression {
+    fn media_type(self) -> &'static str {
+        match self {
+            LayerCompression::Gzip(_) | LayerCompression::Estargz(_) => MEDIA_TYPE_LAYER_GZIP,
+            LayerCompression::Zstd(_) | LayerCompression::ZstdChunked(_) => MEDIA_TYPE_LAYER_ZSTD,
+            LayerCompression::None => MEDIA_TYPE_LAYER,
+        }
+    }
+
+    /// The same compression without a table of contents, for layers that
+    /// have to be restored bit for bit
+    fn plain(self) -> Self {
+        match self {
+            LayerCompression::Estargz(level) => LayerCompression::Gzip(level),
+            LayerCompression::ZstdChunked(level) => LayerCompression::Zstd(level),
+            other => other,
+        }
+    }
+
+    fn encoder(self, out: BufWriter<File>) -> std::io::Result<LayerEncoder> {
+        Ok(match self.plain() {
+            LayerCompression::Gzip(level) => {
+                LayerEncoder::Gzip(flate2::write::GzEncoder::new(out, flate2::Compression::new(level)))
+            }
+            LayerCompression::Zstd(level) => LayerEncoder::Zstd(zstd::stream::write::Encoder::new(out, level)?),
+            _ => LayerEncoder::None(out),
+        })
+    }
+}
+
+impl Descriptor {
+    pub(crate) fn is_index(&self) -> bool {
+        self.media_type == MEDIA_TYPE_INDEX || self.media_type == MEDIA_TYPE_DOCKER_LIST
+    }
+}
+
+/// How a layer of the target image is restored by a delta image made by
+/// [`diff_oci_layers`]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+struct LayerDelta {
+    /// Digest of the uncompressed target layer
+    diff_id: String,
+    /// Index of the source layer that the target layer is the same as, or
+    /// is encoded against
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_layer: Option<usize>,
+    /// Name of the zstd patch-from patch in the delta tree, unless the same
+    /// as the source layer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    patch: Option<String>,
+    #[serde(default)]
+    window_log: u32,
+}
+
+/// An OCI image layout directory
+pub(crate) struct ImageLayout {
+    pub(crate) dir: PathBuf,
+    /// Of the layers written to it
+    compression: LayerCompression,
+}
+
+impl ImageLayout {
+    /// Open an image layout directory, or extract an image layout tarball
+    /// into `extract_dir` first.
+    pub(crate) fn open(path: &Path, extract_dir: &Path) -> anyhow::Result<Self> {
+        if path.is_file() {
+            std::fs::create_dir(extract_dir)
+                .with_context(|| format!("failed creating directory {}", extract_dir.display()))?;
+            let mut directories = HashMap::new();
+            unpack_layer(BufReader::new(File::open(path)?), extract_dir, &mut directories)
+                .with_context(|| format!("failed to extract {}", path.display()))?;
+            if !extract_dir.join("oci-layout").exists() && extract_dir.join("manifest.json").exists() {
+                return Self::from_docker_archive(extract_dir);
+            }
+            Self::open_dir(extract_dir)
+        } else {
+            Self::open_dir(path)
+        }
+    }
+
+    pub(crate) fn open_dir(dir: &Path) -> anyhow::Result<Self> {
+        if !dir.join("oci-layout").is_file() {
+            return Err(Error::InvalidOciImage(format!("no oci-layout file in {}",
+                dir.display())).into());
+        }
+        Ok(Self { dir: dir.to_owned(), compression: LayerCompression::default() })
+    }
+
+    /// Turn an extracted archive of `docker save` from before Docker 25, which
+    /// only has a `manifest.json`, into an image layout in place.
+    fn from_docker_archive(dir: &Path) -> anyhow::Result<Self> {
+        let entries: Vec<DockerArchiveManifest> = deserialize_from_json(&dir.join("manifest.json"))?;
+        let Some(entry) = entries.into_iter().next() else {
+            return Err(Error::InvalidOciImage("docker archive lists no images".to_owned()).into());
+        };
+
+        let layout = Self::create(dir)?;
+        let move_blob = |name: &str, media_type: &str| -> anyhow::Result<Descriptor> {
+            let path = dir.join(name);
+            let digest = format!("sha256:{}", digest_file(&path)?);
+            let size = path.metadata()?.len();
+            let blob_path = layout.blob_path(&digest)?;
+            if !blob_path.exists() {
+                std::fs::rename(&path, &blob_path)
+                    .with_context(|| format!("failed to move {}", path.display()))?;
+            }
+            Ok(Descriptor {
+                media_type: media_type.to_owned(),
+                digest,
+                size,
+                platform: None,
+                annotations: BTreeMap::new(),
+            })
+        };
+
+        let manifest = Manifest {
+            schema_version: 2,
+            media_type: Some(MEDIA_TYPE_MANIFEST.to_owned()),
+            config: move_blob(&entry.config, MEDIA_TYPE_CONFIG)?,
+            layers: entry.layers.iter().map(|layer| move_blob(layer, MEDIA_TYPE_DOCKER_LAYER))
+                .collect::<anyhow::Result<_>>()?,
+            annotations: BTreeMap::new(),
+        };
+        let descriptor = layout.write_json_blob(&manifest, MEDIA_TYPE_MANIFEST)?;
+        layout.write_index(descriptor)?;
+        Ok(layout)
+    }
+
+    pub(crate) fn create(dir: &Path) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir.join("blobs").join("sha256"))
+            .with_context(|| format!("failed creating directory {}", dir.display()))?;
+        std::fs::write(dir.join("oci-layout"), r#"{"imageLayoutVersion":"1.0.0"}"#)?;
+        Ok(Self { dir: dir.to_owned(), compression: LayerCompression::default() })
+    }
+
+    pub(crate) fn blob_path(&self, digest: &str) -> anyhow::Result<PathBuf> {
+        let Some((algorithm, hex)) = digest.split_once(':') else {
+            return Err(Error::InvalidOciImage(format!("malformed digest {}", digest)).into());
+        };
+        Ok(self.dir.join("blobs").join(algorithm).join(hex))
+    }
+
+    /// The descriptor of the image manifest. For multi-image indexes, this is
+    /// the first image listed.
+    pub(crate) fn manifest_descriptor(&self) -> anyhow::Result<Descriptor> {
+        let index: ImageIndex = deserialize_from_json(&self.dir.join("index.json"))?;
+        let mut descriptor = index.manifests.into_iter().next()
+            .ok_or_else(|| Error::InvalidOciImage("index lists no manifests".to_owned()))?;
+
+        while descriptor.is_index() {
+            let index: ImageIndex = deserialize_from_json(&self.blob_path(&descriptor.digest)?)?;
+            descriptor = index.manifests.into_iter().next()
+                .ok_or_else(|| Error::InvalidOciImage("nested index lists no manifests".to_owned()))?;
+        }
+
+        Ok(descriptor)
+    }
+
+    /// The manifest of the image and its configuration
+    pub(crate) fn manifest(&self) -> anyhow::Result<(Manifest, serde_json::Value)> {
+        let descriptor = self.manifest_descriptor()?;
+        let manifest: Manifest = deserialize_from_json(&self.blob_path(&descriptor.digest)?)?;
+        let config = deserialize_from_json(&self.blob_path(&manifest.config.digest)?)?;
+        Ok((manifest, config))
+    }
+
+    pub(crate) fn write_blob(&self, data: &[u8], media_type: &str) -> anyhow::Result<Descriptor> {
+        let digest = format!("sha256:{}", utils::digest_bytes(data));
+        std::fs::write(self.blob_path(&digest)?, data)?;
+        Ok(Descriptor {
+            media_type: media_type.to_owned(),
+            digest,
+            size: data.len() as u64,
+            platform: None,
+            annotations: BTreeMap::new(),
+        })
+    }
+
+    fn write_json_blob(&self, value: &impl Serialize, media_type: &str) -> anyhow::Result<Descriptor> {
+        self.write_blob(&serde_json::to_vec(value)?, media_type)
+    }
+
+    /// Add a blob of another layout, sharing its storage when possible
+    fn copy_blob(&self, from: &ImageLayout, descriptor: &Descriptor) -> anyhow::Result<()> {
+        let (src, dst) = (from.blob_path(&descriptor.digest)?, self.blob_path(&descriptor.digest)?);
+        if std::fs::hard_link(&src, &dst).is_err() {
+            std::fs::copy(&src, &dst)
+                .with_context(|| format!("failed copying {} -> {}", src.display(), dst.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Write the tree at `dir` as a new layer, placing its paths under
+    /// `prefix`. Returns the layer descriptor and the digest of
+    /// the uncompressed layer, to be listed in the image configuration.
+    fn write_layer(&self, dir: &Path, prefix: &Path) -> anyhow::Result<(Descriptor, String)> {
+        self.write_layer_with(|builder| append_tree(builder, dir, prefix))
+    }
+
+    /// As [`write_layer`](Self::write_layer), splitting the tree into layers
+    /// of about `max_size` bytes of file content each if given
+    fn write_layers(&self, dir: &Path, prefix: &Path, max_size: Option<u64>)
+        -> anyhow::Result<Vec<(Descriptor, String)>>
+    {
+        let Some(max_size) = max_size else {
+            return Ok(vec![self.write_layer(dir, prefix)?]);
+        };
+        split_tree(dir, max_size)?.iter()
+            .map(|part| self.write_layer_with(|builder| {
+                append_part(builder, dir, prefix, |rel_path| part.contains(rel_path))
+            }))
+            .collect()
+    }
+
+    /// Write a layer of the paths of `upper` that differ from `lower`, as
+    /// [`append_changes`] does
+    fn write_changes_layer(&self, lower: &Path, upper: &Path, ignored: &Path)
+        -> anyhow::Result<(Descriptor, String)>
+    {
+        self.write_layer_with(|builder| append_changes(builder, lower, upper, ignored))
+    }
+
+    fn write_layer_with(&self, append: impl FnOnce(&mut LayerBuilder) -> anyhow::Result<()>)
+        -> anyhow::Result<(Descriptor, String)>
+    {
+        let write = |out| {
+            let mut builder = tar::Builder::new(out);
+            append(&mut builder)?;
+            Ok(builder.into_inner()?)
+        };
+        if self.compression == self.compression.plain() {
+            return self.write_raw_layer(write);
+        }
+
+        // Lazy-pulling formats are made from the whole uncompressed layer
+        let tar_path = self.dir.join("blobs").join("layer.tar.tmp");
+        encode_layer(&tar_path, LayerCompression::None, write)?;
+        let tmp_path = self.dir.join("blobs").join("layer.tmp");
+        let layer = match self.compression {
+            LayerCompression::Estargz(level) => lazy_layer::write_estargz(&tar_path, &tmp_path, level)?,
+            LayerCompression::ZstdChunked(level) => lazy_layer::write_zstd_chunked(&tar_path, &tmp_path, level)?,
+            _ => unreachable!(),
+        };
+        std::fs::remove_file(&tar_path)?;
+        let descriptor = self.store_layer(&tmp_path, self.compression.media_type(), layer.annotations)?;
+        Ok((descriptor, layer.diff_id))
+    }
+
+    /// Write a compressed layer of the uncompressed content written by
+    /// `write`, returning the layer descriptor and its diff ID
+    fn write_raw_layer(&self, write: impl FnOnce(LayerWriter) -> anyhow::Result<LayerWriter>)
+        -> anyhow::Result<(Descriptor, String)>
+    {
+        let compression = self.compression.plain();
+        let tmp_path = self.dir.join("blobs").join("layer.tmp");
+        let diff_id = encode_layer(&tmp_path, compression, write)?;
+        Ok((self.store_layer(&tmp_path, compression.media_type(), BTreeMap::new())?, diff_id))
+    }
+
+    /// Move the layer at `tmp_path` to the blobs of the layout
+    fn store_layer(&self, tmp_path: &Path, media_type: &str, annotations: BTreeMap<String, String>)
+        -> anyhow::Result<Descriptor>
+    {
+        let digest = format!("sha256:{}", digest_file(tmp_path)?);
+        let size = tmp_path.metadata()?.len();
+        std::fs::rename(tmp_path, self.blob_path(&digest)?)?;
+
+        Ok(Descriptor {
+            media_type: media_type.to_owned(),
+            digest,
+            size,
+            platform: None,
+            annotations,
+        })
+    }
+
+    /// Write the image configuration and manifest, and list the manifest as
+    /// the only image of the layout.
+    fn write_image(&self, config: &serde_json::Value, layers: Vec<Descriptor>,
+        annotations: BTreeMap<String, String>) -> anyhow::Result<()>
+    {
+        let manifest = Manifest {
+            schema_version: 2,
+            media_type: Some(MEDIA_TYPE_MANIFEST.to_owned()),
+            config: self.write_json_blob(config, MEDIA_TYPE_CONFIG)?,
+            layers,
+            annotations,
+        };
+        let descriptor = self.write_json_blob(&manifest, MEDIA_TYPE_MANIFEST)?;
+        self.write_index(descriptor)
+    }
+
+    /// List a manifest as the only image of the layout
+    pub(crate) fn write_index(&self, manifest: Descriptor) -> anyhow::Result<()> {
+        let index = ImageIndex {
+            schema_version: 2,
+            media_type: Some(MEDIA_TYPE_INDEX.to_owned()),
+            manifests: vec![manifest],
+        };
+        serialize_to_json(&index, &self.dir.join("index.json"))
+    }
+
+    /// Name the image of the layout, both in its index and in a `manifest.json`
+    /// file, so that `docker load` of a tarball of it tags it under any Docker
+    /// version.
+    fn tag_image(&self, name: &str) -> anyhow::Result<()> {
+        let mut descriptor = self.manifest_descriptor()?;
+        let tag = match name.rsplit_once(':') {
+            Some((_, tag)) if !tag.contains('/') => tag,
+            _ => "latest",
+        };
+        descriptor.annotations.insert(ANNOTATION_REF_NAME.to_owned(), tag.to_owned());
+        descriptor.annotations.insert(ANNOTATION_CONTAINERD_NAME.to_owned(), name.to_owned());
+        self.write_index(descriptor)?;
+
+        let (manifest, _) = self.manifest()?;
+        let blob_name = |digest: &str| digest.replacen(':', "/", 1);
+        let entry = DockerArchiveManifest {
+            config: format!("blobs/{}", blob_name(&manifest.config.digest)),
+            repo_tags: Some(vec![name.to_owned()]),
+            layers: manifest.layers.iter()
+                .map(|layer| format!("blobs/{}", blob_name(&layer.digest)))
+                .collect(),
+        };
+        serialize_to_json(&vec![entry], &self.dir.join("manifest.json"))
+    }
+}
+
+/// Write the uncompressed content written by `write` to `path` with
+/// `compression`, returning its diff ID
+fn encode_layer(path: &Path, compression: LayerCompression,
+    write: impl FnOnce(LayerWriter) -> anyhow::Result<LayerWriter>) -> anyhow::Result<String>
+{
+    let out = BufWriter::new(File::create(path)
+        .with_context(|| format!("failed to create {}", path.display()))?);
+    let encoder = compression.encoder(out)?;
+
+    let HashingWriter { inner, hasher } = write(HashingWriter { inner: encoder, hasher: Sha256::new() })?;
+    inner.finish()?.flush()?;
+    Ok(format!("sha256:{:x}", hasher.finalize()))
+}
+
+type LayerWriter = HashingWriter<LayerEncoder>;
+type LayerBuilder = tar::Builder<LayerWriter>;
+
+/// Compresses a layer as it is written
+enum LayerEncoder {
+    Gzip(flate2::write::GzEncoder<BufWriter<File>>),
+    Zstd(zstd::stream::write::Encoder<'static, BufWriter<File>>),
+    None(BufWriter<File>),
+}
+
+impl LayerEncoder {
+    fn finish(self) -> std::io::Result<BufWriter<File>> {
+        match self {
+            LayerEncoder::Gzip(encoder) => encoder.finish(),
+            LayerEncoder::Zstd(encoder) => encoder.finish(),
+            LayerEncoder::None(out) => Ok(out),
+        }
+    }
+}
+
+impl Write for LayerEncoder {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            LayerEncoder::Gzip(encoder) => encoder.write(buf),
+            LayerEncoder::Zstd(encoder) => encoder.write(buf),
+            LayerEncoder::None(out) => out.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            LayerEncoder::Gzip(encoder) => encoder.flush(),
+            LayerEncoder::Zstd(encoder) => encoder.flush(),
+            LayerEncoder::None(out) => out.flush(),
+        }
+    }
+}
+
+/// Passes written data through, digesting it on the way
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Scratch directories of an OCI operation, removed once it succeeds
+pub(crate) struct WorkDir {
+    dir: PathBuf,
+}
+
+impl WorkDir {
+    pub(crate) fn create(dir: &Path) -> anyhow::Result<Self> {
+        std::fs::create_dir(dir)
+            .with_context(|| format!("failed creating directory {}", dir.display()))?;
+        Ok(Self { dir: dir.to_owned() })
+    }
+
+    pub(crate) fn join(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    pub(crate) fn remove(self) -> anyhow::Result<()> {
+        std::fs::remove_dir_all(&self.dir)
+            .with_context(|| format!("failed removing {}", self.dir.display()))
+    }
+}
+
+/// Create the layout of an output image, unless it already exists, writing
+/// layers with `compression`. Output paths ending with `.tar` are written as
+/// tarballs, staged in `work_dir`.
+fn create_output(output_image: &Path, work_dir: &WorkDir, compression: LayerCompression)
+    -> anyhow::Result<ImageLayout>
+{
+    if output_image.exists() {
+        return Err(Error::OutputImageExists(output_image.to_owned()).into());
+    }
+    let layout = match is_tarball(output_image) {
+        true => ImageLayout::create(&work_dir.join("output"))?,
+        false => ImageLayout::create(output_image)?,
+    };
+    Ok(ImageLayout { compression, ..layout })
+}
+
+/// Number of layers on top of a delta image holding the delta tree
+fn delta_layer_count(manifest: &Manifest) -> anyhow::Result<usize> {
+    let count = match manifest.annotations.get(ANNOTATION_DELTA_LAYERS) {
+        Some(count) => count.parse().ok().filter(|&count| count >= 1 && count <= manifest.layers.len())
+            .ok_or_else(|| Error::InvalidOciImage(format!("invalid delta layer count {}", count)))?,
+        None => 1,
+    };
+    Ok(count)
+}
+
+/// Write the delta tree as the layers of a delta image, listing them in its
+/// configuration and annotations
+fn write_delta_layers(output: &ImageLayout, delta_dir: &Path, max_size: Option<u64>,
+    config: &mut serde_json::Value, created_by: &str, annotations: &mut BTreeMap<String, String>)
+    -> anyhow::Result<Vec<Descriptor>>
+{
+    let mut delta_layers = vec![];
+    for (delta_layer, diff_id) in output.write_layers(delta_dir, Path::new(DELTA_DIR_NAME), max_size)? {
+        tracing::info!("Delta layer {}: {}", delta_layer.digest, delta_layer.size);
+        push_config_layer(config, diff_id, created_by)?;
+        delta_layers.push(delta_layer);
+    }
+    if delta_layers.len() > 1 {
+        annotations.insert(ANNOTATION_DELTA_LAYERS.to_owned(), delta_layers.len().to_string());
+    }
+    Ok(delta_layers)
+}
+
+fn finish_output(output_image: &Path, layout: &ImageLayout) -> anyhow::Result<()> {
+    if is_tarball(output_image) {
+        let out = BufWriter::new(File::create(output_image)
+            .with_context(|| format!("failed to create {}", output_image.display()))?);
+        let mut builder = tar::Builder::new(out);
+        append_tree(&mut builder, &layout.dir, Path::new(""))?;
+        builder.into_inner()?.flush()
+            .with_context(|| format!("failed to write to {}", output_image.display()))?;
+    }
+    Ok(())
+}
+
+fn is_tarball(path: &Path) -> bool {
+    path.extension() == Some(OsStr::new("tar"))
+}
+
+/// Compute the delta between two OCI images, and write a delta image made of
+/// the layers of `source_image` plus one layer holding the delta tree.
+///
+/// Each image is either an OCI layout directory or a tarball of one. The
+/// images are unpacked under `work_dir`, which must not exist and is removed
+/// once done.
+pub fn diff_oci(source_image: &Path, target_image: &Path, output_image: &Path, work_dir: &Path,
+    options: DiffOptions) -> anyhow::Result<DiffStats>
+{
+    diff_oci_images(source_image, target_image, output_image, work_dir, options, None)
+}
+
+/// Names of the images of a thin delta image, under which a container engine
+/// knows them
+pub(crate) struct ImageNames<'a> {
+    pub(crate) source: &'a str,
+    pub(crate) target: &'a str,
+}
+
+/// As [`diff_oci`], or with `names`, write a thin delta image which only has
+/// the delta layer. The source layers are then taken from the local image
+/// store of the receiving host when applying it.
+pub(crate) fn diff_oci_images(source_image: &Path, target_image: &Path, output_image: &Path,
+    work_dir: &Path, options: DiffOptions, names: Option<&ImageNames>) -> anyhow::Result<DiffStats>
+{
+    let work_dir = WorkDir::create(work_dir)?;
+    let source = ImageLayout::open(source_image, &work_dir.join("source-layout"))?;
+    let target = ImageLayout::open(target_image, &work_dir.join("target-layout"))?;
+    let (source_manifest, mut config) = source.manifest()?;
+    let (target_manifest, target_config) = target.manifest()?;
+
+    let source_dir = work_dir.join("source");
+    let delta_dir = work_dir.join("delta");
+    unpack_layers(&source, &source_manifest.layers, &source_dir)?;
+    unpack_layers(&target, &target_manifest.layers, &delta_dir)?;
+
+    let (layer_compression, layer_size) = (options.layer_compression, options.layer_size);
+    let stats = DeltaBuilder::new(&source_dir, &delta_dir).options(options).run()?;
+
+    let output = create_output(output_image, &work_dir, layer_compression)?;
+    let mut annotations = BTreeMap::new();
+    let delta_layers = write_delta_layers(&output, &delta_dir, layer_size, &mut config,
+        "deltaimage diff-oci", &mut annotations)?;
+    annotations.insert(ANNOTATION_VERSION.to_owned(), env!("CARGO_PKG_VERSION").to_owned());
+    annotations.insert(ANNOTATION_TARGET_CONFIG.to_owned(), serde_json::to_string(&target_config)?);
+
+    let layers = match names {
+        None => {
+            for layer in source_manifest.layers.iter() {
+                output.copy_blob(&source, layer)?;
+            }
+            let mut layers = source_manifest.layers;
+            layers.extend(delta_layers);
+            layers
+        }
+        Some(names) => {
+            annotations.insert(ANNOTATION_SOURCE_IMAGE.to_owned(), names.source.to_owned());
+            annotations.insert(ANNOTATION_SOURCE_CONFIG.to_owned(),
+                source_manifest.config.digest.clone());
+            annotations.insert(ANNOTATION_TARGET_IMAGE.to_owned(), names.target.to_owned());
+            delta_layers
+        }
+    };
+    output.write_image(&config, layers, annotations)?;
+    finish_output(output_image, &output)?;
+
+    work_dir.remove()?;
+    Ok(stats)
+}
+
+/// Compute a delta image that restores the target image with its layers as
+/// they are, rather than as a single layer.
+///
+/// Each layer of the target image is either the same as a layer of the source
+/// image, or encoded as a whole with zstd patch-from against the source layer
+/// at the same position, or the top one. The patches are held by the delta
+/// layer, on top of the layers of the source image, and [`apply_oci`] restores
+/// the layers with the same diff IDs, so that the image configuration is kept
+/// as it is.
+pub fn diff_oci_layers(source_image: &Path, target_image: &Path, output_image: &Path,
+    work_dir: &Path, options: DiffOptions) -> anyhow::Result<DiffStats>
+{
+    let started = std::time::Instant::now();
+    let work_dir = WorkDir::create(work_dir)?;
+    let source = ImageLayout::open(source_image, &work_dir.join("source-layout"))?;
+    let target = ImageLayout::open(target_image, &work_dir.join("target-layout"))?;
+    let (source_manifest, mut config) = source.manifest()?;
+    let (target_manifest, target_config) = target.manifest()?;
+    let source_diff_ids = config_diff_ids(&config)?;
+    let target_diff_ids = config_diff_ids(&target_config)?;
+    if target_diff_ids.len() != target_manifest.layers.len() {
+        return Err(Error::InvalidOciImage("target layers do not match its configuration"
+            .to_owned()).into());
+    }
+
+    let delta_dir = work_dir.join("delta");
+    std::fs::create_dir(&delta_dir)
+        .with_context(|| format!("failed creating directory {}", delta_dir.display()))?;
+    let empty_path = work_dir.join("empty.tar");
+    File::create(&empty_path)?;
+
+    let mut layer_deltas = vec![];
+    let mut total_size = 0;
+    let mut reduced_size = 0;
+    let mut decompressed = HashSet::new();
+    for (index, (layer, diff_id)) in target_manifest.layers.iter().zip(target_diff_ids).enumerate() {
+        if let Some(source_layer) = source_diff_ids.iter().position(|id| *id == diff_id) {
+            tracing::info!("Layer {} is source layer {}", diff_id, source_layer);
+            layer_deltas.push(LayerDelta { diff_id, source_layer: Some(source_layer), patch: None,
+                window_log: 0 });
+            continue;
+        }
+
+        let target_tar = work_dir.join(&format!("target-{}.tar", index));
+        if decompress_layer(&target, layer, &target_tar)? != diff_id {
+            return Err(Error::LayerDigestMismatch(diff_id).into());
+        }
+
+        let source_layer = source_manifest.layers.len().checked_sub(1).map(|top| index.min(top));
+        let source_tar = match source_layer {
+            Some(source_layer) => {
+                let path = work_dir.join(&format!("source-{}.tar", source_layer));
+                if decompressed.insert(source_layer) {
+                    decompress_layer(&source, &source_manifest.layers[source_layer], &path)?;
+                }
+                path
+            }
+            None => empty_path.clone(),
+        };
+
+        let target_size = target_tar.metadata()?.len();
+        let window_log = patch_from::window_log(source_tar.metadata()?.len(), target_size);
+        let patch = format!("{}.patch", index);
+        let patch_size = patch_from::encode_to_file(&source_tar, &target_tar, &delta_dir.join(&patch),
+            options.compression_level, window_log)?;
+        std::fs::remove_file(&target_tar)?;
+        tracing::info!("Layer {}: {} -> {}", diff_id, target_size, patch_size);
+
+        total_size += target_size;
+        reduced_size += patch_size;
+        layer_deltas.push(LayerDelta { diff_id, source_layer, patch: Some(patch), window_log });
+    }
+
+    let output = create_output(output_image, &work_dir, options.layer_compression)?;
+    let mut annotations = BTreeMap::new();
+    let delta_layers = write_delta_layers(&output, &delta_dir, options.layer_size, &mut config,
+        "deltaimage diff-oci --per-layer", &mut annotations)?;
+    annotations.insert(ANNOTATION_VERSION.to_owned(), env!("CARGO_PKG_VERSION").to_owned());
+    annotations.insert(ANNOTATION_TARGET_CONFIG.to_owned(), serde_json::to_string(&target_config)?);
+    annotations.insert(ANNOTATION_LAYER_DELTAS.to_owned(), serde_json::to_string(&layer_deltas)?);
+
+    for layer in source_manifest.layers.iter() {
+        output.copy_blob(&source, layer)?;
+    }
+    let mut layers = source_manifest.layers;
+    layers.extend(delta_layers);
+    output.write_image(&config, layers, annotations)?;
+    finish_output(output_image, &output)?;
+
+    work_dir.remove()?;
+    Ok(DiffStats { total_size, reduced_size, duration: started.elapsed(), ..DiffStats::default() })
+}
+
+/// Restore the layers of the target image from a delta image made by
+/// [`diff_oci_layers`], with the configuration of the target image as it is
+fn apply_layer_deltas(delta: &ImageLayout, manifest: &Manifest, config: &serde_json::Value,
+    layer_deltas: &[LayerDelta], output: &ImageLayout, work_dir: &WorkDir)
+    -> anyhow::Result<ApplyStats>
+{
+    let started = std::time::Instant::now();
+    if manifest.layers.is_empty() {
+        return Err(Error::InvalidOciImage("delta image has no layers".to_owned()).into());
+    }
+    let (source_layers, delta_layers) = manifest.layers.split_at(manifest.layers.len()
+        - delta_layer_count(manifest)?);
+
+    let delta_dir = work_dir.join("delta");
+    unpack_layers(delta, delta_layers, &delta_dir)?;
+    let delta_dir = delta_dir.join(DELTA_DIR_NAME);
+    let empty_path = work_dir.join("empty.tar");
+    File::create(&empty_path)?;
+
+    let mut layers = vec![];
+    let mut total_size = 0;
+    let mut reduced_size = 0;
+    let mut decompressed = HashSet::new();
+    for layer_delta in layer_deltas {
+        let source_layer = match layer_delta.source_layer {
+            Some(index) => Some(source_layers.get(index).ok_or_else(|| {
+                Error::InvalidOciImage(format!("no source layer {}", index))
+            })?),
+            None => None,
+        };
+
+        let Some(patch) = &layer_delta.patch else {
+            // The very same layer as in the source image
+            let Some(source_layer) = source_layer else {
+                return Err(Error::InvalidOciImage("layer delta refers to nothing".to_owned()).into());
+            };
+            output.copy_blob(delta, source_layer)?;
+            layers.push(source_layer.clone());
+            continue;
+        };
+
+        let source_tar = match (layer_delta.source_layer, source_layer) {
+            (Some(index), Some(source_layer)) => {
+                let path = work_dir.join(&format!("source-{}.tar", index));
+                if decompressed.insert(index) {
+                    decompress_layer(delta, source_layer, &path)?;
+                }
+                path
+            }
+            _ => empty_path.clone(),
+        };
+
+        let patch_path = delta_dir.join(patch);
+        let mut restored_size = 0;
+        let (layer, diff_id) = output.write_raw_layer(|mut out| {
+            restored_size = patch_from::decode(&source_tar, &patch_path, &mut out,
+                layer_delta.window_log)?;
+            Ok(out)
+        })?;
+        if diff_id != layer_delta.diff_id {
+            return Err(Error::LayerDigestMismatch(layer_delta.diff_id.clone()).into());
+        }
+        tracing::info!("Restored layer {}: {}", diff_id, restored_size);
+
+        total_size += restored_size;
+        reduced_size += patch_path.metadata()?.len();
+        layers.push(layer);
+    }
+
+    output.write_image(config, layers, BTreeMap::new())?;
+    Ok(ApplyStats { reduced_size, total_size, files: vec![], duration: started.elapsed(),
+        tree_digest: None, unapplied_metadata: vec![], image_config: None })
+}
+
+/// Write the uncompressed content of a layer to `path`, returning its diff ID
+fn decompress_layer(layout: &ImageLayout, layer: &Descriptor, path: &Path) -> anyhow::Result<String> {
+    let blob_path = layout.blob_path(&layer.digest)?;
+    let mut blob = BufReader::new(File::open(&blob_path)
+        .with_context(|| format!("failed to open {}", blob_path.display()))?);
+    let out = BufWriter::new(File::create(path)
+        .with_context(|| format!("failed to create {}", path.display()))?);
+    let mut out = HashingWriter { inner: out, hasher: Sha256::new() };
+
+    match layer.media_type.as_str() {
+        MEDIA_TYPE_LAYER_GZIP | MEDIA_TYPE_DOCKER_LAYER_GZIP => {
+            std::io::copy(&mut flate2::read::MultiGzDecoder::new(blob), &mut out)
+        }
+        MEDIA_TYPE_LAYER_ZSTD => zstd::stream::read::Decoder::with_buffer(blob)
+            .and_then(|mut decoder| std::io::copy(&mut decoder, &mut out)),
+        MEDIA_TYPE_LAYER | MEDIA_TYPE_DOCKER_LAYER => std::io::copy(&mut blob, &mut out),
+        other => {
+            return Err(Error::InvalidOciImage(format!("unsupported layer media type {}",
+                other)).into());
+        }
+    }.with_context(|| format!("failed to decompress layer {}", layer.digest))?;
+
+    out.inner.flush()?;
+    Ok(format!("sha256:{:x}", out.hasher.finalize()))
+}
+
+/// Restore the target image from a delta image made by [`diff_oci`], writing
+/// it as a single-layer image with the configuration of the original.
+///
+/// As with [`diff_oci`], the images are unpacked under `work_dir`.
+pub fn apply_oci(delta_image: &Path, output_image: &Path, work_dir: &Path, options: ApplyOptions)
+    -> anyhow::Result<ApplyStats>
+{
+    apply_oci_images(delta_image, output_image, work_dir, options, None, None)
+}
+
+/// Writes a tarball of the named image to the given path
+pub(crate) type ExportImage<'a> = &'a dyn Fn(&str, &Path) -> anyhow::Result<()>;
+
+/// As [`apply_oci`], also accepting thin delta images, whose source image is
+/// then obtained through `export_source`. The restored image is named after
+/// `tag`, or after the target image of a thin delta image.
+pub(crate) fn apply_oci_images(delta_image: &Path, output_image: &Path, work_dir: &Path,
+    options: ApplyOptions, export_source: Option<ExportImage>, tag: Option<&str>)
+    -> anyhow::Result<ApplyStats>
+{
+    let work_dir = WorkDir::create(work_dir)?;
+    let delta = ImageLayout::open(delta_image, &work_dir.join("delta-layout"))?;
+    let (manifest, _) = delta.manifest()?;
+
+    let Some(target_config) = manifest.annotations.get(ANNOTATION_TARGET_CONFIG) else {
+        return Err(Error::InvalidOciImage("not a delta image, target configuration missing"
+            .to_owned()).into());
+    };
+    let mut config: serde_json::Value = serde_json::from_str(target_config)
+        .context("failed to parse the target configuration")?;
+
+    if let Some(layer_deltas) = manifest.annotations.get(ANNOTATION_LAYER_DELTAS) {
+        let layer_deltas: Vec<LayerDelta> = serde_json::from_str(layer_deltas)
+            .context("failed to parse the layer deltas")?;
+        let output = create_output(output_image, &work_dir, options.layer_compression)?;
+        let stats = apply_layer_deltas(&delta, &manifest, &config, &layer_deltas, &output,
+            &work_dir)?;
+        if let Some(tag) = tag {
+            output.tag_image(tag)?;
+        }
+        finish_output(output_image, &output)?;
+
+        work_dir.remove()?;
+        return Ok(stats);
+    }
+
+    // Also the layer digests of the source image, as listed in its configuration
+    let delta_layers = delta_layer_count(&manifest)?;
+    let (layout, layers, mut source_diff_ids) = match manifest.annotations.get(ANNOTATION_SOURCE_IMAGE) {
+        None => {
+            let (_, delta_config) = delta.manifest()?;
+            let mut diff_ids = config_diff_ids(&delta_config)?;
+            diff_ids.truncate(diff_ids.len().saturating_sub(delta_layers));
+            (delta, manifest.layers.clone(), diff_ids)
+        }
+        Some(source_image) => {
+            let Some(export_source) = export_source else {
+                return Err(Error::InvalidOciImage(format!("thin delta image, load it with \
+                    load-delta on a host having {}", source_image)).into());
+            };
+            let source_tar = work_dir.join("source.tar");
+            export_source(source_image, &source_tar)?;
+            let source = ImageLayout::open(&source_tar, &work_dir.join("source-layout"))?;
+            let (source_manifest, source_config) = source.manifest()?;
+            if manifest.annotations.get(ANNOTATION_SOURCE_CONFIG) != Some(&source_manifest.config.digest) {
+                return Err(Error::SourceImageMismatch(source_image.to_owned()).into());
+            }
+
+            // The source layers as stored locally, with the delta layer on top
+            let merged = ImageLayout::create(&work_dir.join("merged-layout"))?;
+            for layer in source_manifest.layers.iter() {
+                merged.copy_blob(&source, layer)?;
+            }
+            for layer in manifest.layers.iter() {
+                merged.copy_blob(&delta, layer)?;
+            }
+            let mut layers = source_manifest.layers;
+            layers.extend(manifest.layers.iter().cloned());
+            (merged, layers, config_diff_ids(&source_config)?)
+        }
+    };
+    let tag = tag.or_else(|| manifest.annotations.get(ANNOTATION_TARGET_IMAGE)
+        .map(|name| name.as_str()));
+
+    // The source image and the delta tree on top of it, as in a container build
+    let root_dir = work_dir.join("root");
+    unpack_layers(&layout, &layers, &root_dir)?;
+    let delta_dir = root_dir.join(DELTA_DIR_NAME);
+    if !delta_dir.is_dir() {
+        return Err(Error::InvalidOciImage(format!("no {} in the delta image", DELTA_DIR_NAME)).into());
+    }
+
+    let (layered, layer_compression) = (options.layered, options.layer_compression);
+    let stats = DeltaApplier::new(&root_dir, &delta_dir).options(options).run()?;
+
+    let output = create_output(output_image, &work_dir, layer_compression)?;
+    let (layer, diff_id, mut output_layers) = if layered {
+        // The restored tree as changes to the source tree that it was applied on
+        let (layer, diff_id) = output.write_changes_layer(&root_dir, &delta_dir, &delta_dir)?;
+        let source_layers = &layers[..layers.len() - delta_layers];
+        for source_layer in source_layers {
+            output.copy_blob(&layout, source_layer)?;
+        }
+        (layer, diff_id, source_layers.to_vec())
+    } else {
+        source_diff_ids.clear();
+        let (layer, diff_id) = output.write_layer(&delta_dir, Path::new(""))?;
+        (layer, diff_id, vec![])
+    };
+    tracing::info!("Restored layer {}: {}", layer.digest, layer.size);
+
+    config["rootfs"] = serde_json::json!({ "type": "layers", "diff_ids": source_diff_ids });
+    config["history"] = serde_json::json!([]);
+    push_config_layer(&mut config, diff_id, "deltaimage apply-oci")?;
+    output_layers.push(layer);
+    output.write_image(&config, output_layers, BTreeMap::new())?;
+    if let Some(tag) = tag {
+        output.tag_image(tag)?;
+    }
+    finish_output(output_image, &output)?;
+
+    work_dir.remove()?;
+    Ok(stats)
+}
+
+/// Digests of the uncompressed layers listed in an image configuration
+pub(crate) fn config_diff_ids(config: &serde_json::Value) -> anyhow::Result<Vec<String>> {
+    let Some(diff_ids) = config.pointer("/rootfs/diff_ids").and_then(|x| x.as_array()) else {
+        return Err(Error::InvalidOciImage("configuration lists no layers".to_owned()).into());
+    };
+    Ok(diff_ids.iter().filter_map(|x| x.as_str()).map(|x| x.to_owned()).collect())
+}
+
+/// Record a new top layer in an image configuration
+fn push_config_layer(config: &mut serde_json::Value, diff_id: String, created_by: &str)
+    -> anyhow::Result<()>
+{
+    let Some(diff_ids) = config.pointer_mut("/rootfs/diff_ids").and_then(|x| x.as_array_mut()) else {
+        return Err(Error::InvalidOciImage("configuration lists no layers".to_owned()).into());
+    };
+    diff_ids.push(diff_id.into());
+
+    if let Some(history) = config.get_mut("history").and_then(|x| x.as_array_mut()) {
+        history.push(serde_json::json!({ "created_by": created_by }));
+    }
+    Ok(())
+}
+
+/// Unpack image layers on top of each other into `root`, applying their
+/// whiteouts.
+fn unpack_layers(layout: &ImageLayout, layers: &[Descriptor], root: &Path)
+    -> anyhow::Result<()>
+{
+    std::fs::create_dir(root)
+        .with_context(|| format!("failed creating directory {}", root.display()))?;
+
+    // Directory meta-data is restored last, once their content is in place
+    let mut directories = HashMap::new();
+
+    for layer in layers {
+        tracing::info!("Unpacking layer {}", layer.digest);
+
+        let blob_path = layout.blob_path(&layer.digest)?;
+        let blob = BufReader::new(File::open(&blob_path)
+            .with_context(|| format!("failed to open {}", blob_path.display()))?);
+        match layer.media_type.as_str() {
+            MEDIA_TYPE_LAYER_GZIP | MEDIA_TYPE_DOCKER_LAYER_GZIP => {
+                unpack_layer(flate2::read::MultiGzDecoder::new(blob), root, &mut directories)
+            }
+            MEDIA_TYPE_LAYER_ZSTD => zstd::stream::read::Decoder::with_buffer(blob).map_err(anyhow::Error::from)
+                .and_then(|decoder| unpack_layer(decoder, root, &mut directories)),
+            MEDIA_TYPE_LAYER | MEDIA_TYPE_DOCKER_LAYER => {
+                unpack_layer(blob, root, &mut directories)
+            }
+            other => {
+                return Err(Error::InvalidOciImage(format!("unsupported layer media type {}",
+                    other)).into());
+            }
+        }.with_context(|| format!("failed to unpack layer {}", layer.digest))?;
+    }
+
+    restore_directories(root, directories)
+}
+
+/// Unpack a tarball of a whole tree into `root`, such as made by `docker
+/// export`, as it is read
+pub(crate) fn unpack_tree(reader: impl Read, root: &Path) -> anyhow::Result<()> {
+    std::fs::create_dir(root)
+        .with_context(|| format!("failed creating directory {}", root.display()))?;
+
+    let mut directories = HashMap::new();
+    unpack_layer(reader, root, &mut directories)?;
+    restore_directories(root, directories)
+}
+
+fn restore_directories(root: &Path, directories: HashMap<PathBuf, utils::MetaData>)
+    -> anyhow::Result<()>
+{
+    for (rel_path, meta_data) in directories {
+        let path = root.join(rel_path);
+        if path.is_dir() {
+            set_meta_data(&path, meta_data)
+                .with_context(|| format!("failed to set meta-data to {}", path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn unpack_layer(reader: impl Read, root: &Path, directories: &mut HashMap<PathBuf, utils::MetaData>)
+    -> anyhow::Result<()>
+{
+    let mut archive = tar::Archive::new(reader);
+
+    // Whiteouts only hide what lower layers provided
+    let mut unpacked = HashSet::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let rel_path = entry_path(&entry.path()?)?;
+        let path = root.join(&rel_path);
+        let name = rel_path.file_name().map(|x| x.as_bytes()).unwrap_or_default();
+
+        if name == WHITEOUT_OPAQUE {
+            let rel_dir = rel_path.parent().unwrap_or(Path::new(""));
+            remove_lower(root, rel_dir, &unpacked, directories)?;
+            continue;
+        }
+        if rel_path.parent() == Some(Path::new("")) && ESTARGZ_METADATA.iter().any(|meta| name == meta.as_bytes()) {
+            // Table of contents of an eStargz layer
+            continue;
+        }
+        if name.starts_with(WHITEOUT_META_PREFIX) {
+            // Other aufs meta-data, such as hard link directories
+            continue;
+        }
+        if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            let hidden = rel_path.with_file_name(platform::os_string_from_bytes(hidden));
+            if !unpacked.contains(&hidden) {
+                remove_whited_out(root, &hidden, directories)?;
+            }
+            continue;
+        }
+
+        let xattrs = pax_xattrs(&mut entry)?;
+        let header = entry.header();
+        let kind = header.entry_type();
+        let modified = Timestamp { secs: header.mtime()? as i64, nanos: 0 };
+        let meta_data = (modified, modified, header.mode()?, header.uid()? as u32, header.gid()? as u32,
+            xattrs, 0, 0);
+
+        if rel_path.as_os_str().is_empty() {
+            if kind.is_dir() {
+                directories.insert(rel_path, meta_data);
+            }
+            continue;
+        }
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed creating directory {}", parent.display()))?;
+        }
+
+        // Replace whatever lower layers had here, merging directories
+        if let Ok(existing) = std::fs::symlink_metadata(&path) {
+            if !(existing.is_dir() && kind.is_dir()) {
+                remove_path(&path)?;
+            }
+        }
+
+        if kind.is_dir() {
+            if !path.is_dir() {
+                std::fs::create_dir(&path)
+                    .with_context(|| format!("failed creating directory {}", path.display()))?;
+            }
+            directories.insert(rel_path.clone(), meta_data);
+        } else if kind.is_file() {
+            let mut file = File::create(&path)
+                .with_context(|| format!("failed to create {}", path.display()))?;
+            std::io::copy(&mut entry, &mut file)
+                .with_context(|| format!("failed to write to {}", path.display()))?;
+            set_meta_data(&path, meta_data)
+                .with_context(|| format!("failed to set meta-data to {}", path.display()))?;
+        } else if kind.is_symlink() {
+            let Some(target) = entry.link_name()? else {
+                return Err(Error::InvalidOciImage(format!("symlink {} has no target",
+                    rel_path.display())).into());
+            };
+            platform::symlink(&target, &path)
+                .with_context(|| format!("failed creating symlink {}", path.display()))?;
+            set_symlink_owner(&path, meta_data.3, meta_data.4)?;
+            let mtime = modified.into();
+            filetime::set_symlink_file_times(&path, mtime, mtime).map_err(|e| {
+                Error::FileTimeError(e, path.to_owned())
+            })?;
+        } else if kind.is_hard_link() {
+            let Some(target) = entry.link_name()? else {
+                return Err(Error::InvalidOciImage(format!("hardlink {} has no target",
+                    rel_path.display())).into());
+            };
+            let target = root.join(entry_path(&target)?);
+            std::fs::hard_link(&target, &path)
+                .with_context(|| format!("failed linking {} -> {}",
+                        target.display(), path.display()))?;
+        } else if let Some(kind) = special_kind(kind) {
+            let major = header.device_major()?.unwrap_or(0) as u64;
+            let minor = header.device_minor()?.unwrap_or(0) as u64;
+            make_special(&path, kind, platform::makedev(major, minor))
+                .with_context(|| format!("failed creating special file {}", path.display()))?;
+            set_meta_data(&path, meta_data)
+                .with_context(|| format!("failed to set meta-data to {}", path.display()))?;
+        } else {
+            tracing::debug!("Skipping {:?} entry {}", kind, rel_path.display());
+            continue;
+        }
+
+        unpacked.insert(rel_path);
+    }
+
+    Ok(())
+}
+
+/// Remove what lower layers provided under the directory `rel_dir`, as of an
+/// opaque whiteout, keeping the paths unpacked from the current layer
+fn remove_lower(root: &Path, rel_dir: &Path, unpacked: &HashSet<PathBuf>,
+    directories: &mut HashMap<PathBuf, utils::MetaData>) -> anyhow::Result<()>
+{
+    let dir = root.join(rel_dir);
+    if !std::fs::symlink_metadata(&dir).map(|x| x.is_dir()).unwrap_or(false) {
+        return Ok(());
+    }
+
+    for child in std::fs::read_dir(&dir)? {
+        let child = child?;
+        let rel_child = rel_dir.join(child.file_name());
+        if !unpacked.contains(&rel_child) {
+            remove_whited_out(root, &rel_child, directories)?;
+        } else if child.file_type()?.is_dir() {
+            // Merged with a lower directory, whose content is hidden as well
+            remove_lower(root, &rel_child, unpacked, directories)?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove a path hidden by a whiteout, along with the meta-data recorded for
+/// the directories under it
+fn remove_whited_out(root: &Path, rel_path: &Path,
+    directories: &mut HashMap<PathBuf, utils::MetaData>) -> anyhow::Result<()>
+{
+    let path = root.join(rel_path);
+    if std::fs::symlink_metadata(&path).is_ok() {
+        remove_path(&path)?;
+    }
+    directories.retain(|dir, _| !dir.starts_with(rel_path));
+    Ok(())
+}
+
+/// Normalize the path of a layer entry, which must stay within the root
+fn entry_path(path: &Path) -> anyhow::Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(Error::InvalidOciImage(format!("entry path escapes the root: {}",
+                    path.display())).into());
+            }
+        }
+    }
+    Ok(normalized)
+}
+
+fn pax_xattrs<R: Read>(entry: &mut tar::Entry<R>) -> anyhow::Result<Vec<(OsString, Vec<u8>)>> {
+    let mut xattrs = vec![];
+    if let Some(extensions) = entry.pax_extensions()? {
+        for extension in extensions {
+            let extension = extension?;
+            if let Some(key) = extension.key().ok().and_then(|x| x.strip_prefix(PAX_XATTR_PREFIX)) {
+                xattrs.push((OsString::from(key), extension.value_bytes().to_owned()));
+            }
+        }
+    }
+    Ok(xattrs)
+}
+
+fn remove_path(path: &Path) -> anyhow::Result<()> {
+    let metadata = std::fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }.with_context(|| format!("failed removing {}", path.display()))
+}
+
+/// Type of special file of a tar entry, if it is one
+fn special_kind(entry_type: tar::EntryType) -> Option<SpecialKind> {
+    match entry_type {
+        tar::EntryType::Fifo => Some(SpecialKind::Fifo),
+        tar::EntryType::Char => Some(SpecialKind::CharDevice),
+        tar::EntryType::Block => Some(SpecialKind::BlockDevice),
+        _ => None,
+    }
+}
+
+/// Append the tree at `dir` to a tar stream, placing its paths under `prefix`.
+/// The root of the tree itself is only included under a non-empty prefix.
+fn append_tree<W: Write>(builder: &mut tar::Builder<W>, dir: &Path, prefix: &Path) -> anyhow::Result<()> {
+    append_part(builder, dir, prefix, |_| true)
+}
+
+/// As [`append_tree`], with only the paths relative to `dir` that `include`
+/// accepts, besides the root of the tree
+fn append_part<W: Write>(builder: &mut tar::Builder<W>, dir: &Path, prefix: &Path,
+    include: impl Fn(&Path) -> bool) -> anyhow::Result<()>
+{
+    let n = dir.components().count();
+    let mut first_links = HashMap::new();
+
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry?;
+        let rel_path = drop_components(n, entry.path());
+        let name = prefix.join(&rel_path);
+        if name.as_os_str().is_empty() || !(rel_path.as_os_str().is_empty() || include(&rel_path)) {
+            continue;
+        }
+        append_path(builder, entry.path(), &name, &entry.metadata()?, &mut first_links)?;
+    }
+
+    Ok(())
+}
+
+/// Split the tree at `dir` into parts of at most `max_size` bytes of file
+/// content, unless for a single larger file, as sets of paths relative to
+/// `dir`. Each part has the directories leading to its files, the first one
+/// all of them, and hardlinked files are kept in the same part.
+fn split_tree(dir: &Path, max_size: u64) -> anyhow::Result<Vec<HashSet<PathBuf>>> {
+    let n = dir.components().count();
+    let mut parts = vec![HashSet::new()];
+    let mut part_size = 0;
+    let mut linked = HashMap::new();
+
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry?;
+        let rel_path = drop_components(n, entry.path());
+        let metadata = entry.metadata()?;
+        if rel_path.as_os_str().is_empty() {
+            continue;
+        }
+        if metadata.is_dir() {
+            parts[0].insert(rel_path);
+            continue;
+        }
+
+        let fsid = (metadata.ino(), metadata.dev());
+        let index = match linked.get(&fsid) {
+            Some(&index) => index,
+            None => {
+                if part_size > 0 && part_size + metadata.len() > max_size {
+                    parts.push(HashSet::new());
+                    part_size = 0;
+                }
+                part_size += metadata.len();
+                parts.len() - 1
+            }
+        };
+        if metadata.is_file() && metadata.nlink() >= 2 {
+            linked.insert(fsid, index);
+        }
+        let part = &mut parts[index];
+        part.extend(rel_path.ancestors().skip(1)
+            .take_while(|ancestor| !ancestor.as_os_str().is_empty())
+            .map(Path::to_owned));
+        part.insert(rel_path);
+    }
+
+    Ok(parts)
+}
+
+/// Append the paths of the tree at `upper` that differ from the tree at
+/// `lower` to a tar stream, making a layer that turns the latter into the
+/// former: paths of `lower` missing from `upper` get whiteouts. The `ignored`
+/// path of `lower` is left out.
+fn append_changes<W: Write>(builder: &mut tar::Builder<W>, lower: &Path, upper: &Path,
+    ignored: &Path) -> anyhow::Result<()>
+{
+    let n = upper.components().count();
+    let mut first_links = HashMap::new();
+
+    for entry in WalkDir::new(upper).sort_by_file_name() {
+        let entry = entry?;
+        let name = drop_components(n, entry.path());
+        if name.as_os_str().is_empty() {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+
+        // Hard links are kept whole within the layer
+        let lower_path = lower.join(&name);
+        if lower_path != ignored && (metadata.is_dir() || metadata.nlink() < 2)
+            && same_entry(&lower_path, entry.path(), &metadata)?
+        {
+            continue;
+        }
+        append_path(builder, entry.path(), &name, &metadata, &mut first_links)?;
+    }
+
+    let n = lower.components().count();
+    let mut walker = WalkDir::new(lower).sort_by_file_name().into_iter();
+    while let Some(entry) = walker.next() {
+        let entry = entry?;
+        let name = drop_components(n, entry.path());
+        if name.as_os_str().is_empty() {
+            continue;
+        }
+
+        let replaced = match std::fs::symlink_metadata(upper.join(&name)) {
+            Ok(metadata) => !metadata.is_dir(),
+            Err(_) => {
+                if entry.path() != ignored {
+                    let mut whiteout = platform::os_string_from_bytes(WHITEOUT_PREFIX);
+                    whiteout.push(entry.file_name());
+                    let mut header = tar::Header::new_gnu();
+                    header.set_entry_type(tar::EntryType::Regular);
+                    header.set_mode(0o644);
+                    header.set_size(0);
+                    builder.append_data(&mut header, name.with_file_name(whiteout), std::io::empty())?;
+                }
+                true
+            }
+        };
+        if replaced && entry.file_type().is_dir() {
+            walker.skip_current_dir();
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the path of the lower tree has the same type, meta-data and
+/// content as that of the upper tree
+pub(crate) fn same_entry(lower_path: &Path, upper_path: &Path, upper: &std::fs::Metadata)
+    -> anyhow::Result<bool>
+{
+    let Ok(lower) = std::fs::symlink_metadata(lower_path) else { return Ok(false) };
+    if lower.file_type() != upper.file_type() || lower.mode() != upper.mode()
+        || lower.uid() != upper.uid() || lower.gid() != upper.gid()
+        || lower.mtime() != upper.mtime()
+    {
+        return Ok(false);
+    }
+
+    if upper.is_symlink() {
+        return Ok(std::fs::read_link(lower_path)? == std::fs::read_link(upper_path)?);
+    }
+    if get_meta_data(lower_path)?.5 != get_meta_data(upper_path)?.5 {
+        return Ok(false);
+    }
+    if upper.is_file() {
+        return Ok(lower.len() == upper.len() && digest_file(lower_path)? == digest_file(upper_path)?);
+    }
+    Ok(upper.is_dir() || lower.rdev() == upper.rdev())
+}
+
+/// Append one path to a tar stream as `name`, as a link to the first name
+/// appended for the same file if it has several
+fn append_path<W: Write>(builder: &mut tar::Builder<W>, path: &Path, name: &Path,
+    metadata: &std::fs::Metadata, first_links: &mut HashMap<(u64, u64), PathBuf>)
+    -> anyhow::Result<()>
+{
+    let file_type = metadata.file_type();
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(metadata.mode() & 0o7777);
+    header.set_uid(metadata.uid() as u64);
+    header.set_gid(metadata.gid() as u64);
+    header.set_mtime(metadata.mtime().max(0) as u64);
+    header.set_size(0);
+
+    if file_type.is_symlink() {
+        header.set_entry_type(tar::EntryType::Symlink);
+        builder.append_link(&mut header, name, std::fs::read_link(path)?)?;
+        return Ok(());
+    }
+
+    let (_, _, _, _, _, xattrs, _, _) = get_meta_data(path)?;
+    let pax: Vec<_> = xattrs.iter()
+        .map(|(key, value)| (format!("{}{}", PAX_XATTR_PREFIX, key.to_string_lossy()), value))
+        .collect();
+
+    if file_type.is_dir() {
+        builder.append_pax_extensions(pax.iter().map(|(k, v)| (k.as_str(), v.as_slice())))?;
+        header.set_entry_type(tar::EntryType::Directory);
+        builder.append_data(&mut header, name, std::io::empty())?;
+    } else if file_type.is_file() {
+        if metadata.nlink() >= 2 {
+            let fsid = (metadata.ino(), metadata.dev());
+            if let Some(first) = first_links.get(&fsid) {
+                header.set_entry_type(tar::EntryType::Link);
+                builder.append_link(&mut header, name, first)?;
+                return Ok(());
+            }
+            first_links.insert(fsid, name.to_owned());
+        }
+
+        builder.append_pax_extensions(pax.iter().map(|(k, v)| (k.as_str(), v.as_slice())))?;
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(metadata.len());
+        builder.append_data(&mut header, name, File::open(path)?)
+            .with_context(|| format!("failed to archive {}", path.display()))?;
+    } else {
+        // Sockets have no tar representation
+        let entry_type = match SpecialKind::of(file_type) {
+            Some(SpecialKind::Fifo) => tar::EntryType::Fifo,
+            Some(SpecialKind::CharDevice) => tar::EntryType::Char,
+            Some(SpecialKind::BlockDevice) => tar::EntryType::Block,
+            Some(SpecialKind::Socket) | None => return Ok(()),
+        };
+        builder.append_pax_extensions(pax.iter().map(|(k, v)| (k.as_str(), v.as_slice())))?;
+        header.set_entry_type(entry_type);
+        header.set_device_major(platform::major(metadata.rdev()) as u32)?;
+        header.set_device_minor(platform::minor(metadata.rdev()) as u32)?;
+        builder.append_data(&mut header, name, std::io::empty())?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,76 @@
+//! Order in which apply restores files, so that those that take the longest,
+//! or that matter the most, such as the binaries of a service whose health
+//! checks wait on them, are restored first.
+//!
+//! The order is recorded in the meta-data of a delta from the report of a
+//! trial apply, slowest files first, which also keeps the last workers from
+//! starting on large files when the others are done.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::Context;
+use glob::Pattern;
+
+use crate::Error;
+use crate::metadata::MetaData;
+use crate::platform;
+use crate::report::Report;
+use crate::signing;
+
+/// Record in a delta directory the order to restore its modified files in,
+/// from the durations in the report of a trial apply, returning the number
+/// of files ordered. Signed deltas are signed again with `sign_key`.
+pub fn record_apply_order(delta_dir: &Path, report: &Report, sign_key: Option<&Path>)
+    -> anyhow::Result<usize>
+{
+    let mut md = MetaData::load(delta_dir)?;
+    if md.signature.is_some() && sign_key.is_none() {
+        return Err(Error::SigningKeyNeeded(delta_dir.to_owned()).into());
+    }
+
+    let changed: HashSet<_> = md.changes.iter().map(|(_, path)| path.as_slice()).collect();
+    let mut files: Vec<_> = report.files.iter()
+        .filter(|file| changed.contains(file.path.as_bytes()))
+        .collect();
+    files.sort_by(|a, b| b.duration_secs.total_cmp(&a.duration_secs)
+        .then(b.original_size.cmp(&a.original_size)));
+    let order: Vec<_> = files.iter().map(|file| file.path.as_bytes().to_owned()).collect();
+    let count = order.len();
+
+    md.apply_order = Some(order);
+    if let Some(key_path) = sign_key {
+        signing::sign(&mut md, key_path)?;
+    }
+    md.save(delta_dir)?;
+
+    Ok(count)
+}
+
+/// Sort work items by the patterns their path matches first, in the order of
+/// the patterns, then by the recorded order, keeping the order of the others
+pub(crate) fn prioritize<T>(work: &mut [T], path: impl Fn(&T) -> &[u8], priority: &[String],
+    recorded: Option<&[Vec<u8>]>) -> anyhow::Result<()>
+{
+    if priority.is_empty() && recorded.is_none() {
+        return Ok(());
+    }
+
+    let patterns = priority.iter()
+        .map(|pattern| Pattern::new(pattern).with_context(|| format!("invalid pattern {}", pattern)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let ranks: HashMap<_, _> = recorded.unwrap_or_default().iter()
+        .enumerate()
+        .map(|(rank, path)| (path.as_slice(), rank))
+        .collect();
+
+    // Matched as diff --exclude patterns are, from the root of the tree
+    work.sort_by_cached_key(|item| {
+        let path = path(item);
+        let absolute = Path::new("/").join(platform::path_from_bytes(path));
+        let pattern = patterns.iter().position(|pattern| pattern.matches_path(&absolute));
+        (pattern.unwrap_or(usize::MAX), ranks.get(path).copied().unwrap_or(usize::MAX))
+    });
+
+    Ok(())
+}
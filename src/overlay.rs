@@ -0,0 +1,167 @@
+//! Output of apply as the upper directory of an overlayfs mount over the
+//! source tree, which then stays untouched as its lower directory.
+//!
+//! The upper directory holds the paths of the restored tree that differ from
+//! the source tree, and a whiteout, a character device numbered 0/0, for each
+//! path of the source tree that the restored one lacks.
+//!
+//! Upper directories are also merged onto clones of the source tree, for
+//! apply on btrfs and ZFS snapshots.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use walkdir::WalkDir;
+
+use crate::Error;
+use crate::attributes::MetaDataWriter;
+use crate::metadata::SpecialKind;
+use crate::oci::same_entry;
+use crate::platform::MetadataExt;
+use crate::utils::{copy_tree, drop_components, get_meta_data, make_special, set_meta_data};
+
+/// Move the paths of the restored tree at `merged` that differ from the
+/// source tree at `lower` into the upper directory `upper`, adding whiteouts
+/// for the deleted paths, and remove what is left of `merged`. Returns the
+/// number of paths moved and of whiteouts.
+pub(crate) fn split_upper_dir(lower: &Path, merged: &Path, upper: &Path, writer: &MetaDataWriter)
+    -> anyhow::Result<(usize, usize)>
+{
+    if std::fs::read_dir(upper).map(|mut entries| entries.next().is_some()).unwrap_or(false) {
+        return Err(Error::UpperDirNotEmpty(upper.to_owned()).into());
+    }
+
+    // Paths replaced by other kinds of paths are moved, hiding what the
+    // source tree has there, and new directories are moved whole
+    let n = merged.components().count();
+    let (mut moved, mut changed_dirs) = (vec![], vec![]);
+    let mut walker = WalkDir::new(merged).sort_by_file_name().into_iter();
+    while let Some(entry) = walker.next() {
+        let entry = entry?;
+        let rel_path = drop_components(n, entry.path());
+        if rel_path.as_os_str().is_empty() {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        let lower_path = lower.join(&rel_path);
+
+        // Hard links are kept whole within the upper directory
+        if (metadata.is_dir() || metadata.nlink() < 2) && same_entry(&lower_path, entry.path(), &metadata)? {
+            continue;
+        }
+        match metadata.is_dir() && lower_path.symlink_metadata().map(|lower| lower.is_dir()).unwrap_or(false) {
+            true => changed_dirs.push(rel_path),
+            false => {
+                if metadata.is_dir() {
+                    walker.skip_current_dir();
+                }
+                moved.push(rel_path);
+            }
+        }
+    }
+
+    let n = lower.components().count();
+    let mut whiteouts = vec![];
+    let mut walker = WalkDir::new(lower).sort_by_file_name().into_iter();
+    while let Some(entry) = walker.next() {
+        let entry = entry?;
+        let rel_path = drop_components(n, entry.path());
+        if rel_path.as_os_str().is_empty() {
+            continue;
+        }
+        let replaced = match std::fs::symlink_metadata(merged.join(&rel_path)) {
+            Ok(metadata) => !metadata.is_dir(),
+            Err(_) => {
+                whiteouts.push(rel_path);
+                true
+            }
+        };
+        if replaced && entry.file_type().is_dir() {
+            walker.skip_current_dir();
+        }
+    }
+
+    // Directories of the upper directory, with those holding its paths
+    let dirs: BTreeSet<PathBuf> = moved.iter().chain(whiteouts.iter())
+        .flat_map(|rel_path| rel_path.ancestors().skip(1))
+        .filter(|ancestor| !ancestor.as_os_str().is_empty())
+        .map(Path::to_owned)
+        .chain(changed_dirs)
+        .collect();
+    std::fs::create_dir_all(upper)
+        .with_context(|| format!("failed creating directory {}", upper.display()))?;
+    for rel_path in dirs.iter() {
+        let path = upper.join(rel_path);
+        std::fs::create_dir(&path)
+            .with_context(|| format!("failed creating directory {}", path.display()))?;
+    }
+
+    for rel_path in moved.iter() {
+        let (path, upper_path) = (merged.join(rel_path), upper.join(rel_path));
+        std::fs::rename(&path, &upper_path)
+            .with_context(|| format!("failed moving {} to {}", path.display(), upper_path.display()))?;
+    }
+    for rel_path in whiteouts.iter() {
+        let path = upper.join(rel_path);
+        make_special(&path, SpecialKind::CharDevice, 0)
+            .with_context(|| format!("failed creating whiteout {}", path.display()))?;
+    }
+
+    // Deepest first, for the modification times to stick
+    for rel_path in dirs.iter().rev() {
+        let path = upper.join(rel_path);
+        writer.set(&path, get_meta_data(&merged.join(rel_path))?)
+            .with_context(|| format!("failed to set meta-data to {}", path.display()))?;
+    }
+    writer.set(upper, get_meta_data(merged)?)
+        .with_context(|| format!("failed to set meta-data to {}", upper.display()))?;
+
+    std::fs::remove_dir_all(merged)
+        .with_context(|| format!("failed removing {}", merged.display()))?;
+    Ok((moved.len(), whiteouts.len()))
+}
+
+/// Apply the upper directory `upper` onto the tree at `dir`, as an overlayfs
+/// mount of the two would show it, and remove `upper`
+pub(crate) fn merge_upper_dir(upper: &Path, dir: &Path) -> anyhow::Result<()> {
+    let n = upper.components().count();
+    let mut dirs = vec![];
+    let mut walker = WalkDir::new(upper).sort_by_file_name().into_iter();
+    while let Some(entry) = walker.next() {
+        let entry = entry?;
+        let rel_path = drop_components(n, entry.path());
+        let path = dir.join(&rel_path);
+        let existing = std::fs::symlink_metadata(&path).ok();
+        if entry.file_type().is_dir() && existing.as_ref().map(|metadata| metadata.is_dir()).unwrap_or(false) {
+            dirs.push((path, get_meta_data(entry.path())?));
+            continue;
+        }
+
+        match existing {
+            Some(metadata) if metadata.is_dir() => std::fs::remove_dir_all(&path),
+            Some(_) => std::fs::remove_file(&path),
+            None => Ok(()),
+        }.with_context(|| format!("failed removing {}", path.display()))?;
+        if entry.file_type().is_dir() {
+            walker.skip_current_dir();
+        }
+        let metadata = entry.metadata()?;
+        if SpecialKind::of(metadata.file_type()) == Some(SpecialKind::CharDevice) && metadata.rdev() == 0 {
+            continue;
+        }
+        match std::fs::rename(entry.path(), &path) {
+            Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => copy_tree(entry.path(), &path)?,
+            result => result.with_context(|| format!("failed moving {} to {}", entry.path().display(),
+                path.display()))?,
+        }
+    }
+
+    // Deepest first, for the modification times to stick
+    for (path, meta_data) in dirs.into_iter().rev() {
+        set_meta_data(&path, meta_data)
+            .with_context(|| format!("failed to set meta-data to {}", path.display()))?;
+    }
+    std::fs::remove_dir_all(upper)
+        .with_context(|| format!("failed removing {}", upper.display()))
+}
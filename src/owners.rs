@@ -0,0 +1,165 @@
+//! Ownership of restored files, for applies in rootless environments, where
+//! the owners and groups stored in a delta cannot be set as they are.
+//!
+//! Deltas store the numeric IDs seen in the image. On apply, the IDs stored in
+//! the meta-data of the delta or in its archive can be translated through an
+//! ID-mapping table, as user namespaces do, or not set at all. Ownership taken
+//! from files on disk, such as the placeholders of the delta tree, is already
+//! translated and copied as it is.
+
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::Error;
+use crate::platform;
+use crate::utils::MetaData;
+
+/// `chown` leaves IDs of -1 unchanged
+pub(crate) const UNCHANGED: u32 = u32::MAX;
+
+/// A range of IDs mapped to another, as in `/proc/<pid>/uid_map`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdRange {
+    /// First ID, as stored in the delta
+    pub inside: u32,
+    /// First ID to set in its place
+    pub outside: u32,
+    pub count: u32,
+}
+
+/// Translation of the owner and group IDs stored in deltas
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OwnerMap {
+    pub uids: Vec<IdRange>,
+    pub gids: Vec<IdRange>,
+}
+
+fn map_id(ranges: &[IdRange], id: u32) -> Option<u32> {
+    ranges.iter()
+        .find(|range| id >= range.inside && id - range.inside < range.count)
+        .map(|range| range.outside + (id - range.inside))
+}
+
+impl OwnerMap {
+    /// Load a mapping table. Each line holds an inside ID, an outside ID and
+    /// a count, as in `/proc/<pid>/uid_map`, applying to both owners and
+    /// groups, or only to one of them if prefixed by `u` or `g`.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let mut map = Self::default();
+
+        for (nr, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || Error::InvalidOwnerMap(path.to_owned(), nr + 1);
+
+            let mut fields: Vec<_> = line.split_whitespace().collect();
+            let (uids, gids) = match fields.first() {
+                Some(&"u") => (true, false),
+                Some(&"g") => (false, true),
+                _ => (true, true),
+            };
+            if !(uids && gids) {
+                fields.remove(0);
+            }
+            let [inside, outside, count] = fields[..] else {
+                return Err(invalid().into());
+            };
+            let parse = |field: &str| field.parse::<u32>().map_err(|_| invalid());
+            let range = IdRange { inside: parse(inside)?, outside: parse(outside)?, count: parse(count)? };
+            if range.outside.checked_add(range.count).is_none() {
+                return Err(invalid().into());
+            }
+
+            if uids {
+                map.uids.push(range);
+            }
+            if gids {
+                map.gids.push(range);
+            }
+        }
+
+        Ok(map)
+    }
+
+    /// The mapping of rootless containers of the current user: root to the
+    /// user itself, and the IDs from 1 on to its ranges in `/etc/subuid` and
+    /// `/etc/subgid`
+    pub fn subordinate() -> anyhow::Result<Self> {
+        let (uid, gid, name) = platform::current_user()?;
+
+        let ranges = |file: &str, id: u32| -> anyhow::Result<Vec<IdRange>> {
+            let content = std::fs::read_to_string(file)
+                .with_context(|| format!("failed to read {}", file))?;
+            let mut ranges = vec![IdRange { inside: 0, outside: id, count: 1 }];
+            let mut inside = 1;
+            for line in content.lines() {
+                let fields: Vec<_> = line.trim().split(':').collect();
+                let [owner, start, count] = fields[..] else { continue };
+                if Some(owner) != name.as_deref() && owner != uid.to_string() {
+                    continue;
+                }
+                let (Ok(outside), Ok(count)) = (start.parse(), count.parse()) else { continue };
+                ranges.push(IdRange { inside, outside, count });
+                inside = inside.saturating_add(count);
+            }
+            Ok(ranges)
+        };
+
+        Ok(Self {
+            uids: ranges("/etc/subuid", uid)?,
+            gids: ranges("/etc/subgid", gid)?,
+        })
+    }
+}
+
+/// How the ownership of restored files is set
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Ownership {
+    /// Set the numeric IDs as stored
+    #[default]
+    Numeric,
+    /// Set the IDs stored translated through a mapping table
+    Map(OwnerMap),
+    /// Never change ownership, leaving the files to the user running apply
+    Skip,
+}
+
+impl Ownership {
+    /// Owner and group to set in place of the ones stored in a delta
+    pub(crate) fn stored_ids(&self, uid: u32, gid: u32) -> anyhow::Result<(u32, u32)> {
+        match self {
+            Ownership::Numeric => Ok((uid, gid)),
+            Ownership::Map(map) => {
+                let uid = map_id(&map.uids, uid).ok_or(Error::UnmappedOwner("user", uid))?;
+                let gid = map_id(&map.gids, gid).ok_or(Error::UnmappedOwner("group", gid))?;
+                Ok((uid, gid))
+            }
+            Ownership::Skip => Ok((UNCHANGED, UNCHANGED)),
+        }
+    }
+
+    /// Owner and group to copy from a file on disk to another
+    pub(crate) fn on_disk_ids(&self, uid: u32, gid: u32) -> (u32, u32) {
+        match self {
+            Ownership::Numeric | Ownership::Map(_) => (uid, gid),
+            Ownership::Skip => (UNCHANGED, UNCHANGED),
+        }
+    }
+
+    /// Meta-data stored in a delta, with the owner and group to set instead
+    pub(crate) fn stored(&self, mut meta_data: MetaData) -> anyhow::Result<MetaData> {
+        (meta_data.3, meta_data.4) = self.stored_ids(meta_data.3, meta_data.4)?;
+        Ok(meta_data)
+    }
+
+    /// Meta-data taken from a file on disk, to set to another
+    pub(crate) fn on_disk(&self, mut meta_data: MetaData) -> MetaData {
+        (meta_data.3, meta_data.4) = self.on_disk_ids(meta_data.3, meta_data.4);
+        meta_data
+    }
+}
@@ -0,0 +1,73 @@
+//! Self-extracting packages, for hosts that do not have deltaimage installed.
+//!
+//! A package is a shell script stub, followed by a deltaimage executable and
+//! a delta archive. The stub extracts both to a temporary directory, by byte
+//! offsets written into it, and runs the apply of the archive.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::archive::pack_archive;
+use crate::platform::OpenOptionsExt;
+use crate::utils::temp_path_for;
+
+/// Offsets are written zero-padded, so that the size of the stub does not
+/// depend on them
+const OFFSET_WIDTH: usize = 20;
+
+fn stub(binary_start: u64, binary_size: u64, archive_start: u64) -> String {
+    format!(r#"#!/bin/sh
+# Self-extracting delta made by deltaimage {version}
+#
+# Usage: sh <this file> <source_dir> <restored_dir>
+#
+# Restores the target tree into <restored_dir>, which must not exist, from the
+# source tree at <source_dir>.
+set -e
+if [ $# -ne 2 ]; then
+    echo "usage: $0 <source_dir> <restored_dir>" >&2
+    exit 2
+fi
+dir=$(mktemp -d)
+trap 'rm -rf "$dir"' EXIT
+tail -c +{binary_start:0width$} "$0" | head -c {binary_size:0width$} > "$dir/deltaimage"
+tail -c +{archive_start:0width$} "$0" > "$dir/delta.arch"
+chmod +x "$dir/deltaimage"
+"$dir/deltaimage" apply --format archive --archive "$dir/delta.arch" "$1" "$2"
+exit 0
+"#, version = env!("CARGO_PKG_VERSION"), width = OFFSET_WIDTH)
+}
+
+/// Pack a delta tree along with the `binary` of deltaimage that applies it into
+/// a single executable file. The binary must be built for the hosts that run
+/// the package, such as a static build of the same architecture.
+pub fn package_self_extracting(delta_dir: &Path, binary: &Path, output: &Path)
+    -> anyhow::Result<()>
+{
+    let archive = temp_path_for(output);
+    pack_archive(delta_dir, &archive)?;
+
+    let result = (|| {
+        let binary_size = binary.metadata()
+            .with_context(|| format!("failed to read {}", binary.display()))?.len();
+        let stub_size = stub(0, 0, 0).len() as u64;
+        // tail counts bytes from 1
+        let stub = stub(stub_size + 1, binary_size, stub_size + binary_size + 1);
+
+        let file = std::fs::OpenOptions::new().write(true).create(true).truncate(true)
+            .mode(0o755).open(output)
+            .with_context(|| format!("failed to create {}", output.display()))?;
+        let mut out = BufWriter::new(file);
+        out.write_all(stub.as_bytes())?;
+        std::io::copy(&mut File::open(binary)?, &mut out)?;
+        std::io::copy(&mut File::open(&archive)?, &mut out)?;
+        out.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        anyhow::Ok(())
+    })();
+
+    std::fs::remove_file(&archive)?;
+    result
+}
@@ -0,0 +1,127 @@
+//! zstd patch-from encoding for very large files.
+//!
+//! The whole source file is given to zstd as a reference prefix, and the
+//! target file is compressed against it with long-distance matching, as done
+//! by `zstd --patch-from`. Unlike chunked xdelta3, matches are found anywhere
+//! in the source, and the target is streamed, so only the source is held in
+//! memory.
+//!
+//! The window covers both files. Its log is recorded in the `Algo`, as apply
+//! needs to allow that much memory for decoding.
+
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::utils::open_noatime;
+
+/// Smallest and largest window logs of zstd on 64-bit systems
+const WINDOW_LOG_MIN: u32 = 10;
+pub const WINDOW_LOG_MAX: u32 = 31;
+
+/// Window log covering files of the given sizes, as far as zstd allows
+pub fn window_log(source_size: u64, target_size: u64) -> u32 {
+    let size = source_size.max(target_size).max(1);
+    (u64::BITS - (size - 1).leading_zeros()).clamp(WINDOW_LOG_MIN, WINDOW_LOG_MAX)
+}
+
+fn read_source(source_path: &Path) -> anyhow::Result<Vec<u8>> {
+    let mut source = vec![];
+    open_noatime(source_path)
+        .and_then(|mut file| file.read_to_end(&mut source))
+        .with_context(|| format!("failed to read {}", source_path.display()))?;
+    Ok(source)
+}
+
+/// Writer counting the bytes written through it
+struct Counter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Encode `target` against `source` in memory, for smaller files
+pub fn encode_all(source: &[u8], target: &[u8], level: i32) -> std::io::Result<Vec<u8>> {
+    let window_log = window_log(source.len() as u64, target.len() as u64);
+    let mut encoder = zstd::stream::write::Encoder::with_ref_prefix(vec![], level, source)?;
+    encoder.long_distance_matching(true)?;
+    encoder.window_log(window_log)?;
+    encoder.write_all(target)?;
+    encoder.finish()
+}
+
+/// Reconstruct in memory the target content encoded by `encode_all`
+pub fn decode_all(source: &[u8], patch: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut decoder = zstd::stream::read::Decoder::with_ref_prefix(patch, source)?;
+    decoder.window_log_max(WINDOW_LOG_MAX)?;
+    let mut target = vec![];
+    decoder.read_to_end(&mut target)?;
+    Ok(target)
+}
+
+/// Encode `target` against `source` into `output`, with the given zstd level
+/// and window log. Returns the size of the patch.
+pub fn encode(source_path: &Path, target_path: &Path, output: &mut impl Write, level: i32,
+    window_log: u32) -> anyhow::Result<u64>
+{
+    let source = read_source(source_path)?;
+    let mut target = open_noatime(target_path)
+        .with_context(|| format!("failed to open {}", target_path.display()))?;
+
+    let counter = Counter { inner: output, count: 0 };
+    let mut encoder = zstd::stream::write::Encoder::with_ref_prefix(counter, level, &source)?;
+    encoder.long_distance_matching(true)?;
+    encoder.window_log(window_log)?;
+    std::io::copy(&mut target, &mut encoder)
+        .with_context(|| format!("failed to encode {}", target_path.display()))?;
+    let mut counter = encoder.finish()?;
+    counter.flush()?;
+
+    Ok(counter.count)
+}
+
+/// Encode `target` against `source` into the patch file `output`. Returns the
+/// size of the patch.
+pub fn encode_to_file(source_path: &Path, target_path: &Path, output_path: &Path, level: i32,
+    window_log: u32) -> anyhow::Result<u64>
+{
+    let mut output = File::create(output_path)
+        .with_context(|| format!("failed to create {}", output_path.display()))?;
+    let written = encode(source_path, target_path, &mut output, level, window_log)?;
+    output.sync_data()
+        .with_context(|| format!("failed to write to {}", output_path.display()))?;
+
+    Ok(written)
+}
+
+/// Reconstruct the target file from `source` and the patch file, writing it to
+/// `output`. Returns the size of the reconstructed file.
+pub fn decode(source_path: &Path, patch_path: &Path, output: &mut impl Write, window_log: u32)
+    -> anyhow::Result<u64>
+{
+    let source = read_source(source_path)?;
+    let patch = File::open(patch_path)
+        .with_context(|| format!("failed to open {}", patch_path.display()))?;
+
+    let mut decoder = zstd::stream::read::Decoder::with_ref_prefix(BufReader::new(patch),
+        &source)?;
+    decoder.window_log_max(window_log)?;
+    let written = std::io::copy(&mut decoder, output)
+        .with_context(|| format!("failed to decode {} against {}", patch_path.display(),
+            source_path.display()))?;
+
+    Ok(written)
+}
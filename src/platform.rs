@@ -0,0 +1,625 @@
+//! What differs between the systems that deltaimage builds on: file
+//! meta-data, special files, device numbers, mounts, memory maps, priorities,
+//! filesystem types and the calls that keep tree walks from following symlinks.
+//!
+//! Linux is the main target. Other Unix systems, such as macOS, leave out the
+//! calls that only Linux has for slower or more limited equivalents, kept next
+//! to them under `cfg(target_os = "linux")`: reads update access times, holes
+//! of sparse files are not found, I/O priorities are not set, files are
+//! copied through user space rather than reflinked or copied within the kernel,
+//! and btrfs and ZFS are not recognized for apply on snapshots.
+//!
+//! Elsewhere, such as on Windows, ownership and xattrs read as empty and are
+//! not set, permissions come down to the read-only flag, and symlinks, special
+//! files and mounts are unsupported, so that deltas of plain artifact trees
+//! made there lose them rather than failing.
+//!
+//! The rest of the crate goes through here rather than `std::os::unix` or
+//! `nix`, including for the extension traits of `std::fs` types, which have
+//! stand-ins of the same names elsewhere.
+
+#[cfg(unix)]
+mod imp {
+    use std::ffi::{c_void, OsStr, OsString};
+    use std::fs::{File, Metadata};
+    use std::num::NonZeroUsize;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::path::{Path, PathBuf};
+
+    use nix::errno::Errno;
+    use nix::fcntl::{openat, OFlag};
+    use nix::libc;
+    use nix::sys::mman::{madvise, mmap, munmap, MapFlags, MmapAdvise, ProtFlags};
+    use nix::sys::stat::{Mode, SFlag};
+    use nix::unistd::{Gid, Uid};
+
+    pub(crate) use std::os::unix::ffi::OsStrExt;
+    pub(crate) use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt, OpenOptionsExt};
+    #[cfg(target_os = "linux")]
+    pub(crate) use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+
+    use crate::metadata::SpecialKind;
+    use super::CloneFs;
+
+    pub(crate) fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+        PathBuf::from(OsStr::from_bytes(bytes))
+    }
+
+    pub(crate) fn os_string_from_bytes(bytes: &[u8]) -> OsString {
+        OsStr::from_bytes(bytes).to_owned()
+    }
+
+    /// Mode, owner, group, inode and device of a file
+    pub(crate) fn attributes(metadata: &Metadata) -> (u32, u32, u32, u64, u64) {
+        (metadata.mode(), metadata.uid(), metadata.gid(), metadata.ino(), metadata.dev())
+    }
+
+    pub(crate) fn chown(path: &Path, uid: u32, gid: u32) -> anyhow::Result<()> {
+        Ok(nix::unistd::chown(path, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)))?)
+    }
+
+    /// Change the ownership of a symlink itself
+    pub(crate) fn lchown(path: &Path, uid: u32, gid: u32) -> anyhow::Result<()> {
+        Ok(nix::unistd::fchownat(None, path, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)),
+            nix::unistd::FchownatFlags::NoFollowSymlink)?)
+    }
+
+    pub(crate) fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+    }
+
+    /// User and group IDs of the current process, and the name of the user
+    pub(crate) fn current_user() -> anyhow::Result<(u32, u32, Option<String>)> {
+        let uid = nix::unistd::getuid();
+        let name = nix::unistd::User::from_uid(uid)?.map(|user| user.name);
+        Ok((uid.as_raw(), nix::unistd::getgid().as_raw(), name))
+    }
+
+    /// Names of the xattrs of a file, none if the filesystem has no xattrs
+    pub(crate) fn list_xattrs(path: &Path) -> Vec<OsString> {
+        xattr::list(path).map(|names| names.collect()).unwrap_or_default()
+    }
+
+    pub(crate) fn get_xattr(path: &Path, name: &OsStr) -> std::io::Result<Option<Vec<u8>>> {
+        xattr::get(path, name)
+    }
+
+    pub(crate) fn set_xattr(path: &Path, name: &OsStr, value: &[u8]) -> std::io::Result<()> {
+        xattr::set(path, name, value)
+    }
+
+    /// Error of a missing xattr
+    #[cfg(target_os = "linux")]
+    const NO_XATTR: Errno = Errno::ENODATA;
+    #[cfg(not(target_os = "linux"))]
+    const NO_XATTR: Errno = Errno::ENOATTR;
+
+    /// Remove an xattr, if the file has it and the filesystem supports it
+    pub(crate) fn remove_xattr(path: &Path, name: &OsStr) -> std::io::Result<()> {
+        match xattr::remove(path, name) {
+            Err(e) if matches!(e.raw_os_error().map(Errno::from_i32), Some(NO_XATTR | Errno::EOPNOTSUPP)) => Ok(()),
+            result => result,
+        }
+    }
+
+    pub(crate) fn symlink(target: &Path, path: &Path) -> std::io::Result<()> {
+        std::os::unix::fs::symlink(target, path)
+    }
+
+    /// Create a FIFO, device node or socket, with no permissions
+    pub(crate) fn mknod(path: &Path, kind: SpecialKind, rdev: u64) -> anyhow::Result<()> {
+        let sflag = match kind {
+            SpecialKind::Fifo => SFlag::S_IFIFO,
+            SpecialKind::CharDevice => SFlag::S_IFCHR,
+            SpecialKind::BlockDevice => SFlag::S_IFBLK,
+            SpecialKind::Socket => SFlag::S_IFSOCK,
+        };
+        nix::sys::stat::mknod(path, sflag, Mode::empty(), rdev as libc::dev_t)?;
+        Ok(())
+    }
+
+    /// Major number of a device, in the encoding of the system
+    pub(crate) fn major(rdev: u64) -> u64 {
+        libc::major(rdev as libc::dev_t) as u64
+    }
+
+    /// Minor number of a device, in the encoding of the system
+    pub(crate) fn minor(rdev: u64) -> u64 {
+        libc::minor(rdev as libc::dev_t) as u64
+    }
+
+    /// Device number, in the encoding of the system, of a major and minor number
+    pub(crate) fn makedev(major: u64, minor: u64) -> u64 {
+        libc::makedev(major as _, minor as _) as u64
+    }
+
+    /// Unmount the filesystem mounted at `path`
+    pub(crate) fn unmount(path: &Path) -> std::io::Result<()> {
+        #[cfg(target_os = "linux")]
+        let result = nix::mount::umount(path);
+        #[cfg(not(target_os = "linux"))]
+        let result = nix::mount::unmount(path, nix::mount::MntFlags::empty());
+        Ok(result?)
+    }
+
+    /// Open a file for reading without updating its access time, where permitted
+    #[cfg(target_os = "linux")]
+    pub(crate) fn open_noatime(path: &Path) -> std::io::Result<File> {
+        std::fs::OpenOptions::new().read(true)
+            .custom_flags(OFlag::O_NOATIME.bits())
+            .open(path)
+            .or_else(|_| File::open(path))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn open_noatime(path: &Path) -> std::io::Result<File> {
+        File::open(path)
+    }
+
+    /// An open directory, relative to which paths are opened
+    pub(crate) type Dir = File;
+
+    pub(crate) fn open_dir(path: &Path) -> std::io::Result<Dir> {
+        File::open(path)
+    }
+
+    /// Open the directory `name` of `dir` with `openat` and `O_NOFOLLOW`.
+    /// `None` if it is a symlink, or no directory.
+    pub(crate) fn open_dir_at(dir: &Dir, name: &OsStr) -> std::io::Result<Option<Dir>> {
+        let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+        match openat(dir.as_raw_fd(), name, flags, Mode::empty()) {
+            // The descriptor was just opened, and nothing else owns it
+            Ok(fd) => Ok(Some(unsafe { File::from_raw_fd(fd) })),
+            Err(Errno::ELOOP | Errno::ENOTDIR) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Create or truncate the file `name` of `dir` for writing, with `openat`
+    /// and `O_NOFOLLOW`. `None` if it is a symlink.
+    pub(crate) fn create_file_at(dir: &Dir, name: &OsStr) -> std::io::Result<Option<File>> {
+        let flags = OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_TRUNC | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+        match openat(dir.as_raw_fd(), name, flags, Mode::from_bits_truncate(0o666)) {
+            // The descriptor was just opened, and nothing else owns it
+            Ok(fd) => Ok(Some(unsafe { File::from_raw_fd(fd) })),
+            Err(Errno::ELOOP) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Offset of the data following `offset` in a file, `None` past the last
+    /// data. Unsupported where the filesystem cannot tell.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn seek_data(file: &File, offset: u64) -> std::io::Result<Option<u64>> {
+        use nix::unistd::{lseek, Whence};
+
+        match lseek(file.as_raw_fd(), offset as i64, Whence::SeekData) {
+            Ok(data) => Ok(Some(data as u64)),
+            Err(Errno::ENXIO) => Ok(None),
+            Err(Errno::EINVAL) => Err(std::io::ErrorKind::Unsupported.into()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Offset of the hole following `offset` in a file, its end at the latest
+    #[cfg(target_os = "linux")]
+    pub(crate) fn seek_hole(file: &File, offset: u64) -> std::io::Result<u64> {
+        use nix::unistd::{lseek, Whence};
+
+        Ok(lseek(file.as_raw_fd(), offset as i64, Whence::SeekHole)? as u64)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn seek_data(_file: &File, _offset: u64) -> std::io::Result<Option<u64>> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn seek_hole(file: &File, _offset: u64) -> std::io::Result<u64> {
+        Ok(file.metadata()?.len())
+    }
+
+    /// Map `len` bytes of a file read-only and private, to be read sequentially
+    pub(crate) fn map_file(file: &File, len: NonZeroUsize) -> std::io::Result<*mut c_void> {
+        // The file is mapped private and read-only, so its content changing
+        // meanwhile only affects what is read
+        let ptr = unsafe {
+            mmap(None, len, ProtFlags::PROT_READ, MapFlags::MAP_PRIVATE, file.as_raw_fd(), 0)
+        }?;
+        // Only a hint
+        let _ = unsafe { madvise(ptr, len.get(), MmapAdvise::MADV_SEQUENTIAL) };
+        Ok(ptr)
+    }
+
+    /// Undo [`map_file`]
+    pub(crate) fn unmap_file(ptr: *mut c_void, len: usize) {
+        let _ = unsafe { munmap(ptr, len) };
+    }
+
+    /// Lower the CPU priority of the calling process to a niceness
+    pub(crate) fn set_niceness(nice: i32) -> std::io::Result<()> {
+        Errno::result(unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) })?;
+        Ok(())
+    }
+
+    /// Set the I/O scheduling class of the calling process, as taken by
+    /// `ioprio_set`
+    #[cfg(target_os = "linux")]
+    pub(crate) fn set_io_priority(value: i32) -> std::io::Result<()> {
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        Errno::result(unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, value) })?;
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn set_io_priority(_value: i32) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    #[cfg(target_os = "linux")]
+    nix::ioctl_write_int!(ficlone, 0x94, 9);
+
+    /// Share the extents of `source` as the content of `file`, on filesystems
+    /// with reflinks. Whether it did.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn reflink(file: &File, source: &File) -> bool {
+        let fd = source.as_raw_fd() as nix::sys::ioctl::ioctl_param_type;
+        unsafe { ficlone(file.as_raw_fd(), fd) }.is_ok()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn reflink(_file: &File, _source: &File) -> bool {
+        false
+    }
+
+    /// Copy up to `len` bytes of `source` at `source_offset` to `file` at
+    /// `offset` within the kernel, advancing `source_offset`. The number of
+    /// bytes copied, none at the end of `source`.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn copy_range(source: &File, source_offset: &mut u64, file: &File, offset: u64, len: usize)
+        -> std::io::Result<usize>
+    {
+        let (mut from, mut to) = (*source_offset as i64, offset as i64);
+        let copied = nix::fcntl::copy_file_range(source.as_raw_fd(), Some(&mut from), file.as_raw_fd(),
+            Some(&mut to), len)?;
+        *source_offset = from as u64;
+        Ok(copied)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn copy_range(_source: &File, _source_offset: &mut u64, _file: &File, _offset: u64, _len: usize)
+        -> std::io::Result<usize>
+    {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    /// Filesystem of `path`, if trees can be cloned on it
+    #[cfg(target_os = "linux")]
+    pub(crate) fn clone_fs(path: &Path) -> std::io::Result<Option<CloneFs>> {
+        use nix::sys::statfs::{statfs, FsType, BTRFS_SUPER_MAGIC};
+
+        const ZFS_SUPER_MAGIC: FsType = FsType(0x2fc12fc1);
+        let fs_type = statfs(path)?.filesystem_type();
+        Ok(if fs_type == BTRFS_SUPER_MAGIC {
+            Some(CloneFs::Btrfs)
+        } else if fs_type == ZFS_SUPER_MAGIC {
+            Some(CloneFs::Zfs)
+        } else {
+            None
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn clone_fs(_path: &Path) -> std::io::Result<Option<CloneFs>> {
+        Ok(None)
+    }
+
+    /// Wait for the events of an inotify instance, up to `timeout`. Whether
+    /// there are any.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn wait_events(inotify: &Inotify, timeout: std::time::Duration) -> std::io::Result<bool> {
+        use nix::poll::{poll, PollFd, PollFlags};
+
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+        let mut fds = [PollFd::new(inotify.as_raw_fd(), PollFlags::POLLIN)];
+        Ok(poll(&mut fds, timeout)? > 0)
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::ffi::{c_void, OsStr, OsString};
+    use std::fs::{File, Metadata};
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::num::NonZeroUsize;
+    use std::path::{Path, PathBuf};
+
+    use crate::metadata::SpecialKind;
+    use super::CloneFs;
+
+    fn unsupported(what: &str) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::Unsupported, format!("{} need Unix", what))
+    }
+
+    /// Stand-in of the Unix one, for the bytes of the paths stored in the
+    /// meta-data
+    pub(crate) trait OsStrExt {
+        fn as_bytes(&self) -> &[u8];
+    }
+
+    impl OsStrExt for OsStr {
+        fn as_bytes(&self) -> &[u8] {
+            self.as_encoded_bytes()
+        }
+    }
+
+    /// Stand-in of the Unix one: root owns everything, files have one link
+    /// and no inode, and the mode is made of the read-only flag
+    pub(crate) trait MetadataExt {
+        fn mode(&self) -> u32;
+        fn uid(&self) -> u32;
+        fn gid(&self) -> u32;
+        fn ino(&self) -> u64;
+        fn dev(&self) -> u64;
+        fn nlink(&self) -> u64;
+        fn rdev(&self) -> u64;
+        fn blocks(&self) -> u64;
+        fn mtime(&self) -> i64;
+    }
+
+    impl MetadataExt for Metadata {
+        fn mode(&self) -> u32 {
+            match (self.is_dir(), self.file_type().is_symlink(), self.permissions().readonly()) {
+                (true, _, _) => 0o040755,
+                (_, true, _) => 0o120777,
+                (_, _, true) => 0o100444,
+                (_, _, false) => 0o100644,
+            }
+        }
+
+        fn uid(&self) -> u32 {
+            0
+        }
+
+        fn gid(&self) -> u32 {
+            0
+        }
+
+        fn ino(&self) -> u64 {
+            0
+        }
+
+        fn dev(&self) -> u64 {
+            0
+        }
+
+        fn nlink(&self) -> u64 {
+            1
+        }
+
+        fn rdev(&self) -> u64 {
+            0
+        }
+
+        fn blocks(&self) -> u64 {
+            self.len().div_ceil(512)
+        }
+
+        fn mtime(&self) -> i64 {
+            filetime::FileTime::from_last_modification_time(self).unix_seconds()
+        }
+    }
+
+    /// Stand-in of the Unix one, with no special files
+    pub(crate) trait FileTypeExt {
+        fn is_fifo(&self) -> bool;
+        fn is_char_device(&self) -> bool;
+        fn is_block_device(&self) -> bool;
+        fn is_socket(&self) -> bool;
+    }
+
+    impl FileTypeExt for std::fs::FileType {
+        fn is_fifo(&self) -> bool {
+            false
+        }
+
+        fn is_char_device(&self) -> bool {
+            false
+        }
+
+        fn is_block_device(&self) -> bool {
+            false
+        }
+
+        fn is_socket(&self) -> bool {
+            false
+        }
+    }
+
+    /// Stand-in of the Unix one, through the position of the file, which
+    /// reads and writes at an offset are therefore not to share
+    pub(crate) trait FileExt {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize>;
+        fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()>;
+        fn write_all_at(&self, buf: &[u8], offset: u64) -> std::io::Result<()>;
+    }
+
+    impl FileExt for File {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+            let mut file = self;
+            file.seek(SeekFrom::Start(offset))?;
+            file.read(buf)
+        }
+
+        fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+            let mut file = self;
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(buf)
+        }
+
+        fn write_all_at(&self, buf: &[u8], offset: u64) -> std::io::Result<()> {
+            let mut file = self;
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(buf)
+        }
+    }
+
+    /// Stand-in of the Unix one, leaving files created with the default
+    /// permissions
+    pub(crate) trait OpenOptionsExt {
+        fn mode(&mut self, mode: u32) -> &mut Self;
+    }
+
+    impl OpenOptionsExt for std::fs::OpenOptions {
+        fn mode(&mut self, _mode: u32) -> &mut Self {
+            self
+        }
+    }
+
+    /// Paths are stored as UTF-8 by Unix builds in practice
+    pub(crate) fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+        PathBuf::from(os_string_from_bytes(bytes))
+    }
+
+    pub(crate) fn os_string_from_bytes(bytes: &[u8]) -> OsString {
+        OsString::from(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    pub(crate) fn attributes(metadata: &Metadata) -> (u32, u32, u32, u64, u64) {
+        (metadata.mode(), 0, 0, 0, 0)
+    }
+
+    pub(crate) fn chown(_path: &Path, _uid: u32, _gid: u32) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    pub(crate) fn lchown(_path: &Path, _uid: u32, _gid: u32) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    pub(crate) fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+        let mut permissions = std::fs::metadata(path)?.permissions();
+        permissions.set_readonly(mode & 0o222 == 0);
+        std::fs::set_permissions(path, permissions)
+    }
+
+    pub(crate) fn current_user() -> anyhow::Result<(u32, u32, Option<String>)> {
+        Ok((0, 0, None))
+    }
+
+    pub(crate) fn list_xattrs(_path: &Path) -> Vec<OsString> {
+        vec![]
+    }
+
+    pub(crate) fn get_xattr(_path: &Path, _name: &OsStr) -> std::io::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    pub(crate) fn set_xattr(_path: &Path, _name: &OsStr, _value: &[u8]) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    pub(crate) fn remove_xattr(_path: &Path, _name: &OsStr) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    pub(crate) fn symlink(_target: &Path, _path: &Path) -> std::io::Result<()> {
+        Err(unsupported("symlinks"))
+    }
+
+    pub(crate) fn mknod(_path: &Path, _kind: SpecialKind, _rdev: u64) -> anyhow::Result<()> {
+        Err(unsupported("special files").into())
+    }
+
+    pub(crate) fn major(rdev: u64) -> u64 {
+        rdev >> 32
+    }
+
+    pub(crate) fn minor(rdev: u64) -> u64 {
+        rdev & 0xffff_ffff
+    }
+
+    pub(crate) fn makedev(major: u64, minor: u64) -> u64 {
+        major << 32 | minor
+    }
+
+    pub(crate) fn unmount(_path: &Path) -> std::io::Result<()> {
+        Err(unsupported("mounts"))
+    }
+
+    pub(crate) fn open_noatime(path: &Path) -> std::io::Result<File> {
+        File::open(path)
+    }
+
+    /// The path of a directory, checked not to be a symlink just before
+    /// being used rather than opened, which leaves a race
+    pub(crate) type Dir = PathBuf;
+
+    pub(crate) fn open_dir(path: &Path) -> std::io::Result<Dir> {
+        match std::fs::metadata(path)?.is_dir() {
+            true => Ok(path.to_owned()),
+            false => Err(std::io::Error::other("not a directory")),
+        }
+    }
+
+    pub(crate) fn open_dir_at(dir: &Dir, name: &OsStr) -> std::io::Result<Option<Dir>> {
+        let path = dir.join(name);
+        let metadata = std::fs::symlink_metadata(&path)?;
+        Ok(metadata.is_dir().then_some(path))
+    }
+
+    pub(crate) fn create_file_at(dir: &Dir, name: &OsStr) -> std::io::Result<Option<File>> {
+        let path = dir.join(name);
+        if std::fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            return Ok(None);
+        }
+        File::create(path).map(Some)
+    }
+
+    pub(crate) fn seek_data(_file: &File, _offset: u64) -> std::io::Result<Option<u64>> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    pub(crate) fn seek_hole(file: &File, _offset: u64) -> std::io::Result<u64> {
+        Ok(file.metadata()?.len())
+    }
+
+    pub(crate) fn map_file(_file: &File, _len: NonZeroUsize) -> std::io::Result<*mut c_void> {
+        Err(unsupported("memory maps"))
+    }
+
+    pub(crate) fn unmap_file(_ptr: *mut c_void, _len: usize) {}
+
+    pub(crate) fn set_niceness(_nice: i32) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    pub(crate) fn set_io_priority(_value: i32) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    pub(crate) fn reflink(_file: &File, _source: &File) -> bool {
+        false
+    }
+
+    pub(crate) fn copy_range(_source: &File, _source_offset: &mut u64, _file: &File, _offset: u64, _len: usize)
+        -> std::io::Result<usize>
+    {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    pub(crate) fn clone_fs(_path: &Path) -> std::io::Result<Option<CloneFs>> {
+        Ok(None)
+    }
+}
+
+/// Filesystems that trees can be cloned on in place
+#[derive(PartialEq, Eq)]
+pub(crate) enum CloneFs {
+    Btrfs,
+    Zfs,
+}
+
+pub(crate) use imp::*;
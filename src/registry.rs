@@ -0,0 +1,451 @@
+//! Minimal client of the OCI distribution API, to pull images from and push
+//! delta images to registries without Docker or a build daemon.
+//!
+//! Credentials are taken from the Docker client configuration, in
+//! `$DOCKER_CONFIG/config.json` or `~/.docker/config.json`, and exchanged for
+//! bearer tokens when the registry asks for them.
+//!
+//! Without the `registry` feature, image references are still parsed, but
+//! talking to a registry fails.
+
+#[cfg(feature = "registry")]
+use std::cell::RefCell;
+#[cfg(feature = "registry")]
+use std::fs::File;
+#[cfg(feature = "registry")]
+use std::io::{BufReader, BufWriter, Read, Write};
+#[cfg(feature = "registry")]
+use std::path::PathBuf;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::Context;
+
+use crate::Error;
+use crate::diff::{DiffOptions, DiffStats};
+use crate::image_config::ImageConfig;
+use crate::oci::{diff_oci, Descriptor, ImageLayout, Manifest, WorkDir};
+#[cfg(feature = "registry")]
+use crate::oci::{ImageIndex, MEDIA_TYPE_INDEX, MEDIA_TYPE_MANIFEST, MEDIA_TYPE_DOCKER_LIST,
+    MEDIA_TYPE_DOCKER_MANIFEST};
+use crate::utils::digest_bytes;
+#[cfg(feature = "registry")]
+use crate::utils::digest_file;
+
+const DOCKER_HUB: &str = "docker.io";
+#[cfg(feature = "registry")]
+const DOCKER_HUB_API: &str = "registry-1.docker.io";
+
+/// Options for registry access
+#[derive(Debug, Clone, Default)]
+pub struct RegistryOptions {
+    /// Talk plain HTTP instead of HTTPS, for local registries
+    pub insecure: bool,
+}
+
+/// A reference to an image in a registry, such as `ubuntu:mantic` or
+/// `registry.example.com:5000/team/app@sha256:...`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageReference {
+    pub registry: String,
+    pub repository: String,
+    /// Tag or digest
+    pub reference: String,
+}
+
+impl FromStr for ImageReference {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidImageReference(s.to_owned());
+
+        let (registry, rest) = match s.split_once('/') {
+            Some((first, rest)) if first.contains(['.', ':']) || first == "localhost" => {
+                (first.to_owned(), rest)
+            }
+            _ => (DOCKER_HUB.to_owned(), s),
+        };
+
+        let (name, reference) = if let Some((name, digest)) = rest.split_once('@') {
+            (name, digest.to_owned())
+        } else {
+            match rest.rsplit_once(':') {
+                Some((name, tag)) if !tag.contains('/') => (name, tag.to_owned()),
+                _ => (rest, "latest".to_owned()),
+            }
+        };
+
+        if name.is_empty() || reference.is_empty() {
+            return Err(invalid());
+        }
+
+        let repository = if registry == DOCKER_HUB && !name.contains('/') {
+            format!("library/{}", name)
+        } else {
+            name.to_owned()
+        };
+
+        Ok(Self { registry, repository, reference })
+    }
+}
+
+#[cfg(feature = "registry")]
+enum Body<'a> {
+    Empty,
+    Bytes(&'a [u8]),
+    File(&'a Path),
+}
+
+/// Client of one repository of a registry
+#[cfg(feature = "registry")]
+struct RegistryClient {
+    agent: ureq::Agent,
+    base_url: String,
+    repository: String,
+    credentials: Option<String>,
+    /// Authorization header value obtained after a challenge
+    authorization: RefCell<Option<String>>,
+}
+
+#[cfg(feature = "registry")]
+impl RegistryClient {
+    fn new(image: &ImageReference, options: &RegistryOptions) -> anyhow::Result<Self> {
+        let host = match image.registry.as_str() {
+            DOCKER_HUB => DOCKER_HUB_API,
+            other => other,
+        };
+        let scheme = if options.insecure { "http" } else { "https" };
+
+        Ok(Self {
+            agent: ureq::AgentBuilder::new().redirects(5).build(),
+            base_url: format!("{}://{}/v2/{}", scheme, host, image.repository),
+            repository: image.repository.clone(),
+            credentials: docker_credentials(&image.registry),
+            authorization: RefCell::new(None),
+        })
+    }
+
+    /// Send a request, authenticating and retrying once if challenged
+    fn send(&self, method: &str, url: &str, headers: &[(&str, &str)], body: &Body)
+        -> anyhow::Result<ureq::Response>
+    {
+        for attempt in 0..2 {
+            let mut request = self.agent.request(method, url);
+            for (name, value) in headers {
+                request = request.set(name, value);
+            }
+            if let Some(authorization) = self.authorization.borrow().as_ref() {
+                request = request.set("Authorization", authorization);
+            }
+
+            let result = match body {
+                Body::Empty => request.call(),
+                Body::Bytes(data) => request.send_bytes(data),
+                Body::File(path) => {
+                    let file = File::open(path)
+                        .with_context(|| format!("failed to open {}", path.display()))?;
+                    request.set("Content-Length", &file.metadata()?.len().to_string())
+                        .send(BufReader::new(file))
+                }
+            };
+
+            match result {
+                Err(ureq::Error::Status(401, response)) if attempt == 0 => {
+                    let challenge = response.header("www-authenticate").unwrap_or_default().to_owned();
+                    self.authenticate(&challenge)?;
+                }
+                Err(err) => {
+                    return Err(Error::RegistryError(format!("{} {}: {}", method, url, err)).into());
+                }
+                Ok(response) => return Ok(response),
+            }
+        }
+
+        Err(Error::RegistryError(format!("{} {}: authentication failed", method, url)).into())
+    }
+
+    /// Answer a `WWW-Authenticate` challenge
+    fn authenticate(&self, challenge: &str) -> anyhow::Result<()> {
+        let Some(params) = challenge.strip_prefix("Bearer ") else {
+            // Basic authentication, straight with the configured credentials
+            let Some(credentials) = &self.credentials else {
+                return Err(Error::RegistryError(format!("no credentials for {}", self.base_url)).into());
+            };
+            *self.authorization.borrow_mut() = Some(format!("Basic {}", credentials));
+            return Ok(());
+        };
+
+        let param = |key: &str| params.split(',')
+            .filter_map(|x| x.trim().split_once('='))
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.trim_matches('"').to_owned());
+
+        let realm = param("realm")
+            .ok_or_else(|| Error::RegistryError(format!("malformed challenge: {}", challenge)))?;
+        let scope = param("scope")
+            .unwrap_or_else(|| format!("repository:{}:pull,push", self.repository));
+        let mut url = format!("{}?scope={}", realm, url_encode(&scope));
+        if let Some(service) = param("service") {
+            url.push_str(&format!("&service={}", url_encode(&service)));
+        }
+
+        let mut request = self.agent.get(&url);
+        if let Some(credentials) = &self.credentials {
+            request = request.set("Authorization", &format!("Basic {}", credentials));
+        }
+        let response = request.call()
+            .map_err(|err| Error::RegistryError(format!("token request to {}: {}", realm, err)))?;
+        let token: serde_json::Value = serde_json::from_str(&response.into_string()?)
+            .context("failed to parse token response")?;
+        let Some(token) = token.get("token").or_else(|| token.get("access_token")).and_then(|x| x.as_str()) else {
+            return Err(Error::RegistryError(format!("no token from {}", realm)).into());
+        };
+
+        *self.authorization.borrow_mut() = Some(format!("Bearer {}", token));
+        Ok(())
+    }
+
+    /// Fetch a manifest or index, returning its media type and content
+    fn get_manifest(&self, reference: &str) -> anyhow::Result<(String, Vec<u8>)> {
+        let accept = [MEDIA_TYPE_MANIFEST, MEDIA_TYPE_INDEX, MEDIA_TYPE_DOCKER_MANIFEST,
+            MEDIA_TYPE_DOCKER_LIST].join(", ");
+        let url = format!("{}/manifests/{}", self.base_url, reference);
+        let response = self.send("GET", &url, &[("Accept", &accept)], &Body::Empty)?;
+
+        let media_type = response.content_type().to_owned();
+        let mut data = vec![];
+        response.into_reader().read_to_end(&mut data)?;
+        Ok((media_type, data))
+    }
+
+    /// Fetch the manifest of an image, following indexes to the first image
+    /// they list
+    fn get_image_manifest(&self, reference: &str) -> anyhow::Result<(String, Vec<u8>)> {
+        let (mut media_type, mut data) = self.get_manifest(reference)?;
+        while media_type == MEDIA_TYPE_INDEX || media_type == MEDIA_TYPE_DOCKER_LIST {
+            let index: ImageIndex = serde_json::from_slice(&data).context("failed to parse image index")?;
+            let Some(descriptor) = index.manifests.into_iter().next() else {
+                return Err(Error::InvalidOciImage("index lists no manifests".to_owned()).into());
+            };
+            (media_type, data) = self.get_manifest(&descriptor.digest)?;
+        }
+        Ok((media_type, data))
+    }
+
+    fn put_manifest(&self, reference: &str, media_type: &str, data: &[u8]) -> anyhow::Result<()> {
+        let url = format!("{}/manifests/{}", self.base_url, reference);
+        self.send("PUT", &url, &[("Content-Type", media_type)], &Body::Bytes(data))?;
+        Ok(())
+    }
+
+    /// Download a blob to `path`, checking its digest
+    fn download_blob(&self, descriptor: &Descriptor, path: &Path) -> anyhow::Result<()> {
+        tracing::info!("Pulling {}: {}", descriptor.digest, descriptor.size);
+
+        let url = format!("{}/blobs/{}", self.base_url, descriptor.digest);
+        let response = self.send("GET", &url, &[], &Body::Empty)?;
+        let mut out = BufWriter::new(File::create(path)
+            .with_context(|| format!("failed to create {}", path.display()))?);
+        std::io::copy(&mut response.into_reader(), &mut out)
+            .with_context(|| format!("failed to download {}", descriptor.digest))?;
+        out.flush()?;
+
+        if format!("sha256:{}", digest_file(path)?) != descriptor.digest {
+            return Err(Error::RegistryError(format!("digest mismatch for blob {}",
+                descriptor.digest)).into());
+        }
+        Ok(())
+    }
+
+    /// Fetch a small blob into memory, checking its digest
+    fn get_blob(&self, descriptor: &Descriptor) -> anyhow::Result<Vec<u8>> {
+        let url = format!("{}/blobs/{}", self.base_url, descriptor.digest);
+        let response = self.send("GET", &url, &[], &Body::Empty)?;
+        let mut data = vec![];
+        response.into_reader().read_to_end(&mut data)
+            .with_context(|| format!("failed to download {}", descriptor.digest))?;
+
+        if format!("sha256:{}", digest_bytes(&data)) != descriptor.digest {
+            return Err(Error::RegistryError(format!("digest mismatch for blob {}",
+                descriptor.digest)).into());
+        }
+        Ok(data)
+    }
+
+    /// Upload a blob, unless the registry already has it
+    fn upload_blob(&self, descriptor: &Descriptor, path: &Path) -> anyhow::Result<()> {
+        let url = format!("{}/blobs/{}", self.base_url, descriptor.digest);
+        if self.send("HEAD", &url, &[], &Body::Empty).is_ok() {
+            return Ok(());
+        }
+
+        tracing::info!("Pushing {}: {}", descriptor.digest, descriptor.size);
+
+        let url = format!("{}/blobs/uploads/", self.base_url);
+        let response = self.send("POST", &url, &[], &Body::Empty)?;
+        let Some(location) = response.header("location") else {
+            return Err(Error::RegistryError(format!("no upload location from {}", url)).into());
+        };
+
+        let location = absolute_url(&self.base_url, location);
+        let separator = if location.contains('?') { '&' } else { '?' };
+        let url = format!("{}{}digest={}", location, separator, url_encode(&descriptor.digest));
+        self.send("PUT", &url, &[("Content-Type", "application/octet-stream")], &Body::File(path))?;
+        Ok(())
+    }
+}
+
+/// No client can be had without the `registry` feature
+#[cfg(not(feature = "registry"))]
+enum RegistryClient {}
+
+#[cfg(not(feature = "registry"))]
+impl RegistryClient {
+    fn new(_image: &ImageReference, _options: &RegistryOptions) -> anyhow::Result<Self> {
+        Err(Error::FeatureDisabled("registry").into())
+    }
+
+    fn get_manifest(&self, _reference: &str) -> anyhow::Result<(String, Vec<u8>)> {
+        match *self {}
+    }
+
+    fn get_image_manifest(&self, _reference: &str) -> anyhow::Result<(String, Vec<u8>)> {
+        match *self {}
+    }
+
+    fn put_manifest(&self, _reference: &str, _media_type: &str, _data: &[u8]) -> anyhow::Result<()> {
+        match *self {}
+    }
+
+    fn download_blob(&self, _descriptor: &Descriptor, _path: &Path) -> anyhow::Result<()> {
+        match *self {}
+    }
+
+    fn get_blob(&self, _descriptor: &Descriptor) -> anyhow::Result<Vec<u8>> {
+        match *self {}
+    }
+
+    fn upload_blob(&self, _descriptor: &Descriptor, _path: &Path) -> anyhow::Result<()> {
+        match *self {}
+    }
+}
+
+/// Base64 credentials of a registry from the Docker client configuration
+#[cfg(feature = "registry")]
+fn docker_credentials(registry: &str) -> Option<String> {
+    let config_dir = match std::env::var_os("DOCKER_CONFIG") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".docker"),
+    };
+    let config: serde_json::Value = serde_json::from_reader(File::open(config_dir.join("config.json")).ok()?).ok()?;
+    let auths = config.get("auths")?.as_object()?;
+
+    let keys: &[&str] = match registry {
+        DOCKER_HUB => &["https://index.docker.io/v1/", "docker.io", DOCKER_HUB_API],
+        other => &[other],
+    };
+    keys.iter().find_map(|key| auths.get(*key))
+        .and_then(|auth| auth.get("auth")?.as_str().map(|x| x.to_owned()))
+}
+
+/// Resolve an upload location, which may be relative to the registry host
+#[cfg(feature = "registry")]
+fn absolute_url(base_url: &str, location: &str) -> String {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        return location.to_owned();
+    }
+    let origin_len = base_url.find("://").map(|i| i + 3).unwrap_or(0);
+    let host_end = base_url[origin_len..].find('/').map(|i| origin_len + i).unwrap_or(base_url.len());
+    format!("{}{}", &base_url[..host_end], location)
+}
+
+#[cfg(feature = "registry")]
+fn url_encode(s: &str) -> String {
+    s.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+/// Pull an image from a registry into a new OCI image layout directory. For
+/// multi-image indexes, the first image listed is pulled.
+pub fn pull_image(image: &str, layout_dir: &Path, options: &RegistryOptions) -> anyhow::Result<()> {
+    let image: ImageReference = image.parse()?;
+    let client = RegistryClient::new(&image, options)?;
+
+    let (media_type, data) = client.get_image_manifest(&image.reference)?;
+
+    let manifest: Manifest = serde_json::from_slice(&data).context("failed to parse image manifest")?;
+    let layout = ImageLayout::create(layout_dir)?;
+    for descriptor in std::iter::once(&manifest.config).chain(manifest.layers.iter()) {
+        client.download_blob(descriptor, &layout.blob_path(&descriptor.digest)?)?;
+    }
+
+    let descriptor = layout.write_blob(&data, &media_type)?;
+    layout.write_index(descriptor)
+}
+
+/// Resolve an image to the digest of its manifest, or of its index for
+/// multi-platform images, such as `sha256:...`
+pub fn resolve_digest(image: &str, options: &RegistryOptions) -> anyhow::Result<String> {
+    let image: ImageReference = image.parse()?;
+    let client = RegistryClient::new(&image, options)?;
+    let (_, data) = client.get_manifest(&image.reference)?;
+    Ok(format!("sha256:{}", digest_bytes(&data)))
+}
+
+/// Fetch the runtime configuration of an image, without its layers. For
+/// multi-image indexes, the one of the first image listed is fetched.
+pub fn pull_config(image: &str, options: &RegistryOptions) -> anyhow::Result<ImageConfig> {
+    let image: ImageReference = image.parse()?;
+    let client = RegistryClient::new(&image, options)?;
+
+    let (_, data) = client.get_image_manifest(&image.reference)?;
+    let manifest: Manifest = serde_json::from_slice(&data).context("failed to parse image manifest")?;
+    let config = serde_json::from_slice(&client.get_blob(&manifest.config)?)
+        .context("failed to parse image configuration")?;
+    ImageConfig::from_json(&config)
+}
+
+/// Push the image of an OCI image layout directory to a registry
+pub fn push_image(layout_dir: &Path, image: &str, options: &RegistryOptions) -> anyhow::Result<()> {
+    let image: ImageReference = image.parse()?;
+    let client = RegistryClient::new(&image, options)?;
+    let layout = ImageLayout::open_dir(layout_dir)?;
+
+    let descriptor = layout.manifest_descriptor()?;
+    let data = std::fs::read(layout.blob_path(&descriptor.digest)?)?;
+    if format!("sha256:{}", digest_bytes(&data)) != descriptor.digest {
+        return Err(Error::InvalidOciImage(format!("corrupted manifest {}", descriptor.digest)).into());
+    }
+
+    let manifest: Manifest = serde_json::from_slice(&data).context("failed to parse image manifest")?;
+    for blob in manifest.layers.iter().chain(std::iter::once(&manifest.config)) {
+        client.upload_blob(blob, &layout.blob_path(&blob.digest)?)?;
+    }
+
+    client.put_manifest(&image.reference, &descriptor.media_type, &data)
+}
+
+/// Pull two images from registries, compute the delta image between them as
+/// [`diff_oci`] does, and push it to `delta_image`.
+///
+/// The images are stored under `work_dir`, which must not exist and is
+/// removed once done.
+pub fn diff_registry(source_image: &str, target_image: &str, delta_image: &str, work_dir: &Path,
+    registry_options: &RegistryOptions, options: DiffOptions) -> anyhow::Result<DiffStats>
+{
+    let work_dir = WorkDir::create(work_dir)?;
+    let source_layout = work_dir.join("source-image");
+    let target_layout = work_dir.join("target-image");
+    let delta_layout = work_dir.join("delta-image");
+
+    pull_image(source_image, &source_layout, registry_options)?;
+    pull_image(target_image, &target_layout, registry_options)?;
+    let stats = diff_oci(&source_layout, &target_layout, &delta_layout, &work_dir.join("diff"),
+        options)?;
+    push_image(&delta_layout, delta_image, registry_options)?;
+
+    work_dir.remove()?;
+    Ok(stats)
+}
@@ -0,0 +1,126 @@
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::Algo;
+use crate::utils::serialize_to_json;
+
+/// How a single file was handled by diff or apply
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileReport {
+    pub path: String,
+    /// Name of the algorithm, or `keep` for unmodified files
+    pub algo: String,
+    pub original_size: u64,
+    pub delta_size: u64,
+    pub ratio: f64,
+    pub duration_secs: f64,
+}
+
+impl FileReport {
+    pub fn new(path: &Path, algo: Option<&Algo>, original_size: u64, delta_size: u64,
+        duration: Duration) -> Self
+    {
+        Self {
+            path: path.to_string_lossy().into_owned(),
+            algo: algo.map(Algo::name).unwrap_or("keep").to_owned(),
+            original_size,
+            delta_size,
+            ratio: ratio(delta_size, original_size),
+            duration_secs: duration.as_secs_f64(),
+        }
+    }
+}
+
+/// A file that diff could not read, and what was done with it instead
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FailedFile {
+    pub path: String,
+    pub error: String,
+    /// `skip` or `as-is`
+    pub action: String,
+}
+
+impl FailedFile {
+    pub fn new(path: &Path, error: String, action: &str) -> Self {
+        Self { path: path.to_string_lossy().into_owned(), error, action: action.to_owned() }
+    }
+}
+
+/// An attribute of a restored file that apply could not set
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UnappliedMetaData {
+    pub path: String,
+    /// Such as `owner`, `permissions` or `xattr security.capability`
+    pub attribute: String,
+    pub error: String,
+}
+
+impl UnappliedMetaData {
+    pub fn new(path: &Path, attribute: String, error: String) -> Self {
+        Self { path: path.to_string_lossy().into_owned(), attribute, error }
+    }
+}
+
+/// Machine-readable summary of a diff or apply run
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Report {
+    pub files: Vec<FileReport>,
+    pub total_original_size: u64,
+    pub total_delta_size: u64,
+    pub ratio: f64,
+    pub duration_secs: f64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed_files: Vec<FailedFile>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unapplied_metadata: Vec<UnappliedMetaData>,
+}
+
+impl Report {
+    pub fn new(files: Vec<FileReport>, duration: Duration) -> Self {
+        let total_original_size = files.iter().map(|x| x.original_size).sum();
+        let total_delta_size = files.iter().map(|x| x.delta_size).sum();
+        Self {
+            files,
+            total_original_size,
+            total_delta_size,
+            ratio: ratio(total_delta_size, total_original_size),
+            duration_secs: duration.as_secs_f64(),
+            failed_files: vec![],
+            unapplied_metadata: vec![],
+        }
+    }
+
+    /// Also list the files that diff could not read
+    pub fn failed_files(mut self, failed_files: Vec<FailedFile>) -> Self {
+        self.failed_files = failed_files;
+        self
+    }
+
+    /// Also list the attributes that apply could not set
+    pub fn unapplied_metadata(mut self, unapplied_metadata: Vec<UnappliedMetaData>) -> Self {
+        self.unapplied_metadata = unapplied_metadata;
+        self
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        serialize_to_json(self, path)
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read(path)
+            .with_context(|| format!("failed to read report {}", path.display()))?;
+        serde_json::from_slice(&data)
+            .with_context(|| format!("failed to parse report {}", path.display()))
+    }
+}
+
+fn ratio(delta_size: u64, original_size: u64) -> f64 {
+    if original_size == 0 {
+        0.0
+    } else {
+        delta_size as f64 / original_size as f64
+    }
+}
@@ -0,0 +1,204 @@
+//! Package-level summary of a delta, from the SBOMs of the source and target
+//! images, for release notes: which packages were added, removed or changed
+//! versions, and how much of the delta their files take.
+//!
+//! SPDX and CycloneDX JSON documents are read, as well as the JSON output of
+//! syft. The files of a package are known from the relationships of SPDX
+//! documents, the file lists of syft, and the evidence of CycloneDX ones.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::Error;
+use crate::stats::DeltaStats;
+
+/// The packages of an image, by name
+#[derive(Debug, Clone, Default)]
+pub struct Sbom {
+    packages: BTreeMap<String, Package>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Package {
+    /// Several for packages installed for several architectures or ABIs
+    versions: BTreeSet<String>,
+    files: Vec<PathBuf>,
+}
+
+/// How a package differs between the images
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum PackageChangeKind {
+    Added,
+    Removed,
+    /// Upgraded or downgraded
+    Changed,
+}
+
+impl PackageChangeKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            PackageChangeKind::Added => "added",
+            PackageChangeKind::Removed => "removed",
+            PackageChangeKind::Changed => "changed",
+        }
+    }
+}
+
+/// A package added, removed or with another version in the target image
+#[derive(Serialize, Debug, Clone)]
+pub struct PackageChange {
+    pub name: String,
+    pub change: PackageChangeKind,
+    pub old_version: Option<String>,
+    pub new_version: Option<String>,
+    /// Files of the package stored in the delta
+    pub files: usize,
+    /// Size of these files in the delta
+    pub delta_size: u64,
+}
+
+/// Package changes of a delta
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct PackageSummary {
+    /// Largest delta size first
+    pub packages: Vec<PackageChange>,
+    /// Size of the stored files of the delta that belong to no package
+    pub unpackaged_delta_size: u64,
+}
+
+/// Path relative to the root of the image
+fn image_path(path: &str) -> PathBuf {
+    PathBuf::from(path.trim_start_matches("./").trim_start_matches('/'))
+}
+
+fn str_at<'a>(value: &'a Value, key: &str) -> Option<&'a str> {
+    value.get(key).and_then(Value::as_str)
+}
+
+fn array_at<'a>(value: &'a Value, key: &str) -> &'a [Value] {
+    value.get(key).and_then(Value::as_array).map(Vec::as_slice).unwrap_or_default()
+}
+
+impl Sbom {
+    /// Load an SPDX or CycloneDX JSON document, or the JSON output of syft
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        let doc: Value = serde_json::from_slice(&data)
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+        let mut sbom = Sbom::default();
+        if doc.get("spdxVersion").is_some() {
+            sbom.add_spdx(&doc);
+        } else if str_at(&doc, "bomFormat") == Some("CycloneDX") {
+            for component in array_at(&doc, "components") {
+                sbom.add_cyclonedx(component);
+            }
+        } else if doc.get("artifacts").is_some() {
+            sbom.add_syft(&doc);
+        } else {
+            return Err(Error::UnknownSbomFormat(path.to_owned()).into());
+        }
+        Ok(sbom)
+    }
+
+    fn add(&mut self, name: &str, version: Option<&str>, files: impl IntoIterator<Item = PathBuf>) {
+        let package = self.packages.entry(name.to_owned()).or_default();
+        package.versions.extend(version.map(str::to_owned));
+        package.files.extend(files);
+    }
+
+    fn add_spdx(&mut self, doc: &Value) {
+        let file_names: HashMap<_, _> = array_at(doc, "files").iter()
+            .filter_map(|file| Some((str_at(file, "SPDXID")?, image_path(str_at(file, "fileName")?))))
+            .collect();
+        let mut package_files: HashMap<&str, Vec<&str>> = HashMap::new();
+        for relationship in array_at(doc, "relationships") {
+            let (Some(element), Some(related)) = (str_at(relationship, "spdxElementId"),
+                str_at(relationship, "relatedSpdxElement")) else { continue };
+            match str_at(relationship, "relationshipType") {
+                Some("CONTAINS") => package_files.entry(element).or_default().push(related),
+                Some("CONTAINED_BY") => package_files.entry(related).or_default().push(element),
+                _ => {}
+            }
+        }
+        for package in array_at(doc, "packages") {
+            let (Some(id), Some(name)) = (str_at(package, "SPDXID"), str_at(package, "name")) else { continue };
+            let files = array_at(package, "hasFiles").iter().filter_map(Value::as_str)
+                .chain(package_files.get(id).into_iter().flatten().copied())
+                .filter_map(|file_id| file_names.get(file_id).cloned());
+            self.add(name, str_at(package, "versionInfo"), files);
+        }
+    }
+
+    fn add_cyclonedx(&mut self, component: &Value) {
+        if let Some(name) = str_at(component, "name").filter(|_| str_at(component, "type") != Some("file")) {
+            let occurrences = component.get("evidence").map(|evidence| array_at(evidence, "occurrences"))
+                .unwrap_or_default();
+            let files = occurrences.iter().filter_map(|occurrence| str_at(occurrence, "location"))
+                .map(image_path);
+            self.add(name, str_at(component, "version"), files);
+        }
+        for nested in array_at(component, "components") {
+            self.add_cyclonedx(nested);
+        }
+    }
+
+    fn add_syft(&mut self, doc: &Value) {
+        for artifact in array_at(doc, "artifacts") {
+            let Some(name) = str_at(artifact, "name") else { continue };
+            let files = artifact.get("metadata").map(|metadata| array_at(metadata, "files"))
+                .unwrap_or_default();
+            let files = files.iter().filter_map(|file| str_at(file, "path")).map(image_path);
+            self.add(name, str_at(artifact, "version"), files);
+        }
+    }
+
+    fn version(&self, name: &str) -> Option<String> {
+        let versions = &self.packages.get(name)?.versions;
+        (!versions.is_empty()).then(|| versions.iter().cloned().collect::<Vec<_>>().join(", "))
+    }
+}
+
+impl PackageSummary {
+    /// The packages that differ between the SBOMs of the source and target
+    /// images, with the stored files of the delta attributed to the packages
+    /// of the target image that own them
+    pub fn new(source: &Sbom, target: &Sbom, stats: &DeltaStats) -> Self {
+        let owners: HashMap<&Path, &str> = target.packages.iter()
+            .flat_map(|(name, package)| package.files.iter().map(move |path| (path.as_path(), name.as_str())))
+            .collect();
+        let mut sizes: HashMap<&str, (usize, u64)> = HashMap::new();
+        let mut unpackaged_delta_size = 0;
+        for file in stats.files.iter() {
+            match owners.get(file.path.as_path()) {
+                Some(name) => {
+                    let (files, delta_size) = sizes.entry(name).or_default();
+                    *files += 1;
+                    *delta_size += file.delta_size;
+                }
+                None => unpackaged_delta_size += file.delta_size,
+            }
+        }
+
+        let names: BTreeSet<_> = source.packages.keys().chain(target.packages.keys()).collect();
+        let mut packages: Vec<_> = names.into_iter()
+            .filter_map(|name| {
+                let (old_version, new_version) = (source.version(name), target.version(name));
+                let change = match (source.packages.contains_key(name), target.packages.contains_key(name)) {
+                    (false, _) => PackageChangeKind::Added,
+                    (_, false) => PackageChangeKind::Removed,
+                    _ if old_version != new_version => PackageChangeKind::Changed,
+                    _ => return None,
+                };
+                let (files, delta_size) = sizes.get(name.as_str()).copied().unwrap_or_default();
+                Some(PackageChange { name: name.clone(), change, old_version, new_version, files, delta_size })
+            })
+            .collect();
+        packages.sort_by(|a, b| b.delta_size.cmp(&a.delta_size).then_with(|| a.name.cmp(&b.name)));
+        Self { packages, unpackaged_delta_size }
+    }
+}
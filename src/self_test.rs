@@ -0,0 +1,144 @@
+//! Checks of what the environment lets deltaimage do, as the binary is copied
+//! into arbitrary base images and run there, possibly rootless or on
+//! filesystems without xattrs.
+//!
+//! Each capability is tried for real in a scratch directory, which should be
+//! on the filesystem that deltas are to be applied on.
+
+use std::ffi::OsStr;
+use std::path::Path;
+
+use anyhow::Context;
+use filetime::FileTime;
+
+use crate::Error;
+use crate::backend::BACKENDS;
+use crate::diff::DiffOptions;
+use crate::metadata::SpecialKind;
+use crate::oci::WorkDir;
+use crate::platform::{self, MetadataExt};
+use crate::utils::make_special;
+
+const XATTR_NAME: &str = "user.deltaimage.self-test";
+
+/// Outcome of trying one capability
+#[derive(Debug, Clone)]
+pub struct Capability {
+    pub name: String,
+    /// Why it does not work, if it does not
+    pub problem: Option<String>,
+    /// Whether apply cannot work at all without it, rather than restoring
+    /// less of the meta-data
+    pub required: bool,
+}
+
+/// Outcome of a self-test
+#[derive(Debug, Clone)]
+pub struct SelfTestReport {
+    /// Version and kind of build, such as `0.1.0 x86_64 musl static`
+    pub build: String,
+    pub capabilities: Vec<Capability>,
+}
+
+impl SelfTestReport {
+    /// Turn a report with required capabilities missing into an error
+    pub fn into_result(self) -> Result<Self, Error> {
+        match self.capabilities.iter().filter(|x| x.required && x.problem.is_some()).count() {
+            0 => Ok(self),
+            count => Err(Error::SelfTestFailed(count)),
+        }
+    }
+}
+
+/// Version and kind of this build, as the static musl build and the glibc
+/// one differ in what they can resolve, such as user names
+pub fn build_description() -> String {
+    let libc = if cfg!(target_env = "musl") {
+        "musl"
+    } else if cfg!(target_env = "gnu") {
+        "glibc"
+    } else {
+        "other-libc"
+    };
+    let linkage = if cfg!(target_feature = "crt-static") { "static" } else { "dynamic" };
+    format!("{} {} {} {}", env!("CARGO_PKG_VERSION"), std::env::consts::ARCH, libc, linkage)
+}
+
+/// Try each capability that diff and apply rely on in `work_dir`, which must
+/// not exist and is removed once done
+pub fn self_test(work_dir: &Path) -> anyhow::Result<SelfTestReport> {
+    let work_dir = WorkDir::create(work_dir)?;
+    let mut capabilities = vec![];
+    let mut check = |name: String, required: bool, result: anyhow::Result<()>| {
+        let problem = result.err().map(|err| format!("{:#}", err));
+        capabilities.push(Capability { name, problem, required });
+    };
+
+    let source: Vec<u8> = (0..64 << 10).map(|i: u32| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+    let mut target = source.clone();
+    target[1000..1100].fill(0x5a);
+    target.extend_from_slice(b"appended");
+    let options = DiffOptions::default();
+    for backend in BACKENDS {
+        let result = backend.encode(&source, &target, &options)
+            .context("encoding failed")
+            .and_then(|stored| backend.decode(&source, &stored).context("decoding failed"))
+            .and_then(|decoded| match decoded == target {
+                true => Ok(()),
+                false => Err(anyhow::anyhow!("decoded content differs")),
+            });
+        check(format!("{} encoding", backend.algo().name()), true, result);
+    }
+
+    let file = work_dir.join("file");
+    std::fs::write(&file, &target)
+        .with_context(|| format!("failed to create {}", file.display()))?;
+    check("file times".to_owned(), true, set_times(&file, |path, time| {
+        filetime::set_file_times(path, time, time)
+    }));
+
+    let symlink = work_dir.join("symlink");
+    let result = platform::symlink(Path::new("file"), &symlink).map_err(anyhow::Error::from)
+        .and_then(|()| set_times(&symlink, |path, time| filetime::set_symlink_file_times(path, time, time)));
+    check("symlink times".to_owned(), false, result);
+
+    check("hardlinks".to_owned(), false, std::fs::hard_link(&file, work_dir.join("hardlink"))
+        .context("failed to link"));
+
+    check("user xattrs".to_owned(), false, check_xattr(&file));
+
+    // Another owner than the current one, as anyone can chown to themselves
+    let result = platform::chown(&file, 1, 1).context("failed to chown to another user")
+        .and_then(|()| match std::fs::metadata(&file)?.uid() {
+            1 => Ok(()),
+            _ => Err(anyhow::anyhow!("owner read back differs")),
+        });
+    check("ownership".to_owned(), false, result);
+
+    let result = make_special(&work_dir.join("fifo"), SpecialKind::Fifo, 0);
+    check("FIFOs".to_owned(), false, result);
+    let result = make_special(&work_dir.join("null"), SpecialKind::CharDevice, platform::makedev(1, 3));
+    check("device nodes".to_owned(), false, result);
+
+    work_dir.remove()?;
+    Ok(SelfTestReport { build: build_description(), capabilities })
+}
+
+/// Set a time with nanoseconds to `path` and read it back
+fn set_times(path: &Path, set: impl Fn(&Path, FileTime) -> std::io::Result<()>) -> anyhow::Result<()> {
+    let time = FileTime::from_unix_time(1_000_000_000, 123_456_789);
+    set(path, time).context("failed to set times")?;
+    let metadata = std::fs::symlink_metadata(path)?;
+    match FileTime::from_last_modification_time(&metadata) == time {
+        true => Ok(()),
+        false => Err(anyhow::anyhow!("times read back differ")),
+    }
+}
+
+fn check_xattr(path: &Path) -> anyhow::Result<()> {
+    platform::set_xattr(path, OsStr::new(XATTR_NAME), b"value").context("failed to set")?;
+    match platform::get_xattr(path, OsStr::new(XATTR_NAME)).context("failed to get")? {
+        Some(value) if value == b"value" => Ok(()),
+        _ => Err(anyhow::anyhow!("value read back differs")),
+    }
+}
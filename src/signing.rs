@@ -0,0 +1,93 @@
+//! Ed25519 signatures of delta meta-data, so that deltas fetched from
+//! untrusted mirrors can be authenticated before anything is patched.
+//!
+//! The signature covers the whole meta-data except the progress of an apply,
+//! which is journaled in the same file, and the signature itself. Since the
+//! meta-data lists every restored file with its digest, this also covers the
+//! content of the delta tree. Keys are stored hex-encoded in text files.
+
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Context;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+use crate::Error;
+use crate::metadata::MetaData;
+use crate::platform::OpenOptionsExt;
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let hex = hex.trim();
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Read a key of `N` bytes, hex-encoded in the file at `path`
+pub(crate) fn read_key<const N: usize>(path: &Path) -> anyhow::Result<[u8; N]> {
+    let hex = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    from_hex(&hex).ok_or_else(|| Error::InvalidKey(path.to_owned()).into())
+}
+
+pub(crate) fn random_bytes<const N: usize>() -> anyhow::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut file| std::io::Read::read_exact(&mut file, &mut bytes))
+        .context("failed to read random bytes")?;
+    Ok(bytes)
+}
+
+/// Write a secret key to a new file, readable only by the current user
+pub(crate) fn write_secret_key(path: &Path, key: &[u8]) -> anyhow::Result<()> {
+    let mut file = std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600)
+        .open(path)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    writeln!(file, "{}", to_hex(key))?;
+    Ok(())
+}
+
+/// Generate a new key pair, writing the secret key readable only by the
+/// current user
+pub fn generate_key(secret_key_path: &Path, public_key_path: &Path) -> anyhow::Result<()> {
+    let key = SigningKey::from_bytes(&random_bytes()?);
+
+    write_secret_key(secret_key_path, &key.to_bytes())?;
+    std::fs::write(public_key_path, format!("{}\n", to_hex(&key.verifying_key().to_bytes())))
+        .with_context(|| format!("failed to write to {}", public_key_path.display()))?;
+
+    Ok(())
+}
+
+/// Sign the meta-data with the secret key stored at `key_path`
+pub(crate) fn sign(md: &mut MetaData, key_path: &Path) -> anyhow::Result<()> {
+    let key = SigningKey::from_bytes(&read_key(key_path)?);
+    let signature = key.sign(&md.signed_content()?);
+    md.signature = Some(to_hex(&signature.to_bytes()));
+    Ok(())
+}
+
+/// Check the signature of the meta-data of `delta_dir` against the public
+/// key stored at `key_path`
+pub(crate) fn verify(md: &MetaData, delta_dir: &Path, key_path: &Path) -> anyhow::Result<()> {
+    let key = VerifyingKey::from_bytes(&read_key(key_path)?)
+        .map_err(|_| Error::InvalidKey(key_path.to_owned()))?;
+    let Some(signature) = &md.signature else {
+        return Err(Error::MissingSignature(delta_dir.to_owned()).into());
+    };
+    let signature = from_hex(signature)
+        .ok_or_else(|| Error::BadSignature(delta_dir.to_owned()))?;
+
+    key.verify(&md.signed_content()?, &Signature::from_bytes(&signature))
+        .map_err(|_| Error::BadSignature(delta_dir.to_owned()))?;
+    Ok(())
+}
@@ -0,0 +1,85 @@
+//! Content similarity of files, to pair a new target file with the source
+//! file it most likely evolved from, e.g. `libfoo.so.1.2` and `libfoo.so.1.3`.
+//!
+//! Each file is summarized by a bottom-k sketch: the `SKETCH_SIZE` smallest
+//! values of a rolling hash taken at every byte offset. The fraction of
+//! shared values among the smallest of the union of two sketches estimates
+//! the resemblance of the two files, regardless of insertions or shifts.
+
+use std::collections::BTreeSet;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use crate::utils::open_noatime;
+
+/// Number of hash values kept per file
+const SKETCH_SIZE: usize = 128;
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64, so the table is fixed across builds
+    let mut table = [0u64; 256];
+    let mut state = 0x9e3779b97f4a7c15u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+pub(crate) static GEAR: [u64; 256] = gear_table();
+
+/// Bottom-k sketch of the content of a file
+#[derive(Debug, Clone, Default)]
+pub struct Sketch {
+    hashes: BTreeSet<u64>,
+}
+
+impl Sketch {
+    /// Sketch the content of the file at `path`.
+    pub fn of_file(path: &Path) -> std::io::Result<Self> {
+        let mut sketch = Sketch::default();
+        let mut reader = BufReader::new(open_noatime(path)?);
+        let mut buf = [0u8; 64 << 10];
+        let mut hash = 0u64;
+
+        loop {
+            let nread = reader.read(&mut buf)?;
+            if nread == 0 {
+                break;
+            }
+            for byte in &buf[..nread] {
+                // Gear hash, in effect covering the last 64 bytes
+                hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+                sketch.insert(hash);
+            }
+        }
+
+        Ok(sketch)
+    }
+
+    fn insert(&mut self, hash: u64) {
+        if self.hashes.len() < SKETCH_SIZE {
+            self.hashes.insert(hash);
+        } else if hash < *self.hashes.last().unwrap() && self.hashes.insert(hash) {
+            self.hashes.pop_last();
+        }
+    }
+
+    /// Estimated resemblance of the two sketched files, from 0 to 1
+    pub fn similarity(&self, other: &Sketch) -> f64 {
+        let union: Vec<_> = self.hashes.union(&other.hashes).take(SKETCH_SIZE).collect();
+        if union.is_empty() {
+            return 0.0;
+        }
+
+        let shared = union.iter()
+            .filter(|hash| self.hashes.contains(hash) && other.hashes.contains(hash))
+            .count();
+        shared as f64 / union.len() as f64
+    }
+}
@@ -0,0 +1,109 @@
+//! Apply on a writable clone of the source tree, a btrfs subvolume snapshot
+//! or a ZFS clone, so that the unchanged files share their storage with the
+//! source tree and a failed apply is rolled back by dropping the clone.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+
+use crate::Error;
+use crate::apply::{ApplyOptions, ApplyStats, DeltaApplier};
+use crate::overlay::merge_upper_dir;
+use crate::platform::{clone_fs, CloneFs, MetadataExt};
+use crate::utils::temp_path_for;
+
+/// Root inode of btrfs subvolumes
+const BTRFS_SUBVOLUME_INO: u64 = 256;
+
+/// A writable clone of a source tree
+enum SnapshotClone {
+    Btrfs(PathBuf),
+    /// Clone dataset and the snapshot it was made from
+    Zfs(String, String),
+}
+
+fn run(program: &str, args: &[&str]) -> anyhow::Result<String> {
+    let command = format!("{} {}", program, args.join(" "));
+    let output = Command::new(program).args(args).stderr(Stdio::inherit()).output()
+        .with_context(|| format!("failed to run {}", program))?;
+    if !output.status.success() {
+        return Err(Error::SnapshotCommandFailed(command).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim_end().to_owned())
+}
+
+fn path_str(path: &Path) -> anyhow::Result<&str> {
+    path.to_str().ok_or_else(|| Error::NotSnapshottable(path.to_owned()).into())
+}
+
+impl SnapshotClone {
+    /// Clone the source tree at `source_dir`, the root of a btrfs subvolume
+    /// or the mountpoint of a ZFS dataset, as `target_dir`
+    fn create(source_dir: &Path, target_dir: &Path) -> anyhow::Result<Self> {
+        let fs = clone_fs(source_dir)
+            .with_context(|| format!("failed to stat the filesystem of {}", source_dir.display()))?;
+        if fs == Some(CloneFs::Btrfs) {
+            if source_dir.metadata()?.ino() != BTRFS_SUBVOLUME_INO {
+                return Err(Error::NotSnapshottable(source_dir.to_owned()).into());
+            }
+            run("btrfs", &["subvolume", "snapshot", path_str(source_dir)?, path_str(target_dir)?])?;
+            return Ok(SnapshotClone::Btrfs(target_dir.to_owned()));
+        }
+        if fs != Some(CloneFs::Zfs) {
+            return Err(Error::NotSnapshottable(source_dir.to_owned()).into());
+        }
+
+        let listed = run("zfs", &["list", "-H", "-o", "name,mountpoint", path_str(source_dir)?])?;
+        let (dataset, mountpoint) = listed.split_once('\t').unwrap_or((&listed, ""));
+        if Path::new(mountpoint) != source_dir.canonicalize()? {
+            return Err(Error::NotSnapshottable(source_dir.to_owned()).into());
+        }
+        let target_name = target_dir.file_name().and_then(|name| name.to_str())
+            .ok_or_else(|| Error::NotSnapshottable(target_dir.to_owned()))?;
+        let clone = match dataset.rsplit_once('/') {
+            Some((parent, _)) => format!("{}/{}", parent, target_name),
+            None => format!("{}/{}", dataset, target_name),
+        };
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let snapshot = format!("{}@deltaimage-{}", dataset, since_epoch);
+        run("zfs", &["snapshot", &snapshot])?;
+        let mountpoint = format!("mountpoint={}", path_str(target_dir)?);
+        if let Err(err) = run("zfs", &["clone", "-o", &mountpoint, &snapshot, &clone]) {
+            let _ = run("zfs", &["destroy", &snapshot]);
+            return Err(err);
+        }
+        Ok(SnapshotClone::Zfs(clone, snapshot))
+    }
+
+    fn destroy(&self) -> anyhow::Result<()> {
+        match self {
+            SnapshotClone::Btrfs(path) => run("btrfs", &["subvolume", "delete", path_str(path)?]).map(|_| ()),
+            SnapshotClone::Zfs(clone, snapshot) => {
+                run("zfs", &["destroy", clone])?;
+                run("zfs", &["destroy", snapshot]).map(|_| ())
+            }
+        }
+    }
+}
+
+/// Clone the source tree into `target_dir`, and apply the delta directory on
+/// the clone: the changed paths restored from the delta replace the ones of
+/// the clone, and the deleted ones are removed from it. The clone is dropped
+/// if anything fails, and the delta directory is consumed.
+pub fn apply_on_snapshot(source_dir: &Path, delta_target_dir: &Path, target_dir: &Path,
+    options: ApplyOptions) -> anyhow::Result<ApplyStats>
+{
+    let clone = SnapshotClone::create(source_dir, target_dir)?;
+    let upper_dir = temp_path_for(delta_target_dir);
+    let result = DeltaApplier::new(source_dir, delta_target_dir)
+        .options(ApplyOptions { overlay_upper_dir: Some(upper_dir.clone()), ..options })
+        .run()
+        .and_then(|stats| merge_upper_dir(&upper_dir, target_dir).map(|()| stats));
+    if result.is_err() {
+        tracing::warn!("Dropping the clone {}", target_dir.display());
+        clone.destroy()?;
+    }
+    result
+}
@@ -0,0 +1,178 @@
+//! Sparse files.
+//!
+//! The holes of the target files are found at diff time with `SEEK_DATA` and
+//! `SEEK_HOLE` on Linux, and recorded in the meta-data as offset and length
+//! ranges.
+//! On apply, restored files are written around them, so that they take no
+//! more disk space than the original files did.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+
+use crate::metadata::Holes;
+use crate::platform::{self, FileExt, MetadataExt};
+use crate::throttle::IoLimiter;
+
+/// Largest range copied by one `copy_file_range` call
+const COPY_RANGE_SIZE: usize = 8 << 20;
+
+/// Size of the reads of files copied through user space
+const COPY_BUFFER_SIZE: usize = 1 << 20;
+
+/// Holes of a file. Empty for files that are not sparse, or on filesystems
+/// that cannot tell.
+pub(crate) fn find_holes(path: &Path) -> anyhow::Result<Holes> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let metadata = file.metadata()?;
+    let len = metadata.len();
+
+    // A file taking as many blocks as its size has no holes
+    if metadata.blocks() * 512 >= len {
+        return Ok(vec![]);
+    }
+
+    let mut holes = vec![];
+    let mut offset = 0;
+    while offset < len {
+        let data = match platform::seek_data(&file, offset) {
+            Ok(data) => data.unwrap_or(len),
+            Err(e) if e.kind() == std::io::ErrorKind::Unsupported => return Ok(vec![]),
+            Err(e) => return Err(e).with_context(|| format!("failed to seek {}", path.display())),
+        };
+        if data > offset {
+            holes.push((offset, data - offset));
+        }
+        if data >= len {
+            break;
+        }
+
+        offset = platform::seek_hole(&file, data)
+            .with_context(|| format!("failed to seek {}", path.display()))?;
+    }
+
+    Ok(holes)
+}
+
+/// Writer of a new file that leaves the given holes unwritten, as long as
+/// the content written there is zeros.
+pub(crate) struct SparseWriter<'a> {
+    file: File,
+    holes: &'a [(u64, u64)],
+    offset: u64,
+    limiter: Option<Arc<IoLimiter>>,
+}
+
+impl<'a> SparseWriter<'a> {
+    pub(crate) fn create(path: &Path, holes: &'a [(u64, u64)]) -> anyhow::Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("failed to create {}", path.display()))?;
+        Ok(Self::new(file, holes))
+    }
+
+    /// Write to an already created, empty file
+    pub(crate) fn new(file: File, holes: &'a [(u64, u64)]) -> Self {
+        Self { file, holes, offset: 0, limiter: None }
+    }
+
+    /// Hold writes to the rate of a limiter
+    pub(crate) fn limited(mut self, limiter: Option<Arc<IoLimiter>>) -> Self {
+        self.limiter = limiter;
+        self
+    }
+
+    /// Share the extents of `source` as the whole content of the file, on
+    /// filesystems with reflinks such as XFS and btrfs, keeping its holes.
+    /// Returns whether it did, for the content to be written otherwise.
+    fn reflink(&mut self, source: &File) -> std::io::Result<bool> {
+        if self.offset != 0 {
+            return Ok(false);
+        }
+        if !platform::reflink(&self.file, source) {
+            return Ok(false);
+        }
+        self.offset = source.metadata()?.len();
+        Ok(true)
+    }
+
+    /// Copy the whole content of `source` into the empty file: by sharing its
+    /// extents where the filesystem allows it, within the kernel if there are
+    /// no holes to leave, and otherwise through a large buffer. Returns the
+    /// size of the file.
+    pub(crate) fn copy_from(&mut self, source: &mut File) -> std::io::Result<u64> {
+        if self.reflink(source)? {
+            return Ok(self.offset);
+        }
+
+        let mut source_offset = 0;
+        if self.holes.is_empty() {
+            loop {
+                match platform::copy_range(source, &mut source_offset, &self.file, self.offset, COPY_RANGE_SIZE) {
+                    Ok(0) => return Ok(self.offset),
+                    Ok(len) => {
+                        self.offset += len as u64;
+                        if let Some(limiter) = &self.limiter {
+                            limiter.consume(len as u64);
+                        }
+                    }
+                    // Not between these files, copied through user space from
+                    // where it stopped
+                    Err(_) => break,
+                }
+            }
+        }
+
+        source.seek(SeekFrom::Start(source_offset))?;
+        let mut buf = vec![0; COPY_BUFFER_SIZE];
+        loop {
+            match source.read(&mut buf) {
+                Ok(0) => return Ok(self.offset),
+                Ok(len) => self.write_all(&buf[..len])?,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Extend the file over a trailing hole. Returns the size of the file.
+    pub(crate) fn finish(self) -> std::io::Result<u64> {
+        self.file.set_len(self.offset)?;
+        Ok(self.offset)
+    }
+}
+
+impl<'a> Write for SparseWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        while let Some(&(start, len)) = self.holes.first() {
+            if start + len > self.offset {
+                break;
+            }
+            self.holes = &self.holes[1..];
+        }
+
+        let (in_hole, len) = match self.holes.first() {
+            Some(&(start, len)) if start <= self.offset => (true, start + len - self.offset),
+            Some(&(start, _)) => (false, start - self.offset),
+            None => (false, buf.len() as u64),
+        };
+        let buf = &buf[..buf.len().min(len as usize)];
+
+        if !in_hole || buf.iter().any(|byte| *byte != 0) {
+            self.file.write_all_at(buf, self.offset)?;
+            if let Some(limiter) = &self.limiter {
+                limiter.consume(buf.len() as u64);
+            }
+        }
+        self.offset += buf.len() as u64;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
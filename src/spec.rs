@@ -0,0 +1,140 @@
+//! Build specs: a small `deltaimage.yaml` describing the images of a delta
+//! build, from which the Dockerfile is generated.
+//!
+//! ```yaml
+//! # Diff from image_a to image_b, or apply with delta_image instead
+//! image_a: ubuntu:mantic-20230607
+//! image_b: ubuntu:mantic-20230624
+//! builder: docker
+//! syntax: docker/dockerfile:1
+//! labels:
+//!   org.opencontainers.image.version: "20230624"
+//! ```
+//!
+//! Only the subset of YAML needed is understood: `key: value` pairs, with
+//! optional quotes and comments, and the nested mapping of `labels`.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::cmdline::{Builder, DockerFile, DockerFileOutput, Label, Platforms};
+
+const KEYS: &[&str] = &["image_a", "image_b", "delta_image", "builder", "platform", "version",
+    "config_from", "syntax", "meta_path", "labels"];
+
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        return value[1..value.len() - 1].replace("\\\"", "\"").replace("\\\\", "\\");
+    }
+    if value.len() >= 2 && value.starts_with('\'') && value.ends_with('\'') {
+        return value[1..value.len() - 1].replace("''", "'");
+    }
+    value.to_owned()
+}
+
+/// The line without its comment, if any, leaving `#` within quotes
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut prev = ' ';
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == '#' && prev.is_whitespace() => return &line[..i],
+            None => {}
+        }
+        prev = c;
+    }
+    line
+}
+
+/// Split `key: value`, where the value may itself contain colons, such as
+/// image references with a tag
+fn split_key(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    match line.find(": ") {
+        Some(i) => Some((line[..i].trim(), line[i + 2..].trim())),
+        None => line.strip_suffix(':').map(|key| (key.trim(), "")),
+    }
+}
+
+/// Read a build spec into the `docker-file` command it stands for, with the
+/// output options of the command line
+pub fn docker_file(path: &Path, mut output: DockerFileOutput) -> anyhow::Result<DockerFile> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("reading {}", path.display()))?;
+
+    let mut fields = BTreeMap::new();
+    let mut labels = vec![];
+    let mut in_labels = false;
+    for (nr, line) in content.lines().enumerate() {
+        let invalid = |reason: &str| anyhow::anyhow!("{}:{}: {}", path.display(), nr + 1, reason);
+        let line = strip_comment(line);
+        if line.trim().is_empty() || line.trim() == "---" {
+            continue;
+        }
+        let (key, value) = split_key(line).ok_or_else(|| invalid("expected key: value"))?;
+        let key = unquote(key);
+
+        if line.starts_with([' ', '\t']) {
+            if !in_labels {
+                return Err(invalid("unexpected indentation"));
+            }
+            labels.push(Label { key, value: unquote(value) });
+            continue;
+        }
+
+        if !KEYS.contains(&key.as_str()) {
+            return Err(invalid(&format!("unknown key {}", key)));
+        }
+        in_labels = key == "labels";
+        if in_labels {
+            if !value.is_empty() {
+                return Err(invalid("labels are given as an indented mapping"));
+            }
+            continue;
+        }
+        if fields.insert(key.clone(), unquote(value)).is_some() {
+            return Err(invalid(&format!("duplicate key {}", key)));
+        }
+    }
+
+    let mut field = |key: &str| fields.remove(key).filter(|value| !value.is_empty());
+    let builder = match field("builder") {
+        Some(builder) => builder.parse::<Builder>().map_err(anyhow::Error::msg)?,
+        None => Builder::Docker,
+    };
+    let platform = field("platform").map(|platform| platform.parse::<Platforms>())
+        .transpose().map_err(anyhow::Error::msg)?;
+    let override_version = field("version");
+
+    // Labels of the command line come last, to override those of the spec
+    labels.append(&mut output.labels);
+    output.labels = labels;
+    if output.syntax.is_none() {
+        output.syntax = field("syntax");
+    }
+    if output.delta_meta_path.is_none() {
+        output.delta_meta_path = field("meta_path").map(PathBuf::from);
+    }
+
+    match (field("image_a"), field("image_b"), field("delta_image")) {
+        (Some(image_a), Some(image_b), None) => {
+            if field("config_from").is_some() {
+                return Err(anyhow::anyhow!("{}: config_from only applies with delta_image",
+                    path.display()));
+            }
+            Ok(DockerFile::Diff { image_a, image_b, override_version, builder, platform, output })
+        }
+        (None, None, Some(delta_image)) => {
+            Ok(DockerFile::Apply { delta_image, override_version, config_from: field("config_from"),
+                config_file: None, builder, platform, output })
+        }
+        _ => Err(anyhow::anyhow!("{}: expected either image_a and image_b, or delta_image",
+            path.display())),
+    }
+}
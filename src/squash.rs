@@ -0,0 +1,328 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use walkdir::WalkDir;
+
+use crate::Error;
+use crate::backend;
+use crate::diff::{DeltaBuilder, DiffOptions};
+use crate::metadata::{Algo, MetaData, META_FORMAT_VERSION, REVERSE_DELTA_DIR};
+use crate::patch_from;
+use crate::platform::{MetadataExt, OsStrExt};
+use crate::stream;
+use crate::utils::{copy_tree, drop_components, get_meta_data, set_meta_data, path_from_bytes,
+    temp_path_for, save_parent_modtime, restore_modtimes};
+
+/// Merges a delta from tree A to tree B and a delta from tree B to tree C
+/// into a single delta from A to C, without restoring B.
+///
+/// Files of C that are stored in terms of B are translated to the way the
+/// first delta stores them in terms of A. The few that are patches against B
+/// are restored, one at a time, and encoded again against A.
+pub struct DeltaSquasher {
+    source_dir: PathBuf,
+    first_delta_dir: PathBuf,
+    second_delta_dir: PathBuf,
+    options: DiffOptions,
+}
+
+impl DeltaSquasher {
+    pub fn new(source_dir: impl Into<PathBuf>, first_delta_dir: impl Into<PathBuf>,
+        second_delta_dir: impl Into<PathBuf>) -> Self
+    {
+        Self {
+            source_dir: source_dir.into(),
+            first_delta_dir: first_delta_dir.into(),
+            second_delta_dir: second_delta_dir.into(),
+            options: DiffOptions::default(),
+        }
+    }
+
+    /// Options for the files that need to be encoded again
+    pub fn options(mut self, options: DiffOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Write the squashed delta to a new directory.
+    pub fn run(&self, output_dir: &Path) -> anyhow::Result<()> {
+        if std::fs::symlink_metadata(output_dir).is_ok() {
+            return Err(Error::DeltaDirExists(output_dir.to_owned()).into());
+        }
+
+        let first = MetaData::load(&self.first_delta_dir)?;
+        let second = MetaData::load(&self.second_delta_dir)?;
+        first.check_paths()?;
+        second.check_paths()?;
+        if first.encryption.is_some() || second.encryption.is_some() {
+            return Err(Error::CannotSquash("they are encrypted".to_owned()).into());
+        }
+        if !first.bases.is_empty() || !second.bases.is_empty() {
+            return Err(Error::CannotSquash("they are patched against extra sources".to_owned()).into());
+        }
+        if !first.placeholders.is_empty() || !second.placeholders.is_empty() {
+            return Err(Error::CannotSquash("their placeholders were dropped".to_owned()).into());
+        }
+
+        // Paths excluded from the second delta are taken from B, which only
+        // works if they were taken from A in the first place
+        let first_excluded: HashSet<_> = first.excluded.iter().collect();
+        if let Some(path) = second.excluded.iter().find(|path| !first_excluded.contains(path)) {
+            return Err(Error::CannotSquash(format!("{} is only excluded from the second delta",
+                path_from_bytes(path).display())).into());
+        }
+
+        // Start from the layout of C, with its placeholders and new files
+        copy_tree(&self.second_delta_dir, output_dir)?;
+        MetaData::remove(output_dir)?;
+        let reverse_delta_path = output_dir.join(REVERSE_DELTA_DIR);
+        if second.reverse.is_some() && reverse_delta_path.is_dir() {
+            std::fs::remove_dir_all(&reverse_delta_path)?;
+        }
+
+        let mut link_groups: HashMap<_, Vec<PathBuf>> = HashMap::new();
+        let n = output_dir.components().count();
+        for entry in WalkDir::new(output_dir) {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                let metadata = entry.metadata()?;
+                if metadata.nlink() >= 2 {
+                    link_groups.entry((metadata.ino(), metadata.dev())).or_default()
+                        .push(drop_components(n, entry.path()));
+                }
+            }
+        }
+
+        // How the first delta stores each file of B, `None` meaning unmodified
+        let first_files: HashMap<_, _> = first.keep_files.iter().map(|path| (path.clone(), None))
+            .chain(first.changes.iter().map(|(algo, path)| (path.clone(), Some(algo.clone()))))
+            .collect();
+
+        let builder = DeltaBuilder::new(&self.source_dir, output_dir)
+            .options(self.options.clone());
+        let mut parent_modtime_save = HashMap::new();
+        let mut keep_files = Vec::new();
+        let mut changes = Vec::new();
+        let mut rewritten = HashSet::new();
+
+        let second_files = second.keep_files.iter().map(|path| (None, path))
+            .chain(second.changes.iter().map(|(algo, path)| (Some(algo), path)));
+        for (algo, path) in second_files {
+            let rel_path = path_from_bytes(path);
+            let output_path = output_dir.join(&rel_path);
+            let meta_data = get_meta_data(&output_path)?;
+            save_parent_modtime(&mut parent_modtime_save, &output_path)?;
+
+            // Same content as the file of B at `from`, or a patch against it
+            let (from, patch) = match algo {
+                None => (path.clone(), None),
+                Some(Algo::CopyFrom(from)) => (from.clone(), None),
+                Some(algo @ (Algo::XDelta3 | Algo::XDelta3Chunked(_) | Algo::BsDiff | Algo::Deflate
+                    | Algo::ZstdPatchFrom(_))) => {
+                    (path.clone(), Some(algo))
+                }
+                Some(algo @ Algo::XDelta3From(from)) => (from.clone(), Some(algo)),
+                Some(algo @ (Algo::AsIs | Algo::Zstd)) => {
+                    // Self-contained, left as it is
+                    changes.push((algo.clone(), path.clone()));
+                    continue;
+                }
+            };
+
+            let translated = match (patch, first_files.get(&from)) {
+                (Some(_), _) => None,
+                (None, Some(None)) if &from == path => Some(Translated::Keep),
+                (None, Some(None)) => Some(Translated::Change(Algo::CopyFrom(from.clone()))),
+                (None, Some(Some(Algo::XDelta3))) if &from == path => {
+                    Some(Translated::Change(Algo::XDelta3))
+                }
+                (None, Some(Some(Algo::XDelta3))) => {
+                    Some(Translated::Change(Algo::XDelta3From(from.clone())))
+                }
+                (None, Some(Some(Algo::XDelta3Chunked(_) | Algo::BsDiff | Algo::ZstdPatchFrom(_))))
+                    if &from != path => None,
+                (None, Some(Some(algo))) => Some(Translated::Change(algo.clone())),
+                (None, None) => Some(Translated::New),
+            };
+
+            let first_path = self.first_delta_dir.join(path_from_bytes(&from));
+            std::fs::remove_file(&output_path)
+                .with_context(|| format!("failed removing {}", output_path.display()))?;
+            rewritten.insert(rel_path.clone());
+
+            if let Some(translated) = translated {
+                tracing::debug!("Translated {} <- {}", rel_path.display(),
+                    path_from_bytes(&from).display());
+
+                std::fs::copy(&first_path, &output_path)
+                    .with_context(|| format!("failed copying {}", first_path.display()))?;
+                set_meta_data(&output_path, meta_data)?;
+                match translated {
+                    Translated::Keep => keep_files.push(path.clone()),
+                    Translated::Change(algo) => changes.push((algo, path.clone())),
+                    // New files are not listed in the meta-data
+                    Translated::New => {}
+                }
+                continue;
+            }
+
+            // Restore the file of B, then the file of C from it
+            let b_path = temp_path_for(&output_path);
+            self.restore_first(&from, first_files.get(&from), &b_path)?;
+            let second_path = self.second_delta_dir.join(&rel_path);
+            match patch {
+                None => std::fs::rename(&b_path, &output_path)?,
+                Some(Algo::XDelta3Chunked(chunk_size)) => {
+                    stream::decode_to_file(&b_path, &second_path, &output_path, *chunk_size)?;
+                    std::fs::remove_file(&b_path)?;
+                }
+                Some(Algo::ZstdPatchFrom(window_log)) => {
+                    let mut output = File::create(&output_path)?;
+                    patch_from::decode(&b_path, &second_path, &mut output, *window_log)?;
+                    std::fs::remove_file(&b_path)?;
+                }
+                Some(algo) => {
+                    let orig = std::fs::read(&b_path)?;
+                    let backend = backend::for_algo(algo).expect("file stored whole");
+                    let content = backend.decode(&orig, &std::fs::read(&second_path)?)
+                        .ok_or_else(|| Error::FailedDecode(algo.name(), b_path.clone(),
+                            second_path.clone()))?;
+                    std::fs::write(&output_path, content)?;
+                    std::fs::remove_file(&b_path)?;
+                }
+            }
+            set_meta_data(&output_path, meta_data)?;
+
+            tracing::debug!("Encoding again {}", rel_path.display());
+
+            // Encode it against A if it has a file there, otherwise keep it new
+            if std::fs::symlink_metadata(self.source_dir.join(&rel_path))
+                .map(|m| m.is_file()).unwrap_or(false)
+            {
+                let mut file_diff = builder.diff_file(&rel_path)?;
+                file_diff.commit(&output_path, None)?;
+                match file_diff.algo {
+                    Some(algo) => changes.push((algo, path.clone())),
+                    None => keep_files.push(path.clone()),
+                }
+            }
+        }
+
+        // Restore hardlinks of C to the rewritten files
+        for group in link_groups.values() {
+            let Some(path) = group.iter().find(|path| rewritten.contains(*path)) else { continue };
+            for other_path in group.iter().filter(|other_path| *other_path != path) {
+                let abs_other_path = output_dir.join(other_path);
+                std::fs::remove_file(&abs_other_path)?;
+                std::fs::hard_link(output_dir.join(path), &abs_other_path)
+                    .with_context(|| format!("failed linking {}", abs_other_path.display()))?;
+            }
+        }
+
+        restore_modtimes(parent_modtime_save)?;
+
+        // Files of A that are not in C: the ones deleted from A to B, and the
+        // ones deleted from B to C that come from A
+        let c_files: HashSet<_> = WalkDir::new(output_dir).into_iter()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| drop_components(n, entry.path()).as_os_str().as_bytes().to_owned())
+            .collect();
+        let from_a = |path: &Vec<u8>| matches!(first_files.get(path),
+            Some(None | Some(Algo::XDelta3 | Algo::XDelta3Chunked(_) | Algo::BsDiff
+                | Algo::ZstdPatchFrom(_))));
+        let deleted_files = first.deleted_files.iter()
+            .filter(|path| !c_files.contains(*path))
+            .chain(second.deleted_files.iter().filter(|path| from_a(path)))
+            .cloned()
+            .collect();
+
+        let md = MetaData {
+            format_version: META_FORMAT_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            keep_files,
+            changes,
+            checksums: second.checksums,
+            symlinks: second.symlinks,
+            deleted_files,
+            directories: second.directories,
+            excluded: second.excluded,
+            reverse: None,
+            journal: None,
+            sparse: second.sparse,
+            specials: second.specials,
+            sizes: second.sizes,
+            xdelta3: Some(self.options.xdelta3.clone()),
+            tree_digest: second.tree_digest,
+            link_groups: second.link_groups,
+            // Composing the two needs the configuration of B
+            image_config: None,
+            // Both deltas are restored at once, at costs of their own
+            apply_order: None,
+            fs_image: None,
+            signature: None,
+            encryption: None,
+            extra_sources: vec![],
+            bases: vec![],
+            placeholders: vec![],
+            ignore_times: second.ignore_times,
+            ignore_owner: second.ignore_owner,
+        };
+        md.save_as(output_dir, self.options.meta_format)?;
+
+        Ok(())
+    }
+
+    /// Restore a single file of B from A and the first delta.
+    fn restore_first(&self, path: &[u8], algo: Option<&Option<Algo>>, output_path: &Path)
+        -> anyhow::Result<()>
+    {
+        let rel_path = path_from_bytes(path);
+        let delta_path = self.first_delta_dir.join(&rel_path);
+        let source_path = |from: &[u8]| self.source_dir.join(path_from_bytes(from));
+
+        match algo {
+            None => std::fs::copy(&delta_path, output_path).map(|_| ())?,
+            Some(None) => std::fs::copy(source_path(path), output_path).map(|_| ())?,
+            Some(Some(Algo::CopyFrom(from))) => {
+                std::fs::copy(source_path(from), output_path).map(|_| ())?
+            }
+            Some(Some(Algo::AsIs)) => std::fs::copy(&delta_path, output_path).map(|_| ())?,
+            Some(Some(Algo::XDelta3Chunked(chunk_size))) => {
+                stream::decode_to_file(&source_path(path), &delta_path, output_path, *chunk_size)?;
+            }
+            Some(Some(Algo::ZstdPatchFrom(window_log))) => {
+                let mut output = File::create(output_path)?;
+                patch_from::decode(&source_path(path), &delta_path, &mut output, *window_log)?;
+            }
+            Some(Some(algo @ (Algo::Zstd | Algo::XDelta3 | Algo::XDelta3From(_) | Algo::BsDiff
+                | Algo::Deflate))) => {
+                let backend = backend::for_algo(algo).expect("file stored whole");
+                let source_path = match algo {
+                    Algo::XDelta3From(from) => source_path(from),
+                    _ => source_path(path),
+                };
+                let orig = match backend.uses_source() {
+                    true => std::fs::read(&source_path)?,
+                    false => vec![],
+                };
+                let content = backend.decode(&orig, &std::fs::read(&delta_path)?)
+                    .ok_or_else(|| Error::FailedDecode(algo.name(), source_path.clone(),
+                        delta_path.clone()))?;
+                std::fs::write(output_path, content)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How a file of C that the second delta stores in terms of a file of B is
+/// stored in terms of A
+enum Translated {
+    Keep,
+    Change(Algo),
+    /// A new file of B, stored as it is
+    New,
+}
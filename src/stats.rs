@@ -0,0 +1,107 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use walkdir::WalkDir;
+
+use crate::metadata::{is_meta_file, MetaData, REVERSE_DELTA_DIR};
+use crate::platform::OsStrExt;
+use crate::utils::{drop_components, path_from_bytes};
+
+/// A file whose content is stored in a delta directory
+#[derive(Debug, Clone)]
+pub struct StoredFile {
+    pub path: PathBuf,
+    /// Name of the algorithm, or `new` for files stored as they are
+    pub algo: &'static str,
+    /// Size of the file in the delta directory
+    pub delta_size: u64,
+    /// Size of the restored file, unknown for deltas of older releases
+    pub original_size: Option<u64>,
+}
+
+impl StoredFile {
+    /// Delta size over restored size
+    pub fn ratio(&self) -> Option<f64> {
+        match self.original_size {
+            Some(0) | None => None,
+            Some(size) => Some(self.delta_size as f64 / size as f64),
+        }
+    }
+}
+
+/// Breakdown of what makes up a delta directory, from its meta-data and the
+/// sizes of its files
+#[derive(Debug, Clone, Default)]
+pub struct DeltaStats {
+    /// Number of files by algorithm name, `keep` for unmodified files and
+    /// `new` for added ones
+    pub counts: BTreeMap<&'static str, usize>,
+    pub deleted_files: usize,
+    /// Files with stored content, largest first
+    pub files: Vec<StoredFile>,
+    /// Total size of the files of the delta directory
+    pub total_delta_size: u64,
+}
+
+impl DeltaStats {
+    /// Gather the statistics of a delta directory, leaving out the reverse
+    /// delta of a bidirectional one
+    pub fn collect(delta_dir: &Path) -> anyhow::Result<Self> {
+        let md = MetaData::load(delta_dir)?;
+        let sizes: HashMap<_, _> = md.sizes.iter().cloned().collect();
+        let keep: HashSet<_> = md.keep_files.iter().collect();
+        let excluded: HashSet<_> = md.excluded.iter().collect();
+        let algos: HashMap<_, _> = md.changes.iter().map(|(algo, path)| (path, algo)).collect();
+
+        let mut stats = DeltaStats {
+            deleted_files: md.deleted_files.len(),
+            ..Default::default()
+        };
+        stats.counts.insert("keep", keep.len());
+
+        let n = delta_dir.components().count();
+        let walker = WalkDir::new(delta_dir).into_iter()
+            .filter_entry(|entry| entry.path() != delta_dir.join(REVERSE_DELTA_DIR));
+        for entry in walker {
+            let entry = entry?;
+            let rel_path = drop_components(n, entry.path());
+            if !entry.file_type().is_file() || is_meta_file(&rel_path) {
+                continue;
+            }
+
+            let delta_size = entry.metadata()?.len();
+            stats.total_delta_size += delta_size;
+
+            let path = rel_path.as_os_str().as_bytes().to_owned();
+            if keep.contains(&path) || excluded.contains(&path) {
+                continue;
+            }
+            let algo = algos.get(&path).map(|algo| algo.name()).unwrap_or("new");
+            *stats.counts.entry(algo).or_default() += 1;
+            stats.files.push(StoredFile {
+                original_size: sizes.get(&path).copied()
+                    .or_else(|| (algo == "new").then_some(delta_size)),
+                path: path_from_bytes(&path),
+                algo,
+                delta_size,
+            });
+        }
+
+        stats.files.sort_by_key(|file| std::cmp::Reverse(file.delta_size));
+        Ok(stats)
+    }
+
+    /// Number of stored files by ratio of delta size to restored size, in
+    /// steps of 10%, the last one counting files that grew larger
+    pub fn ratio_histogram(&self) -> [usize; 11] {
+        let mut histogram = [0; 11];
+        for ratio in self.files.iter().filter_map(StoredFile::ratio) {
+            let bucket = match ratio > 1.0 {
+                true => 10,
+                false => ((ratio * 10.0) as usize).min(9),
+            };
+            histogram[bucket] += 1;
+        }
+        histogram
+    }
+}
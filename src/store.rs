@@ -0,0 +1,139 @@
+//! Object stores holding delta archives, keyed by the digests of the images
+//! that they go from and to, so that CI can publish deltas and devices can
+//! fetch the exact one they need.
+//!
+//! Stores are given as URLs:
+//!
+//! - `file:///path/prefix`, a directory, such as a mounted share
+//! - `s3://bucket/prefix`, with the `s3` feature
+//! - `gs://bucket/prefix`, with the `gcs` feature
+//! - `azblob://account/container/prefix`, with the `azblob` feature
+//!
+//! Each delta is stored as `<prefix>/<from digest>/<to digest>.arch`.
+
+#[cfg(feature = "azblob")]
+mod azblob;
+#[cfg(feature = "gcs")]
+mod gcs;
+#[cfg(feature = "s3")]
+mod s3;
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::Error;
+use crate::utils::temp_path_for;
+
+/// A store of objects, in which deltas are pushed and pulled as files
+pub(crate) trait ObjectStore {
+    /// Upload the file at `path` as `key`
+    fn put(&self, key: &str, path: &Path) -> anyhow::Result<()>;
+
+    /// Download `key` to the file at `path`
+    fn get(&self, key: &str, path: &Path) -> anyhow::Result<()>;
+}
+
+/// Store of objects as files under a directory
+struct DirStore {
+    dir: PathBuf,
+}
+
+impl ObjectStore for DirStore {
+    fn put(&self, key: &str, path: &Path) -> anyhow::Result<()> {
+        let dest = self.dir.join(key);
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Never leave a partial object in place
+        let staged = temp_path_for(&dest);
+        std::fs::copy(path, &staged)?;
+        std::fs::rename(&staged, &dest)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str, path: &Path) -> anyhow::Result<()> {
+        std::fs::copy(self.dir.join(key), path)?;
+        Ok(())
+    }
+}
+
+/// Open the store of a URL, returning it along with the prefix of its keys
+pub(crate) fn open_store(url: &str) -> anyhow::Result<(Box<dyn ObjectStore>, String)> {
+    let unsupported = || Error::UnsupportedStore(url.to_owned());
+    let (scheme, rest) = url.split_once("://").ok_or_else(unsupported)?;
+
+    match scheme {
+        "file" => Ok((Box::new(DirStore { dir: PathBuf::from(rest) }), String::new())),
+        #[cfg(feature = "s3")]
+        "s3" => {
+            let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+            Ok((Box::new(s3::S3Store::from_env(bucket)?), prefix.to_owned()))
+        }
+        #[cfg(feature = "gcs")]
+        "gs" => {
+            let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+            Ok((Box::new(gcs::GcsStore::from_env(bucket)?), prefix.to_owned()))
+        }
+        #[cfg(feature = "azblob")]
+        "azblob" => {
+            let mut parts = rest.splitn(3, '/');
+            let (Some(account), Some(container)) = (parts.next(), parts.next()) else {
+                return Err(unsupported().into());
+            };
+            let prefix = parts.next().unwrap_or("");
+            Ok((Box::new(azblob::AzureStore::from_env(account, container)?), prefix.to_owned()))
+        }
+        _ => Err(unsupported().into()),
+    }
+}
+
+/// Key of the delta from the image of digest `from` to the one of digest `to`
+fn delta_key(prefix: &str, from: &str, to: &str) -> anyhow::Result<String> {
+    for digest in [from, to] {
+        if digest.is_empty() || digest.contains('/') || digest.starts_with('.') {
+            return Err(Error::StoreError(format!("invalid digest {}", digest)).into());
+        }
+    }
+
+    let prefix = prefix.trim_matches('/');
+    Ok(match prefix {
+        "" => format!("{}/{}.arch", from, to),
+        _ => format!("{}/{}/{}.arch", prefix, from, to),
+    })
+}
+
+/// URI-encode a key, keeping its `/` separators
+#[cfg(any(feature = "s3", feature = "gcs", feature = "azblob"))]
+fn encode_key(key: &str, keep_slash: bool) -> String {
+    key.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        b'/' if keep_slash => "/".to_owned(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+/// Publish a delta archive to the store at `store_url`, as the delta from the
+/// image of digest `from` to the one of digest `to`. Returns the key under
+/// which it was stored.
+pub fn push_delta(store_url: &str, from: &str, to: &str, archive: &Path) -> anyhow::Result<String> {
+    let (store, prefix) = open_store(store_url)?;
+    let key = delta_key(&prefix, from, to)?;
+
+    tracing::info!("Pushing {} to {}", archive.display(), key);
+    store.put(&key, archive).with_context(|| format!("failed to push {} to {}", key, store_url))?;
+    Ok(key)
+}
+
+/// Fetch the delta archive from the image of digest `from` to the one of
+/// digest `to` from the store at `store_url`, writing it to `archive`
+pub fn pull_delta(store_url: &str, from: &str, to: &str, archive: &Path) -> anyhow::Result<()> {
+    let (store, prefix) = open_store(store_url)?;
+    let key = delta_key(&prefix, from, to)?;
+
+    tracing::info!("Pulling {} to {}", key, archive.display());
+    let staged = temp_path_for(archive);
+    store.get(&key, &staged).with_context(|| format!("failed to pull {} from {}", key, store_url))?;
+    std::fs::rename(&staged, archive)?;
+    Ok(())
+}
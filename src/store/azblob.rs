@@ -0,0 +1,55 @@
+//! Azure Blob Storage.
+//!
+//! Requests are authorized with the shared access signature in
+//! `AZURE_STORAGE_SAS_TOKEN`, which must allow reading, or creating and
+//! writing blobs of the container.
+
+use std::fs::File;
+use std::path::Path;
+
+use crate::Error;
+use super::{encode_key, ObjectStore};
+
+pub(crate) struct AzureStore {
+    agent: ureq::Agent,
+    base_url: String,
+    sas_token: String,
+}
+
+impl AzureStore {
+    pub(crate) fn from_env(account: &str, container: &str) -> anyhow::Result<Self> {
+        let sas_token = std::env::var("AZURE_STORAGE_SAS_TOKEN").map_err(|_| {
+            Error::StoreError("AZURE_STORAGE_SAS_TOKEN is not set".to_owned())
+        })?;
+
+        Ok(Self {
+            agent: ureq::AgentBuilder::new().redirects(5).build(),
+            base_url: format!("https://{}.blob.core.windows.net/{}", account,
+                encode_key(container, false)),
+            sas_token: sas_token.trim_start_matches('?').to_owned(),
+        })
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}/{}?{}", self.base_url, encode_key(key, true), self.sas_token)
+    }
+}
+
+impl ObjectStore for AzureStore {
+    fn put(&self, key: &str, path: &Path) -> anyhow::Result<()> {
+        let size = path.metadata()?.len();
+        self.agent.put(&self.url(key))
+            .set("x-ms-blob-type", "BlockBlob")
+            .set("Content-Length", &size.to_string())
+            .send(File::open(path)?)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str, path: &Path) -> anyhow::Result<()> {
+        let response = self.agent.get(&self.url(key)).call()?;
+        let mut file = File::create(path)?;
+        std::io::copy(&mut response.into_reader(), &mut file)?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+//! Google Cloud Storage, through its JSON API.
+//!
+//! Requests are authorized with the OAuth access token in
+//! `GOOGLE_OAUTH_ACCESS_TOKEN`, such as printed by
+//! `gcloud auth print-access-token`.
+
+use std::fs::File;
+use std::path::Path;
+
+use crate::Error;
+use super::{encode_key, ObjectStore};
+
+const API: &str = "https://storage.googleapis.com";
+
+pub(crate) struct GcsStore {
+    agent: ureq::Agent,
+    bucket: String,
+    authorization: String,
+}
+
+impl GcsStore {
+    pub(crate) fn from_env(bucket: &str) -> anyhow::Result<Self> {
+        let token = std::env::var("GOOGLE_OAUTH_ACCESS_TOKEN").map_err(|_| {
+            Error::StoreError("GOOGLE_OAUTH_ACCESS_TOKEN is not set".to_owned())
+        })?;
+
+        Ok(Self {
+            agent: ureq::AgentBuilder::new().redirects(5).build(),
+            bucket: encode_key(bucket, false),
+            authorization: format!("Bearer {}", token),
+        })
+    }
+}
+
+impl ObjectStore for GcsStore {
+    fn put(&self, key: &str, path: &Path) -> anyhow::Result<()> {
+        let url = format!("{}/upload/storage/v1/b/{}/o?uploadType=media&name={}", API,
+            self.bucket, encode_key(key, false));
+        let size = path.metadata()?.len();
+        self.agent.post(&url)
+            .set("Authorization", &self.authorization)
+            .set("Content-Type", "application/octet-stream")
+            .set("Content-Length", &size.to_string())
+            .send(File::open(path)?)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str, path: &Path) -> anyhow::Result<()> {
+        let url = format!("{}/storage/v1/b/{}/o/{}?alt=media", API, self.bucket,
+            encode_key(key, false));
+        let response = self.agent.get(&url).set("Authorization", &self.authorization).call()?;
+        let mut file = File::create(path)?;
+        std::io::copy(&mut response.into_reader(), &mut file)?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
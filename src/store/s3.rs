@@ -0,0 +1,155 @@
+//! Amazon S3, and S3-compatible stores such as MinIO, through requests signed
+//! with AWS Signature Version 4.
+//!
+//! Credentials are taken from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+//! optionally `AWS_SESSION_TOKEN`, the region from `AWS_REGION`, and another
+//! endpoint than AWS from `AWS_ENDPOINT_URL`.
+
+use std::fs::File;
+use std::path::Path;
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+
+use crate::Error;
+use crate::utils::{civil_date, digest_bytes};
+use super::{encode_key, ObjectStore};
+
+pub(crate) struct S3Store {
+    agent: ureq::Agent,
+    /// Base URL of the bucket
+    base_url: String,
+    host: String,
+    /// Path of the bucket under the host, empty for virtual-hosted buckets
+    bucket_path: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+fn env(name: &'static str) -> anyhow::Result<String> {
+    std::env::var(name).map_err(|_| Error::StoreError(format!("{} is not set", name)).into())
+}
+
+impl S3Store {
+    pub(crate) fn from_env(bucket: &str) -> anyhow::Result<Self> {
+        let region = std::env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".to_owned());
+
+        // Custom endpoints are addressed path-style, as most do not resolve
+        // bucket host names
+        let (scheme, host, bucket_path) = match std::env::var("AWS_ENDPOINT_URL") {
+            Ok(endpoint) => {
+                let (scheme, host) = endpoint.split_once("://").unwrap_or(("https", &endpoint));
+                (scheme.to_owned(), host.trim_end_matches('/').to_owned(), format!("/{}", bucket))
+            }
+            Err(_) => ("https".to_owned(), format!("{}.s3.{}.amazonaws.com", bucket, region),
+                String::new()),
+        };
+
+        Ok(Self {
+            agent: ureq::AgentBuilder::new().redirects(5).build(),
+            base_url: format!("{}://{}{}", scheme, host, bucket_path),
+            host,
+            bucket_path,
+            region,
+            access_key: env("AWS_ACCESS_KEY_ID")?,
+            secret_key: env("AWS_SECRET_ACCESS_KEY")?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+
+    /// Build a signed request of an object. The payload is left unsigned, as
+    /// allowed over HTTPS, so that uploads are streamed.
+    fn request(&self, method: &str, key: &str) -> ureq::Request {
+        let encoded_key = encode_key(key, true);
+        let path = format!("{}/{}", self.bucket_path, encoded_key);
+        let (date, time) = utc_now();
+        let amz_date = format!("{}T{}Z", date, time);
+        let payload = "UNSIGNED-PAYLOAD";
+
+        let mut headers = vec![
+            ("host", self.host.as_str()),
+            ("x-amz-content-sha256", payload),
+            ("x-amz-date", amz_date.as_str()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token));
+        }
+
+        let canonical_headers: String = headers.iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value.trim())).collect();
+        let signed_headers = headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";");
+        let canonical_request = format!("{}\n{}\n\n{}\n{}\n{}", method, path, canonical_headers,
+            signed_headers, payload);
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope,
+            digest_bytes(canonical_request.as_bytes()));
+
+        let mut signing_key = hmac_sha256(format!("AWS4{}", self.secret_key).as_bytes(),
+            date.as_bytes());
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            signing_key = hmac_sha256(&signing_key, part.as_bytes());
+        }
+        let signature: String = hmac_sha256(&signing_key, string_to_sign.as_bytes()).iter()
+            .map(|b| format!("{:02x}", b)).collect();
+
+        let authorization = format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature);
+
+        let url = format!("{}/{}", self.base_url, encoded_key);
+        let mut request = self.agent.request(method, &url).set("Authorization", &authorization);
+        for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+            request = request.set(name, value);
+        }
+        request
+    }
+}
+
+impl ObjectStore for S3Store {
+    fn put(&self, key: &str, path: &Path) -> anyhow::Result<()> {
+        let size = path.metadata()?.len();
+        self.request("PUT", key)
+            .set("Content-Length", &size.to_string())
+            .send(File::open(path)?)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str, path: &Path) -> anyhow::Result<()> {
+        let response = self.request("GET", key).call()?;
+        let mut file = File::create(path)?;
+        std::io::copy(&mut response.into_reader(), &mut file)?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    const BLOCK_SIZE: usize = 64;
+
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(data);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().to_vec()
+}
+
+/// Current UTC date and time, as `YYYYMMDD` and `HHMMSS`
+fn utc_now() -> (String, String) {
+    let secs = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default().as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+    let (year, month, day) = civil_date(days);
+    (format!("{:04}{:02}{:02}", year, month, day),
+     format!("{:02}{:02}{:02}", secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60))
+}
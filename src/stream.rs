@@ -0,0 +1,180 @@
+//! Windowed xdelta3 encoding for files too large to be held in memory.
+//!
+//! The target file is split into fixed-size chunks. Each chunk is encoded
+//! against a window of the source file around the same offset, so memory
+//! usage is bounded by a few chunk sizes regardless of the file size.
+//!
+//! The resulting patch file is a sequence of records, one per chunk:
+//!
+//! ```text
+//! u8 kind (0 = xdelta3, 1 = raw) | u64 LE payload length | payload
+//! ```
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::Error;
+use crate::platform::FileExt;
+use crate::utils::open_noatime;
+use crate::xdelta::{self, XDelta3Params};
+
+/// Default size of the target chunks encoded independently
+pub const STREAM_CHUNK_SIZE: u64 = 16 << 20;
+
+const CHUNK_XDELTA3: u8 = 0;
+const CHUNK_RAW: u8 = 1;
+
+/// Read up to `len` bytes at `offset`, stopping early at end of file.
+pub(crate) fn read_at(file: &File, offset: u64, len: u64) -> std::io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; len as usize];
+    let mut filled = 0;
+    while filled < buf.len() {
+        let nread = file.read_at(&mut buf[filled..], offset + filled as u64)?;
+        if nread == 0 {
+            break;
+        }
+        filled += nread;
+    }
+    buf.truncate(filled);
+    Ok(buf)
+}
+
+/// The source window that chunk `index` is encoded against: the chunk at
+/// the same offset, plus one chunk of slack on each side.
+fn source_window(source: &File, index: u64, chunk_size: u64) -> std::io::Result<Vec<u8>> {
+    let offset = (index * chunk_size).saturating_sub(chunk_size);
+    read_at(source, offset, chunk_size * 3)
+}
+
+/// Compare two files for equality without loading them fully.
+pub fn files_equal(a: &Path, b: &Path) -> std::io::Result<bool> {
+    let (a, b) = (open_noatime(a)?, open_noatime(b)?);
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+
+    let mut offset = 0;
+    loop {
+        let chunk_a = read_at(&a, offset, STREAM_CHUNK_SIZE)?;
+        let chunk_b = read_at(&b, offset, STREAM_CHUNK_SIZE)?;
+        if chunk_a != chunk_b {
+            return Ok(false);
+        }
+        if chunk_a.is_empty() {
+            return Ok(true);
+        }
+        offset += chunk_a.len() as u64;
+    }
+}
+
+/// Encode `target` against `source` into the chunked patch file `output`,
+/// in chunks of `params.window` bytes. Returns the size of the patch.
+pub fn encode_to_file(source_path: &Path, target_path: &Path, output_path: &Path,
+    params: &XDelta3Params) -> anyhow::Result<u64>
+{
+    let mut output = BufWriter::new(File::create(output_path)
+        .with_context(|| format!("failed to create {}", output_path.display()))?);
+    let written = encode(source_path, target_path, &mut output, params)?;
+    output.flush()
+        .with_context(|| format!("failed to write to {}", output_path.display()))?;
+
+    Ok(written)
+}
+
+/// Encode `target` against `source` into `output`, in chunks of
+/// `params.window` bytes, validating each chunk. Returns the size of the patch.
+pub fn encode(source_path: &Path, target_path: &Path, output: &mut impl Write,
+    params: &XDelta3Params) -> anyhow::Result<u64>
+{
+    let chunk_size = params.window;
+    let source = File::open(source_path)
+        .with_context(|| format!("failed to open {}", source_path.display()))?;
+    let target = open_noatime(target_path)
+        .with_context(|| format!("failed to open {}", target_path.display()))?;
+
+    let mut written = 0u64;
+    for index in 0.. {
+        let chunk = read_at(&target, index * chunk_size, chunk_size)?;
+        if chunk.is_empty() {
+            break;
+        }
+
+        let window = source_window(&source, index, chunk_size)?;
+        let delta = xdelta::encode(&chunk, &window, params)
+            .ok_or(Error::XDelta3EncodeError)?;
+
+        let (kind, payload) = match xdelta3::decode(&delta, &window) {
+            Some(deflated) if deflated == chunk => (CHUNK_XDELTA3, delta),
+            Some(_) => {
+                return Err(Error::XDelta3FailedValidation(source_path.to_owned(),
+                    target_path.to_owned()).into());
+            }
+            None => (CHUNK_RAW, chunk),
+        };
+
+        output.write_all(&[kind])?;
+        output.write_all(&(payload.len() as u64).to_le_bytes())?;
+        output.write_all(&payload)?;
+        written += 9 + payload.len() as u64;
+    }
+
+    Ok(written)
+}
+
+/// Reconstruct the target file into `output` from `source` and the chunked
+/// patch file. Returns the size of the reconstructed file.
+pub fn decode_to_file(source_path: &Path, patch_path: &Path, output_path: &Path, chunk_size: u64)
+    -> anyhow::Result<u64>
+{
+    let mut output = BufWriter::new(File::create(output_path)
+        .with_context(|| format!("failed to create {}", output_path.display()))?);
+    let written = decode(source_path, patch_path, &mut output, chunk_size)?;
+    output.flush()
+        .with_context(|| format!("failed to write to {}", output_path.display()))?;
+
+    Ok(written)
+}
+
+/// Reconstruct the target file from `source` and the chunked patch file,
+/// writing it to `output`. Returns the size of the reconstructed file.
+pub fn decode(source_path: &Path, patch_path: &Path, output: &mut impl Write, chunk_size: u64)
+    -> anyhow::Result<u64>
+{
+    let source = File::open(source_path)
+        .with_context(|| format!("failed to open {}", source_path.display()))?;
+    let mut patch = BufReader::new(File::open(patch_path)
+        .with_context(|| format!("failed to open {}", patch_path.display()))?);
+
+    let deflation_error = || Error::XDelta3FailedDeflation(source_path.to_owned(),
+        patch_path.to_owned());
+
+    let mut written = 0u64;
+    for index in 0.. {
+        let mut kind = [0u8; 1];
+        if patch.read(&mut kind)? == 0 {
+            break;
+        }
+
+        let mut len = [0u8; 8];
+        patch.read_exact(&mut len).map_err(|_| deflation_error())?;
+        let mut payload = vec![0u8; u64::from_le_bytes(len) as usize];
+        patch.read_exact(&mut payload).map_err(|_| deflation_error())?;
+
+        let chunk = match kind[0] {
+            CHUNK_XDELTA3 => {
+                let window = source_window(&source, index, chunk_size)?;
+                xdelta3::decode(&payload, &window).ok_or_else(deflation_error)?
+            }
+            CHUNK_RAW => payload,
+            _ => return Err(deflation_error().into()),
+        };
+
+        output.write_all(&chunk)?;
+        written += chunk.len() as u64;
+    }
+
+    Ok(written)
+}
@@ -0,0 +1,505 @@
+//! Deltas between two tarballs, such as the layers written by `docker save`,
+//! computed entry by entry without extracting them, and thus without root.
+//!
+//! The delta is a stream of records, one per entry of the target tarball,
+//! each holding the tar header of the entry verbatim, so that apply writes
+//! back the exact same tarball. All integers are little-endian:
+//!
+//! ```text
+//! header:  "DELTAIMGTAR" | u32 version
+//! raw:     u8 0 | header block | u64 length | data blocks, as in the target
+//! keep:    u8 1 | header block | path
+//! patch:   u8 2 | header block | path | algo name | u64 length | stored content
+//! end:     u8 3 | u64 length of the end-of-archive blocks | SHA-256 of the target
+//! ```
+//!
+//! Paths and algo names are stored as `u32 length | bytes`. Keep and patch
+//! records restore the content of a regular file from the source entry of the
+//! same path.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+
+use crate::Error;
+use crate::backend;
+use crate::diff::{self, DiffOptions, DiffStats};
+use crate::platform::FileExt;
+use crate::report::FileReport;
+use crate::utils::{open_noatime, path_from_bytes, temp_path_for};
+
+const MAGIC: &[u8] = b"DELTAIMGTAR";
+const VERSION: u32 = 1;
+
+const RECORD_RAW: u8 = 0;
+const RECORD_KEEP: u8 = 1;
+const RECORD_PATCH: u8 = 2;
+const RECORD_END: u8 = 3;
+
+const BLOCK_SIZE: u64 = 512;
+
+/// Header block of a tar entry
+struct TarHeader([u8; BLOCK_SIZE as usize]);
+
+impl TarHeader {
+    fn is_zero(&self) -> bool {
+        self.0.iter().all(|byte| *byte == 0)
+    }
+
+    fn entry_type(&self) -> u8 {
+        self.0[156]
+    }
+
+    fn is_file(&self) -> bool {
+        matches!(self.entry_type(), b'0' | 0 | b'7')
+    }
+
+    /// Size of the data, in octal or in the base-256 encoding of GNU tar
+    fn size(&self) -> Result<u64, Error> {
+        let field = &self.0[124..136];
+        if field[0] & 0x80 != 0 {
+            return Ok(field[1..].iter().fold(0, |size, byte| size << 8 | *byte as u64));
+        }
+        let digits = std::str::from_utf8(field).map_err(|_| Error::InvalidTar("bad size"))?;
+        let digits = digits.trim_matches(|c: char| c == '\0' || c == ' ');
+        match digits {
+            "" => Ok(0),
+            _ => u64::from_str_radix(digits, 8).map_err(|_| Error::InvalidTar("bad size")),
+        }
+    }
+
+    /// Size of the data blocks following the header
+    fn data_len(&self) -> Result<u64, Error> {
+        Ok(self.size()?.div_ceil(BLOCK_SIZE) * BLOCK_SIZE)
+    }
+
+    /// Path of the entry, from the name field and the prefix field of ustar
+    fn path(&self) -> Vec<u8> {
+        let field = |range: std::ops::Range<usize>| {
+            let bytes = &self.0[range];
+            bytes[..bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len())].to_owned()
+        };
+        let name = field(0..100);
+        if &self.0[257..263] != b"ustar\0" {
+            return name;
+        }
+        let mut path = field(345..500);
+        if path.is_empty() {
+            return name;
+        }
+        path.push(b'/');
+        path.extend(name);
+        path
+    }
+}
+
+/// Entry paths without their leading `./` or `/`, as they vary between tools
+fn normalize(path: &[u8]) -> Vec<u8> {
+    let mut path = path;
+    loop {
+        match path {
+            [b'.', b'/', rest @ ..] | [b'/', rest @ ..] => path = rest,
+            _ => return path.to_owned(),
+        }
+    }
+}
+
+/// Path given by the data of a GNU long name entry, or the `path` record of
+/// a pax extended header
+fn extended_path(entry_type: u8, data: &[u8]) -> Option<Vec<u8>> {
+    match entry_type {
+        b'L' => Some(data[..data.iter().position(|b| *b == 0).unwrap_or(data.len())].to_owned()),
+        b'x' => {
+            let mut records = data;
+            let mut path = None;
+            while let Some(space) = records.iter().position(|b| *b == b' ') {
+                let len: usize = std::str::from_utf8(&records[..space]).ok()?.parse().ok()?;
+                let record = records.get(space + 1..len)?;
+                if let Some(value) = record.strip_prefix(b"path=") {
+                    path = Some(value.strip_suffix(b"\n").unwrap_or(value).to_owned());
+                }
+                records = &records[len..];
+            }
+            path
+        }
+        _ => None,
+    }
+}
+
+/// Reader hashing and counting the bytes read through it
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    count: u64,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Writer hashing the bytes written through it
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Read a header block, or `None` at the end of the input
+fn read_header(input: &mut impl Read) -> anyhow::Result<Option<TarHeader>> {
+    let mut block = [0u8; BLOCK_SIZE as usize];
+    let mut filled = 0;
+    while filled < block.len() {
+        match input.read(&mut block[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(Error::InvalidTar("truncated header").into()),
+            n => filled += n,
+        }
+    }
+    Ok(Some(TarHeader(block)))
+}
+
+fn read_data(input: &mut impl Read, len: u64) -> anyhow::Result<Vec<u8>> {
+    let mut data = vec![0u8; len as usize];
+    input.read_exact(&mut data).map_err(|_| Error::InvalidTar("truncated entry"))?;
+    Ok(data)
+}
+
+/// Input tarball, possibly gzip-compressed, and whether it is
+fn open_tar(path: &Path) -> anyhow::Result<(Box<dyn Read>, bool)> {
+    let file = open_noatime(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let mut magic = [0u8; 2];
+    let gzipped = file.read_at(&mut magic, 0)? == 2 && magic == [0x1f, 0x8b];
+    let file = BufReader::new(file);
+    Ok(match gzipped {
+        true => (Box::new(flate2::read::GzDecoder::new(file)), true),
+        false => (Box::new(file), false),
+    })
+}
+
+/// Regular files of the source tarball, by path, which are read from it as
+/// needed. A gzip-compressed tarball is decompressed to a temporary file
+/// first, removed when dropped.
+struct SourceTar {
+    file: File,
+    files: HashMap<Vec<u8>, (u64, u64)>,
+    decompressed: Option<PathBuf>,
+}
+
+impl SourceTar {
+    fn open(path: &Path, work_path: &Path) -> anyhow::Result<Self> {
+        let mut source = match open_tar(path)? {
+            (mut input, true) => {
+                // Next to the temporary file of the output, which apply
+                // writes while reading this one
+                let mut tmp_name = work_path.as_os_str().to_owned();
+                tmp_name.push(".source");
+                let tmp_path = temp_path_for(Path::new(&tmp_name));
+                let mut tmp_file = File::create(&tmp_path)
+                    .with_context(|| format!("failed to create {}", tmp_path.display()))?;
+                std::io::copy(&mut input, &mut tmp_file)
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                Self { file: File::open(&tmp_path)?, files: HashMap::new(), decompressed: Some(tmp_path) }
+            }
+            (_, false) => Self { file: open_noatime(path)?, files: HashMap::new(), decompressed: None },
+        };
+
+        let mut input = BufReader::new(&source.file);
+        let mut offset = 0;
+        let mut pending_path = None;
+        while let Some(header) = read_header(&mut input)? {
+            if header.is_zero() {
+                break;
+            }
+            let (size, data_len) = (header.size()?, header.data_len()?);
+            offset += BLOCK_SIZE;
+            match header.entry_type() {
+                entry_type @ (b'L' | b'x') => {
+                    pending_path = extended_path(entry_type, &read_data(&mut input, data_len)?)
+                        .or(pending_path);
+                }
+                _ => {
+                    let path = pending_path.take().unwrap_or_else(|| header.path());
+                    if header.is_file() {
+                        source.files.insert(normalize(&path), (offset, size));
+                    }
+                    std::io::copy(&mut (&mut input).take(data_len), &mut std::io::sink())?;
+                }
+            }
+            offset += data_len;
+        }
+
+        Ok(source)
+    }
+
+    fn read(&self, path: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(&(offset, size)) = self.files.get(path) else { return Ok(None) };
+        let mut content = vec![0u8; size as usize];
+        self.file.read_exact_at(&mut content, offset)?;
+        Ok(Some(content))
+    }
+}
+
+impl Drop for SourceTar {
+    fn drop(&mut self) {
+        if let Some(path) = &self.decompressed {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+struct DeltaWriter<W: Write> {
+    out: W,
+    written: u64,
+}
+
+impl<W: Write> DeltaWriter<W> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.out.write_all(data)?;
+        self.written += data.len() as u64;
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.write(&(data.len() as u32).to_le_bytes())?;
+        self.write(data)
+    }
+
+    fn write_record(&mut self, kind: u8, header: &TarHeader) -> std::io::Result<()> {
+        self.write(&[kind])?;
+        self.write(&header.0)
+    }
+}
+
+/// Compute the delta from the source tarball to the target tarball. With
+/// `dry_run`, only the sizes are computed.
+pub fn diff_tar(source_tar: &Path, target_tar: &Path, delta_path: &Path, options: &DiffOptions)
+    -> anyhow::Result<DiffStats>
+{
+    let started = Instant::now();
+    let source = SourceTar::open(source_tar, delta_path)?;
+    let mut input = HashingReader { inner: open_tar(target_tar)?.0, hasher: Sha256::new(), count: 0 };
+
+    let output: Box<dyn Write> = match options.dry_run {
+        true => Box::new(std::io::sink()),
+        false => Box::new(BufWriter::new(File::create(delta_path)
+            .with_context(|| format!("failed to create {}", delta_path.display()))?)),
+    };
+    let mut delta = DeltaWriter { out: output, written: 0 };
+    delta.write(MAGIC)?;
+    delta.write(&VERSION.to_le_bytes())?;
+
+    let mut stats = DiffStats { duration: started.elapsed(), ..DiffStats::default() };
+    let mut pending_path = None;
+    loop {
+        let Some(header) = read_header(&mut input)? else {
+            return Err(Error::InvalidTar("missing end of archive").into());
+        };
+        if header.is_zero() {
+            // End of the archive, followed by zero padding
+            let start = input.count - BLOCK_SIZE;
+            let mut rest = vec![];
+            input.read_to_end(&mut rest)?;
+            if rest.iter().any(|byte| *byte != 0) {
+                return Err(Error::InvalidTar("data after the end of the archive").into());
+            }
+            delta.write(&[RECORD_END])?;
+            delta.write(&(input.count - start).to_le_bytes())?;
+            delta.write(&input.hasher.clone().finalize())?;
+            break;
+        }
+
+        let (size, data_len) = (header.size()?, header.data_len()?);
+        let data = read_data(&mut input, data_len)?;
+        let path = match header.entry_type() {
+            entry_type @ (b'L' | b'x') => {
+                pending_path = extended_path(entry_type, &data).or(pending_path);
+                None
+            }
+            _ => Some(pending_path.take().unwrap_or_else(|| header.path())),
+        };
+
+        // Regular files also found in the source, and padded with zeros as
+        // written back on apply
+        let file_started = Instant::now();
+        let content = &data[..size as usize];
+        let old_content = match &path {
+            Some(path) if header.is_file() && data[size as usize..].iter().all(|b| *b == 0) => {
+                source.read(&normalize(path))?
+            }
+            _ => None,
+        };
+        let (Some(path), Some(old_content)) = (path, old_content) else {
+            if header.is_file() {
+                stats.new_files += 1;
+            }
+            delta.write_record(RECORD_RAW, &header)?;
+            delta.write(&data_len.to_le_bytes())?;
+            delta.write(&data)?;
+            continue;
+        };
+
+        let rel_path = path_from_bytes(&normalize(&path));
+        let (algo, reduced_size) = if old_content == content {
+            delta.write_record(RECORD_KEEP, &header)?;
+            delta.write_bytes(&path)?;
+            (None, 0)
+        } else {
+            let (algo, stored) = diff::encode(options, &source_tar.join(&rel_path),
+                &target_tar.join(&rel_path), &old_content, content)?;
+            delta.write_record(RECORD_PATCH, &header)?;
+            delta.write_bytes(&path)?;
+            delta.write_bytes(algo.name().as_bytes())?;
+            delta.write(&(stored.len() as u64).to_le_bytes())?;
+            delta.write(&stored)?;
+            (Some(algo), stored.len() as u64)
+        };
+
+        tracing::debug!("{} {}: {} -> {}", algo.as_ref().map(|algo| algo.name()).unwrap_or("Keep"),
+            rel_path.display(), size, reduced_size);
+        stats.total_size += size;
+        stats.reduced_size += reduced_size;
+        stats.files.push(FileReport::new(&rel_path, algo.as_ref(), size, reduced_size,
+            file_started.elapsed()));
+    }
+
+    delta.out.flush()
+        .with_context(|| format!("failed to write to {}", delta_path.display()))?;
+    stats.duration = started.elapsed();
+
+    Ok(stats)
+}
+
+struct DeltaReader<R: Read> {
+    input: R,
+}
+
+impl<R: Read> DeltaReader<R> {
+    fn read_exact<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.input.read_exact(&mut buf).map_err(|_| Error::InvalidTarDelta("truncated"))?;
+        Ok(buf)
+    }
+
+    fn read_u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.read_exact()?))
+    }
+
+    fn read_bytes(&mut self) -> anyhow::Result<Vec<u8>> {
+        let len = u32::from_le_bytes(self.read_exact()?);
+        self.read_vec(len as u64)
+    }
+
+    fn read_vec(&mut self, len: u64) -> anyhow::Result<Vec<u8>> {
+        let mut buf = vec![];
+        (&mut self.input).take(len).read_to_end(&mut buf)?;
+        if buf.len() as u64 != len {
+            return Err(Error::InvalidTarDelta("truncated").into());
+        }
+        Ok(buf)
+    }
+}
+
+/// Restore the target tarball from the source tarball and a delta made by
+/// `diff_tar`, writing it to `output_tar`, which must not exist.
+pub fn apply_tar(source_tar: &Path, delta_path: &Path, output_tar: &Path)
+    -> anyhow::Result<()>
+{
+    if std::fs::symlink_metadata(output_tar).is_ok() {
+        return Err(Error::OutputImageExists(output_tar.to_owned()).into());
+    }
+
+    let source = SourceTar::open(source_tar, output_tar)?;
+    let mut delta = DeltaReader { input: BufReader::new(File::open(delta_path)
+        .with_context(|| format!("failed to open {}", delta_path.display()))?) };
+    if delta.read_exact::<11>()? != MAGIC {
+        return Err(Error::InvalidTarDelta("bad magic").into());
+    }
+    if u32::from_le_bytes(delta.read_exact()?) != VERSION {
+        return Err(Error::InvalidTarDelta("unsupported version").into());
+    }
+
+    let tmp_path = temp_path_for(output_tar);
+    let mut output = HashingWriter {
+        inner: BufWriter::new(File::create(&tmp_path)
+            .with_context(|| format!("failed to create {}", tmp_path.display()))?),
+        hasher: Sha256::new(),
+    };
+
+    loop {
+        let [kind] = delta.read_exact()?;
+        if kind == RECORD_END {
+            let len = delta.read_u64()?;
+            output.write_all(&vec![0u8; len as usize])?;
+            let digest: [u8; 32] = delta.read_exact()?;
+            if output.hasher.clone().finalize()[..] != digest {
+                return Err(Error::TarDigestMismatch(output_tar.to_owned()).into());
+            }
+            break;
+        }
+
+        let header = TarHeader(delta.read_exact()?);
+        output.write_all(&header.0)?;
+        let content = match kind {
+            RECORD_RAW => {
+                let len = delta.read_u64()?;
+                output.write_all(&delta.read_vec(len)?)?;
+                continue;
+            }
+            RECORD_KEEP | RECORD_PATCH => {
+                let path = delta.read_bytes()?;
+                let old_content = source.read(&normalize(&path))?
+                    .ok_or(Error::InvalidTarDelta("file missing from the source tarball"))?;
+                if kind == RECORD_KEEP {
+                    old_content
+                } else {
+                    let name = String::from_utf8_lossy(&delta.read_bytes()?).into_owned();
+                    let backend = backend::by_name(&name).ok_or(Error::UnknownAlgo(name))?;
+                    let len = delta.read_u64()?;
+                    let content = backend.decode(&old_content, &delta.read_vec(len)?)
+                        .ok_or_else(|| Error::FailedDecode(backend.algo().name(),
+                            source_tar.join(path_from_bytes(&path)), delta_path.to_owned()))?;
+                    tracing::debug!("Modified {}: {} -> {}", path_from_bytes(&path).display(), len,
+                        content.len());
+                    content
+                }
+            }
+            _ => return Err(Error::InvalidTarDelta("unknown record kind").into()),
+        };
+
+        if content.len() as u64 != header.size()? {
+            return Err(Error::TarDigestMismatch(output_tar.to_owned()).into());
+        }
+        output.write_all(&content)?;
+        output.write_all(&vec![0u8; (header.data_len()? - header.size()?) as usize])?;
+    }
+
+    output.flush()
+        .with_context(|| format!("failed to write to {}", tmp_path.display()))?;
+    drop(output);
+    std::fs::rename(&tmp_path, output_tar)
+        .with_context(|| format!("failed to create {}", output_tar.display()))?;
+
+    Ok(())
+}
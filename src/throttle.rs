@@ -0,0 +1,101 @@
+//! Limits on what apply takes from the workloads running next to it: the
+//! bandwidth of the files it writes, and the CPU and I/O priorities of the
+//! process.
+
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+
+use crate::platform;
+
+/// Writes allowed at once after being idle, for the limit not to sleep on
+/// every small write
+const BURST: Duration = Duration::from_millis(100);
+
+/// Shared limit of the rate at which bytes are written
+pub(crate) struct IoLimiter {
+    bytes_per_sec: f64,
+    /// When the bytes accounted so far are all within the limit
+    next: Mutex<Instant>,
+}
+
+impl IoLimiter {
+    pub(crate) fn new(bytes_per_sec: u64) -> Self {
+        Self { bytes_per_sec: bytes_per_sec.max(1) as f64, next: Mutex::new(Instant::now()) }
+    }
+
+    /// Account for bytes written, sleeping for as long as they go over the
+    /// limit
+    pub(crate) fn consume(&self, bytes: u64) {
+        let wait = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let start = (*next).max(now.checked_sub(BURST).unwrap_or(now));
+            *next = start + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec);
+            next.saturating_duration_since(now)
+        };
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+/// I/O scheduling class, as set by `ionice`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    /// Only served when no other process uses the disk
+    Idle,
+    /// Served at a level from 0, the highest, to 7
+    BestEffort(u8),
+}
+
+impl IoPriority {
+    /// Value taken by `ioprio_set`, the class in the upper bits
+    fn value(self) -> i32 {
+        match self {
+            IoPriority::Idle => 3 << 13,
+            IoPriority::BestEffort(level) => 2 << 13 | level as i32,
+        }
+    }
+}
+
+impl FromStr for IoPriority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "idle" => Ok(IoPriority::Idle),
+            None if s == "best-effort" => Ok(IoPriority::BestEffort(4)),
+            Some(("best-effort", level)) => match level.parse() {
+                Ok(level @ 0..=7) => Ok(IoPriority::BestEffort(level)),
+                _ => Err(format!("invalid best-effort level {}, expected 0 to 7", level)),
+            },
+            _ => Err(format!("unknown I/O priority {}, expected idle or best-effort[:level]", s)),
+        }
+    }
+}
+
+/// Lower the CPU priority of the calling process to a niceness, and set its
+/// I/O scheduling class, on Linux. Threads started afterwards inherit both.
+/// Either is skipped with a warning where the system has no such thing.
+pub fn set_priorities(nice: Option<i32>, io_priority: Option<IoPriority>) -> anyhow::Result<()> {
+    if let Some(nice) = nice {
+        match platform::set_niceness(nice) {
+            Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+                tracing::warn!("Not setting niceness {}, which this system lacks", nice);
+            }
+            result => result.with_context(|| format!("failed to set niceness {}", nice))?,
+        }
+    }
+    if let Some(io_priority) = io_priority {
+        match platform::set_io_priority(io_priority.value()) {
+            Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+                tracing::warn!("Not setting I/O priority {:?}, which only Linux has", io_priority);
+            }
+            result => result.with_context(|| format!("failed to set I/O priority {:?}", io_priority))?,
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,94 @@
+//! Merkle-style digest of a whole tree, identifying what a delta restores.
+//!
+//! Each directory is digested from the sorted records of its entries: their
+//! type, name, permissions and ownership, and the digest of their content,
+//! which for subdirectories is their own digest. Timestamps and extended
+//! attributes are left out, as they may legitimately differ between two
+//! copies of the same image.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::platform::{MetadataExt, OsStrExt};
+use crate::utils::{default_jobs, digest_bytes, digest_file, drop_components, parallel_map};
+
+/// Digest the tree at `dir`, leaving out the paths for which `skip` returns
+/// true along with anything under them. Files listed in `known` are taken to
+/// have the given content digests instead of being read.
+pub(crate) fn tree_digest(dir: &Path, skip: impl Fn(&Path) -> bool,
+    known: &HashMap<PathBuf, String>) -> anyhow::Result<String>
+{
+    let n = dir.components().count();
+    let mut unknown = vec![];
+    let mut walker = WalkDir::new(dir).into_iter();
+    while let Some(entry) = walker.next() {
+        let entry = entry?;
+        let rel_path = drop_components(n, entry.path());
+        if skip(&rel_path) {
+            if entry.file_type().is_dir() {
+                walker.skip_current_dir();
+            }
+            continue;
+        }
+        if entry.file_type().is_file() && !known.contains_key(&rel_path) {
+            unknown.push(rel_path);
+        }
+    }
+
+    let digests = parallel_map(default_jobs(), &unknown, |rel_path| digest_file(&dir.join(rel_path)))?;
+    let mut file_digests: HashMap<&Path, &str> = known.iter()
+        .map(|(path, digest)| (path.as_path(), digest.as_str()))
+        .collect();
+    file_digests.extend(unknown.iter().map(|path| path.as_path()).zip(digests.iter().map(|x| x.as_str())));
+
+    dir_digest(dir, Path::new(""), &skip, &file_digests)
+}
+
+fn dir_digest(dir: &Path, rel_dir: &Path, skip: &impl Fn(&Path) -> bool,
+    file_digests: &HashMap<&Path, &str>) -> anyhow::Result<String>
+{
+    let mut entries = std::fs::read_dir(dir.join(rel_dir))?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let mut hasher = Sha256::new();
+    for entry in entries {
+        let rel_path = rel_dir.join(entry.file_name());
+        if skip(&rel_path) {
+            continue;
+        }
+
+        let metadata = entry.metadata()?;
+        let file_type = metadata.file_type();
+        let (kind, mode, digest) = if file_type.is_dir() {
+            (b'd', metadata.mode(), dir_digest(dir, &rel_path, skip, file_digests)?)
+        } else if file_type.is_file() {
+            (b'f', metadata.mode(), file_digests[rel_path.as_path()].to_owned())
+        } else if file_type.is_symlink() {
+            // Permissions of symlinks are not meaningful
+            let target = std::fs::read_link(entry.path())?;
+            (b'l', 0, digest_bytes(target.as_os_str().as_bytes()))
+        } else {
+            let device = format!("{:o}:{}", metadata.mode() & 0o170000, metadata.rdev());
+            (b's', metadata.mode(), digest_bytes(device.as_bytes()))
+        };
+
+        let name = entry.file_name();
+        hasher.update([kind]);
+        hasher.update((name.len() as u32).to_le_bytes());
+        hasher.update(name.as_bytes());
+        hasher.update((mode & 0o7777).to_le_bytes());
+        hasher.update(metadata.uid().to_le_bytes());
+        hasher.update(metadata.gid().to_le_bytes());
+        hasher.update(digest.as_bytes());
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Merkle-style digest of the tree at `dir`, as recorded by diff for the
+/// target tree and checked by apply
+pub fn digest_tree(dir: &Path) -> anyhow::Result<String> {
+    tree_digest(dir, |_| false, &HashMap::new())
+}
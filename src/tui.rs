@@ -0,0 +1,187 @@
+//! Interactive browser of the tree of a delta, largest subtrees first, for
+//! image maintainers to find out why a delta is as big as it is.
+
+use std::collections::HashSet;
+use std::io::Stdout;
+use std::path::{Path, PathBuf};
+
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use ratatui::{Frame, Terminal};
+
+use crate::inspect::DeltaNode;
+
+/// Rows moved by page up and down
+const PAGE: usize = 20;
+
+const HELP: &str = " q quit  ↑↓ move  → expand  ← collapse  PgUp/PgDn page";
+
+/// Browse the tree of a delta directory in the terminal until quit. With
+/// `source_dir`, the tree the delta was computed against, the details of a
+/// path show how its meta-data changed.
+pub fn browse_delta(delta_dir: &Path, source_dir: Option<&Path>) -> anyhow::Result<()> {
+    let root = DeltaNode::load(delta_dir)?;
+    let mut browser = Browser {
+        title: format!(" {} ", delta_dir.display()),
+        root: &root,
+        source_dir,
+        expanded: HashSet::from([root.path.clone()]),
+        state: ListState::default().with_selected(Some(0)),
+    };
+
+    enable_raw_mode()?;
+    crossterm::execute!(std::io::stdout(), EnterAlternateScreen)?;
+    let result = Terminal::new(CrosstermBackend::new(std::io::stdout()))
+        .map_err(anyhow::Error::from)
+        .and_then(|mut terminal| browser.run(&mut terminal));
+    disable_raw_mode()?;
+    crossterm::execute!(std::io::stdout(), LeaveAlternateScreen)?;
+    result
+}
+
+struct Browser<'a> {
+    title: String,
+    root: &'a DeltaNode,
+    source_dir: Option<&'a Path>,
+    /// Paths of the expanded directories
+    expanded: HashSet<PathBuf>,
+    state: ListState,
+}
+
+impl Browser<'_> {
+    fn run(&mut self, terminal: &mut Terminal<CrosstermBackend<Stdout>>) -> anyhow::Result<()> {
+        loop {
+            let rows = rows(self.root, &self.expanded);
+            terminal.draw(|frame| self.draw(frame, &rows))?;
+
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            let selected = self.state.selected().unwrap_or(0).min(rows.len() - 1);
+            let last = rows.len() - 1;
+            let (depth, node) = rows[selected];
+            let selected = match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Down | KeyCode::Char('j') => (selected + 1).min(last),
+                KeyCode::Up | KeyCode::Char('k') => selected.saturating_sub(1),
+                KeyCode::PageDown => (selected + PAGE).min(last),
+                KeyCode::PageUp => selected.saturating_sub(PAGE),
+                KeyCode::Home | KeyCode::Char('g') => 0,
+                KeyCode::End | KeyCode::Char('G') => last,
+                KeyCode::Right | KeyCode::Char('l') | KeyCode::Enter => {
+                    if !node.children.is_empty() {
+                        self.expanded.insert(node.path.clone());
+                    }
+                    selected
+                }
+                // Collapse, or go up to the parent directory
+                KeyCode::Left | KeyCode::Char('h') => match self.expanded.remove(&node.path) {
+                    true => selected,
+                    false => rows[..selected].iter().rposition(|(parent_depth, _)| *parent_depth < depth)
+                        .unwrap_or(selected),
+                },
+                _ => selected,
+            };
+            self.state.select(Some(selected));
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame, rows: &[(usize, &DeltaNode)]) {
+        let areas = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(1), Constraint::Length(1)])
+            .split(frame.size());
+        let (main, help) = (areas[0], areas[1]);
+        let areas = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(60), Constraint::Percentage(40)])
+            .split(main);
+        let (tree, details) = (areas[0], areas[1]);
+
+        let items: Vec<_> = rows.iter()
+            .map(|(depth, node)| ListItem::new(row(*depth, node, self.expanded.contains(&node.path))))
+            .collect();
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title(self.title.as_str()))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(list, tree, &mut self.state);
+
+        let selected = rows.get(self.state.selected().unwrap_or(0)).map(|(_, node)| *node);
+        let lines = selected.map(|node| self.details(node)).unwrap_or_default();
+        frame.render_widget(Paragraph::new(lines)
+            .block(Block::default().borders(Borders::ALL).title(" Details ")), details);
+        frame.render_widget(Paragraph::new(HELP), help);
+    }
+
+    fn details(&self, node: &DeltaNode) -> Vec<Line<'static>> {
+        let path = match node.path.as_os_str().is_empty() {
+            true => ".".to_owned(),
+            false => node.path.display().to_string(),
+        };
+        let mut lines = vec![Line::from(path), Line::from("")];
+        if let Some(entry) = &node.entry {
+            lines.push(Line::from(format!("Kind:          {}", entry.kind)));
+            if let (Some(mode), Some(uid), Some(gid)) = (entry.mode, entry.uid, entry.gid) {
+                lines.push(Line::from(format!("Mode:          {:04o}", mode)));
+                lines.push(Line::from(format!("Owner:         {}/{}", uid, gid)));
+            }
+        }
+        lines.push(Line::from(format!("Delta size:    {}", node.total_delta_size)));
+        lines.push(Line::from(format!("Restored size: {}", node.total_size)));
+        if node.total_size > 0 {
+            lines.push(Line::from(format!("Ratio:         {:.1}%",
+                node.total_delta_size as f64 * 100.0 / node.total_size as f64)));
+        }
+        if !node.children.is_empty() {
+            lines.push(Line::from(format!("Entries:       {}", node.children.len())));
+        }
+
+        if let Some(source_dir) = self.source_dir {
+            let changes = node.changes_from(source_dir);
+            lines.push(Line::from(""));
+            lines.push(Line::from("Changes from the source:"));
+            match changes.is_empty() {
+                true => lines.push(Line::from("  none")),
+                false => lines.extend(changes.into_iter().map(|change| Line::from(format!("  {}", change)))),
+            }
+        }
+        lines
+    }
+}
+
+/// The rows of the expanded part of the tree, with their depths
+fn rows<'a>(root: &'a DeltaNode, expanded: &HashSet<PathBuf>) -> Vec<(usize, &'a DeltaNode)> {
+    fn visit<'a>(node: &'a DeltaNode, depth: usize, expanded: &HashSet<PathBuf>,
+        rows: &mut Vec<(usize, &'a DeltaNode)>)
+    {
+        rows.push((depth, node));
+        if expanded.contains(&node.path) {
+            for child in node.children.iter() {
+                visit(child, depth + 1, expanded, rows);
+            }
+        }
+    }
+
+    let mut rows = vec![];
+    visit(root, 0, expanded, &mut rows);
+    rows
+}
+
+fn row(depth: usize, node: &DeltaNode, expanded: bool) -> String {
+    let marker = match (node.children.is_empty(), expanded) {
+        (true, _) => " ",
+        (false, true) => "▾",
+        (false, false) => "▸",
+    };
+    let kind = node.entry.as_ref().map(|entry| entry.kind.as_str()).unwrap_or("directory");
+    format!("{:>12} {:>12} {:<12} {}{} {}", node.total_delta_size, node.total_size, kind,
+        "  ".repeat(depth), marker, node.name)
+}
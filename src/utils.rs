@@ -1,12 +1,19 @@
-use std::ffi::OsString;
+use std::collections::{hash_map, HashMap};
+use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::io::{Write, BufWriter};
-use std::path::{PathBuf, Path};
-use std::os::unix::prelude::{PermissionsExt, MetadataExt};
+use std::path::{Component, PathBuf, Path};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::time::SystemTime;
 use anyhow::Context;
-use nix::unistd::{Uid, Gid};
 use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::Error;
+use crate::metadata::{SpecialKind, Timestamp};
+use crate::platform::{self, MetadataExt, OsStrExt};
+use crate::sparse::{find_holes, SparseWriter};
 
 pub fn drop_components(nr: usize, path: &Path) -> PathBuf {
     path.components()
@@ -17,55 +24,213 @@ pub fn drop_components(nr: usize, path: &Path) -> PathBuf {
         })
 }
 
-type MetaData = (SystemTime, u32, u32, u32, Vec<(OsString, Vec<u8>)>, u64, u64);
+/// Convert a relative path as stored in the meta-data file
+pub fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    platform::path_from_bytes(bytes)
+}
+
+/// Whether a relative path as stored in the meta-data file stays beneath the
+/// tree it is joined onto, being made of names only
+pub fn is_plain_relative(path: &Path) -> bool {
+    path.components().all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Remember the modification time of the parent directory of `path`, before
+/// it gets modified, so that it can be restored afterwards.
+pub fn save_parent_modtime(saved: &mut HashMap<PathBuf, SystemTime>, path: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        if let hash_map::Entry::Vacant(v) = saved.entry(parent.to_owned()) {
+            v.insert(parent.metadata()?.modified()?);
+        }
+    }
+    Ok(())
+}
+
+/// Restore the modification times saved by `save_parent_modtime`.
+pub fn restore_modtimes(saved: HashMap<PathBuf, SystemTime>) -> anyhow::Result<()> {
+    for (pathname, modified) in saved {
+        let mtime = filetime::FileTime::from_system_time(modified);
+        filetime::set_file_times(&pathname, mtime, mtime).map_err(|e| {
+            crate::Error::FileTimeError(e, pathname.to_owned())
+        })?;
+    }
+    Ok(())
+}
+
+/// Xattrs holding the POSIX ACL of a file, and the default ACL of a directory
+const POSIX_ACL_XATTRS: [&str; 2] = ["system.posix_acl_access", "system.posix_acl_default"];
+
+/// Modification and access times, mode, owner, group, xattrs, inode and device
+pub type MetaData = (Timestamp, Timestamp, u32, u32, u32, Vec<(OsString, Vec<u8>)>, u64, u64);
 
 pub fn get_meta_data(target_path: &Path) -> anyhow::Result<MetaData> {
-    let meta_data = std::fs::metadata(&target_path)?;
-    let modified = meta_data.modified()?;
-    let mode = meta_data.permissions().mode();
-    let uid = meta_data.uid();
-    let gid = meta_data.gid();
-    let ino = meta_data.ino();
-    let dev = meta_data.dev();
+    let meta_data = std::fs::metadata(target_path)?;
+    let modified = filetime::FileTime::from_last_modification_time(&meta_data).into();
+    let accessed = filetime::FileTime::from_last_access_time(&meta_data).into();
+    let (mode, uid, gid, ino, dev) = platform::attributes(&meta_data);
     let mut xattrs = vec![];
 
-    match xattr::list(target_path) {
-        Ok(attributes) => {
-            for attribute in attributes {
-                if let Some(value) = xattr::get(target_path, &attribute)? {
-                    xattrs.push((attribute, value));
-                }
+    for attribute in platform::list_xattrs(target_path) {
+        if let Some(value) = platform::get_xattr(target_path, &attribute)? {
+            xattrs.push((attribute, value));
+        }
+    }
+
+    // Some filesystems leave the ACLs out of the listing
+    for name in POSIX_ACL_XATTRS {
+        if !xattrs.iter().any(|(key, _)| key == name) {
+            if let Ok(Some(value)) = platform::get_xattr(target_path, OsStr::new(name)) {
+                xattrs.push((name.into(), value));
             }
-        },
-        _ => {}
+        }
     }
 
-    return Ok((modified, mode, uid, gid, xattrs, ino, dev));
+    Ok((modified, accessed, mode, uid, gid, xattrs, ino, dev))
 }
 
+/// Apply meta-data taken by `get_meta_data`. Ownership is changed first, as
+/// doing so clears `security.capability` and the setuid bits, which are then
+/// restored along with the other xattrs and the permissions. Write the content
+/// of a file before calling this, since writing clears them too.
 pub fn set_meta_data(target_path: &Path, meta_data: MetaData) -> anyhow::Result<()> {
-    let (modified, mode, uid, gid, xattrs, _, _) = meta_data;
+    set_meta_data_with(target_path, meta_data, true, &mut |_, err| Err(err))
+}
+
+/// As `set_meta_data`, passing the failure to set each attribute, named such
+/// as `owner` or `xattr user.foo`, to `failed`, which returns whether to go on
+pub fn set_meta_data_with(target_path: &Path, meta_data: MetaData, times: bool,
+    failed: &mut dyn FnMut(String, anyhow::Error) -> anyhow::Result<()>) -> anyhow::Result<()>
+{
+    let (modified, accessed, mode, uid, gid, xattrs, _, _) = meta_data;
 
-    nix::unistd::chown(target_path, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid)))
-        .with_context(|| format!("failed to chown"))?;
+    if let Err(err) = platform::chown(target_path, uid, gid)
+        .context("failed to chown")
+    {
+        failed("owner".to_owned(), err)?;
+    }
+
+    if times {
+        filetime::set_file_times(target_path, accessed.into(), modified.into()).map_err(|e| {
+            crate::Error::FileTimeError(e, target_path.to_owned())
+        }).context("failed to set file time")?;
+    }
+
+    let is_acl = |key: &OsString| POSIX_ACL_XATTRS.iter().any(|name| key == name);
+    for (key, value) in xattrs.iter().filter(|(key, _)| !is_acl(key)) {
+        if let Err(err) = platform::set_xattr(target_path, key, value.as_slice())
+            .with_context(|| format!("failed to set xattr {}", key.to_string_lossy()))
+        {
+            failed(format!("xattr {}", key.to_string_lossy()), err)?;
+        }
+    }
+
+    if let Err(err) = platform::set_mode(target_path, mode)
+        .context("failed to set permissions")
+    {
+        failed("permissions".to_owned(), err)?;
+    }
+
+    // ACLs go after the permissions, whose group bits they override with their
+    // mask. ACLs inherited from the default ACL of the parent directory are
+    // dropped if the original file had none.
+    for name in POSIX_ACL_XATTRS {
+        let result = match xattrs.iter().find(|(key, _)| key == name) {
+            Some((key, value)) => platform::set_xattr(target_path, key, value.as_slice())
+                .with_context(|| format!("failed to set ACL {}", name)),
+            None => platform::remove_xattr(target_path, OsStr::new(name))
+                .with_context(|| format!("failed to remove ACL {}", name)),
+        };
+        if let Err(err) = result {
+            failed(format!("ACL {}", name), err)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Copy a directory tree with its ownership, permissions, xattrs and
+/// modification times, preserving the hardlinks, special files and holes of
+/// sparse files within it.
+pub fn copy_tree(source_dir: &Path, dest_dir: &Path) -> anyhow::Result<()> {
+    let n = source_dir.components().count();
+    let mut links = HashMap::new();
+    let mut directories = Vec::new();
 
-    let mtime = filetime::FileTime::from_system_time(modified);
-    filetime::set_file_times(target_path, mtime, mtime).map_err(|e| {
-        crate::Error::FileTimeError(e, target_path.to_owned())
-    }).with_context(|| format!("failed to set file time"))?;
+    for entry in walkdir::WalkDir::new(source_dir) {
+        let entry = entry?;
+        let path = entry.path();
+        let dest_path = dest_dir.join(drop_components(n, path));
+        let file_type = entry.file_type();
 
-    for (key, value) in xattrs {
-        xattr::set(&target_path, key, value.as_slice())
-            .with_context(|| format!("failed to set xattr"))?;
+        if file_type.is_dir() {
+            std::fs::create_dir(&dest_path)
+                .with_context(|| format!("failed creating directory {}", dest_path.display()))?;
+            directories.push((dest_path, get_meta_data(path)?));
+        } else if file_type.is_symlink() {
+            let metadata = entry.metadata()?;
+            platform::symlink(&std::fs::read_link(path)?, &dest_path)
+                .with_context(|| format!("failed creating symlink {}", dest_path.display()))?;
+            set_symlink_owner(&dest_path, metadata.uid(), metadata.gid())
+                .with_context(|| format!("failed to chown symlink {}", dest_path.display()))?;
+        } else if file_type.is_file() {
+            let metadata = entry.metadata()?;
+            if metadata.nlink() >= 2 {
+                match links.entry((metadata.dev(), metadata.ino())) {
+                    hash_map::Entry::Occupied(o) => {
+                        std::fs::hard_link(o.get(), &dest_path)
+                            .with_context(|| format!("failed linking {}", dest_path.display()))?;
+                        continue;
+                    }
+                    hash_map::Entry::Vacant(v) => {
+                        v.insert(dest_path.clone());
+                    }
+                }
+            }
+            copy_file(path, &dest_path)?;
+        } else if let Some(kind) = SpecialKind::of(file_type) {
+            make_special(&dest_path, kind, entry.metadata()?.rdev())
+                .with_context(|| format!("failed creating special file {}", dest_path.display()))?;
+            set_meta_data(&dest_path, get_meta_data(path)?)
+                .with_context(|| format!("failed to set meta-data to {}", dest_path.display()))?;
+        }
     }
 
-    let perm = std::fs::Permissions::from_mode(mode);
-    std::fs::set_permissions(&target_path, perm)
-        .with_context(|| format!("failed to set permissions"))?;
+    // Directory meta-data last, so that the modification times stick
+    for (dest_path, meta_data) in directories {
+        set_meta_data(&dest_path, meta_data)
+            .with_context(|| format!("failed to set meta-data to {}", dest_path.display()))?;
+    }
 
     Ok(())
 }
 
+/// Copy a regular file with its meta-data and holes
+pub(crate) fn copy_file(path: &Path, dest_path: &Path) -> anyhow::Result<()> {
+    let meta_data = get_meta_data(path)?;
+    let holes = find_holes(path)?;
+    let mut dest = SparseWriter::create(dest_path, &holes)?;
+    dest.copy_from(&mut File::open(path)?)
+        .with_context(|| format!("failed copying {} to {}", path.display(),
+                dest_path.display()))?;
+    dest.finish()?;
+    set_meta_data(dest_path, meta_data)
+        .with_context(|| format!("failed to set meta-data to {}", dest_path.display()))
+}
+
+/// Change the ownership of a symlink itself, if it differs
+pub fn set_symlink_owner(path: &Path, uid: u32, gid: u32) -> anyhow::Result<()> {
+    let meta_data = std::fs::symlink_metadata(path)?;
+    if meta_data.uid() != uid || meta_data.gid() != gid {
+        platform::lchown(path, uid, gid)?;
+    }
+    Ok(())
+}
+
+/// Create a FIFO, device node or socket, with no permissions until its
+/// meta-data is set
+pub(crate) fn make_special(path: &Path, kind: SpecialKind, rdev: u64) -> anyhow::Result<()> {
+    platform::mknod(path, kind, rdev)
+}
 
 pub fn serialize_to_json<T>(data: &T, filename: &Path) -> anyhow::Result<()>
     where T: Serialize
@@ -86,3 +251,147 @@ pub fn deserialize_from_json<T>(filename: &Path) -> anyhow::Result<T>
     let data = serde_json::from_reader(file).context("Failed to deserialize data")?;
     Ok(data)
 }
+
+/// A sibling path used to stage a replacement of `path`
+pub fn temp_path_for(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(|x| x.to_owned()).unwrap_or_default();
+    name.push(".deltaimage-tmp");
+    path.with_file_name(name)
+}
+
+/// Whether a path is one returned by `temp_path_for`
+pub fn is_temp_path(path: &Path) -> bool {
+    path.as_os_str().as_bytes().ends_with(b".deltaimage-tmp")
+}
+
+/// Open a file for reading without updating its access time, where permitted,
+/// so that reading the files of the target tree leaves their meta-data as it was
+pub fn open_noatime(path: &Path) -> std::io::Result<File> {
+    platform::open_noatime(path)
+}
+
+/// Open the directory `path`, beneath `root`, one component at a time with
+/// `openat` and `O_NOFOLLOW`, so that a directory of the tree that was
+/// replaced with a symlink is never followed out of it
+pub(crate) fn open_dir_beneath(root: &Path, path: &Path) -> anyhow::Result<platform::Dir> {
+    let rel_path = path.strip_prefix(root).map_err(|_| Error::UnsafePath(path.to_owned()))?;
+    let mut dir = platform::open_dir(root)
+        .with_context(|| format!("failed to open directory {}", root.display()))?;
+
+    for component in rel_path.components() {
+        let Component::Normal(name) = component else {
+            return Err(Error::UnsafePath(path.to_owned()).into());
+        };
+        dir = platform::open_dir_at(&dir, name)
+            .with_context(|| format!("failed to open directory {}", path.display()))?
+            .ok_or_else(|| Error::UnsafePath(path.to_owned()))?;
+    }
+
+    Ok(dir)
+}
+
+/// Check that no directory on the way from `root` to `path` is a symlink,
+/// before `path` gets replaced or removed. `root` itself, as the path of the
+/// top directory of the tree, is beneath it.
+pub fn ensure_beneath(root: &Path, path: &Path) -> anyhow::Result<()> {
+    let parent = match path.strip_prefix(root) {
+        Ok(rel_path) if rel_path.as_os_str().is_empty() => root,
+        _ => path.parent().unwrap_or(root),
+    };
+    match open_dir_beneath(root, parent) {
+        // Nor any of the missing ones
+        Err(err) if err.downcast_ref::<std::io::Error>().map(|e| e.kind()) == Some(std::io::ErrorKind::NotFound)
+            => Ok(()),
+        result => result.map(|_| ()),
+    }
+}
+
+/// Create or truncate the file `path`, beneath `root`, for writing, without
+/// following a symlink at `path` or anywhere on the way to it
+pub fn create_beneath(root: &Path, path: &Path) -> anyhow::Result<File> {
+    let dir = open_dir_beneath(root, path.parent().unwrap_or(root))?;
+    let name = path.file_name().ok_or_else(|| Error::UnsafePath(path.to_owned()))?;
+
+    Ok(platform::create_file_at(&dir, name)
+        .with_context(|| format!("failed to create {}", path.display()))?
+        .ok_or_else(|| Error::UnsafePath(path.to_owned()))?)
+}
+
+/// Read a whole file like `std::fs::read`, without updating its access time
+pub fn read_noatime(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut content = Vec::new();
+    std::io::Read::read_to_end(&mut open_noatime(path)?, &mut content)?;
+    Ok(content)
+}
+
+/// Hex-encoded SHA-256 digest of a buffer
+pub fn digest_bytes(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Hex-encoded SHA-256 digest of a file, read in a streaming fashion
+pub fn digest_file(path: &Path) -> anyhow::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut open_noatime(path)?, &mut hasher)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Year, month and day of a number of days since the epoch, after Howard
+/// Hinnant
+pub(crate) fn civil_date(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+pub fn default_jobs() -> usize {
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+/// Run `f` over all items using up to `jobs` worker threads, returning the
+/// results in the order of the items. Stops at the first error.
+pub fn parallel_map<T, R, F>(jobs: usize, items: &[T], f: F) -> anyhow::Result<Vec<R>>
+    where T: Sync, R: Send, F: Fn(&T) -> anyhow::Result<R> + Sync
+{
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results = Mutex::new(Vec::with_capacity(items.len()));
+    let nr_workers = jobs.clamp(1, items.len().max(1));
+
+    std::thread::scope(|s| {
+        let workers: Vec<_> = (0..nr_workers).map(|_| s.spawn(|| -> anyhow::Result<()> {
+            while !failed.load(Ordering::Relaxed) {
+                let idx = next.fetch_add(1, Ordering::Relaxed);
+                let Some(item) = items.get(idx) else { break };
+                match f(item) {
+                    Ok(result) => results.lock().unwrap().push((idx, result)),
+                    Err(err) => {
+                        failed.store(true, Ordering::Relaxed);
+                        return Err(err);
+                    }
+                }
+            }
+            Ok(())
+        })).collect();
+
+        let mut res = Ok(());
+        for worker in workers {
+            let worker_res = worker.join().expect("worker thread panicked");
+            if res.is_ok() {
+                res = worker_res;
+            }
+        }
+        res
+    })?;
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(idx, _)| *idx);
+    Ok(results.into_iter().map(|(_, result)| result).collect())
+}
@@ -0,0 +1,220 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::Error;
+use crate::backend;
+use crate::encryption::Cipher;
+use crate::metadata::{Algo, MetaData};
+use crate::patch_from;
+use crate::platform::OsStrExt;
+use crate::stream;
+use crate::utils::{parallel_map, default_jobs, path_from_bytes, digest_bytes, digest_file};
+
+/// Options controlling delta verification
+#[derive(Debug, Clone, Default)]
+pub struct VerifyOptions {
+    /// Number of files to decode concurrently, defaulting to the number of CPUs
+    pub jobs: Option<usize>,
+
+    /// Decrypt the stored files of encrypted deltas with the AES-256-GCM key
+    /// stored in this file
+    pub decrypt_key: Option<PathBuf>,
+
+    /// Extra source trees that diff was given, in the same order
+    pub extra_sources: Vec<PathBuf>,
+}
+
+/// A path in the delta directory that failed verification
+#[derive(Debug, Clone)]
+pub struct VerifyProblem {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// Outcome of verifying a delta directory
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// Number of paths listed in the meta-data that were checked
+    pub checked: usize,
+    pub problems: Vec<VerifyProblem>,
+}
+
+impl VerifyReport {
+    /// Turn a report with problems into an error
+    pub fn into_result(self) -> Result<Self, Error> {
+        if self.problems.is_empty() {
+            Ok(self)
+        } else {
+            Err(Error::VerificationFailed(self.problems.len()))
+        }
+    }
+}
+
+/// Checks that a delta directory can be applied to a source tree, without
+/// modifying either of them.
+pub struct DeltaVerifier {
+    source_dir: PathBuf,
+    delta_dir: PathBuf,
+    options: VerifyOptions,
+}
+
+enum Entry {
+    /// Modified file, with the index of the extra source it is patched
+    /// against, if not the source
+    Change(Algo, Option<usize>),
+    /// Unmodified file, and whether its placeholder was left out of the delta
+    Keep(bool),
+}
+
+impl DeltaVerifier {
+    pub fn new(source_dir: impl Into<PathBuf>, delta_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            source_dir: source_dir.into(),
+            delta_dir: delta_dir.into(),
+            options: VerifyOptions::default(),
+        }
+    }
+
+    pub fn options(mut self, options: VerifyOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Check every path listed in the meta-data file, collecting problems
+    /// instead of stopping at the first one.
+    pub fn run(&self) -> anyhow::Result<VerifyReport> {
+        let md = MetaData::load(&self.delta_dir)?;
+        md.check_paths()?;
+        let cipher = Cipher::for_delta(&md, &self.delta_dir, self.options.decrypt_key.as_deref())?;
+        if let Some(missing) = md.extra_sources.get(self.options.extra_sources.len()..).filter(|x| !x.is_empty()) {
+            return Err(Error::MissingExtraSources(missing.to_vec()).into());
+        }
+        let bases: HashMap<_, _> = md.bases.into_iter().map(|(path, base)| (path, base as usize)).collect();
+        let checksums: HashMap<_, _> = md.checksums.into_iter()
+            .map(|(path, checksum)| (path_from_bytes(&path), checksum))
+            .collect();
+
+        let mut entries: Vec<_> = md.changes.into_iter()
+            .map(|(algo, path)| (path_from_bytes(&path), Entry::Change(algo, bases.get(&path).copied())))
+            .collect();
+        let dropped: HashSet<_> = md.placeholders.iter().map(|placeholder| &placeholder.path).collect();
+        entries.extend(md.keep_files.iter()
+            .map(|path| (path_from_bytes(path), Entry::Keep(dropped.contains(path)))));
+
+        let jobs = self.options.jobs.unwrap_or_else(default_jobs);
+        let results = parallel_map(jobs, &entries, |(rel_path, entry)| {
+            let _span = tracing::trace_span!("file", path = %rel_path.display()).entered();
+            tracing::debug!("Verifying {}", rel_path.display());
+            Ok(self.check(rel_path, entry, checksums.get(rel_path), cipher.as_ref()).err())
+        })?;
+
+        let checked = entries.len();
+        let problems = entries.into_iter().zip(results)
+            .filter_map(|((path, _), reason)| reason.map(|reason| VerifyProblem { path, reason }))
+            .collect();
+
+        Ok(VerifyReport { checked, problems })
+    }
+
+    fn check(&self, rel_path: &Path, entry: &Entry, checksum: Option<&String>, cipher: Option<&Cipher>)
+        -> Result<(), String>
+    {
+        let source_path = match entry {
+            Entry::Change(Algo::CopyFrom(from) | Algo::XDelta3From(from), _) => {
+                self.source_dir.join(path_from_bytes(from))
+            }
+            Entry::Change(_, Some(base)) => self.options.extra_sources[*base].join(rel_path),
+            _ => self.source_dir.join(rel_path),
+        };
+        let delta_path = self.delta_dir.join(rel_path);
+
+        // Files stored as they are or compressed may be new files
+        if !source_path.is_file() && !matches!(entry, Entry::Change(Algo::AsIs | Algo::Zstd, _)) {
+            return Err(format!("missing from source: {}", source_path.display()));
+        }
+        let delta_len = match std::fs::symlink_metadata(&delta_path) {
+            Err(_) if matches!(entry, Entry::Keep(true)) => 0,
+            Err(e) => return Err(format!("missing from delta: {}", e)),
+            Ok(delta_meta) if !delta_meta.is_file() => return Err("not a regular file in delta".to_owned()),
+            Ok(delta_meta) => delta_meta.len(),
+        };
+
+        // Digest of the restored content, if it is to be checked
+        let digest = match entry {
+            Entry::Keep(_) | Entry::Change(Algo::CopyFrom(_), _) => {
+                if delta_len != 0 {
+                    return Err(format!("kept file placeholder is {} bytes", delta_len));
+                }
+                checksum.map(|_| digest_file(&source_path)).transpose()
+                    .map_err(|e| e.to_string())?
+            }
+            Entry::Change(Algo::AsIs, _) => match cipher {
+                Some(cipher) => {
+                    let mut hasher = Sha256::new();
+                    let file = std::fs::File::open(&delta_path).map_err(|e| e.to_string())?;
+                    cipher.decrypt_to(std::io::BufReader::new(file), &mut hasher, &delta_path)
+                        .map_err(|e| e.to_string())?;
+                    Some(format!("{:x}", hasher.finalize()))
+                }
+                None => checksum.map(|_| digest_file(&delta_path)).transpose()
+                    .map_err(|e| e.to_string())?,
+            },
+            Entry::Change(algo @ (Algo::Zstd | Algo::XDelta3 | Algo::XDelta3From(_) | Algo::BsDiff
+                | Algo::Deflate), _) => {
+                let backend = backend::for_algo(algo).expect("file stored whole");
+                let orig = match backend.uses_source() {
+                    true => std::fs::read(&source_path).map_err(|e| e.to_string())?,
+                    false => vec![],
+                };
+                let patch_data = match cipher {
+                    Some(cipher) => cipher.decrypt_file(&delta_path),
+                    None => std::fs::read(&delta_path).map_err(Into::into),
+                }.map_err(|e| e.to_string())?;
+                let Some(deflated_content) = backend.decode(&orig, &patch_data) else {
+                    return Err(format!("{} content does not decode against source", algo.name()));
+                };
+                Some(digest_bytes(&deflated_content))
+            }
+            Entry::Change(algo @ (Algo::XDelta3Chunked(_) | Algo::ZstdPatchFrom(_)), _) => {
+                // Encrypted patches are decrypted aside, leaving the delta untouched
+                let decrypted_path = std::env::temp_dir().join(format!("deltaimage-verify-{}-{}",
+                    std::process::id(), digest_bytes(rel_path.as_os_str().as_bytes())));
+                let patch_path = match cipher {
+                    Some(cipher) => {
+                        let output = std::fs::File::create(&decrypted_path).map_err(|e| e.to_string())?;
+                        cipher.decrypt_file_to(&delta_path, output).map_err(|e| e.to_string())?;
+                        &decrypted_path
+                    }
+                    None => &delta_path,
+                };
+                let mut hasher = Sha256::new();
+                let decoded = match algo {
+                    Algo::XDelta3Chunked(chunk_size) => {
+                        stream::decode(&source_path, patch_path, &mut hasher, *chunk_size)
+                            .map_err(|e| format!("chunked patch does not decode against source: {}", e))
+                    }
+                    Algo::ZstdPatchFrom(window_log) => {
+                        patch_from::decode(&source_path, patch_path, &mut hasher, *window_log)
+                            .map_err(|e| format!("zstd patch does not decode against source: {}", e))
+                    }
+                    _ => unreachable!(),
+                };
+                if cipher.is_some() {
+                    let _ = std::fs::remove_file(&decrypted_path);
+                }
+                decoded?;
+                Some(format!("{:x}", hasher.finalize()))
+            }
+        };
+
+        if let (Some(checksum), Some(digest)) = (checksum, digest) {
+            if checksum != &digest {
+                return Err(format!("checksum mismatch: expected {}, got {}", checksum, digest));
+            }
+        }
+
+        Ok(())
+    }
+}
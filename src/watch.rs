@@ -0,0 +1,375 @@
+//! Watch mode, keeping a delta up to date with a target tree as its files
+//! change, for iterating on the content of an image locally.
+//!
+//! Regular files that change are diffed on their own, and the meta-data of
+//! the delta is updated in place. Other changes, such as to directories,
+//! symlinks or hardlinked files, rebuild the whole delta. Changes to the
+//! source tree are not watched.
+//!
+//! The target tree is watched with inotify on Linux, and polled elsewhere.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+#[cfg(not(target_os = "linux"))]
+use std::time::SystemTime;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use walkdir::WalkDir;
+
+use crate::Error;
+use crate::diff::{DeltaBuilder, DiffOptions, FileDiff};
+use crate::filter::PathFilter;
+use crate::journal::DIFF_JOURNAL_FILE;
+use crate::metadata::{is_meta_file, Directory, Holes, MetaData, MetaFormat};
+#[cfg(target_os = "linux")]
+use crate::platform::{wait_events, AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+use crate::platform::{MetadataExt, OsStrExt};
+use crate::sparse::find_holes;
+use crate::tree_digest::tree_digest;
+use crate::utils::{copy_file, drop_components, get_meta_data, path_from_bytes, set_meta_data, temp_path_for};
+
+/// Keeps a delta directory up to date with a target tree, without modifying
+/// the target tree
+pub struct DeltaWatcher {
+    source_dir: PathBuf,
+    target_dir: PathBuf,
+    output: PathBuf,
+    options: DiffOptions,
+    debounce: Duration,
+}
+
+/// Directories of the target tree watched with inotify, by watch descriptor
+#[cfg(target_os = "linux")]
+struct Watches {
+    inotify: Inotify,
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+}
+
+#[cfg(target_os = "linux")]
+impl Watches {
+    fn new() -> anyhow::Result<Self> {
+        Ok(Self { inotify: Inotify::init(InitFlags::IN_CLOEXEC)?, dirs: HashMap::new() })
+    }
+}
+
+/// Kind, size and modification time of each path of the target tree, polled
+/// for changes without inotify
+#[cfg(not(target_os = "linux"))]
+#[derive(Default)]
+struct Watches {
+    entries: HashMap<PathBuf, (bool, u64, Option<SystemTime>)>,
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Watches {
+    fn new() -> anyhow::Result<Self> {
+        Ok(Self::default())
+    }
+}
+
+/// Paths changed in a batch of events
+#[derive(Default)]
+struct Changes {
+    paths: BTreeSet<PathBuf>,
+    /// Changes other than to regular files were seen
+    rebuild: bool,
+}
+
+impl DeltaWatcher {
+    pub fn new(source_dir: impl Into<PathBuf>, target_dir: impl Into<PathBuf>,
+        output: impl Into<PathBuf>) -> Self
+    {
+        Self {
+            source_dir: source_dir.into(),
+            target_dir: target_dir.into(),
+            output: output.into(),
+            options: DiffOptions::default(),
+            debounce: Duration::from_millis(300),
+        }
+    }
+
+    pub fn options(mut self, options: DiffOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// How long to wait for more changes before updating the delta, so that
+    /// files being saved are handled once
+    pub fn debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// Build the delta, then update it on every change to the target tree,
+    /// only returning on error
+    pub fn run(&self) -> anyhow::Result<()> {
+        let unsupported = [
+            (self.options.bidirectional, "--bidirectional"),
+            (self.options.archive.is_some(), "archives"),
+            (self.options.sign_key.is_some(), "signing"),
+            (self.options.encrypt_key.is_some(), "encryption"),
+            (self.options.dry_run, "dry runs"),
+            (!self.options.extra_sources.is_empty(), "extra sources"),
+            (self.options.compress_new_files, "--compress-new-files"),
+            (self.options.drop_placeholders, "--drop-placeholders"),
+        ];
+        if let Some(&(_, option)) = unsupported.iter().find(|(set, _)| *set) {
+            return Err(Error::UnsupportedWatchOption(option).into());
+        }
+
+        let filter = PathFilter::new(&self.options.exclude, &self.options.include)?;
+        let mut watches = Watches::new()?;
+
+        // Watched first, not to miss the changes made while building
+        self.add_watches(&mut watches)?;
+        self.build_span().in_scope(|| self.rebuild())?;
+
+        loop {
+            let changes = self.next_changes(&mut watches, &filter)?;
+            let _span = self.build_span().entered();
+            let started = Instant::now();
+            if changes.rebuild || !self.update(&changes.paths, &filter)? {
+                self.add_watches(&mut watches)?;
+                self.rebuild()?;
+            } else {
+                tracing::info!("Updated {} paths in {:.2}s", changes.paths.len(),
+                    started.elapsed().as_secs_f64());
+            }
+        }
+    }
+
+    /// Span of each build or update of the delta
+    fn build_span(&self) -> tracing::Span {
+        tracing::info_span!("build", output = %self.output.display())
+    }
+
+    /// Watch every directory of the target tree, as inotify does not recurse
+    #[cfg(target_os = "linux")]
+    fn add_watches(&self, watches: &mut Watches) -> anyhow::Result<()> {
+        let mask = AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_ATTRIB | AddWatchFlags::IN_CREATE
+            | AddWatchFlags::IN_DELETE | AddWatchFlags::IN_MOVED_FROM | AddWatchFlags::IN_MOVED_TO
+            | AddWatchFlags::IN_ONLYDIR | AddWatchFlags::IN_DONT_FOLLOW;
+        let n = self.target_dir.components().count();
+
+        for entry in WalkDir::new(&self.target_dir) {
+            let entry = entry?;
+            if entry.file_type().is_dir() {
+                let wd = watches.inotify.add_watch(entry.path(), mask)
+                    .with_context(|| format!("failed to watch {}", entry.path().display()))?;
+                watches.dirs.insert(wd, drop_components(n, entry.path()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Wait for changes, then gather those that follow until the tree is
+    /// quiet for the debounce time
+    #[cfg(target_os = "linux")]
+    fn next_changes(&self, watches: &mut Watches, filter: &PathFilter) -> anyhow::Result<Changes> {
+        let mut changes = Changes::default();
+
+        loop {
+            for event in watches.inotify.read_events()? {
+                if event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+                    changes.rebuild = true;
+                    continue;
+                }
+                if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+                    watches.dirs.remove(&event.wd);
+                    continue;
+                }
+                let (Some(dir), Some(name)) = (watches.dirs.get(&event.wd), &event.name) else {
+                    // The watched directory itself changed
+                    changes.rebuild = true;
+                    continue;
+                };
+                let rel_path = dir.join(name);
+                if filter.is_excluded(&rel_path) {
+                    continue;
+                }
+                if event.mask.contains(AddWatchFlags::IN_ISDIR) {
+                    changes.rebuild = true;
+                }
+                changes.paths.insert(rel_path);
+            }
+
+            let quiet = !wait_events(&watches.inotify, self.debounce)?;
+            if quiet && (changes.rebuild || !changes.paths.is_empty()) {
+                return Ok(changes);
+            }
+        }
+    }
+
+    /// Take the state of the target tree, to compare the next polls with
+    #[cfg(not(target_os = "linux"))]
+    fn add_watches(&self, watches: &mut Watches) -> anyhow::Result<()> {
+        let n = self.target_dir.components().count();
+        watches.entries.clear();
+        for entry in WalkDir::new(&self.target_dir) {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            watches.entries.insert(drop_components(n, entry.path()),
+                (metadata.is_dir(), metadata.len(), metadata.modified().ok()));
+        }
+        Ok(())
+    }
+
+    /// Poll the target tree every debounce time, until it changed and then
+    /// stayed the same for one poll
+    #[cfg(not(target_os = "linux"))]
+    fn next_changes(&self, watches: &mut Watches, filter: &PathFilter) -> anyhow::Result<Changes> {
+        let mut changes = Changes::default();
+
+        loop {
+            std::thread::sleep(self.debounce);
+            let previous = std::mem::take(&mut watches.entries);
+            self.add_watches(watches)?;
+
+            let paths: BTreeSet<_> = previous.keys().chain(watches.entries.keys())
+                .filter(|rel_path| previous.get(*rel_path) != watches.entries.get(*rel_path))
+                .filter(|rel_path| !filter.is_excluded(rel_path))
+                .cloned()
+                .collect();
+            let mut changed = false;
+            for rel_path in paths {
+                match (previous.get(&rel_path), watches.entries.get(&rel_path)) {
+                    // Directories change along with their content
+                    (Some((true, ..)), Some((true, ..))) => continue,
+                    (Some((true, ..)), _) | (_, Some((true, ..))) => changes.rebuild = true,
+                    _ => {}
+                }
+                changed = true;
+                changes.paths.insert(rel_path);
+            }
+            if !changed && (changes.rebuild || !changes.paths.is_empty()) {
+                return Ok(changes);
+            }
+        }
+    }
+
+    /// Remove the previous delta, and diff the whole target tree again
+    fn rebuild(&self) -> anyhow::Result<()> {
+        let started = Instant::now();
+        if MetaData::exists(&self.output) || self.output.join(DIFF_JOURNAL_FILE).exists() {
+            std::fs::remove_dir_all(&self.output)
+                .with_context(|| format!("failed removing {}", self.output.display()))?;
+        } else if std::fs::symlink_metadata(&self.output).is_ok() {
+            return Err(Error::DeltaDirExists(self.output.clone()).into());
+        }
+
+        let options = DiffOptions { output: Some(self.output.clone()), ..self.options.clone() };
+        let stats = DeltaBuilder::new(&self.source_dir, &self.target_dir).options(options).run()?;
+        tracing::info!("Built in {:.2}s, total size: {}, reduced size: {}",
+            started.elapsed().as_secs_f64(), stats.total_size, stats.reduced_size);
+        Ok(())
+    }
+
+    /// Diff the changed paths on their own, returning false if the whole
+    /// delta needs to be rebuilt instead
+    fn update(&self, paths: &BTreeSet<PathBuf>, filter: &PathFilter) -> anyhow::Result<bool> {
+        let format = MetaFormat::of(&self.output).unwrap_or(self.options.meta_format);
+        let mut md = MetaData::load(&self.output)?;
+        let builder = DeltaBuilder::new(&self.source_dir, &self.output).options(self.options.clone());
+        let linked: HashSet<_> = md.link_groups.iter().flatten()
+            .flat_map(|group| group.paths.iter())
+            .collect();
+        let is_plain_file = |metadata: &std::fs::Metadata| metadata.is_file() && metadata.nlink() < 2;
+
+        // Checked first, not to leave a delta half updated
+        for rel_path in paths {
+            let key = rel_path.as_os_str().as_bytes().to_owned();
+            let target = std::fs::symlink_metadata(self.target_dir.join(rel_path));
+            let delta = std::fs::symlink_metadata(self.output.join(rel_path));
+            if linked.contains(&key) || target.as_ref().is_ok_and(|metadata| !is_plain_file(metadata))
+                || delta.as_ref().is_ok_and(|metadata| !is_plain_file(metadata))
+            {
+                return Ok(false);
+            }
+        }
+        let parents: BTreeSet<_> = paths.iter()
+            .map(|rel_path| rel_path.parent().unwrap_or(Path::new("")).to_owned())
+            .collect();
+        let parent_keys: Vec<_> = parents.iter().map(|parent| parent.as_os_str().as_bytes()).collect();
+        if parent_keys.iter().any(|key| !md.directories.iter().any(|dir| dir.path == *key)) {
+            return Ok(false);
+        }
+
+        for rel_path in paths {
+            let key = rel_path.as_os_str().as_bytes().to_owned();
+            let target_path = self.target_dir.join(rel_path);
+            let output_path = self.output.join(rel_path);
+            let in_source = std::fs::symlink_metadata(self.source_dir.join(rel_path))
+                .is_ok_and(|metadata| metadata.is_file());
+            forget(&mut md, &key);
+
+            if std::fs::symlink_metadata(&target_path).is_err() {
+                if output_path.exists() {
+                    std::fs::remove_file(&output_path)
+                        .with_context(|| format!("failed removing {}", output_path.display()))?;
+                }
+                if in_source {
+                    md.deleted_files.push(key);
+                }
+                continue;
+            }
+
+            let tmp_path = temp_path_for(&output_path);
+            copy_file(&target_path, &tmp_path)?;
+            std::fs::rename(&tmp_path, &output_path)
+                .with_context(|| format!("failed replacing {}", output_path.display()))?;
+
+            // New files are stored as they are
+            if in_source {
+                let holes = find_holes(&output_path)?;
+                let mut result = builder.diff_file(rel_path)?;
+                result.commit(&output_path, None)?;
+                record(&mut md, key, result, holes);
+            }
+        }
+
+        for parent in &parents {
+            let meta_data = get_meta_data(&self.target_dir.join(parent))?;
+            let key = parent.as_os_str().as_bytes();
+            if let Some(dir) = md.directories.iter_mut().find(|dir| dir.path == key) {
+                *dir = Directory::new(parent, meta_data.clone());
+            }
+            set_meta_data(&self.output.join(parent), meta_data)?;
+        }
+
+        if md.tree_digest.is_some() {
+            let known: HashMap<_, _> = md.checksums.iter()
+                .map(|(rel_path, checksum)| (path_from_bytes(rel_path), checksum.clone()))
+                .collect();
+            // As it was digested before the meta-data was written
+            let skip = |rel_path: &Path| is_meta_file(rel_path) || filter.is_excluded(rel_path);
+            md.tree_digest = Some(tree_digest(&self.output, skip, &known)?);
+        }
+        md.save_as(&self.output, format)?;
+
+        Ok(true)
+    }
+}
+
+/// Remove whatever the meta-data records of a file
+fn forget(md: &mut MetaData, key: &[u8]) {
+    md.keep_files.retain(|path| path != key);
+    md.changes.retain(|(_, path)| path != key);
+    md.checksums.retain(|(path, _)| path != key);
+    md.sizes.retain(|(path, _)| path != key);
+    md.sparse.retain(|(path, _)| path != key);
+    md.deleted_files.retain(|path| path != key);
+}
+
+fn record(md: &mut MetaData, key: Vec<u8>, result: FileDiff, holes: Holes) {
+    md.checksums.push((key.clone(), result.checksum));
+    md.sizes.push((key.clone(), result.total_size));
+    if !holes.is_empty() {
+        md.sparse.push((key.clone(), holes));
+    }
+    match result.algo {
+        Some(algo) => md.changes.push((algo, key)),
+        None => md.keep_files.push(key),
+    }
+}
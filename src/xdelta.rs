@@ -0,0 +1,109 @@
+//! xdelta3 encoding with the tuning that the `xdelta3` crate does not expose.
+//!
+//! The crate links the xdelta3 library, whose `xd3_encode_memory` takes the
+//! compression level and the secondary compressor as flags. Patches carry
+//! what is needed to decode them, so decoding still goes through the crate.
+
+use std::os::raw::c_int;
+use std::str::FromStr;
+
+use serde::{Serialize, Deserialize};
+
+use crate::stream::STREAM_CHUNK_SIZE;
+
+const XD3_SEC_DJW: c_int = 1 << 5;
+const XD3_SEC_FGK: c_int = 1 << 6;
+const XD3_NOCOMPRESS: c_int = 1 << 13;
+const XD3_COMPLEVEL_SHIFT: c_int = 20;
+
+extern "C" {
+    fn xd3_encode_memory(input: *const u8, input_size: u32, source: *const u8, source_size: u32,
+        output_buffer: *mut u8, output_size: *mut u32, avail_output: u32, flags: c_int) -> c_int;
+}
+
+/// Secondary compression of the instructions and data of xdelta3 patches
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum XDelta3Secondary {
+    #[default]
+    None,
+    /// Static Huffman coding
+    Djw,
+    /// Adaptive Huffman coding
+    Fgk,
+}
+
+impl FromStr for XDelta3Secondary {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(XDelta3Secondary::None),
+            "djw" => Ok(XDelta3Secondary::Djw),
+            "fgk" => Ok(XDelta3Secondary::Fgk),
+            _ => Err(format!("unknown secondary compression {}", s)),
+        }
+    }
+}
+
+/// Tuning of xdelta3 encoding, trading encode time for smaller patches
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct XDelta3Params {
+    /// Compression level from 0, for none, to 9, or the xdelta3 default
+    pub level: Option<u8>,
+
+    pub secondary: XDelta3Secondary,
+
+    /// Size of the chunks that files above the stream threshold are encoded
+    /// in, each one against a source window three times as large. Must not
+    /// be zero.
+    pub window: u64,
+}
+
+impl Default for XDelta3Params {
+    fn default() -> Self {
+        Self {
+            level: None,
+            secondary: XDelta3Secondary::None,
+            window: STREAM_CHUNK_SIZE,
+        }
+    }
+}
+
+impl XDelta3Params {
+    fn flags(&self) -> c_int {
+        let level = match self.level {
+            None => 0,
+            Some(0) => XD3_NOCOMPRESS,
+            Some(level) => c_int::from(level.min(9)) << XD3_COMPLEVEL_SHIFT,
+        };
+        let secondary = match self.secondary {
+            XDelta3Secondary::None => 0,
+            XDelta3Secondary::Djw => XD3_SEC_DJW,
+            XDelta3Secondary::Fgk => XD3_SEC_FGK,
+        };
+        level | secondary
+    }
+}
+
+/// Encode `input` against `src`, like `xdelta3::encode`
+pub(crate) fn encode(input: &[u8], src: &[u8], params: &XDelta3Params) -> Option<Vec<u8>> {
+    let input_len = u32::try_from(input.len()).ok()?;
+    let src_len = u32::try_from(src.len()).ok()?;
+    // With room for the headers of the patches of small files
+    let avail_output = input_len.checked_add(src_len)?.checked_mul(2)?.checked_add(1024)?;
+    let mut output = Vec::with_capacity(avail_output as usize);
+    let mut output_len = 0;
+
+    // SAFETY: the buffers are valid for the given sizes, and xdelta3 writes
+    // at most `avail_output` bytes to the output one
+    unsafe {
+        let result = xd3_encode_memory(input.as_ptr(), input_len, src.as_ptr(), src_len,
+            output.as_mut_ptr(), &mut output_len, avail_output, params.flags());
+        if result != 0 {
+            return None;
+        }
+        output.set_len(output_len as usize);
+    }
+
+    Some(output)
+}
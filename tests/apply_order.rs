@@ -0,0 +1,44 @@
+//! Order in which apply restores the files of a delta
+
+mod common;
+
+use std::path::Path;
+
+use deltaimage::{record_apply_order, ApplyOptions, DeltaApplier, MetaData, Report};
+
+use common::{diff, write_tree, Scratch};
+
+const SOURCE: &[(&str, &str)] = &[("a", "old a\n"), ("b", "old b\n"), ("c", "old c\n"), ("kept", "kept\n")];
+const TARGET: &[(&str, &str)] = &[("a", "new a\n"), ("b", "new b\n"), ("c", "new c\n"), ("kept", "kept\n")];
+
+fn make_delta(source: &Path, delta: &Path) {
+    write_tree(source, SOURCE);
+    write_tree(delta, TARGET);
+    diff(source, delta);
+}
+
+fn restored_order(source: &Path, delta: &Path, priority: &[&str]) -> Vec<String> {
+    let options = ApplyOptions { priority: priority.iter().map(|glob| glob.to_string()).collect(),
+        ..Default::default() };
+    let stats = DeltaApplier::new(source, delta).options(options).run().unwrap();
+    stats.files.into_iter().map(|file| file.path).collect()
+}
+
+#[test]
+fn restores_slowest_files_first() {
+    let scratch = Scratch::new("apply-order");
+    let (source, trial, delta) = (scratch.join("source"), scratch.join("trial"), scratch.join("delta"));
+    make_delta(&source, &trial);
+    let mut report = Report::new(DeltaApplier::new(&source, &trial).run().unwrap().files, Default::default());
+    for file in report.files.iter_mut() {
+        file.duration_secs = match file.path.as_str() { "b" => 3.0, "c" => 2.0, _ => 1.0 };
+    }
+
+    std::fs::remove_dir_all(&source).unwrap();
+    make_delta(&source, &delta);
+    assert_eq!(record_apply_order(&delta, &report, None).unwrap(), 3);
+    assert_eq!(MetaData::load(&delta).unwrap().apply_order,
+        Some(vec![b"b".to_vec(), b"c".to_vec(), b"a".to_vec()]));
+
+    assert_eq!(restored_order(&source, &delta, &["/a"]), ["a", "b", "c", "kept"]);
+}
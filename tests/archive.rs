@@ -0,0 +1,143 @@
+//! Unpacking of delta archives, including ones whose entries point out of
+//! the tree they are unpacked to
+
+#![cfg(unix)]
+
+mod common;
+
+use std::path::Path;
+
+use deltaimage::{pack_archive, unpack_archive, Error};
+
+use common::{read_tree, write_tree, Scratch};
+
+/// An archive written entry by entry, in the layout of `pack_archive`
+struct Archive {
+    data: Vec<u8>,
+    index: Vec<(Vec<u8>, u64)>,
+}
+
+impl Archive {
+    fn new() -> Self {
+        let mut data = b"DELTAIMGARCH".to_vec();
+        data.extend(2u32.to_le_bytes());
+        Archive { data, index: vec![] }.dir("")
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend((bytes.len() as u32).to_le_bytes());
+        self.data.extend(bytes);
+    }
+
+    fn entry(&mut self, kind: u8, path: &str, mode: Option<u32>) {
+        self.index.push((path.as_bytes().to_vec(), self.data.len() as u64));
+        self.data.push(kind);
+        self.bytes(path.as_bytes());
+        if let Some(mode) = mode {
+            // Mode, owner, modification and access times, no xattrs
+            for value in [mode, 0, 0] {
+                self.data.extend(value.to_le_bytes());
+            }
+            for _ in 0..2 {
+                self.data.extend(0u64.to_le_bytes());
+                self.data.extend(0u32.to_le_bytes());
+            }
+            self.data.extend(0u32.to_le_bytes());
+        }
+    }
+
+    fn dir(mut self, path: &str) -> Self {
+        self.entry(0, path, Some(0o40755));
+        self
+    }
+
+    fn file(mut self, path: &str, content: &str) -> Self {
+        self.entry(1, path, Some(0o100644));
+        self.data.extend((content.len() as u64).to_le_bytes());
+        self.data.extend(content.as_bytes());
+        self
+    }
+
+    fn symlink(mut self, path: &str, target: &Path) -> Self {
+        self.entry(2, path, Some(0o120777));
+        self.bytes(target.as_os_str().as_encoded_bytes());
+        self
+    }
+
+    fn hardlink(mut self, path: &str, first: &str) -> Self {
+        self.entry(3, path, None);
+        self.bytes(first.as_bytes());
+        self
+    }
+
+    fn write(mut self, path: &Path) {
+        let (index_offset, nr_entries) = (self.data.len() as u64, self.index.len() as u64);
+        for (entry_path, offset) in std::mem::take(&mut self.index) {
+            self.bytes(&entry_path);
+            self.data.extend(offset.to_le_bytes());
+        }
+        self.data.extend(index_offset.to_le_bytes());
+        self.data.extend(nr_entries.to_le_bytes());
+        self.data.extend(b"DELTAIDX");
+        std::fs::write(path, self.data).unwrap();
+    }
+}
+
+/// Unpack a hand-made archive, which must be refused without anything
+/// written next to the tree
+fn unpack_unsafe(name: &str, archive: impl FnOnce(&Scratch) -> Archive) {
+    let scratch = Scratch::new(name);
+    let outside = scratch.join("outside");
+    write_tree(&outside, &[("secret", "secret\n")]);
+    archive(&scratch).write(&scratch.join("archive"));
+
+    let err = unpack_archive(&scratch.join("archive"), &scratch.join("delta")).expect_err("unpack succeeded");
+    assert!(matches!(err.downcast_ref::<Error>(), Some(Error::UnsafePath(_))), "unexpected error: {:?}", err);
+    assert_eq!(read_tree(&outside).into_keys().collect::<Vec<_>>(), [Path::new("secret")]);
+    assert_eq!(std::fs::read_to_string(outside.join("secret")).unwrap(), "secret\n");
+}
+
+#[test]
+fn parent_dir_entry() {
+    unpack_unsafe("archive-parent-dir", |_| Archive::new().file("../outside/planted", "planted\n"));
+}
+
+#[test]
+fn absolute_entry() {
+    unpack_unsafe("archive-absolute", |scratch| {
+        Archive::new().file(scratch.join("outside/planted").to_str().unwrap(), "planted\n")
+    });
+}
+
+#[test]
+fn entry_through_symlink() {
+    unpack_unsafe("archive-symlink", |scratch| {
+        Archive::new().symlink("escape", &scratch.join("outside")).file("escape/planted", "planted\n")
+    });
+}
+
+#[test]
+fn file_over_symlink() {
+    unpack_unsafe("archive-file-symlink", |scratch| {
+        Archive::new().symlink("escape", &scratch.join("outside/secret")).file("escape", "planted\n")
+    });
+}
+
+#[test]
+fn hardlink_outside() {
+    unpack_unsafe("archive-hardlink", |_| Archive::new().hardlink("stolen", "../outside/secret"));
+}
+
+#[test]
+fn pack_and_unpack() {
+    let scratch = Scratch::new("archive-roundtrip");
+    let (tree, delta) = (scratch.join("tree"), scratch.join("delta"));
+    write_tree(&tree, &[("file", "file\n"), ("dir/nested", "nested\n")]);
+    std::os::unix::fs::symlink("file", tree.join("dir/link")).unwrap();
+    std::fs::hard_link(tree.join("file"), tree.join("dir/hardlink")).unwrap();
+
+    pack_archive(&tree, &scratch.join("archive")).unwrap();
+    unpack_archive(&scratch.join("archive"), &delta).unwrap();
+    assert_eq!(read_tree(&delta), read_tree(&tree));
+    assert_eq!(std::fs::read_link(delta.join("dir/link")).unwrap(), Path::new("file"));
+}
@@ -0,0 +1,47 @@
+//! Applies going on when attributes cannot be set, which run as root does
+//! not come across
+
+#![cfg(unix)]
+
+mod common;
+
+use deltaimage::pack_archive;
+
+use common::{deltaimage, deltaimage_error, diff, read_tree, write_tree, Scratch};
+
+#[test]
+fn reports_unapplied_owners() {
+    if nix::unistd::geteuid().is_root() {
+        return;
+    }
+    let scratch = Scratch::new("best-effort");
+    let (source, delta, archive) = (scratch.join("source"), scratch.join("delta"), scratch.join("delta.arch"));
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n")]);
+    write_tree(&delta, &[("kept", "kept\n"), ("changed", "new content\n")]);
+    let target = read_tree(&delta);
+    diff(&source, &delta);
+    pack_archive(&delta, &archive).unwrap();
+
+    // Restoring the files of the user running apply as owned by root
+    let (map, report) = (scratch.join("map"), scratch.join("report.json"));
+    std::fs::write(&map, format!("u {} 0 1\ng {} 0 1\n", nix::unistd::geteuid(), nix::unistd::getegid()))
+        .unwrap();
+    let args = ["apply", "--format", "archive", "--archive", archive.to_str().unwrap(), "--owner-map",
+        map.to_str().unwrap()];
+
+    let restored = scratch.join("failed");
+    let err = deltaimage_error(&[&args[..], &[source.to_str().unwrap(), restored.to_str().unwrap()]].concat());
+    assert!(err.contains("failed to chown"), "{}", err);
+
+    let restored = scratch.join("restored");
+    deltaimage(&[&args[..], &["--best-effort-metadata", "--report", report.to_str().unwrap()]].concat(),
+        &[&source, &restored]);
+    assert_eq!(read_tree(&restored), target);
+
+    let report: serde_json::Value = serde_json::from_slice(&std::fs::read(&report).unwrap()).unwrap();
+    let unapplied = report["unapplied_metadata"].as_array().unwrap();
+    for path in ["", "kept", "changed"] {
+        assert!(unapplied.iter().any(|entry| entry["path"] == path && entry["attribute"] == "owner"),
+            "{} not reported: {:?}", path, unapplied);
+    }
+}
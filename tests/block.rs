@@ -0,0 +1,56 @@
+//! Block-level deltas of disk images by diff-block and apply-block
+
+mod common;
+
+use deltaimage::{apply_block, diff_block, BlockDiffOptions, Chunking};
+
+use common::Scratch;
+
+const CHUNK: usize = 64 << 10;
+
+/// Bytes that do not repeat nor compress
+fn noise(seed: u64, len: usize) -> Vec<u8> {
+    let mut state = seed;
+    (0..len).map(|_| {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (state >> 33) as u8
+    }).collect()
+}
+
+fn roundtrip(name: &str, chunking: Chunking, source: &[u8], target: &[u8]) -> deltaimage::BlockStats {
+    let scratch = Scratch::new(name);
+    let (source_path, target_path) = (scratch.join("source.img"), scratch.join("target.img"));
+    let (delta, restored) = (scratch.join("image.blkdelta"), scratch.join("restored.img"));
+    std::fs::write(&source_path, source).unwrap();
+    std::fs::write(&target_path, target).unwrap();
+
+    let options = BlockDiffOptions { chunking, chunk_size: CHUNK as u64, ..Default::default() };
+    let stats = diff_block(&source_path, &target_path, &delta, &options).unwrap();
+    apply_block(&source_path, &delta, &restored).unwrap();
+    assert!(std::fs::read(&restored).unwrap() == target);
+    stats
+}
+
+#[test]
+fn restores_fixed_chunks() {
+    let source = noise(1, 4 * CHUNK);
+    let mut target = source.clone();
+    target[CHUNK..2 * CHUNK].fill(0);
+    target[2 * CHUNK + 100..2 * CHUNK + 200].fill(7);
+    target[3 * CHUNK..].copy_from_slice(&noise(2, CHUNK));
+    target.extend(noise(3, CHUNK / 2));
+
+    let stats = roundtrip("block-fixed", Chunking::Fixed, &source, &target);
+    assert_eq!(stats.target_size, target.len() as u64);
+    assert_eq!((stats.copied, stats.zero), (CHUNK as u64, CHUNK as u64));
+}
+
+#[test]
+fn finds_shifted_chunks() {
+    let source = noise(1, 8 * CHUNK);
+    let mut target = noise(4, 1000);
+    target.extend(&source);
+
+    let stats = roundtrip("block-content-defined", Chunking::ContentDefined, &source, &target);
+    assert!(stats.copied > 4 * CHUNK as u64, "{:?}", stats);
+}
@@ -0,0 +1,49 @@
+//! Reuse of encoded files between diffs through `--cache-dir`
+
+mod common;
+
+use std::path::Path;
+
+use common::{deltaimage, read_tree, read_tree_bytes, write_tree, Scratch};
+
+fn diff_cached(scratch: &Scratch, name: &str, cache: &Path) -> std::path::PathBuf {
+    let (source, delta) = (scratch.join("source"), scratch.join(name));
+    let old = "content of a modified file\n".repeat(100);
+    write_tree(&source, &[("changed", old.as_str()), ("large", old.as_str())]);
+    write_tree(&delta, &[("changed", &old.replacen("modified", "MODIFIED", 10)),
+        ("large", &old.replacen("file", "FILE", 10))]);
+    deltaimage(&["diff", "--stream-threshold", "2000", "--cache-dir", cache.to_str().unwrap()],
+        &[&source, &delta]);
+    delta
+}
+
+#[test]
+fn reuses_cached_encodings() {
+    let scratch = Scratch::new("cache-reuse");
+    let cache = scratch.join("cache");
+    let first = diff_cached(&scratch, "first", &cache);
+    let entries = read_tree_bytes(&cache);
+    assert_eq!(entries.len(), 2);
+
+    let second = diff_cached(&scratch, "second", &cache);
+    assert_eq!(read_tree_bytes(&cache), entries);
+    assert_eq!(read_tree_bytes(&second).get(Path::new("changed")), read_tree_bytes(&first).get(Path::new("changed")));
+    deltaimage(&["apply"], &[&scratch.join("source"), &second]);
+    assert_eq!(read_tree(&second).get(Path::new("large")).unwrap(),
+        &"content of a modified file\n".repeat(100).replacen("file", "FILE", 10));
+}
+
+#[test]
+fn ignores_corrupted_entries() {
+    let scratch = Scratch::new("cache-corrupted");
+    let cache = scratch.join("cache");
+    diff_cached(&scratch, "first", &cache);
+    for path in read_tree_bytes(&cache).keys() {
+        std::fs::write(cache.join(path), "\"XDelta3\"\ngarbage").unwrap();
+    }
+
+    let delta = diff_cached(&scratch, "second", &cache);
+    deltaimage(&["apply"], &[&scratch.join("source"), &delta]);
+    let old = "content of a modified file\n".repeat(100);
+    assert_eq!(read_tree(&delta).get(Path::new("changed")).unwrap(), &old.replacen("modified", "MODIFIED", 10));
+}
@@ -0,0 +1,55 @@
+//! Catalogs of deltas between image digests, and chains resolved from them
+
+mod common;
+
+use deltaimage::{Catalog, CatalogEntry, Error};
+
+use common::{deltaimage_output, Scratch};
+
+fn entry(from: &str, to: &str, size: u64) -> CatalogEntry {
+    CatalogEntry { from: from.to_owned(), to: to.to_owned(), size, location: None }
+}
+
+fn resolve<'a>(catalog: &'a Catalog, from: &str, to: &str) -> Vec<(&'a str, &'a str)> {
+    catalog.resolve(from, to).unwrap().into_iter().map(|delta| (&delta.from[..], &delta.to[..])).collect()
+}
+
+#[test]
+fn resolves_cheapest_chain() {
+    let mut catalog = Catalog::default();
+    for delta in [entry("1", "2", 10), entry("2", "3", 10), entry("1", "3", 30), entry("3", "4", 5),
+        entry("2", "4", 15)]
+    {
+        catalog.add(delta);
+    }
+    assert_eq!(resolve(&catalog, "1", "3"), [("1", "2"), ("2", "3")]);
+    // Fewer deltas on ties
+    assert_eq!(resolve(&catalog, "1", "4"), [("1", "2"), ("2", "4")]);
+    assert_eq!(resolve(&catalog, "3", "3"), []);
+
+    // Replacing the delta between the same digests
+    catalog.add(entry("1", "3", 5));
+    assert_eq!(catalog.deltas.len(), 5);
+    assert_eq!(resolve(&catalog, "1", "4"), [("1", "3"), ("3", "4")]);
+
+    let err = catalog.resolve("4", "1").expect_err("resolved");
+    assert!(matches!(err.downcast_ref::<Error>(), Some(Error::NoDeltaChain(..))), "{:?}", err);
+}
+
+#[test]
+fn adds_to_catalog_files() {
+    let scratch = Scratch::new("catalog-files");
+    let delta = scratch.join("delta.arch");
+    std::fs::write(&delta, "0123456789").unwrap();
+
+    for name in ["catalog.json", "catalog.cbor"] {
+        let catalog = scratch.join(name);
+        let catalog = catalog.to_str().unwrap();
+        deltaimage_output(&["catalog-add", catalog, delta.to_str().unwrap(), "--from", "a", "--to", "b",
+            "--location", "https://cdn.example.com/a-b.arch"]);
+        deltaimage_output(&["catalog-add", catalog, delta.to_str().unwrap(), "--from", "b", "--to", "c"]);
+
+        let resolved = deltaimage_output(&["resolve", catalog, "--from", "a", "--to", "c"]);
+        assert_eq!(resolved, "a -> b: 10 https://cdn.example.com/a-b.arch\nb -> c: 10 -\nTotal size: 20\n");
+    }
+}
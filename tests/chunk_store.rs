@@ -0,0 +1,78 @@
+//! Deltas exported into a content-addressed chunk store and restored from it
+
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use deltaimage::{assemble_chunks, export_chunks, ChunkIndex, ChunkOptions, Error, FetchOptions};
+
+use common::{deltaimage, diff, read_tree, write_tree, Scratch};
+
+/// Content that compresses poorly, for the chunks to be cut within it
+fn noise(seed: u64, lines: u64) -> String {
+    (0..lines).map(|i| format!("{:x}\n", (seed + i).wrapping_mul(0x9e3779b97f4a7c15))).collect()
+}
+
+fn options() -> ChunkOptions {
+    ChunkOptions { chunk_size: 1024, ..Default::default() }
+}
+
+/// A delta of `target` files against a source tree, at `delta`
+fn make_delta(scratch: &Scratch, delta: &str, target: &[(&str, &str)]) -> (PathBuf, PathBuf) {
+    let (source, delta) = (scratch.join("source"), scratch.join(delta));
+    if !source.exists() {
+        write_tree(&source, &[("kept", "kept\n")]);
+    }
+    write_tree(&delta, target);
+    diff(&source, &delta);
+    (source, delta)
+}
+
+#[test]
+fn restores_from_chunk_store() {
+    let scratch = Scratch::new("chunks-restore");
+    let added = noise(1, 4000);
+    let (source, delta) = make_delta(&scratch, "delta", &[("kept", "kept\n"), ("added", &added)]);
+    let (store, index, restored) = (scratch.join("store"), scratch.join("delta.idx"), scratch.join("restored"));
+
+    let stats = export_chunks(&delta, &store, &index, &options()).unwrap();
+    assert!(stats.chunks > 1 && stats.new_chunks > 0, "{:?}", stats);
+
+    deltaimage(&["apply-chunks", "--store", store.to_str().unwrap()], &[&source, &index, &restored]);
+    let expected = scratch.join("expected");
+    write_tree(&expected, &[("kept", "kept\n"), ("added", &added)]);
+    assert_eq!(read_tree(&restored), read_tree(&expected));
+}
+
+#[test]
+fn stores_shared_chunks_once() {
+    let scratch = Scratch::new("chunks-shared");
+    let (shared, store) = (noise(1, 4000), scratch.join("store"));
+    let (_, first) = make_delta(&scratch, "first", &[("kept", "kept\n"), ("shared", &shared), ("a", "a\n")]);
+    let (_, second) = make_delta(&scratch, "second", &[("kept", "kept\n"), ("shared", &shared), ("b", "b\n")]);
+
+    let first = export_chunks(&first, &store, &scratch.join("first.idx"), &options()).unwrap();
+    let second = export_chunks(&second, &store, &scratch.join("second.idx"), &options()).unwrap();
+    assert_eq!(first.new_chunks, first.chunks);
+    assert!(second.new_chunks < second.chunks / 2, "{:?}", second);
+}
+
+#[test]
+fn refuses_corrupt_chunks() {
+    let scratch = Scratch::new("chunks-corrupt");
+    let (_, delta) = make_delta(&scratch, "delta", &[("added", &noise(1, 4000))]);
+    let (store, index_path) = (scratch.join("store"), scratch.join("delta.idx"));
+    export_chunks(&delta, &store, &index_path, &options()).unwrap();
+
+    let index = ChunkIndex::load(&index_path).unwrap();
+    let digest = &index.chunks[0].0;
+    let chunk = store.join("chunks").join(&digest[..2]).join(format!("{}.zst", digest));
+    std::fs::write(&chunk, zstd::stream::encode_all(&b"tampered"[..], 3).unwrap()).unwrap();
+
+    let archive = scratch.join("delta.archive");
+    let err = assemble_chunks(&index, store.to_str().unwrap(), &store, &archive, None, &FetchOptions::default())
+        .expect_err("assembled a corrupt chunk");
+    assert!(matches!(err.downcast_ref::<Error>(), Some(Error::ChunkDigestMismatch(_))), "{:?}", err);
+    // Removed, to be fetched again
+    assert!(!Path::new(&chunk).exists());
+}
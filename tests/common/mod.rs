@@ -0,0 +1,99 @@
+//! Scratch trees shared by the integration tests
+
+#![allow(dead_code)]
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use deltaimage::{DeltaBuilder, MetaData};
+
+/// A scratch directory, removed when dropped
+pub struct Scratch(PathBuf);
+
+impl Scratch {
+    /// Unique to the test `name` and the test process
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("deltaimage-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, path: &str) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Write the files of a tree, as paths relative to `root` and contents
+pub fn write_tree(root: &Path, files: &[(&str, &str)]) {
+    for (rel_path, content) in files {
+        let path = root.join(rel_path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, content).unwrap();
+    }
+}
+
+/// Contents of the files of a tree, by path relative to `root`
+pub fn read_tree(root: &Path) -> BTreeMap<PathBuf, String> {
+    walkdir::WalkDir::new(root).into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| (entry.path().strip_prefix(root).unwrap().to_owned(),
+            std::fs::read_to_string(entry.path()).unwrap()))
+        .collect()
+}
+
+/// Contents of the files of a tree as bytes, such as the patches of a delta
+pub fn read_tree_bytes(root: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+    walkdir::WalkDir::new(root).into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| (entry.path().strip_prefix(root).unwrap().to_owned(), std::fs::read(entry.path()).unwrap()))
+        .collect()
+}
+
+/// Turn the target tree into a delta directory against the source tree
+pub fn diff(source: &Path, target: &Path) {
+    DeltaBuilder::new(source, target).run().unwrap();
+}
+
+/// Rewrite the meta-data of a delta directory
+pub fn tamper(delta: &Path, f: impl FnOnce(&mut MetaData)) {
+    let mut md = MetaData::load(delta).unwrap();
+    f(&mut md);
+    md.save(delta).unwrap();
+}
+
+/// Run the deltaimage command with the given arguments followed by paths,
+/// failing unless it succeeds
+pub fn deltaimage(args: &[&str], paths: &[&Path]) {
+    let status = Command::new(env!("CARGO_BIN_EXE_deltaimage")).args(args).args(paths).status().unwrap();
+    assert!(status.success(), "deltaimage {} failed with {}", args.join(" "), status);
+}
+
+/// Run the deltaimage command with the given arguments, returning what it
+/// printed and failing unless it succeeds
+pub fn deltaimage_output(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_deltaimage")).args(args).output().unwrap();
+    assert!(output.status.success(), "deltaimage {} failed with {}", args.join(" "), output.status);
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Run the deltaimage command with the given arguments, returning what it
+/// printed on errors and failing unless it fails
+pub fn deltaimage_error(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_deltaimage")).args(args).output().unwrap();
+    assert!(!output.status.success(), "deltaimage {} succeeded", args.join(" "));
+    String::from_utf8(output.stderr).unwrap()
+}
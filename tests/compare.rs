@@ -0,0 +1,73 @@
+//! Comparison of the deltas of two releases against the same source tree
+
+mod common;
+
+use std::path::PathBuf;
+
+use deltaimage::{CompareOptions, DeltaComparison, PathChange};
+
+use common::{deltaimage_error, deltaimage_output, diff, write_tree, Scratch};
+
+/// Content of `lines` lines that compresses poorly
+fn noise(seed: u64, lines: u64) -> String {
+    (0..lines).map(|i| format!("{:x}\n", (seed + i).wrapping_mul(0x9e3779b97f4a7c15))).collect()
+}
+
+/// Deltas of two releases: the later one adds `debug`, drops `gone`, and
+/// rewrites `grown` entirely
+fn two_releases(scratch: &Scratch) -> (PathBuf, PathBuf) {
+    let (source, old, new) = (scratch.join("source"), scratch.join("old"), scratch.join("new"));
+    let grown = noise(1 << 20, 200);
+    write_tree(&source, &[("grown", &grown), ("kept", "kept\n")]);
+    let grown_tail = grown.clone() + "tail\n";
+    write_tree(&old, &[("grown", &grown_tail), ("kept", "kept\n"), ("gone", &noise(2 << 20, 100))]);
+    write_tree(&new, &[("grown", &noise(3 << 20, 200)), ("kept", "kept\n"), ("debug", &noise(4 << 20, 100))]);
+    diff(&source, &old);
+    diff(&source, &new);
+    (old, new)
+}
+
+#[test]
+fn reports_appeared_disappeared_and_grown_paths() {
+    let scratch = Scratch::new("compare-paths");
+    let (old, new) = two_releases(&scratch);
+
+    let options = CompareOptions { min_size: 0, ..Default::default() };
+    let comparison = DeltaComparison::compare(&old, &new, &options).unwrap();
+    let paths: Vec<_> = comparison.paths.iter().map(|path| (path.change, path.path.as_str())).collect();
+    assert_eq!(paths, [(PathChange::Appeared, "debug"), (PathChange::Disappeared, "gone"),
+        (PathChange::Grew, "grown")]);
+    assert!(comparison.new_delta_size > comparison.old_delta_size);
+
+    // Both ways round
+    let comparison = DeltaComparison::compare(&new, &old, &options).unwrap();
+    let paths: Vec<_> = comparison.paths.iter().map(|path| (path.change, path.path.as_str())).collect();
+    assert_eq!(paths, [(PathChange::Appeared, "gone"), (PathChange::Disappeared, "debug"),
+        (PathChange::Shrank, "grown")]);
+}
+
+#[test]
+fn leaves_out_small_paths() {
+    let scratch = Scratch::new("compare-min-size");
+    let (old, new) = two_releases(&scratch);
+
+    let comparison = DeltaComparison::compare(&old, &new, &CompareOptions::default()).unwrap();
+    assert!(comparison.paths.is_empty(), "{:?}", comparison.paths);
+    deltaimage_output(&["compare", "--check", old.to_str().unwrap(), new.to_str().unwrap()]);
+}
+
+#[test]
+fn check_fails_on_reported_paths() {
+    let scratch = Scratch::new("compare-check");
+    let (old, new) = two_releases(&scratch);
+
+    let output = deltaimage_output(&["compare", "--min-size", "0", "--json", old.to_str().unwrap(),
+        new.to_str().unwrap()]);
+    let json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(json["paths"][0]["change"], "appeared");
+    assert_eq!(json["paths"][0]["path"], "debug");
+
+    let error = deltaimage_error(&["compare", "--min-size", "0", "--check", old.to_str().unwrap(),
+        new.to_str().unwrap()]);
+    assert!(error.contains("The deltas differ by 3 paths"), "{}", error);
+}
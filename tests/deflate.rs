@@ -0,0 +1,135 @@
+//! Gzip files and zip archives patched with their deflate streams inflated,
+//! and deflated again on apply to the very same bytes
+
+mod common;
+
+use std::io::Write;
+use std::path::Path;
+
+use deltaimage::{Algo, DeltaApplier, DeltaBuilder, DiffOptions, MetaData};
+
+use common::{read_tree_bytes, Scratch};
+
+/// Text that compresses, with a line of `version` at its end, close to the
+/// checksum that gzip puts after it
+fn text(version: u32) -> Vec<u8> {
+    let text: String = (0..2000).map(|i| format!("line {} of the manifest\n", i)).collect();
+    format!("{}version {}\n", text, version).into_bytes()
+}
+
+/// Text with a line of `version` at its start, close to the checksum that
+/// zip puts before it
+fn zip_text(version: u32) -> Vec<u8> {
+    let text: String = (0..2000).map(|i| format!("line {} of the module\n", i)).collect();
+    format!("version {}\n{}", version, text).into_bytes()
+}
+
+fn gzip(content: &[u8], level: u32) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::new(level));
+    encoder.write_all(content).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// A raw deflate stream of two stored blocks, as zlib never writes them
+fn stored_blocks(content: &[u8]) -> Vec<u8> {
+    let (first, second) = content.split_at(content.len() / 2);
+    let mut stream = vec![];
+    for (last, block) in [(0, first), (1, second)] {
+        let len = block.len() as u16;
+        stream.push(last);
+        stream.extend(len.to_le_bytes());
+        stream.extend((!len).to_le_bytes());
+        stream.extend(block);
+    }
+    stream
+}
+
+/// A gzip member around a raw deflate stream
+fn gzip_member(stream: &[u8], content: &[u8]) -> Vec<u8> {
+    let mut crc = flate2::Crc::new();
+    crc.update(content);
+    let mut member = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x00\xff".to_vec();
+    member.extend(stream);
+    member.extend(crc.sum().to_le_bytes());
+    member.extend((content.len() as u32).to_le_bytes());
+    member
+}
+
+/// A zip archive of deflated entries, with a stand-in for the central
+/// directory, which is kept as it is
+fn zip(entries: &[(&str, &[u8], u32)]) -> Vec<u8> {
+    let mut archive = vec![];
+    for (name, content, level) in entries {
+        let mut encoder = flate2::write::DeflateEncoder::new(vec![], flate2::Compression::new(*level));
+        encoder.write_all(content).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let mut crc = flate2::Crc::new();
+        crc.update(content);
+
+        archive.extend(b"PK\x03\x04\x14\x00\x00\x00\x08\x00\x00\x00\x00\x00");
+        archive.extend(crc.sum().to_le_bytes());
+        archive.extend((compressed.len() as u32).to_le_bytes());
+        archive.extend((content.len() as u32).to_le_bytes());
+        archive.extend((name.len() as u16).to_le_bytes());
+        archive.extend(0u16.to_le_bytes());
+        archive.extend(name.as_bytes());
+        archive.extend(compressed);
+    }
+    archive.extend(b"PK\x01\x02 central directory stand-in");
+    archive
+}
+
+/// Diff `old` to `new` with `algo`, apply, and check that `new` is restored
+/// byte for byte. The algorithm that the file was stored with.
+fn roundtrip(name: &str, old: &[u8], new: &[u8], algo: Option<&str>) -> Algo {
+    let scratch = Scratch::new(name);
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    for (dir, content) in [(&source, old), (&delta, new)] {
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join("artifact"), content).unwrap();
+    }
+    let target = read_tree_bytes(&delta);
+
+    let options = DiffOptions { algo: algo.map(str::to_owned), ..Default::default() };
+    DeltaBuilder::new(&source, &delta).options(options).run().unwrap();
+    let md = MetaData::load(&delta).unwrap();
+    let stored = md.changes.iter().find(|(_, path)| path == b"artifact").expect("no change").0.clone();
+
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    assert_eq!(read_tree_bytes(&delta), target);
+    assert_eq!(std::fs::read(delta.join(Path::new("artifact"))).unwrap(), new);
+    stored
+}
+
+#[test]
+fn rebuilds_gzip_members() {
+    // Two members, at levels found from the first one and then anew
+    let mut old = gzip(&zip_text(0), 1);
+    old.extend(gzip(&text(1), 6));
+    let mut new = gzip(&zip_text(0), 1);
+    new.extend(gzip(&text(2), 6));
+    assert_eq!(roundtrip("deflate-gzip", &old, &new, Some("deflate")), Algo::Deflate);
+}
+
+#[test]
+fn rebuilds_zip_entries() {
+    let old = zip(&[("META-INF/MANIFEST.MF", &text(0), 6), ("lib/module.py", &zip_text(1), 9)]);
+    let new = zip(&[("META-INF/MANIFEST.MF", &text(0), 6), ("lib/module.py", &zip_text(2), 9)]);
+    assert_eq!(roundtrip("deflate-zip", &old, &new, Some("deflate")), Algo::Deflate);
+}
+
+#[test]
+fn keeps_unreproducible_streams_compressed() {
+    // The first member is inflated, and the second kept as it is
+    let tail = b"another implementation\n".repeat(4);
+    let mut new = gzip(&text(2), 6);
+    new.extend(gzip_member(&stored_blocks(&tail), &tail));
+    assert_eq!(roundtrip("deflate-partial", &gzip(&text(1), 6), &new, Some("deflate")), Algo::Deflate);
+}
+
+#[test]
+fn falls_back_without_reproducible_streams() {
+    let new = gzip_member(&stored_blocks(&text(2)), &text(2));
+    let stored = roundtrip("deflate-fallback", &gzip(&text(1), 6), &new, None);
+    assert_ne!(stored, Algo::Deflate);
+}
@@ -0,0 +1,113 @@
+//! Dockerfiles generated by the docker-file command
+
+mod common;
+
+use common::{deltaimage_error, deltaimage_output, Scratch};
+
+#[test]
+fn emits_dockerfile_per_builder() {
+    let docker = deltaimage_output(&["docker-file", "diff", "image-a", "image-b", "--override-version", "1.0"]);
+    assert!(docker.contains("COPY --from=deltaimage/deltaimage:1.0 /opt/deltaimage"), "{}", docker);
+    assert!(docker.contains("FROM image-a\n"), "{}", docker);
+    assert!(docker.contains(r#"RUN ["/opt/deltaimage", "diff", "--fresh", "/source", "/delta"]"#), "{}", docker);
+
+    let podman = deltaimage_output(&["docker-file", "diff", "image-a", "image-b", "--builder", "podman"]);
+    assert!(podman.contains("RUN --mount=type=bind,from=docker.io/deltaimage/deltaimage:"), "{}", podman);
+
+    let kaniko = deltaimage_output(&["docker-file", "apply", "delta", "--builder", "kaniko"]);
+    assert!(kaniko.contains("COPY --from=deltaimage /opt/deltaimage /opt/deltaimage"), "{}", kaniko);
+    assert!(kaniko.contains("FROM delta as applied"), "{}", kaniko);
+}
+
+#[test]
+fn emits_multi_arch_dockerfile() {
+    let diff = deltaimage_output(&["docker-file", "diff", "image-a", "image-b", "--platform", "linux/arm64,linux/amd64"]);
+    assert!(diff.contains("docker buildx build --platform linux/arm64,linux/amd64"), "{}", diff);
+    assert!(diff.contains("FROM --platform=$TARGETPLATFORM image-b as target"), "{}", diff);
+
+    let error = deltaimage_error(&["docker-file", "apply", "delta", "--platform", "linux/arm64", "--builder", "kaniko"]);
+    assert!(error.contains("--platform is only supported"), "{}", error);
+    deltaimage_error(&["docker-file", "apply", "delta", "--platform", "arm64"]);
+}
+
+#[test]
+fn writes_labels_to_output() {
+    let scratch = Scratch::new("docker-file-output");
+    let output = scratch.join("Dockerfile");
+    let printed = deltaimage_output(&["docker-file", "apply", "delta", "--label", "org.example.tag=say \"hi\"",
+        "--output", output.to_str().unwrap()]);
+    assert_eq!(printed, "");
+    let docker = std::fs::read_to_string(&output).unwrap();
+    assert!(docker.contains("FROM delta as applied"), "{}", docker);
+    assert!(docker.ends_with("LABEL org.example.tag=\"say \\\"hi\\\"\"\n\n"), "{}", docker);
+
+    deltaimage_error(&["docker-file", "apply", "delta", "--label", "no-value"]);
+}
+
+#[test]
+fn renders_template() {
+    let scratch = Scratch::new("docker-file-template");
+    let template = scratch.join("template");
+    std::fs::write(&template, "FROM mirror/{{ delta_image }}\n{{labels}}# {{version}}\n").unwrap();
+    let docker = deltaimage_output(&["docker-file", "apply", "delta", "--override-version", "1.0",
+        "--label", "a=b", "--template", template.to_str().unwrap()]);
+    assert_eq!(docker, "FROM mirror/delta\nLABEL a=\"b\"\n# 1.0\n\n");
+
+    std::fs::write(&template, "FROM {{image_a}}\n").unwrap();
+    let error = deltaimage_error(&["docker-file", "apply", "delta", "--template", template.to_str().unwrap()]);
+    assert!(error.contains("unknown template variable image_a"), "{}", error);
+}
+
+#[test]
+fn pins_given_digest() {
+    let digest = format!("sha256:{}", "ab".repeat(32));
+    let docker = deltaimage_output(&["docker-file", "diff", "image-a", "image-b", "--override-version", "1.0",
+        "--deltaimage-digest", &digest]);
+    assert!(docker.contains(&format!("COPY --from=deltaimage/deltaimage:1.0@{} /opt/deltaimage", digest)),
+        "{}", docker);
+
+    let error = deltaimage_error(&["docker-file", "apply", "delta", "--deltaimage-digest", "sha256:abc"]);
+    assert!(error.contains("invalid digest sha256:abc"), "{}", error);
+}
+
+#[test]
+fn keeps_image_configuration() {
+    let scratch = Scratch::new("docker-file-config");
+    let config = scratch.join("config.json");
+    std::fs::write(&config, r#"[{"Config": {"Env": ["PATH=/usr/bin", "GREETING=say \"hi\""],
+        "Entrypoint": ["/bin/sh", "-c"], "Cmd": ["echo"], "WorkingDir": "/srv", "User": "app",
+        "ExposedPorts": {"80/tcp": {}}, "Labels": {"org.example.tag": "v1"}}}]"#).unwrap();
+    let docker = deltaimage_output(&["docker-file", "apply", "delta", "--config-file", config.to_str().unwrap()]);
+    for line in ["ENV PATH=\"/usr/bin\"\n", "ENV GREETING=\"say \\\"hi\\\"\"\n", "LABEL \"org.example.tag\"=\"v1\"\n",
+        "EXPOSE 80/tcp\n", "WORKDIR /srv\n", "USER app\n", "ENTRYPOINT [\"/bin/sh\",\"-c\"]\n", "CMD [\"echo\"]\n"]
+    {
+        assert!(docker.contains(line), "{}", docker);
+    }
+
+    let template = scratch.join("template");
+    std::fs::write(&template, "{{dockerfile}}").unwrap();
+    let error = deltaimage_error(&["docker-file", "apply", "delta", "--config-file", config.to_str().unwrap(),
+        "--template", template.to_str().unwrap()]);
+    assert!(error.contains("--config-from needs {{config}}"), "{}", error);
+}
+
+#[test]
+fn generates_from_spec() {
+    let scratch = Scratch::new("docker-file-spec");
+    let spec = scratch.join("deltaimage.yaml");
+    std::fs::write(&spec, "# Release build\nimage_a: ubuntu:mantic-20230607\nimage_b: \"ubuntu:mantic-20230624\"\n\
+        version: '1.0'\nsyntax: docker/dockerfile:1\nlabels:\n  org.example.tag: \"v1 # kept\"\n").unwrap();
+    let docker = deltaimage_output(&["docker-file", "spec", spec.to_str().unwrap(), "--label", "a=b"]);
+    assert!(docker.starts_with("# syntax=docker/dockerfile:1\n"), "{}", docker);
+    assert!(docker.contains("FROM ubuntu:mantic-20230607\n"), "{}", docker);
+    assert!(docker.contains("COPY --from=deltaimage/deltaimage:1.0 /opt/deltaimage"), "{}", docker);
+    assert!(docker.ends_with("LABEL org.example.tag=\"v1 # kept\"\nLABEL a=\"b\"\n\n"), "{}", docker);
+
+    std::fs::write(&spec, "image_a: a\ndelta_image: d\n").unwrap();
+    let error = deltaimage_error(&["docker-file", "spec", spec.to_str().unwrap()]);
+    assert!(error.contains("expected either image_a and image_b, or delta_image"), "{}", error);
+
+    std::fs::write(&spec, "delta_image: d\nimage: x\n").unwrap();
+    let error = deltaimage_error(&["docker-file", "spec", spec.to_str().unwrap()]);
+    assert!(error.contains(":2: unknown key image"), "{}", error);
+}
@@ -0,0 +1,90 @@
+//! Deltas whose stored files are encrypted by diff and decrypted by apply
+
+#![cfg(all(unix, feature = "encryption"))]
+
+mod common;
+
+use std::os::unix::fs::MetadataExt;
+
+use deltaimage::{generate_encryption_key, DELTAIMAGE_META_BIN_FILE};
+
+use common::{deltaimage, deltaimage_error, read_tree, read_tree_bytes, write_tree, Scratch};
+
+/// Contents that no encrypted file of the delta may show
+fn secrets() -> Vec<String> {
+    vec![
+        "secret of a modified file\n".repeat(20),
+        "secret of a new file\n".repeat(20),
+        "secret of a large new file\n".repeat(1000),
+        "secret of hardlinked new files\n".repeat(20),
+    ]
+}
+
+fn write_trees(scratch: &Scratch) {
+    let [changed, added, large, linked] = &secrets()[..] else { unreachable!() };
+    let old_changed = changed.replacen("modified", "MODIFIED", 5);
+    write_tree(&scratch.join("source"), &[("kept", "kept\n"), ("changed", &old_changed)]);
+    for dir in ["delta", "target"] {
+        let root = scratch.join(dir);
+        write_tree(&root, &[("kept", "kept\n"), ("changed", changed), ("added", added), ("large", large),
+            ("linked/a", linked)]);
+        std::fs::hard_link(root.join("linked/a"), root.join("linked/b")).unwrap();
+    }
+}
+
+fn leaked(scratch: &Scratch) -> Vec<std::path::PathBuf> {
+    let secrets = secrets();
+    read_tree_bytes(&scratch.join("delta")).into_iter()
+        .filter(|(path, content)| path.as_os_str() != DELTAIMAGE_META_BIN_FILE
+            && secrets.iter().any(|secret| content.windows(20).any(|window| secret.as_bytes().starts_with(window))))
+        .map(|(path, _)| path)
+        .collect()
+}
+
+#[test]
+fn leaves_no_plaintext_file() {
+    for options in [&[][..], &["--no-pair-similar", "--no-detect-renames"][..]] {
+        let scratch = Scratch::new("encryption-no-plaintext");
+        write_trees(&scratch);
+        let key = scratch.join("delta.key");
+        generate_encryption_key(&key).unwrap();
+
+        let mut args = vec!["diff", "--encrypt-key", key.to_str().unwrap(), "--stream-threshold", "4096"];
+        args.extend(options);
+        deltaimage(&args, &[&scratch.join("source"), &scratch.join("delta")]);
+        assert_eq!(leaked(&scratch), Vec::<std::path::PathBuf>::new(), "with {:?}", options);
+
+        deltaimage(&["apply", "--decrypt-key", key.to_str().unwrap()],
+            &[&scratch.join("source"), &scratch.join("delta")]);
+        assert_eq!(read_tree(&scratch.join("delta")), read_tree(&scratch.join("target")));
+        let linked = |name: &str| std::fs::metadata(scratch.join("delta/linked").join(name)).unwrap().ino();
+        assert_eq!(linked("a"), linked("b"));
+    }
+}
+
+#[test]
+fn refuses_apply_without_key() {
+    let scratch = Scratch::new("encryption-without-key");
+    write_trees(&scratch);
+    let key = scratch.join("delta.key");
+    generate_encryption_key(&key).unwrap();
+    deltaimage(&["diff", "--encrypt-key", key.to_str().unwrap()], &[&scratch.join("source"), &scratch.join("delta")]);
+
+    let err = deltaimage_error(&["apply", scratch.join("source").to_str().unwrap(), scratch.join("delta").to_str().unwrap()]);
+    assert!(err.contains("encrypted"), "{}", err);
+}
+
+#[test]
+fn refuses_apply_with_other_key() {
+    let scratch = Scratch::new("encryption-other-key");
+    write_trees(&scratch);
+    let (key, other) = (scratch.join("delta.key"), scratch.join("other.key"));
+    generate_encryption_key(&key).unwrap();
+    generate_encryption_key(&other).unwrap();
+    deltaimage(&["diff", "--encrypt-key", key.to_str().unwrap()], &[&scratch.join("source"), &scratch.join("delta")]);
+
+    let err = deltaimage_error(&["apply", "--decrypt-key", other.to_str().unwrap(),
+        scratch.join("source").to_str().unwrap(), scratch.join("delta").to_str().unwrap()]);
+    assert!(err.contains("decrypt"), "{}", err);
+    assert!(leaked(&scratch).is_empty());
+}
@@ -0,0 +1,190 @@
+//! save-delta, load-delta and diff --from-container against a stand-in for
+//! `docker`, whose store is a directory of `docker save` and `docker export`
+//! archives
+
+#![cfg(unix)]
+
+mod common;
+
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use sha2::{Digest, Sha256};
+
+use common::{read_tree, write_tree, Scratch};
+
+/// Handles `save -o <path> <image>`, `load -i <path>`, the latter keeping
+/// the loaded archive as `loaded.tar`, and `export <container>`
+const FAKE_DOCKER: &str = r#"#!/bin/sh
+case "$1" in
+    save) cp "$FAKE_STORE/$(echo "$4" | tr :/ __).tar" "$3" ;;
+    load) cp "$3" "$FAKE_STORE/loaded.tar" ;;
+    export) cat "$FAKE_STORE/container-$2.tar" ;;
+    *) exit 1 ;;
+esac
+"#;
+
+fn tar_of(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(vec![]);
+    for (path, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(1_700_000_000);
+        builder.append_data(&mut header, path, *content).unwrap();
+    }
+    builder.into_inner().unwrap()
+}
+
+/// Add an image to the store as an archive of `docker save` before Docker 25
+fn store_image(store: &Path, name: &str, files: &[(&str, &str)]) {
+    let files: Vec<_> = files.iter().map(|(path, content)| (*path, content.as_bytes())).collect();
+    let layer = tar_of(&files);
+    let config = serde_json::json!({
+        "architecture": "amd64",
+        "os": "linux",
+        "config": { "Cmd": ["/bin/sh"] },
+        "rootfs": { "type": "layers", "diff_ids": [format!("sha256:{:x}", Sha256::digest(&layer))] },
+        "history": [],
+    });
+    let manifest = serde_json::json!([{ "Config": "config.json", "RepoTags": [name], "Layers": ["layer.tar"] }]);
+    let archive = tar_of(&[
+        ("manifest.json", &serde_json::to_vec(&manifest).unwrap()),
+        ("config.json", &serde_json::to_vec(&config).unwrap()),
+        ("layer.tar", &layer),
+    ]);
+    std::fs::write(store.join(format!("{}.tar", name.replace([':', '/'], "_"))), archive).unwrap();
+}
+
+fn run(scratch: &Scratch, args: &[&str]) {
+    let bin = scratch.join("bin");
+    let path = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap_or_default());
+    let status = Command::new(env!("CARGO_BIN_EXE_deltaimage")).args(args)
+        .env("PATH", path).env("FAKE_STORE", scratch.join("store"))
+        .status().unwrap();
+    assert!(status.success(), "deltaimage {} failed with {}", args.join(" "), status);
+}
+
+fn setup(name: &str) -> (Scratch, PathBuf) {
+    let scratch = Scratch::new(name);
+    let bin = scratch.join("bin");
+    std::fs::create_dir_all(&bin).unwrap();
+    std::fs::write(bin.join("docker"), FAKE_DOCKER).unwrap();
+    std::fs::set_permissions(bin.join("docker"), std::fs::Permissions::from_mode(0o755)).unwrap();
+    let store = scratch.join("store");
+    std::fs::create_dir(&store).unwrap();
+    (scratch, store)
+}
+
+/// Files of the single layer of the image layout in a loaded archive, with
+/// the tags of its `manifest.json`
+fn loaded_image(scratch: &Scratch) -> (serde_json::Value, PathBuf) {
+    let dir = scratch.join("loaded");
+    tar::Archive::new(std::fs::File::open(scratch.join("store/loaded.tar")).unwrap()).unpack(&dir).unwrap();
+    let entries: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.join("manifest.json")).unwrap()).unwrap();
+
+    let mut layer = vec![];
+    let layer_path = dir.join(entries[0]["Layers"][0].as_str().unwrap());
+    flate2::read::GzDecoder::new(std::fs::File::open(layer_path).unwrap()).read_to_end(&mut layer).unwrap();
+    let root = scratch.join("root");
+    tar::Archive::new(&layer[..]).unpack(&root).unwrap();
+    (entries[0]["RepoTags"].clone(), root)
+}
+
+#[test]
+fn moves_image_through_delta() {
+    let (scratch, store) = setup("engine-moves-image");
+    store_image(&store, "app:1", &[("etc/config", "old config\n"), ("usr/lib/kept", "kept\n")]);
+    store_image(&store, "app:2", &[("etc/config", "new config\n"), ("usr/lib/kept", "kept\n")]);
+
+    let delta = scratch.join("delta.tar");
+    run(&scratch, &["save-delta", "app:1", "app:2", "-o", delta.to_str().unwrap(),
+        "--work-dir", scratch.join("work-save").to_str().unwrap()]);
+    run(&scratch, &["load-delta", delta.to_str().unwrap(),
+        "--work-dir", scratch.join("work-load").to_str().unwrap()]);
+
+    let (tags, root) = loaded_image(&scratch);
+    assert_eq!(tags, serde_json::json!(["app:2"]));
+    let expected = scratch.join("expected");
+    write_tree(&expected, &[("etc/config", "new config\n"), ("usr/lib/kept", "kept\n")]);
+    assert_eq!(read_tree(&root), read_tree(&expected));
+}
+
+#[test]
+fn loads_image_under_given_tag() {
+    let (scratch, store) = setup("engine-tag");
+    store_image(&store, "app:1", &[("etc/config", "old config\n")]);
+    store_image(&store, "app:2", &[("etc/config", "new config\n")]);
+
+    let delta = scratch.join("delta.tar");
+    run(&scratch, &["save-delta", "app:1", "app:2", "-o", delta.to_str().unwrap(),
+        "--work-dir", scratch.join("work-save").to_str().unwrap()]);
+    run(&scratch, &["load-delta", delta.to_str().unwrap(), "--tag", "app:restored",
+        "--work-dir", scratch.join("work-load").to_str().unwrap()]);
+
+    let (tags, _) = loaded_image(&scratch);
+    assert_eq!(tags, serde_json::json!(["app:restored"]));
+}
+
+#[test]
+fn diffs_containers() {
+    let (scratch, store) = setup("engine-containers");
+    let old: &[(&str, &str)] = &[("etc/config", "old config\n"), ("usr/lib/kept", "kept\n")];
+    let new: &[(&str, &str)] = &[("etc/config", "new config\n"), ("usr/lib/kept", "kept\n"), ("tmp/added", "added\n")];
+    for (container, files) in [("old", old), ("new", new)] {
+        let files: Vec<_> = files.iter().map(|(path, content)| (*path, content.as_bytes())).collect();
+        std::fs::write(store.join(format!("container-{}.tar", container)), tar_of(&files)).unwrap();
+    }
+
+    let delta = scratch.join("delta");
+    run(&scratch, &["diff", "--from-container", "--output", delta.to_str().unwrap(), "old", "new"]);
+
+    let source = scratch.join("source");
+    write_tree(&source, old);
+    run(&scratch, &["apply", source.to_str().unwrap(), delta.to_str().unwrap()]);
+    let expected = scratch.join("expected");
+    write_tree(&expected, new);
+    assert_eq!(read_tree(&delta), read_tree(&expected));
+}
+
+/// Logs its arguments, lists `app:1` and reads content from the store,
+/// failing on anything else such as snapshot commands
+const FAKE_CTR: &str = r#"#!/bin/sh
+echo "$@" >> "$FAKE_STORE/ctr.log"
+case "$1" in
+    images) printf 'REF TYPE DIGEST SIZE\napp:1 application/vnd.oci.image.manifest.v1+json sha256:manifest 1\n' ;;
+    content) cat "$FAKE_STORE/content-$(echo "$3" | tr : _)" ;;
+    *) exit 1 ;;
+esac
+"#;
+
+#[test]
+fn finds_snapshot_of_source_image() {
+    let (scratch, store) = setup("engine-containerd");
+    let ctr = scratch.join("bin/ctr");
+    std::fs::write(&ctr, FAKE_CTR).unwrap();
+    std::fs::set_permissions(&ctr, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let (lower, upper) = (format!("sha256:{}", "1".repeat(64)), format!("sha256:{}", "2".repeat(64)));
+    let manifest = serde_json::json!({ "config": { "digest": "sha256:config" } });
+    let config = serde_json::json!({ "rootfs": { "type": "layers", "diff_ids": [lower, upper] } });
+    std::fs::write(store.join("content-sha256_manifest"), manifest.to_string()).unwrap();
+    std::fs::write(store.join("content-sha256_config"), config.to_string()).unwrap();
+
+    let path = format!("{}:{}", scratch.join("bin").display(), std::env::var("PATH").unwrap_or_default());
+    let work_dir = scratch.join("work");
+    let status = Command::new(env!("CARGO_BIN_EXE_deltaimage"))
+        .args(["containerd-snapshot", "app:1", "delta", "app:2", "--work-dir", work_dir.to_str().unwrap()])
+        .env("PATH", path).env("FAKE_STORE", &store)
+        .status().unwrap();
+    assert!(!status.success());
+
+    // The top snapshot is named by the chain ID of the layers
+    let chain_id = format!("sha256:{:x}", Sha256::digest(format!("{} {}", lower, upper)));
+    let log = std::fs::read_to_string(store.join("ctr.log")).unwrap();
+    assert!(log.ends_with(&format!("snapshots --snapshotter overlayfs view app:2.deltaimage-view {}\n", chain_id)),
+        "{}", log);
+}
@@ -0,0 +1,27 @@
+//! Estimates of the size of deltas, without encoding them
+
+mod common;
+
+use deltaimage::{estimate, EstimateOptions};
+
+use common::{write_tree, Scratch};
+
+#[test]
+fn estimates_modified_and_new_files() {
+    let scratch = Scratch::new("estimate");
+    let (source, target) = (scratch.join("source"), scratch.join("target"));
+    let old = (0..20_000u32).map(|i| format!("{:x}\n", i.wrapping_mul(2654435761))).collect::<String>();
+    let new = old.replacen("a", "b", 10);
+    let added = (0..20_000u32).map(|i| format!("{:x}\n", i.wrapping_mul(40503))).collect::<String>();
+    write_tree(&source, &[("kept", "kept\n"), ("changed", &old), ("excluded", "old\n")]);
+    write_tree(&target, &[("kept", "kept\n"), ("changed", &new), ("added", &added), ("excluded", "new\n")]);
+
+    let options = EstimateOptions { exclude: vec!["/excluded".to_owned()], ..Default::default() };
+    let estimated = estimate(&source, &target, &options).unwrap();
+    assert_eq!((estimated.unchanged_files, estimated.modified_files, estimated.new_files), (1, 1, 1));
+    assert_eq!(estimated.total_size, (5 + new.len() + added.len()) as u64);
+    // Less than storing the files whole
+    assert!(estimated.estimated_delta_size < (new.len() + added.len()) as u64, "{:?}", estimated);
+    assert!(estimated.estimated_delta_size > 0);
+
+}
@@ -0,0 +1,39 @@
+//! Optional features reported by `version --features`, and refused when
+//! left out of the build
+
+mod common;
+
+#[cfg(not(feature = "encryption"))]
+use common::{deltaimage_error, write_tree, Scratch};
+use common::deltaimage_output;
+
+#[test]
+fn reports_features() {
+    let output = deltaimage_output(&["version", "--features"]);
+    let (version, features) = output.split_once('\n').unwrap();
+    assert_eq!(version, format!("deltaimage {}", env!("CARGO_PKG_VERSION")));
+    let features: Vec<_> = features.split_whitespace().collect();
+    let sign = |enabled| if enabled { "+" } else { "-" };
+    assert_eq!(features, [
+        format!("{}s3", sign(cfg!(feature = "s3"))),
+        format!("{}gcs", sign(cfg!(feature = "gcs"))),
+        format!("{}azblob", sign(cfg!(feature = "azblob"))),
+        format!("{}registry", sign(cfg!(feature = "registry"))),
+        format!("{}encryption", sign(cfg!(feature = "encryption"))),
+        format!("{}fs-image", sign(cfg!(feature = "fs-image"))),
+        format!("{}tui", sign(cfg!(feature = "tui"))),
+    ]);
+}
+
+#[cfg(not(feature = "encryption"))]
+#[test]
+fn refuses_encryption() {
+    let scratch = Scratch::new("features-encryption");
+    let (source, target, key) = (scratch.join("source"), scratch.join("target"), scratch.join("key"));
+    write_tree(&source, &[("changed", "old content\n")]);
+    write_tree(&target, &[("changed", "new content\n")]);
+    std::fs::write(&key, "00".repeat(32)).unwrap();
+    let error = deltaimage_error(&["diff", "--encrypt-key", key.to_str().unwrap(), source.to_str().unwrap(),
+        target.to_str().unwrap()]);
+    assert!(error.contains("Built without the `encryption` feature"), "{}", error);
+}
@@ -0,0 +1,89 @@
+//! Download of deltas given as HTTP URLs, from a local server
+
+mod common;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+
+use deltaimage::{fetch_delta, is_url, pack_archive, Error, FetchOptions};
+
+use common::{deltaimage, diff, read_tree, write_tree, Scratch};
+
+/// Serve `content` at any path over HTTP/1.0, honoring range requests.
+/// Returns the URL and the offsets requested so far.
+fn serve(content: Vec<u8>) -> (String, Arc<Mutex<Vec<u64>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/delta.arch", listener.local_addr().unwrap());
+    let offsets = Arc::new(Mutex::new(vec![]));
+    let requested = offsets.clone();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut offset = 0;
+            for line in BufReader::new(&stream).lines() {
+                let line = line.unwrap();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(range) = line.strip_prefix("Range: bytes=") {
+                    offset = range.trim_end_matches('-').parse().unwrap();
+                }
+            }
+            requested.lock().unwrap().push(offset);
+            let status = if offset > 0 { "206 Partial Content" } else { "200 OK" };
+            let body = &content[offset as usize..];
+            write!(stream, "HTTP/1.0 {}\r\nContent-Length: {}\r\n\r\n", status, body.len()).unwrap();
+            stream.write_all(body).unwrap();
+        }
+    });
+    (url, offsets)
+}
+
+#[test]
+fn resumes_interrupted_download() {
+    let scratch = Scratch::new("fetch-resume");
+    let content = b"content of a delta archive\n".repeat(100);
+    let (url, offsets) = serve(content.clone());
+    assert!(is_url(&url));
+
+    let path = scratch.join("fetched");
+    std::fs::write(&path, &content[..1000]).unwrap();
+    let digest = format!("sha256:{}", sha256_hex(&content));
+    fetch_delta(&url, &path, &FetchOptions { digest: Some(digest), ..Default::default() }).unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), content);
+    assert_eq!(*offsets.lock().unwrap(), [1000]);
+}
+
+#[test]
+fn refuses_digest_mismatch() {
+    let scratch = Scratch::new("fetch-digest");
+    let (url, _) = serve(b"content\n".to_vec());
+    let path = scratch.join("fetched");
+    let digest = format!("sha256:{}", "00".repeat(32));
+    let err = fetch_delta(&url, &path, &FetchOptions { digest: Some(digest), ..Default::default() })
+        .expect_err("fetch succeeded");
+    assert!(matches!(err.downcast_ref::<Error>(), Some(Error::FetchDigestMismatch(..))), "{:?}", err);
+    assert!(!path.exists());
+}
+
+#[test]
+fn applies_archive_from_url() {
+    let scratch = Scratch::new("fetch-apply");
+    let (source, delta, restored) = (scratch.join("source"), scratch.join("delta"), scratch.join("restored"));
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n")]);
+    write_tree(&delta, &[("kept", "kept\n"), ("changed", "new content\n")]);
+    let target = read_tree(&delta);
+    diff(&source, &delta);
+    pack_archive(&delta, &scratch.join("delta.arch")).unwrap();
+    let (url, _) = serve(std::fs::read(scratch.join("delta.arch")).unwrap());
+
+    deltaimage(&["apply", "--format", "archive", "--archive", &url], &[&source, &restored]);
+    assert_eq!(read_tree(&restored), target);
+    assert!(!scratch.join("restored.deltaimage-fetch").exists());
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    use sha2::Digest;
+    sha2::Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,45 @@
+//! Failures of single files, collected and reported together
+
+mod common;
+
+use std::path::PathBuf;
+
+use deltaimage::{ApplyOptions, DeltaApplier, Error, FileErrorKind, FileErrorSummary};
+
+use common::{diff, write_tree, Scratch};
+
+/// Make a delta of three changed files, remove the stored patches of two of
+/// them, and apply it with `max_errors`
+fn apply_with_missing_patches(name: &str, max_errors: usize) -> FileErrorSummary {
+    let scratch = Scratch::new(name);
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("a", "old a\n"), ("b", "old b\n"), ("c", "old c\n")]);
+    write_tree(&delta, &[("a", "new a\n"), ("b", "new b\n"), ("c", "new c\n")]);
+    diff(&source, &delta);
+    std::fs::remove_file(delta.join("a")).unwrap();
+    std::fs::remove_file(delta.join("c")).unwrap();
+
+    let options = ApplyOptions { max_errors, ..Default::default() };
+    let err = DeltaApplier::new(&source, &delta).options(options).run().expect_err("apply succeeded");
+    match err.downcast::<Error>() {
+        Ok(Error::FileErrors(summary)) => summary,
+        other => panic!("unexpected error: {:?}", other),
+    }
+}
+
+#[test]
+fn reports_all_failed_files() {
+    let summary = apply_with_missing_patches("file-errors-all", 0);
+    let failed: Vec<_> = summary.failures.iter().map(|(path, kind, _)| (path.clone(), *kind)).collect();
+    assert_eq!(failed, vec![(PathBuf::from("a"), FileErrorKind::Read), (PathBuf::from("c"), FileErrorKind::Read)]);
+    assert!(!summary.stopped);
+    assert!(summary.to_string().starts_with("2 files failed (read 2)"), "{}", summary);
+}
+
+#[test]
+fn gives_up_at_max_errors() {
+    let summary = apply_with_missing_patches("file-errors-max", 1);
+    assert_eq!(summary.failures.len(), 1);
+    assert!(summary.stopped);
+    assert!(summary.to_string().contains("giving up at --max-errors"), "{}", summary);
+}
@@ -0,0 +1,83 @@
+//! diff-fs-image and apply-fs-image against stand-ins for the squashfs
+//! tools, whose images are a superblock followed by a tarball of the tree
+
+#![cfg(all(unix, feature = "fs-image"))]
+
+mod common;
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+
+use common::{write_tree, Scratch};
+
+/// Makes an image with the superblock in `$FAKE_SUPERBLOCK`, logging its
+/// arguments
+const FAKE_MKSQUASHFS: &str = r#"#!/bin/sh
+echo "$@" >> "$FAKE_LOG"
+tree="$1" out="$2"
+{ cat "$FAKE_SUPERBLOCK"; tar -c --sort=name --mtime=@0 --owner=0 --group=0 --numeric-owner -C "$tree" .; } > "$out"
+"#;
+
+/// Handles `-no-progress -d <dir> <image>`
+const FAKE_UNSQUASHFS: &str = r#"#!/bin/sh
+mkdir "$3" && tail -c +97 "$4" | tar -x -C "$3"
+"#;
+
+fn run(scratch: &Scratch, program: &str, args: &[&Path]) -> std::process::Output {
+    let bin = scratch.join("bin");
+    let path = format!("{}:{}", bin.display(), std::env::var("PATH").unwrap_or_default());
+    Command::new(program).args(args)
+        .env("PATH", path).env("FAKE_LOG", scratch.join("log")).env("FAKE_SUPERBLOCK", scratch.join("superblock"))
+        .output().unwrap()
+}
+
+#[test]
+fn makes_target_image_again() {
+    let scratch = Scratch::new("fs-image");
+    let bin = scratch.join("bin");
+    std::fs::create_dir(&bin).unwrap();
+    for (name, script) in [("mksquashfs", FAKE_MKSQUASHFS), ("unsquashfs", FAKE_UNSQUASHFS)] {
+        std::fs::write(bin.join(name), script).unwrap();
+        std::fs::set_permissions(bin.join(name), std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    // xz, 128kB blocks, with duplicates and exportable
+    let mut superblock = vec![0; 96];
+    superblock[..4].copy_from_slice(b"hsqs");
+    superblock[8..12].copy_from_slice(&1_700_000_000u32.to_le_bytes());
+    superblock[12..16].copy_from_slice(&131_072u32.to_le_bytes());
+    superblock[20..22].copy_from_slice(&4u16.to_le_bytes());
+    superblock[24..26].copy_from_slice(&0xc0u16.to_le_bytes());
+    std::fs::write(scratch.join("superblock"), &superblock).unwrap();
+
+    let (source, target) = (scratch.join("source"), scratch.join("target"));
+    write_tree(&source, &[("etc/config", "old config\n"), ("usr/lib/kept", "kept\n")]);
+    write_tree(&target, &[("etc/config", "new config\n"), ("usr/lib/kept", "kept\n"), ("usr/bin/added", "added\n")]);
+    let (source_image, target_image) = (scratch.join("source.img"), scratch.join("target.img"));
+    run(&scratch, "mksquashfs", &[&source, &source_image]);
+    run(&scratch, "mksquashfs", &[&target, &target_image]);
+
+    let deltaimage = env!("CARGO_BIN_EXE_deltaimage");
+    let (delta, restored) = (scratch.join("delta"), scratch.join("restored.img"));
+    let diff = run(&scratch, deltaimage, &[Path::new("diff-fs-image"), &source_image, &target_image, &delta,
+        Path::new("--work-dir"), &scratch.join("work-diff")]);
+    assert!(diff.status.success(), "{}", String::from_utf8_lossy(&diff.stderr));
+    let apply = run(&scratch, deltaimage, &[Path::new("apply-fs-image"), &source_image, &delta, &restored,
+        Path::new("--work-dir"), &scratch.join("work-apply")]);
+    assert!(apply.status.success(), "{}", String::from_utf8_lossy(&apply.stderr));
+    assert_eq!(std::fs::read(&restored).unwrap(), std::fs::read(&target_image).unwrap());
+
+    let log = std::fs::read_to_string(scratch.join("log")).unwrap();
+    assert!(log.ends_with(" -noappend -no-progress -comp xz -b 131072 -mkfs-time 1700000000\n"), "{}", log);
+}
+
+#[test]
+fn refuses_other_files() {
+    let scratch = Scratch::new("fs-image-unknown");
+    let (image, delta) = (scratch.join("image"), scratch.join("delta"));
+    std::fs::write(&image, "not an image").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_deltaimage"))
+        .arg("diff-fs-image").arg(&image).arg(&image).arg(&delta).output().unwrap();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Not a squashfs or EROFS image"));
+}
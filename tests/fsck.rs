@@ -0,0 +1,47 @@
+//! Checks of delta directories against their own meta-data by `fsck`
+
+#![cfg(unix)]
+
+mod common;
+
+use std::os::unix::fs::MetadataExt;
+
+use deltaimage::{DeltaChecker, FsckOptions};
+
+use common::{diff, write_tree, Scratch};
+
+#[test]
+fn finds_and_fixes_problems() {
+    let scratch = Scratch::new("fsck");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n"), ("deleted", "deleted\n")]);
+    write_tree(&delta, &[("kept", "kept\n"), ("changed", "new content\n"), ("linked", "linked\n")]);
+    std::fs::hard_link(delta.join("linked"), delta.join("other")).unwrap();
+    diff(&source, &delta);
+    assert!(DeltaChecker::new(&delta).run().unwrap().problems.is_empty());
+
+    // Copied apart, with a placeholder filled in and a deleted file back
+    std::fs::remove_file(delta.join("other")).unwrap();
+    std::fs::write(delta.join("other"), "linked\n").unwrap();
+    std::fs::write(delta.join("kept"), "kept\n").unwrap();
+    std::fs::write(delta.join("deleted"), "deleted\n").unwrap();
+    std::fs::remove_file(delta.join("changed")).unwrap();
+
+    let report = DeltaChecker::new(&delta).run().unwrap();
+    let problems: Vec<_> = report.problems.iter()
+        .map(|problem| (problem.path.to_str().unwrap(), problem.reason.as_str(), problem.fixed))
+        .collect();
+    assert_eq!(problems, [
+        ("changed", "regular file missing from the tree", false),
+        ("deleted", "listed as deleted", false),
+        ("kept", "placeholder is 5 bytes", false),
+        ("linked", "no longer hardlinked to the rest of its link group", false),
+    ]);
+
+    let report = DeltaChecker::new(&delta).options(FsckOptions { fix: true }).run().unwrap();
+    assert_eq!(report.problems.iter().filter(|problem| !problem.fixed).count(), 1);
+    assert!(!delta.join("deleted").exists());
+    assert_eq!(delta.join("kept").metadata().unwrap().len(), 0);
+    assert_eq!(delta.join("other").metadata().unwrap().nlink(), 2);
+    assert!(report.into_result().is_err());
+}
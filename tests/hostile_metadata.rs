@@ -0,0 +1,134 @@
+//! Apply of deltas whose meta-data names paths outside of the source and
+//! delta trees, which must be refused before anything is read or written
+
+#![cfg(unix)]
+
+mod common;
+
+use std::os::unix::fs::MetadataExt;
+
+use deltaimage::{Algo, ApplyState, DeltaApplier, DeltaChecker, DeltaVerifier, Error, FsckOptions, Journal, LinkGroup,
+    MetaData, Placeholder, Timestamp};
+
+use common::{diff, write_tree, Scratch};
+
+fn assert_unsafe(result: anyhow::Result<impl std::fmt::Debug>) {
+    let err = result.expect_err("hostile meta-data accepted");
+    assert!(matches!(err.downcast_ref::<Error>(), Some(Error::UnsafeMetaPath(_))),
+        "unexpected error: {:?}", err);
+}
+
+/// Make a delta, let `tamper` edit its meta-data, and check that verify,
+/// fsck and apply refuse it, leaving the tree next to the source and delta ones alone
+fn apply_tampered(name: &str, tamper: impl FnOnce(&mut MetaData, &Scratch)) {
+    let scratch = Scratch::new(name);
+    let (source, delta, outside) = (scratch.join("source"), scratch.join("delta"), scratch.join("outside"));
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n")]);
+    write_tree(&delta, &[("kept", "kept\n"), ("changed", "new content\n"), ("added", "added\n")]);
+    write_tree(&outside, &[("secret", "secret\n")]);
+    diff(&source, &delta);
+
+    let mut md = MetaData::load(&delta).unwrap();
+    tamper(&mut md, &scratch);
+    md.save(&delta).unwrap();
+    assert_unsafe(DeltaVerifier::new(&source, &delta).run());
+    assert_unsafe(DeltaChecker::new(&delta).options(FsckOptions { fix: true }).run());
+    assert_unsafe(DeltaApplier::new(&source, &delta).run());
+
+    let names: Vec<_> = std::fs::read_dir(&outside).unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(names, ["secret"]);
+    let secret = outside.join("secret");
+    assert_eq!(std::fs::read_to_string(&secret).unwrap(), "secret\n");
+    assert_eq!(secret.metadata().unwrap().nlink(), 1);
+    assert_eq!(std::fs::read_to_string(delta.join("added")).unwrap(), "added\n");
+}
+
+#[test]
+fn kept_file_outside() {
+    apply_tampered("kept-outside", |md, _| md.keep_files.push(b"../outside/secret".to_vec()));
+}
+
+#[test]
+fn changed_file_outside() {
+    apply_tampered("changed-outside", |md, _| {
+        md.changes[0].1 = b"../outside/secret".to_vec();
+    });
+}
+
+#[test]
+fn copied_from_outside() {
+    apply_tampered("copied-from-outside", |md, _| {
+        md.changes.push((Algo::CopyFrom(b"../outside/secret".to_vec()), b"copy".to_vec()));
+    });
+}
+
+#[test]
+fn patched_from_absolute() {
+    apply_tampered("patched-from-absolute", |md, scratch| {
+        let secret = scratch.join("outside/secret").into_os_string().into_encoded_bytes();
+        md.changes.push((Algo::XDelta3From(secret), b"copy".to_vec()));
+    });
+}
+
+#[test]
+fn checksum_outside() {
+    apply_tampered("checksum-outside", |md, _| {
+        md.checksums.push((b"../outside/secret".to_vec(), String::new()));
+    });
+}
+
+#[test]
+fn excluded_outside() {
+    apply_tampered("excluded-outside", |md, _| md.excluded.push(b"../outside/secret".to_vec()));
+}
+
+#[test]
+fn link_group_outside() {
+    // As journaled by an interrupted apply, which resuming would relink
+    apply_tampered("link-group-outside", |md, _| {
+        md.journal = Some(Journal {
+            state: ApplyState::Staging,
+            link_groups: vec![vec![b"added".to_vec(), b"../outside/secret".to_vec()]],
+        });
+    });
+}
+
+#[test]
+fn recorded_link_group_outside() {
+    apply_tampered("recorded-link-group-outside", |md, _| {
+        md.link_groups = Some(vec![LinkGroup {
+            paths: vec![b"../outside/secret".to_vec(), b"added".to_vec()],
+            checksum: String::new(),
+        }]);
+    });
+}
+
+#[test]
+fn reverse_delta_outside() {
+    apply_tampered("reverse-outside", |md, scratch| {
+        let mut reverse = MetaData::load(&scratch.join("delta")).unwrap();
+        reverse.deleted_files.push(b"../outside/secret".to_vec());
+        md.reverse = Some(Box::new(reverse));
+    });
+}
+
+#[test]
+fn base_outside() {
+    apply_tampered("base-outside", |md, scratch| {
+        md.extra_sources.push(scratch.path().to_str().unwrap().to_owned());
+        md.bases.push((b"../outside/secret".to_vec(), 0));
+    });
+}
+
+#[test]
+fn placeholder_outside() {
+    apply_tampered("placeholder-outside", |md, _| {
+        let time = Timestamp { secs: 0, nanos: 0 };
+        md.placeholders.push(Placeholder {
+            path: b"../outside/secret".to_vec(), modified: time, accessed: time, mode: 0o100644, uid: 0, gid: 0,
+            xattrs: vec![],
+        });
+    });
+}
@@ -0,0 +1,38 @@
+//! Image configurations recorded in deltas and reconstructed by apply
+
+mod common;
+
+use common::{deltaimage, deltaimage_error, read_tree, write_tree, Scratch};
+
+#[test]
+fn reconstructs_target_config() {
+    let scratch = Scratch::new("image-config");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("changed", "old content\n")]);
+    write_tree(&delta, &[("changed", "new content\n")]);
+    let target = read_tree(&delta);
+
+    let source_config = serde_json::json!({"architecture": "amd64", "config": {"Env": ["A=1"], "User": "app"},
+        "history": [{"created_by": "first"}]});
+    let target_config = serde_json::json!({"architecture": "amd64", "config": {"Env": ["A=2"], "Cmd": ["sh"]},
+        "history": [{"created_by": "first"}, {"created_by": "second"}]});
+    let (a, b) = (scratch.join("a.json"), scratch.join("b.json"));
+    std::fs::write(&a, source_config.to_string()).unwrap();
+    std::fs::write(&b, target_config.to_string()).unwrap();
+    deltaimage(&["diff", "--source-config", a.to_str().unwrap(), "--target-config", b.to_str().unwrap()],
+        &[&source, &delta]);
+
+    // Made against another configuration
+    let other = scratch.join("other.json");
+    std::fs::write(&other, target_config.to_string()).unwrap();
+    let error = deltaimage_error(&["apply", "--source-config", other.to_str().unwrap(),
+        source.to_str().unwrap(), delta.to_str().unwrap()]);
+    assert!(error.contains("Image configuration mismatch"), "{}", error);
+
+    let emitted = scratch.join("emitted.json");
+    deltaimage(&["apply", "--source-config", a.to_str().unwrap(), "--emit-config", emitted.to_str().unwrap()],
+        &[&source, &delta]);
+    assert_eq!(read_tree(&delta), target);
+    let emitted: serde_json::Value = serde_json::from_slice(&std::fs::read(&emitted).unwrap()).unwrap();
+    assert_eq!(emitted, target_config);
+}
@@ -0,0 +1,33 @@
+//! The tree of a delta browsed by the tui command, with its sizes summed by
+//! subtree
+
+mod common;
+
+use deltaimage::DeltaNode;
+
+use common::{diff, write_tree, Scratch};
+
+#[test]
+fn sums_sizes_by_subtree() {
+    let scratch = Scratch::new("inspect-sizes");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    let big: String = (0..2000u64).map(|i| format!("{:x}\n", i.wrapping_mul(0x9e3779b97f4a7c15))).collect();
+    write_tree(&source, &[("dir/changed", "old content\n"), ("kept", "kept\n")]);
+    write_tree(&delta, &[("dir/changed", "new content\n"), ("dir/big", &big), ("kept", "kept\n"),
+        ("small", "small\n")]);
+    diff(&source, &delta);
+
+    let root = DeltaNode::load(&delta).unwrap();
+    let names: Vec<_> = root.children.iter().map(|child| child.name.as_str()).collect();
+    assert_eq!(names[0], "dir", "largest subtree first: {:?}", names);
+    let dir = &root.children[0];
+    assert_eq!(dir.total_delta_size, dir.children.iter().map(|child| child.total_delta_size).sum::<u64>());
+    assert_eq!(root.total_delta_size, root.children.iter().map(|child| child.total_delta_size).sum::<u64>());
+    assert!(dir.children.windows(2).all(|pair| pair[0].total_delta_size >= pair[1].total_delta_size));
+
+    let big = dir.children.iter().find(|child| child.name == "big").unwrap();
+    assert_eq!(big.path, std::path::Path::new("dir/big"));
+    assert_eq!(big.changes_from(&source), ["new"]);
+    let changed = dir.children.iter().find(|child| child.name == "changed").unwrap();
+    assert!(changed.changes_from(&source).is_empty());
+}
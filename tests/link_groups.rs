@@ -0,0 +1,99 @@
+//! Hardlinks of the target tree, restored by apply whether the files of a
+//! group were kept from the source tree, changed or added
+
+#![cfg(unix)]
+
+mod common;
+
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use deltaimage::{Algo, DeltaApplier, DeltaBuilder, DiffOptions, MetaData};
+
+use common::{diff, read_tree, write_tree, Scratch};
+
+/// Make `to` a hardlink of `from`, in each of the trees
+fn link(roots: &[&Path], from: &str, to: &str) {
+    for root in roots {
+        let _ = std::fs::remove_file(root.join(to));
+        std::fs::hard_link(root.join(from), root.join(to)).unwrap();
+    }
+}
+
+fn inode(root: &Path, rel_path: &str) -> u64 {
+    std::fs::symlink_metadata(root.join(rel_path)).unwrap().ino()
+}
+
+#[test]
+fn links_kept_changed_and_added_files() {
+    let scratch = Scratch::new("links-kept-changed-added");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    write_tree(&source, &[("kept", "kept\n"), ("dir/changed", "old content\n")]);
+    for root in [&delta, &target] {
+        write_tree(root, &[("kept", "kept\n"), ("dir/changed", "old content\n")]);
+    }
+    link(&[&delta, &target], "kept", "dir/changed");
+    link(&[&delta, &target], "kept", "dir/added");
+
+    diff(&source, &delta);
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    assert_eq!(read_tree(&delta), read_tree(&target));
+    assert_eq!(inode(&delta, "dir/changed"), inode(&delta, "kept"));
+    assert_eq!(inode(&delta, "dir/added"), inode(&delta, "kept"));
+}
+
+#[test]
+fn links_changed_and_added_files() {
+    let scratch = Scratch::new("links-changed-added");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    write_tree(&source, &[("changed", "old content\n")]);
+    for root in [&delta, &target] {
+        write_tree(root, &[("changed", "new content\n")]);
+    }
+    link(&[&delta, &target], "changed", "added");
+
+    diff(&source, &delta);
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    assert_eq!(read_tree(&delta), read_tree(&target));
+    assert_eq!(inode(&delta, "added"), inode(&delta, "changed"));
+}
+
+#[test]
+fn links_files_kept_apart_in_the_source() {
+    let scratch = Scratch::new("links-kept-apart");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    let files = [("first", "same\n"), ("second", "same\n"), ("other", "other\n")];
+    write_tree(&source, &files);
+    write_tree(&delta, &files);
+    write_tree(&target, &files);
+    link(&[&delta, &target], "first", "second");
+
+    diff(&source, &delta);
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    assert_eq!(read_tree(&delta), read_tree(&target));
+    assert_eq!(inode(&delta, "second"), inode(&delta, "first"));
+    assert_ne!(inode(&delta, "other"), inode(&delta, "first"));
+}
+
+#[test]
+fn compresses_first_of_new_linked_files() {
+    let scratch = Scratch::new("links-compressed");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    let content = "compressible content\n".repeat(100);
+    write_tree(&source, &[("kept", "kept\n")]);
+    for root in [&delta, &target] {
+        write_tree(root, &[("kept", "kept\n"), ("dir/first", content.as_str()), ("plain", content.as_str())]);
+    }
+    link(&[&delta, &target], "dir/first", "second");
+
+    let options = DiffOptions { compress_new_files: true, pair_similar: false, ..Default::default() };
+    DeltaBuilder::new(&source, &delta).options(options).run().unwrap();
+    let md = MetaData::load(&delta).unwrap();
+    let compressed: Vec<_> = md.changes.iter().filter(|(algo, _)| *algo == Algo::Zstd).collect();
+    assert_eq!(compressed.len(), 2, "{:?}", md.changes);
+    assert!(compressed.iter().any(|(_, path)| path == b"plain"), "{:?}", md.changes);
+
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    assert_eq!(read_tree(&delta), read_tree(&target));
+    assert_eq!(inode(&delta, "second"), inode(&delta, "dir/first"));
+}
@@ -0,0 +1,50 @@
+//! Listing of delta directories by `list`
+
+#![cfg(unix)]
+
+mod common;
+
+use std::os::unix::fs::PermissionsExt;
+
+use deltaimage::DeltaEntry;
+
+use common::{deltaimage_output, diff, write_tree, Scratch};
+
+#[test]
+fn lists_every_path_with_its_kind() {
+    let scratch = Scratch::new("list-kinds");
+    let (source, target) = (scratch.join("source"), scratch.join("target"));
+    write_tree(&source, &[("kept", "same"), ("changed", "before"), ("deleted", "gone")]);
+    write_tree(&target, &[("kept", "same"), ("changed", "after"), ("dir/added", "new file")]);
+    std::os::unix::fs::symlink("kept", target.join("link")).unwrap();
+    diff(&source, &target);
+
+    let entries = DeltaEntry::list(&target).unwrap();
+    let kind_of = |path: &str| entries.iter().find(|entry| entry.path == path)
+        .map(|entry| entry.kind.as_str());
+    assert_eq!(kind_of("kept"), Some("keep"));
+    assert_eq!(kind_of("dir/added"), Some("new"));
+    assert_eq!(kind_of("dir"), Some("directory"));
+    assert_eq!(kind_of("link"), Some("symlink"));
+    assert_eq!(kind_of("deleted"), Some("deleted"));
+    assert!(kind_of("changed").is_some_and(|kind| !["keep", "new"].contains(&kind)));
+    assert!(entries.windows(2).all(|pair| pair[0].path <= pair[1].path));
+}
+
+#[test]
+fn lists_restored_permissions_and_sizes() {
+    let scratch = Scratch::new("list-permissions");
+    let (source, target) = (scratch.join("source"), scratch.join("target"));
+    std::fs::create_dir(&source).unwrap();
+    write_tree(&target, &[("script", "#!/bin/sh\n")]);
+    std::fs::set_permissions(target.join("script"), std::fs::Permissions::from_mode(0o750)).unwrap();
+    diff(&source, &target);
+
+    let output = deltaimage_output(&["list", "--json", target.to_str().unwrap()]);
+    let entries: serde_json::Value = serde_json::from_str(&output).unwrap();
+    let script = entries.as_array().unwrap().iter()
+        .find(|entry| entry["path"] == "script").unwrap();
+    assert_eq!(script["kind"], "new");
+    assert_eq!(script["mode"], 0o750);
+    assert_eq!(script["size"], 10);
+}
@@ -0,0 +1,30 @@
+//! Leveled logging to stderr, as text or as JSON lines
+
+mod common;
+
+use std::process::Command;
+
+use common::{deltaimage_error, write_tree, Scratch};
+
+#[test]
+fn logs_json_lines() {
+    let scratch = Scratch::new("logging-json");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("changed", "old content\n")]);
+    write_tree(&delta, &[("changed", "new content\n"), ("added", "added\n")]);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_deltaimage"))
+        .args(["-vvv", "--log-format", "json", "diff"]).args([&source, &delta])
+        .output().unwrap();
+    assert!(output.status.success());
+    for line in String::from_utf8(output.stderr).unwrap().lines() {
+        let event: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert!(event["level"].is_string(), "no level in {}", line);
+    }
+}
+
+#[test]
+fn rejects_unknown_log_format() {
+    let err = deltaimage_error(&["--log-format", "xml", "diff", "/nonexistent", "/nonexistent"]);
+    assert!(err.contains("'xml' isn't a valid value"), "unexpected error: {}", err);
+}
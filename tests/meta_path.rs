@@ -0,0 +1,51 @@
+//! Deltas with the meta-data file placed elsewhere with --meta-path, in a
+//! test binary of its own as the path is set for the whole process
+
+mod common;
+
+use std::path::Path;
+
+use deltaimage::{set_meta_path, DeltaApplier, DeltaBuilder, Error, MetaData, DELTAIMAGE_META_BIN_FILE};
+
+use common::{deltaimage_error, read_tree, write_tree, Scratch};
+
+const META_PATH: &str = "etc/deltaimage.meta";
+
+#[test]
+fn places_meta_data_file_at_meta_path() {
+    set_meta_path(Path::new(META_PATH)).unwrap();
+    let scratch = Scratch::new("meta-path");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    let files = [("etc/config", "new config\n"), (DELTAIMAGE_META_BIN_FILE, "content of the image\n")];
+    write_tree(&source, &[("etc/config", "old config\n"), (DELTAIMAGE_META_BIN_FILE, "content of the image\n")]);
+    write_tree(&delta, &files);
+    write_tree(&target, &files);
+
+    DeltaBuilder::new(&source, &delta).run().unwrap();
+    assert!(delta.join(META_PATH).is_file());
+    assert!(MetaData::load(&delta).is_ok());
+    // Not found by commands given the default path
+    let error = deltaimage_error(&["list", delta.to_str().unwrap()]);
+    assert!(!error.is_empty());
+
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    assert_eq!(read_tree(&delta), read_tree(&target));
+}
+
+#[test]
+fn refuses_trees_holding_meta_path() {
+    set_meta_path(Path::new(META_PATH)).unwrap();
+    let scratch = Scratch::new("meta-path-collision");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("etc/config", "old config\n")]);
+    write_tree(&delta, &[("etc/config", "new config\n"), (META_PATH, "content of the image\n")]);
+
+    let err = DeltaBuilder::new(&source, &delta).run().expect_err("diff succeeded");
+    let collision = matches!(err.downcast_ref::<Error>(),
+        Some(Error::MetaPathCollision(path)) if *path == delta.join(META_PATH));
+    assert!(collision, "unexpected error: {:?}", err);
+    assert_eq!(std::fs::read_to_string(delta.join(META_PATH)).unwrap(), "content of the image\n");
+
+    let error = deltaimage_error(&["--meta-path", "../meta", "list", delta.to_str().unwrap()]);
+    assert!(error.contains(&Error::InvalidMetaPath("../meta".into()).to_string()), "{}", error);
+}
@@ -0,0 +1,96 @@
+//! Meta-data files as written by earlier releases, one for each format
+//! version under `tests/fixtures/metadata`, upgraded as they are loaded
+
+mod common;
+
+use std::path::PathBuf;
+
+use deltaimage::{Algo, Error, MetaData, MetaFormat, META_FORMAT_VERSION, MIN_META_FORMAT_VERSION};
+
+use common::Scratch;
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/metadata").join(name)
+}
+
+fn has_change(md: &MetaData, algo: Algo) -> bool {
+    md.changes.iter().any(|(change, _)| *change == algo)
+}
+
+#[test]
+fn loads_every_supported_version() {
+    for version in MIN_META_FORMAT_VERSION..=META_FORMAT_VERSION {
+        let md = MetaData::load(&fixture(&format!("v{}", version)))
+            .unwrap_or_else(|err| panic!("format version {}: {:?}", version, err));
+        assert_eq!(md.format_version, META_FORMAT_VERSION);
+        assert_eq!(md.keep_files, [b"kept".to_vec()]);
+        assert!(has_change(&md, Algo::XDelta3));
+    }
+}
+
+#[test]
+fn loads_first_release() {
+    let md = MetaData::load(&fixture("v1-baseline")).unwrap();
+    assert_eq!(md.format_version, META_FORMAT_VERSION);
+    assert_eq!(md.changes.len(), 2);
+    assert!(md.directories.is_empty() && md.link_groups.is_none());
+}
+
+#[test]
+fn upgrades_directory_times() {
+    let md = MetaData::load(&fixture("v1")).unwrap();
+    for directory in md.directories.iter() {
+        assert_eq!(directory.modified.secs, 1700000000);
+        assert_eq!(directory.accessed, Some(directory.modified));
+    }
+}
+
+#[test]
+fn loads_version_specific_fields() {
+    assert!(has_change(&MetaData::load(&fixture("v3")).unwrap(), Algo::BsDiff));
+    assert!(has_change(&MetaData::load(&fixture("v4")).unwrap(), Algo::ZstdPatchFrom(27)));
+    let md = MetaData::load(&fixture("v5")).unwrap();
+    assert_eq!((md.extra_sources.len(), md.bases.len()), (1, 1));
+    assert_eq!(MetaData::load(&fixture("v6")).unwrap().placeholders.len(), 1);
+    let md = MetaData::load(&fixture("v7")).unwrap();
+    assert!(md.ignore_times && md.ignore_owner);
+    assert!(has_change(&MetaData::load(&fixture("v8")).unwrap(), Algo::Deflate));
+}
+
+#[test]
+fn migrates_to_current_version() {
+    let scratch = Scratch::new("migrates-meta");
+    let delta = scratch.join("delta");
+    std::fs::create_dir(&delta).unwrap();
+    std::fs::copy(fixture("v1/__deltaimage.meta.json"), delta.join("__deltaimage.meta.json")).unwrap();
+
+    assert_eq!(MetaData::migrate_dir(&delta, Some(MetaFormat::CborZstd)).unwrap(), 1);
+    assert_eq!(MetaFormat::of(&delta), Some(MetaFormat::CborZstd));
+    let md = MetaData::load(&delta).unwrap();
+    assert_eq!(md.format_version, META_FORMAT_VERSION);
+    assert_eq!(md.directories.len(), 2);
+}
+
+#[test]
+fn refuses_newer_version() {
+    let Err(err) = MetaData::load(&fixture("too-new")) else { panic!("loaded a newer version") };
+    match err.downcast_ref::<Error>() {
+        Some(Error::MetaFormatTooNew(_, produced_by, version, min, max)) => {
+            assert_eq!(produced_by, "release 0.2.0");
+            assert_eq!((*version, *min, *max), (1000, MIN_META_FORMAT_VERSION, META_FORMAT_VERSION));
+        }
+        _ => panic!("unexpected error: {:?}", err),
+    }
+}
+
+#[test]
+fn refuses_older_version() {
+    let Err(err) = MetaData::load(&fixture("too-old")) else { panic!("loaded an older version") };
+    match err.downcast_ref::<Error>() {
+        Some(Error::MetaFormatTooOld(_, produced_by, version, min, max)) => {
+            assert_eq!(produced_by, "an unknown release");
+            assert_eq!((*version, *min, *max), (0, MIN_META_FORMAT_VERSION, META_FORMAT_VERSION));
+        }
+        _ => panic!("unexpected error: {:?}", err),
+    }
+}
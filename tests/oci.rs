@@ -0,0 +1,285 @@
+//! Deltas computed and applied directly on OCI image layouts
+
+mod common;
+
+use std::io::Read;
+use std::path::Path;
+
+use deltaimage::{apply_oci, diff_oci, diff_oci_layers, ApplyOptions, DiffOptions, LayerCompression};
+use sha2::{Digest, Sha256};
+
+use common::{read_tree, write_tree, Scratch};
+
+/// Write a blob of an image layout, returning its descriptor
+fn write_blob(dir: &Path, data: &[u8], media_type: &str) -> serde_json::Value {
+    let hex = format!("{:x}", Sha256::digest(data));
+    std::fs::write(dir.join("blobs/sha256").join(&hex), data).unwrap();
+    serde_json::json!({ "mediaType": media_type, "digest": format!("sha256:{}", hex), "size": data.len() })
+}
+
+/// Write an image layout with one uncompressed layer per list of files
+fn write_image(dir: &Path, layers: &[&[(&str, &str)]]) {
+    std::fs::create_dir_all(dir.join("blobs/sha256")).unwrap();
+    std::fs::write(dir.join("oci-layout"), r#"{"imageLayoutVersion":"1.0.0"}"#).unwrap();
+
+    let (mut descriptors, mut diff_ids) = (vec![], vec![]);
+    for files in layers {
+        let mut builder = tar::Builder::new(vec![]);
+        for (path, content) in files.iter() {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(1_700_000_000);
+            builder.append_data(&mut header, path, content.as_bytes()).unwrap();
+        }
+        let layer = builder.into_inner().unwrap();
+        diff_ids.push(format!("sha256:{:x}", Sha256::digest(&layer)));
+        descriptors.push(write_blob(dir, &layer, "application/vnd.oci.image.layer.v1.tar"));
+    }
+
+    let config = serde_json::json!({
+        "architecture": "amd64",
+        "os": "linux",
+        "config": { "Cmd": ["/bin/sh"] },
+        "rootfs": { "type": "layers", "diff_ids": diff_ids },
+        "history": [],
+    });
+    let config = write_blob(dir, &serde_json::to_vec(&config).unwrap(),
+        "application/vnd.oci.image.config.v1+json");
+    let manifest = serde_json::json!({ "schemaVersion": 2, "config": config, "layers": descriptors });
+    let manifest = write_blob(dir, &serde_json::to_vec(&manifest).unwrap(),
+        "application/vnd.oci.image.manifest.v1+json");
+    let index = serde_json::json!({ "schemaVersion": 2, "manifests": [manifest] });
+    std::fs::write(dir.join("index.json"), serde_json::to_vec(&index).unwrap()).unwrap();
+}
+
+/// Read a JSON blob of an image layout by its descriptor
+fn read_blob(dir: &Path, descriptor: &serde_json::Value) -> Vec<u8> {
+    let digest = descriptor["digest"].as_str().unwrap();
+    std::fs::read(dir.join("blobs/sha256").join(digest.strip_prefix("sha256:").unwrap())).unwrap()
+}
+
+/// Unpack the single layer of a restored image, returning its configuration
+fn unpack_image(dir: &Path, root: &Path) -> serde_json::Value {
+    let index: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.join("index.json")).unwrap()).unwrap();
+    let manifest: serde_json::Value = serde_json::from_slice(&read_blob(dir, &index["manifests"][0])).unwrap();
+    let layers = manifest["layers"].as_array().unwrap();
+    assert_eq!(layers.len(), 1);
+
+    let mut layer = vec![];
+    flate2::read::GzDecoder::new(&read_blob(dir, &layers[0])[..]).read_to_end(&mut layer).unwrap();
+    tar::Archive::new(&layer[..]).unpack(root).unwrap();
+    serde_json::from_slice(&read_blob(dir, &manifest["config"])).unwrap()
+}
+
+#[test]
+fn restores_target_image() {
+    let scratch = Scratch::new("oci-restores-target");
+    let (source, target) = (scratch.join("source"), scratch.join("target"));
+    let (delta, restored) = (scratch.join("delta.tar"), scratch.join("restored"));
+    write_image(&source, &[&[("etc/config", "old config\n"), ("usr/lib/kept", "kept\n")]]);
+    write_image(&target, &[
+        &[("etc/config", "new config\n"), ("usr/lib/kept", "kept\n"), ("usr/lib/removed", "removed\n")],
+        &[("usr/lib/.wh.removed", ""), ("usr/bin/added", "added\n")],
+    ]);
+
+    diff_oci(&source, &target, &delta, &scratch.join("work-diff"), DiffOptions::default()).unwrap();
+    apply_oci(&delta, &restored, &scratch.join("work-apply"), ApplyOptions::default()).unwrap();
+
+    let root = scratch.join("root");
+    let config = unpack_image(&restored, &root);
+    assert_eq!(config["config"]["Cmd"], serde_json::json!(["/bin/sh"]));
+    assert_eq!(config["rootfs"]["diff_ids"].as_array().unwrap().len(), 1);
+    let expected = scratch.join("expected");
+    write_tree(&expected, &[("etc/config", "new config\n"), ("usr/lib/kept", "kept\n"), ("usr/bin/added", "added\n")]);
+    assert_eq!(read_tree(&root), read_tree(&expected));
+}
+
+#[test]
+fn hides_merged_directories_of_opaque_whiteouts() {
+    let scratch = Scratch::new("oci-opaque");
+    let (source, target) = (scratch.join("source"), scratch.join("target"));
+    let (delta, restored) = (scratch.join("delta.tar"), scratch.join("restored"));
+    write_image(&source, &[&[("etc/config", "config\n")]]);
+    write_image(&target, &[
+        &[("etc/config", "config\n"), ("etc/sub/lower", "lower\n"), ("etc/other", "other\n")],
+        &[("etc/.wh..wh..opq", ""), ("etc/sub/upper", "upper\n")],
+    ]);
+
+    diff_oci(&source, &target, &delta, &scratch.join("work-diff"), DiffOptions::default()).unwrap();
+    apply_oci(&delta, &restored, &scratch.join("work-apply"), ApplyOptions::default()).unwrap();
+
+    let root = scratch.join("root");
+    unpack_image(&restored, &root);
+    let expected = scratch.join("expected");
+    write_tree(&expected, &[("etc/sub/upper", "upper\n")]);
+    assert_eq!(read_tree(&root), read_tree(&expected));
+}
+
+#[test]
+fn restores_layered_image() {
+    let scratch = Scratch::new("oci-layered");
+    let (source, target) = (scratch.join("source"), scratch.join("target"));
+    let (delta, restored) = (scratch.join("delta.tar"), scratch.join("restored"));
+    write_image(&source, &[&[("etc/config", "old config\n"), ("usr/lib/kept", "kept\n"), ("usr/lib/removed", "removed\n")]]);
+    write_image(&target, &[&[("etc/config", "new config\n"), ("usr/lib/kept", "kept\n")]]);
+
+    diff_oci(&source, &target, &delta, &scratch.join("work-diff"), DiffOptions::default()).unwrap();
+    let options = ApplyOptions { layered: true, ..Default::default() };
+    apply_oci(&delta, &restored, &scratch.join("work-apply"), options).unwrap();
+
+    let index: serde_json::Value = serde_json::from_slice(&std::fs::read(restored.join("index.json")).unwrap()).unwrap();
+    let manifest: serde_json::Value = serde_json::from_slice(&read_blob(&restored, &index["manifests"][0])).unwrap();
+    let source_index: serde_json::Value = serde_json::from_slice(&std::fs::read(source.join("index.json")).unwrap()).unwrap();
+    let source_manifest: serde_json::Value = serde_json::from_slice(&read_blob(&source, &source_index["manifests"][0])).unwrap();
+    let layers = manifest["layers"].as_array().unwrap();
+    assert_eq!(layers.len(), 2);
+    assert_eq!(layers[0]["digest"], source_manifest["layers"][0]["digest"]);
+
+    let mut layer = vec![];
+    flate2::read::GzDecoder::new(&read_blob(&restored, &layers[1])[..]).read_to_end(&mut layer).unwrap();
+    let mut names: Vec<_> = tar::Archive::new(&layer[..]).entries().unwrap()
+        .map(|entry| entry.unwrap().path().unwrap().to_string_lossy().trim_end_matches('/').to_owned())
+        .filter(|name| !name.is_empty() && !name.ends_with("etc") && !name.ends_with("usr/lib") && name != "usr")
+        .collect();
+    names.sort();
+    assert_eq!(names, ["etc/config", "usr/lib/.wh.removed"]);
+}
+
+#[test]
+fn restores_layers_of_target_image() {
+    let scratch = Scratch::new("oci-per-layer");
+    let (source, target) = (scratch.join("source"), scratch.join("target"));
+    let (delta, restored) = (scratch.join("delta.tar"), scratch.join("restored"));
+    let base: &[(&str, &str)] = &[("etc/config", "config\n"), ("usr/lib/kept", "kept\n")];
+    write_image(&source, &[base, &[("usr/bin/tool", "old tool\n")]]);
+    write_image(&target, &[base, &[("usr/bin/tool", "new tool\n")], &[("usr/bin/added", "added\n")]]);
+
+    diff_oci_layers(&source, &target, &delta, &scratch.join("work-diff"), DiffOptions::default()).unwrap();
+    apply_oci(&delta, &restored, &scratch.join("work-apply"), ApplyOptions::default()).unwrap();
+
+    let config = |dir: &Path| -> serde_json::Value {
+        let index: serde_json::Value = serde_json::from_slice(&std::fs::read(dir.join("index.json")).unwrap()).unwrap();
+        let manifest: serde_json::Value = serde_json::from_slice(&read_blob(dir, &index["manifests"][0])).unwrap();
+        serde_json::from_slice(&read_blob(dir, &manifest["config"])).unwrap()
+    };
+    let (restored_config, target_config) = (config(&restored), config(&target));
+    assert_eq!(restored_config["rootfs"]["diff_ids"], target_config["rootfs"]["diff_ids"]);
+    assert_eq!(restored_config["config"], target_config["config"]);
+}
+
+#[test]
+fn splits_delta_layer_compressed_with_zstd() {
+    let scratch = Scratch::new("oci-split-zstd");
+    let (source, target) = (scratch.join("source"), scratch.join("target"));
+    let (delta, restored) = (scratch.join("delta"), scratch.join("restored"));
+    write_image(&source, &[&[("etc/config", "old config\n")]]);
+    write_image(&target, &[&[("etc/config", "new config\n"), ("usr/bin/a", "added a\n"), ("usr/bin/b", "added b\n")]]);
+
+    let options = DiffOptions {
+        layer_compression: LayerCompression::Zstd(3),
+        layer_size: Some(1),
+        ..Default::default()
+    };
+    diff_oci(&source, &target, &delta, &scratch.join("work-diff"), options).unwrap();
+
+    let index: serde_json::Value = serde_json::from_slice(&std::fs::read(delta.join("index.json")).unwrap()).unwrap();
+    let manifest: serde_json::Value = serde_json::from_slice(&read_blob(&delta, &index["manifests"][0])).unwrap();
+    let count: usize = manifest["annotations"]["io.deltaimage.delta-layers"].as_str().unwrap().parse().unwrap();
+    let layers = manifest["layers"].as_array().unwrap();
+    assert!(count > 1 && count <= layers.len(), "{} delta layers", count);
+    for layer in &layers[layers.len() - count..] {
+        assert_eq!(layer["mediaType"], "application/vnd.oci.image.layer.v1.tar+zstd");
+    }
+
+    apply_oci(&delta, &restored, &scratch.join("work-apply"), ApplyOptions::default()).unwrap();
+    let root = scratch.join("root");
+    unpack_image(&restored, &root);
+    let expected = scratch.join("expected");
+    write_tree(&expected, &[("etc/config", "new config\n"), ("usr/bin/a", "added a\n"), ("usr/bin/b", "added b\n")]);
+    assert_eq!(read_tree(&root), read_tree(&expected));
+}
+
+/// Diff and apply with the delta layers and the restored layer in the given
+/// lazy-pulling format, returning the restored layer, decompressed, and its
+/// descriptor
+fn lazy_roundtrip(name: &str, compression: LayerCompression) -> (Vec<u8>, serde_json::Value) {
+    let scratch = Scratch::new(name);
+    let (source, target) = (scratch.join("source"), scratch.join("target"));
+    let (delta, restored) = (scratch.join("delta"), scratch.join("restored"));
+    write_image(&source, &[&[("etc/config", "old config\n"), ("usr/lib/kept", "kept\n")]]);
+    write_image(&target, &[&[("etc/config", "new config\n"), ("usr/lib/kept", "kept\n"), ("usr/bin/a", "added a\n")]]);
+
+    let options = DiffOptions { layer_compression: compression, ..Default::default() };
+    diff_oci(&source, &target, &delta, &scratch.join("work-diff"), options).unwrap();
+    let options = ApplyOptions { layer_compression: compression, ..Default::default() };
+    apply_oci(&delta, &restored, &scratch.join("work-apply"), options).unwrap();
+
+    let index: serde_json::Value = serde_json::from_slice(&std::fs::read(restored.join("index.json")).unwrap()).unwrap();
+    let manifest: serde_json::Value = serde_json::from_slice(&read_blob(&restored, &index["manifests"][0])).unwrap();
+    let descriptor = manifest["layers"][0].clone();
+    let blob = read_blob(&restored, &descriptor);
+    let mut layer = vec![];
+    match compression {
+        LayerCompression::Estargz(_) => flate2::read::MultiGzDecoder::new(&blob[..]).read_to_end(&mut layer).unwrap(),
+        _ => zstd::stream::read::Decoder::new(&blob[..]).unwrap().read_to_end(&mut layer).unwrap(),
+    };
+
+    let config: serde_json::Value = serde_json::from_slice(&read_blob(&restored, &manifest["config"])).unwrap();
+    assert_eq!(config["rootfs"]["diff_ids"][0], format!("sha256:{:x}", Sha256::digest(&layer)));
+    let root = scratch.join("root");
+    tar::Archive::new(&layer[..]).unpack(&root).unwrap();
+    let expected = scratch.join("expected");
+    write_tree(&expected, &[("etc/config", "new config\n"), ("usr/lib/kept", "kept\n"), ("usr/bin/a", "added a\n")]);
+    let mut tree = read_tree(&root);
+    tree.remove(Path::new("stargz.index.json"));
+    assert_eq!(tree, read_tree(&expected));
+    (layer, descriptor)
+}
+
+#[test]
+fn writes_estargz_layers() {
+    let (layer, descriptor) = lazy_roundtrip("oci-estargz", LayerCompression::Estargz(6));
+    assert_eq!(descriptor["mediaType"], "application/vnd.oci.image.layer.v1.tar+gzip");
+    let toc_digest = descriptor["annotations"]["containerd.io/snapshot/stargz/toc.digest"].as_str().unwrap();
+    assert!(toc_digest.starts_with("sha256:"), "{}", toc_digest);
+    let size = &descriptor["annotations"]["io.containers.estargz.uncompressed-size"];
+    assert_eq!(size.as_str().unwrap().parse::<usize>().unwrap(), layer.len());
+
+    // The table of contents lists every file, with its digest
+    let mut archive = tar::Archive::new(&layer[..]);
+    let mut entry = archive.entries().unwrap().map(Result::unwrap)
+        .find(|entry| entry.path().unwrap() == Path::new("stargz.index.json")).expect("no table of contents");
+    let mut toc = vec![];
+    entry.read_to_end(&mut toc).unwrap();
+    assert_eq!(toc_digest, format!("sha256:{:x}", Sha256::digest(&toc)));
+    let toc: serde_json::Value = serde_json::from_slice(&toc).unwrap();
+    let added = toc["entries"].as_array().unwrap().iter().find(|entry| entry["name"] == "usr/bin/a").unwrap();
+    assert_eq!(added["type"], "reg");
+    assert_eq!(added["digest"], format!("sha256:{:x}", Sha256::digest(b"added a\n")));
+}
+
+#[test]
+fn writes_zstd_chunked_layers() {
+    let (_, descriptor) = lazy_roundtrip("oci-zstd-chunked", LayerCompression::ZstdChunked(3));
+    assert_eq!(descriptor["mediaType"], "application/vnd.oci.image.layer.v1.tar+zstd");
+    let annotations = &descriptor["annotations"];
+    assert!(annotations["io.github.containers.zstd-chunked.manifest-checksum"].as_str().unwrap().starts_with("sha256:"));
+    assert!(annotations["io.github.containers.zstd-chunked.manifest-position"].is_string());
+}
+
+#[test]
+fn parses_layer_compression() {
+    assert_eq!("gzip".parse(), Ok(LayerCompression::Gzip(6)));
+    assert_eq!("gzip:9".parse(), Ok(LayerCompression::Gzip(9)));
+    assert_eq!("zstd:-3".parse(), Ok(LayerCompression::Zstd(-3)));
+    assert_eq!("none".parse(), Ok(LayerCompression::None));
+    assert_eq!("estargz".parse(), Ok(LayerCompression::Estargz(6)));
+    assert_eq!("estargz:9".parse(), Ok(LayerCompression::Estargz(9)));
+    assert_eq!("zstd:chunked".parse(), Ok(LayerCompression::ZstdChunked(3)));
+    assert_eq!("zstd:chunked:19".parse(), Ok(LayerCompression::ZstdChunked(19)));
+    assert!("gzip:10".parse::<LayerCompression>().is_err());
+    assert!("lz4".parse::<LayerCompression>().is_err());
+}
@@ -0,0 +1,74 @@
+//! Diff of trees with files that cannot be read, which run as root does not
+//! come across
+
+#![cfg(unix)]
+
+mod common;
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use deltaimage::{DeltaApplier, DeltaBuilder, DiffOptions, DiffStats, MetaData, OnError};
+
+use common::{read_tree, write_tree, Scratch};
+
+/// Diff a tree whose source `changed` file has no read permission
+fn diff_unreadable(scratch: &Scratch, on_error: OnError) -> anyhow::Result<DiffStats> {
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n")]);
+    write_tree(&delta, &[("kept", "kept\n"), ("changed", "new content\n")]);
+    set_mode(&source.join("changed"), 0o000);
+
+    let options = DiffOptions { on_error, ..Default::default() };
+    DeltaBuilder::new(&source, &delta).options(options).run()
+}
+
+fn set_mode(path: &Path, mode: u32) {
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).unwrap();
+}
+
+fn is_root() -> bool {
+    nix::unistd::geteuid().is_root()
+}
+
+#[test]
+fn fails_by_default() {
+    if is_root() {
+        return;
+    }
+    let scratch = Scratch::new("on-error-fail");
+    diff_unreadable(&scratch, OnError::Fail).expect_err("diff succeeded");
+}
+
+#[test]
+fn skips_unreadable_file() {
+    if is_root() {
+        return;
+    }
+    let scratch = Scratch::new("on-error-skip");
+    let stats = diff_unreadable(&scratch, OnError::Skip).unwrap();
+    assert_eq!(stats.failed.iter().map(|file| (&file.path[..], &file.action[..])).collect::<Vec<_>>(),
+        [("changed", "skip")]);
+
+    let delta = scratch.join("delta");
+    assert!(!delta.join("changed").exists());
+    let md = MetaData::load(&delta).unwrap();
+    assert_eq!(md.excluded, [b"changed".to_vec()]);
+    assert!(md.tree_digest.is_some());
+}
+
+#[test]
+fn keeps_unreadable_file_as_is() {
+    if is_root() {
+        return;
+    }
+    let scratch = Scratch::new("on-error-as-is");
+    let stats = diff_unreadable(&scratch, OnError::AsIs).unwrap();
+    assert_eq!(stats.failed.len(), 1);
+
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    assert!(MetaData::load(&delta).unwrap().tree_digest.is_none());
+    set_mode(&source.join("changed"), 0o644);
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    assert_eq!(read_tree(&delta)[Path::new("changed")], "new content\n");
+}
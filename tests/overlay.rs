@@ -0,0 +1,58 @@
+//! Apply writing only the changes and whiteouts into an overlayfs upper
+//! directory, which needs to run as root to create the whiteouts
+
+#![cfg(unix)]
+
+mod common;
+
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+use common::{deltaimage, deltaimage_error, diff, read_tree, write_tree, Scratch};
+
+fn is_root() -> bool {
+    nix::unistd::geteuid().is_root()
+}
+
+#[test]
+fn writes_changes_and_whiteouts() {
+    if !is_root() {
+        return;
+    }
+    let scratch = Scratch::new("overlay-upper");
+    let (source, delta, upper) = (scratch.join("source"), scratch.join("delta"), scratch.join("upper"));
+    let source_files = [("kept", "kept\n"), ("dir/changed", "old content\n"), ("dir/deleted", "deleted\n"),
+        ("gone/file", "gone\n")];
+    write_tree(&source, &source_files);
+    write_tree(&delta, &[("kept", "kept\n"), ("dir/changed", "new content\n"), ("new/added", "added\n")]);
+    diff(&source, &delta);
+
+    deltaimage(&["apply", "--overlay-upperdir", upper.to_str().unwrap()], &[&source, &delta]);
+    assert!(!delta.exists());
+    let expected = scratch.join("expected");
+    write_tree(&expected, &[("dir/changed", "new content\n"), ("new/added", "added\n")]);
+    assert_eq!(read_tree(&upper), read_tree(&expected));
+    for whiteout in ["dir/deleted", "gone"] {
+        let metadata = upper.join(whiteout).symlink_metadata().unwrap();
+        assert!(metadata.file_type().is_char_device() && metadata.rdev() == 0, "{}", whiteout);
+    }
+    assert!(!upper.join("gone/file").exists() && !upper.join("kept").exists());
+
+    // The source tree is left as it was
+    let unchanged = scratch.join("unchanged");
+    write_tree(&unchanged, &source_files);
+    assert_eq!(read_tree(&source), read_tree(&unchanged));
+}
+
+#[test]
+fn refuses_non_empty_upper_dir() {
+    let scratch = Scratch::new("overlay-not-empty");
+    let (source, delta, upper) = (scratch.join("source"), scratch.join("delta"), scratch.join("upper"));
+    write_tree(&source, &[("changed", "old content\n")]);
+    write_tree(&delta, &[("changed", "new content\n")]);
+    write_tree(&upper, &[("stale", "stale\n")]);
+    diff(&source, &delta);
+
+    let error = deltaimage_error(&["apply", "--overlay-upperdir", upper.to_str().unwrap(), source.to_str().unwrap(),
+        delta.to_str().unwrap()]);
+    assert!(error.contains("is not empty"), "{}", error);
+}
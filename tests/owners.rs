@@ -0,0 +1,88 @@
+//! Ownership of restored files translated through owner maps, which needs
+//! to run as root to set the owners of the trees
+
+#![cfg(unix)]
+
+mod common;
+
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use deltaimage::pack_archive;
+
+use common::{deltaimage, deltaimage_error, diff, write_tree, Scratch};
+
+/// Make a delta archive of a tree whose `dir` and `dir/changed` belong to
+/// 1234:1234, returning the source tree and the archive
+fn owned_delta(scratch: &Scratch) -> (PathBuf, PathBuf) {
+    let (source, delta, archive) = (scratch.join("source"), scratch.join("delta"), scratch.join("delta.arch"));
+    write_tree(&source, &[("kept", "kept\n"), ("dir/changed", "old content\n")]);
+    write_tree(&delta, &[("kept", "kept\n"), ("dir/changed", "new content\n")]);
+    for path in ["dir", "dir/changed"] {
+        std::os::unix::fs::chown(delta.join(path), Some(1234), Some(1234)).unwrap();
+    }
+    diff(&source, &delta);
+    pack_archive(&delta, &archive).unwrap();
+    (source, archive)
+}
+
+fn owner(path: &Path) -> (u32, u32) {
+    let metadata = path.symlink_metadata().unwrap();
+    (metadata.uid(), metadata.gid())
+}
+
+fn is_root() -> bool {
+    nix::unistd::geteuid().is_root()
+}
+
+#[test]
+fn maps_stored_owners() {
+    if !is_root() {
+        return;
+    }
+    let scratch = Scratch::new("owners-map");
+    let (source, archive) = owned_delta(&scratch);
+    let (map, restored) = (scratch.join("map"), scratch.join("restored"));
+    std::fs::write(&map, "# Root as it is\n0 0 1\nu 1234 5000 1\ng 1000 6000 1000\n").unwrap();
+
+    deltaimage(&["apply", "--format", "archive", "--archive", archive.to_str().unwrap(), "--owner-map",
+        map.to_str().unwrap()], &[&source, &restored]);
+    assert_eq!(owner(&restored.join("dir")), (5000, 6234));
+    assert_eq!(owner(&restored.join("dir/changed")), (5000, 6234));
+    assert_eq!(owner(&restored.join("kept")), (0, 0));
+}
+
+#[test]
+fn refuses_unmapped_owners() {
+    if !is_root() {
+        return;
+    }
+    let scratch = Scratch::new("owners-unmapped");
+    let (source, archive) = owned_delta(&scratch);
+    let (map, restored) = (scratch.join("map"), scratch.join("restored"));
+    std::fs::write(&map, "0 0 1\n").unwrap();
+
+    let err = deltaimage_error(&["apply", "--format", "archive", "--archive", archive.to_str().unwrap(),
+        "--owner-map", map.to_str().unwrap(), source.to_str().unwrap(), restored.to_str().unwrap()]);
+    assert!(err.contains("The user ID 1234 is outside of the owner map"), "{}", err);
+
+    std::fs::write(&map, "0 0\n").unwrap();
+    let err = deltaimage_error(&["apply", "--format", "archive", "--archive", archive.to_str().unwrap(),
+        "--owner-map", map.to_str().unwrap(), source.to_str().unwrap(), restored.to_str().unwrap()]);
+    assert!(err.contains("line 1"), "{}", err);
+}
+
+#[test]
+fn skips_chown() {
+    if !is_root() {
+        return;
+    }
+    let scratch = Scratch::new("owners-skip");
+    let (source, archive) = owned_delta(&scratch);
+    let restored = scratch.join("restored");
+
+    deltaimage(&["apply", "--format", "archive", "--archive", archive.to_str().unwrap(), "--skip-chown"],
+        &[&source, &restored]);
+    assert_eq!(owner(&restored.join("dir")), (0, 0));
+    assert_eq!(owner(&restored.join("dir/changed")), (0, 0));
+}
@@ -0,0 +1,27 @@
+//! Delta directories packed into archives and self-extracting scripts
+
+mod common;
+
+use std::process::Command;
+
+use common::{deltaimage, diff, read_tree, write_tree, Scratch};
+
+#[test]
+fn self_extracting_script_restores_target() {
+    let scratch = Scratch::new("package-self-extracting");
+    let (source, delta, restored) = (scratch.join("source"), scratch.join("delta"), scratch.join("restored"));
+    let target_files = [("kept", "kept\n"), ("changed", "new content\n"), ("dir/added", "added\n")];
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n")]);
+    write_tree(&delta, &target_files);
+    let target = read_tree(&delta);
+    diff(&source, &delta);
+
+    let script = scratch.join("update.run");
+    deltaimage(&["package", "--self-extracting", "-o"], &[&script, &delta]);
+    let status = Command::new("sh").arg(&script).args([&source, &restored]).status().unwrap();
+    assert!(status.success());
+    assert_eq!(read_tree(&restored), target);
+
+    let status = Command::new("sh").arg(&script).arg(&source).status().unwrap();
+    assert_eq!(status.code(), Some(2));
+}
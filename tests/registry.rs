@@ -0,0 +1,29 @@
+//! Parsing of image references, as given to `--from-registry` and `--push`
+
+use deltaimage::{Error, ImageReference};
+
+fn parse(reference: &str) -> (String, String, String) {
+    let reference: ImageReference = reference.parse().unwrap();
+    (reference.registry, reference.repository, reference.reference)
+}
+
+#[test]
+fn parses_docker_hub_references() {
+    assert_eq!(parse("ubuntu"),
+        ("docker.io".to_owned(), "library/ubuntu".to_owned(), "latest".to_owned()));
+    assert_eq!(parse("team/app:1.2"),
+        ("docker.io".to_owned(), "team/app".to_owned(), "1.2".to_owned()));
+}
+
+#[test]
+fn parses_registry_references() {
+    assert_eq!(parse("registry.example.com:5000/team/app:1.2"),
+        ("registry.example.com:5000".to_owned(), "team/app".to_owned(), "1.2".to_owned()));
+    assert_eq!(parse("localhost/app@sha256:0123"),
+        ("localhost".to_owned(), "app".to_owned(), "sha256:0123".to_owned()));
+}
+
+#[test]
+fn refuses_empty_names() {
+    assert!(matches!("registry.example.com/:tag".parse::<ImageReference>(), Err(Error::InvalidImageReference(_))));
+}
@@ -0,0 +1,33 @@
+//! JSON reports of how diff and apply handled each file
+
+mod common;
+
+use std::path::Path;
+
+use common::{deltaimage, write_tree, Scratch};
+
+/// File paths and algorithm names of a report written by `--report`
+fn report_files(report: &Path) -> Vec<(String, String)> {
+    let report: serde_json::Value = serde_json::from_slice(&std::fs::read(report).unwrap()).unwrap();
+    let mut files: Vec<_> = report["files"].as_array().unwrap().iter()
+        .map(|file| (file["path"].as_str().unwrap().to_owned(), file["algo"].as_str().unwrap().to_owned()))
+        .collect();
+    files.sort();
+    files
+}
+
+#[test]
+fn reports_each_file() {
+    let scratch = Scratch::new("reports-each-file");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    let (diff_report, apply_report) = (scratch.join("diff.json"), scratch.join("apply.json"));
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n")]);
+    write_tree(&delta, &[("kept", "kept\n"), ("changed", "new content\n")]);
+
+    deltaimage(&["diff", "--report", diff_report.to_str().unwrap()], &[&source, &delta]);
+    deltaimage(&["apply", "--report", apply_report.to_str().unwrap()], &[&source, &delta]);
+
+    let expected = [("changed".to_owned(), "xdelta3".to_owned()), ("kept".to_owned(), "keep".to_owned())];
+    assert_eq!(report_files(&diff_report), expected);
+    assert_eq!(report_files(&apply_report), expected);
+}
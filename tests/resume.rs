@@ -0,0 +1,146 @@
+//! Interrupted diffs and applies, resumed or rolled back
+
+mod common;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use deltaimage::{ApplyState, Error, Journal, MetaData, DELTAIMAGE_META_BIN_FILE};
+use sha2::{Digest, Sha256};
+
+use common::{deltaimage, deltaimage_error, diff, read_tree, read_tree_bytes, tamper, write_tree, Scratch};
+
+const SOURCE: &[(&str, &str)] = &[("kept", "kept\n"), ("changed", "old content\n"), ("deleted", "deleted\n")];
+const TARGET: &[(&str, &str)] = &[("kept", "kept\n"), ("changed", "new content\n"), ("added", "added\n")];
+
+/// Source and delta trees, and the target tree for comparison
+fn trees(scratch: &Scratch) -> (PathBuf, PathBuf, PathBuf) {
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    write_tree(&source, SOURCE);
+    write_tree(&delta, TARGET);
+    write_tree(&target, TARGET);
+    diff(&source, &delta);
+    (source, delta, target)
+}
+
+/// Leave the delta directory as an apply stopped in the given state does:
+/// while committing, `changed` is already in place with its patch kept aside,
+/// and `kept` is still staged.
+fn interrupt(delta: &Path, state: ApplyState) {
+    tamper(delta, |md| md.journal = Some(Journal { state, link_groups: vec![] }));
+    if state == ApplyState::Committing {
+        std::fs::rename(delta.join("changed"), delta.join("changed.deltaimage-orig")).unwrap();
+        std::fs::write(delta.join("changed"), "new content\n").unwrap();
+        std::fs::write(delta.join("kept.deltaimage-tmp"), "kept\n").unwrap();
+    }
+}
+
+/// The delta tree without its meta-data file
+fn delta_files(delta: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+    let mut files = read_tree_bytes(delta);
+    files.remove(Path::new(DELTAIMAGE_META_BIN_FILE));
+    files
+}
+
+#[test]
+fn resumes_interrupted_apply() {
+    let scratch = Scratch::new("resumes-apply");
+    let (source, delta, target) = trees(&scratch);
+    interrupt(&delta, ApplyState::Committing);
+
+    deltaimage(&["apply"], &[&source, &delta]);
+    assert_eq!(read_tree(&delta), read_tree(&target));
+}
+
+#[test]
+fn rolls_back_interrupted_apply() {
+    let scratch = Scratch::new("rolls-back-apply");
+    let (source, delta, target) = trees(&scratch);
+    let before = delta_files(&delta);
+    interrupt(&delta, ApplyState::Committing);
+
+    deltaimage(&["apply", "--rollback"], &[&source, &delta]);
+    assert_eq!(delta_files(&delta), before);
+    deltaimage(&["apply"], &[&source, &delta]);
+    assert_eq!(read_tree(&delta), read_tree(&target));
+}
+
+#[test]
+fn refuses_rollback_once_committed() {
+    let scratch = Scratch::new("refuses-rollback");
+    let (source, delta, _) = trees(&scratch);
+    interrupt(&delta, ApplyState::Committed);
+
+    let error = deltaimage_error(&["apply", "--rollback", source.to_str().unwrap(), delta.to_str().unwrap()]);
+    assert!(error.contains(&Error::CannotRollBack(delta.clone()).to_string()), "{}", error);
+}
+
+#[test]
+fn resumed_diff_matches_uninterrupted_diff() {
+    let scratch = Scratch::new("resumes-diff");
+    let (source, reference, resumed) = (scratch.join("source"), scratch.join("reference"), scratch.join("resumed"));
+    let target = [("kept", "kept\n"), ("changed", "new content\n"), ("dir/rewritten", "new rewritten\n"),
+        ("dir/journaled", "new journaled\n"), ("added", "added\n")];
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n"), ("dir/rewritten", "old rewritten\n"),
+        ("dir/journaled", "old journaled\n")]);
+    write_tree(&reference, &target);
+    write_tree(&resumed, &target);
+    let modified = std::fs::metadata(resumed.join("dir")).unwrap().modified().unwrap();
+    let checksums: BTreeMap<_, _> = target.iter()
+        .map(|(path, content)| (path.to_string(), format!("{:x}", Sha256::digest(content.as_bytes()))))
+        .collect();
+
+    diff(&source, &reference);
+    let md = MetaData::load(&reference).unwrap();
+
+    // As left by a diff stopped after rewriting dir/rewritten, and after
+    // journaling dir/journaled but before rewriting it
+    let journal = [
+        serde_json::json!({
+            "directories": md.directories,
+            "link_groups": [],
+            "parent_modtimes": [[resumed.join("dir"), modified]],
+        }),
+        serde_json::json!({
+            "path": b"dir/rewritten", "algo": "XDelta3", "total_size": 14, "reduced_size": 14,
+            "checksum": checksums["dir/rewritten"],
+        }),
+        serde_json::json!({
+            "path": b"dir/journaled", "algo": "XDelta3", "total_size": 14, "reduced_size": 14,
+            "checksum": checksums["dir/journaled"],
+        }),
+    ];
+    let journal: Vec<_> = journal.iter().map(|entry| entry.to_string() + "\n").collect();
+    std::fs::write(resumed.join("__deltaimage.diff-journal"), journal.concat()).unwrap();
+    std::fs::copy(reference.join("dir/rewritten"), resumed.join("dir/rewritten")).unwrap();
+
+    diff(&source, &resumed);
+    let resumed_md = MetaData::load(&resumed).unwrap();
+    assert_eq!(serde_json::to_value(&resumed_md).unwrap(), serde_json::to_value(&md).unwrap());
+    assert_eq!(read_tree_bytes(&resumed), read_tree_bytes(&reference));
+}
+
+#[test]
+fn refuses_diffing_delta() {
+    let scratch = Scratch::new("refuses-delta");
+    let (source, delta, target) = trees(&scratch);
+
+    let error = deltaimage_error(&["diff", source.to_str().unwrap(), delta.to_str().unwrap()]);
+    assert!(error.contains(&Error::TargetIsDelta(delta.clone()).to_string()), "{}", error);
+    deltaimage(&["apply"], &[&source, &delta]);
+    assert_eq!(read_tree(&delta), read_tree(&target));
+}
+
+#[test]
+fn refuses_journal_with_fresh() {
+    let scratch = Scratch::new("refuses-journal");
+    let (source, target) = (scratch.join("source"), scratch.join("target"));
+    write_tree(&source, SOURCE);
+    write_tree(&target, TARGET);
+    std::fs::write(target.join("__deltaimage.diff-journal"), "").unwrap();
+    let before = read_tree(&target);
+
+    let error = deltaimage_error(&["diff", "--fresh", source.to_str().unwrap(), target.to_str().unwrap()]);
+    assert!(error.contains(&Error::UnexpectedDiffJournal(target.clone()).to_string()), "{}", error);
+    assert_eq!(read_tree(&target), before);
+}
@@ -0,0 +1,792 @@
+//! Diff of two trees followed by apply of the delta, restoring the target tree
+
+#![cfg(unix)]
+
+mod common;
+
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+
+use deltaimage::{digest_tree, Algo, DeltaApplier, DeltaBuilder, DeltaSquasher, DiffOptions, Durability, Error,
+    MetaData, MetaFormat, XDelta3Secondary};
+
+use common::{deltaimage, deltaimage_error, deltaimage_output, diff, read_tree, tamper, write_tree, Scratch};
+
+#[test]
+fn restores_target() {
+    let scratch = Scratch::new("restores-target");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    let source_files = [("kept", "kept\n"), ("changed", "old content\n"), ("dir/deleted", "deleted\n")];
+    let target_files = [("kept", "kept\n"), ("changed", "new content\n"), ("dir/added", "added\n")];
+    write_tree(&source, &source_files);
+    write_tree(&delta, &target_files);
+    write_tree(&target, &target_files);
+
+    deltaimage(&["diff"], &[&source, &delta]);
+    deltaimage(&["apply"], &[&source, &delta]);
+    assert_eq!(read_tree(&delta), read_tree(&target));
+}
+
+#[test]
+fn restores_target_encoded_concurrently() {
+    let scratch = Scratch::new("restores-concurrently");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    let names: Vec<_> = (0..32).map(|i| format!("dir{}/file{}", i % 4, i)).collect();
+    let old: Vec<_> = names.iter().map(|name| (name.as_str(), "old content\n")).collect();
+    let new: Vec<_> = names.iter().map(|name| (name.as_str(), "new content\n")).collect();
+    write_tree(&source, &old);
+    write_tree(&delta, &new);
+    write_tree(&target, &new);
+
+    deltaimage(&["diff", "--jobs", "4"], &[&source, &delta]);
+    deltaimage(&["apply"], &[&source, &delta]);
+    assert_eq!(read_tree(&delta), read_tree(&target));
+}
+
+#[test]
+fn restores_target_concurrently() {
+    let scratch = Scratch::new("restores-concurrently-apply");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    let names: Vec<_> = (0..32).map(|i| format!("dir{}/file{}", i % 4, i)).collect();
+    let old: Vec<_> = names.iter().map(|name| (name.as_str(), "old content\n")).collect();
+    // Every other file is kept, sharing directories with the modified ones
+    let new: Vec<_> = names.iter().enumerate()
+        .map(|(i, name)| (name.as_str(), if i % 2 == 0 { "old content\n" } else { "new content\n" }))
+        .collect();
+    write_tree(&source, &old);
+    write_tree(&delta, &new);
+    write_tree(&target, &new);
+
+    deltaimage(&["diff"], &[&source, &delta]);
+    deltaimage(&["apply", "--jobs", "4"], &[&source, &delta]);
+    assert_eq!(read_tree(&delta), read_tree(&target));
+}
+
+#[test]
+fn links_identical_files() {
+    let scratch = Scratch::new("links-identical");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    let target_files = [("a/changed", "new content\n"), ("b/changed", "new content\n"), ("a/added", "added\n"),
+        ("b/added", "added\n"), ("c/added", "added\n"), ("unique", "unique\n")];
+    write_tree(&source, &[("a/changed", "old content\n"), ("b/changed", "old content\n"), ("unique", "old\n")]);
+    write_tree(&delta, &target_files);
+    write_tree(&target, &target_files);
+
+    // New files are left as they are, unless identical to others
+    deltaimage(&["diff", "--dedup-identical", "--no-pair-similar", "--no-detect-renames"],
+        &[&source, &delta]);
+    deltaimage(&["apply"], &[&source, &delta]);
+    assert_eq!(read_tree(&delta), read_tree(&target));
+    let inode = |path: &str| delta.join(path).metadata().unwrap().ino();
+    assert_eq!(inode("a/changed"), inode("b/changed"));
+    assert_eq!(inode("a/added"), inode("b/added"));
+    assert_eq!(inode("a/added"), inode("c/added"));
+    assert_ne!(inode("a/added"), inode("unique"));
+}
+
+#[test]
+fn restores_large_files_in_chunks() {
+    let scratch = Scratch::new("restores-chunks");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    // Over one chunk of 16 MiB, and growing to a third one
+    let old = "line of a large file\n".repeat(900_000);
+    let new = old.replacen("line", "LINE", 1000) + &"appended\n".repeat(1_800_000);
+    write_tree(&source, &[("large", old.as_str()), ("small", "old\n")]);
+    write_tree(&delta, &[("large", new.as_str()), ("small", "new\n")]);
+    write_tree(&target, &[("large", new.as_str()), ("small", "new\n")]);
+
+    deltaimage(&["diff", "--stream-threshold", "4096"], &[&source, &delta]);
+    deltaimage(&["apply"], &[&source, &delta]);
+    assert_eq!(read_tree(&delta), read_tree(&target));
+}
+
+#[test]
+fn keeps_unmodified_files_of_same_size() {
+    let scratch = Scratch::new("keeps-same-size");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    let large = "line of a large file\n".repeat(1000);
+    let source_files = [("same", "content\n"), ("empty", ""), ("large", large.as_str()), ("changed", "content\n")];
+    let target_files = [("same", "content\n"), ("empty", ""), ("large", large.as_str()), ("changed", "CONTENT\n")];
+    write_tree(&source, &source_files);
+    write_tree(&delta, &target_files);
+    write_tree(&target, &target_files);
+
+    deltaimage(&["diff", "--stream-threshold", "4096"], &[&source, &delta]);
+    let md = MetaData::load(&delta).unwrap();
+    let mut kept = md.keep_files.clone();
+    kept.sort();
+    assert_eq!(kept, [b"empty".to_vec(), b"large".to_vec(), b"same".to_vec()]);
+    assert_eq!(md.changes.iter().map(|(_, path)| &path[..]).collect::<Vec<_>>(), [b"changed"]);
+
+    deltaimage(&["apply"], &[&source, &delta]);
+    assert_eq!(read_tree(&delta), read_tree(&target));
+}
+
+#[test]
+fn stores_small_and_poorly_patched_files_whole() {
+    let scratch = Scratch::new("stores-whole");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    let old = "line of a small file\n".repeat(100);
+    let new = old.replacen("line", "LINE", 1);
+    let unrelated = (0..2000u32).map(|i| format!("{:x}", i.wrapping_mul(2654435761))).collect::<String>();
+    let source_files = [("small", old.as_str()), ("rewritten", old.as_str())];
+    let target_files = [("small", new.as_str()), ("rewritten", unrelated.as_str())];
+    write_tree(&source, &source_files);
+    write_tree(&delta, &target_files);
+    write_tree(&target, &target_files);
+
+    deltaimage(&["diff", "--min-file-size", "4096", "--min-delta-ratio", "0.5"], &[&source, &delta]);
+    let md = MetaData::load(&delta).unwrap();
+    assert!(md.changes.iter().all(|(algo, _)| matches!(algo, Algo::Zstd | Algo::AsIs)),
+        "patched files: {:?}", md.changes);
+    assert_eq!(md.changes.len(), 2);
+
+    deltaimage(&["apply"], &[&source, &delta]);
+    assert_eq!(read_tree(&delta), read_tree(&target));
+}
+
+#[test]
+fn restores_target_with_xdelta3_tuning() {
+    let scratch = Scratch::new("restores-xdelta3-tuning");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    let old = "line of a large file\n".repeat(10_000);
+    let new = old.replacen("line", "LINE", 100);
+    write_tree(&source, &[("large", old.as_str()), ("small", "old\n")]);
+    write_tree(&delta, &[("large", new.as_str()), ("small", "new\n")]);
+    write_tree(&target, &[("large", new.as_str()), ("small", "new\n")]);
+
+    deltaimage(&["diff", "--stream-threshold", "4096", "--xdelta3-level", "9", "--xdelta3-secondary", "djw",
+        "--xdelta3-window", "65536"], &[&source, &delta]);
+    let params = MetaData::load(&delta).unwrap().xdelta3.unwrap();
+    assert_eq!((params.level, params.secondary, params.window), (Some(9), XDelta3Secondary::Djw, 65536));
+    deltaimage(&["apply"], &[&source, &delta]);
+    assert_eq!(read_tree(&delta), read_tree(&target));
+}
+
+#[test]
+fn restores_large_files_by_patch_from() {
+    let scratch = Scratch::new("restores-patch-from");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    let old = "line of a large file\n".repeat(50_000);
+    let new = old.replacen("line", "LINE", 100) + "appended\n";
+    write_tree(&source, &[("large", old.as_str()), ("same", old.as_str()), ("small", "old\n")]);
+    write_tree(&delta, &[("large", new.as_str()), ("same", old.as_str()), ("small", "new\n")]);
+    write_tree(&target, &[("large", new.as_str()), ("same", old.as_str()), ("small", "new\n")]);
+
+    deltaimage(&["diff", "--patch-from-threshold", "4096"], &[&source, &delta]);
+    let md = MetaData::load(&delta).unwrap();
+    assert!(md.changes.iter().any(|(algo, path)| matches!(algo, Algo::ZstdPatchFrom(_)) && path == b"large"));
+    assert!(md.keep_files.contains(&b"same".to_vec()));
+    assert!(std::fs::metadata(delta.join("large")).unwrap().len() < new.len() as u64 / 10);
+    deltaimage(&["apply"], &[&source, &delta]);
+    assert_eq!(read_tree(&delta), read_tree(&target));
+}
+
+#[test]
+fn restores_target_through_library() {
+    let scratch = Scratch::new("restores-library");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n")]);
+    write_tree(&delta, &[("kept", "kept\n"), ("changed", "new content\n")]);
+    write_tree(&target, &[("kept", "kept\n"), ("changed", "new content\n")]);
+
+    let stats = DeltaBuilder::new(&source, &delta)
+        .options(DiffOptions { jobs: Some(2), ..Default::default() })
+        .run()
+        .unwrap();
+    assert_eq!(stats.total_size, 17);
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    assert_eq!(read_tree(&delta), read_tree(&target));
+}
+
+#[test]
+fn refuses_source_tree_that_changed() {
+    let scratch = Scratch::new("refuses-changed-source");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n")]);
+    write_tree(&delta, &[("kept", "kept\n"), ("changed", "new content\n")]);
+    diff(&source, &delta);
+    std::fs::write(source.join("kept"), "changed since the diff\n").unwrap();
+
+    let err = DeltaApplier::new(&source, &delta).run().unwrap_err();
+    match err.downcast_ref::<Error>() {
+        Some(Error::ChecksumMismatch(paths)) => assert_eq!(paths, &[PathBuf::from("kept")]),
+        _ => panic!("unexpected error: {:?}", err),
+    }
+}
+
+#[test]
+fn checks_digest_of_restored_tree() {
+    let scratch = Scratch::new("checks-tree-digest");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n")]);
+    write_tree(&delta, &[("kept", "kept\n"), ("changed", "new content\n"), ("dir/added", "added\n")]);
+    write_tree(&target, &[("kept", "kept\n"), ("changed", "new content\n"), ("dir/added", "added\n")]);
+    let expected = digest_tree(&target).unwrap();
+    diff(&source, &delta);
+    assert_eq!(MetaData::load(&delta).unwrap().tree_digest, Some(expected.clone()));
+
+    let stats = DeltaApplier::new(&source, &delta).run().unwrap();
+    assert_eq!(stats.tree_digest, Some(expected.clone()));
+    assert_eq!(deltaimage_output(&["digest", delta.to_str().unwrap()]).trim(), expected);
+}
+
+#[test]
+fn refuses_restored_tree_of_other_digest() {
+    let scratch = Scratch::new("refuses-tree-digest");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n")]);
+    write_tree(&delta, &[("kept", "kept\n"), ("changed", "new content\n")]);
+    diff(&source, &delta);
+    tamper(&delta, |md| md.tree_digest = Some("0".repeat(64)));
+
+    let err = DeltaApplier::new(&source, &delta).run().unwrap_err();
+    assert!(matches!(err.downcast_ref::<Error>(), Some(Error::TreeDigestMismatch(..))), "{:?}", err);
+}
+
+#[test]
+fn repairs_symlinks() {
+    let scratch = Scratch::new("repairs-symlinks");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("file", "old content\n")]);
+    write_tree(&delta, &[("file", "new content\n"), ("dir/other", "other\n")]);
+    std::os::unix::fs::symlink("../file", delta.join("dir/missing")).unwrap();
+    std::os::unix::fs::symlink("file", delta.join("changed")).unwrap();
+    diff(&source, &delta);
+
+    // As if the delta tree was copied by a tool losing or mangling symlinks
+    std::fs::remove_file(delta.join("dir/missing")).unwrap();
+    std::fs::remove_file(delta.join("changed")).unwrap();
+    std::os::unix::fs::symlink("elsewhere", delta.join("changed")).unwrap();
+
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    assert_eq!(std::fs::read_link(delta.join("dir/missing")).unwrap(), PathBuf::from("../file"));
+    assert_eq!(std::fs::read_link(delta.join("changed")).unwrap(), PathBuf::from("file"));
+}
+
+#[test]
+fn removes_deleted_files() {
+    let scratch = Scratch::new("removes-deleted");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("kept", "kept\n"), ("dir/deleted", "deleted\n")]);
+    write_tree(&delta, &[("kept", "kept\n"), ("dir/added", "added\n")]);
+    diff(&source, &delta);
+
+    // As if the delta was unpacked over the source tree
+    write_tree(&delta, &[("dir/deleted", "deleted\n")]);
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    assert!(!delta.join("dir/deleted").exists());
+    assert!(delta.join("dir/added").exists());
+}
+
+#[test]
+fn removes_deleted_directories() {
+    let scratch = Scratch::new("removes-deleted-dirs");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    write_tree(&source, &[("kept", "kept\n"), ("gone/deleted", "deleted\n")]);
+    write_tree(&delta, &[("kept", "kept\n")]);
+    write_tree(&target, &[("kept", "kept\n")]);
+
+    diff(&source, &delta);
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    assert_eq!(read_tree(&delta), read_tree(&target));
+    assert!(!delta.join("gone").exists());
+}
+
+#[test]
+fn restores_directories() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let scratch = Scratch::new("restores-dirs");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("dir/file", "old content\n")]);
+    write_tree(&delta, &[("dir/file", "new content\n")]);
+    std::fs::create_dir_all(delta.join("empty/nested")).unwrap();
+    std::fs::set_permissions(delta.join("empty"), std::fs::Permissions::from_mode(0o700)).unwrap();
+    let modified = std::fs::metadata(delta.join("dir")).unwrap().modified().unwrap();
+    diff(&source, &delta);
+
+    // As if the delta tree was copied by a tool dropping empty directories
+    std::fs::remove_dir_all(delta.join("empty")).unwrap();
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    assert!(delta.join("empty/nested").is_dir());
+    assert_eq!(std::fs::metadata(delta.join("empty")).unwrap().permissions().mode() & 0o777, 0o700);
+    assert_eq!(std::fs::metadata(delta.join("dir")).unwrap().modified().unwrap(), modified);
+}
+
+#[test]
+fn restores_zstd_compressed_files() {
+    let scratch = Scratch::new("restores-zstd");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    let content = "new content\n".repeat(100);
+    write_tree(&source, &[("changed", "old content\n")]);
+    write_tree(&delta, &[("changed", content.as_str())]);
+    diff(&source, &delta);
+
+    // As stored for files that xdelta3 cannot encode
+    std::fs::write(delta.join("changed"), zstd::stream::encode_all(content.as_bytes(), 3).unwrap()).unwrap();
+    tamper(&delta, |md| md.changes = vec![(Algo::Zstd, b"changed".to_vec())]);
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    assert_eq!(std::fs::read_to_string(delta.join("changed")).unwrap(), content);
+}
+
+#[test]
+fn restores_target_with_each_algo() {
+    for (name, algo) in [("xdelta3", Algo::XDelta3), ("zstd-patch-from", Algo::ZstdPatchFrom(12)),
+        ("zstd", Algo::Zstd), ("as-is", Algo::AsIs)]
+    {
+        let scratch = Scratch::new(&format!("restores-algo-{}", name));
+        let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+        let old = "content of a modified file\n".repeat(100);
+        let new = old.replacen("modified", "MODIFIED", 10);
+        write_tree(&source, &[("changed", old.as_str())]);
+        write_tree(&delta, &[("changed", new.as_str())]);
+
+        deltaimage(&["diff", "--algo", name], &[&source, &delta]);
+        let md = MetaData::load(&delta).unwrap();
+        assert!(md.changes.iter().any(|(change, _)| *change == algo), "{}: {:?}", name, md.changes);
+        deltaimage(&["apply"], &[&source, &delta]);
+        assert_eq!(std::fs::read_to_string(delta.join("changed")).unwrap(), new, "{}", name);
+    }
+}
+
+#[test]
+fn restores_moved_files() {
+    let scratch = Scratch::new("restores-moved");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    let content = "moved content\n".repeat(100);
+    write_tree(&source, &[("old/name", content.as_str())]);
+    write_tree(&delta, &[("new/name", content.as_str())]);
+    write_tree(&target, &[("new/name", content.as_str())]);
+
+    diff(&source, &delta);
+    assert_eq!(std::fs::metadata(delta.join("new/name")).unwrap().len(), 0);
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    assert_eq!(read_tree(&delta), read_tree(&target));
+}
+
+#[test]
+fn restores_similar_files() {
+    let scratch = Scratch::new("restores-similar");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    let similar = "line of a file that is changed a little\n".repeat(100);
+    let changed = similar.clone() + "and more\n";
+    write_tree(&source, &[("lib/libfoo.so.1", similar.as_str())]);
+    write_tree(&delta, &[("lib/libfoo.so.2", changed.as_str())]);
+    write_tree(&target, &[("lib/libfoo.so.2", changed.as_str())]);
+
+    diff(&source, &delta);
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    assert_eq!(read_tree(&delta), read_tree(&target));
+}
+
+#[test]
+fn takes_excluded_paths_from_source() {
+    let scratch = Scratch::new("takes-excluded");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("kept", "kept\n"), ("var/log/old.log", "old log\n"), ("var/cache/old", "old\n")]);
+    write_tree(&delta, &[("kept", "kept\n"), ("var/log/new.log", "new log\n"), ("var/cache/new", "new\n"),
+        ("var/cache/app/data", "data\n")]);
+
+    deltaimage(&["diff", "--exclude", "/var/log", "--exclude", "/var/cache/*", "--include", "/var/cache/app"],
+        &[&source, &delta]);
+    assert!(!delta.join("var/log").exists());
+    assert!(!delta.join("var/cache/new").exists());
+
+    deltaimage(&["apply"], &[&source, &delta]);
+    let expected = scratch.join("expected");
+    write_tree(&expected, &[("kept", "kept\n"), ("var/log/old.log", "old log\n"), ("var/cache/old", "old\n"),
+        ("var/cache/app/data", "data\n")]);
+    assert_eq!(read_tree(&delta), read_tree(&expected));
+}
+
+#[test]
+fn leaves_target_untouched_on_dry_run() {
+    let scratch = Scratch::new("dry-run");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n"), ("var/log/old", "old\n")]);
+    write_tree(&delta, &[("kept", "kept\n"), ("changed", "new content\n"), ("var/log/new", "new\n")]);
+    let before = read_tree(&delta);
+
+    let stats = DeltaBuilder::new(&source, &delta)
+        .options(DiffOptions { dry_run: true, exclude: vec!["/var/log".to_owned()], ..Default::default() })
+        .run()
+        .unwrap();
+    assert_eq!(stats.files.len(), 2);
+    assert_eq!(read_tree(&delta), before);
+}
+
+#[test]
+fn writes_delta_to_output_directory() {
+    let scratch = Scratch::new("output-dir");
+    let (source, target, delta) = (scratch.join("source"), scratch.join("target"), scratch.join("delta"));
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n")]);
+    write_tree(&target, &[("kept", "kept\n"), ("changed", "new content\n"), ("added", "added\n")]);
+    let before = read_tree(&target);
+
+    deltaimage(&["diff", "--output", delta.to_str().unwrap()], &[&source, &target]);
+    assert_eq!(read_tree(&target), before);
+    deltaimage(&["apply"], &[&source, &delta]);
+    assert_eq!(read_tree(&delta), before);
+}
+
+#[test]
+fn restores_source_in_reverse() {
+    let scratch = Scratch::new("reverse");
+    let (source, target) = (scratch.join("source"), scratch.join("target"));
+    let (forward, reverse) = (scratch.join("forward"), scratch.join("reverse"));
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n"), ("deleted", "deleted\n")]);
+    write_tree(&target, &[("kept", "kept\n"), ("changed", "new content\n"), ("added", "added\n")]);
+
+    for delta in [&forward, &reverse] {
+        deltaimage(&["diff", "--bidirectional", "--output", delta.to_str().unwrap()], &[&source, &target]);
+    }
+    deltaimage(&["apply"], &[&source, &forward]);
+    assert_eq!(read_tree(&forward), read_tree(&target));
+    deltaimage(&["apply", "--reverse"], &[&target, &reverse]);
+    assert_eq!(read_tree(&reverse), read_tree(&source));
+}
+
+#[test]
+fn squashed_delta_restores_as_chain() {
+    let scratch = Scratch::new("squash");
+    let (a, b, c) = (scratch.join("a"), scratch.join("b"), scratch.join("c"));
+    write_tree(&a, &[("kept", "kept\n"), ("changed-once", "a\n"), ("changed-twice", "a\n"),
+        ("changed-late", "a\n"), ("deleted-late", "a\n")]);
+    write_tree(&b, &[("kept", "kept\n"), ("changed-once", "b\n"), ("changed-twice", "b\n"),
+        ("changed-late", "a\n"), ("deleted-late", "a\n"), ("added", "b\n")]);
+    write_tree(&c, &[("kept", "kept\n"), ("changed-once", "b\n"), ("changed-twice", "c\n"),
+        ("changed-late", "c\n"), ("added", "b\n"), ("added-late", "c\n")]);
+
+    let delta = |from: &PathBuf, to: &PathBuf, name: &str| {
+        let delta = scratch.join(name);
+        deltaimage(&["diff", "--output", delta.to_str().unwrap()], &[from, to]);
+        delta
+    };
+    let (chain_ab, chain_bc) = (delta(&a, &b, "chain-ab"), delta(&b, &c, "chain-bc"));
+    let (squash_ab, squash_bc) = (delta(&a, &b, "squash-ab"), delta(&b, &c, "squash-bc"));
+
+    deltaimage(&["apply"], &[&a, &chain_ab, &chain_bc]);
+    let squashed = scratch.join("squashed");
+    DeltaSquasher::new(&a, &squash_ab, &squash_bc).run(&squashed).unwrap();
+    DeltaApplier::new(&a, &squashed).run().unwrap();
+    assert_eq!(read_tree(&squashed), read_tree(&chain_bc));
+    assert_eq!(read_tree(&squashed), read_tree(&c));
+}
+
+#[test]
+fn restores_xattrs() {
+    let scratch = Scratch::new("restores-xattrs");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n")]);
+    write_tree(&delta, &[("kept", "kept\n"), ("changed", "new content\n")]);
+    // Effective and permitted cap_net_raw, which only root may set
+    let capability = [1, 0, 0, 2, 0, 0x20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let with_capability = xattr::set(delta.join("changed"), "security.capability", &capability).is_ok();
+    for path in ["kept", "changed"] {
+        xattr::set(delta.join(path), "user.origin", b"target").unwrap();
+    }
+
+    diff(&source, &delta);
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    for path in ["kept", "changed"] {
+        assert_eq!(xattr::get(delta.join(path), "user.origin").unwrap().as_deref(), Some(&b"target"[..]));
+    }
+    if with_capability {
+        assert_eq!(xattr::get(delta.join("changed"), "security.capability").unwrap().as_deref(),
+            Some(&capability[..]));
+    }
+}
+
+#[test]
+fn restores_posix_acls() {
+    let scratch = Scratch::new("restores-acls");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("dir/acl", "old content\n"), ("dir/plain", "old content\n")]);
+    write_tree(&delta, &[("dir/acl", "new content\n"), ("dir/plain", "new content\n")]);
+    // Owner rw, user 1000 r, group r, mask r, other r
+    let mut acl = 2u32.to_le_bytes().to_vec();
+    for (tag, perm, id) in [(0x01u16, 6u16, u32::MAX), (0x02, 4, 1000), (0x04, 4, u32::MAX), (0x10, 4, u32::MAX),
+        (0x20, 4, u32::MAX)]
+    {
+        acl.extend(tag.to_le_bytes().into_iter().chain(perm.to_le_bytes()).chain(id.to_le_bytes()));
+    }
+    xattr::set(delta.join("dir/acl"), "system.posix_acl_access", &acl).unwrap();
+    // Files created beneath on apply inherit it, unlike the original ones
+    xattr::set(delta.join("dir"), "system.posix_acl_default", &acl).unwrap();
+
+    diff(&source, &delta);
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    assert_eq!(xattr::get(delta.join("dir/acl"), "system.posix_acl_access").unwrap(), Some(acl));
+    assert_eq!(xattr::get(delta.join("dir/plain"), "system.posix_acl_access").unwrap(), None);
+}
+
+#[test]
+fn restores_sparse_files() {
+    use std::os::unix::fs::{FileExt, MetadataExt};
+
+    let scratch = Scratch::new("restores-sparse");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("image", "old content\n")]);
+    std::fs::create_dir(&delta).unwrap();
+    let image = std::fs::File::create(delta.join("image")).unwrap();
+    image.set_len(64 << 20).unwrap();
+    image.write_all_at(b"new content\n", 0).unwrap();
+    image.write_all_at(b"middle\n", 32 << 20).unwrap();
+    let content = std::fs::read(delta.join("image")).unwrap();
+
+    diff(&source, &delta);
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    let metadata = std::fs::metadata(delta.join("image")).unwrap();
+    assert!(metadata.blocks() * 512 < 1 << 20, "restored {} blocks", metadata.blocks());
+    assert!(std::fs::read(delta.join("image")).unwrap() == content);
+}
+
+#[test]
+fn restores_kept_files_from_source() {
+    use std::os::unix::fs::{FileExt, MetadataExt};
+
+    // Reflinked where the filesystem allows it, and copied with their holes
+    // otherwise
+    let scratch = Scratch::new("restores-kept");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("changed", "old content\n")]);
+    write_tree(&delta, &[("changed", "new content\n")]);
+    for dir in [&source, &delta] {
+        let image = std::fs::File::create(dir.join("image")).unwrap();
+        image.set_len(64 << 20).unwrap();
+        image.write_all_at(b"kept content\n", 32 << 20).unwrap();
+    }
+    let content = std::fs::read(delta.join("image")).unwrap();
+
+    diff(&source, &delta);
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    let metadata = std::fs::metadata(delta.join("image")).unwrap();
+    assert!(metadata.blocks() * 512 < 1 << 20, "restored {} blocks", metadata.blocks());
+    assert!(std::fs::read(delta.join("image")).unwrap() == content);
+    assert_eq!(std::fs::read(source.join("image")).unwrap(), content);
+}
+
+#[test]
+fn restores_files_stored_whole() {
+    // Larger than a single range copied within the kernel
+    let scratch = Scratch::new("restores-stored");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    let content: Vec<u8> = (0..10u64 << 20).map(|i| (i.wrapping_mul(0x9e3779b97f4a7c15) >> 56) as u8).collect();
+    write_tree(&source, &[("image", "old content\n")]);
+    std::fs::create_dir(&delta).unwrap();
+    std::fs::write(delta.join("image"), &content).unwrap();
+
+    let options = DiffOptions { algo: Some("as-is".to_owned()), ..Default::default() };
+    DeltaBuilder::new(&source, &delta).options(options).run().unwrap();
+    assert_eq!(MetaData::load(&delta).unwrap().changes, [(Algo::AsIs, b"image".to_vec())]);
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    assert!(std::fs::read(delta.join("image")).unwrap() == content);
+}
+
+#[test]
+fn recreates_special_files() {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+
+    let scratch = Scratch::new("recreates-specials");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("file", "old content\n")]);
+    write_tree(&delta, &[("file", "new content\n")]);
+    nix::unistd::mkfifo(&delta.join("fifo"), Mode::from_bits_truncate(0o640)).unwrap();
+    // Device nodes, such as /dev/null, can only be made by root
+    let with_device = mknod(&delta.join("null"), SFlag::S_IFCHR, Mode::from_bits_truncate(0o666),
+        makedev(1, 3)).is_ok();
+    diff(&source, &delta);
+
+    // As if the delta tree was copied by a tool leaving special files out
+    std::fs::remove_file(delta.join("fifo")).unwrap();
+    if with_device {
+        std::fs::remove_file(delta.join("null")).unwrap();
+    }
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    let fifo = std::fs::symlink_metadata(delta.join("fifo")).unwrap();
+    assert!(fifo.file_type().is_fifo());
+    assert_eq!(fifo.mode() & 0o777, 0o640);
+    if with_device {
+        let null = std::fs::symlink_metadata(delta.join("null")).unwrap();
+        assert!(null.file_type().is_char_device());
+        assert_eq!(null.rdev(), makedev(1, 3));
+    }
+}
+
+#[test]
+fn restores_nanosecond_timestamps() {
+    let scratch = Scratch::new("restores-timestamps");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n")]);
+    write_tree(&delta, &[("kept", "kept\n"), ("changed", "new content\n")]);
+    let accessed = filetime::FileTime::from_unix_time(1_600_000_000, 123_456_789);
+    let modified = filetime::FileTime::from_unix_time(1_700_000_000, 987_654_321);
+    for path in ["kept", "changed"] {
+        filetime::set_file_times(delta.join(path), accessed, modified).unwrap();
+    }
+
+    diff(&source, &delta);
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    for path in ["kept", "changed"] {
+        let metadata = std::fs::metadata(delta.join(path)).unwrap();
+        assert_eq!(filetime::FileTime::from_last_modification_time(&metadata), modified);
+        assert_eq!(filetime::FileTime::from_last_access_time(&metadata), accessed);
+    }
+}
+
+#[test]
+fn restores_target_with_each_meta_format() {
+    for (format, file) in [("json", "__deltaimage.meta.json"), ("cbor", "__deltaimage.meta"),
+        ("cbor-zstd", "__deltaimage.meta")]
+    {
+        let scratch = Scratch::new(&format!("meta-format-{}", format));
+        let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+        write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n")]);
+        write_tree(&delta, &[("kept", "kept\n"), ("changed", "new content\n")]);
+        write_tree(&target, &[("kept", "kept\n"), ("changed", "new content\n")]);
+
+        deltaimage(&["diff", "--meta-format", format], &[&source, &delta]);
+        assert_eq!(MetaFormat::of(&delta), Some(format.parse().unwrap()));
+        assert!(delta.join(file).exists());
+        deltaimage(&["apply"], &[&source, &delta]);
+        assert_eq!(read_tree(&delta), read_tree(&target));
+    }
+}
+
+#[test]
+fn restores_target_with_each_durability() {
+    for durability in ["none", "dir", "full"] {
+        let scratch = Scratch::new(&format!("durability-{}", durability));
+        let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+        let files = [("kept", "kept\n"), ("dir/changed", "new content\n"), ("dir/added", "added\n")];
+        write_tree(&source, &[("kept", "kept\n"), ("dir/changed", "old content\n"), ("deleted", "deleted\n")]);
+        write_tree(&delta, &files);
+        write_tree(&target, &files);
+
+        diff(&source, &delta);
+        deltaimage(&["apply", "--durability", durability], &[&source, &delta]);
+        assert_eq!(read_tree(&delta), read_tree(&target));
+    }
+    assert_eq!("dir".parse(), Ok(Durability::Dir));
+    assert!("fsync".parse::<Durability>().is_err());
+}
+
+#[test]
+fn restores_files_patched_against_extra_source() {
+    let scratch = Scratch::new("extra-source");
+    let (source, extra, delta, target) =
+        (scratch.join("source"), scratch.join("extra"), scratch.join("delta"), scratch.join("target"));
+    // Hardly compressible, so that only a patch against the extra source is small
+    let old: String = (0..2000u64).map(|i| format!("{:x}\n", i.wrapping_mul(0x9e3779b97f4a7c15))).collect();
+    let new = old.replacen('a', "A", 1);
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "unrelated\n")]);
+    write_tree(&extra, &[("changed", old.as_str())]);
+    write_tree(&delta, &[("kept", "kept\n"), ("changed", new.as_str())]);
+    write_tree(&target, &[("kept", "kept\n"), ("changed", new.as_str())]);
+
+    deltaimage(&["diff", "--extra-source", extra.to_str().unwrap()], &[&source, &delta]);
+    let md = MetaData::load(&delta).unwrap();
+    assert_eq!(md.bases, [(b"changed".to_vec(), 0)]);
+    let error = deltaimage_error(&["apply", source.to_str().unwrap(), delta.to_str().unwrap()]);
+    assert!(error.contains(&Error::MissingExtraSources(md.extra_sources.clone()).to_string()), "{}", error);
+    deltaimage(&["apply", "--extra-source", extra.to_str().unwrap()], &[&source, &delta]);
+    assert_eq!(read_tree(&delta), read_tree(&target));
+}
+
+#[test]
+fn restores_dropped_placeholders() {
+    let scratch = Scratch::new("drop-placeholders");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    let files = [("kept", "kept\n"), ("dir/kept", "kept too\n"), ("changed", "new content\n")];
+    write_tree(&source, &[("kept", "kept\n"), ("dir/kept", "kept too\n"), ("changed", "old content\n")]);
+    write_tree(&delta, &files);
+    write_tree(&target, &files);
+    let modified = filetime::FileTime::from_unix_time(1_700_000_000, 0);
+    filetime::set_file_mtime(delta.join("dir/kept"), modified).unwrap();
+
+    deltaimage(&["diff", "--drop-placeholders"], &[&source, &delta]);
+    assert!(!delta.join("kept").exists() && !delta.join("dir/kept").exists());
+    assert_eq!(MetaData::load(&delta).unwrap().placeholders.len(), 2);
+    deltaimage(&["apply"], &[&source, &delta]);
+    assert_eq!(read_tree(&delta), read_tree(&target));
+    let metadata = std::fs::metadata(delta.join("dir/kept")).unwrap();
+    assert_eq!(filetime::FileTime::from_last_modification_time(&metadata), modified);
+}
+
+#[test]
+fn leaves_out_times_and_ownership() {
+    let scratch = Scratch::new("ignore-times");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    let files = [("kept", "kept\n"), ("changed", "new content\n"), ("added", "added\n")];
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n")]);
+    write_tree(&delta, &files);
+    write_tree(&target, &files);
+    let modified = filetime::FileTime::from_unix_time(1_700_000_000, 0);
+    for path in ["kept", "changed"] {
+        filetime::set_file_mtime(delta.join(path), modified).unwrap();
+    }
+
+    deltaimage(&["diff", "--ignore-times", "--ignore-owner"], &[&source, &delta]);
+    let md = MetaData::load(&delta).unwrap();
+    assert!(md.ignore_times && md.ignore_owner && md.tree_digest.is_none());
+    deltaimage(&["apply"], &[&source, &delta]);
+    assert_eq!(read_tree(&delta), read_tree(&target));
+    for path in ["kept", "changed"] {
+        let metadata = std::fs::metadata(delta.join(path)).unwrap();
+        assert_ne!(filetime::FileTime::from_last_modification_time(&metadata), modified, "{}", path);
+    }
+}
+
+#[test]
+fn keeps_files_found_unchanged_by_prehash() {
+    let scratch = Scratch::new("prehash");
+    let source = scratch.join("source");
+    // Files of the same size, with the same content or not
+    let files = [("kept", "kept\n"), ("dir/kept", "kept too\n"), ("changed", "new content\n"), ("added", "added\n")];
+    write_tree(&source, &[("kept", "kept\n"), ("dir/kept", "kept too\n"), ("changed", "old content\n")]);
+
+    let mut deltas = vec![];
+    for (name, args) in [("delta", &["diff"][..]), ("compared", &["diff", "--no-prehash"])] {
+        let delta = scratch.join(name);
+        write_tree(&delta, &files);
+        deltaimage(args, &[&source, &delta]);
+        deltas.push((MetaData::load(&delta).unwrap(), delta));
+    }
+    let [(prehashed, delta), (compared, _)] = &deltas[..] else { unreachable!() };
+    assert_eq!(prehashed.keep_files, compared.keep_files);
+    assert_eq!(prehashed.changes, compared.changes);
+    assert_eq!(prehashed.checksums, compared.checksums);
+
+    deltaimage(&["apply"], &[&source, delta]);
+    let target = scratch.join("target");
+    write_tree(&target, &files);
+    assert_eq!(read_tree(delta), read_tree(&target));
+}
+
+#[test]
+fn trusts_modification_times() {
+    let scratch = Scratch::new("trust-mtime");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("kept", "kept\n"), ("touched", "old content\n"), ("changed", "old content\n")]);
+    write_tree(&delta, &[("kept", "kept\n"), ("touched", "new content\n"), ("changed", "new content\n")]);
+    // `touched` was rewritten with its modification time restored
+    let modified = filetime::FileTime::from_unix_time(1_700_000_000, 0);
+    for dir in [&source, &delta] {
+        for path in ["kept", "touched"] {
+            filetime::set_file_mtime(dir.join(path), modified).unwrap();
+        }
+    }
+
+    deltaimage(&["diff", "--trust-mtime"], &[&source, &delta]);
+    let md = MetaData::load(&delta).unwrap();
+    let checksums: Vec<_> = md.checksums.iter().map(|(path, _)| path.as_slice()).collect();
+    assert_eq!(checksums, [&b"changed"[..]]);
+    assert!(md.tree_digest.is_none());
+
+    deltaimage(&["apply"], &[&source, &delta]);
+    let target = scratch.join("target");
+    write_tree(&target, &[("kept", "kept\n"), ("touched", "old content\n"), ("changed", "new content\n")]);
+    assert_eq!(read_tree(&delta), read_tree(&target));
+}
@@ -0,0 +1,110 @@
+//! Package changes of a delta, from the SBOMs of the source and target images
+
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use deltaimage::{DeltaStats, Error, PackageChangeKind, PackageSummary, Sbom};
+
+use common::{deltaimage_output, diff, write_tree, Scratch};
+
+/// A delta upgrading `tool`, adding `libnew` and removing `libold`, with a
+/// changed file of no package
+fn make_delta(scratch: &Scratch) -> PathBuf {
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("usr/bin/tool", "tool 1.0\n"), ("usr/lib/libold.so", "old library\n"),
+        ("etc/motd", "welcome\n")]);
+    write_tree(&delta, &[("usr/bin/tool", "tool 1.1\n"), ("usr/lib/libnew.so", "new library\n"),
+        ("etc/motd", "welcome back\n")]);
+    diff(&source, &delta);
+    delta
+}
+
+fn write_json(path: &Path, value: serde_json::Value) {
+    std::fs::write(path, serde_json::to_vec(&value).unwrap()).unwrap();
+}
+
+/// SPDX document of the source image
+fn source_spdx(path: &Path) {
+    write_json(path, serde_json::json!({
+        "spdxVersion": "SPDX-2.3",
+        "packages": [
+            { "SPDXID": "SPDXRef-tool", "name": "tool", "versionInfo": "1.0", "hasFiles": ["SPDXRef-f1"] },
+            { "SPDXID": "SPDXRef-libold", "name": "libold", "versionInfo": "2.0" },
+            { "SPDXID": "SPDXRef-base", "name": "base-files", "versionInfo": "12" },
+        ],
+        "files": [
+            { "SPDXID": "SPDXRef-f1", "fileName": "/usr/bin/tool" },
+            { "SPDXID": "SPDXRef-f2", "fileName": "/usr/lib/libold.so" },
+        ],
+        "relationships": [
+            { "spdxElementId": "SPDXRef-libold", "relationshipType": "CONTAINS", "relatedSpdxElement": "SPDXRef-f2" },
+        ],
+    }));
+}
+
+/// CycloneDX document of the target image
+fn target_cyclonedx(path: &Path) {
+    let occurrences = |location: &str| serde_json::json!({ "occurrences": [{ "location": location }] });
+    write_json(path, serde_json::json!({
+        "bomFormat": "CycloneDX",
+        "components": [
+            { "type": "application", "name": "tool", "version": "1.1", "evidence": occurrences("/usr/bin/tool") },
+            { "type": "library", "name": "libnew", "version": "1.0", "evidence": occurrences("usr/lib/libnew.so") },
+            { "type": "operating-system", "name": "base-files", "version": "12" },
+        ],
+    }));
+}
+
+#[test]
+fn lists_changed_packages() {
+    let scratch = Scratch::new("sbom-packages");
+    let delta = make_delta(&scratch);
+    let (source_sbom, target_sbom) = (scratch.join("a.spdx.json"), scratch.join("b.cdx.json"));
+    source_spdx(&source_sbom);
+    target_cyclonedx(&target_sbom);
+
+    let stats = DeltaStats::collect(&delta).unwrap();
+    let summary = PackageSummary::new(&Sbom::load(&source_sbom).unwrap(), &Sbom::load(&target_sbom).unwrap(),
+        &stats);
+    let mut packages: Vec<_> = summary.packages.iter()
+        .map(|package| (package.name.as_str(), package.change, package.old_version.as_deref(),
+            package.new_version.as_deref(), package.files))
+        .collect();
+    packages.sort();
+    assert_eq!(packages, [
+        ("libnew", PackageChangeKind::Added, None, Some("1.0"), 1),
+        ("libold", PackageChangeKind::Removed, Some("2.0"), None, 0),
+        ("tool", PackageChangeKind::Changed, Some("1.0"), Some("1.1"), 1),
+    ]);
+    let motd = stats.files.iter().find(|file| file.path == Path::new("etc/motd")).unwrap();
+    assert_eq!(summary.unpackaged_delta_size, motd.delta_size);
+    assert!(summary.packages.windows(2).all(|pair| pair[0].delta_size >= pair[1].delta_size));
+}
+
+#[test]
+fn reads_syft_output() {
+    let scratch = Scratch::new("sbom-syft");
+    let delta = make_delta(&scratch);
+    let (source_sbom, target_sbom) = (scratch.join("a.syft.json"), scratch.join("b.syft.json"));
+    let artifact = |name: &str, version: &str, path: &str| serde_json::json!({
+        "name": name, "version": version, "metadata": { "files": [{ "path": path }] },
+    });
+    write_json(&source_sbom, serde_json::json!({ "artifacts": [artifact("tool", "1.0", "/usr/bin/tool")] }));
+    write_json(&target_sbom, serde_json::json!({ "artifacts": [artifact("tool", "1.1", "/usr/bin/tool")] }));
+
+    let output = deltaimage_output(&["stats", delta.to_str().unwrap(), "--source-sbom", source_sbom.to_str().unwrap(),
+        "--target-sbom", target_sbom.to_str().unwrap()]);
+    assert!(output.contains("Package changes:"), "{}", output);
+    let line = output.lines().find(|line| line.starts_with("changed")).expect("no changed package");
+    assert!(line.ends_with(" tool 1.0 -> 1.1"), "{}", line);
+}
+
+#[test]
+fn refuses_unknown_formats() {
+    let scratch = Scratch::new("sbom-unknown");
+    let path = scratch.join("sbom.json");
+    write_json(&path, serde_json::json!({ "packages": [] }));
+    let err = Sbom::load(&path).expect_err("loaded an unknown format");
+    assert!(matches!(err.downcast_ref::<Error>(), Some(Error::UnknownSbomFormat(_))), "{:?}", err);
+}
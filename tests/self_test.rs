@@ -0,0 +1,29 @@
+//! Capabilities of the environment found by `self-test`
+
+#![cfg(unix)]
+
+mod common;
+
+use deltaimage::self_test;
+
+use common::Scratch;
+
+#[test]
+fn finds_capabilities() {
+    let scratch = Scratch::new("self-test");
+    let work_dir = scratch.join("work");
+    let report = self_test(&work_dir).unwrap();
+    assert!(report.build.starts_with(env!("CARGO_PKG_VERSION")), "{}", report.build);
+    assert!(!work_dir.exists());
+
+    let problem = |name: &str| report.capabilities.iter().find(|capability| capability.name == name)
+        .unwrap_or_else(|| panic!("{} not tried", name)).problem.clone();
+    assert_eq!(problem("xdelta3 encoding"), None);
+    assert_eq!(problem("file times"), None);
+    assert_eq!(problem("hardlinks"), None);
+    assert_eq!(problem("ownership").is_none(), nix::unistd::geteuid().is_root());
+    let missing: Vec<_> = report.capabilities.iter()
+        .filter(|capability| capability.required && capability.problem.is_some())
+        .collect();
+    assert!(missing.is_empty(), "{:?}", missing);
+}
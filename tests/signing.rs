@@ -0,0 +1,74 @@
+//! Deltas signed by diff and checked by apply
+
+mod common;
+
+use std::path::Path;
+
+use deltaimage::{generate_key, ApplyOptions, DeltaApplier, DeltaBuilder, DiffOptions, Error};
+
+use common::{read_tree, tamper, write_tree, Scratch};
+
+fn signed_delta(scratch: &Scratch, key: Option<&Path>) {
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n")]);
+    write_tree(&delta, &[("kept", "kept\n"), ("changed", "new content\n")]);
+    let options = DiffOptions { sign_key: key.map(Path::to_owned), ..Default::default() };
+    DeltaBuilder::new(&source, &delta).options(options).run().unwrap();
+}
+
+fn apply_verified(scratch: &Scratch, key: &Path) -> anyhow::Result<()> {
+    let options = ApplyOptions { verify_key: Some(key.to_owned()), ..Default::default() };
+    DeltaApplier::new(scratch.join("source"), scratch.join("delta")).options(options).run()?;
+    Ok(())
+}
+
+fn keygen(scratch: &Scratch, name: &str) -> (std::path::PathBuf, std::path::PathBuf) {
+    let (secret, public) = (scratch.join(&format!("{}.sec", name)), scratch.join(&format!("{}.pub", name)));
+    generate_key(&secret, &public).unwrap();
+    (secret, public)
+}
+
+#[test]
+fn applies_signed_delta() {
+    let scratch = Scratch::new("signing-applies");
+    let (secret, public) = keygen(&scratch, "key");
+    signed_delta(&scratch, Some(&secret));
+
+    apply_verified(&scratch, &public).unwrap();
+    let expected = scratch.join("expected");
+    write_tree(&expected, &[("kept", "kept\n"), ("changed", "new content\n")]);
+    assert_eq!(read_tree(&scratch.join("delta")), read_tree(&expected));
+}
+
+#[test]
+fn refuses_unsigned_delta() {
+    let scratch = Scratch::new("signing-unsigned");
+    let (_, public) = keygen(&scratch, "key");
+    signed_delta(&scratch, None);
+
+    let err = apply_verified(&scratch, &public).unwrap_err();
+    assert!(matches!(err.downcast_ref::<Error>(), Some(Error::MissingSignature(_))), "{:?}", err);
+}
+
+#[test]
+fn refuses_delta_signed_with_other_key() {
+    let scratch = Scratch::new("signing-other-key");
+    let (secret, _) = keygen(&scratch, "key");
+    let (_, other) = keygen(&scratch, "other");
+    signed_delta(&scratch, Some(&secret));
+
+    let err = apply_verified(&scratch, &other).unwrap_err();
+    assert!(matches!(err.downcast_ref::<Error>(), Some(Error::BadSignature(_))), "{:?}", err);
+}
+
+#[test]
+fn refuses_tampered_meta_data() {
+    let scratch = Scratch::new("signing-tampered");
+    let (secret, public) = keygen(&scratch, "key");
+    signed_delta(&scratch, Some(&secret));
+    tamper(&scratch.join("delta"), |md| md.deleted_files.push(b"kept".to_vec()));
+
+    let err = apply_verified(&scratch, &public).unwrap_err();
+    assert!(matches!(err.downcast_ref::<Error>(), Some(Error::BadSignature(_))), "{:?}", err);
+    assert!(scratch.join("delta/kept").exists());
+}
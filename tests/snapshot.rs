@@ -0,0 +1,23 @@
+//! Apply on a btrfs or ZFS clone of the source tree, refused elsewhere
+
+mod common;
+
+use deltaimage::{apply_on_snapshot, ApplyOptions, Error};
+
+use common::{diff, read_tree_bytes, write_tree, Scratch};
+
+#[test]
+fn refuses_trees_that_cannot_be_cloned() {
+    let scratch = Scratch::new("snapshot-refused");
+    let (source, delta, target) = (scratch.join("source"), scratch.join("delta"), scratch.join("target"));
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n")]);
+    write_tree(&delta, &[("kept", "kept\n"), ("changed", "new content\n")]);
+    diff(&source, &delta);
+    let stored = read_tree_bytes(&delta);
+
+    let err = apply_on_snapshot(&source, &delta, &target, ApplyOptions::default()).expect_err("cloned a plain tree");
+    assert!(matches!(err.downcast_ref::<Error>(), Some(Error::NotSnapshottable(path)) if path == &source), "{:?}", err);
+    // The delta is left to be applied otherwise
+    assert!(!target.exists());
+    assert_eq!(read_tree_bytes(&delta), stored);
+}
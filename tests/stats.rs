@@ -0,0 +1,55 @@
+//! Breakdown of delta directories by `stats`
+
+mod common;
+
+use std::path::Path;
+
+use deltaimage::DeltaStats;
+
+use common::{deltaimage_output, diff, write_tree, Scratch};
+
+#[test]
+fn counts_files_by_algorithm() {
+    let scratch = Scratch::new("stats-counts");
+    let (source, target) = (scratch.join("source"), scratch.join("target"));
+    write_tree(&source, &[("kept", "same"), ("changed", "before"), ("deleted", "gone")]);
+    write_tree(&target, &[("kept", "same"), ("changed", "after, and longer"), ("added", "new file")]);
+    diff(&source, &target);
+
+    let stats = DeltaStats::collect(&target).unwrap();
+    assert_eq!(stats.counts.get("keep"), Some(&1));
+    assert_eq!(stats.counts.get("new"), Some(&1));
+    assert_eq!(stats.deleted_files, 1);
+    assert_eq!(stats.files.len(), 2);
+    assert_eq!(stats.total_delta_size, stats.files.iter().map(|file| file.delta_size).sum::<u64>());
+}
+
+#[test]
+fn records_restored_sizes() {
+    let scratch = Scratch::new("stats-sizes");
+    let (source, target) = (scratch.join("source"), scratch.join("target"));
+    write_tree(&source, &[("changed", "before")]);
+    write_tree(&target, &[("changed", "after, and longer"), ("added", "new file")]);
+    diff(&source, &target);
+
+    let stats = DeltaStats::collect(&target).unwrap();
+    let size_of = |path: &str| stats.files.iter()
+        .find(|file| file.path == Path::new(path))
+        .and_then(|file| file.original_size);
+    assert_eq!(size_of("changed"), Some(17));
+    assert_eq!(size_of("added"), Some(8));
+    assert_eq!(stats.ratio_histogram().iter().sum::<usize>(), 2);
+}
+
+#[test]
+fn lists_largest_files_first() {
+    let scratch = Scratch::new("stats-largest");
+    let (source, target) = (scratch.join("source"), scratch.join("target"));
+    std::fs::create_dir(&source).unwrap();
+    write_tree(&target, &[("small", "a"), ("large", "a much longer file")]);
+    diff(&source, &target);
+
+    let output = deltaimage_output(&["stats", "--top", "1", target.to_str().unwrap()]);
+    assert!(output.contains("large"));
+    assert!(!output.contains("small"));
+}
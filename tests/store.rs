@@ -0,0 +1,41 @@
+//! Push and pull of delta archives to object stores, as a directory
+
+mod common;
+
+use deltaimage::{pull_delta, push_delta, Error};
+
+use common::{deltaimage_error, deltaimage_output, Scratch};
+
+#[test]
+fn pushes_and_pulls_by_digests() {
+    let scratch = Scratch::new("store-dir");
+    let archive = scratch.join("delta.arch");
+    std::fs::write(&archive, "archive\n").unwrap();
+    let store = format!("file://{}", scratch.join("store").display());
+
+    let pushed = deltaimage_output(&["push", &store, archive.to_str().unwrap(), "--from", "sha256:aa",
+        "--to", "sha256:bb"]);
+    assert_eq!(pushed, "Pushed sha256:aa/sha256:bb.arch\n");
+    assert!(scratch.join("store/sha256:aa/sha256:bb.arch").is_file());
+
+    let pulled = scratch.join("pulled.arch");
+    pull_delta(&store, "sha256:aa", "sha256:bb", &pulled).unwrap();
+    assert_eq!(std::fs::read_to_string(&pulled).unwrap(), "archive\n");
+    pull_delta(&store, "sha256:aa", "sha256:cc", &scratch.join("missing.arch")).expect_err("pull succeeded");
+}
+
+#[test]
+fn refuses_invalid_keys_and_stores() {
+    let scratch = Scratch::new("store-invalid");
+    let archive = scratch.join("delta.arch");
+    std::fs::write(&archive, "archive\n").unwrap();
+    let store = format!("file://{}", scratch.join("store").display());
+
+    for (from, to) in [("../escape", "sha256:bb"), ("sha256:aa", ".hidden"), ("", "sha256:bb")] {
+        let err = push_delta(&store, from, to, &archive).expect_err("push succeeded");
+        assert!(matches!(err.downcast_ref::<Error>(), Some(Error::StoreError(_))), "{:?}", err);
+    }
+
+    let err = deltaimage_error(&["push", "ftp://host/deltas", archive.to_str().unwrap(), "--from", "a", "--to", "b"]);
+    assert!(err.contains("Unsupported object store URL ftp://host/deltas"), "{}", err);
+}
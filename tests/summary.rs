@@ -0,0 +1,45 @@
+//! Summaries and exit codes of diff for pipelines to branch on
+
+mod common;
+
+use std::path::Path;
+use std::process::Command;
+
+use common::{write_tree, Scratch};
+
+/// Diff with the given arguments, returning the exit code and the printed
+/// `key=value` summary
+fn diff_summary(source: &Path, target: &Path, args: &[&str]) -> (i32, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_deltaimage"))
+        .args(["diff", "--exit-code", "--summary-format", "key-value"]).args(args).arg(source).arg(target)
+        .output().unwrap();
+    (output.status.code().unwrap(), String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn exits_with_outcome() {
+    let scratch = Scratch::new("summary-outcome");
+    let source = scratch.join("source");
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n")]);
+
+    let unchanged = scratch.join("unchanged");
+    write_tree(&unchanged, &[("kept", "kept\n"), ("changed", "old content\n")]);
+    let (code, summary) = diff_summary(&source, &unchanged, &[]);
+    assert_eq!(code, 2, "{}", summary);
+    assert!(summary.contains("result=unchanged\n"), "{}", summary);
+
+    // Patches that do not save enough are stored whole
+    let fallback = scratch.join("fallback");
+    write_tree(&fallback, &[("kept", "kept\n"), ("changed", "new content\n")]);
+    let (code, summary) = diff_summary(&source, &fallback, &["--min-delta-ratio", "1"]);
+    assert_eq!(code, 3, "{}", summary);
+    assert!(summary.contains("result=fallback-heavy\n"), "{}", summary);
+    assert!(summary.contains("fallback_ratio=1.0000\n"), "{}", summary);
+
+    let delta = scratch.join("delta");
+    write_tree(&delta, &[("kept", "kept\n"), ("changed", "new content\n"), ("added", "added\n")]);
+    let (code, summary) = diff_summary(&source, &delta, &["--fallback-threshold", "1"]);
+    assert_eq!(code, 0, "{}", summary);
+    assert!(summary.contains("result=delta\n"), "{}", summary);
+    assert!(summary.contains("new_files=1\n"), "{}", summary);
+}
@@ -0,0 +1,77 @@
+//! Apply of trees whose directories were replaced by symlinks to outside of
+//! them, which must not be followed
+
+#![cfg(unix)]
+
+mod common;
+
+use std::path::Path;
+
+use deltaimage::{DeltaApplier, Error, LinkGroup, MetaData};
+
+use common::{diff, read_tree, write_tree, Scratch};
+
+/// Make a delta of a tree with a nested directory, let `replace` swap one
+/// of the directories for a symlink into `outside`, and check that apply
+/// refuses it without touching `outside`
+fn apply_through_symlink(name: &str, replace: impl FnOnce(&Path, &Path, &Path)) {
+    let scratch = Scratch::new(name);
+    let (source, delta, outside) = (scratch.join("source"), scratch.join("delta"), scratch.join("outside"));
+    write_tree(&source, &[("dir/kept", "kept\n"), ("dir/changed", "old content\n")]);
+    write_tree(&delta, &[("dir/kept", "kept\n"), ("dir/changed", "new content\n")]);
+    diff(&source, &delta);
+    std::fs::create_dir(&outside).unwrap();
+    replace(&source, &delta, &outside);
+    let before = read_tree(&outside);
+
+    let err = DeltaApplier::new(&source, &delta).run().expect_err("apply succeeded");
+    assert!(matches!(err.downcast_ref::<Error>(), Some(Error::UnsafePath(_))), "unexpected error: {:?}", err);
+    assert_eq!(read_tree(&outside), before);
+}
+
+fn move_to_symlink(dir: &Path, outside: &Path) {
+    std::fs::rename(dir, outside).unwrap();
+    std::os::unix::fs::symlink(outside, dir).unwrap();
+}
+
+#[test]
+fn delta_directory_symlink() {
+    apply_through_symlink("symlink-delta-dir", |_, delta, outside| {
+        move_to_symlink(&delta.join("dir"), &outside.join("dir"))
+    });
+}
+
+#[test]
+fn source_directory_symlink() {
+    apply_through_symlink("symlink-source-dir", |source, _, outside| {
+        move_to_symlink(&source.join("dir"), &outside.join("dir"))
+    });
+}
+
+#[test]
+fn link_group_through_symlink() {
+    apply_through_symlink("symlink-link-group", |_, delta, outside| {
+        write_tree(outside, &[("secret", "secret\n")]);
+        std::os::unix::fs::symlink(outside, delta.join("escape")).unwrap();
+        let mut md = MetaData::load(delta).unwrap();
+        let checksum = md.checksums.iter().find(|(path, _)| path == b"dir/kept").unwrap().1.clone();
+        md.link_groups = Some(vec![LinkGroup {
+            paths: vec![b"dir/kept".to_vec(), b"escape/secret".to_vec()],
+            checksum,
+        }]);
+        md.save(delta).unwrap();
+    });
+}
+
+#[test]
+fn applies_with_deleted_directory() {
+    let scratch = Scratch::new("symlink-deleted-dir");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("gone/file", "gone\n"), ("kept", "kept\n")]);
+    write_tree(&delta, &[("kept", "kept\n")]);
+    let target = read_tree(&delta);
+    diff(&source, &delta);
+
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    assert_eq!(read_tree(&delta), target);
+}
@@ -0,0 +1,87 @@
+//! Deltas of tarballs made with `--from-tar`, without extracting them
+
+mod common;
+
+use std::io::Write;
+use std::path::Path;
+
+use common::{deltaimage, deltaimage_error, Scratch};
+
+fn write_tar(path: &Path, files: &[(&str, String)]) -> Vec<u8> {
+    let mut builder = tar::Builder::new(vec![]);
+    for (name, content) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(1_700_000_000);
+        builder.append_data(&mut header, name, content.as_bytes()).unwrap();
+    }
+    let tar = builder.into_inner().unwrap();
+    std::fs::write(path, &tar).unwrap();
+    tar
+}
+
+/// Files of a tarball, by name
+type Files = Vec<(&'static str, String)>;
+
+fn tar_trees() -> (Files, Files) {
+    let content = "content of a file in a layer\n".repeat(200);
+    let source = vec![("kept", content.clone()), ("changed", content.clone()), ("deleted", "gone\n".to_owned())];
+    let target = vec![("kept", content.clone()), ("changed", content.replacen("file", "FILE", 5)),
+        ("added", "new file\n".to_owned())];
+    (source, target)
+}
+
+#[test]
+fn restores_identical_tarball() {
+    let scratch = Scratch::new("tar-identical");
+    let (source, target) = tar_trees();
+    write_tar(&scratch.join("a.tar"), &source);
+    let target_tar = write_tar(&scratch.join("b.tar"), &target);
+
+    let (delta, output) = (scratch.join("layer.delta"), scratch.join("restored.tar"));
+    deltaimage(&["diff", "--from-tar", "--output", delta.to_str().unwrap()],
+        &[&scratch.join("a.tar"), &scratch.join("b.tar")]);
+    assert!(std::fs::metadata(&delta).unwrap().len() < target_tar.len() as u64);
+    deltaimage(&["apply", "--from-tar", "--output", output.to_str().unwrap()],
+        &[&scratch.join("a.tar"), &delta]);
+    assert_eq!(std::fs::read(&output).unwrap(), target_tar);
+}
+
+#[test]
+fn reads_gzip_compressed_tarballs() {
+    let scratch = Scratch::new("tar-gzip");
+    let (source, target) = tar_trees();
+    let source_tar = write_tar(&scratch.join("a.tar"), &source);
+    let target_tar = write_tar(&scratch.join("b.tar"), &target);
+    let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    encoder.write_all(&source_tar).unwrap();
+    std::fs::write(scratch.join("a.tar.gz"), encoder.finish().unwrap()).unwrap();
+
+    let (delta, output) = (scratch.join("layer.delta"), scratch.join("restored.tar"));
+    deltaimage(&["diff", "--from-tar", "--output", delta.to_str().unwrap()],
+        &[&scratch.join("a.tar.gz"), &scratch.join("b.tar")]);
+    deltaimage(&["apply", "--from-tar", "--output", output.to_str().unwrap()],
+        &[&scratch.join("a.tar.gz"), &delta]);
+    assert_eq!(std::fs::read(&output).unwrap(), target_tar);
+}
+
+#[test]
+fn refuses_mismatching_source_tarball() {
+    let scratch = Scratch::new("tar-mismatch");
+    let (source, target) = tar_trees();
+    write_tar(&scratch.join("a.tar"), &source);
+    write_tar(&scratch.join("b.tar"), &target);
+    let delta = scratch.join("layer.delta");
+    deltaimage(&["diff", "--from-tar", "--output", delta.to_str().unwrap()],
+        &[&scratch.join("a.tar"), &scratch.join("b.tar")]);
+
+    let other: Vec<_> = source.iter().map(|(name, content)| (*name, content.replace("layer", "LAYER"))).collect();
+    write_tar(&scratch.join("other.tar"), &other);
+    let output = scratch.join("restored.tar");
+    let err = deltaimage_error(&["apply", "--from-tar", "--output", output.to_str().unwrap(),
+        scratch.join("other.tar").to_str().unwrap(), delta.to_str().unwrap()]);
+    assert!(err.contains("does not match"), "{}", err);
+}
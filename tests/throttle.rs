@@ -0,0 +1,32 @@
+//! Applies limited in the bandwidth and priorities they take
+
+mod common;
+
+use std::time::Instant;
+
+use common::{deltaimage, deltaimage_error, diff, read_tree, write_tree, Scratch};
+
+#[test]
+fn limits_write_rate() {
+    let scratch = Scratch::new("throttle-io-limit");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    let (old, new) = ("old\n".repeat(10_000), "new\n".repeat(10_000));
+    write_tree(&source, &[("changed", &old)]);
+    write_tree(&delta, &[("changed", &new)]);
+    let target = read_tree(&delta);
+    diff(&source, &delta);
+
+    // 40kB at 20kB/s, less the burst allowed at first
+    let started = Instant::now();
+    deltaimage(&["apply", "--io-limit", "0.02", "--nice", "19", "--ionice", "best-effort:7"], &[&source, &delta]);
+    assert!(started.elapsed().as_secs_f64() > 1.5, "took {:?}", started.elapsed());
+    assert_eq!(read_tree(&delta), target);
+}
+
+#[test]
+fn rejects_invalid_limits() {
+    let error = deltaimage_error(&["apply", "--io-limit", "0", "source", "delta"]);
+    assert!(error.contains("--io-limit must be a positive number"), "{}", error);
+    let error = deltaimage_error(&["apply", "--ionice", "best-effort:8", "source", "delta"]);
+    assert!(error.contains("invalid best-effort level 8"), "{}", error);
+}
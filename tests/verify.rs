@@ -0,0 +1,41 @@
+//! Verification of a delta directory against a source tree, without applying it
+
+mod common;
+
+use std::path::PathBuf;
+
+use deltaimage::DeltaVerifier;
+
+use common::{diff, read_tree_bytes, write_tree, Scratch};
+
+#[test]
+fn reports_no_problems() {
+    let scratch = Scratch::new("verify-clean");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n")]);
+    write_tree(&delta, &[("kept", "kept\n"), ("changed", "new content\n")]);
+    diff(&source, &delta);
+
+    let before = read_tree_bytes(&delta);
+    let report = DeltaVerifier::new(&source, &delta).run().unwrap();
+    assert_eq!(report.checked, 2);
+    assert!(report.problems.is_empty());
+    assert_eq!(read_tree_bytes(&delta), before);
+}
+
+#[test]
+fn reports_each_problem() {
+    let scratch = Scratch::new("verify-problems");
+    let (source, delta) = (scratch.join("source"), scratch.join("delta"));
+    write_tree(&source, &[("kept", "kept\n"), ("other", "other\n"), ("changed", "old content\n")]);
+    write_tree(&delta, &[("kept", "kept\n"), ("other", "other\n"), ("changed", "new content\n")]);
+    diff(&source, &delta);
+    std::fs::write(delta.join("kept"), "no longer a placeholder\n").unwrap();
+    std::fs::remove_file(source.join("changed")).unwrap();
+
+    let report = DeltaVerifier::new(&source, &delta).run().unwrap();
+    let mut paths: Vec<_> = report.problems.iter().map(|problem| problem.path.clone()).collect();
+    paths.sort();
+    assert_eq!(paths, [PathBuf::from("changed"), PathBuf::from("kept")]);
+    assert!(report.into_result().is_err());
+}
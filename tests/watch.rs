@@ -0,0 +1,44 @@
+//! Watch mode keeping a delta up to date with a changing target tree
+
+mod common;
+
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use deltaimage::{DeltaApplier, MetaData};
+
+use common::{read_tree, write_tree, Scratch};
+
+/// Wait for the meta-data of the delta to satisfy `f`
+fn wait_for(delta: &Path, f: impl Fn(&MetaData) -> bool) {
+    let started = Instant::now();
+    while !MetaData::load(delta).is_ok_and(|md| f(&md)) {
+        assert!(started.elapsed() < Duration::from_secs(30), "delta was not updated");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn updates_changed_files() {
+    let scratch = Scratch::new("watch");
+    let (source, target, delta) = (scratch.join("source"), scratch.join("target"), scratch.join("delta"));
+    write_tree(&source, &[("kept", "kept\n"), ("changed", "old content\n")]);
+    write_tree(&target, &[("kept", "kept\n"), ("changed", "new content\n")]);
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_deltaimage"))
+        .args(["watch", "--debounce-ms", "50", "--output", delta.to_str().unwrap()])
+        .arg(&source).arg(&target)
+        .spawn().unwrap();
+    wait_for(&delta, |md| md.keep_files.len() == 1);
+
+    // Unmodified files become modified ones
+    std::fs::write(target.join("kept"), "no longer kept\n").unwrap();
+    wait_for(&delta, |md| md.keep_files.is_empty());
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    let expected = read_tree(&target);
+    DeltaApplier::new(&source, &delta).run().unwrap();
+    assert_eq!(read_tree(&delta), expected);
+}